@@ -0,0 +1,10 @@
+//! Generates the gRPC service of `--grpc` (`src/grpc.rs`) from `proto/fileserver.proto`.
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=proto/fileserver.proto");
+    let descriptors = protox::compile(["proto/fileserver.proto"], ["proto"])?;
+    tonic_build::configure()
+        .build_client(false)
+        .compile_fds(descriptors)?;
+    Ok(())
+}
@@ -0,0 +1,344 @@
+//! The `/admin` API (`--admin-token`): inspecting and purging the archive cache, listing the
+//! requests in flight, reloading the configuration and draining the server for maintenance.
+//!
+//! The routes sit outside the normal authentication and only accept
+//! `Authorization: Bearer <admin token>`, so download credentials never grant admin access and
+//! the admin token grants nothing else. While the server drains, every new request apart from
+//! `/admin` and `/metrics` is refused with 503 Service Unavailable and a Retry-After, and the
+//! requests in flight run to completion; `GET /admin` shows when none are left.
+
+use axum::{
+    extract::{Query, State},
+    http::{HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info, warn};
+
+use crate::cache::ArchiveCache;
+use crate::client::{server_error, ClientResult, Connection};
+use crate::metrics::Metrics;
+
+/// Where the admin routes live.
+pub const ADMIN_PREFIX: &str = "/admin";
+/// What a client refused while the server drains is told to wait.
+const DRAIN_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// What `GET /admin` reports, and what the other changes answer with.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdminStatus {
+    /// New requests are being refused
+    pub draining: bool,
+    /// Requests being served, not counting the admin API's own
+    pub requests_in_flight: usize,
+    /// The archive cache, when the server has one
+    pub cache: Option<CacheUsage>,
+}
+
+/// How full the archive cache is.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CacheUsage {
+    pub archives: usize,
+    pub bytes: u64,
+    /// The --cache-max-size the cache is kept under
+    pub max_bytes: u64,
+}
+
+/// Body of `GET /admin/cache`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CacheReport {
+    pub usage: CacheUsage,
+    /// Most recently used first
+    pub archives: Vec<CachedArchive>,
+}
+
+/// One archive in the cache.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CachedArchive {
+    pub etag: String,
+    pub size: u64,
+}
+
+/// Body of `DELETE /admin/cache`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CachePurged {
+    pub removed: usize,
+    pub bytes: u64,
+}
+
+/// One request in flight, in `GET /admin/transfers`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Transfer {
+    pub id: u64,
+    pub client: String,
+    pub method: String,
+    /// Path and query
+    pub path: String,
+    /// Response body bytes sent so far
+    pub bytes_sent: u64,
+    /// How long it has been running
+    pub seconds: f64,
+}
+
+/// Query of `DELETE /admin/cache`.
+#[derive(Deserialize, Debug)]
+struct PurgeParams {
+    /// Only remove the archive with this ETag
+    etag: Option<String>,
+}
+
+/// A `POST /admin/reload` waiting for the configuration to be applied again; see
+/// [`crate::server::Reloader::admin_requests`].
+pub struct ReloadRequest(oneshot::Sender<Result<(), String>>);
+
+impl ReloadRequest {
+    /// Answers the request with the outcome of the reload.
+    pub fn respond(self, result: Result<(), String>) {
+        let _ = self.0.send(result);
+    }
+}
+
+/// The switches the admin API flips, kept across reloads.
+#[derive(Default)]
+pub(crate) struct ServerControl {
+    draining: AtomicBool,
+    /// Where `POST /admin/reload` is handed on to, once somebody asked for reload requests
+    reloads: Mutex<Option<mpsc::UnboundedSender<ReloadRequest>>>,
+}
+
+impl ServerControl {
+    pub(crate) fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Starts handing reload requests to the returned receiver, instead of any earlier one.
+    pub(crate) fn reload_requests(&self) -> mpsc::UnboundedReceiver<ReloadRequest> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        *self.reloads.lock().unwrap_or_else(|e| e.into_inner()) = Some(sender);
+        receiver
+    }
+}
+
+/// What the admin routes act on.
+#[derive(Clone)]
+pub(crate) struct AdminState {
+    pub(crate) token: Arc<str>,
+    pub(crate) control: Arc<ServerControl>,
+    pub(crate) cache: Option<Arc<ArchiveCache>>,
+    pub(crate) metrics: Arc<Metrics>,
+}
+
+impl AdminState {
+    fn status(&self) -> AdminStatus {
+        AdminStatus {
+            draining: self.control.is_draining(),
+            requests_in_flight: self.transfers().len(),
+            cache: self.cache.as_deref().map(cache_usage),
+        }
+    }
+
+    fn transfers(&self) -> Vec<Transfer> {
+        self.metrics.requests_in_flight().into_iter()
+            .filter(|request| !is_admin_path(&request.path))
+            .map(|request| Transfer {
+                id: request.id,
+                client: request.client.clone(),
+                method: request.method.clone(),
+                path: request.path.clone(),
+                bytes_sent: request.sent.load(Ordering::Relaxed),
+                seconds: request.started.elapsed().as_secs_f64(),
+            })
+            .collect()
+    }
+}
+
+/// The `/admin` routes, behind the admin token.
+pub(crate) fn routes(state: AdminState) -> Router {
+    Router::new()
+        .route(ADMIN_PREFIX, get(status_handler))
+        .route(&format!("{}/cache", ADMIN_PREFIX), get(cache_handler).delete(purge_handler))
+        .route(&format!("{}/transfers", ADMIN_PREFIX), get(transfers_handler))
+        .route(&format!("{}/reload", ADMIN_PREFIX), axum::routing::post(reload_handler))
+        .route(&format!("{}/drain", ADMIN_PREFIX), axum::routing::post(drain_handler).delete(resume_handler))
+        .route_layer(axum::middleware::from_fn_with_state(state.clone(), require_admin_token))
+        .with_state(state)
+}
+
+fn is_admin_path(path: &str) -> bool {
+    crate::grpc::is_admin_call(path) || path == ADMIN_PREFIX || path.starts_with(&format!("{}/", ADMIN_PREFIX)) || path.starts_with(&format!("{}?", ADMIN_PREFIX))
+}
+
+/// Middleware letting through only requests that carry the admin token.
+async fn require_admin_token(
+    State(state): State<AdminState>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let token = request.headers().get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match token {
+        Some(token) if crate::server::constant_time_eq(token.trim().as_bytes(), state.token.as_bytes()) => next.run(request).await,
+        _ => {
+            debug!("Responding with 401 Unauthorized: {} {}", request.method(), request.uri().path());
+            (StatusCode::UNAUTHORIZED, [(axum::http::header::WWW_AUTHENTICATE, "Bearer")], "The admin API needs the admin token").into_response()
+        }
+    }
+}
+
+/// Middleware refusing new requests with 503 while the server drains. The admin API and the
+/// metrics stay reachable, to follow the drain and to end it.
+pub(crate) async fn refuse_while_draining(
+    State(control): State<Arc<ServerControl>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let path = request.uri().path();
+    if !control.is_draining() || is_admin_path(path) || path == "/metrics" {
+        return next.run(request).await;
+    }
+    debug!("Responding with 503 to {} {}: draining", request.method(), path);
+    let mut response = (StatusCode::SERVICE_UNAVAILABLE, "The server is draining for maintenance, try again later").into_response();
+    response.headers_mut().insert(axum::http::header::RETRY_AFTER, HeaderValue::from(DRAIN_RETRY_AFTER.as_secs()));
+    response
+}
+
+fn cache_usage(cache: &ArchiveCache) -> CacheUsage {
+    let (bytes, max_bytes) = cache.usage();
+    CacheUsage { archives: cache.entries().len(), bytes, max_bytes }
+}
+
+fn no_cache() -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, "The server has no archive cache (start it with --cache-dir)".to_string())
+}
+
+async fn status_handler(State(state): State<AdminState>) -> Json<AdminStatus> {
+    Json(state.status())
+}
+
+async fn cache_handler(State(state): State<AdminState>) -> Result<Json<CacheReport>, (StatusCode, String)> {
+    let cache = state.cache.as_deref().ok_or_else(no_cache)?;
+    let archives = cache.entries().into_iter().map(|(etag, size)| CachedArchive { etag, size }).collect();
+    Ok(Json(CacheReport { usage: cache_usage(cache), archives }))
+}
+
+async fn purge_handler(
+    State(state): State<AdminState>,
+    Query(params): Query<PurgeParams>,
+) -> Result<Json<CachePurged>, (StatusCode, String)> {
+    let cache = state.cache.clone().ok_or_else(no_cache)?;
+    let etag = params.etag.clone();
+    let (removed, bytes) = tokio::task::spawn_blocking(move || cache.purge(etag.as_deref())).await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Purge task failed".to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Could not purge the cache: {}", e)))?;
+    if removed == 0 {
+        if let Some(etag) = params.etag {
+            return Err((StatusCode::NOT_FOUND, format!("No cached archive has the ETag {}", etag)));
+        }
+    }
+    info!("Purged {} archive(s) ({} bytes) from the cache", removed, bytes);
+    Ok(Json(CachePurged { removed, bytes }))
+}
+
+async fn transfers_handler(State(state): State<AdminState>) -> Json<Vec<Transfer>> {
+    Json(state.transfers())
+}
+
+async fn reload_handler(State(state): State<AdminState>) -> Result<Json<AdminStatus>, (StatusCode, String)> {
+    let reloads = state.control.reloads.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    let Some(reloads) = reloads else {
+        return Err((StatusCode::NOT_IMPLEMENTED, "This server does not take reloads over the admin API".to_string()));
+    };
+    let (sender, outcome) = oneshot::channel();
+    if reloads.send(ReloadRequest(sender)).is_err() {
+        return Err((StatusCode::SERVICE_UNAVAILABLE, "Reloading is no longer possible".to_string()));
+    }
+    match outcome.await {
+        Ok(Ok(())) => Ok(Json(state.status())),
+        Ok(Err(e)) => Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Could not reload, keeping the running configuration: {}", e))),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "The reload was abandoned".to_string())),
+    }
+}
+
+async fn drain_handler(State(state): State<AdminState>) -> Json<AdminStatus> {
+    if !state.control.draining.swap(true, Ordering::Relaxed) {
+        warn!("Draining for maintenance: new requests are refused until DELETE /admin/drain");
+    }
+    Json(state.status())
+}
+
+async fn resume_handler(State(state): State<AdminState>) -> Json<AdminStatus> {
+    if state.control.draining.swap(false, Ordering::Relaxed) {
+        info!("Drain ended, accepting requests again");
+    }
+    Json(state.status())
+}
+
+// --- CLIENT ---
+
+/// Drives the admin API of a running server, for the `admin` command. The connection's
+/// `--token` is the admin token.
+pub struct AdminClient {
+    connection: Connection,
+}
+
+impl AdminClient {
+    pub fn new(connection: Connection) -> Self {
+        AdminClient { connection }
+    }
+
+    async fn call<T: serde::de::DeserializeOwned>(&self, method: reqwest::Method, path: &str, query: &[(&str, &str)]) -> ClientResult<T> {
+        let response = self.connection.client()?
+            .request(method, format!("{}{}{}", self.connection.base_url(), ADMIN_PREFIX, path))
+            .query(query)
+            .send().await?;
+        match response.status() {
+            status if status.is_success() => Ok(response.json().await?),
+            reqwest::StatusCode::UNAUTHORIZED => Err("The server refused the admin token (pass it with --token)".into()),
+            reqwest::StatusCode::NOT_FOUND if path.is_empty() => Err("The server has no admin API (start it with --admin-token)".into()),
+            _ => Err(server_error(response).await),
+        }
+    }
+
+    pub async fn status(&self) -> ClientResult<AdminStatus> {
+        self.call(reqwest::Method::GET, "", &[]).await
+    }
+
+    pub async fn cache(&self) -> ClientResult<CacheReport> {
+        self.call(reqwest::Method::GET, "/cache", &[]).await
+    }
+
+    /// Removes the cached archive with `etag`, or every cached archive.
+    pub async fn purge(&self, etag: Option<&str>) -> ClientResult<CachePurged> {
+        let query: Vec<_> = etag.map(|etag| ("etag", etag)).into_iter().collect();
+        self.call(reqwest::Method::DELETE, "/cache", &query).await
+    }
+
+    pub async fn transfers(&self) -> ClientResult<Vec<Transfer>> {
+        self.call(reqwest::Method::GET, "/transfers", &[]).await
+    }
+
+    /// Has the server apply its configuration again, as on SIGHUP.
+    pub async fn reload(&self) -> ClientResult<AdminStatus> {
+        self.call(reqwest::Method::POST, "/reload", &[]).await
+    }
+
+    /// Starts draining the server, or with `drain` false ends it.
+    pub async fn drain(&self, drain: bool) -> ClientResult<AdminStatus> {
+        let method = match drain {
+            true => reqwest::Method::POST,
+            false => reqwest::Method::DELETE,
+        };
+        self.call(method, "/drain", &[]).await
+    }
+}
@@ -0,0 +1,2113 @@
+//! Building, measuring and verifying archives.
+//!
+//! [`ArchiveBuilder`] is the entry point for embedding: it collects files and options and
+//! produces tarballs (gzip, zstd, xz, bzip2 or a codec added with [`crate::compress::register`])
+//! or zip files, in memory or into any writer.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tar::{Archive, Builder};
+
+use crate::compress::Encoder;
+use crate::paths::{long_path, slash_path, unportable_component};
+use crate::storage::{Storage, StorageMetadata};
+
+pub use crate::compress::Codec;
+
+/// File name (without extension) the server suggests for archives it has no better name for.
+pub const DEFAULT_OUTPUT_STEM: &str = "archive";
+
+/// The file name for an archive of the `requested` paths: `chosen` when given, otherwise the
+/// base name of the only path requested (`docs/reports` gives `reports.tar.gz`), and
+/// `archive.<extension>` for several paths, a glob pattern or the whole root. Only the last
+/// component of `chosen` is used, quotes and control characters are dropped, and an
+/// `extension` it already ends in is not added twice.
+///
+/// ```
+/// use rust_file_archive_server::archive::archive_file_name;
+///
+/// assert_eq!(archive_file_name(&["docs/reports/".to_string()], None, "tar.gz"), "reports.tar.gz");
+/// assert_eq!(archive_file_name(&["a".to_string(), "b".to_string()], None, "zip"), "archive.zip");
+/// assert_eq!(archive_file_name(&[], Some("backup.tar.zst"), "tar.zst"), "backup.tar.zst");
+/// ```
+pub fn archive_file_name(requested: &[String], chosen: Option<&str>, extension: &str) -> String {
+    let name = match (chosen, requested) {
+        (Some(chosen), _) => chosen,
+        (None, [only]) if !only.contains(['*', '?', '[']) => only,
+        _ => "",
+    };
+    let name = name.trim_end_matches(['/', '\\']).rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name.chars().filter(|c| !c.is_control() && *c != '"').collect();
+    let stem = name.strip_suffix(&format!(".{}", extension)).unwrap_or(&name).trim();
+    match stem {
+        "" | "." | ".." => format!("{}.{}", DEFAULT_OUTPUT_STEM, extension),
+        stem => format!("{}.{}", stem, extension),
+    }
+}
+
+/// A single source file as seen by a dry run.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileInfo {
+    pub name: String,
+    pub path: String,
+    pub size: u64,
+}
+
+/// What an archive request would contain, computed without building the archive.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DryRunReport {
+    pub files: Vec<FileInfo>,
+    pub total_size: u64,
+    pub estimated_compressed_size: u64,
+}
+
+/// One entry of a [`Manifest`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ManifestEntry {
+    /// Path inside the archive, with `/` separators
+    pub path: String,
+    pub kind: EntryKind,
+    /// Size of a file's contents; 0 for directories and links
+    pub size: u64,
+    /// Modification time stored in the archive (seconds since the Unix epoch); left out when
+    /// the server normalizes metadata
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
+    /// SHA-256 of a file's contents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Where a preserved symlink points
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+/// Every entry an archive will contain, with per-file checksums, so a request can be checked
+/// before downloading and the extracted files afterwards (see [`check_manifest`]).
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    /// The ETag the archive is served under
+    pub etag: String,
+    pub entries: Vec<ManifestEntry>,
+}
+
+/// A difference between a [`Manifest`] and the files on disk.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ManifestMismatch {
+    pub path: String,
+    pub problem: String,
+}
+
+/// The container formats the server knows how to produce and verify.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum ArchiveFormat {
+    #[default]
+    #[serde(rename = "tar", alias = "tar.gz", alias = "tgz")]
+    #[value(name = "tar", alias = "tar.gz", alias = "tgz")]
+    Tar,
+    #[serde(rename = "zip")]
+    #[value(name = "zip")]
+    Zip,
+}
+
+/// Everything needed to build one archive, and to read it back for verification.
+#[derive(Clone, Copy, Debug)]
+pub struct ArchiveOptions {
+    pub format: ArchiveFormat,
+    pub codec: Codec,
+    /// Always within `codec.level_range()` when built with [`ArchiveOptions::new`]
+    pub level: i32,
+    /// Keep each file's permissions, modification time and owner. When false, entries get
+    /// fixed timestamps, owner 0 and mode 0644 or 0755 (keeping only the executable bit), so
+    /// nothing about the server's accounts or file history is revealed.
+    pub preserve_metadata: bool,
+    /// Record each local file's extended attributes, POSIX ACLs and SELinux labels included,
+    /// as `SCHILY.xattr.*` PAX records (tarballs only).
+    pub xattrs: bool,
+    /// Threads compressing a tarball; see [`Codec::parallel_encoder`]. Any number above one
+    /// gives the same bytes, which can differ from a single thread's. Zip is always built on one.
+    pub threads: usize,
+    /// Latest modification time to store, in seconds since the Unix epoch (`SOURCE_DATE_EPOCH`
+    /// for reproducible builds). Entries keep their own time up to it, even when other metadata
+    /// is normalized, and later times are clamped to it.
+    pub mtime_clamp: Option<u64>,
+}
+
+impl ArchiveOptions {
+    /// Combines a format and codec, rejecting a level the codec cannot use.
+    pub fn new(format: ArchiveFormat, codec: Codec, level: Option<i32>) -> Result<Self, String> {
+        let level = level.unwrap_or(codec.default_level());
+        if !codec.level_range().contains(&level) {
+            let range = codec.level_range();
+            return Err(format!("Invalid {} level {}: expected {}-{}", codec.name(), level, range.start(), range.end()));
+        }
+        Ok(ArchiveOptions { format, codec, level, preserve_metadata: true, xattrs: false, threads: 1, mtime_clamp: None })
+    }
+
+    /// Starts compressing a tarball into `writer` with these options' codec, level and threads.
+    fn encoder<'a>(&self, writer: &'a mut dyn Write) -> io::Result<Box<dyn Encoder + 'a>> {
+        self.codec.parallel_encoder(writer, self.level, self.threads)
+    }
+
+    /// File name extension, without the leading dot.
+    pub fn extension(&self) -> String {
+        match self.format {
+            ArchiveFormat::Tar => match self.codec.extension() {
+                Some(extension) => format!("tar.{}", extension),
+                None => "tar".to_string(),
+            },
+            ArchiveFormat::Zip => "zip".to_string(),
+        }
+    }
+
+    /// MIME type of the archive.
+    pub fn content_type(&self) -> &'static str {
+        match self.format {
+            ArchiveFormat::Tar => self.codec.content_type(),
+            ArchiveFormat::Zip => "application/zip",
+        }
+    }
+
+    /// Whether the archive can be written front to back without seeking (required for --stream).
+    pub fn is_streamable(&self) -> bool {
+        match self.format {
+            ArchiveFormat::Tar => true,
+            ArchiveFormat::Zip => false,
+        }
+    }
+}
+
+// --- ARCHIVING LOGIC ---
+
+/// How symlinks met while collecting files are archived.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SymlinkPolicy {
+    /// Archive what the link points to, as a regular file or directory (portable)
+    #[default]
+    Follow,
+    /// Store the link itself (faithful, but needs an extractor that understands links)
+    Preserve,
+    /// Leave links out of the archive
+    Skip,
+}
+
+/// What an archive entry is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+/// One file, directory or symlink as it will be stored in an archive.
+#[derive(Clone, Debug)]
+pub(crate) struct ArchiveEntry {
+    /// Where the data comes from in `storage`
+    pub source: PathBuf,
+    /// Relative path inside the archive
+    pub name: PathBuf,
+    pub kind: EntryKind,
+    pub storage: Storage,
+}
+
+/// File name of the optional ignore file read from the server root.
+pub const IGNORE_FILE_NAME: &str = ".serverignore";
+
+/// Gitignore-style patterns for paths that are never archived, such as `target/`,
+/// `node_modules/` or `*.tmp`.
+#[derive(Clone, Debug, Default)]
+pub struct ExcludeRules {
+    matcher: Option<ignore::gitignore::Gitignore>,
+}
+
+impl ExcludeRules {
+    /// Compiles `patterns` plus the lines of `ignore_file` (when given and present). Patterns
+    /// containing a slash are anchored at `root`; others match a name at any depth.
+    pub fn new(root: &Path, patterns: &[String], ignore_file: Option<&Path>) -> io::Result<Self> {
+        let invalid = |e: ignore::Error| io::Error::new(io::ErrorKind::InvalidInput, e.to_string());
+
+        let mut builder = ignore::gitignore::GitignoreBuilder::new(root);
+        if let Some(ignore_file) = ignore_file.filter(|path| path.is_file()) {
+            if let Some(e) = builder.add(ignore_file) {
+                return Err(invalid(e));
+            }
+        }
+        for pattern in patterns {
+            builder.add_line(None, pattern).map_err(invalid)?;
+        }
+
+        let matcher = builder.build().map_err(invalid)?;
+        Ok(ExcludeRules { matcher: (!matcher.is_empty()).then_some(matcher) })
+    }
+
+    /// Number of patterns in effect.
+    pub fn len(&self) -> usize {
+        self.matcher.as_ref().map_or(0, |matcher| matcher.num_ignores() as usize)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.matcher.is_none()
+    }
+
+    /// Whether `path`, or a directory above it, is excluded. Paths outside the root are
+    /// matched by name only.
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        let Some(matcher) = &self.matcher else { return false };
+        let relative = match path.strip_prefix(matcher.path()) {
+            Ok(relative) => relative,
+            Err(_) if path.is_relative() => path,
+            Err(_) => match path.file_name() {
+                Some(name) => Path::new(name),
+                None => return false,
+            },
+        };
+        !relative.as_os_str().is_empty() && matcher.matched_path_or_any_parents(relative, is_dir).is_ignore()
+    }
+}
+
+/// How requested paths are expanded into archive entries.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct EntryOptions {
+    /// Store every file under its base name and leave directories out
+    pub flatten: bool,
+    pub symlinks: SymlinkPolicy,
+    /// Paths left out of the archive (and refused when named explicitly)
+    pub exclude: ExcludeRules,
+    /// With `SymlinkPolicy::Follow`, links resolving outside this directory are skipped
+    pub within: Option<PathBuf>,
+    /// Where the requested paths are read from
+    pub storage: Storage,
+    /// Order all entries by archive name, rather than keeping the requested paths in the
+    /// order they were given
+    pub sort: bool,
+}
+
+/// Expands the requested paths into archive entries. A file is stored under its base name; a
+/// directory is stored under its base name with everything below it, in sorted order, so the
+/// structure survives. With `flatten`, every file is stored under its base name instead and
+/// directories themselves are left out. Two different files that would get the same name are
+/// refused rather than silently shadowing each other. With `sort`, the entries end up in name
+/// order, each directory still ahead of its contents.
+pub(crate) fn collect_entries(file_paths: &[PathBuf], options: &EntryOptions) -> io::Result<Vec<ArchiveEntry>> {
+    let mut collector = EntryCollector { options, entries: Vec::new(), ancestors: Vec::new() };
+    for file_path in file_paths {
+        // Paths such as `.` or `logs/..` only have a name once resolved
+        let file_name = match file_path.file_name() {
+            Some(name) => PathBuf::from(name),
+            None => options.storage.canonicalize(file_path)?.file_name().map(PathBuf::from)
+                .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "Invalid file path name"))?,
+        };
+        collector.add(file_path, file_name, true)?;
+    }
+
+    let mut seen = std::collections::HashMap::new();
+    let mut unique = Vec::with_capacity(collector.entries.len());
+    for entry in collector.entries {
+        match seen.get(&entry.name) {
+            None => {
+                seen.insert(entry.name.clone(), entry.source.clone());
+                unique.push(entry);
+            }
+            // The same file or directory requested twice (e.g. `logs` and `logs/a.log` with flatten)
+            Some(source) if *source == entry.source || entry.kind == EntryKind::Dir => {}
+            Some(source) => {
+                let hint = if options.flatten { " (drop flatten to keep the directory structure)" } else { "" };
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!(
+                    "{} and {} would both be stored as {}{}",
+                    source.display(), entry.source.display(), entry.name.display(), hint)));
+            }
+        }
+    }
+    if options.sort {
+        // Paths compare component by component, so `a` sorts before `a/b` and `a/b` before `a.txt`
+        unique.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+    Ok(unique)
+}
+
+struct EntryCollector<'a> {
+    options: &'a EntryOptions,
+    entries: Vec<ArchiveEntry>,
+    /// Resolved directories currently being walked, to notice followed links that loop back
+    ancestors: Vec<PathBuf>,
+}
+
+impl EntryCollector<'_> {
+    /// Adds `source`, stored as `name`. Paths named explicitly must exist and be files or
+    /// directories; anything else found while walking (sockets, dangling links) is passed over.
+    fn add(&mut self, source: &Path, name: PathBuf, explicit: bool) -> io::Result<()> {
+        let not_found = || io::Error::new(io::ErrorKind::NotFound, format!("Source file not found: {}", source.display()));
+        let storage = &self.options.storage;
+        let link_metadata = storage.symlink_metadata(source).map_err(|_| not_found())?;
+
+        let is_dir = storage.metadata(source).is_ok_and(|metadata| metadata.is_dir());
+        if self.options.exclude.is_excluded(source, is_dir) {
+            return match explicit {
+                true => Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                    format!("{} is excluded from archives", source.display()))),
+                false => Ok(()),
+            };
+        }
+
+        if link_metadata.is_symlink() {
+            match self.options.symlinks {
+                SymlinkPolicy::Skip => return Ok(()),
+                SymlinkPolicy::Preserve => {
+                    self.push(source, name, EntryKind::Symlink);
+                    return Ok(());
+                }
+                SymlinkPolicy::Follow => match storage.canonicalize(source) {
+                    Ok(target) if self.options.within.as_ref().is_none_or(|within| target.starts_with(within)) => {}
+                    Ok(_) => return Ok(()),
+                    Err(_) if explicit => return Err(not_found()),
+                    Err(_) => return Ok(()),
+                },
+            }
+        }
+
+        let metadata = storage.metadata(source).map_err(|_| not_found())?;
+        if metadata.is_dir() {
+            self.walk(source, name)
+        } else if metadata.is_file() {
+            self.push(source, name, EntryKind::File);
+            Ok(())
+        } else if explicit {
+            Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("Not a regular file or directory: {}", source.display())))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Adds `dir` (stored as `name`) and everything below it.
+    fn walk(&mut self, dir: &Path, name: PathBuf) -> io::Result<()> {
+        let resolved = self.options.storage.canonicalize(dir)?;
+        if self.ancestors.contains(&resolved) {
+            // A followed link back up the tree; archiving it again would never end
+            return Ok(());
+        }
+
+        let mut children = self.options.storage.read_dir(dir)?;
+        children.sort();
+
+        self.ancestors.push(resolved);
+        self.push(dir, name.clone(), EntryKind::Dir);
+        for child in children {
+            self.add(&dir.join(&child), name.join(child), false)?;
+        }
+        self.ancestors.pop();
+        Ok(())
+    }
+
+    fn push(&mut self, source: &Path, name: PathBuf, kind: EntryKind) {
+        let name = match (self.options.flatten, kind) {
+            (true, EntryKind::Dir) => return,
+            (true, _) => name.file_name().map(PathBuf::from).unwrap_or(name),
+            (false, _) => name,
+        };
+        let storage = self.options.storage.clone();
+        self.entries.push(ArchiveEntry { source: source.to_path_buf(), name, kind, storage });
+    }
+}
+
+/// Archives the entries in the requested format, in memory (Bytes).
+pub(crate) fn create_archive(entries: &[ArchiveEntry], options: &ArchiveOptions) -> io::Result<bytes::Bytes> {
+    create_archive_tracked(entries, options, None)
+}
+
+/// Like [`create_archive`], counting every file in `progress` once it has been added.
+pub(crate) fn create_archive_with_progress(entries: &[ArchiveEntry], options: &ArchiveOptions, progress: &ArchiveProgress) -> io::Result<bytes::Bytes> {
+    create_archive_tracked(entries, options, Some(progress))
+}
+
+fn create_archive_tracked(entries: &[ArchiveEntry], options: &ArchiveOptions, progress: Option<&ArchiveProgress>) -> io::Result<bytes::Bytes> {
+    match options.format {
+        ArchiveFormat::Tar => write_tar_tracked(entries, options, Vec::new(), progress).map(bytes::Bytes::from),
+        ArchiveFormat::Zip => create_zip(entries, options, progress),
+    }
+}
+
+/// Writes a compressed tarball of the entries into `writer`, returning the writer once
+/// the compressed stream is finished.
+pub(crate) fn write_tar<W: Write>(entries: &[ArchiveEntry], options: &ArchiveOptions, writer: W) -> io::Result<W> {
+    write_tar_tracked(entries, options, writer, None)
+}
+
+fn write_tar_tracked<W: Write>(entries: &[ArchiveEntry], options: &ArchiveOptions, mut writer: W, progress: Option<&ArchiveProgress>) -> io::Result<W> {
+    let encoder = options.encoder(&mut writer)?;
+    append_tar_entries(Builder::new(encoder), entries, options, progress)?.finish()?;
+    Ok(writer)
+}
+
+/// Appends every entry to the tarball under its archive name and hands back the (still open) compressor.
+fn append_tar_entries<W: Write>(mut tar: Builder<W>, entries: &[ArchiveEntry], options: &ArchiveOptions, progress: Option<&ArchiveProgress>) -> io::Result<W> {
+    let preserve = options.preserve_metadata;
+    let mode = if preserve { tar::HeaderMode::Complete } else { tar::HeaderMode::Deterministic };
+    tar.mode(mode);
+
+    let mut hard_links = HardLinks::default();
+    for entry in entries {
+        if options.xattrs && entry.storage.is_local() {
+            append_xattrs(&mut tar, entry, &hard_links)?;
+        }
+        append_tar_entry(&mut tar, entry, mode, options, &mut hard_links)?;
+        if let Some(progress) = progress {
+            progress.add(entry)?;
+        }
+    }
+
+    tar.into_inner()
+}
+
+fn append_tar_entry<W: Write>(tar: &mut Builder<W>, entry: &ArchiveEntry, mode: tar::HeaderMode, options: &ArchiveOptions, hard_links: &mut HardLinks) -> io::Result<()> {
+    if entry.storage.is_local() {
+        return append_local_entry(tar, entry, mode, options, hard_links);
+    }
+
+    let metadata = entry_metadata(entry)?;
+    let mut header = tar_header(&metadata, options);
+    match entry.kind {
+        EntryKind::Dir => {
+            header.set_entry_type(tar::EntryType::Directory);
+            tar.append_data(&mut header, &entry.name, io::empty())
+        }
+        EntryKind::File => {
+            header.set_size(metadata.len);
+            tar.append_data(&mut header, &entry.name, entry.storage.open(&entry.source)?)
+        }
+        EntryKind::Symlink => {
+            header.set_entry_type(tar::EntryType::Symlink);
+            tar.append_link(&mut header, &entry.name, entry.storage.read_link(&entry.source)?)
+        }
+    }
+}
+
+/// Appends a local file, directory or symlink, letting tar read its metadata itself. Files
+/// already archived under another name become hard link entries, and files with holes are
+/// stored sparse.
+fn append_local_entry<W: Write>(tar: &mut Builder<W>, entry: &ArchiveEntry, mode: tar::HeaderMode, options: &ArchiveOptions, hard_links: &mut HardLinks) -> io::Result<()> {
+    // tar's header modes only know a file's own timestamp and a fixed one
+    let local_header = |metadata: &std::fs::Metadata| {
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata_in_mode(metadata, mode);
+        if let (Some(_), Ok(modified)) = (options.mtime_clamp, metadata.modified()) {
+            header.set_mtime(stored_mtime(modified, options).unwrap_or(DETERMINISTIC_MTIME));
+        }
+        header
+    };
+    match entry.kind {
+        EntryKind::File => {
+            let metadata = std::fs::metadata(&entry.source)?;
+            let mut header = local_header(&metadata);
+            if let Some(first) = hard_links.first_name(&metadata, &entry.name) {
+                header.set_entry_type(tar::EntryType::Link);
+                header.set_size(0);
+                return tar.append_link(&mut header, &entry.name, first);
+            }
+            let file = File::open(&entry.source)?;
+            match sparse_regions(&file, &metadata)? {
+                Some(regions) => append_sparse(tar, &mut header, &entry.name, file, regions, metadata.len()),
+                None if options.mtime_clamp.is_some() => tar.append_data(&mut header, &entry.name, file),
+                None => tar.append_path_with_name(&entry.source, &entry.name),
+            }
+        }
+        // Directories are added on their own; their contents follow as separate entries
+        EntryKind::Dir if options.mtime_clamp.is_some() => {
+            let mut header = local_header(&std::fs::metadata(&entry.source)?);
+            tar.append_data(&mut header, &entry.name, io::empty())
+        }
+        EntryKind::Dir => tar.append_path_with_name(&entry.source, &entry.name),
+        EntryKind::Symlink => {
+            let mut header = local_header(&std::fs::symlink_metadata(&entry.source)?);
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            tar.append_link(&mut header, &entry.name, std::fs::read_link(&entry.source)?)
+        }
+    }
+}
+
+// --- HARD LINKS AND SPARSE FILES ---
+// A file with several names is stored once: its first name gets the data and every later one
+// a hard link entry pointing at it, which extraction turns back into a link. A file with
+// holes (a VM image, say) is stored as a GNU sparse entry: the header lists the
+// (offset, length) regions that hold data, the first four in the header itself and the rest
+// in 512-byte extension blocks right after it, and only those regions follow as the entry's
+// data. Readers fill the gaps with zeros, and tar's own extraction leaves them as holes.
+
+/// Archive names of the multiply linked files added so far, by device and inode.
+#[derive(Default)]
+struct HardLinks {
+    #[cfg(unix)]
+    seen: std::collections::HashMap<(u64, u64), PathBuf>,
+}
+
+impl HardLinks {
+    /// The name the file behind `metadata` was first archived under, or `None` (after
+    /// remembering `name`) if this is its first appearance.
+    #[cfg(unix)]
+    fn first_name(&mut self, metadata: &std::fs::Metadata, name: &Path) -> Option<PathBuf> {
+        use std::os::unix::fs::MetadataExt;
+        if metadata.nlink() < 2 {
+            return None;
+        }
+        match self.seen.entry((metadata.dev(), metadata.ino())) {
+            std::collections::hash_map::Entry::Occupied(first) => Some(first.get().clone()),
+            std::collections::hash_map::Entry::Vacant(slot) => {
+                slot.insert(name.to_path_buf());
+                None
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn first_name(&mut self, _metadata: &std::fs::Metadata, _name: &Path) -> Option<PathBuf> {
+        None
+    }
+
+    /// Whether the file behind `metadata` was already archived under another name.
+    #[cfg(unix)]
+    fn contains(&self, metadata: &std::fs::Metadata) -> bool {
+        use std::os::unix::fs::MetadataExt;
+        metadata.nlink() > 1 && self.seen.contains_key(&(metadata.dev(), metadata.ino()))
+    }
+
+    #[cfg(not(unix))]
+    fn contains(&self, _metadata: &std::fs::Metadata) -> bool {
+        false
+    }
+}
+
+/// The (offset, length) regions of `file` that hold data, or `None` if it has no holes or
+/// the platform cannot tell.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn sparse_regions(file: &File, metadata: &std::fs::Metadata) -> io::Result<Option<Vec<(u64, u64)>>> {
+    use std::os::{fd::AsRawFd, unix::fs::MetadataExt};
+    let len = metadata.len();
+    // Fully allocated files are the common case; skip the seeking for them
+    if metadata.blocks().saturating_mul(512) >= len {
+        return Ok(None);
+    }
+
+    let fd = file.as_raw_fd();
+    let seek = |offset: u64, whence| match unsafe { libc::lseek(fd, offset as libc::off_t, whence) } {
+        -1 => Err(io::Error::last_os_error()),
+        position => Ok(position as u64),
+    };
+    let mut regions = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let start = match seek(offset, libc::SEEK_DATA) {
+            Ok(start) => start,
+            // No data after `offset`: the file ends in a hole
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            // The file system cannot report holes
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let end = seek(start, libc::SEEK_HOLE)?.min(len);
+        if end > start {
+            regions.push((start, end - start));
+        }
+        offset = end.max(start + 1);
+    }
+    let stored: u64 = regions.iter().map(|(_, length)| length).sum();
+    Ok((stored < len).then_some(regions))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn sparse_regions(_file: &File, _metadata: &std::fs::Metadata) -> io::Result<Option<Vec<(u64, u64)>>> {
+    Ok(None)
+}
+
+/// Appends `file` as a GNU sparse entry holding only `regions`, `header` already carrying its
+/// name-independent metadata.
+fn append_sparse<W: Write>(tar: &mut Builder<W>, header: &mut tar::Header, name: &Path, file: File, regions: Vec<(u64, u64)>, len: u64) -> io::Result<()> {
+    // The map has to cover the whole file, so a trailing hole ends in an empty region
+    let mut map = regions.clone();
+    if map.last().is_none_or(|(offset, length)| offset + length < len) {
+        map.push((len, 0));
+    }
+    let stored = regions.iter().map(|(_, length)| length).sum();
+
+    header.set_entry_type(tar::EntryType::GNUSparse);
+    header.set_size(stored);
+    let gnu = header.as_gnu_mut().ok_or_else(|| io::Error::other("Sparse entries need a GNU header"))?;
+    gnu.set_real_size(len);
+    for (index, slot) in gnu.sparse.iter_mut().enumerate() {
+        match map.get(index) {
+            Some(&(offset, length)) => {
+                slot.set_offset(offset);
+                slot.set_length(length);
+            }
+            None => *slot = tar::GnuSparseHeader { offset: [0; 12], numbytes: [0; 12] },
+        }
+    }
+    let rest = map.get(gnu.sparse.len()..).unwrap_or_default();
+    gnu.set_is_extended(!rest.is_empty());
+
+    let mut extensions = Vec::new();
+    let groups: Vec<_> = rest.chunks(EXTENSION_REGIONS).collect();
+    for (index, group) in groups.iter().enumerate() {
+        let mut extension = tar::GnuExtSparseHeader::new();
+        for (slot, (offset, length)) in extension.sparse_mut().iter_mut().zip(group.iter()) {
+            slot.set_offset(*offset);
+            slot.set_length(*length);
+        }
+        extension.set_is_extended(index + 1 < groups.len());
+        extensions.extend_from_slice(extension.as_bytes());
+    }
+
+    // tar writes the data right after the header, so the extension blocks go in front of it
+    let data = SparseReader { file, regions: regions.into_iter(), remaining: 0 };
+    tar.append_data(header, name, io::Cursor::new(extensions).chain(data))
+}
+
+/// How many regions each sparse extension block lists.
+const EXTENSION_REGIONS: usize = 21;
+
+/// Reads the data regions of a file back to back.
+struct SparseReader {
+    file: File,
+    regions: std::vec::IntoIter<(u64, u64)>,
+    /// Bytes left in the current region
+    remaining: u64,
+}
+
+impl Read for SparseReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        use std::io::Seek;
+        while self.remaining == 0 {
+            let Some((offset, length)) = self.regions.next() else { return Ok(0) };
+            self.file.seek(io::SeekFrom::Start(offset))?;
+            self.remaining = length;
+        }
+        let wanted = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let read = self.file.read(&mut buf[..wanted])?;
+        if read == 0 && wanted > 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File shrank while it was being archived"));
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// Copies a sparse entry of another tarball, keeping its holes: the data goes into a scratch
+/// file with the zero blocks skipped over, which is then stored like any sparse local file.
+fn copy_sparse_entry<W: Write, R: Read>(tar: &mut Builder<W>, header: &mut tar::Header, name: &Path, entry: &mut tar::Entry<R>) -> io::Result<()> {
+    use std::io::Seek;
+    let scratch = std::env::temp_dir().join(format!(".sparse-copy-{}", std::process::id()));
+    let result = (|| {
+        let mut file = File::options().read(true).write(true).create(true).truncate(true).open(&scratch)?;
+        let len = entry.size();
+        let mut block = Vec::with_capacity(SPARSE_BLOCK_SIZE);
+        loop {
+            block.clear();
+            if (&mut *entry).take(SPARSE_BLOCK_SIZE as u64).read_to_end(&mut block)? == 0 {
+                break;
+            }
+            match block.iter().all(|&byte| byte == 0) {
+                true => file.seek(io::SeekFrom::Current(block.len() as i64)).map(drop)?,
+                false => file.write_all(&block)?,
+            }
+        }
+        file.set_len(len)?;
+        let metadata = file.metadata()?;
+        match sparse_regions(&file, &metadata)? {
+            Some(regions) => append_sparse(tar, header, name, file, regions, len),
+            None => {
+                file.rewind()?;
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_size(len);
+                tar.append_data(header, name, file)
+            }
+        }
+    })();
+    let _ = std::fs::remove_file(&scratch);
+    result
+}
+
+/// Granularity at which copied sparse entries are checked for holes.
+const SPARSE_BLOCK_SIZE: usize = 4096;
+
+// --- EXTENDED ATTRIBUTES ---
+// With `ArchiveOptions::xattrs`, every local entry is preceded by a PAX extended header with
+// one `SCHILY.xattr.<name>` record per attribute, the convention GNU tar and bsdtar use. On
+// Linux, POSIX ACLs are the `system.posix_acl_access` and `system.posix_acl_default`
+// attributes and SELinux labels `security.selinux`, so they travel the same way. Extraction
+// with `ExtractOptions::xattrs` sets the attributes again once the entry is written.
+
+/// PAX record key prefix for an extended attribute.
+const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+/// Writes the PAX header holding `entry`'s extended attributes, if it has any. Hard links
+/// share their attributes with the file they name, so they get none of their own.
+fn append_xattrs<W: Write>(tar: &mut Builder<W>, entry: &ArchiveEntry, hard_links: &HardLinks) -> io::Result<()> {
+    if entry.kind == EntryKind::File && hard_links.contains(&std::fs::metadata(&entry.source)?) {
+        return Ok(());
+    }
+    append_xattr_header(tar, &local_xattrs(&entry.source, entry.kind != EntryKind::Symlink)?)
+}
+
+/// Writes a PAX header recording `attributes` for the entry that follows; nothing if there
+/// are none.
+fn append_xattr_header<W: Write>(tar: &mut Builder<W>, attributes: &[(String, Vec<u8>)]) -> io::Result<()> {
+    let keys: Vec<_> = attributes.iter().map(|(name, _)| format!("{}{}", PAX_XATTR_PREFIX, name)).collect();
+    tar.append_pax_extensions(keys.iter().map(String::as_str).zip(attributes.iter().map(|(_, value)| value.as_slice())))
+}
+
+/// The extended attributes of `path` (of what it points to with `follow`), by name. File
+/// systems without attribute support have none; names that are not UTF-8 cannot be PAX
+/// keys and are left out.
+#[cfg(unix)]
+fn local_xattrs(path: &Path, follow: bool) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let names = match follow {
+        true => xattr::list_deref(path),
+        false => xattr::list(path),
+    };
+    let names = match names {
+        Ok(names) => names,
+        Err(e) if e.kind() == io::ErrorKind::Unsupported || e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut attributes = Vec::new();
+    for name in names {
+        let value = match follow {
+            true => xattr::get_deref(path, &name)?,
+            false => xattr::get(path, &name)?,
+        };
+        if let (Some(name), Some(value)) = (name.to_str(), value) {
+            attributes.push((name.to_string(), value));
+        }
+    }
+    attributes.sort();
+    Ok(attributes)
+}
+
+#[cfg(not(unix))]
+fn local_xattrs(_path: &Path, _follow: bool) -> io::Result<Vec<(String, Vec<u8>)>> {
+    Ok(Vec::new())
+}
+
+/// The extended attributes recorded for a tar entry.
+fn entry_xattrs<R: Read>(entry: &mut tar::Entry<R>) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let Some(extensions) = entry.pax_extensions()? else { return Ok(Vec::new()) };
+    let mut attributes = Vec::new();
+    for extension in extensions {
+        let extension = extension?;
+        if let Some(name) = extension.key().ok().and_then(|key| key.strip_prefix(PAX_XATTR_PREFIX)) {
+            attributes.push((name.to_string(), extension.value_bytes().to_vec()));
+        }
+    }
+    Ok(attributes)
+}
+
+/// Sets extended attributes on an extracted file, without following it if it is a symlink.
+#[cfg(unix)]
+fn restore_xattrs(path: &Path, attributes: &[(String, Vec<u8>)]) -> io::Result<()> {
+    for (name, value) in attributes {
+        xattr::set(path, name, value).map_err(|e| io::Error::new(e.kind(),
+            format!("Could not set extended attribute {} on {}: {}", name, path.display(), e)))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn restore_xattrs(_path: &Path, _attributes: &[(String, Vec<u8>)]) -> io::Result<()> {
+    Ok(())
+}
+
+/// The timestamp tar::HeaderMode::Deterministic stores.
+const DETERMINISTIC_MTIME: u64 = 1153704088;
+
+/// The modification time to store for an entry modified at `modified`: its own (clamped to
+/// [`ArchiveOptions::mtime_clamp`]), or `None` where normalized metadata has a fixed one.
+fn stored_mtime(modified: std::time::SystemTime, options: &ArchiveOptions) -> Option<u64> {
+    let mtime = modified.duration_since(std::time::UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+    match options.mtime_clamp {
+        Some(clamp) => Some(mtime.min(clamp)),
+        None => options.preserve_metadata.then_some(mtime),
+    }
+}
+
+/// A header carrying the metadata tar's `HeaderMode` would store for a local file, for
+/// entries from other storage backends.
+fn tar_header(metadata: &StorageMetadata, options: &ArchiveOptions) -> tar::Header {
+    let preserve = options.preserve_metadata;
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(0);
+    header.set_mode(entry_permissions(metadata, preserve));
+    header.set_mtime(stored_mtime(metadata.modified, options).unwrap_or(DETERMINISTIC_MTIME));
+    if preserve {
+        header.set_uid(metadata.uid);
+        header.set_gid(metadata.gid);
+    } else {
+        header.set_uid(0);
+        header.set_gid(0);
+    }
+    header
+}
+
+/// Unix permission bits to store for an entry: its own, or 0644/0755 when normalizing.
+fn entry_permissions(metadata: &StorageMetadata, preserve: bool) -> u32 {
+    match preserve {
+        true => metadata.mode,
+        false if metadata.is_dir() || metadata.mode & 0o111 != 0 => 0o755,
+        false => 0o644,
+    }
+}
+
+/// Archives the entries into a zip file in memory (Bytes), deflated at `options.level`
+/// unless the codec is `Store`.
+fn create_zip(entries: &[ArchiveEntry], options: &ArchiveOptions, progress: Option<&ArchiveProgress>) -> io::Result<bytes::Bytes> {
+    let preserve = options.preserve_metadata;
+    let (method, level) = match options.codec == Codec::Store {
+        true => (zip::CompressionMethod::Stored, None),
+        false => (zip::CompressionMethod::Deflated, Some(options.level.min(9) as i64)),
+    };
+    let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+
+    for entry in entries {
+        let metadata = entry_metadata(entry)?;
+        // An explicit timestamp keeps archives byte-identical; the writer would use the current time
+        let mut modified = zip::DateTime::default();
+        if let Some(mtime) = stored_mtime(metadata.modified, options) {
+            // Zip timestamps cover 1980-2107 at two-second resolution; others keep the default
+            modified = time::OffsetDateTime::from_unix_timestamp(mtime as i64).ok()
+                .and_then(|mtime| zip::DateTime::try_from(mtime).ok())
+                .unwrap_or(modified);
+        }
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(method)
+            .compression_level(level)
+            .last_modified_time(modified);
+
+        match entry.kind {
+            EntryKind::Dir => {
+                let options = options.unix_permissions(entry_permissions(&metadata, preserve));
+                zip.add_directory(slash_path(&entry.name), options)?;
+                continue;
+            }
+            EntryKind::Symlink => {
+                let target = entry.storage.read_link(&entry.source)?;
+                zip.add_symlink(slash_path(&entry.name), slash_path(&target), options)?;
+                continue;
+            }
+            EntryKind::File => {}
+        }
+
+        let mut file = entry.storage.open(&entry.source)?;
+        let options = options
+            .unix_permissions(entry_permissions(&metadata, preserve))
+            // Entries over 4 GiB need the zip64 extension
+            .large_file(metadata.len >= u32::MAX as u64);
+
+        zip.start_file(slash_path(&entry.name), options)?;
+        io::copy(&mut file, &mut zip)?;
+        if let Some(progress) = progress {
+            progress.file_added(metadata.len);
+        }
+    }
+
+    let cursor = zip.finish()?;
+    Ok(bytes::Bytes::from(cursor.into_inner()))
+}
+
+/// Derives a strong ETag for the archive a request would produce from its inputs: the archive
+/// options plus each entry's path, name, size and modification time. Archiving is deterministic, so
+/// the same inputs yield byte-identical archives and the tag is known before anything is built.
+pub(crate) fn archive_etag(entries: &[ArchiveEntry], options: &ArchiveOptions) -> io::Result<String> {
+    use sha2::{Digest, Sha256};
+
+    let mut hasher = Sha256::new();
+    // A different build may compress differently, so it gets different tags
+    hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+    hasher.update(format!("{:?}/{}/{}/{}/{}/{}/{:?}", options.format, options.codec, options.level, options.preserve_metadata, options.xattrs, options.threads > 1, options.mtime_clamp).as_bytes());
+
+    for entry in entries {
+        let metadata = entry_metadata(entry)?;
+        let mtime = metadata.modified
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|duration| duration.as_nanos())
+            .unwrap_or(0);
+
+        hasher.update([0]);
+        hasher.update(entry.source.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        hasher.update(entry.name.to_string_lossy().as_bytes());
+        if entry.kind == EntryKind::Symlink {
+            hasher.update(entry.storage.read_link(&entry.source)?.to_string_lossy().as_bytes());
+        }
+        hasher.update(metadata.len.to_le_bytes());
+        hasher.update(mtime.to_le_bytes());
+        // chmod and chown leave the modification time alone too
+        hasher.update(entry_permissions(&metadata, options.preserve_metadata).to_le_bytes());
+        if options.preserve_metadata {
+            hasher.update(metadata.uid.to_le_bytes());
+            hasher.update(metadata.gid.to_le_bytes());
+        }
+        // Changing an attribute leaves the modification time alone
+        if options.xattrs && entry.storage.is_local() {
+            for (name, value) in local_xattrs(&entry.source, entry.kind != EntryKind::Symlink)? {
+                hasher.update(name.as_bytes());
+                hasher.update([0]);
+                hasher.update(&value);
+            }
+        }
+        // Object stores can replace an object within the same second
+        if let Some(version) = &metadata.version {
+            hasher.update(version.as_bytes());
+        }
+    }
+
+    Ok(format!("\"{}\"", hex_digest(&hasher.finalize()[..16])))
+}
+
+/// Metadata of the entry itself: the link for preserved symlinks, the target otherwise.
+fn entry_metadata(entry: &ArchiveEntry) -> io::Result<StorageMetadata> {
+    match entry.kind {
+        EntryKind::Symlink => entry.storage.symlink_metadata(&entry.source),
+        EntryKind::File | EntryKind::Dir => entry.storage.metadata(&entry.source),
+    }
+}
+
+/// The newest modification time among the archived entries, sent as Last-Modified.
+pub(crate) fn archive_last_modified(entries: &[ArchiveEntry]) -> io::Result<std::time::SystemTime> {
+    let mut newest = std::time::UNIX_EPOCH;
+    for entry in entries {
+        newest = newest.max(entry_metadata(entry)?.modified);
+    }
+    Ok(newest)
+}
+
+/// How far an archive build has got, as reported by `/progress/{job_id}`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BuildProgress {
+    pub files_added: u64,
+    pub files_total: u64,
+    /// Bytes of file contents archived so far
+    pub bytes_processed: u64,
+    pub bytes_total: u64,
+}
+
+/// Counters a build advances after every entry, readable from other threads while it runs.
+#[derive(Debug, Default)]
+pub(crate) struct ArchiveProgress {
+    files_added: AtomicU64,
+    files_total: AtomicU64,
+    bytes_processed: AtomicU64,
+    bytes_total: AtomicU64,
+}
+
+impl ArchiveProgress {
+    /// Records how many files and bytes a build of `entries` will go through.
+    pub(crate) fn set_totals(&self, entries: &[ArchiveEntry]) -> io::Result<()> {
+        let (mut files, mut bytes) = (0, 0);
+        for entry in entries.iter().filter(|entry| entry.kind == EntryKind::File) {
+            files += 1;
+            bytes += entry.storage.metadata(&entry.source)?.len;
+        }
+        self.files_total.store(files, Ordering::Relaxed);
+        self.bytes_total.store(bytes, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Marks the whole build as done at once, e.g. when a cached copy is used.
+    pub(crate) fn complete(&self) {
+        self.files_added.store(self.files_total.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.bytes_processed.store(self.bytes_total.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    /// Counts `entry` once it is in the archive; only files count.
+    fn add(&self, entry: &ArchiveEntry) -> io::Result<()> {
+        if entry.kind == EntryKind::File {
+            self.file_added(entry.storage.metadata(&entry.source)?.len);
+        }
+        Ok(())
+    }
+
+    fn file_added(&self, len: u64) {
+        self.files_added.fetch_add(1, Ordering::Relaxed);
+        self.bytes_processed.fetch_add(len, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> BuildProgress {
+        BuildProgress {
+            files_added: self.files_added.load(Ordering::Relaxed),
+            files_total: self.files_total.load(Ordering::Relaxed),
+            bytes_processed: self.bytes_processed.load(Ordering::Relaxed),
+            bytes_total: self.bytes_total.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Size and SHA-256 of an archive, as served by `/checksum`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ArchiveChecksum {
+    /// Lowercase hex SHA-256 of the archive bytes
+    pub sha256: String,
+    pub size: u64,
+}
+
+impl ArchiveChecksum {
+    /// Checksums an archive that is already in memory.
+    pub fn of(data: &[u8]) -> Self {
+        use sha2::{Digest, Sha256};
+        ArchiveChecksum { sha256: hex_digest(&Sha256::digest(data)), size: data.len() as u64 }
+    }
+}
+
+/// Lowercase hex of a digest.
+pub(crate) fn hex_digest(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The size of a file and the SHA-256 of its contents as lowercase hex.
+pub fn hash_file(path: &Path) -> io::Result<(u64, String)> {
+    hash_reader(File::open(path)?)
+}
+
+/// The combined size and SHA-256 of several files read one after another, as if joined.
+pub(crate) fn hash_files(paths: &[PathBuf]) -> io::Result<(u64, String)> {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    for path in paths {
+        size += io::copy(&mut File::open(path)?, &mut hasher)?;
+    }
+    Ok((size, hex_digest(&hasher.finalize())))
+}
+
+/// The number of bytes `reader` yields and their SHA-256 as lowercase hex.
+fn hash_reader(mut reader: impl Read) -> io::Result<(u64, String)> {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut reader, &mut hasher)?;
+    Ok((size, hex_digest(&hasher.finalize())))
+}
+
+/// Hashes and counts the bytes written to it and throws them away.
+#[derive(Default)]
+struct DigestWriter {
+    hasher: sha2::Sha256,
+    count: u64,
+}
+
+impl Write for DigestWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        use sha2::Digest;
+        self.hasher.update(buf);
+        self.count += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Measures the size and checksum the archive would have without keeping it. Tarballs are
+/// compressed into a digest; zip needs a seekable writer, so it is built in memory and dropped.
+pub(crate) fn measure_archive(entries: &[ArchiveEntry], options: &ArchiveOptions) -> io::Result<ArchiveChecksum> {
+    use sha2::Digest;
+
+    match options.format {
+        ArchiveFormat::Tar => {
+            let writer = write_tar(entries, options, DigestWriter::default())?;
+            Ok(ArchiveChecksum { sha256: hex_digest(&writer.hasher.finalize()), size: writer.count })
+        }
+        ArchiveFormat::Zip => create_zip(entries, options, None).map(|data| ArchiveChecksum::of(&data)),
+    }
+}
+
+/// Bytes of each file that are sample-compressed to estimate the archive size.
+const DRY_RUN_SAMPLE_SIZE: u64 = 64 * 1024;
+
+/// Walks the same entries as `create_archive`, checking that every file is readable, and
+/// estimates the (gzip) compressed size without producing an archive.
+pub(crate) fn dry_run_archive(entries: &[ArchiveEntry]) -> io::Result<DryRunReport> {
+    let mut files = Vec::with_capacity(entries.len());
+    let mut total_size = 0u64;
+    let mut estimated_compressed_size = 0u64;
+
+    for entry in entries.iter().filter(|entry| entry.kind == EntryKind::File) {
+        let size = entry.storage.metadata(&entry.source)?.len;
+
+        // Compress a sample from the start of the file and extrapolate the ratio; reading it
+        // doubles as the permission check
+        let sample = entry.storage.read_range(&entry.source, 0, DRY_RUN_SAMPLE_SIZE.min(size))?;
+        if !sample.is_empty() {
+            let mut compressed = Vec::new();
+            let mut encoder = Codec::Gzip.encoder(&mut compressed, Codec::Gzip.default_level())?;
+            encoder.write_all(&sample)?;
+            encoder.finish()?;
+            let compressed_sample = compressed.len() as u64;
+            estimated_compressed_size += size * compressed_sample / sample.len() as u64;
+        }
+
+        total_size += size;
+        files.push(FileInfo {
+            name: slash_path(&entry.name),
+            path: entry.source.display().to_string(),
+            size,
+        });
+    }
+
+    Ok(DryRunReport { files, total_size, estimated_compressed_size })
+}
+
+/// Describes the entries as a manifest, hashing every file. Modification times are included
+/// when `options` store them, as the archive does.
+pub(crate) fn manifest_entries(entries: &[ArchiveEntry], options: &ArchiveOptions) -> io::Result<Vec<ManifestEntry>> {
+    let mut manifest = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let metadata = entry_metadata(entry)?;
+
+        let (mut size, mut sha256, mut target) = (0, None, None);
+        match entry.kind {
+            EntryKind::File => {
+                let (file_size, digest) = hash_reader(entry.storage.open(&entry.source)?)?;
+                (size, sha256) = (file_size, Some(digest));
+            }
+            EntryKind::Symlink => target = Some(slash_path(&entry.storage.read_link(&entry.source)?)),
+            EntryKind::Dir => {}
+        }
+
+        manifest.push(ManifestEntry {
+            path: slash_path(&entry.name),
+            kind: entry.kind,
+            size,
+            mtime: stored_mtime(metadata.modified, options),
+            sha256,
+            target,
+        });
+    }
+    Ok(manifest)
+}
+
+/// Compares the extracted files under `dir` with a manifest: every entry must exist with
+/// the right type, and files must have the listed size and SHA-256. Files in `dir` that are
+/// not in the manifest are ignored.
+pub fn check_manifest(manifest: &Manifest, dir: &Path) -> io::Result<Vec<ManifestMismatch>> {
+    let mut mismatches = Vec::new();
+    for entry in &manifest.entries {
+        let mismatch = |problem: String| ManifestMismatch { path: entry.path.clone(), problem };
+        let Some(relative) = safe_entry_path(Path::new(&entry.path)) else {
+            mismatches.push(mismatch("unsafe path in manifest".to_string()));
+            continue;
+        };
+        let path = dir.join(relative);
+
+        let metadata = match std::fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                mismatches.push(mismatch("missing".to_string()));
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        let actual_kind = kind_of(&metadata);
+        if actual_kind != entry.kind {
+            mismatches.push(mismatch(format!("expected a {:?}, found a {:?}", entry.kind, actual_kind).to_lowercase()));
+            continue;
+        }
+
+        if entry.kind == EntryKind::File {
+            if metadata.len() != entry.size {
+                mismatches.push(mismatch(format!("size is {} bytes, expected {}", metadata.len(), entry.size)));
+                continue;
+            }
+            if let Some(expected) = &entry.sha256 {
+                if hash_file(&path)?.1 != *expected {
+                    mismatches.push(mismatch("contents differ (SHA-256 mismatch)".to_string()));
+                }
+            }
+        }
+    }
+    Ok(mismatches)
+}
+
+/// Describes what is already under `dir` in the same terms as a server manifest, with paths
+/// relative to `dir`, so a server can tell which of its entries a client still needs.
+/// Symlinks are listed, not followed.
+pub fn local_manifest(dir: &Path) -> io::Result<Vec<ManifestEntry>> {
+    let mut manifest = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative_dir) = pending.pop() {
+        for dir_entry in std::fs::read_dir(dir.join(&relative_dir))? {
+            let dir_entry = dir_entry?;
+            let relative = relative_dir.join(dir_entry.file_name());
+            let metadata = dir_entry.path().symlink_metadata()?;
+            let mtime = metadata.modified().ok()
+                .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs());
+
+            let kind = kind_of(&metadata);
+            let (mut size, mut sha256, mut target) = (0, None, None);
+            match kind {
+                EntryKind::File => {
+                    let (file_size, digest) = hash_file(&dir_entry.path())?;
+                    (size, sha256) = (file_size, Some(digest));
+                }
+                EntryKind::Symlink => target = Some(slash_path(&std::fs::read_link(dir_entry.path())?)),
+                EntryKind::Dir => pending.push(relative.clone()),
+            }
+            manifest.push(ManifestEntry { path: slash_path(&relative), kind, size, mtime, sha256, target });
+        }
+    }
+    manifest.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(manifest)
+}
+
+/// The entries a client that already has `have` (see [`local_manifest`]) is missing: files
+/// that are new or whose contents differ, symlinks with another target, and directories it
+/// does not have. Files are only hashed when their size matches, so most changes are cheap
+/// to spot.
+pub(crate) fn changed_entries(entries: Vec<ArchiveEntry>, have: &[ManifestEntry]) -> io::Result<Vec<ArchiveEntry>> {
+    let have: std::collections::HashMap<&str, &ManifestEntry> =
+        have.iter().map(|entry| (entry.path.as_str(), entry)).collect();
+
+    let mut changed = Vec::new();
+    for entry in entries {
+        let unchanged = match have.get(slash_path(&entry.name).as_str()) {
+            Some(existing) if existing.kind == entry.kind => match entry.kind {
+                EntryKind::Dir => true,
+                EntryKind::Symlink => existing.target == Some(slash_path(&entry.storage.read_link(&entry.source)?)),
+                EntryKind::File => match &existing.sha256 {
+                    Some(digest) if entry_metadata(&entry)?.len == existing.size =>
+                        *digest == hash_reader(entry.storage.open(&entry.source)?)?.1,
+                    _ => false,
+                },
+            },
+            _ => false,
+        };
+        if !unchanged {
+            changed.push(entry);
+        }
+    }
+    Ok(changed)
+}
+
+/// The kind of entry a file with this (not followed) metadata would be stored as.
+fn kind_of(metadata: &std::fs::Metadata) -> EntryKind {
+    if metadata.is_symlink() {
+        EntryKind::Symlink
+    } else if metadata.is_dir() {
+        EntryKind::Dir
+    } else {
+        EntryKind::File
+    }
+}
+
+/// Wraps `reader` in the decompressor for `codec`.
+fn tar_decoder<'a, R: Read + 'a>(reader: R, codec: Codec) -> io::Result<Box<dyn Read + 'a>> {
+    codec.decoder(Box::new(reader))
+}
+
+/// Reads back a generated archive and confirms every entry can be decoded.
+/// Returns the total uncompressed size of all entries.
+pub fn verify_archive(data: &[u8], options: &ArchiveOptions) -> io::Result<u64> {
+    match options.format {
+        ArchiveFormat::Tar => {
+            let mut archive = Archive::new(tar_decoder(data, options.codec)?);
+            let mut total_size = 0u64;
+
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                total_size += io::copy(&mut entry, &mut io::sink())?;
+            }
+
+            // Drain anything after the tar end marker so the compressor's checksum trailer is checked too
+            io::copy(&mut archive.into_inner(), &mut io::sink())?;
+
+            Ok(total_size)
+        }
+        ArchiveFormat::Zip => {
+            let mut archive = zip::ZipArchive::new(io::Cursor::new(data))?;
+            let mut total_size = 0u64;
+
+            // Reading each entry to the end checks its CRC
+            for index in 0..archive.len() {
+                let mut entry = archive.by_index(index)?;
+                total_size += io::copy(&mut entry, &mut io::sink())?;
+            }
+
+            Ok(total_size)
+        }
+    }
+}
+
+/// Reads the archive file at `archive` (a tarball in any supported compression, or a zip) to
+/// the end, which checks its structure and the compressor's checksums, and describes every
+/// entry in the terms of a server [`Manifest`], with the SHA-256 of each file.
+pub fn read_archive_manifest(archive: &Path) -> io::Result<Vec<ManifestEntry>> {
+    let (format, codec) = sniff_archive(archive)?;
+    let file = File::open(archive)?;
+    let mut entries = Vec::new();
+    match format {
+        ArchiveFormat::Tar => {
+            let mut archive = Archive::new(tar_decoder(io::BufReader::new(file), codec)?);
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                let path = entry.path()?.to_string_lossy().trim_end_matches('/').to_string();
+                let entry_type = entry.header().entry_type();
+                let mtime = entry.header().mtime().ok();
+                let target = entry.link_name()?.map(|target| target.to_string_lossy().into_owned());
+                let kind = match entry_type {
+                    _ if entry_type.is_dir() => EntryKind::Dir,
+                    _ if entry_type.is_symlink() => EntryKind::Symlink,
+                    _ => EntryKind::File,
+                };
+                let (size, sha256, target) = match entry_type {
+                    // A hard link has the contents of the entry it names
+                    tar::EntryType::Link => {
+                        let first = target.as_deref().map(|target| target.trim_end_matches('/'));
+                        let first = entries.iter().rev().find(|earlier: &&ManifestEntry| Some(earlier.path.as_str()) == first)
+                            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+                                format!("{} is a hard link to an entry the archive does not have before it", path)))?;
+                        (first.size, first.sha256.clone(), None)
+                    }
+                    _ if kind == EntryKind::File => hash_reader(&mut entry).map(|(size, digest)| (size, Some(digest), None))?,
+                    _ => (0, None, target),
+                };
+                entries.push(ManifestEntry { path, kind, size, mtime, sha256, target });
+            }
+            // Drain anything after the tar end marker so the compressor's checksum trailer is checked too
+            io::copy(&mut archive.into_inner(), &mut io::sink())?;
+        }
+        ArchiveFormat::Zip => {
+            let mut archive = zip::ZipArchive::new(io::BufReader::new(file))?;
+            for index in 0..archive.len() {
+                let mut entry = archive.by_index(index)?;
+                let path = entry.name().trim_end_matches('/').to_string();
+                let mtime = entry.last_modified()
+                    .and_then(|modified| time::OffsetDateTime::try_from(modified).ok())
+                    .and_then(|modified| u64::try_from(modified.unix_timestamp()).ok());
+                let (kind, size, sha256, target) = if entry.is_dir() {
+                    (EntryKind::Dir, 0, None, None)
+                } else if entry.is_symlink() {
+                    let mut target = String::new();
+                    entry.read_to_string(&mut target)?;
+                    (EntryKind::Symlink, 0, None, Some(target))
+                } else {
+                    // Reading each entry to the end checks its CRC
+                    let (size, digest) = hash_reader(&mut entry)?;
+                    (EntryKind::File, size, Some(digest), None)
+                };
+                entries.push(ManifestEntry { path, kind, size, mtime, sha256, target });
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Compares what an archive holds (see [`read_archive_manifest`]) with the manifest it was
+/// served under: every entry must be there with the right type, size, SHA-256 and link
+/// target, and the archive must hold nothing else.
+pub fn compare_archive_manifest(manifest: &Manifest, entries: &[ManifestEntry]) -> Vec<ManifestMismatch> {
+    let actual: std::collections::HashMap<&str, &ManifestEntry> = entries.iter().map(|entry| (entry.path.as_str(), entry)).collect();
+    let mut mismatches = Vec::new();
+    for expected in &manifest.entries {
+        let mismatch = |problem: String| ManifestMismatch { path: expected.path.clone(), problem };
+        let Some(entry) = actual.get(expected.path.trim_end_matches('/')) else {
+            mismatches.push(mismatch("missing from the archive".to_string()));
+            continue;
+        };
+        if entry.kind != expected.kind {
+            mismatches.push(mismatch(format!("expected a {:?}, found a {:?}", expected.kind, entry.kind).to_lowercase()));
+        } else if entry.size != expected.size {
+            mismatches.push(mismatch(format!("size is {} bytes, expected {}", entry.size, expected.size)));
+        } else if expected.sha256.is_some() && entry.sha256 != expected.sha256 {
+            mismatches.push(mismatch("contents differ (SHA-256 mismatch)".to_string()));
+        } else if expected.target.is_some() && entry.target != expected.target {
+            mismatches.push(mismatch(format!("points to {}, expected {}", entry.target.as_deref().unwrap_or("nothing"), expected.target.as_deref().unwrap_or_default())));
+        }
+    }
+
+    let listed: std::collections::HashSet<&str> = manifest.entries.iter().map(|entry| entry.path.trim_end_matches('/')).collect();
+    for entry in entries.iter().filter(|entry| !listed.contains(entry.path.as_str())) {
+        mismatches.push(ManifestMismatch { path: entry.path.clone(), problem: "not in the manifest".to_string() });
+    }
+    mismatches
+}
+
+
+// --- EXTRACTION ---
+
+/// What to do when an archive entry would replace a file that already exists.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OverwritePolicy {
+    /// Stop with an error naming the file
+    #[default]
+    Error,
+    /// Keep the existing file and carry on
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+}
+
+/// How [`extract_archive_with`] unpacks an archive.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExtractOptions {
+    pub policy: OverwritePolicy,
+    /// Leading directories dropped from every entry name, as `tar --strip-components` does
+    pub strip_components: usize,
+    /// Set the extended attributes (ACLs and SELinux labels among them) a tarball recorded
+    /// for its entries; see [`ArchiveOptions::xattrs`]
+    pub xattrs: bool,
+}
+
+/// What an extraction did.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExtractSummary {
+    /// Files written
+    pub files: usize,
+    /// Entries left alone because the file already existed (`OverwritePolicy::Skip`)
+    pub skipped: usize,
+    /// Bytes written
+    pub bytes: u64,
+}
+
+/// Works out an archive's format from its first bytes.
+fn detect_format(magic: &[u8]) -> Option<(ArchiveFormat, Codec)> {
+    match magic {
+        [b'P', b'K', 0x03, 0x04, ..] | [b'P', b'K', 0x05, 0x06, ..] => Some((ArchiveFormat::Zip, Codec::default())),
+        _ => Codec::all().iter().find(|codec| codec.detect(magic)).map(|codec| (ArchiveFormat::Tar, *codec)),
+    }
+}
+
+/// An entry name as a relative path without `..`, root or drive components, or `None`
+/// if it could land outside the destination.
+fn safe_entry_path(name: &Path) -> Option<PathBuf> {
+    let mut safe = PathBuf::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => safe.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    (!safe.as_os_str().is_empty()).then_some(safe)
+}
+
+/// Whether a symlink stored at `entry` (relative to the destination) pointing at `target`
+/// resolves inside the destination. `..` is only allowed before the first name: after one it
+/// would climb out of wherever that name leads, which for a link in the same archive (made
+/// before or after this one) can be anywhere its own `..` reached, as with `d/e/up -> ../..`
+/// and `z -> d/e/up/..`. Climbing first only passes through real directories, since links
+/// are never extracted below other links, and every name after it resolves inside in turn.
+fn symlink_stays_inside(entry: &Path, target: &Path) -> bool {
+    let mut depth = entry.components().count() as isize - 1;
+    let mut named = false;
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => {
+                depth += 1;
+                named = true;
+            }
+            Component::CurDir => {}
+            Component::ParentDir if named => return false,
+            Component::ParentDir => depth -= 1,
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+        if depth < 0 {
+            return false;
+        }
+    }
+    true
+}
+
+/// Whether any directory between `dest` and the entry at `path` is a symlink.
+fn has_symlinked_parent(dest: &Path, path: &Path) -> bool {
+    path.ancestors().skip(1).filter(|parent| !parent.as_os_str().is_empty()).any(|parent| {
+        dest.join(parent).symlink_metadata().is_ok_and(|metadata| metadata.file_type().is_symlink())
+    })
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+/// Windows has separate links for files and directories, so the target has to be looked at.
+/// Creating either needs Developer Mode or the symlink privilege.
+#[cfg(windows)]
+fn create_symlink(target: &Path, link: &Path) -> io::Result<()> {
+    // Entry targets use `/`, which Windows links do not resolve
+    let target: PathBuf = target.components().collect();
+    let points_to_dir = link.parent().is_some_and(|parent| parent.join(&target).is_dir());
+    match points_to_dir {
+        true => std::os::windows::fs::symlink_dir(&target, link),
+        false => std::os::windows::fs::symlink_file(&target, link),
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn create_symlink(_target: &Path, link: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported,
+        format!("Symlink entries cannot be extracted on this platform: {}", link.display())))
+}
+
+fn unsafe_entry(name: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Refusing to extract unsafe entry {}", name.display()))
+}
+
+/// Refuses an entry whose (stripped) `path` has a name this platform cannot create.
+fn check_portable(name: &Path, path: &Path) -> io::Result<()> {
+    match unportable_component(path) {
+        Some((part, problem)) => Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("Cannot extract {}: {} ({})", name.display(), problem, part))),
+        None => Ok(()),
+    }
+}
+
+/// Applies `policy` to an entry whose destination already exists; true means extract it.
+fn should_write(target: &Path, policy: OverwritePolicy, summary: &mut ExtractSummary) -> io::Result<bool> {
+    if target.symlink_metadata().is_err() {
+        return Ok(true);
+    }
+    match policy {
+        OverwritePolicy::Overwrite => Ok(true),
+        OverwritePolicy::Skip => {
+            summary.skipped += 1;
+            Ok(false)
+        }
+        OverwritePolicy::Error => Err(io::Error::new(io::ErrorKind::AlreadyExists,
+            format!("{} already exists (use --overwrite skip or --overwrite overwrite)", target.display()))),
+    }
+}
+
+/// Unpacks a tarball (any supported codec) or zip file into `dest`, creating it if needed.
+/// The format is detected from the file's contents. Entries whose names (or symlink
+/// targets) would escape `dest` are refused before anything is written for them.
+pub fn extract_archive(archive: &Path, dest: &Path, policy: OverwritePolicy) -> io::Result<ExtractSummary> {
+    extract_archive_stripped(archive, dest, policy, 0)
+}
+
+/// Like [`extract_archive`], but drops the first `strip_components` directories from every
+/// entry name (as `tar --strip-components` does), so `reports/2024/q1.pdf` with 1 lands in
+/// `dest/2024/q1.pdf`. Entries with no name left are skipped.
+pub fn extract_archive_stripped(archive: &Path, dest: &Path, policy: OverwritePolicy, strip_components: usize) -> io::Result<ExtractSummary> {
+    extract_archive_with(archive, dest, &ExtractOptions { policy, strip_components, ..Default::default() })
+}
+
+/// Like [`extract_archive`], with every option spelled out.
+pub fn extract_archive_with(archive: &Path, dest: &Path, options: &ExtractOptions) -> io::Result<ExtractSummary> {
+    let (format, codec) = sniff_archive(archive)?;
+
+    let dest = &long_path(dest)?;
+    std::fs::create_dir_all(dest)?;
+    let file = File::open(archive)?;
+    match format {
+        ArchiveFormat::Tar => extract_tar(tar_decoder(io::BufReader::new(file), codec)?, dest, options),
+        ArchiveFormat::Zip => extract_zip(file, dest, options.policy, options.strip_components),
+    }
+}
+
+/// Unpacks a tarball from `reader` as it is read, e.g. straight from a download, so the
+/// archive itself is never stored. The compression is detected from the first bytes; zip
+/// files need seeking and are refused.
+pub fn extract_tar_stream<R: Read>(mut reader: R, dest: &Path, options: &ExtractOptions) -> io::Result<ExtractSummary> {
+    let mut magic = Vec::with_capacity(262);
+    (&mut reader).take(262).read_to_end(&mut magic)?;
+    if crate::crypt::has_encrypted_magic(&magic) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "The archive is encrypted and cannot be unpacked as it arrives"));
+    }
+    let codec = match detect_format(&magic) {
+        Some((ArchiveFormat::Tar, codec)) => codec,
+        Some((ArchiveFormat::Zip, _)) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Zip archives cannot be unpacked as they arrive; use a tarball"));
+        }
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a supported archive (expected tar, tar.gz, tar.zst, tar.xz or tar.bz2)")),
+    };
+
+    let dest = &long_path(dest)?;
+    std::fs::create_dir_all(dest)?;
+    extract_tar(tar_decoder(io::Cursor::new(magic).chain(reader), codec)?, dest, options)
+}
+
+/// The format of the archive file at `archive`, from its first bytes.
+fn sniff_archive(archive: &Path) -> io::Result<(ArchiveFormat, Codec)> {
+    let mut magic = Vec::with_capacity(262);
+    File::open(archive)?.take(262).read_to_end(&mut magic)?;
+    if crate::crypt::has_encrypted_magic(&magic) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("{} is encrypted; decrypt it first (extract --decrypt)", archive.display())));
+    }
+    detect_format(&magic).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+        format!("{} is not a supported archive (expected tar, tar.gz, tar.zst, tar.xz, tar.bz2 or zip)", archive.display())))
+}
+
+/// `path` without its first `count` components, or `None` if nothing would be left.
+fn strip_components(path: &Path, count: usize) -> Option<PathBuf> {
+    let stripped: PathBuf = path.components().skip(count).collect();
+    (!stripped.as_os_str().is_empty()).then_some(stripped)
+}
+
+fn extract_tar(decoder: impl Read, dest: &Path, options: &ExtractOptions) -> io::Result<ExtractSummary> {
+    let (policy, strip) = (options.policy, options.strip_components);
+    let mut summary = ExtractSummary::default();
+    let mut archive = Archive::new(decoder);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.into_owned();
+        let path = safe_entry_path(&name).ok_or_else(|| unsafe_entry(&name))?;
+        let Some(path) = strip_components(&path, strip) else { continue };
+        check_portable(&name, &path)?;
+
+        let entry_type = entry.header().entry_type();
+        if let Some(target) = entry.link_name()? {
+            let stays_inside = match entry_type {
+                // Relative targets start from the link's real directory, so it must not be a link
+                tar::EntryType::Symlink => symlink_stays_inside(&path, &target) && !has_symlinked_parent(dest, &path),
+                _ => safe_entry_path(&target).is_some(),
+            };
+            if !stays_inside {
+                return Err(unsafe_entry(&name));
+            }
+        }
+
+        if !entry_type.is_dir() && !should_write(&dest.join(&path), policy, &mut summary)? {
+            continue;
+        }
+        let xattrs = match options.xattrs {
+            true => entry_xattrs(&mut entry)?,
+            false => Vec::new(),
+        };
+
+        if strip == 0 {
+            // unpack_in also refuses to write through symlinks that leave `dest`
+            if !entry.unpack_in(dest)? {
+                return Err(unsafe_entry(&name));
+            }
+        } else {
+            // unpack_in would use the full name, so do its checks for the shortened one here
+            if has_symlinked_parent(dest, &path) {
+                return Err(unsafe_entry(&name));
+            }
+            let target = dest.join(&path);
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            match entry.link_name()?.filter(|_| entry_type.is_hard_link()).map(|first| first.into_owned()) {
+                // A hard link names the file it shares data with, which lost the same components
+                Some(first) => {
+                    let first = strip_components(&first, strip).ok_or_else(|| unsafe_entry(&name))?;
+                    if has_symlinked_parent(dest, &first) {
+                        return Err(unsafe_entry(&name));
+                    }
+                    if target.symlink_metadata().is_ok() {
+                        std::fs::remove_file(&target)?;
+                    }
+                    std::fs::hard_link(dest.join(first), &target)?;
+                }
+                None => {
+                    entry.unpack(&target)?;
+                }
+            }
+        }
+        restore_xattrs(&dest.join(&path), &xattrs)?;
+        if entry_type.is_file() || entry_type.is_gnu_sparse() {
+            summary.files += 1;
+            summary.bytes += entry.size();
+        } else if entry_type.is_hard_link() {
+            // Its data was counted with the file it links to
+            summary.files += 1;
+        }
+    }
+
+    Ok(summary)
+}
+
+fn extract_zip(file: File, dest: &Path, policy: OverwritePolicy, strip: usize) -> io::Result<ExtractSummary> {
+    let mut summary = ExtractSummary::default();
+    let mut archive = zip::ZipArchive::new(io::BufReader::new(file))?;
+
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        let name = PathBuf::from(entry.name());
+        let path = entry.enclosed_name().and_then(|path| safe_entry_path(&path)).ok_or_else(|| unsafe_entry(&name))?;
+        let Some(path) = strip_components(&path, strip) else { continue };
+        check_portable(&name, &path)?;
+        let target = dest.join(&path);
+
+        // Writing below a link extracted earlier could land anywhere it points
+        if has_symlinked_parent(dest, &path) {
+            return Err(unsafe_entry(&name));
+        }
+
+        if entry.is_dir() {
+            std::fs::create_dir_all(&target)?;
+            continue;
+        }
+
+        let link_target = if entry.is_symlink() {
+            let mut link_target = String::new();
+            entry.read_to_string(&mut link_target)?;
+            if !symlink_stays_inside(&path, Path::new(&link_target)) {
+                return Err(unsafe_entry(&name));
+            }
+            Some(link_target)
+        } else {
+            None
+        };
+
+        if !should_write(&target, policy, &mut summary)? {
+            continue;
+        }
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // Replace rather than write through whatever is there, which may itself be a link
+        if target.symlink_metadata().is_ok() {
+            std::fs::remove_file(&target)?;
+        }
+
+        match link_target {
+            Some(link_target) => create_symlink(Path::new(&link_target), &target)?,
+            None => {
+                let modified = entry.last_modified().and_then(|modified| time::OffsetDateTime::try_from(modified).ok());
+                let mode = entry.unix_mode();
+
+                let mut file = File::create(&target)?;
+                summary.bytes += io::copy(&mut entry, &mut file)?;
+                summary.files += 1;
+
+                // Like tar, restore timestamps and permission bits but never setuid/setgid
+                if let Some(modified) = modified {
+                    file.set_modified(modified.into())?;
+                }
+                #[cfg(unix)]
+                if let Some(mode) = mode {
+                    use std::os::unix::fs::PermissionsExt;
+                    file.set_permissions(std::fs::Permissions::from_mode(mode & 0o777))?;
+                }
+                #[cfg(not(unix))]
+                let _ = mode;
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+// --- ARCHIVE BUILDER ---
+
+/// What [`ArchiveBuilder::append_to`] did.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AppendSummary {
+    /// Entries copied over unchanged
+    pub kept: usize,
+    /// Entries that were not in the archive before
+    pub added: usize,
+    /// Entries replaced by a newer version
+    pub updated: usize,
+}
+
+/// Writes `path` through `write`, into a temporary file next to it that is synced and renamed
+/// over `path` once `write` succeeds, and removed if it fails.
+fn replace_file(path: &Path, write: impl FnOnce(&File) -> io::Result<()>) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(".tmp-{}", std::process::id()));
+    let temp = PathBuf::from(temp);
+    let result = File::create(&temp).and_then(|file| {
+        write(&file)?;
+        file.sync_all()
+    });
+    match result.and_then(|()| std::fs::rename(&temp, path)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = std::fs::remove_file(&temp);
+            Err(e)
+        }
+    }
+}
+
+/// Builds an archive of a list of files and directories. Files are stored under their base
+/// names and directories with their structure below their base name (see [`ArchiveBuilder::flatten`]).
+///
+/// ```no_run
+/// use rust_file_archive_server::archive::{ArchiveBuilder, Codec};
+///
+/// let tarball = ArchiveBuilder::new()
+///     .file("report.pdf")
+///     .file("data.csv")
+///     .codec(Codec::Zstd)
+///     .build()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ArchiveBuilder {
+    files: Vec<PathBuf>,
+    format: ArchiveFormat,
+    codec: Codec,
+    level: Option<i32>,
+    flatten: bool,
+    symlinks: SymlinkPolicy,
+    normalize_metadata: bool,
+    xattrs: bool,
+    threads: usize,
+    exclude: Vec<String>,
+    sort: bool,
+    mtime_clamp: Option<u64>,
+}
+
+impl ArchiveBuilder {
+    /// A builder for a gzip tarball at the default level, with no files yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds one file.
+    pub fn file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.files.push(path.into());
+        self
+    }
+
+    /// Adds several files.
+    pub fn files<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.files.extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn format(mut self, format: ArchiveFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Compression for tarballs; ignored for zip.
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Compression level; defaults to the codec's `default_level`.
+    pub fn level(mut self, level: i32) -> Self {
+        self.level = Some(level);
+        self
+    }
+
+    /// Store every file under its base name, dropping directory structure. Files that would
+    /// end up with the same name make the build fail.
+    pub fn flatten(mut self, flatten: bool) -> Self {
+        self.flatten = flatten;
+        self
+    }
+
+    /// How symlinks are archived; followed by default.
+    pub fn symlinks(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlinks = policy;
+        self
+    }
+
+    /// Leaves out paths matching a gitignore-style pattern such as `target/` or `*.tmp`.
+    /// Patterns containing a slash are relative to the working directory.
+    pub fn exclude(mut self, pattern: impl Into<String>) -> Self {
+        self.exclude.push(pattern.into());
+        self
+    }
+
+    /// Whether to keep permissions, modification times and owners (the default) or store
+    /// normalized values; see [`ArchiveOptions::preserve_metadata`].
+    pub fn preserve_metadata(mut self, preserve: bool) -> Self {
+        self.normalize_metadata = !preserve;
+        self
+    }
+
+    /// Whether to record extended attributes in tarballs; see [`ArchiveOptions::xattrs`].
+    pub fn xattrs(mut self, xattrs: bool) -> Self {
+        self.xattrs = xattrs;
+        self
+    }
+
+    /// Threads to compress a tarball on; one by default. See [`ArchiveOptions::threads`].
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Makes the same files give byte-identical archives wherever and whenever they are
+    /// archived: entries in name order, metadata normalized, and modification times fixed or,
+    /// given a `source_date_epoch`, clamped to it (see [`ArchiveOptions::mtime_clamp`]).
+    pub fn reproducible(mut self, source_date_epoch: Option<u64>) -> Self {
+        self.sort = true;
+        self.normalize_metadata = true;
+        self.mtime_clamp = source_date_epoch;
+        self
+    }
+
+    /// The validated options, or `InvalidInput` if the level doesn't suit the codec.
+    pub fn options(&self) -> io::Result<ArchiveOptions> {
+        let options = ArchiveOptions::new(self.format, self.codec, self.level)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(ArchiveOptions {
+            preserve_metadata: !self.normalize_metadata,
+            xattrs: self.xattrs,
+            threads: self.threads.max(1),
+            mtime_clamp: self.mtime_clamp,
+            ..options
+        })
+    }
+
+    fn entries(&self) -> io::Result<Vec<ArchiveEntry>> {
+        let exclude = ExcludeRules::new(&std::env::current_dir()?, &self.exclude, None)?;
+        collect_entries(&self.files, &EntryOptions {
+            flatten: self.flatten,
+            symlinks: self.symlinks,
+            exclude,
+            within: None,
+            storage: Storage::default(),
+            sort: self.sort,
+        })
+    }
+
+    /// Builds the archive in memory.
+    pub fn build(&self) -> io::Result<bytes::Bytes> {
+        create_archive(&self.entries()?, &self.options()?)
+    }
+
+    /// Writes the archive into `writer` and hands it back. Tarballs are streamed as they
+    /// are compressed; zip needs to seek, so it is built in memory first.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<W> {
+        let (entries, options) = (self.entries()?, self.options()?);
+        match options.format {
+            ArchiveFormat::Tar => write_tar(&entries, &options, writer),
+            ArchiveFormat::Zip => {
+                writer.write_all(&create_zip(&entries, &options, None)?)?;
+                Ok(writer)
+            }
+        }
+    }
+
+    /// Writes the archive to `path`, by way of a temporary file next to it that replaces
+    /// `path` only once the archive is complete.
+    pub fn write_file(&self, path: &Path) -> io::Result<()> {
+        replace_file(path, |file| self.write_to(file).map(drop))
+    }
+
+    /// Adds this builder's files to the tarball at `path`, creating it if it does not exist.
+    /// Entries already in it are copied over as they are; files that are new are added and
+    /// files that changed (in size or modification time, or in content when metadata is
+    /// normalized) replace their old entry. Nothing is removed. The existing compression is
+    /// kept, whatever [`codec`](Self::codec) says, and the result is written to a temporary
+    /// file that replaces `path` only once it is complete.
+    pub fn append_to(&self, path: &Path) -> io::Result<AppendSummary> {
+        let options = self.options()?;
+        if options.format == ArchiveFormat::Zip {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Only tarballs can be appended to"));
+        }
+        let entries = self.entries()?;
+        if !path.exists() {
+            self.write_file(path)?;
+            return Ok(AppendSummary { added: entries.len(), ..AppendSummary::default() });
+        }
+        let (format, codec) = sniff_archive(path)?;
+        if format != ArchiveFormat::Tar {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is a zip file; only tarballs can be appended to", path.display())));
+        }
+
+        // 1. Work out which entries are to be replaced; normalized or clamped entries can share
+        // a timestamp, so for those the old contents are hashed first
+        let by_name: std::collections::HashMap<&Path, &ArchiveEntry> = entries.iter().map(|entry| (entry.name.as_path(), entry)).collect();
+        let mut old_digests = std::collections::HashMap::new();
+        if !options.preserve_metadata || options.mtime_clamp.is_some() {
+            let mut archive = Archive::new(tar_decoder(File::open(path)?, codec)?);
+            for old in archive.entries()? {
+                let old = old?;
+                let name = old.path()?.into_owned();
+                if by_name.get(name.as_path()).is_some_and(|entry| entry.kind == EntryKind::File) {
+                    old_digests.insert(name, hash_reader(old)?.1);
+                }
+            }
+        }
+        let (mut present, mut unchanged) = (std::collections::HashSet::new(), std::collections::HashSet::new());
+        let mut archive = Archive::new(tar_decoder(File::open(path)?, codec)?);
+        for old in archive.entries()? {
+            let old = old?;
+            let name = old.path()?.into_owned();
+            let Some(entry) = by_name.get(name.as_path()) else { continue };
+            present.insert(name.clone());
+            let same = match (entry.kind, old.header().entry_type()) {
+                (EntryKind::Dir, tar::EntryType::Directory) => true,
+                (EntryKind::Symlink, tar::EntryType::Symlink) => {
+                    old.link_name()?.is_some_and(|target| entry.storage.read_link(&entry.source).is_ok_and(|link| link == target))
+                }
+                (EntryKind::File, tar::EntryType::Regular | tar::EntryType::Continuous | tar::EntryType::GNUSparse) => {
+                    let metadata = entry_metadata(entry)?;
+                    let mtime = metadata.modified.duration_since(std::time::UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+                    match old_digests.get(&name) {
+                        Some(digest) => metadata.len == old.size() && *digest == hash_reader(entry.storage.open(&entry.source)?)?.1,
+                        None => metadata.len == old.size() && old.header().mtime()? == mtime,
+                    }
+                }
+                _ => false,
+            };
+            if same {
+                unchanged.insert(name);
+            }
+        }
+
+        // 2. Copy what stays, then add what is new or changed
+        let added: Vec<ArchiveEntry> = entries.iter().filter(|entry| !unchanged.contains(&entry.name)).cloned().collect();
+        let mut kept = 0;
+        replace_file(path, |file| {
+            let mut writer = io::BufWriter::new(file);
+            let encoder = ArchiveOptions { codec, ..options }.encoder(&mut writer)?;
+            let mut tar = Builder::new(encoder);
+            let mut archive = Archive::new(tar_decoder(File::open(path)?, codec)?);
+            for old in archive.entries()? {
+                let mut old = old?;
+                let name = old.path()?.into_owned();
+                if by_name.contains_key(name.as_path()) && !unchanged.contains(&name) {
+                    continue;
+                }
+                kept += 1;
+                // The attributes are in a PAX header of their own, which the copy needs too
+                append_xattr_header(&mut tar, &entry_xattrs(&mut old)?)?;
+                let mut header = old.header().clone();
+                match old.link_name()? {
+                    Some(target) if matches!(header.entry_type(), tar::EntryType::Symlink | tar::EntryType::Link) => {
+                        let target = target.into_owned();
+                        tar.append_link(&mut header, &name, target)?;
+                    }
+                    // Reading a sparse entry fills in its holes, so copying it as is would not fit its header
+                    _ if header.entry_type().is_gnu_sparse() => copy_sparse_entry(&mut tar, &mut header, &name, &mut old)?,
+                    _ => tar.append_data(&mut header, &name, &mut old)?,
+                }
+            }
+            append_tar_entries(tar, &added, &options, None)?.finish()?;
+            writer.flush()
+        })?;
+        Ok(AppendSummary {
+            kept,
+            added: entries.len() - present.len(),
+            updated: present.len() - unchanged.len(),
+        })
+    }
+
+    /// Size and SHA-256 of the archive, computed without keeping it.
+    pub fn checksum(&self) -> io::Result<ArchiveChecksum> {
+        measure_archive(&self.entries()?, &self.options()?)
+    }
+
+    /// The strong ETag the server sends for this archive; it changes whenever a file does.
+    pub fn etag(&self) -> io::Result<String> {
+        archive_etag(&self.entries()?, &self.options()?)
+    }
+
+    /// Checks that every file is readable and estimates the compressed size.
+    pub fn dry_run(&self) -> io::Result<DryRunReport> {
+        dry_run_archive(&self.entries()?)
+    }
+}
@@ -0,0 +1,111 @@
+//! The download audit log (`--audit-log`): one JSON line per download, appended once its
+//! response has been sent or the client went away, recording who pulled which files.
+//!
+//! Downloads are `/download`, `/file`, `POST /download.chunks`, WebDAV `GET` and, in static
+//! mode, files under their own path or `/browse`. The server marks each one on its response;
+//! the access log then writes the line with the bytes it counted. Reloading the server opens
+//! the file again, so it can be rotated like the other logs.
+
+use serde::Serialize;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+/// The file audit records are appended to.
+pub(crate) struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+/// One line of the audit log.
+#[derive(Serialize, Debug)]
+struct AuditRecord<'a> {
+    /// When the request arrived, in UTC (`2024-01-01T12:00:00Z`)
+    time: String,
+    client: &'a str,
+    /// Who authenticated, or `token`, `share link` or `one-time token` for credentials without
+    /// a user; absent without authentication
+    user: Option<&'a str>,
+    method: &'a str,
+    /// Path and query as requested
+    path: &'a str,
+    /// What the request named, relative to the root the user sees
+    files: &'a [String],
+    status: u16,
+    /// Response body bytes sent
+    bytes: u64,
+    /// `complete` when the whole response (just the range, for a resumed download) was sent,
+    /// `interrupted` when the client went away first, `failed` for an error response
+    outcome: &'static str,
+    duration_ms: u64,
+}
+
+impl AuditLog {
+    /// Opens `path` for appending, creating it if needed.
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog { path: path.to_path_buf(), file: Mutex::new(file) })
+    }
+}
+
+/// A download to be audited, attached to its response.
+#[derive(Clone)]
+pub(crate) struct AuditedDownload {
+    pub(crate) log: Arc<AuditLog>,
+    pub(crate) user: Option<String>,
+    pub(crate) files: Vec<String>,
+}
+
+/// How an audited download went, as the access log saw it.
+pub(crate) struct DownloadOutcome<'a> {
+    pub(crate) client: &'a str,
+    pub(crate) method: &'a str,
+    pub(crate) path: &'a str,
+    pub(crate) status: u16,
+    pub(crate) bytes: u64,
+    /// The response body was sent to the end
+    pub(crate) complete: bool,
+    pub(crate) started: SystemTime,
+    pub(crate) duration: Duration,
+}
+
+impl AuditedDownload {
+    /// Appends the download's line. A write that fails is logged, as the download has already
+    /// been served by then.
+    pub(crate) fn record(&self, outcome: DownloadOutcome) {
+        let record = AuditRecord {
+            time: format_timestamp(outcome.started),
+            client: outcome.client,
+            user: self.user.as_deref(),
+            method: outcome.method,
+            path: outcome.path,
+            files: &self.files,
+            status: outcome.status,
+            bytes: outcome.bytes,
+            outcome: match (outcome.status, outcome.complete) {
+                (200..=399, true) => "complete",
+                (200..=399, false) => "interrupted",
+                _ => "failed",
+            },
+            duration_ms: outcome.duration.as_millis() as u64,
+        };
+        let mut line = serde_json::to_vec(&record).unwrap_or_default();
+        line.push(b'\n');
+        // One write per line, so lines from concurrent downloads never interleave
+        let mut file = self.log.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(&line) {
+            warn!("Could not write to the audit log {}: {}", self.log.path.display(), e);
+        }
+    }
+}
+
+/// `2024-01-01T12:00:00Z`
+fn format_timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    format!("{}:{:02}Z", crate::format_unix_time(secs).replace(' ', "T"), secs % 60)
+}
@@ -0,0 +1,194 @@
+//! Throughput testing against a running server, for the `bench` command.
+//!
+//! A run fetches one payload a few times over, either the server's `/bench` filler or the
+//! archive of some files, as Range requests of one chunk size spread over a number of
+//! concurrent requests. Each run reports the throughput, the latency of the individual requests and the CPU time
+//! it cost: always the client's, and the server's too when its `/metrics` can be read.
+//! Comparing runs with different connection counts and chunk sizes, and servers started with
+//! different settings, shows which ones suit a machine and its network.
+
+use futures_util::StreamExt;
+use serde::Serialize;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::client::{files_query, server_error, ClientResult, Connection};
+
+/// What a benchmark downloads.
+#[derive(Clone, Debug)]
+pub enum Payload {
+    /// This many bytes of `/bench` filler, which the server sends without reading any files
+    Filler(u64),
+    /// The archive of these files, built the way `/download` builds it for every request
+    Files(Vec<String>),
+}
+
+/// The outcome of one run.
+#[derive(Clone, Debug, Serialize)]
+pub struct BenchReport {
+    pub connections: usize,
+    pub chunk_size: u64,
+    /// Requests made, one per chunk
+    pub requests: u64,
+    pub bytes: u64,
+    pub seconds: f64,
+    /// Bytes per second over the whole run
+    pub throughput: f64,
+    /// Request latencies in seconds, from sending a request until its last byte arrived
+    pub latency: Latency,
+    /// CPU time the client spent, where the platform reports it
+    pub client_cpu_seconds: Option<f64>,
+    /// CPU time the server spent, when its `/metrics` report it
+    pub server_cpu_seconds: Option<f64>,
+}
+
+/// Percentiles of request latency, in seconds.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Latency {
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Latency {
+    fn of(mut samples: Vec<f64>) -> Self {
+        if samples.is_empty() {
+            return Latency::default();
+        }
+        samples.sort_by(f64::total_cmp);
+        // Nearest rank: the smallest sample at least that share of the others are no larger than
+        let rank = |share: f64| samples[((share * samples.len() as f64).ceil() as usize).clamp(1, samples.len()) - 1];
+        Latency { p50: rank(0.5), p90: rank(0.9), p99: rank(0.99), max: samples[samples.len() - 1] }
+    }
+}
+
+/// Runs benchmarks of one payload on one server.
+pub struct Bench {
+    connection: Connection,
+    payload: Payload,
+}
+
+impl Bench {
+    pub fn new(connection: Connection, payload: Payload) -> Self {
+        Bench { connection, payload }
+    }
+
+    fn request(&self, method: reqwest::Method) -> ClientResult<reqwest::RequestBuilder> {
+        let client = self.connection.client()?;
+        Ok(match &self.payload {
+            Payload::Filler(size) => client.request(method, format!("{}/bench", self.connection.base_url()))
+                .query(&[("size", size.to_string())]),
+            Payload::Files(files) => client.request(method, format!("{}/download", self.connection.base_url()))
+                .query(&files_query(files)),
+        })
+    }
+
+    /// The size of the payload, as the server reports it.
+    pub async fn size(&self) -> ClientResult<u64> {
+        let response = self.request(reqwest::Method::HEAD)?.send().await?;
+        if !response.status().is_success() {
+            return Err(server_error(response).await);
+        }
+        response.headers().get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+            .ok_or_else(|| "The server did not say how large the payload is; benchmarks need buffered archives (no --stream)".into())
+    }
+
+    /// Fetches the `size`-byte payload `rounds` times as chunks of `chunk_size`, with up to
+    /// `connections` requests in flight at a time.
+    pub async fn run(&self, size: u64, connections: usize, chunk_size: u64, rounds: u32) -> ClientResult<BenchReport> {
+        let chunk_size = chunk_size.max(1);
+        let chunks = size.div_ceil(chunk_size);
+        let server_cpu = self.server_cpu_time().await;
+        let client_cpu = crate::process_cpu_time();
+        let started = Instant::now();
+
+        let (mut latencies, mut bytes) = (Vec::new(), 0);
+        for _ in 0..rounds.max(1) {
+            // Each worker takes the next chunk nobody has yet, so slow requests do not hold up the rest
+            let next = AtomicU64::new(0);
+            let workers = (0..connections.max(1)).map(|_| async {
+                let mut latencies = Vec::new();
+                let mut bytes = 0;
+                loop {
+                    let chunk = next.fetch_add(1, Ordering::Relaxed);
+                    if chunk >= chunks {
+                        return Ok::<_, Box<dyn std::error::Error>>((latencies, bytes));
+                    }
+                    let start = chunk * chunk_size;
+                    let end = (start + chunk_size).min(size) - 1;
+                    let sent = Instant::now();
+                    bytes += self.fetch_range(start, end).await?;
+                    latencies.push(sent.elapsed().as_secs_f64());
+                }
+            });
+            for result in futures_util::future::join_all(workers).await {
+                let (worker_latencies, worker_bytes) = result?;
+                latencies.extend(worker_latencies);
+                bytes += worker_bytes;
+            }
+        }
+
+        let seconds = started.elapsed().as_secs_f64();
+        let client_cpu_seconds = crate::process_cpu_time().zip(client_cpu).map(|(after, before)| (after - before).as_secs_f64());
+        let server_cpu_seconds = match server_cpu {
+            Some(before) => self.server_cpu_time().await.map(|after| (after - before).max(0.0)),
+            None => None,
+        };
+        Ok(BenchReport {
+            connections,
+            chunk_size,
+            requests: latencies.len() as u64,
+            bytes,
+            seconds,
+            throughput: bytes as f64 / seconds.max(f64::MIN_POSITIVE),
+            latency: Latency::of(latencies),
+            client_cpu_seconds,
+            server_cpu_seconds,
+        })
+    }
+
+    /// Downloads bytes `start..=end` of the payload and throws them away, returning how many came.
+    async fn fetch_range(&self, start: u64, end: u64) -> ClientResult<u64> {
+        let response = self.request(reqwest::Method::GET)?
+            .header(reqwest::header::RANGE, format!("bytes={}-{}", start, end))
+            .send().await?;
+        if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+            if !response.status().is_success() {
+                return Err(server_error(response).await);
+            }
+            return Err("The server ignored the Range request; benchmarks need ranges of the payload".into());
+        }
+        let mut body = response.bytes_stream();
+        let mut received = 0;
+        while let Some(piece) = body.next().await {
+            received += piece?.len() as u64;
+        }
+        Ok(received)
+    }
+
+    /// The server's `process_cpu_seconds_total` from `/metrics`, if it can be read.
+    async fn server_cpu_time(&self) -> Option<f64> {
+        let client = self.connection.client().ok()?;
+        let response = client.get(format!("{}/metrics", self.connection.base_url())).send().await.ok()?;
+        if !response.status().is_success() {
+            return None;
+        }
+        let metrics = response.text().await.ok()?;
+        metrics.lines()
+            .find_map(|line| line.strip_prefix("process_cpu_seconds_total "))
+            .and_then(|value| value.trim().parse().ok())
+    }
+}
+
+/// How long an interval of `seconds` is, for showing, e.g. `3.2ms` or `1.50s`.
+pub fn format_seconds(seconds: f64) -> String {
+    match Duration::from_secs_f64(seconds.max(0.0)) {
+        duration if duration < Duration::from_millis(1) => format!("{:.0}µs", seconds * 1e6),
+        duration if duration < Duration::from_secs(1) => format!("{:.1}ms", seconds * 1e3),
+        _ => format!("{:.2}s", seconds),
+    }
+}
@@ -0,0 +1,285 @@
+//! On-disk cache of generated archives.
+//!
+//! Archives are stored under their ETag, which already hashes every input file's path, size
+//! and modification time together with the archive options, so a hit is always byte-identical
+//! to what a rebuild would produce. When the cache grows past its size limit, the least
+//! recently used archives are deleted.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Extension of cached archives, so unrelated files in the directory are left alone.
+const CACHE_EXTENSION: &str = "archive";
+
+/// A size-bounded directory of archives keyed by ETag.
+pub(crate) struct ArchiveCache {
+    dir: PathBuf,
+    max_size: u64,
+    state: Mutex<CacheState>,
+    /// Makes temporary file names unique across concurrent inserts
+    next_temp: AtomicU64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, CacheEntry>,
+    total_size: u64,
+    /// Incremented on every access; an entry's `last_used` is the tick it was last touched
+    clock: u64,
+}
+
+struct CacheEntry {
+    size: u64,
+    last_used: u64,
+}
+
+impl ArchiveCache {
+    /// Opens (creating if needed) the cache in `dir`. Archives left by an earlier run are
+    /// picked up, oldest first, and leftover temporary files are removed.
+    pub(crate) fn open(dir: &Path, max_size: u64) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+
+        let mut found = Vec::new();
+        for dir_entry in std::fs::read_dir(dir)? {
+            let path = dir_entry?.path();
+            if path.extension().is_some_and(|extension| extension == CACHE_EXTENSION) {
+                let metadata = std::fs::metadata(&path)?;
+                let Some(key) = path.file_stem().and_then(|stem| stem.to_str()) else { continue };
+                found.push((metadata.modified()?, key.to_string(), metadata.len()));
+            }
+        }
+        found.sort();
+        remove_temp_files(dir)?;
+
+        let mut state = CacheState::default();
+        for (_, key, size) in found {
+            state.clock += 1;
+            state.total_size += size;
+            state.entries.insert(key, CacheEntry { size, last_used: state.clock });
+        }
+
+        let cache = ArchiveCache { dir: dir.to_path_buf(), max_size, state: Mutex::new(state), next_temp: AtomicU64::new(0) };
+        cache.evict()?;
+        Ok(cache)
+    }
+
+    pub(crate) fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The cached archive for `etag`, marking it as recently used.
+    pub(crate) fn get(&self, etag: &str) -> Option<PathBuf> {
+        let key = cache_key(etag);
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+        let entry = state.entries.get_mut(&key)?;
+        entry.last_used = clock;
+        Some(self.path_for(&key))
+    }
+
+    /// Forgets an entry whose file turned out to be missing or unreadable.
+    pub(crate) fn remove(&self, etag: &str) {
+        let key = cache_key(etag);
+        let mut state = self.state.lock().unwrap();
+        if let Some(entry) = state.entries.remove(&key) {
+            state.total_size -= entry.size;
+        }
+        let _ = std::fs::remove_file(self.path_for(&key));
+    }
+
+    /// Stores an archive under `etag`, then evicts the least recently used archives until the
+    /// cache fits its limit again. Archives bigger than the whole cache are not stored.
+    pub(crate) fn insert(&self, etag: &str, data: &[u8]) -> io::Result<()> {
+        let size = data.len() as u64;
+        if size > self.max_size {
+            return Ok(());
+        }
+
+        // Each insert writes its own temporary file and renames it into place, so concurrent
+        // requests for the same archive never write into one file and readers never see half of one
+        let key = cache_key(etag);
+        let temp = self.temp_path(&key);
+        if let Err(e) = std::fs::write(&temp, data) {
+            let _ = std::fs::remove_file(&temp);
+            return Err(e);
+        }
+        self.store(key, &temp, size)
+    }
+
+    /// Starts storing an archive under `etag` as it is produced, e.g. while it streams to a
+    /// client. It only joins the cache once [`CacheWriter::commit`] is called.
+    pub(crate) fn writer(self: &Arc<Self>, etag: &str) -> io::Result<CacheWriter> {
+        let key = cache_key(etag);
+        let temp = self.temp_path(&key);
+        let file = BufWriter::new(File::create(&temp)?);
+        Ok(CacheWriter { cache: self.clone(), key, temp, file: Some(file), size: 0 })
+    }
+
+    /// Moves a finished temporary file into place under `key`, then evicts as `insert` does.
+    fn store(&self, key: String, temp: &Path, size: u64) -> io::Result<()> {
+        if let Err(e) = std::fs::rename(temp, self.path_for(&key)) {
+            let _ = std::fs::remove_file(temp);
+            return Err(e);
+        }
+
+        {
+            let mut state = self.state.lock().unwrap();
+            state.clock += 1;
+            let last_used = state.clock;
+            if let Some(previous) = state.entries.insert(key, CacheEntry { size, last_used }) {
+                state.total_size -= previous.size;
+            }
+            state.total_size += size;
+        }
+        self.evict()
+    }
+
+    fn temp_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}.tmp", key, self.next_temp.fetch_add(1, Ordering::Relaxed)))
+    }
+
+    /// Every cached archive as its ETag and size, most recently used first.
+    pub(crate) fn entries(&self) -> Vec<(String, u64)> {
+        let state = self.state.lock().unwrap();
+        let mut entries: Vec<_> = state.entries.iter().map(|(key, entry)| (entry.last_used, key, entry.size)).collect();
+        entries.sort_by_key(|&(last_used, _, _)| std::cmp::Reverse(last_used));
+        entries.into_iter().map(|(_, key, size)| (format!("\"{}\"", key), size)).collect()
+    }
+
+    /// Total size of the cached archives, and the limit it is kept under.
+    pub(crate) fn usage(&self) -> (u64, u64) {
+        (self.state.lock().unwrap().total_size, self.max_size)
+    }
+
+    /// Deletes every cached archive, or with `etag` only that one, returning how many archives
+    /// and bytes were removed.
+    pub(crate) fn purge(&self, etag: Option<&str>) -> io::Result<(usize, u64)> {
+        let mut state = self.state.lock().unwrap();
+        let keys: Vec<String> = match etag {
+            Some(etag) => state.entries.contains_key(&cache_key(etag)).then(|| cache_key(etag)).into_iter().collect(),
+            None => state.entries.keys().cloned().collect(),
+        };
+        let (mut removed, mut bytes) = (0, 0);
+        for key in keys {
+            let entry = state.entries.remove(&key).unwrap();
+            state.total_size -= entry.size;
+            match std::fs::remove_file(self.path_for(&key)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            removed += 1;
+            bytes += entry.size;
+        }
+        Ok((removed, bytes))
+    }
+
+    /// Deletes temporary files of inserts that never finished, e.g. on shutdown.
+    pub(crate) fn remove_temp_files(&self) -> io::Result<()> {
+        remove_temp_files(&self.dir)
+    }
+
+    fn evict(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        while state.total_size > self.max_size {
+            let Some(oldest) = state.entries.iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+
+            let entry = state.entries.remove(&oldest).unwrap();
+            state.total_size -= entry.size;
+            match std::fs::remove_file(self.path_for(&oldest)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn path_for(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", key, CACHE_EXTENSION))
+    }
+}
+
+/// An archive on its way into the cache. Writes never fail: an archive that outgrows the cache
+/// or cannot be written is dropped, and with it the temporary file.
+pub(crate) struct CacheWriter {
+    cache: Arc<ArchiveCache>,
+    key: String,
+    temp: PathBuf,
+    /// `None` once the archive was given up on
+    file: Option<BufWriter<File>>,
+    size: u64,
+}
+
+impl CacheWriter {
+    /// Stores the archive written so far as complete.
+    pub(crate) fn commit(mut self) -> io::Result<()> {
+        let Some(file) = self.file.take() else {
+            return Ok(());
+        };
+        if let Err(e) = file.into_inner() {
+            let _ = std::fs::remove_file(&self.temp);
+            return Err(e.into_error());
+        }
+        self.cache.store(self.key.clone(), &self.temp, self.size)
+    }
+
+    fn give_up(&mut self) {
+        if self.file.take().is_some() {
+            let _ = std::fs::remove_file(&self.temp);
+        }
+    }
+}
+
+impl Write for CacheWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.size += data.len() as u64;
+        if self.size > self.cache.max_size {
+            self.give_up();
+        }
+        if let Some(file) = &mut self.file {
+            if let Err(e) = file.write_all(data) {
+                tracing::warn!("Could not cache archive: {}", e);
+                self.give_up();
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for CacheWriter {
+    fn drop(&mut self) {
+        self.give_up();
+    }
+}
+
+fn remove_temp_files(dir: &Path) -> io::Result<()> {
+    for dir_entry in std::fs::read_dir(dir)? {
+        let path = dir_entry?.path();
+        if path.extension().is_some_and(|extension| extension == "tmp") {
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+    Ok(())
+}
+
+/// The file name for an ETag: its hex digest without the quotes.
+fn cache_key(etag: &str) -> String {
+    etag.trim_matches('"').to_string()
+}
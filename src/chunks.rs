@@ -0,0 +1,195 @@
+//! Content-defined chunking, for transfers that only send what the client does not have yet.
+//!
+//! An archive is cut into chunks of 16 KiB to 256 KiB (64 KiB on average) with FastCDC: the
+//! cut points depend on the bytes around them, not on their offsets, so inserting or removing
+//! data only changes the chunks it touches and every other chunk keeps its hash. A client keeps
+//! the chunks it has received in a [`ChunkStore`] and on the next transfer asks the server to
+//! send only the ones missing from it.
+//!
+//! ```
+//! use rust_file_archive_server::chunks::chunk_lengths;
+//!
+//! let data: Vec<u8> = (0..1_000_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+//! let lengths = chunk_lengths(&data);
+//! assert_eq!(lengths.iter().sum::<usize>(), data.len());
+//! assert!(lengths.iter().all(|&length| length <= 256 * 1024));
+//! ```
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// No chunk is cut shorter than this, except the last one.
+pub const MIN_CHUNK_SIZE: usize = 16 * 1024;
+/// The size chunks are cut at on average.
+pub const AVERAGE_CHUNK_SIZE: usize = 64 * 1024;
+/// Chunks are cut at this size when no cut point turns up before it.
+pub const MAX_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Cut points before the average size need more matching bits than those after it, which keeps
+/// chunk sizes close to the average ("normalized chunking" in the FastCDC paper).
+const MASK_BEFORE_AVERAGE: u64 = u64::MAX << (64 - (AVERAGE_CHUNK_SIZE.trailing_zeros() + 2));
+const MASK_AFTER_AVERAGE: u64 = u64::MAX << (64 - (AVERAGE_CHUNK_SIZE.trailing_zeros() - 2));
+
+/// A fixed random value per byte for the rolling gear hash. The values must never change:
+/// they decide where chunks are cut, and so which chunks earlier transfers left in a store.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64, seeded with a constant
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6a09_e667_f3bc_c908;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// The length of the chunk `data` starts with.
+fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+    let end = data.len().min(MAX_CHUNK_SIZE);
+    let average = end.min(AVERAGE_CHUNK_SIZE);
+
+    // Each byte shifts the hash left, so its top bits depend on the last 64 bytes only
+    let mut hash = 0u64;
+    for (i, &byte) in data.iter().enumerate().take(end).skip(MIN_CHUNK_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        let mask = if i < average { MASK_BEFORE_AVERAGE } else { MASK_AFTER_AVERAGE };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// The lengths of the chunks `data` is cut into, in order. They add up to `data.len()`.
+pub fn chunk_lengths(data: &[u8]) -> Vec<usize> {
+    let mut lengths = Vec::with_capacity(data.len() / AVERAGE_CHUNK_SIZE + 1);
+    let mut rest = data;
+    while !rest.is_empty() {
+        let length = cut_point(rest);
+        lengths.push(length);
+        rest = &rest[length..];
+    }
+    lengths
+}
+
+/// The SHA-256 a chunk is addressed by, in lowercase hex.
+pub fn chunk_hash(data: &[u8]) -> String {
+    crate::archive::hex_digest(&Sha256::digest(data))
+}
+
+/// One chunk of an archive, as listed in its [`ChunkIndex`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ChunkRef {
+    /// SHA-256 of the chunk's bytes
+    pub hash: String,
+    pub size: u64,
+}
+
+/// Answer to `GET /download.chunks`: the archive `/download` would send for the same query,
+/// as the chunks it is cut into, in order.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChunkIndex {
+    pub etag: String,
+    /// Size of the whole archive
+    pub size: u64,
+    /// SHA-256 of the whole archive
+    pub sha256: String,
+    pub chunks: Vec<ChunkRef>,
+}
+
+impl ChunkIndex {
+    /// Cuts `data` into chunks and lists them.
+    pub fn of(etag: &str, data: &[u8]) -> Self {
+        let mut offset = 0;
+        let chunks = chunk_lengths(data).into_iter()
+            .map(|length| {
+                let chunk = &data[offset..offset + length];
+                offset += length;
+                ChunkRef { hash: chunk_hash(chunk), size: length as u64 }
+            })
+            .collect();
+        ChunkIndex {
+            etag: etag.to_string(),
+            size: data.len() as u64,
+            sha256: crate::archive::hex_digest(&Sha256::digest(data)),
+            chunks,
+        }
+    }
+
+    /// The chunks `have` lacks, each once, in the order they first appear in the archive.
+    /// This is the order `POST /download.chunks` sends them in.
+    pub fn missing<'a>(&'a self, have: &'a std::collections::HashSet<String>) -> impl Iterator<Item = &'a ChunkRef> {
+        let mut seen = std::collections::HashSet::new();
+        self.chunks.iter().filter(move |chunk| !have.contains(&chunk.hash) && seen.insert(chunk.hash.as_str()))
+    }
+}
+
+/// Body of `POST /download.chunks`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChunkRequest {
+    /// ETag of the index the client worked from; a different archive is answered with 412
+    pub etag: String,
+    /// Hashes of the archive's chunks the client already has
+    pub have: Vec<String>,
+}
+
+/// A directory of chunks named by their hash, `<dir>/<first two hex digits>/<hash>`.
+///
+/// Chunks are only ever added, so every transfer can reuse what any earlier one received;
+/// delete the directory to reclaim the space.
+#[derive(Clone, Debug)]
+pub struct ChunkStore {
+    dir: PathBuf,
+}
+
+impl ChunkStore {
+    /// Opens (creating if needed) the store in `dir`.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(ChunkStore { dir: dir.to_path_buf() })
+    }
+
+    fn path(&self, hash: &str) -> PathBuf {
+        self.dir.join(hash.get(..2).unwrap_or("00")).join(hash)
+    }
+
+    /// Whether the chunk `hash` is in the store with the expected size.
+    pub fn contains(&self, chunk: &ChunkRef) -> bool {
+        std::fs::metadata(self.path(&chunk.hash)).is_ok_and(|metadata| metadata.len() == chunk.size)
+    }
+
+    pub fn read(&self, hash: &str) -> io::Result<Vec<u8>> {
+        std::fs::read(self.path(hash))
+    }
+
+    /// Adds a chunk after checking that `data` really hashes to `hash`. The file is written
+    /// under a temporary name first, so an interrupted write never leaves a damaged chunk.
+    pub fn insert(&self, hash: &str, data: &[u8]) -> io::Result<()> {
+        if chunk_hash(data) != hash {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Chunk {} does not match its hash", hash)));
+        }
+        let path = self.path(hash);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let temp = path.with_extension(format!("tmp-{}", std::process::id()));
+        let mut file = std::fs::File::create(&temp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        std::fs::rename(&temp, &path)
+    }
+}
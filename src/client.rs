@@ -0,0 +1,2859 @@
+//! Clients for a running archive server.
+//!
+//! [`Connection`] says how to reach the server; [`Downloader`] fetches archives (resuming,
+//! retrying, splitting them over several connections and verifying them), and [`Uploader`]
+//! sends files through resumable upload sessions.
+
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::archive::{
+    extract_archive_stripped, local_manifest, ArchiveChecksum, BuildProgress, EntryKind, ExtractOptions, ExtractSummary, ManifestEntry, OverwritePolicy, ArchiveFormat, ArchiveOptions, Codec, DryRunReport, Manifest,
+    archive_file_name,
+};
+use crate::chunks::{ChunkIndex, ChunkRequest, ChunkStore};
+use crate::crypt::VerifyingKey;
+use crate::output::{self, emit};
+use crate::rate::RateLimiter;
+use crate::server::{ArchiveParts, JobFailed, JobFinished, JobQueued, JobStarted, ListEntry, SearchResults, ShareLink, ShareRequest, SyncRequest, UploadStatus, SYNC_UNCHANGED_HEADER, UPLOAD_OFFSET_HEADER};
+
+// --- Client Configuration ---
+/// Server the client talks to when none is given.
+pub const DEFAULT_SERVER_URL: &str = "http://127.0.0.1:8080";
+/// Bytes per upload request when no chunk size is given (8 MiB).
+pub const DEFAULT_UPLOAD_CHUNK_SIZE: u64 = 8 << 20;
+/// How much of the end of a partial file a resumed download fetches again, to check that the
+/// server is still sending the same bytes (64 KiB).
+pub const RESUME_OVERLAP: u64 = 64 << 10;
+/// Redirects followed per request when [`Connection::max_redirects`] is not set.
+pub const DEFAULT_MAX_REDIRECTS: usize = 10;
+/// How long the command-line client waits for the server to send anything before giving up.
+pub const DEFAULT_CLIENT_READ_TIMEOUT: Duration = Duration::from_secs(300);
+/// How long the command-line client waits for a connection to the server.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+// --------------------------
+
+/// Errors from client operations.
+pub type ClientResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+// --- CONNECTION ---
+
+/// How to reach a server: its URL, TLS trust and credentials.
+#[derive(Clone, Debug, Default)]
+pub struct Connection {
+    /// Base URL of the server (http:// or https://)
+    pub server_url: String,
+    /// Accept any TLS certificate, e.g. a self-signed one (insecure)
+    pub insecure: bool,
+    /// Additional PEM CA certificate to trust
+    pub ca_cert: Option<PathBuf>,
+    /// PEM client certificate chain and private key, for servers requiring mutual TLS
+    pub client_identity: Option<(PathBuf, PathBuf)>,
+    /// Bearer token to authenticate with
+    pub token: Option<String>,
+    /// User name and password for HTTP Basic authentication
+    pub basic_auth: Option<(String, String)>,
+    /// Speak HTTP/3 (QUIC) to the server instead of HTTP/1.1 or HTTP/2; needs the `http3` feature
+    pub http3: bool,
+    /// Proxy for every request (`http://`, `https://`, `socks5://` or `socks5h://`; a bare
+    /// `host:port` is HTTP), in place of the HTTP_PROXY/HTTPS_PROXY/ALL_PROXY variables. Hosts
+    /// in NO_PROXY still go direct.
+    pub proxy: Option<String>,
+    /// Give up on a request when the server sends nothing for this long; `None` waits forever
+    pub read_timeout: Option<Duration>,
+    /// Give up on connecting to the server after this long; `None` waits forever
+    pub connect_timeout: Option<Duration>,
+    /// How many redirects a request follows before failing (default [`DEFAULT_MAX_REDIRECTS`]);
+    /// `Some(0)` follows none. Range headers go along to the new location, credentials only
+    /// when it is on the same host and port, and https:// is never left for http://.
+    pub max_redirects: Option<usize>,
+    /// Extra headers sent with every request, e.g. ones a gateway in front of the server
+    /// requires; they replace the credentials' Authorization header when they name it too
+    pub headers: Vec<(String, String)>,
+    /// The HTTP client built on first use, shared by every clone of this connection so that
+    /// their requests reuse the same pool of open connections. Leave it at its default.
+    pub client_cache: ClientCache,
+}
+
+/// Where a [`Connection`] keeps its HTTP client once built; empty until the first request.
+#[derive(Clone, Debug, Default)]
+pub struct ClientCache(std::sync::Arc<std::sync::OnceLock<HttpClient>>);
+
+impl Connection {
+    /// A connection to `server_url` without credentials.
+    pub fn new(server_url: impl Into<String>) -> Self {
+        Connection { server_url: server_url.into(), ..Default::default() }
+    }
+
+    /// The server URL without a trailing slash, ready to have a route appended.
+    pub fn base_url(&self) -> &str {
+        self.server_url.trim_end_matches('/')
+    }
+
+    /// Whether the server is reached over TLS or with credentials of any kind, so what it
+    /// sends is not meant for everyone who can reach this machine.
+    pub fn is_protected(&self) -> bool {
+        self.base_url().to_ascii_lowercase().starts_with("https://")
+            || self.token.is_some()
+            || self.basic_auth.is_some()
+            || self.client_identity.is_some()
+            || self.headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+    }
+
+    /// The HTTP client, built on first use with the TLS options and credentials.
+    pub(crate) fn client(&self) -> ClientResult<HttpClient> {
+        if let Some(client) = self.client_cache.0.get() {
+            return Ok(client.clone());
+        }
+        let client = self.build_client()?;
+        Ok(self.client_cache.0.get_or_init(|| client).clone())
+    }
+
+    fn build_client(&self) -> ClientResult<HttpClient> {
+        check_server_url(self.base_url())?;
+        let mut builder = reqwest::Client::builder();
+
+        if let Some(ca_cert) = &self.ca_cert {
+            let pem = std::fs::read(ca_cert)
+                .map_err(|e| format!("Could not read CA certificate {}: {}", ca_cert.display(), e))?;
+            for certificate in reqwest::Certificate::from_pem_bundle(&pem)? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        if let Some((cert_path, key_path)) = &self.client_identity {
+            let read = |path: &PathBuf, what: &str| std::fs::read(path)
+                .map_err(|e| format!("Could not read client {} {}: {}", what, path.display(), e));
+            // reqwest takes the key and the certificates as one PEM bundle
+            let mut pem = read(key_path, "key")?;
+            pem.push(b'\n');
+            pem.extend(read(cert_path, "certificate")?);
+            let identity = reqwest::Identity::from_pem(&pem)
+                .map_err(|e| format!("Invalid client certificate {} or key {}: {}", cert_path.display(), key_path.display(), e))?;
+            builder = builder.identity(identity);
+        }
+        // Without an explicit proxy reqwest picks one up from the environment
+        if let Some(proxy) = &self.proxy {
+            // Like curl, a bare host:port is an HTTP proxy
+            let proxy = match proxy.contains("://") {
+                true => proxy.clone(),
+                false => format!("http://{}", proxy),
+            };
+            let scheme = proxy.split_once("://").map(|(scheme, _)| scheme.to_ascii_lowercase());
+            if !matches!(scheme.as_deref(), Some("http" | "https" | "socks5" | "socks5h")) {
+                return Err(format!("Unsupported proxy {}: use an http://, https://, socks5:// or socks5h:// URL", proxy).into());
+            }
+            let proxy = reqwest::Proxy::all(proxy.as_str())
+                .map_err(|e| format!("Invalid proxy {}: {}", proxy, e))?
+                .no_proxy(reqwest::NoProxy::from_env());
+            builder = builder.proxy(proxy);
+        }
+        builder = builder.redirect(redirect_policy(self.max_redirects.unwrap_or(DEFAULT_MAX_REDIRECTS)));
+        if let Some(timeout) = self.read_timeout {
+            builder = builder.read_timeout(timeout);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if self.insecure {
+            output::warn(format_args!("TLS certificate verification is disabled (--insecure)"));
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        if self.http3 {
+            #[cfg(feature = "http3")]
+            {
+                builder = builder.http3_prior_knowledge();
+            }
+            #[cfg(not(feature = "http3"))]
+            return Err("This build has no HTTP/3 support; rebuild with --features http3 (and RUSTFLAGS=\"--cfg reqwest_unstable\")".into());
+        }
+
+        // Credentials go on every request as a default header
+        let authorization = match (&self.token, &self.basic_auth) {
+            (Some(token), _) => Some(format!("Bearer {}", token)),
+            (None, Some((user, password))) => {
+                use base64::Engine;
+                let encoded = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password));
+                Some(format!("Basic {}", encoded))
+            }
+            _ => None,
+        };
+        let mut headers = reqwest::header::HeaderMap::new();
+        if let Some(authorization) = authorization {
+            let mut value = reqwest::header::HeaderValue::from_str(&authorization)?;
+            value.set_sensitive(true);
+            headers.insert(reqwest::header::AUTHORIZATION, value);
+        }
+        // A later header of the same name wins, so naming Authorization replaces the credentials
+        for (name, value) in &self.headers {
+            let name = reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("Invalid header name {:?}", name))?;
+            // Gateway secrets should not end up in debug output
+            let mut value = reqwest::header::HeaderValue::from_str(value)
+                .map_err(|_| format!("Invalid value for header {}", name))?;
+            value.set_sensitive(true);
+            headers.insert(name, value);
+        }
+        if !headers.is_empty() {
+            builder = builder.default_headers(headers);
+        }
+
+        // reqwest only uses HTTP/3 for requests that ask for it, so HttpClient marks each one
+        let version = self.http3.then_some(reqwest::Version::HTTP_3);
+        Ok(HttpClient { inner: builder.build()?, version })
+    }
+
+    /// Lists a directory (relative to the server root; the root itself for `None`).
+    pub async fn list(&self, path: Option<&str>) -> ClientResult<Vec<ListEntry>> {
+        let mut request = self.client()?.get(format!("{}/list", self.base_url()));
+        if let Some(path) = path {
+            request = request.query(&[("path", path)]);
+        }
+
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            return Err(server_error(response).await);
+        }
+        Ok(response.json().await?)
+    }
+
+    /// Finds the files on the server matching `query`, sorted by path.
+    pub async fn search(&self, query: &SearchQuery) -> ClientResult<SearchResults> {
+        let response = self.client()?.get(format!("{}/search", self.base_url())).query(query).send().await?;
+        if !response.status().is_success() {
+            return Err(server_error(response).await);
+        }
+        Ok(response.json().await?)
+    }
+
+    /// Asks the server what it would archive for `files`, without building anything.
+    pub async fn preview(&self, files: &[String]) -> ClientResult<DryRunReport> {
+        let preview_url = format!("{}/preview", self.base_url());
+        let response = self.client()?.get(&preview_url).query(&files_query(files)).send().await?;
+
+        if !response.status().is_success() {
+            return Err(server_error(response).await);
+        }
+        Ok(response.json().await?)
+    }
+
+    /// Fetches the manifest of the archive the server would build for `files`: every entry
+    /// with its size, modification time and SHA-256.
+    pub async fn manifest(&self, files: &[String], flatten: bool) -> ClientResult<Manifest> {
+        let manifest_url = format!("{}/manifest", self.base_url());
+        let mut request = self.client()?.get(&manifest_url).query(&files_query(files));
+        if flatten {
+            request = request.query(&[("flatten", true)]);
+        }
+        let response = request.send().await?;
+
+        if !response.status().is_success() {
+            return Err(server_error(response).await);
+        }
+        Ok(response.json().await?)
+    }
+
+    /// Asks the server for a link that downloads `files` without credentials until it expires,
+    /// at most `max_uses` times when given. The server must have authentication enabled.
+    pub async fn share(&self, files: &[String], expires_in: Duration, max_uses: Option<u32>) -> ClientResult<ShareLink> {
+        let share_url = format!("{}/share", self.base_url());
+        let body = ShareRequest { files: files.to_vec(), expires_in: expires_in.as_secs(), max_uses };
+        let response = self.client()?.post(&share_url).json(&body).send().await?;
+
+        if !response.status().is_success() {
+            return Err(server_error(response).await);
+        }
+        Ok(response.json().await?)
+    }
+
+    /// Deletes the file or directory at `path` on a server running with `--manage-files`;
+    /// directories with anything in them only when `recursive`.
+    pub async fn remove(&self, path: &str, recursive: bool) -> ClientResult<()> {
+        let url = format!("{}/files", self.base_url());
+        let response = self.client()?.request(reqwest::Method::DELETE, &url).query(&[("path", path), ("recursive", bool_param(recursive))]).send().await?;
+        self.manage_outcome(response).await
+    }
+
+    /// Moves or renames `from` to the new path `to`, replacing a file there only when `overwrite`.
+    pub async fn rename(&self, from: &str, to: &str, overwrite: bool) -> ClientResult<()> {
+        let url = format!("{}/files/move", self.base_url());
+        let response = self.client()?.post(&url).query(&[("from", from), ("to", to), ("overwrite", bool_param(overwrite))]).send().await?;
+        self.manage_outcome(response).await
+    }
+
+    /// Creates the directory `path`, and with `parents` any missing ones above it.
+    pub async fn make_dir(&self, path: &str, parents: bool) -> ClientResult<()> {
+        let url = format!("{}/files/mkdir", self.base_url());
+        let response = self.client()?.post(&url).query(&[("path", path), ("parents", bool_param(parents))]).send().await?;
+        self.manage_outcome(response).await
+    }
+
+    async fn manage_outcome(&self, response: reqwest::Response) -> ClientResult<()> {
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            // Without --manage-files there is no such route, which answers without a message
+            reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::METHOD_NOT_ALLOWED if response.content_length() == Some(0) => {
+                Err("The server does not allow managing files (start it with --manage-files)".into())
+            }
+            _ => Err(server_error(response).await),
+        }
+    }
+}
+
+fn bool_param(value: bool) -> &'static str {
+    match value {
+        true => "true",
+        false => "false",
+    }
+}
+
+/// What `/search` looks for; every filter left out matches everything.
+#[derive(Serialize, Debug, Default, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct SearchQuery {
+    /// Glob pattern matched against file names, or with a `/` against paths below the root
+    #[serde(rename = "q", skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    /// Directory below the root to search in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
+    /// A date such as `2024-01-01`, `@<unix time>` or a time ago such as `7d`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub newer_than: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub older_than: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+/// Builds the repeated `files=` query pairs understood by `/download`, `/preview` and `/manifest`.
+pub(crate) fn files_query(files: &[String]) -> Vec<(&'static str, &str)> {
+    files.iter().map(|file| ("files", file.as_str())).collect()
+}
+
+/// Where a download to `output` is written until it is complete and verified, e.g.
+/// `archive.tar.gz.part`, so an interrupted transfer never leaves a truncated file under the
+/// final name.
+pub fn part_path(output: &Path) -> PathBuf {
+    sidecar_path(output, "part")
+}
+
+/// The last `length` bytes of the file at `path`.
+async fn read_tail(path: &Path, length: u64) -> io::Result<Vec<u8>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(io::SeekFrom::End(-(length as i64))).await?;
+    let mut tail = vec![0; length as usize];
+    file.read_exact(&mut tail).await?;
+    Ok(tail)
+}
+
+/// The file name in a Content-Disposition header, preferring the UTF-8 `filename*` form. Only
+/// the last path component is kept, so a server cannot make the client write elsewhere.
+fn disposition_filename(value: &str) -> Option<String> {
+    // Parameters are separated by `;`, except inside a quoted name
+    let mut parameters = vec![String::new()];
+    let mut quoted = false;
+    for c in value.chars() {
+        match c {
+            ';' if !quoted => parameters.push(String::new()),
+            _ => {
+                quoted ^= c == '"';
+                parameters.last_mut().unwrap().push(c);
+            }
+        }
+    }
+
+    let mut plain = None;
+    let mut extended = None;
+    for parameter in parameters.iter().map(|parameter| parameter.trim()) {
+        let Some((key, value)) = parameter.split_once('=') else { continue };
+        match key.trim().to_ascii_lowercase().as_str() {
+            "filename*" => {
+                let encoded = value.get(..7).filter(|charset| charset.eq_ignore_ascii_case("UTF-8''")).map(|_| &value[7..]);
+                extended = encoded.and_then(|encoded| urlencoding::decode(encoded).ok()).map(|name| name.into_owned());
+            }
+            "filename" => plain = Some(value.trim().trim_matches('"').to_string()),
+            _ => {}
+        }
+    }
+    let name = extended.or(plain)?;
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default().trim();
+    match name {
+        "" | "." | ".." => None,
+        _ if name.chars().any(char::is_control) => None,
+        _ => Some(name.to_string()),
+    }
+}
+
+/// `dir/name`, or when a file by that name exists or is `reserved` (by other downloads about to
+/// write it), the first of `name(1)`, `name(2)`, ... that is free, numbered before the
+/// extension: `archive(1).tar.gz`. A `.part` file left by an earlier download does not count,
+/// so that download is resumed.
+pub fn numbered_path(dir: &Path, name: &str, reserved: &[PathBuf]) -> PathBuf {
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => match stem.strip_suffix(".tar") {
+            Some(base) if !base.is_empty() => (base, format!(".tar.{}", extension)),
+            _ => (stem, format!(".{}", extension)),
+        },
+        _ => (name, String::new()),
+    };
+    let mut path = dir.join(name);
+    let mut number = 1;
+    while path.exists() || reserved.contains(&path) {
+        path = dir.join(format!("{}({}){}", stem, number, extension));
+        number += 1;
+    }
+    path
+}
+
+/// A file next to `output` where the client keeps resume state, e.g. `archive.tar.gz.etag`.
+pub(crate) fn sidecar_path(output: &Path, extension: &str) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+/// A configured reqwest client that sends every request with the connection's HTTP version.
+#[derive(Clone, Debug)]
+pub(crate) struct HttpClient {
+    inner: reqwest::Client,
+    /// HTTP/3 with `Connection::http3`; otherwise negotiated as usual
+    version: Option<reqwest::Version>,
+}
+
+impl HttpClient {
+    pub(crate) fn request(&self, method: reqwest::Method, url: impl reqwest::IntoUrl) -> reqwest::RequestBuilder {
+        let request = self.inner.request(method, url);
+        match self.version {
+            Some(version) => request.version(version),
+            None => request,
+        }
+    }
+
+    pub(crate) fn get(&self, url: impl reqwest::IntoUrl) -> reqwest::RequestBuilder {
+        self.request(reqwest::Method::GET, url)
+    }
+
+    fn post(&self, url: impl reqwest::IntoUrl) -> reqwest::RequestBuilder {
+        self.request(reqwest::Method::POST, url)
+    }
+
+    fn patch(&self, url: impl reqwest::IntoUrl) -> reqwest::RequestBuilder {
+        self.request(reqwest::Method::PATCH, url)
+    }
+}
+
+/// Follows up to `max` redirects. reqwest already drops credentials when a redirect leaves the
+/// host, but not when it only drops TLS, which would send them in the clear.
+fn redirect_policy(max: usize) -> reqwest::redirect::Policy {
+    if max == 0 {
+        return reqwest::redirect::Policy::none();
+    }
+    reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() > max {
+            return attempt.error(format!("more than {} redirects (see --max-redirects)", max));
+        }
+        let downgraded = attempt.previous().last().is_some_and(|previous| previous.scheme() == "https") && attempt.url().scheme() == "http";
+        if downgraded {
+            let refusal = format!("refusing to follow a redirect from https:// to {}", attempt.url());
+            return attempt.error(refusal);
+        }
+        attempt.follow()
+    })
+}
+
+/// Rejects IPv6 literals written without the brackets URLs require (`http://::1:8080`), which
+/// would otherwise fail with a confusing "invalid port" error or reach the wrong port.
+fn check_server_url(server_url: &str) -> ClientResult<()> {
+    let authority = server_url.split_once("://").map_or(server_url, |(_, rest)| rest);
+    let host_port = authority.split('/').next().unwrap_or_default();
+    let host_port = host_port.rsplit_once('@').map_or(host_port, |(_, host_port)| host_port);
+    if !host_port.starts_with('[') && host_port.matches(':').count() > 1 {
+        return Err(format!("IPv6 addresses in server URLs must be in brackets, e.g. http://[::1]:8080 (got {})", server_url).into());
+    }
+    Ok(())
+}
+
+// --- DOWNLOADER ---
+
+/// Downloads an archive from a server into a file.
+///
+/// ```no_run
+/// use rust_file_archive_server::client::{Connection, Downloader};
+///
+/// # async fn fetch() -> rust_file_archive_server::client::ClientResult<()> {
+/// let downloader = Downloader::new(Connection::new("http://192.168.1.32:8080"))
+///     .file("report.pdf")
+///     .connections(4);
+/// downloader.download(&downloader.default_output()).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Downloader {
+    connection: Connection,
+    files: Vec<String>,
+    format: ArchiveFormat,
+    compression: Option<Codec>,
+    level: Option<i32>,
+    flatten: bool,
+    connections: u16,
+    retries: u32,
+    retry_delay: Duration,
+    verify: bool,
+    verify_key: Option<VerifyingKey>,
+    /// Shared by every attempt and segment, so --limit-rate caps the whole download
+    rate_limit: Option<std::sync::Arc<RateLimiter>>,
+    background_build: bool,
+    /// The background job whose archive is being fetched
+    job: Option<String>,
+    join_parts: bool,
+    /// The part of a split archive being fetched
+    part: Option<u32>,
+    chunk_store: Option<PathBuf>,
+    name: Option<String>,
+    /// Of the download in progress, shared with the downloaders of its parts
+    stats: TransferStats,
+}
+
+impl Downloader {
+    /// Downloads the server's default file set as a tarball in the server's compression,
+    /// over one connection, with 5 retries and a checksum check at the end.
+    pub fn new(connection: Connection) -> Self {
+        Downloader {
+            connection,
+            files: Vec::new(),
+            format: ArchiveFormat::default(),
+            compression: None,
+            level: None,
+            flatten: false,
+            connections: 1,
+            retries: 5,
+            retry_delay: Duration::from_secs(1),
+            verify: true,
+            verify_key: None,
+            rate_limit: None,
+            background_build: false,
+            job: None,
+            join_parts: false,
+            part: None,
+            chunk_store: None,
+            name: None,
+            stats: TransferStats::default(),
+        }
+    }
+
+    /// Requests a file, relative to the server root.
+    pub fn file(mut self, path: impl Into<String>) -> Self {
+        self.files.push(path.into());
+        self
+    }
+
+    /// Requests several files, relative to the server root.
+    pub fn files<I, S>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.files.extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn format(mut self, format: ArchiveFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Tarball compression to request instead of the server's default.
+    pub fn compression(mut self, codec: Codec) -> Self {
+        self.compression = Some(codec);
+        self
+    }
+
+    /// Compression level to request instead of the server's default.
+    pub fn level(mut self, level: i32) -> Self {
+        self.level = Some(level);
+        self
+    }
+
+    /// Asks the server to store every file under its base name instead of keeping the
+    /// structure of requested directories.
+    pub fn flatten(mut self, flatten: bool) -> Self {
+        self.flatten = flatten;
+        self
+    }
+
+    /// Splits the download into up to this many concurrent byte ranges.
+    pub fn connections(mut self, connections: u16) -> Self {
+        self.connections = connections.max(1);
+        self
+    }
+
+    /// Reconnects up to `retries` times after transient failures.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Wait before the first reconnect; it doubles after each failed attempt.
+    pub fn retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Whether to check the finished file against the server's /checksum.
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Requires the archive to carry a valid signature by this key (from the server's
+    /// /download.sig), checked once the download is complete.
+    pub fn verify_key(mut self, key: VerifyingKey) -> Self {
+        self.verify_key = Some(key);
+        self
+    }
+
+    /// Caps the download at `bytes_per_second` by reading the response no faster, across
+    /// all connections together.
+    pub fn limit_rate(mut self, bytes_per_second: u64) -> Self {
+        self.rate_limit = Some(std::sync::Arc::new(RateLimiter::new(bytes_per_second)));
+        self
+    }
+
+    /// Has the server build the archive as a background job first and shows how far it has
+    /// got, instead of waiting on a silent connection while a large archive is put together.
+    pub fn background_build(mut self, background_build: bool) -> Self {
+        self.background_build = background_build;
+        self
+    }
+
+    /// The name the server should suggest for the archive (and [`default_output`](Self::default_output)
+    /// use), instead of one derived from the requested files.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Joins the parts of an archive the server splits (with --split-size) into the output file
+    /// once they are all downloaded, instead of leaving them next to it as `<output>.001`, ...
+    pub fn join_parts(mut self, join_parts: bool) -> Self {
+        self.join_parts = join_parts;
+        self
+    }
+
+    /// Downloads by way of a [`ChunkStore`] in `dir`, from a server running with --chunked:
+    /// only the chunks of the archive not already in the store are transferred, and they are
+    /// kept there for next time. Unless [`compression`](Self::compression) says otherwise, the
+    /// archive is requested uncompressed, since compressing it would make every chunk after
+    /// the first change differ; the chunks are compressed on the way instead.
+    pub fn chunk_store(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.chunk_store = Some(dir.into());
+        self
+    }
+
+    /// The compression to ask the server for, if not its default.
+    fn codec(&self) -> Option<Codec> {
+        self.compression.or(self.chunk_store.as_ref().map(|_| Codec::Store))
+    }
+
+    /// The name the server suggests for the archive, with the requested format's extension so
+    /// a zip never lands in a .tar.gz: see [`archive_file_name`].
+    pub fn default_output(&self) -> PathBuf {
+        PathBuf::from(archive_file_name(&self.files, self.name.as_deref(), &self.extension()))
+    }
+
+    /// The file name the server suggests for the archive in the Content-Disposition header of
+    /// a HEAD request, if it sends one. It knows the server's default compression, which
+    /// [`default_output`](Self::default_output) can only guess. Background builds are not
+    /// asked, since answering would mean building the archive up front.
+    pub async fn suggested_name(&self) -> ClientResult<Option<String>> {
+        if self.background_build && self.job.is_none() {
+            return Ok(None);
+        }
+        let client = self.connection.client()?;
+        let response = self.request(&client, reqwest::Method::HEAD, "download").send().await?;
+        if !response.status().is_success() {
+            return Err(server_error(response).await);
+        }
+        Ok(response.headers().get(reqwest::header::CONTENT_DISPOSITION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(disposition_filename))
+    }
+
+    /// A client for the downloader's connection.
+    pub(crate) fn http_client(&self) -> ClientResult<HttpClient> {
+        self.connection.client()
+    }
+
+    pub(crate) fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// The file extension of the requested format, e.g. `tar.gz`.
+    fn extension(&self) -> String {
+        let codec = self.codec().unwrap_or_default();
+        ArchiveOptions { format: self.format, codec, level: codec.default_level(), preserve_metadata: true, xattrs: false, threads: 1, mtime_clamp: None }.extension()
+    }
+
+    /// Downloads the archive into `output`, resuming the `.part` file (see [`part_path`]) left
+    /// by an earlier attempt, retrying transient failures and verifying the checksum before the
+    /// file is renamed to `output`. Ends with a `transfer_summary` of the whole download.
+    pub async fn download(&self, output: &Path) -> ClientResult<()> {
+        let downloader = Downloader { stats: TransferStats::default(), ..self.clone() };
+        match downloader.background_build && downloader.job.is_none() {
+            true => {
+                let job = with_retries(&downloader.stats, self.retries, self.retry_delay, || downloader.build_in_background()).await?;
+                Downloader { job: Some(job), ..downloader.clone() }.fetch(output).await?;
+            }
+            false => downloader.fetch(output).await?,
+        }
+        downloader.stats.summarize("download");
+        Ok(())
+    }
+
+    async fn fetch(&self, output: &Path) -> ClientResult<()> {
+        if let Some(dir) = &self.chunk_store {
+            return self.fetch_chunked(output, dir).await;
+        }
+        match self.parts_index().await? {
+            Some(index) => self.fetch_parts(output, index).await,
+            None => self.fetch_file(output).await,
+        }
+    }
+
+    /// Downloads the archive, or the one part of it this downloader is for, into `output` by
+    /// way of its `.part` file.
+    async fn fetch_file(&self, output: &Path) -> ClientResult<()> {
+        let partial = part_path(output);
+        with_retries(&self.stats, self.retries, self.retry_delay, || async {
+            if self.connections > 1 || sidecar_path(&partial, "segments").exists() {
+                self.download_segmented(&partial).await
+            } else {
+                self.download_once(&partial).await
+            }
+        }).await?;
+
+        if self.verify {
+            self.verify_checksum(&partial).await?;
+        }
+        if let Some(key) = &self.verify_key {
+            self.verify_signature(&partial, key).await?;
+        }
+
+        tokio::fs::rename(&partial, output).await?;
+        let size = tokio::fs::metadata(output).await?.len();
+        emit("download_complete", serde_json::json!({ "path": output, "bytes": size }),
+            format_args!("✅ Download complete: {} ({} bytes)", output.display(), size));
+        Ok(())
+    }
+
+    /// A request to `endpoint` (e.g. `download`) about the archive this downloader fetches.
+    pub(crate) fn request(&self, client: &HttpClient, method: reqwest::Method, endpoint: &str) -> reqwest::RequestBuilder {
+        let mut request = client.request(method, format!("{}/{}", self.connection.base_url(), endpoint))
+            .query(&files_query(&self.files))
+            .query(&[("format", self.format)]);
+        if let Some(codec) = self.codec() {
+            request = request.query(&[("compression", codec)]);
+        }
+        if let Some(level) = self.level {
+            request = request.query(&[("level", level)]);
+        }
+        if self.flatten {
+            request = request.query(&[("flatten", true)]);
+        }
+        if let Some(job) = &self.job {
+            request = request.query(&[("job", job)]);
+        }
+        if let Some(part) = self.part {
+            request = request.query(&[("part", part)]);
+        }
+        if let Some(name) = &self.name {
+            request = request.query(&[("name", name)]);
+        }
+        request
+    }
+
+    /// Starts a background build and follows its progress events until the archive is
+    /// ready, returning the job's ID.
+    async fn build_in_background(&self) -> ClientResult<String> {
+        let client = self.connection.client()?;
+        let response = self.request(&client, reqwest::Method::GET, "download").query(&[("async", "1")]).send().await?;
+        if response.status() != reqwest::StatusCode::ACCEPTED {
+            return Err(server_error(response).await);
+        }
+        let started: JobStarted = response.json().await?;
+        emit("build_started", serde_json::json!({ "job_id": started.job_id }),
+            format_args!("🛠️  The server is building the archive (job {})", started.job_id));
+
+        let response = client.get(format!("{}{}", self.connection.base_url(), started.progress_url)).send().await?;
+        if !response.status().is_success() {
+            return Err(server_error(response).await);
+        }
+
+        let mut progress: Option<TransferProgress> = None;
+        let mut queued_at = None;
+        let mut events = ServerEvents::default();
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            events.push(&chunk?);
+            while let Some((event, data)) = events.next_event() {
+                match event.as_str() {
+                    "queued" => {
+                        let queued: JobQueued = serde_json::from_str(&data)?;
+                        if queued_at != Some(queued.position) {
+                            queued_at = Some(queued.position);
+                            emit("build_queued", serde_json::json!({ "job_id": started.job_id, "position": queued.position }),
+                                format_args!("⏳ Waiting for the server to start building (number {} in line)", queued.position));
+                        }
+                    }
+                    "progress" => {
+                        let report: BuildProgress = serde_json::from_str(&data)?;
+                        let progress = progress.get_or_insert_with(|| transfer_progress("🛠️", "build", Some(report.bytes_total), 0));
+                        progress.set_length(report.bytes_total);
+                        progress.set_position(report.bytes_processed);
+                    }
+                    "done" => {
+                        let finished: JobFinished = serde_json::from_str(&data)?;
+                        if let Some(progress) = progress.take() {
+                            progress.finish_and_clear();
+                        }
+                        emit("build_complete", serde_json::json!({ "job_id": started.job_id, "bytes": finished.size }),
+                            format_args!("📦 Archive built ({} bytes)", finished.size));
+                        return Ok(started.job_id);
+                    }
+                    "failed" => {
+                        let failed: JobFailed = serde_json::from_str(&data)?;
+                        let status = reqwest::StatusCode::from_u16(failed.status).unwrap_or(reqwest::StatusCode::INTERNAL_SERVER_ERROR);
+                        return Err(Box::new(ServerError { status, message: failed.message, retry_after: None }));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::UnexpectedEof, "progress stream ended before the archive was built").into())
+    }
+
+    /// Downloads the archive into `output` over one connection, resuming from the end of an
+    /// existing partial file.
+    /// The partial file's ETag is sent as If-Range, so a changed archive is restarted instead
+    /// of having its bytes appended to the old ones. A server (or proxy) ignoring If-Range is
+    /// caught too: a partial answer whose ETag or total size differs from those recorded on the
+    /// first attempt also restarts the download. The request also starts [`RESUME_OVERLAP`]
+    /// bytes early, and the download restarts from scratch unless those bytes match the end of
+    /// the partial file, which catches an archive rebuilt differently under the same ETag.
+    async fn download_once(&self, output: &Path) -> ClientResult<()> {
+        // 1. A partial file from an earlier attempt means we only ask for the remaining bytes
+        let resume_from = tokio::fs::metadata(output).await.map(|m| m.len()).unwrap_or(0);
+        let overlap = resume_from.min(RESUME_OVERLAP);
+
+        let client = self.connection.client()?;
+        let etag_path = sidecar_path(output, "etag");
+        let size_path = sidecar_path(output, "size");
+        let mut request = self.request(&client, reqwest::Method::GET, "download");
+        if resume_from > 0 {
+            self.stats.resumed();
+
+            emit("resuming", serde_json::json!({ "path": output, "offset": resume_from }),
+                format_args!("⏯️  Resuming {} from byte {}", output.display(), resume_from));
+            request = request.header(reqwest::header::RANGE, format!("bytes={}-", resume_from - overlap));
+            if let Ok(etag) = tokio::fs::read_to_string(&etag_path).await {
+                request = request.header(reqwest::header::IF_RANGE, etag.trim());
+            }
+        }
+
+        let response = request.send().await?;
+
+        // 2. Decide whether to append to or replace the local file based on the status
+        let append = match response.status() {
+            reqwest::StatusCode::PARTIAL_CONTENT => true,
+            reqwest::StatusCode::OK => {
+                if resume_from > 0 {
+                    emit("restarting", serde_json::json!({ "path": output, "reason": "archive changed" }),
+                        format_args!("🔄 The archive on the server changed, restarting the download from scratch"));
+                }
+                false
+            }
+            reqwest::StatusCode::RANGE_NOT_SATISFIABLE if resume_from > 0 => {
+                emit("already_downloaded", serde_json::json!({ "path": output, "bytes": resume_from }),
+                    format_args!("ℹ️  {} was already fully downloaded ({} bytes)", output.display(), resume_from));
+                let _ = tokio::fs::remove_file(&etag_path).await;
+                let _ = tokio::fs::remove_file(&size_path).await;
+                return Ok(());
+            }
+            _ => return Err(server_error(response).await),
+        };
+
+        let etag = response.headers().get(reqwest::header::ETAG).and_then(|value| value.to_str().ok()).map(str::to_string);
+        if append && !same_archive(&response, resume_from - overlap, etag.as_deref(), &etag_path, &size_path).await {
+            emit("restarting", serde_json::json!({ "path": output, "reason": "archive changed" }),
+                format_args!("🔄 The archive on the server changed, restarting the download from scratch"));
+            drop(response);
+            tokio::fs::remove_file(output).await?;
+            let _ = tokio::fs::remove_file(&etag_path).await;
+            let _ = tokio::fs::remove_file(&size_path).await;
+            return Box::pin(self.download_once(output)).await;
+        }
+
+        let mut downloaded = if append { resume_from - overlap } else { 0 };
+        let total_size = response.content_length().map(|len| len + downloaded);
+        let mut stream = response.bytes_stream();
+
+        // 3. The overlap must repeat the end of the partial file before anything is appended to it
+        let mut rest = bytes::Bytes::new();
+        if append && overlap > 0 {
+            let expected = read_tail(output, overlap).await?;
+            let mut received = Vec::with_capacity(expected.len());
+            while received.len() < expected.len() {
+                match stream.next().await {
+                    Some(chunk) => received.extend_from_slice(&chunk?),
+                    None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before the download finished").into()),
+                }
+            }
+            rest = bytes::Bytes::from(received.split_off(expected.len()));
+            if received != expected {
+                emit("restarting", serde_json::json!({ "path": output, "reason": "partial file differs" }),
+                    format_args!("🔄 The end of {} does not match the archive on the server, restarting the download from scratch", output.display()));
+                drop(stream);
+                tokio::fs::remove_file(output).await?;
+                let _ = tokio::fs::remove_file(&etag_path).await;
+                let _ = tokio::fs::remove_file(&size_path).await;
+                return Box::pin(self.download_once(output)).await;
+            }
+            downloaded += overlap;
+        }
+        let progress = transfer_progress("📥", "download", total_size, downloaded).counting(&self.stats);
+
+        // Remember which archive these bytes belong to in case this attempt is interrupted too
+        match etag {
+            Some(etag) => tokio::fs::write(&etag_path, etag).await?,
+            None => {
+                let _ = tokio::fs::remove_file(&etag_path).await;
+            }
+        }
+        match total_size {
+            Some(size) => tokio::fs::write(&size_path, size.to_string()).await?,
+            None => {
+                let _ = tokio::fs::remove_file(&size_path).await;
+            }
+        }
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(output)
+            .await?;
+
+        // 4. Stream the body to disk chunk by chunk
+        if !rest.is_empty() {
+            file.write_all(&rest).await?;
+            downloaded += rest.len() as u64;
+            progress.set_position(downloaded);
+        }
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            self.pace(chunk.len()).await;
+            file.write_all(&chunk).await?;
+            downloaded += chunk.len() as u64;
+            progress.set_position(downloaded);
+        }
+        file.flush().await?;
+        if total_size.is_some_and(|total| downloaded < total) {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before the download finished").into());
+        }
+        let _ = tokio::fs::remove_file(&etag_path).await;
+        let _ = tokio::fs::remove_file(&size_path).await;
+
+        progress.finish_and_clear();
+        Ok(())
+    }
+
+    /// Downloads the archive over several concurrent connections when the server allows it,
+    /// otherwise falls back to `download_once`. A HEAD request supplies the size and ETag; each
+    /// segment is fetched with its own Range request guarded by If-Range and written in place
+    /// into a preallocated file, and progress is saved so an interrupted run picks up where it stopped.
+    async fn download_segmented(&self, output: &Path) -> ClientResult<()> {
+        let client = self.connection.client()?;
+        let state_path = sidecar_path(output, "segments");
+
+        // 1. A partial single-connection download is finished the way it was started
+        let previous: Option<SegmentedState> = tokio::fs::read(&state_path).await.ok()
+            .and_then(|data| serde_json::from_slice(&data).ok());
+        if previous.is_none() && tokio::fs::metadata(output).await.map(|m| m.len() > 0).unwrap_or(false) {
+            return self.download_once(output).await;
+        }
+
+        // 2. Learn the size and identity of the archive without downloading it
+        let head = self.request(&client, reqwest::Method::HEAD, "download").send().await?;
+        if !head.status().is_success() {
+            return Err(server_error(head).await);
+        }
+        let header = |name: reqwest::header::HeaderName| {
+            head.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string)
+        };
+        let size = header(reqwest::header::CONTENT_LENGTH).and_then(|len| len.parse::<u64>().ok());
+        let ranged = header(reqwest::header::ACCEPT_RANGES).as_deref() == Some("bytes");
+        let (Some(size), Some(etag), true) = (size, header(reqwest::header::ETAG), ranged) else {
+            emit("segments_unsupported", serde_json::json!({}),
+                format_args!("ℹ️  The server does not support segmented downloads, using a single connection"));
+            return self.download_once(output).await;
+        };
+
+        let segments = match previous {
+            Some(state) if state.etag == etag && state.size == size => {
+                self.stats.resumed();
+
+                emit("resuming", serde_json::json!({ "path": output, "offset": state.received(), "remaining": size - state.received() }),
+                    format_args!("⏯️  Resuming {} ({} bytes left)", output.display(), size - state.received()));
+                state.segments
+            }
+            stale => {
+                if stale.is_some() {
+                    emit("restarting", serde_json::json!({ "path": output, "reason": "archive changed" }),
+                        format_args!("🔄 The archive on the server changed, restarting the download from scratch"));
+                }
+                let file = tokio::fs::File::create(output).await?;
+                file.set_len(size).await?;
+                plan_segments(size, self.connections)
+            }
+        };
+        if segments.len() < 2 && segments.iter().all(|s| s.received == 0) {
+            tokio::fs::remove_file(output).await?;
+            return self.download_once(output).await;
+        }
+        emit("segmented_download", serde_json::json!({ "bytes": size, "connections": segments.len() }),
+            format_args!("🔀 Downloading {} bytes over {} connections", size, segments.len()));
+
+        // 3. Fetch all segments concurrently, saving progress as we go
+        let received: Vec<std::sync::atomic::AtomicU64> = segments.iter()
+            .map(|segment| std::sync::atomic::AtomicU64::new(segment.received))
+            .collect();
+        let snapshot = || SegmentedState {
+            etag: etag.clone(),
+            size,
+            segments: segments.iter().zip(&received)
+                .map(|(segment, done)| Segment { received: done.load(std::sync::atomic::Ordering::Relaxed), ..*segment })
+                .collect(),
+        };
+
+        tokio::fs::write(&state_path, serde_json::to_vec(&snapshot())?).await?;
+
+        let downloads = futures_util::future::try_join_all(segments.iter().zip(&received)
+            .map(|(segment, done)| self.download_segment(&client, output, &etag, *segment, done)));
+        tokio::pin!(downloads);
+
+        let progress = transfer_progress("📥", "download", Some(size), snapshot().received()).counting(&self.stats);
+        let mut ticker = tokio::time::interval(SEGMENT_PROGRESS_INTERVAL);
+        let result = loop {
+            tokio::select! {
+                result = &mut downloads => break result,
+                _ = ticker.tick() => {
+                    let state = snapshot();
+                    progress.set_position(state.received());
+                    tokio::fs::write(&state_path, serde_json::to_vec(&state)?).await?;
+                }
+            }
+        };
+
+        // 4. Keep the resume state on failure; if the archive changed, its stale ETag makes the next run start over
+        let state = snapshot();
+        progress.set_position(state.received());
+        if let Err(e) = result {
+            tokio::fs::write(&state_path, serde_json::to_vec(&state)?).await?;
+            return Err(e);
+        }
+        progress.finish_and_clear();
+        let _ = tokio::fs::remove_file(&state_path).await;
+        Ok(())
+    }
+
+    /// Holds off reading more of a response until `bytes` fit under --limit-rate. Reading
+    /// slower lets TCP flow control slow the server down too.
+    async fn pace(&self, bytes: usize) {
+        if let Some(rate_limit) = &self.rate_limit {
+            rate_limit.pace(bytes).await;
+        }
+    }
+
+    /// Fetches the rest of one segment and writes it at its offset in `output`, counting
+    /// bytes in `received` only once they have been handed to the file.
+    async fn download_segment(
+        &self,
+        client: &HttpClient,
+        output: &Path,
+        etag: &str,
+        segment: Segment,
+        received: &std::sync::atomic::AtomicU64,
+    ) -> ClientResult<()> {
+        let offset = segment.start + segment.received;
+        if offset > segment.end {
+            return Ok(());
+        }
+
+        let response = self.request(client, reqwest::Method::GET, "download")
+            .header(reqwest::header::RANGE, format!("bytes={}-{}", offset, segment.end))
+            .header(reqwest::header::IF_RANGE, etag)
+            .send()
+            .await?;
+        match response.status() {
+            reqwest::StatusCode::PARTIAL_CONTENT => {}
+            reqwest::StatusCode::OK => {
+                return Err("the archive on the server changed during the download; run the command again to start over".into());
+            }
+            _ => return Err(server_error(response).await),
+        }
+
+        let mut file = tokio::fs::OpenOptions::new().write(true).open(output).await?;
+        file.seek(io::SeekFrom::Start(offset)).await?;
+
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            self.pace(chunk.len()).await;
+            file.write_all(&chunk).await?;
+            // Wait for the write to land so saved progress never runs ahead of the file
+            file.flush().await?;
+            received.fetch_add(chunk.len() as u64, std::sync::atomic::Ordering::Relaxed);
+        }
+
+        let done = received.load(std::sync::atomic::Ordering::Relaxed);
+        if segment.start + done <= segment.end {
+            let message = format!("connection closed early in bytes {}-{}", segment.start, segment.end);
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, message).into());
+        }
+        Ok(())
+    }
+
+    /// Compares the SHA-256 of the downloaded file with the server's /checksum. A mismatch
+    /// deletes the file, since resuming a corrupt download would only keep the corruption.
+    async fn verify_checksum(&self, output: &Path) -> ClientResult<()> {
+        let Some(expected) = self.expected_checksum().await? else { return Ok(()) };
+        let actual = file_sha256(output).await?;
+
+        if actual != expected.sha256 {
+            tokio::fs::remove_file(output).await?;
+            return Err(format!(
+                "checksum mismatch for {} (expected {}, got {}); the file was deleted, download it again",
+                output.display(), expected.sha256, actual
+            ).into());
+        }
+
+        self.stats.checksum(Verification::Verified);
+
+        emit("checksum_verified", serde_json::json!({ "path": output, "sha256": actual }),
+            format_args!("🔒 Verified SHA-256 {}", actual));
+        Ok(())
+    }
+
+    /// The server's /checksum for the archive, or `None` (after saying so) when it publishes none.
+    async fn expected_checksum(&self) -> ClientResult<Option<ArchiveChecksum>> {
+        let client = self.connection.client()?;
+        let response = self.request(&client, reqwest::Method::GET, "checksum").send().await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND && response.headers().get(reqwest::header::ETAG).is_none() {
+            self.stats.checksum(Verification::Unavailable);
+            emit("checksum_unavailable", serde_json::json!({}),
+                format_args!("ℹ️  The server does not publish checksums, skipping verification"));
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(server_error(response).await);
+        }
+        Ok(Some(response.json().await?))
+    }
+
+    /// Checks the server's /download.sig for the archive against `key`. A bad or missing
+    /// signature deletes the file, so an archive of unknown origin is never left behind.
+    async fn verify_signature(&self, output: &Path, key: &VerifyingKey) -> ClientResult<()> {
+        let actual = file_sha256(output).await?;
+        if let Err(e) = self.check_signature(output, &actual, key).await {
+            tokio::fs::remove_file(output).await?;
+            return Err(format!("{}; the file was deleted", e).into());
+        }
+
+        self.stats.signature_verified();
+
+        emit("signature_verified", serde_json::json!({ "path": output }), format_args!("✍️  Verified signature"));
+        Ok(())
+    }
+
+    /// Checks the server's /download.sig against `key` for an archive with SHA-256 `digest`,
+    /// saved as `output`.
+    async fn check_signature(&self, output: &Path, digest: &str, key: &VerifyingKey) -> Result<(), String> {
+        let client = self.connection.client().map_err(|e| e.to_string())?;
+        let response = self.request(&client, reqwest::Method::GET, "download.sig").send().await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("could not get the archive's signature ({})", server_error(response).await));
+        }
+        let signature = response.bytes().await.map_err(|e| e.to_string())?;
+
+        crate::crypt::verify_digest_signature(key, digest, &signature)
+            .map_err(|e| format!("signature check failed for {}: {}", output.display(), e))
+    }
+}
+
+// --- STREAMING EXTRACTION ---
+
+/// How far a streamed download has got, carried over from one attempt to the next.
+#[derive(Default)]
+struct StreamedDownload {
+    received: u64,
+    /// Of the archive being received, so a resumed request only continues that one
+    etag: Option<String>,
+    hasher: sha2::Sha256,
+    progress: Option<TransferProgress>,
+    /// The consumer stopped reading (it failed, and says why)
+    abandoned: bool,
+    /// What has been done with the bytes so far, for when the download cannot be continued
+    received_so_far: &'static str,
+}
+
+impl Downloader {
+    /// Downloads the archive and unpacks it into `dest` as it arrives, so the archive is never
+    /// stored and needs no disk space of its own. Only tarballs can be read this way. After a
+    /// network error the same archive continues with a Range request; if the server's files
+    /// changed in the meantime the download fails instead, since the files already written
+    /// cannot be matched up with a new archive. The SHA-256 is computed on the way and checked
+    /// against /checksum (and the signature against `verify_key`) at the end.
+    pub async fn download_extracting(&self, dest: &Path, options: ExtractOptions) -> ClientResult<ExtractSummary> {
+        let downloader = Downloader { stats: TransferStats::default(), ..self.clone() };
+        let summary = downloader.extract_streamed(dest, options).await?;
+        downloader.stats.summarize("download");
+        Ok(summary)
+    }
+
+    async fn extract_streamed(&self, dest: &Path, options: ExtractOptions) -> ClientResult<ExtractSummary> {
+        if self.format == ArchiveFormat::Zip {
+            return Err("Zip archives cannot be unpacked as they arrive; use a tarball".into());
+        }
+        if self.background_build && self.job.is_none() {
+            let job = with_retries(&self.stats, self.retries, self.retry_delay, || self.build_in_background()).await?;
+            return Box::pin(Downloader { job: Some(job), ..self.clone() }.extract_streamed(dest, options)).await;
+        }
+
+        let target = dest.to_path_buf();
+        let (unpacked, actual) = self.stream_through("the files unpacked so far", move |reader| {
+            let summary = crate::archive::extract_tar_stream(&mut *reader, &target, &options)?;
+            // Padding after the end of the tarball still counts towards the checksum
+            io::copy(reader, &mut io::sink())?;
+            Ok(summary)
+        }).await?;
+        let summary = unpacked.map_err(|e| format!("Failed to extract into {}: {}", dest.display(), e))?;
+
+        let damaged = format!("the files unpacked into {} may be damaged, download them again", dest.display());
+        let untrusted = format!("do not trust the files unpacked into {}", dest.display());
+        self.verify_streamed(&actual, dest, serde_json::json!({ "dest": dest }), &damaged, &untrusted).await?;
+        Ok(summary)
+    }
+
+    /// Downloads the archive into `writer` as it arrives, e.g. stdout for a pipe, and returns
+    /// its size. Interrupted downloads continue as [`Downloader::download_extracting`] does, and
+    /// the SHA-256 is checked at the end; by then the bytes have been written, so a mismatch
+    /// can only be reported.
+    pub async fn download_into<W: io::Write + Send + 'static>(&self, writer: W) -> ClientResult<u64> {
+        let downloader = Downloader { stats: TransferStats::default(), ..self.clone() };
+        let size = downloader.write_streamed(writer).await?;
+        downloader.stats.summarize("download");
+        Ok(size)
+    }
+
+    async fn write_streamed<W: io::Write + Send + 'static>(&self, mut writer: W) -> ClientResult<u64> {
+        if self.background_build && self.job.is_none() {
+            let job = with_retries(&self.stats, self.retries, self.retry_delay, || self.build_in_background()).await?;
+            return Box::pin(Downloader { job: Some(job), ..self.clone() }.write_streamed(writer)).await;
+        }
+
+        let (written, actual) = self.stream_through("what was written so far", move |reader| {
+            let size = io::copy(reader, &mut writer)?;
+            writer.flush()?;
+            Ok(size)
+        }).await?;
+        let size = written.map_err(|e| format!("Failed to write the archive: {}", e))?;
+
+        let (damaged, untrusted) = ("the archive that was written is damaged", "do not trust the archive that was written");
+        self.verify_streamed(&actual, Path::new("the archive"), serde_json::json!({}), damaged, untrusted).await?;
+        Ok(size)
+    }
+
+    /// Downloads the archive through `consume`, which reads it on a blocking thread as it
+    /// arrives, continuing after network errors with Range requests. Returns what `consume`
+    /// made of it and the archive's SHA-256. A download that cannot be continued fails, since
+    /// what `consume` did with the bytes so far (`received`, for the message) cannot be undone.
+    async fn stream_through<T, F>(&self, received: &'static str, consume: F) -> ClientResult<(io::Result<T>, String)>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut ChunkReader) -> io::Result<T> + Send + 'static,
+    {
+        // The archive goes from this task to the one consuming it through a few chunks of buffer
+        let (sender, receiver) = tokio::sync::mpsc::channel(16);
+        let consuming = tokio::task::spawn_blocking(move || {
+            let mut reader = ChunkReader { receiver, chunk: bytes::Bytes::new() };
+            consume(&mut reader)
+        });
+
+        let state = std::sync::Mutex::new(StreamedDownload { received_so_far: received, ..Default::default() });
+        let fetched = with_retries(&self.stats, self.retries, self.retry_delay, || self.stream_once(&state, &sender)).await;
+        if let Err(e) = &fetched {
+            let _ = sender.send(Err(io::Error::other(e.to_string()))).await;
+        }
+        drop(sender);
+        let consumed = consuming.await?;
+
+        let state = state.into_inner().unwrap_or_else(|e| e.into_inner());
+        if let Some(progress) = &state.progress {
+            progress.finish_and_clear();
+        }
+        match fetched {
+            Err(e) if !state.abandoned => Err(e),
+            _ => Ok((consumed, crate::archive::hex_digest(&sha2::Digest::finalize(state.hasher)))),
+        }
+    }
+
+    /// Checks a streamed archive's SHA-256 `actual` against /checksum and its signature against
+    /// `verify_key`, saying in `damaged` or `untrusted` what a mismatch means for what was made
+    /// of it (`shown`). `fields` go into the JSON lines reporting success.
+    async fn verify_streamed(&self, actual: &str, shown: &Path, fields: serde_json::Value, damaged: &str, untrusted: &str) -> ClientResult<()> {
+        if self.verify {
+            if let Some(expected) = self.expected_checksum().await? {
+                if actual != expected.sha256 {
+                    return Err(format!("checksum mismatch (expected {}, got {}); {}", expected.sha256, actual, damaged).into());
+                }
+                self.stats.checksum(Verification::Verified);
+
+                let mut fields = fields.clone();
+                fields["sha256"] = serde_json::Value::from(actual);
+                emit("checksum_verified", fields, format_args!("🔒 Verified SHA-256 {}", actual));
+            }
+        }
+        if let Some(key) = &self.verify_key {
+            self.check_signature(shown, actual, key).await
+                .map_err(|e| format!("{}; {}", e, untrusted))?;
+            self.stats.signature_verified();
+
+            emit("signature_verified", fields, format_args!("✍️  Verified signature"));
+        }
+        Ok(())
+    }
+
+    /// One request for the (rest of the) archive, handing every chunk to the consumer.
+    async fn stream_once(&self, state: &std::sync::Mutex<StreamedDownload>, sender: &tokio::sync::mpsc::Sender<io::Result<bytes::Bytes>>) -> ClientResult<()> {
+        let (offset, etag) = {
+            let state = state.lock().unwrap();
+            (state.received, state.etag.clone())
+        };
+        let client = self.connection.client()?;
+        let mut request = self.request(&client, reqwest::Method::GET, "download");
+        if offset > 0 {
+            let etag = etag.ok_or("The server sent no ETag, so the interrupted download cannot be continued")?;
+            self.stats.resumed();
+
+            emit("resuming", serde_json::json!({ "offset": offset }),
+                format_args!("⏯️  Resuming the download from byte {}", offset));
+            request = request
+                .header(reqwest::header::RANGE, format!("bytes={}-", offset))
+                .header(reqwest::header::IF_RANGE, etag);
+        }
+
+        let response = request.send().await?;
+        match response.status() {
+            reqwest::StatusCode::OK if offset == 0 => {}
+            reqwest::StatusCode::PARTIAL_CONTENT if offset > 0 => {}
+            reqwest::StatusCode::OK => {
+                let received = state.lock().unwrap().received_so_far;
+                return Err(format!("The archive changed on the server during the download; {} may be inconsistent, download it again", received).into());
+            }
+            _ => return Err(server_error(response).await),
+        }
+
+        let total = response.content_length().map(|len| len + offset);
+        if offset == 0 {
+            let mut state = state.lock().unwrap();
+            state.etag = response.headers().get(reqwest::header::ETAG)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
+            state.progress = Some(transfer_progress("📥", "download", total, 0).counting(&self.stats));
+        }
+
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            self.pace(chunk.len()).await;
+            {
+                let mut state = state.lock().unwrap();
+                sha2::Digest::update(&mut state.hasher, &chunk);
+                state.received += chunk.len() as u64;
+                if let Some(progress) = &state.progress {
+                    progress.set_position(state.received);
+                }
+            }
+            if sender.send(Ok(chunk)).await.is_err() {
+                state.lock().unwrap().abandoned = true;
+                return Ok(());
+            }
+        }
+        if total.is_some_and(|total| state.lock().unwrap().received < total) {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before the download finished").into());
+        }
+        Ok(())
+    }
+}
+
+/// Reads the chunks a download sends over a channel, blocking until the next one arrives.
+/// Must be used off the async runtime.
+struct ChunkReader {
+    receiver: tokio::sync::mpsc::Receiver<io::Result<bytes::Bytes>>,
+    chunk: bytes::Bytes,
+}
+
+impl io::Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.receiver.blocking_recv() {
+                Some(chunk) => self.chunk = chunk?,
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk.split_to(n));
+        Ok(n)
+    }
+}
+
+// --- CHUNKED DOWNLOADS ---
+
+impl Downloader {
+    /// Downloads the archive into `output` as chunks, taking what it can from the store in
+    /// `dir` and adding the rest to it. The archive is put together from the store once every
+    /// chunk is there, and checked against the SHA-256 in the server's chunk list.
+    async fn fetch_chunked(&self, output: &Path, dir: &Path) -> ClientResult<()> {
+        // 1. What the archive is made of, and what of it is here already
+        let store = ChunkStore::open(dir).map_err(|e| format!("Could not open chunk store {}: {}", dir.display(), e))?;
+        let client = self.connection.client()?;
+        let response = self.request(&client, reqwest::Method::GET, "download.chunks").send().await?;
+        if !response.status().is_success() {
+            return Err(server_error(response).await);
+        }
+        let index = std::sync::Arc::new(response.json::<ChunkIndex>().await?);
+
+        let have = stored_chunks(&store, &index).await?;
+        let missing = index.missing(&have).count();
+        let reused: u64 = index.chunks.iter().filter(|chunk| have.contains(&chunk.hash)).map(|chunk| chunk.size).sum();
+        emit("chunks_reused", serde_json::json!({ "chunks": index.chunks.len(), "missing": missing, "reused_bytes": reused, "size": index.size }),
+            format_args!("♻️  {} of {} bytes are already in {}; fetching {} chunk(s)", reused, index.size, dir.display(), missing));
+
+        // 2. Every attempt asks for what the store still lacks, so a retry continues where the
+        // last one stopped
+        if missing > 0 {
+            with_retries(&self.stats, self.retries, self.retry_delay, || self.fetch_missing_chunks(&index, &store)).await?;
+        }
+
+        // 3. Put the archive together from the store
+        let partial = part_path(output);
+        let (target, source, chunks) = (partial.clone(), store.clone(), index.clone());
+        let actual = tokio::task::spawn_blocking(move || {
+            let mut file = io::BufWriter::new(std::fs::File::create(&target)?);
+            let mut hasher = sha2::Sha256::default();
+            for chunk in &chunks.chunks {
+                let data = source.read(&chunk.hash)?;
+                sha2::Digest::update(&mut hasher, &data);
+                io::Write::write_all(&mut file, &data)?;
+            }
+            io::Write::flush(&mut file)?;
+            Ok::<_, io::Error>(crate::archive::hex_digest(&sha2::Digest::finalize(hasher)))
+        }).await?
+            .map_err(|e| format!("Could not put {} together from {}: {}", output.display(), dir.display(), e))?;
+
+        if actual != index.sha256 {
+            tokio::fs::remove_file(&partial).await?;
+            return Err(format!(
+                "checksum mismatch for {} (expected {}, got {}); the file was deleted, download it again",
+                output.display(), index.sha256, actual
+            ).into());
+        }
+        self.stats.checksum(Verification::Verified);
+        if let Some(key) = &self.verify_key {
+            self.verify_signature(&partial, key).await?;
+        }
+
+        tokio::fs::rename(&partial, output).await?;
+        emit("download_complete", serde_json::json!({ "path": output, "bytes": index.size }),
+            format_args!("✅ Download complete: {} ({} bytes)", output.display(), index.size));
+        Ok(())
+    }
+
+    /// Asks the server for the chunks of `index` missing from `store` and adds them to it as
+    /// they arrive.
+    async fn fetch_missing_chunks(&self, index: &std::sync::Arc<ChunkIndex>, store: &ChunkStore) -> ClientResult<()> {
+        let have = stored_chunks(store, index).await?;
+        if index.missing(&have).next().is_none() {
+            return Ok(());
+        }
+
+        let client = self.connection.client()?;
+        let response = self.request(&client, reqwest::Method::POST, "download.chunks")
+            .json(&ChunkRequest { etag: index.etag.clone(), have: have.iter().cloned().collect() })
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::PRECONDITION_FAILED {
+            return Err("The archive changed on the server during the download; download it again".into());
+        }
+        if !response.status().is_success() {
+            return Err(server_error(response).await);
+        }
+
+        // The body is decompressed and split into chunks off the async runtime
+        let (sender, receiver) = tokio::sync::mpsc::channel(16);
+        let (chunks, target) = (index.clone(), store.clone());
+        let storing = tokio::task::spawn_blocking(move || {
+            let reader = ChunkReader { receiver, chunk: bytes::Bytes::new() };
+            let mut decoder = Codec::Zstd.decoder(Box::new(reader))?;
+            let mut stored = 0u64;
+            for chunk in chunks.missing(&have) {
+                let mut data = vec![0; chunk.size as usize];
+                io::Read::read_exact(&mut decoder, &mut data)?;
+                target.insert(&chunk.hash, &data)?;
+                stored += 1;
+            }
+            Ok::<_, io::Error>(stored)
+        });
+
+        let progress = transfer_progress("📥", "download", response.content_length(), 0);
+        let mut received = 0;
+        let mut stream = response.bytes_stream();
+        let mut failed = None;
+        while let Some(chunk) = stream.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    let _ = sender.send(Err(io::Error::other(e.to_string()))).await;
+                    failed = Some(e);
+                    break;
+                }
+            };
+            self.pace(chunk.len()).await;
+            received += chunk.len() as u64;
+            progress.set_position(received);
+            if sender.send(Ok(chunk)).await.is_err() {
+                break;
+            }
+        }
+        drop(sender);
+        progress.finish_and_clear();
+
+        let stored = storing.await?;
+        if let Some(e) = failed {
+            return Err(e.into());
+        }
+        let stored = stored.map_err(|e| format!("Could not store the chunks received: {}", e))?;
+        emit("chunks_fetched", serde_json::json!({ "chunks": stored, "bytes": received }),
+            format_args!("📦 Fetched {} chunk(s), {} bytes compressed", stored, received));
+        Ok(())
+    }
+}
+
+/// The hashes of `index`'s chunks that `store` already holds, checked on a blocking thread.
+async fn stored_chunks(store: &ChunkStore, index: &std::sync::Arc<ChunkIndex>) -> ClientResult<std::collections::HashSet<String>> {
+    let (store, index) = (store.clone(), index.clone());
+    Ok(tokio::task::spawn_blocking(move || {
+        index.chunks.iter().filter(|chunk| store.contains(chunk)).map(|chunk| chunk.hash.clone()).collect()
+    }).await?)
+}
+
+// --- SPLIT ARCHIVES ---
+
+impl Downloader {
+    /// The server's index of the parts it splits this archive into, or `None` when it serves
+    /// archives whole.
+    pub(crate) async fn parts_index(&self) -> ClientResult<Option<ArchiveParts>> {
+        let client = self.connection.client()?;
+        let response = self.request(&client, reqwest::Method::GET, "download.index").send().await?;
+        match response.status() {
+            reqwest::StatusCode::NOT_FOUND if response.headers().get(reqwest::header::ETAG).is_none() => Ok(None),
+            status if status.is_success() => Ok(Some(response.json().await?)),
+            _ => Err(server_error(response).await),
+        }
+    }
+
+    /// Downloads every part of a split archive next to `output` (as `<output>.001`, ...), with
+    /// the server's index beside them as `<output>.index.json`. The parts are checked together
+    /// against the archive's checksum and signature, then joined into `output` if asked to.
+    async fn fetch_parts(&self, output: &Path, index: ArchiveParts) -> ClientResult<()> {
+        let index_path = sidecar_path(output, "index.json");
+        let part_path = |number: u32| sidecar_path(output, &format!("{:03}", number));
+
+        // Parts finished by an earlier run belong to the archive the server had then
+        let previous: Option<ArchiveParts> = tokio::fs::read(&index_path).await.ok()
+            .and_then(|data| serde_json::from_slice(&data).ok());
+        if let Some(previous) = previous.filter(|previous| previous.etag != index.etag) {
+            emit("restarting", serde_json::json!({ "path": output, "reason": "archive changed" }),
+                format_args!("🔄 The archive on the server changed, restarting the download from scratch"));
+            for part in &previous.parts {
+                remove_part(&part_path(part.number)).await;
+            }
+        }
+        tokio::fs::write(&index_path, serde_json::to_vec_pretty(&index)?).await?;
+
+        emit("split_archive", serde_json::json!({ "parts": index.parts.len(), "part_size": index.part_size, "index": index_path }),
+            format_args!("🧩 The archive is split into {} parts of up to {} bytes", index.parts.len(), index.part_size));
+        let paths: Vec<PathBuf> = index.parts.iter().map(|part| part_path(part.number)).collect();
+        for (part, path) in index.parts.iter().zip(&paths) {
+            // Parts only get their final name once complete
+            if tokio::fs::metadata(path).await.is_ok_and(|metadata| metadata.len() == part.size) {
+                emit("already_downloaded", serde_json::json!({ "path": path, "bytes": part.size }),
+                    format_args!("✅ {} is already downloaded", path.display()));
+                continue;
+            }
+            let downloader = Downloader { part: Some(part.number), verify: false, verify_key: None, ..self.clone() };
+            downloader.fetch_file(path).await?;
+        }
+
+        // A bad part is only found by checking them all, so every part has to be fetched again
+        if self.verify || self.verify_key.is_some() {
+            let hashed = paths.clone();
+            let (size, actual) = tokio::task::spawn_blocking(move || crate::archive::hash_files(&hashed)).await??;
+            let mut mismatch = (self.verify && (actual != index.sha256 || size != index.size)).then(|| format!(
+                "checksum mismatch for the parts of {} (expected {}, got {})", output.display(), index.sha256, actual
+            ));
+            if let (None, Some(key)) = (&mismatch, &self.verify_key) {
+                mismatch = self.check_signature(output, &actual, key).await.err();
+            }
+            if let Some(mismatch) = mismatch {
+                for path in &paths {
+                    remove_part(path).await;
+                }
+                let _ = tokio::fs::remove_file(&index_path).await;
+                return Err(format!("{}; the parts were deleted, download them again", mismatch).into());
+            }
+            if self.verify {
+                self.stats.checksum(Verification::Verified);
+
+                emit("checksum_verified", serde_json::json!({ "path": output, "sha256": actual }),
+                    format_args!("🔒 Verified SHA-256 {}", actual));
+            }
+            if self.verify_key.is_some() {
+                self.stats.signature_verified();
+
+                emit("signature_verified", serde_json::json!({ "path": output }), format_args!("✍️  Verified signature"));
+            }
+        }
+
+        if self.join_parts {
+            join_files(&paths, output).await?;
+            let _ = tokio::fs::remove_file(&index_path).await;
+            emit("download_complete", serde_json::json!({ "path": output, "bytes": index.size, "parts": paths.len() }),
+                format_args!("✅ Joined {} parts into {} ({} bytes)", paths.len(), output.display(), index.size));
+        } else {
+            emit("download_complete", serde_json::json!({ "parts": paths, "index": index_path, "bytes": index.size }),
+                format_args!("✅ Saved {} parts next to {}; join them in order to get the archive", paths.len(), index_path.display()));
+        }
+        Ok(())
+    }
+}
+
+/// Whether a partial answer continues the archive the partial file holds the start of: it
+/// starts at `start`, and its ETag and total size match those recorded by the attempt that
+/// began the file, where both are known.
+async fn same_archive(response: &reqwest::Response, start: u64, etag: Option<&str>, etag_path: &Path, size_path: &Path) -> bool {
+    let range = response.headers().get(reqwest::header::CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("bytes "))
+        .and_then(|value| value.split_once('/'))
+        .and_then(|(range, total)| Some((range.split_once('-')?.0.parse::<u64>().ok()?, total.parse::<u64>().ok())));
+    let Some((first, total)) = range else {
+        return true;
+    };
+    if first != start {
+        return false;
+    }
+    if let (Some(etag), Ok(recorded)) = (etag, tokio::fs::read_to_string(etag_path).await) {
+        if recorded.trim() != etag {
+            return false;
+        }
+    }
+    let recorded_size = tokio::fs::read_to_string(size_path).await.ok().and_then(|size| size.trim().parse::<u64>().ok());
+    !matches!((total, recorded_size), (Some(total), Some(recorded)) if total != recorded)
+}
+
+/// Deletes a downloaded part along with an unfinished download of it and its resume state.
+async fn remove_part(path: &Path) {
+    let partial = part_path(path);
+    let _ = tokio::fs::remove_file(path).await;
+    let _ = tokio::fs::remove_file(sidecar_path(&partial, "etag")).await;
+    let _ = tokio::fs::remove_file(sidecar_path(&partial, "size")).await;
+    let _ = tokio::fs::remove_file(sidecar_path(&partial, "segments")).await;
+    let _ = tokio::fs::remove_file(partial).await;
+}
+
+/// Writes `parts` one after another into `output` (by way of its `.part` file), then deletes them.
+async fn join_files(parts: &[PathBuf], output: &Path) -> ClientResult<()> {
+    let partial = part_path(output);
+    let mut file = tokio::fs::File::create(&partial).await?;
+    for part in parts {
+        let mut source = tokio::fs::File::open(part).await?;
+        tokio::io::copy(&mut source, &mut file).await?;
+    }
+    file.flush().await?;
+    drop(file);
+    tokio::fs::rename(&partial, output).await?;
+    for part in parts {
+        tokio::fs::remove_file(part).await?;
+    }
+    Ok(())
+}
+
+/// The SHA-256 of a file as lowercase hex, hashed on a blocking thread.
+async fn file_sha256(path: &Path) -> ClientResult<String> {
+    let path = path.to_path_buf();
+    let (_, digest) = tokio::task::spawn_blocking(move || crate::archive::hash_file(&path)).await??;
+    Ok(digest)
+}
+
+// --- DELTA SYNC ---
+
+/// What a sync changed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SyncSummary {
+    /// Entries the client already had
+    pub unchanged: u64,
+    /// What was unpacked from the delta archive (nothing when everything was up to date)
+    pub extracted: ExtractSummary,
+}
+
+impl Downloader {
+    /// Brings `dest` up to date with the archive this downloader describes: the server is sent
+    /// a manifest of what `dest` already holds and answers with an archive of only the new and
+    /// changed entries, which is unpacked over `dest`. `passphrase` decrypts the delta from a
+    /// server running with --encrypt. Files the server no longer has are left alone.
+    pub async fn sync(&self, dest: &Path, passphrase: Option<&str>) -> ClientResult<SyncSummary> {
+        let have = hash_local(dest).await?;
+        self.fetch_delta(have, dest, 0, passphrase).await
+    }
+
+    /// Sends `have` to /sync and unpacks the delta into `dest`, dropping `strip_components`
+    /// leading directories from its entry names.
+    async fn fetch_delta(
+        &self,
+        have: Vec<ManifestEntry>,
+        dest: &Path,
+        strip_components: usize,
+        passphrase: Option<&str>,
+    ) -> ClientResult<SyncSummary> {
+        // 1. Ask for the difference
+        let client = self.connection.client()?;
+        let response = self.request(&client, reqwest::Method::POST, "sync")
+            .json(&SyncRequest { have })
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(server_error(response).await);
+        }
+        let unchanged = response.headers().get(SYNC_UNCHANGED_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0);
+        if response.status() == reqwest::StatusCode::NO_CONTENT {
+            return Ok(SyncSummary { unchanged, extracted: ExtractSummary::default() });
+        }
+
+        // 2. Keep the delta in a temporary file while it is unpacked
+        let delta = std::env::temp_dir().join(format!("file-server-sync-{}.delta", std::process::id()));
+        let result = self.receive_delta(response, &delta, dest, strip_components, passphrase).await;
+        let _ = tokio::fs::remove_file(&delta).await;
+        Ok(SyncSummary { unchanged, extracted: result? })
+    }
+
+    /// Writes a /sync response to `delta`, decrypting it if needed, and unpacks it into `dest`.
+    async fn receive_delta(
+        &self,
+        response: reqwest::Response,
+        delta: &Path,
+        dest: &Path,
+        strip_components: usize,
+        passphrase: Option<&str>,
+    ) -> ClientResult<ExtractSummary> {
+        self.save_body(response, delta).await?;
+
+        // A delta from an encrypting server is decrypted next to itself first
+        let encrypted = crate::crypt::is_encrypted(delta)?;
+        let archive = if encrypted {
+            let passphrase = passphrase.ok_or("the server encrypts archives; sync again with --decrypt and the passphrase")?;
+            let decrypted = delta.with_extension("decrypted");
+            let (from, to, passphrase) = (delta.to_path_buf(), decrypted.clone(), passphrase.to_string());
+            tokio::task::spawn_blocking(move || crate::crypt::decrypt_file(&from, &to, &passphrase))
+                .await?
+                .map_err(|e| format!("Failed to decrypt the changes: {}", e))?;
+            decrypted
+        } else {
+            delta.to_path_buf()
+        };
+
+        let (archive_for_task, dest) = (archive.clone(), dest.to_path_buf());
+        let summary = tokio::task::spawn_blocking(move || {
+            extract_archive_stripped(&archive_for_task, &dest, OverwritePolicy::Overwrite, strip_components)
+        }).await?;
+        if encrypted {
+            let _ = tokio::fs::remove_file(&archive).await;
+        }
+        Ok(summary?)
+    }
+
+    /// Writes the body of `response` to `path` at the download's rate limit, with a progress
+    /// bar, and returns its size.
+    async fn save_body(&self, response: reqwest::Response, path: &Path) -> ClientResult<u64> {
+        let progress = transfer_progress("📥", "download", response.content_length(), 0);
+        let mut file = tokio::fs::File::create(path).await?;
+        let mut received = 0;
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            self.pace(chunk.len()).await;
+            file.write_all(&chunk).await?;
+            received += chunk.len() as u64;
+            progress.set_position(received);
+        }
+        file.flush().await?;
+        progress.finish_and_clear();
+        Ok(received)
+    }
+}
+
+/// Describes what `dest` holds (nothing if it does not exist yet), hashing on a blocking thread.
+async fn hash_local(dest: &Path) -> ClientResult<Vec<ManifestEntry>> {
+    if !tokio::fs::try_exists(dest).await? {
+        return Ok(Vec::new());
+    }
+    let dir = dest.to_path_buf();
+    let have = tokio::task::spawn_blocking(move || local_manifest(&dir)).await??;
+    emit("hashed_local", serde_json::json!({ "path": dest, "entries": have.len() }),
+        format_args!("🔎 Hashed {} local entries in {}", have.len(), dest.display()));
+    Ok(have)
+}
+
+// --- MIRRORING ---
+
+/// What one mirror pass found and, unless it was a dry run, did.
+#[derive(Clone, Debug, Default)]
+pub struct MirrorReport {
+    /// Paths (relative to the local directory) that are new or changed on the server
+    pub changed: Vec<String>,
+    /// Local paths the server no longer has (removed only when deleting is enabled)
+    pub removed: Vec<String>,
+    /// Entries that already matched the server
+    pub unchanged: usize,
+    /// What was unpacked; nothing on a dry run
+    pub extracted: ExtractSummary,
+}
+
+impl Downloader {
+    /// Makes `dest` a copy of the one directory (or file) this downloader requests: the
+    /// server's manifest is compared with what `dest` holds, new and changed entries are
+    /// fetched through /sync, and with `delete` local files the server no longer has are
+    /// removed. A `dry_run` only reports the differences. The requested directory's own name
+    /// is not repeated inside `dest`.
+    pub async fn mirror(&self, dest: &Path, delete: bool, dry_run: bool, passphrase: Option<&str>) -> ClientResult<MirrorReport> {
+        // 1. Compare the server's manifest with the local tree
+        let client = self.connection.client()?;
+        let response = self.request(&client, reqwest::Method::GET, "manifest").send().await?;
+        if !response.status().is_success() {
+            return Err(server_error(response).await);
+        }
+        let manifest: Manifest = response.json().await?;
+
+        // A requested directory is the single top-level entry; its contents go straight into `dest`
+        let mut top_level = manifest.entries.iter().filter(|entry| !entry.path.contains('/'));
+        let prefix = match (top_level.next(), top_level.next()) {
+            (Some(entry), None) if entry.kind == EntryKind::Dir => Some(format!("{}/", entry.path)),
+            _ => None,
+        };
+        let remote: std::collections::HashMap<&str, &ManifestEntry> = manifest.entries.iter()
+            .filter_map(|entry| match &prefix {
+                Some(prefix) => entry.path.strip_prefix(prefix.as_str()).map(|path| (path, entry)),
+                None => Some((entry.path.as_str(), entry)),
+            })
+            .collect();
+
+        let local = hash_local(dest).await?;
+        let local_paths: std::collections::HashMap<&str, &ManifestEntry> =
+            local.iter().map(|entry| (entry.path.as_str(), entry)).collect();
+
+        let mut report = MirrorReport::default();
+        let mut changed: Vec<&str> = remote.iter()
+            .filter(|(path, entry)| !local_paths.get(*path).is_some_and(|existing| same_entry(entry, existing)))
+            .map(|(path, _)| *path)
+            .collect();
+        changed.sort_unstable();
+        report.unchanged = remote.len() - changed.len();
+        report.changed = changed.iter().map(|path| path.to_string()).collect();
+
+        // Only the topmost of a removed tree is listed
+        let mut removed_dirs = std::collections::HashSet::new();
+        for entry in &local {
+            let inside_removed = Path::new(&entry.path).ancestors().skip(1).any(|parent| removed_dirs.contains(parent));
+            if remote.contains_key(entry.path.as_str()) || inside_removed {
+                continue;
+            }
+            if entry.kind == EntryKind::Dir {
+                removed_dirs.insert(Path::new(&entry.path));
+            }
+            report.removed.push(entry.path.clone());
+        }
+
+        if dry_run {
+            return Ok(report);
+        }
+
+        // 2. Fetch what changed. Anything whose type changed goes first, or it would be in the way
+        if !report.changed.is_empty() {
+            for path in &report.changed {
+                if local_paths.get(path.as_str()).is_some_and(|existing| existing.kind != remote[path.as_str()].kind) {
+                    remove_local(&dest.join(path)).await?;
+                }
+            }
+            let have = local.iter()
+                .map(|entry| ManifestEntry {
+                    path: format!("{}{}", prefix.as_deref().unwrap_or_default(), entry.path),
+                    ..entry.clone()
+                })
+                .collect();
+            report.extracted = self.fetch_delta(have, dest, usize::from(prefix.is_some()), passphrase).await?.extracted;
+        }
+
+        // 3. Remove what the server no longer has, once everything new is in place
+        if delete {
+            for path in &report.removed {
+                remove_local(&dest.join(path)).await?;
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Whether a local entry already matches the server's.
+fn same_entry(remote: &ManifestEntry, local: &ManifestEntry) -> bool {
+    remote.kind == local.kind && match remote.kind {
+        EntryKind::File => remote.size == local.size && remote.sha256 == local.sha256,
+        EntryKind::Symlink => remote.target == local.target,
+        EntryKind::Dir => true,
+    }
+}
+
+/// Removes a file, link or whole directory.
+async fn remove_local(path: &Path) -> io::Result<()> {
+    match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) if metadata.is_dir() => tokio::fs::remove_dir_all(path).await,
+        Ok(_) => tokio::fs::remove_file(path).await,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+// --- BACKUPS ---
+//
+// A backup directory holds numbered snapshots of what the server serves for one request: a
+// full archive, then archives of only what is new or changed since a parent snapshot, fetched
+// through /sync. Each snapshot also records the server's whole manifest at the time, so a
+// restore knows what was removed in between. `snapshots.json` lists them.
+
+/// Name of the index in a backup directory.
+pub const SNAPSHOT_INDEX_FILE: &str = "snapshots.json";
+
+/// One snapshot of a backup.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Snapshot {
+    pub id: u32,
+    /// The snapshot this one holds the changes since; `None` for a full snapshot
+    pub parent: Option<u32>,
+    /// When it was taken, in seconds since the Unix epoch
+    pub created: u64,
+    /// The archive of new and changed entries, in the backup directory; `None` when entries
+    /// were only removed
+    pub archive: Option<String>,
+    /// The server's manifest when the snapshot was taken, in the backup directory
+    pub manifest: String,
+    /// Entries in the archive
+    pub changed: usize,
+    /// Entries unchanged since the parent
+    pub unchanged: usize,
+    /// Size of the archive
+    pub bytes: u64,
+}
+
+/// The `snapshots.json` of a backup directory.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SnapshotIndex {
+    /// The files backed up, as requested; every snapshot in a directory is of the same ones
+    pub files: Vec<String>,
+    pub flatten: bool,
+    /// Oldest first
+    pub snapshots: Vec<Snapshot>,
+}
+
+impl SnapshotIndex {
+    /// Reads the index of the backup in `dir`, if there is one.
+    pub async fn load(dir: &Path) -> ClientResult<Option<Self>> {
+        match tokio::fs::read(dir.join(SNAPSHOT_INDEX_FILE)).await {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)
+                .map_err(|e| format!("Invalid {} in {}: {}", SNAPSHOT_INDEX_FILE, dir.display(), e))?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replaces the index, by way of a temporary file so that it is never half written.
+    async fn save(&self, dir: &Path) -> ClientResult<()> {
+        let path = dir.join(SNAPSHOT_INDEX_FILE);
+        let partial = part_path(&path);
+        tokio::fs::write(&partial, serde_json::to_vec_pretty(self)?).await?;
+        tokio::fs::rename(&partial, &path).await?;
+        Ok(())
+    }
+
+    /// The snapshots restoring `id` replays: the full snapshot it goes back to first, `id` last.
+    pub fn chain(&self, id: u32) -> ClientResult<Vec<&Snapshot>> {
+        let mut chain = Vec::new();
+        let mut next = Some(id);
+        while let Some(id) = next {
+            let snapshot = self.snapshots.iter().find(|snapshot| snapshot.id == id)
+                .ok_or_else(|| format!("Snapshot {} is missing from the backup", id))?;
+            if chain.len() > self.snapshots.len() {
+                return Err("The backup's snapshots form a loop".into());
+            }
+            chain.push(snapshot);
+            next = snapshot.parent;
+        }
+        chain.reverse();
+        Ok(chain)
+    }
+}
+
+/// What a restore did.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RestoreSummary {
+    /// The snapshot restored
+    pub snapshot: u32,
+    /// Snapshots replayed to get there
+    pub replayed: usize,
+    /// What was unpacked, over all the snapshots
+    pub extracted: ExtractSummary,
+    /// Entries removed because a later snapshot no longer had them
+    pub removed: usize,
+}
+
+impl Downloader {
+    /// Adds a snapshot of what this downloader requests to the backup in `dir` (created if
+    /// needed): only what is new or changed since the latest snapshot, or everything if there
+    /// is none yet or `full` is set. Returns `None`, adding nothing, when nothing changed.
+    pub async fn backup(&self, dir: &Path, full: bool) -> ClientResult<Option<Snapshot>> {
+        tokio::fs::create_dir_all(dir).await?;
+        let mut index = match SnapshotIndex::load(dir).await? {
+            Some(index) if index.files != self.files || index.flatten != self.flatten => {
+                return Err(format!(
+                    "{} holds a backup of {}; back up other files into another directory",
+                    dir.display(), describe_files(&index.files)
+                ).into());
+            }
+            Some(index) => index,
+            None => SnapshotIndex { files: self.files.clone(), flatten: self.flatten, snapshots: Vec::new() },
+        };
+
+        // 1. What the server has now, and what the parent snapshot left off with
+        let parent = if full { None } else { index.snapshots.last().cloned() };
+        let have = match &parent {
+            Some(parent) => read_snapshot_manifest(dir, parent).await?.entries,
+            None => Vec::new(),
+        };
+        let client = self.connection.client()?;
+        let response = self.request(&client, reqwest::Method::GET, "manifest").send().await?;
+        if !response.status().is_success() {
+            return Err(server_error(response).await);
+        }
+        let manifest: Manifest = response.json().await?;
+
+        // 2. The changes since, as an archive
+        let response = self.request(&client, reqwest::Method::POST, "sync")
+            .json(&SyncRequest { have: have.clone() })
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(server_error(response).await);
+        }
+        let unchanged = response.headers().get(SYNC_UNCHANGED_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0);
+
+        let id = index.snapshots.iter().map(|snapshot| snapshot.id).max().unwrap_or(0) + 1;
+        let stem = format!("snapshot-{:04}", id);
+        let (archive, bytes) = if response.status() == reqwest::StatusCode::NO_CONTENT {
+            // Nothing new, but removals still make a snapshot
+            let paths = |entries: &[ManifestEntry]| entries.iter().map(|entry| entry.path.clone()).collect::<std::collections::HashSet<_>>();
+            if parent.is_some() && paths(&have) == paths(&manifest.entries) {
+                return Ok(None);
+            }
+            (None, 0)
+        } else {
+            let name = format!("{}.{}", stem, self.extension());
+            let path = dir.join(&name);
+            let partial = part_path(&path);
+            let bytes = self.save_body(response, &partial).await?;
+            tokio::fs::rename(&partial, &path).await?;
+            (Some(name), bytes)
+        };
+        let manifest_name = format!("{}.manifest.json", stem);
+        tokio::fs::write(dir.join(&manifest_name), serde_json::to_vec(&manifest)?).await?;
+
+        // 3. Recorded last, so an interrupted backup adds no snapshot
+        let created = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs();
+        let snapshot = Snapshot {
+            id,
+            parent: parent.map(|parent| parent.id),
+            created,
+            archive,
+            manifest: manifest_name,
+            changed: manifest.entries.len().saturating_sub(unchanged),
+            unchanged,
+            bytes,
+        };
+        index.snapshots.push(snapshot.clone());
+        index.save(dir).await?;
+        Ok(Some(snapshot))
+    }
+}
+
+/// Rebuilds a snapshot of the backup in `dir` (the latest if `id` is `None`) in `dest` by
+/// replaying its chain: the full snapshot is unpacked first, then each incremental one over
+/// it, removing in between whatever the next snapshot no longer had. `passphrase` decrypts
+/// snapshots taken from a server running with --encrypt.
+pub async fn restore(dir: &Path, dest: &Path, id: Option<u32>, passphrase: Option<&str>) -> ClientResult<RestoreSummary> {
+    let index = SnapshotIndex::load(dir).await?
+        .ok_or_else(|| format!("No backup in {} ({} not found)", dir.display(), SNAPSHOT_INDEX_FILE))?;
+    let id = match id.or(index.snapshots.last().map(|snapshot| snapshot.id)) {
+        Some(id) => id,
+        None => return Err(format!("The backup in {} has no snapshots yet", dir.display()).into()),
+    };
+    let chain = index.chain(id)?;
+
+    let mut summary = RestoreSummary { snapshot: id, replayed: chain.len(), ..RestoreSummary::default() };
+    let mut previous: Option<Manifest> = None;
+    for snapshot in chain {
+        let manifest = read_snapshot_manifest(dir, snapshot).await?;
+
+        // Whatever this snapshot no longer has (or has as another kind of entry) goes first
+        if let Some(previous) = &previous {
+            let current: std::collections::HashMap<&str, EntryKind> =
+                manifest.entries.iter().map(|entry| (entry.path.as_str(), entry.kind)).collect();
+            let mut removed_dirs = std::collections::HashSet::new();
+            for entry in &previous.entries {
+                let inside_removed = Path::new(&entry.path).ancestors().skip(1).any(|parent| removed_dirs.contains(parent));
+                if current.get(entry.path.as_str()) == Some(&entry.kind) || inside_removed || !is_relative_path(&entry.path) {
+                    continue;
+                }
+                if entry.kind == EntryKind::Dir {
+                    removed_dirs.insert(Path::new(&entry.path));
+                }
+                remove_local(&dest.join(&entry.path)).await?;
+                summary.removed += 1;
+            }
+        }
+
+        if let Some(archive) = &snapshot.archive {
+            emit("restoring", serde_json::json!({ "snapshot": snapshot.id, "archive": archive }),
+                format_args!("⏪ Replaying snapshot {} ({})", snapshot.id, archive));
+            let extracted = restore_archive(&dir.join(archive), dest, passphrase).await
+                .map_err(|e| format!("Failed to restore snapshot {}: {}", snapshot.id, e))?;
+            summary.extracted.files += extracted.files;
+            summary.extracted.bytes += extracted.bytes;
+        }
+        previous = Some(manifest);
+    }
+    Ok(summary)
+}
+
+/// Unpacks one snapshot's archive over `dest`, decrypting it next to the system's temporary
+/// files first if it is encrypted.
+async fn restore_archive(archive: &Path, dest: &Path, passphrase: Option<&str>) -> ClientResult<ExtractSummary> {
+    let encrypted = crate::crypt::is_encrypted(archive)?;
+    let source = match encrypted {
+        true => {
+            let passphrase = passphrase.ok_or("the snapshot is encrypted; restore with --decrypt and the passphrase")?.to_string();
+            let decrypted = std::env::temp_dir().join(format!("file-server-restore-{}.archive", std::process::id()));
+            let (from, to) = (archive.to_path_buf(), decrypted.clone());
+            tokio::task::spawn_blocking(move || crate::crypt::decrypt_file(&from, &to, &passphrase)).await??;
+            decrypted
+        }
+        false => archive.to_path_buf(),
+    };
+    let (from, to) = (source.clone(), dest.to_path_buf());
+    let summary = tokio::task::spawn_blocking(move || extract_archive_stripped(&from, &to, OverwritePolicy::Overwrite, 0)).await?;
+    if encrypted {
+        let _ = tokio::fs::remove_file(&source).await;
+    }
+    Ok(summary?)
+}
+
+async fn read_snapshot_manifest(dir: &Path, snapshot: &Snapshot) -> ClientResult<Manifest> {
+    let path = dir.join(&snapshot.manifest);
+    let data = tokio::fs::read(&path).await
+        .map_err(|e| format!("Could not read the manifest of snapshot {} ({}): {}", snapshot.id, path.display(), e))?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+/// Whether a manifest path stays inside the directory it is joined to.
+fn is_relative_path(path: &str) -> bool {
+    Path::new(path).components().all(|component| matches!(component, std::path::Component::Normal(_)))
+}
+
+/// The requested files for a message, or "the server's default files".
+fn describe_files(files: &[String]) -> String {
+    match files.is_empty() {
+        true => "the server's default files".to_string(),
+        false => files.join(", "),
+    }
+}
+
+// --- SEGMENTED DOWNLOADS ---
+
+/// Archives smaller than this per connection are not worth splitting further.
+const MIN_SEGMENT_SIZE: u64 = 1024 * 1024;
+
+/// How often a segmented download reports progress and saves its resume state.
+const SEGMENT_PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Resume state of a segmented download, kept in `<output>.segments` until it completes.
+#[derive(Serialize, Deserialize)]
+struct SegmentedState {
+    etag: String,
+    size: u64,
+    segments: Vec<Segment>,
+}
+
+/// One byte range of the archive, `start..=end`, of which `received` bytes are on disk.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct Segment {
+    start: u64,
+    end: u64,
+    received: u64,
+}
+
+/// Splits `size` bytes into at most `connections` segments of at least `MIN_SEGMENT_SIZE`.
+fn plan_segments(size: u64, connections: u16) -> Vec<Segment> {
+    let count = (connections as u64).min(size.div_ceil(MIN_SEGMENT_SIZE)).max(1);
+    let length = size.div_ceil(count);
+    (0..count)
+        .map(|index| index * length)
+        .take_while(|&start| start < size)
+        .map(|start| Segment { start, end: (start + length).min(size) - 1, received: 0 })
+        .collect()
+}
+
+impl SegmentedState {
+    /// Bytes received so far across all segments.
+    fn received(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.received).sum()
+    }
+}
+
+// --- RETRIES ---
+
+/// The longest the client waits between two attempts, however many have failed.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// The longest the client waits when the server asks it to with Retry-After.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// Whether an attempt failed for a reason that may go away on its own: lost or refused
+/// connections, timeouts, truncated bodies and 5xx responses. Client errors and local
+/// I/O failures are reported straight away.
+fn is_retryable(error: &(dyn std::error::Error + 'static)) -> bool {
+    if let Some(e) = error.downcast_ref::<reqwest::Error>() {
+        return !e.is_builder() && !e.is_status();
+    }
+    if let Some(e) = error.downcast_ref::<ServerError>() {
+        return e.status.is_server_error() || (e.status == reqwest::StatusCode::TOO_MANY_REQUESTS && e.retry_after.is_some());
+    }
+    if let Some(e) = error.downcast_ref::<io::Error>() {
+        return matches!(e.kind(), io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset);
+    }
+    false
+}
+
+/// Says which limit a request that timed out ran into, in place of the transport's own
+/// "operation timed out"; `None` for every other error.
+pub fn timeout_message(error: &(dyn std::error::Error + 'static)) -> Option<String> {
+    let mut source = Some(error);
+    while let Some(e) = source {
+        if let Some(e) = e.downcast_ref::<reqwest::Error>().filter(|e| e.is_timeout()) {
+            let server = e.url().map_or_else(|| "the server".to_string(), |url| url.origin().ascii_serialization());
+            return Some(match e.is_connect() {
+                true => format!("Timed out connecting to {} (see --connect-timeout)", server),
+                false => format!("Timed out waiting for {} to send more (see --read-timeout)", server),
+            });
+        }
+        if let Some(e) = e.downcast_ref::<io::Error>() {
+            if e.kind() == io::ErrorKind::TimedOut {
+                return Some("Timed out waiting for the server to send more (see --read-timeout)".to_string());
+            }
+            if let Some(inner) = e.get_ref() {
+                if let Some(message) = timeout_message(inner) {
+                    return Some(message);
+                }
+            }
+        }
+        source = e.source();
+    }
+    None
+}
+
+/// Runs `attempt` until it succeeds, retrying transient failures up to `retries` times with
+/// exponential backoff, or after the time the server names in a Retry-After. Downloads resume
+/// from what is already on disk, so every retry re-requests only the missing bytes. Retries
+/// are counted in `stats`.
+async fn with_retries<T, F, Fut>(stats: &TransferStats, retries: u32, first_delay: Duration, mut attempt: F) -> ClientResult<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = ClientResult<T>>,
+{
+    let mut delay = first_delay;
+    let mut retry = 0;
+    loop {
+        match attempt().await {
+            Err(e) if retry < retries && is_retryable(e.as_ref()) => {
+                retry += 1;
+                stats.retried();
+                let retry_after = e.downcast_ref::<ServerError>().and_then(|e| e.retry_after);
+                let wait = retry_after.map_or(delay, |retry_after| retry_after.min(MAX_RETRY_AFTER));
+                let error = timeout_message(e.as_ref()).unwrap_or_else(|| e.to_string());
+                emit("retrying", serde_json::json!({ "error": error, "delay_seconds": wait.as_secs_f64(), "attempt": retry, "retries": retries }),
+                    format_args!("⚠️  {}; retrying in {:.1}s ({}/{})", error, wait.as_secs_f64(), retry, retries));
+                tokio::time::sleep(wait).await;
+                if retry_after.is_none() {
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+            }
+            result => return result,
+        }
+    }
+}
+
+// --- PROGRESS ---
+
+/// Splits a `text/event-stream` body into events as its bytes arrive.
+#[derive(Default)]
+pub(crate) struct ServerEvents {
+    /// Bytes received but not yet part of a complete event, which may end mid-character
+    buffer: Vec<u8>,
+}
+
+impl ServerEvents {
+    pub(crate) fn push(&mut self, chunk: &[u8]) {
+        self.buffer.extend_from_slice(chunk);
+    }
+
+    /// The next complete event's name (`message` when unnamed) and data.
+    pub(crate) fn next_event(&mut self) -> Option<(String, String)> {
+        loop {
+            let end = self.buffer.windows(2).position(|pair| pair == b"\n\n")?;
+            let block: Vec<u8> = self.buffer.drain(..end + 2).collect();
+            let block = String::from_utf8_lossy(&block);
+            let (mut event, mut data) = ("message".to_string(), Vec::new());
+            for line in block.lines() {
+                let (field, value) = line.split_once(':').unwrap_or((line, ""));
+                let value = value.strip_prefix(' ').unwrap_or(value);
+                match field {
+                    "event" => event = value.to_string(),
+                    "data" => data.push(value),
+                    _ => {}
+                }
+            }
+            // Blocks of comments only (keep-alives) are not events
+            if !data.is_empty() {
+                return Some((event, data.join("\n")));
+            }
+        }
+    }
+}
+
+/// How often a transfer reports its progress in JSON mode.
+const JSON_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A transfer's progress: a bar on the terminal, or in JSON mode a `progress` line about once
+/// a second naming the `action` (`download`, `upload` or `build`).
+struct TransferProgress {
+    bar: indicatif::ProgressBar,
+    action: &'static str,
+    reported: std::sync::Mutex<Option<std::time::Instant>>,
+    /// Where the bytes moved are added up, for transfers rather than builds
+    stats: Option<TransferStats>,
+    last_position: std::sync::atomic::AtomicU64,
+}
+
+impl TransferProgress {
+    /// Adds the bytes this progress sees moving to `stats`.
+    fn counting(mut self, stats: &TransferStats) -> Self {
+        self.stats = Some(stats.clone());
+        self
+    }
+
+    fn set_position(&self, position: u64) {
+        self.bar.set_position(position);
+        let last = self.last_position.swap(position, std::sync::atomic::Ordering::Relaxed);
+        if let Some(stats) = &self.stats {
+            stats.moved(position.saturating_sub(last));
+        }
+        self.report(false);
+    }
+
+    fn set_length(&self, length: u64) {
+        self.bar.set_length(length);
+    }
+
+    fn finish_and_clear(&self) {
+        self.report(true);
+        self.bar.finish_and_clear();
+    }
+
+    fn report(&self, finished: bool) {
+        if !crate::output::is_json() {
+            return;
+        }
+        let mut reported = self.reported.lock().unwrap_or_else(|e| e.into_inner());
+        if !finished && reported.is_some_and(|reported| reported.elapsed() < JSON_PROGRESS_INTERVAL) {
+            return;
+        }
+        *reported = Some(std::time::Instant::now());
+        crate::output::print_event("progress", serde_json::json!({
+            "action": self.action,
+            "bytes": self.bar.position(),
+            "total": self.bar.length(),
+            "bytes_per_second": self.bar.per_sec().round() as u64,
+        }));
+    }
+}
+
+/// A progress bar for a transfer of `total` bytes (a spinner when unknown) starting at
+/// `position`, showing rate and ETA. It draws to stderr and stays hidden when that isn't a
+/// terminal (or in JSON mode, or while bars are switched off), so logs and pipes only see the
+/// summary lines.
+fn transfer_progress(prefix: &'static str, action: &'static str, total: Option<u64>, position: u64) -> TransferProgress {
+    let (progress, template) = match total {
+        Some(total) => (
+            indicatif::ProgressBar::new(total),
+            "  {prefix} [{bar:30}] {bytes} / {total_bytes} ({percent}%) {binary_bytes_per_sec}, ETA {eta}",
+        ),
+        None => (indicatif::ProgressBar::no_length(), "  {prefix} {spinner} {bytes} {binary_bytes_per_sec}"),
+    };
+    if crate::output::is_json() || !crate::output::progress_bars_shown() {
+        progress.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+    }
+    // Dropping the bar on an error path clears it, so the error message gets a clean line
+    let progress = progress.with_finish(indicatif::ProgressFinish::AndClear);
+    progress.set_style(indicatif::ProgressStyle::with_template(template).unwrap().progress_chars("=> "));
+    progress.set_prefix(prefix);
+    // Bytes already on disk from an earlier attempt don't count towards the rate
+    progress.set_position(position);
+    progress.reset_eta();
+    TransferProgress { bar: progress, action, reported: Default::default(), stats: None, last_position: position.into() }
+}
+
+// --- TRANSFER STATISTICS ---
+
+/// Throughput is sampled over windows of at least this long to find the peak.
+const PEAK_WINDOW: Duration = Duration::from_secs(1);
+
+/// What one download or upload did, added up over its retries, segments and parts, for the
+/// `transfer_summary` printed at the end. Clones share the same tally.
+#[derive(Clone, Debug)]
+struct TransferStats(std::sync::Arc<std::sync::Mutex<TransferTally>>);
+
+#[derive(Debug)]
+struct TransferTally {
+    started: std::time::Instant,
+    bytes: u64,
+    retries: u32,
+    resumes: u32,
+    /// Start of the current throughput window and the bytes moved in it
+    window: (std::time::Instant, u64),
+    peak: f64,
+    checksum: Verification,
+    signature: bool,
+}
+
+/// Whether a transfer's SHA-256 was checked.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Verification {
+    Verified,
+    /// The server publishes no checksums
+    Unavailable,
+    /// Not checked (`--no-verify`, or uploads, which the server only confirms by size)
+    Skipped,
+}
+
+impl Default for TransferStats {
+    fn default() -> Self {
+        let now = std::time::Instant::now();
+        TransferStats(std::sync::Arc::new(std::sync::Mutex::new(TransferTally {
+            started: now,
+            bytes: 0,
+            retries: 0,
+            resumes: 0,
+            window: (now, 0),
+            peak: 0.0,
+            checksum: Verification::Skipped,
+            signature: false,
+        })))
+    }
+}
+
+impl TransferStats {
+    fn tally(&self) -> std::sync::MutexGuard<'_, TransferTally> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Counts `bytes` sent or received.
+    fn moved(&self, bytes: u64) {
+        let mut tally = self.tally();
+        tally.bytes += bytes;
+        tally.window.1 += bytes;
+        let elapsed = tally.window.0.elapsed();
+        if elapsed >= PEAK_WINDOW {
+            tally.peak = tally.peak.max(tally.window.1 as f64 / elapsed.as_secs_f64());
+            tally.window = (std::time::Instant::now(), 0);
+        }
+    }
+
+    fn retried(&self) {
+        self.tally().retries += 1;
+    }
+
+    fn resumed(&self) {
+        self.tally().resumes += 1;
+    }
+
+    fn checksum(&self, verification: Verification) {
+        self.tally().checksum = verification;
+    }
+
+    fn signature_verified(&self) {
+        self.tally().signature = true;
+    }
+
+    /// Prints the totals of the finished `action` (`download` or `upload`).
+    fn summarize(&self, action: &str) {
+        let tally = self.tally();
+        let seconds = tally.started.elapsed().as_secs_f64();
+        let average = tally.bytes as f64 / seconds.max(f64::MIN_POSITIVE);
+        // Transfers shorter than a window never close one
+        let peak = tally.peak.max(average);
+        let count = |n: u32, one: &str, many: &str| match n {
+            0 => format!("no {}", many),
+            1 => format!("1 {}", one),
+            n => format!("{} {}", n, many),
+        };
+        let checksum = match tally.checksum {
+            Verification::Verified => "SHA-256 verified",
+            Verification::Unavailable => "no checksum to verify",
+            Verification::Skipped => "not verified",
+        };
+        let signature = if tally.signature { ", signature verified" } else { "" };
+        emit("transfer_summary", serde_json::json!({
+            "action": action,
+            "bytes": tally.bytes,
+            "seconds": seconds,
+            "average_bytes_per_second": average.round() as u64,
+            "peak_bytes_per_second": peak.round() as u64,
+            "retries": tally.retries,
+            "resumes": tally.resumes,
+            "checksum": tally.checksum,
+            "signature_verified": tally.signature,
+        }), format_args!(
+            "📈 {} {} in {:.1}s: {}/s on average, {}/s at peak; {}, {}; {}{}",
+            if action == "upload" { "Sent" } else { "Received" }, crate::format_size(tally.bytes), seconds,
+            crate::format_size(average as u64), crate::format_size(peak as u64),
+            count(tally.retries, "retry", "retries"), count(tally.resumes, "resume", "resumes"), checksum, signature,
+        ));
+    }
+}
+
+// --- UPLOADER ---
+
+/// Uploads a file through a resumable upload session.
+#[derive(Clone, Debug)]
+pub struct Uploader {
+    connection: Connection,
+    file: PathBuf,
+    name: Option<String>,
+    path: Option<String>,
+    overwrite: bool,
+    chunk_size: u64,
+    retries: u32,
+    retry_delay: Duration,
+    stats: TransferStats,
+}
+
+impl Uploader {
+    /// Uploads `file` under its own name into the server's upload directory, with 5 retries.
+    pub fn new(connection: Connection, file: impl Into<PathBuf>) -> Self {
+        Uploader {
+            connection,
+            file: file.into(),
+            name: None,
+            path: None,
+            overwrite: false,
+            chunk_size: DEFAULT_UPLOAD_CHUNK_SIZE,
+            retries: 5,
+            retry_delay: Duration::from_secs(1),
+            stats: TransferStats::default(),
+        }
+    }
+
+    /// Name to store the file under.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Subdirectory of the server's upload directory to store the file in.
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Replace an existing file with the same name.
+    pub fn overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Bytes sent per request; an interrupted upload loses at most one chunk.
+    pub fn chunk_size(mut self, chunk_size: u64) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Reconnects up to `retries` times after transient failures.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Wait before the first reconnect; it doubles after each failed attempt.
+    pub fn retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Uploads a file through a resumable upload session, continuing from whatever the
+    /// server already holds for the same destination. Every retry re-opens the session
+    /// and sends only what the server has not acknowledged yet.
+    pub async fn upload(&self) -> ClientResult<()> {
+        let name = match &self.name {
+            Some(name) => name.clone(),
+            None => self.file.file_name().ok_or("Upload path has no file name")?.to_string_lossy().into_owned(),
+        };
+        let uploader = Uploader { stats: TransferStats::default(), ..self.clone() };
+        with_retries(&uploader.stats, self.retries, self.retry_delay, || uploader.upload_once(&name)).await?;
+        uploader.stats.summarize("upload");
+        Ok(())
+    }
+
+    async fn upload_once(&self, name: &str) -> ClientResult<()> {
+        let base_url = self.connection.base_url();
+        let mut file = tokio::fs::File::open(&self.file).await?;
+        let size = file.metadata().await?.len();
+        let client = self.connection.client()?;
+
+        // 1. Open (or re-open) the session to learn where to start
+        let mut query = vec![("name", name.to_string()), ("size", size.to_string())];
+        if let Some(path) = &self.path {
+            query.push(("path", path.clone()));
+        }
+        if self.overwrite {
+            query.push(("overwrite", "true".to_string()));
+        }
+
+        let response = client.post(format!("{}/upload/session", base_url)).query(&query).send().await?;
+        if !response.status().is_success() {
+            return Err(server_error(response).await);
+        }
+        let mut status: UploadStatus = response.json().await?;
+        let session_url = format!("{}/upload/session/{}", base_url, status.id);
+
+        if status.offset > 0 && !status.complete {
+            self.stats.resumed();
+
+            emit("resuming", serde_json::json!({ "name": name, "offset": status.offset }),
+                format_args!("⏯️  Resuming upload of {} from byte {}", name, status.offset));
+        }
+
+        // 2. Send the rest of the file one chunk at a time
+        let progress = transfer_progress("📤", "upload", Some(size), status.offset).counting(&self.stats);
+        let mut buffer = vec![0u8; self.chunk_size.max(1) as usize];
+        while !status.complete {
+            use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+            file.seek(io::SeekFrom::Start(status.offset)).await?;
+            let want = (size - status.offset).min(buffer.len() as u64) as usize;
+            file.read_exact(&mut buffer[..want]).await?;
+
+            let response = client.patch(&session_url)
+                .header(UPLOAD_OFFSET_HEADER, status.offset)
+                .body(buffer[..want].to_vec())
+                .send()
+                .await?;
+
+            if response.status() == reqwest::StatusCode::CONFLICT {
+                // Our offset is stale (e.g. a previous chunk landed but its reply was lost): re-sync
+                status = client.get(&session_url).send().await?.error_for_status()?.json().await?;
+                continue;
+            }
+            if !response.status().is_success() {
+                return Err(server_error(response).await);
+            }
+            status = response.json().await?;
+            progress.set_position(status.offset);
+        }
+
+        progress.finish_and_clear();
+        emit("upload_complete", serde_json::json!({ "name": name, "bytes": size }),
+            format_args!("✅ Upload complete: {} ({} bytes)", name, size));
+        Ok(())
+    }
+
+    /// Uploads everything `reader` produces under the name set with [`Uploader::name`], for
+    /// data that can only be read once, such as stdin. With no size known up front it goes to
+    /// `/upload` as a single multipart request instead of a resumable session, so a dropped
+    /// connection fails the upload rather than retrying it.
+    pub async fn upload_stream<R>(&self, reader: R) -> ClientResult<()>
+    where
+        R: tokio::io::AsyncRead + Send + Unpin + 'static,
+    {
+        let name = self.name.clone().ok_or("Uploading from stdin needs a --name")?;
+        if name.contains(['"', '\r', '\n']) {
+            return Err(format!("{:?} cannot be sent as a file name", name).into());
+        }
+        let stats = TransferStats::default();
+        let progress = std::sync::Arc::new(transfer_progress("📤", "upload", None, 0).counting(&stats));
+
+        // A random boundary, so it cannot turn up in the payload by accident
+        let mut token = [0u8; 16];
+        getrandom::getrandom(&mut token).map_err(|e| format!("Could not generate a multipart boundary: {}", e))?;
+        let boundary: String = token.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let head = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+            boundary, name,
+        );
+        let tail = format!("\r\n--{}--\r\n", boundary);
+
+        let sent = progress.clone();
+        let payload = futures_util::stream::unfold(Some((reader, 0u64)), move |state| {
+            let sent = sent.clone();
+            async move {
+                let (mut reader, total) = state?;
+                let mut buffer = vec![0u8; STREAM_READ_SIZE];
+                match reader.read(&mut buffer).await {
+                    Ok(0) => None,
+                    Ok(read) => {
+                        buffer.truncate(read);
+                        sent.set_position(total + read as u64);
+                        Some((Ok(bytes::Bytes::from(buffer)), Some((reader, total + read as u64))))
+                    }
+                    Err(e) => Some((Err(e), None)),
+                }
+            }
+        });
+        let body = futures_util::stream::once(async move { Ok(bytes::Bytes::from(head)) })
+            .chain(payload)
+            .chain(futures_util::stream::once(async move { Ok(bytes::Bytes::from(tail)) }));
+
+        let mut query = vec![("overwrite", self.overwrite.to_string())];
+        if let Some(path) = &self.path {
+            query.push(("path", path.clone()));
+        }
+        let response = self.connection.client()?
+            .post(format!("{}/upload", self.connection.base_url()))
+            .query(&query)
+            .header(reqwest::header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", boundary))
+            .body(reqwest::Body::wrap_stream(body))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(server_error(response).await);
+        }
+        let stored: Vec<crate::server::UploadedFile> = response.json().await?;
+        progress.finish_and_clear();
+
+        let size = stored.iter().map(|file| file.size).sum::<u64>();
+        emit("upload_complete", serde_json::json!({ "name": name, "bytes": size }),
+            format_args!("✅ Upload complete: {} ({} bytes)", name, size));
+        stats.summarize("upload");
+        Ok(())
+    }
+}
+
+/// How much of a streamed upload is read at a time.
+const STREAM_READ_SIZE: usize = 64 << 10;
+
+// --- ERRORS ---
+
+/// Returns the server's error text for a failed response.
+pub(crate) async fn server_error(response: reqwest::Response) -> Box<dyn std::error::Error> {
+    let status = response.status();
+    let retry_after = response.headers().get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_retry_after);
+    let location = response.headers().get(reqwest::header::LOCATION)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .filter(|_| status.is_redirection());
+    let message = response.text().await.unwrap_or_default();
+    // Redirects only get here when they are not followed
+    if let Some(location) = location {
+        return Box::new(ServerError { status, message: format!("redirected to {}", location), retry_after });
+    }
+    // Some errors, like a quota being exceeded, come as JSON with an `error` explanation
+    let message = serde_json::from_str::<serde_json::Value>(&message).ok()
+        .and_then(|body| body.get("error")?.as_str().map(str::to_string))
+        .unwrap_or(message);
+    Box::new(ServerError { status, message, retry_after })
+}
+
+/// A Retry-After value, either seconds or an HTTP date, as the time left to wait.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    match value.trim().parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            let date = httpdate::parse_http_date(value.trim()).ok()?;
+            Some(date.duration_since(std::time::SystemTime::now()).unwrap_or_default())
+        }
+    }
+}
+
+/// An error status returned by the server.
+#[derive(Debug)]
+pub struct ServerError {
+    pub status: reqwest::StatusCode,
+    /// The response body, usually a short explanation
+    pub message: String,
+    /// How long the server asked the client to wait before trying again (Retry-After), as it
+    /// does when it is busy (503) or the client is over its limits (429)
+    pub retry_after: Option<Duration>,
+}
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Server returned {}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for ServerError {}
@@ -0,0 +1,588 @@
+//! Tarball compression: the [`Compressor`] trait and the [`Codec`] handles built on it.
+//!
+//! Gzip, zstd, xz, bzip2 and store (no compression) are built in. [`register`] adds another
+//! codec, which can then be picked by name like the others: on the command line, in
+//! `?compression=`, with [`ArchiveBuilder::codec`](crate::archive::ArchiveBuilder::codec),
+//! and when an archive's format is detected from its first bytes.
+//!
+//! ```no_run
+//! use rust_file_archive_server::compress::{self, Compressor, Encoder};
+//! use std::io::{self, Read, Write};
+//!
+//! struct Zlib;
+//!
+//! impl Compressor for Zlib {
+//!     fn name(&self) -> &str { "zlib" }
+//!     fn extension(&self) -> Option<&str> { Some("zz") }
+//!     fn content_type(&self) -> &str { "application/zlib" }
+//!     fn default_level(&self) -> i32 { 6 }
+//!     fn level_range(&self) -> std::ops::RangeInclusive<i32> { 0..=9 }
+//!     fn detect(&self, header: &[u8]) -> bool {
+//!         matches!(header, [0x78, second, ..] if (0x7800 | *second as u16) % 31 == 0)
+//!     }
+//!     fn encoder<'a>(&self, writer: &'a mut dyn Write, level: i32) -> io::Result<Box<dyn Encoder + 'a>> {
+//!         let encoder = flate2::write::ZlibEncoder::new(writer, flate2::Compression::new(level as u32));
+//!         Ok(compress::finish_with(encoder, |encoder| encoder.finish().map(drop)))
+//!     }
+//!     fn decoder<'a>(&self, reader: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+//!         Ok(Box::new(flate2::read::ZlibDecoder::new(reader)))
+//!     }
+//! }
+//!
+//! let zlib = compress::register(Zlib)?;
+//! let tarball = rust_file_archive_server::ArchiveBuilder::new().file("notes.txt").codec(zlib).build()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    ops::RangeInclusive,
+    sync::RwLock,
+};
+
+/// A compression format for tarballs.
+pub trait Compressor: Send + Sync {
+    /// The name used on the command line and in `?compression=`, e.g. `gzip`.
+    fn name(&self) -> &str;
+
+    /// Other names accepted for it.
+    fn aliases(&self) -> &[&str] {
+        &[]
+    }
+
+    /// Extension appended after `.tar`, without the leading dot; `None` keeps plain `.tar`.
+    fn extension(&self) -> Option<&str>;
+
+    /// MIME type of a tarball compressed this way.
+    fn content_type(&self) -> &str;
+
+    /// Level used when none is requested.
+    fn default_level(&self) -> i32;
+
+    /// Levels the compressor accepts.
+    fn level_range(&self) -> RangeInclusive<i32>;
+
+    /// Whether `header`, the first 262 bytes of a file (or all of a shorter one), starts a
+    /// stream this compressor wrote.
+    fn detect(&self, header: &[u8]) -> bool;
+
+    /// Starts compressing into `writer` at `level`, which is within `level_range`.
+    fn encoder<'a>(&self, writer: &'a mut dyn Write, level: i32) -> io::Result<Box<dyn Encoder + 'a>>;
+
+    /// Like [`encoder`](Compressor::encoder), spreading the work over `threads` threads (2 or
+    /// more). The output may differ from `encoder`'s, but not between thread counts, so an
+    /// archive keeps its bytes (and ETag) however many threads build it. Compressors that
+    /// cannot work in parallel keep this default, which is `encoder`.
+    fn parallel_encoder<'a>(&self, writer: &'a mut dyn Write, level: i32, threads: usize) -> io::Result<Box<dyn Encoder + 'a>> {
+        let _ = threads;
+        self.encoder(writer, level)
+    }
+
+    /// Decompresses what `reader` yields.
+    fn decoder<'a>(&self, reader: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>>;
+}
+
+/// A compressing writer, which has to be told where the data ends.
+pub trait Encoder: Write {
+    /// Writes whatever the format puts after the data (trailers, checksums) and flushes.
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+/// Turns a compressing writer into an [`Encoder`] whose `finish` calls `finish` on it
+/// (usually the writer's own `finish` method).
+pub fn finish_with<'a, E, F>(writer: E, finish: F) -> Box<dyn Encoder + 'a>
+where
+    E: Write + 'a,
+    F: FnOnce(E) -> io::Result<()> + 'a,
+{
+    Box::new(Finishing { writer, finish })
+}
+
+struct Finishing<E, F> {
+    writer: E,
+    finish: F,
+}
+
+impl<E: Write, F> Write for Finishing<E, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<E: Write, F: FnOnce(E) -> io::Result<()>> Encoder for Finishing<E, F> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        let Finishing { writer, finish } = *self;
+        finish(writer)
+    }
+}
+
+/// Compression applied to tar archives: one of the built-in codecs or a [`register`]ed one.
+/// Zip archives use deflate, or no compression with `Store`.
+#[derive(Clone, Copy)]
+pub struct Codec(&'static dyn Compressor);
+
+#[allow(non_upper_case_globals)]
+impl Codec {
+    pub const Gzip: Codec = Codec(&Gzip);
+    pub const Zstd: Codec = Codec(&Zstd);
+    pub const Xz: Codec = Codec(&Xz);
+    pub const Bzip2: Codec = Codec(&Bzip2);
+    /// No compression, for files that are already compressed (video, images, archives)
+    pub const Store: Codec = Codec(&Store);
+}
+
+const BUILT_IN: &[Codec] = &[Codec::Gzip, Codec::Zstd, Codec::Xz, Codec::Bzip2, Codec::Store];
+
+/// Every codec, built-in ones first. Replaced as a whole on registration, so readers can keep
+/// the slice they got.
+static CODECS: RwLock<&'static [Codec]> = RwLock::new(BUILT_IN);
+
+/// Makes `compressor` available under its name and aliases, which must not be taken yet.
+pub fn register(compressor: impl Compressor + 'static) -> Result<Codec, String> {
+    let mut codecs = CODECS.write().unwrap();
+    let names = || std::iter::once(compressor.name()).chain(compressor.aliases().iter().copied());
+    if let Some(taken) = names().find(|name| codecs.iter().any(|codec| codec.is_called(name))) {
+        return Err(format!("A codec named {} is already registered", taken));
+    }
+
+    let codec = Codec(Box::leak(Box::new(compressor)));
+    let mut all = codecs.to_vec();
+    all.push(codec);
+    *codecs = Box::leak(all.into_boxed_slice());
+    Ok(codec)
+}
+
+impl Codec {
+    /// Every codec that can be used, built-in ones first.
+    pub fn all() -> &'static [Codec] {
+        *CODECS.read().unwrap()
+    }
+
+    /// The codec called `name` (or with it as an alias).
+    pub fn from_name(name: &str) -> Option<Codec> {
+        Codec::all().iter().copied().find(|codec| codec.is_called(name))
+    }
+
+    fn is_called(self, name: &str) -> bool {
+        self.name() == name || self.aliases().contains(&name)
+    }
+
+    /// The name used on the command line and in `?compression=`.
+    pub fn name(self) -> &'static str {
+        self.0.name()
+    }
+
+    pub fn aliases(self) -> &'static [&'static str] {
+        self.0.aliases()
+    }
+
+    /// Extension appended after `.tar`, without the leading dot (none for `Store`).
+    pub fn extension(self) -> Option<&'static str> {
+        self.0.extension()
+    }
+
+    /// MIME type of a tarball compressed with this codec.
+    pub fn content_type(self) -> &'static str {
+        self.0.content_type()
+    }
+
+    /// Level used when none is requested.
+    pub fn default_level(self) -> i32 {
+        self.0.default_level()
+    }
+
+    /// Levels the codec accepts.
+    pub fn level_range(self) -> RangeInclusive<i32> {
+        self.0.level_range()
+    }
+
+    /// Whether `header` starts a stream of this codec; see [`Compressor::detect`].
+    pub fn detect(self, header: &[u8]) -> bool {
+        self.0.detect(header)
+    }
+
+    /// Starts compressing into `writer`; see [`Compressor::encoder`].
+    pub fn encoder<'a>(self, writer: &'a mut dyn Write, level: i32) -> io::Result<Box<dyn Encoder + 'a>> {
+        self.0.encoder(writer, level)
+    }
+
+    /// Starts compressing into `writer` on `threads` threads; a single thread is the same as
+    /// [`encoder`](Codec::encoder). See [`Compressor::parallel_encoder`].
+    pub fn parallel_encoder<'a>(self, writer: &'a mut dyn Write, level: i32, threads: usize) -> io::Result<Box<dyn Encoder + 'a>> {
+        match threads {
+            0 | 1 => self.0.encoder(writer, level),
+            threads => self.0.parallel_encoder(writer, level, threads),
+        }
+    }
+
+    /// Decompresses what `reader` yields.
+    pub fn decoder<'a>(self, reader: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        self.0.decoder(reader)
+    }
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::Gzip
+    }
+}
+
+impl PartialEq for Codec {
+    fn eq(&self, other: &Self) -> bool {
+        // Names are unique, while vtable addresses need not be
+        self.name() == other.name()
+    }
+}
+
+impl Eq for Codec {}
+
+impl fmt::Debug for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for Codec {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, String> {
+        Codec::from_name(name).ok_or_else(|| {
+            let names: Vec<_> = Codec::all().iter().map(|codec| codec.name()).collect();
+            format!("Unknown compression {}: expected one of {}", name, names.join(", "))
+        })
+    }
+}
+
+impl serde::Serialize for Codec {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Codec {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl clap::ValueEnum for Codec {
+    fn value_variants<'a>() -> &'a [Self] {
+        Codec::all()
+    }
+
+    fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
+        Some(clap::builder::PossibleValue::new(self.name()).aliases(self.aliases().iter().copied()))
+    }
+}
+
+// --- BUILT-IN CODECS ---
+
+struct Gzip;
+struct Zstd;
+struct Xz;
+struct Bzip2;
+struct Store;
+
+impl Compressor for Gzip {
+    fn name(&self) -> &str {
+        "gzip"
+    }
+
+    fn extension(&self) -> Option<&str> {
+        Some("gz")
+    }
+
+    fn content_type(&self) -> &str {
+        "application/x-tar"
+    }
+
+    fn default_level(&self) -> i32 {
+        6
+    }
+
+    fn level_range(&self) -> RangeInclusive<i32> {
+        0..=9
+    }
+
+    fn detect(&self, header: &[u8]) -> bool {
+        header.starts_with(&[0x1f, 0x8b])
+    }
+
+    fn encoder<'a>(&self, writer: &'a mut dyn Write, level: i32) -> io::Result<Box<dyn Encoder + 'a>> {
+        let encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::new(level as u32));
+        Ok(finish_with(encoder, |encoder| encoder.finish().map(drop)))
+    }
+
+    fn parallel_encoder<'a>(&self, writer: &'a mut dyn Write, level: i32, threads: usize) -> io::Result<Box<dyn Encoder + 'a>> {
+        Ok(Box::new(ParallelGzip::new(writer, level as u32, threads)?))
+    }
+
+    fn decoder<'a>(&self, reader: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(Box::new(flate2::read::GzDecoder::new(reader)))
+    }
+}
+
+impl Compressor for Zstd {
+    fn name(&self) -> &str {
+        "zstd"
+    }
+
+    fn extension(&self) -> Option<&str> {
+        Some("zst")
+    }
+
+    fn content_type(&self) -> &str {
+        "application/zstd"
+    }
+
+    fn default_level(&self) -> i32 {
+        3
+    }
+
+    fn level_range(&self) -> RangeInclusive<i32> {
+        1..=22
+    }
+
+    fn detect(&self, header: &[u8]) -> bool {
+        header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd])
+    }
+
+    fn encoder<'a>(&self, writer: &'a mut dyn Write, level: i32) -> io::Result<Box<dyn Encoder + 'a>> {
+        let mut encoder = zstd::Encoder::new(writer, level)?;
+        // Lets verification detect corrupted frames, like gzip's CRC
+        encoder.include_checksum(true)?;
+        Ok(finish_with(encoder, |encoder| encoder.finish().map(drop)))
+    }
+
+    fn parallel_encoder<'a>(&self, writer: &'a mut dyn Write, level: i32, threads: usize) -> io::Result<Box<dyn Encoder + 'a>> {
+        let mut encoder = zstd::Encoder::new(writer, level)?;
+        encoder.include_checksum(true)?;
+        // libzstd's worker threads produce the same frames for any number of workers
+        encoder.multithread(u32::try_from(threads).unwrap_or(u32::MAX))?;
+        Ok(finish_with(encoder, |encoder| encoder.finish().map(drop)))
+    }
+
+    fn decoder<'a>(&self, reader: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(Box::new(zstd::Decoder::new(reader)?))
+    }
+}
+
+impl Compressor for Xz {
+    fn name(&self) -> &str {
+        "xz"
+    }
+
+    fn extension(&self) -> Option<&str> {
+        Some("xz")
+    }
+
+    fn content_type(&self) -> &str {
+        "application/x-xz"
+    }
+
+    fn default_level(&self) -> i32 {
+        6
+    }
+
+    fn level_range(&self) -> RangeInclusive<i32> {
+        0..=9
+    }
+
+    fn detect(&self, header: &[u8]) -> bool {
+        header.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00])
+    }
+
+    fn encoder<'a>(&self, writer: &'a mut dyn Write, level: i32) -> io::Result<Box<dyn Encoder + 'a>> {
+        let encoder = xz2::write::XzEncoder::new(writer, level as u32);
+        Ok(finish_with(encoder, |encoder| encoder.finish().map(drop)))
+    }
+
+    fn decoder<'a>(&self, reader: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(Box::new(xz2::read::XzDecoder::new(reader)))
+    }
+}
+
+impl Compressor for Bzip2 {
+    fn name(&self) -> &str {
+        "bzip2"
+    }
+
+    fn extension(&self) -> Option<&str> {
+        Some("bz2")
+    }
+
+    fn content_type(&self) -> &str {
+        "application/x-bzip2"
+    }
+
+    fn default_level(&self) -> i32 {
+        6
+    }
+
+    fn level_range(&self) -> RangeInclusive<i32> {
+        1..=9
+    }
+
+    fn detect(&self, header: &[u8]) -> bool {
+        header.starts_with(b"BZh")
+    }
+
+    fn encoder<'a>(&self, writer: &'a mut dyn Write, level: i32) -> io::Result<Box<dyn Encoder + 'a>> {
+        let encoder = bzip2::write::BzEncoder::new(writer, bzip2::Compression::new(level as u32));
+        Ok(finish_with(encoder, |encoder| encoder.finish().map(drop)))
+    }
+
+    fn decoder<'a>(&self, reader: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(Box::new(bzip2::read::BzDecoder::new(reader)))
+    }
+}
+
+impl Compressor for Store {
+    fn name(&self) -> &str {
+        "store"
+    }
+
+    fn aliases(&self) -> &[&str] {
+        &["none"]
+    }
+
+    fn extension(&self) -> Option<&str> {
+        None
+    }
+
+    fn content_type(&self) -> &str {
+        "application/x-tar"
+    }
+
+    fn default_level(&self) -> i32 {
+        0
+    }
+
+    fn level_range(&self) -> RangeInclusive<i32> {
+        0..=0
+    }
+
+    fn detect(&self, header: &[u8]) -> bool {
+        // The ustar magic sits in the first header of a plain tarball
+        header.get(257..262) == Some(b"ustar")
+    }
+
+    fn encoder<'a>(&self, writer: &'a mut dyn Write, _level: i32) -> io::Result<Box<dyn Encoder + 'a>> {
+        Ok(finish_with(writer, |writer| writer.flush()))
+    }
+
+    fn decoder<'a>(&self, reader: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(reader)
+    }
+}
+
+// --- PARALLEL GZIP ---
+// As pigz does it: the data is cut into blocks of GZIP_BLOCK_SIZE, each block is deflated on
+// its own thread with no history from the block before, and every block but the last ends
+// with a sync flush, which pads it to a byte boundary. The compressed blocks, written in order
+// between one gzip header and trailer, then form a single ordinary deflate stream. Starting
+// each block afresh costs a little compression, and the block size, not the thread count,
+// decides where blocks start, so every thread count gives the same bytes.
+
+/// Uncompressed size of the blocks compressed in parallel.
+const GZIP_BLOCK_SIZE: usize = 1024 * 1024;
+
+struct ParallelGzip<'a> {
+    writer: &'a mut dyn Write,
+    level: u32,
+    threads: usize,
+    /// Data not yet handed to a thread
+    block: Vec<u8>,
+    /// Blocks being compressed, oldest first
+    in_flight: std::collections::VecDeque<std::thread::JoinHandle<io::Result<Vec<u8>>>>,
+    crc: flate2::Crc,
+}
+
+impl<'a> ParallelGzip<'a> {
+    fn new(writer: &'a mut dyn Write, level: u32, threads: usize) -> io::Result<Self> {
+        // Magic, deflate, no flags, no modification time, no extra flags, unknown OS
+        writer.write_all(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255])?;
+        Ok(ParallelGzip {
+            writer,
+            level,
+            threads,
+            block: Vec::with_capacity(GZIP_BLOCK_SIZE),
+            in_flight: Default::default(),
+            crc: flate2::Crc::new(),
+        })
+    }
+
+    /// Hands the current block to a thread, first writing out the oldest compressed block
+    /// if every thread is busy.
+    fn dispatch(&mut self, last: bool) -> io::Result<()> {
+        if self.in_flight.len() >= self.threads {
+            self.write_oldest()?;
+        }
+        let block = std::mem::replace(&mut self.block, Vec::with_capacity(GZIP_BLOCK_SIZE));
+        let level = self.level;
+        self.in_flight.push_back(std::thread::spawn(move || deflate_block(&block, level, last)));
+        Ok(())
+    }
+
+    fn write_oldest(&mut self) -> io::Result<()> {
+        let Some(handle) = self.in_flight.pop_front() else { return Ok(()) };
+        let compressed = handle.join().map_err(|_| io::Error::other("A compression thread panicked"))??;
+        self.writer.write_all(&compressed)
+    }
+}
+
+/// Deflates `block` with no history; all but the `last` block end byte-aligned and unfinished.
+fn deflate_block(block: &[u8], level: u32, last: bool) -> io::Result<Vec<u8>> {
+    let mut encoder = flate2::write::DeflateEncoder::new(Vec::with_capacity(block.len() / 2), flate2::Compression::new(level));
+    encoder.write_all(block)?;
+    match last {
+        true => encoder.finish(),
+        false => {
+            // flush() ends with a sync flush; the encoder's own end-of-stream block is thrown away
+            encoder.flush()?;
+            Ok(std::mem::take(encoder.get_mut()))
+        }
+    }
+}
+
+impl Write for ParallelGzip<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let taken = buf.len().min(GZIP_BLOCK_SIZE - self.block.len());
+        self.block.extend_from_slice(&buf[..taken]);
+        self.crc.update(&buf[..taken]);
+        if self.block.len() == GZIP_BLOCK_SIZE {
+            self.dispatch(false)?;
+        }
+        Ok(taken)
+    }
+
+    /// Blocks only end at block boundaries, so this flushes what is already compressed.
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Encoder for ParallelGzip<'_> {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.dispatch(true)?;
+        while !self.in_flight.is_empty() {
+            self.write_oldest()?;
+        }
+        let (crc, size) = (self.crc.sum(), self.crc.amount());
+        self.writer.write_all(&crc.to_le_bytes())?;
+        self.writer.write_all(&size.to_le_bytes())?;
+        self.writer.flush()
+    }
+}
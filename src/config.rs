@@ -0,0 +1,284 @@
+//! The configuration file (`--config`, by default `~/.config/file_server/config.toml`): the
+//! settings of the command line, kept in one place for deployments with too many to type.
+//!
+//! Keys are long flag names, such as `bind-address` (or `bind_address`). `[server]` holds the
+//! server's flags, `[client]` flags for every client command that has them, such as
+//! `server-url`, and a table named after a command (`[download]`) flags for that command alone.
+//! `true` sets a switch, and an array gives a repeatable flag several values. A flag given on
+//! the command line or through its environment variable wins over the file, and so does one
+//! the file's setting would conflict with.
+
+use clap::{parser::ValueSource, Arg, ArgMatches, Command};
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+use toml::Value;
+
+/// The table of settings every client command reads.
+const CLIENT_TABLE: &str = "client";
+/// The table of the server's settings, named after its subcommand.
+const SERVER_TABLE: &str = "server";
+
+/// Where the configuration is read from when no `--config` is given, if the file exists:
+/// `file_server/config.toml` in `$XDG_CONFIG_HOME`, `~/.config` or `%APPDATA%`.
+pub fn default_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()).map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(base.join("file_server").join("config.toml"))
+}
+
+/// One `key = value` of a table.
+#[derive(Clone, Debug)]
+struct Setting {
+    table: String,
+    key: String,
+    value: Value,
+}
+
+impl Setting {
+    /// Where the setting is, for errors.
+    fn name(&self) -> String {
+        format!("[{}] {}", self.table, self.key)
+    }
+}
+
+/// A configuration file: its settings by table.
+#[derive(Debug, Default)]
+pub struct Config {
+    tables: BTreeMap<String, Vec<Setting>>,
+}
+
+impl Config {
+    pub fn read(path: &Path) -> Result<Config, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        Config::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let document: toml::Table = text.parse().map_err(|e: toml::de::Error| e.message().trim().replace('\n', "; ") + &error_line(text, &e))?;
+        let mut config = Config::default();
+        for (table, settings) in document {
+            let Value::Table(settings) = settings else {
+                return Err(format!("{} is outside any table; put it under [server] or [client]", table));
+            };
+            let settings = settings.into_iter()
+                .map(|(key, value)| Setting { table: table.clone(), key, value })
+                .collect();
+            config.tables.insert(table, settings);
+        }
+        Ok(config)
+    }
+
+    /// `args` (the program's own, its name first) with the settings added that they leave
+    /// open. `command` is the program's command line and `matches` what it made of `args`.
+    /// Fails on tables and keys that match no command or flag, whichever command runs.
+    pub fn apply(&self, mut command: Command, matches: &ArgMatches, mut args: Vec<OsString>) -> Result<Vec<OsString>, String> {
+        // Building propagates global flags and the arguments flattened into every subcommand
+        command.build();
+        self.check(&command)?;
+
+        let (level, level_matches, tables, insert_at) = match matches.subcommand() {
+            None => (&command, matches, vec![SERVER_TABLE], 1),
+            Some((name, sub_matches)) => {
+                let level = command.find_subcommand(name).ok_or_else(|| format!("Unknown command {}", name))?;
+                let tables = match name {
+                    SERVER_TABLE => vec![SERVER_TABLE],
+                    _ => vec![CLIENT_TABLE, name],
+                };
+                (level, sub_matches, tables, subcommand_position(&args, name).map_or(args.len(), |position| position + 1))
+            }
+        };
+
+        // A command's own table overrides what [client] says
+        let mut chosen: Vec<(&Arg, &Setting)> = Vec::new();
+        for setting in tables.iter().filter_map(|table| self.tables.get(*table)).flatten() {
+            let Some(arg) = find_flag(level, &setting.key) else { continue };
+            chosen.retain(|(other, _)| other.get_id() != arg.get_id());
+            chosen.push((arg, setting));
+        }
+
+        let given = |arg: &Arg| matches!(level_matches.value_source(arg.get_id().as_str()), Some(ValueSource::CommandLine | ValueSource::EnvVariable));
+        let mut added = Vec::new();
+        for (arg, setting) in chosen {
+            let conflicts = level.get_arguments().filter(|other| {
+                level.get_arg_conflicts_with(arg).iter().any(|conflict| conflict.get_id() == other.get_id())
+                    || level.get_arg_conflicts_with(other).iter().any(|conflict| conflict.get_id() == arg.get_id())
+            });
+            if given(arg) || conflicts.into_iter().any(given) {
+                continue;
+            }
+            added.extend(flag_args(arg, setting)?);
+        }
+        args.splice(insert_at..insert_at, added);
+        Ok(args)
+    }
+
+    /// Makes sure every table names a command and every key one of its flags.
+    fn check(&self, command: &Command) -> Result<(), String> {
+        for (table, settings) in &self.tables {
+            let levels: Vec<&Command> = match table.as_str() {
+                CLIENT_TABLE => command.get_subcommands().filter(|sub| sub.get_name() != SERVER_TABLE).collect(),
+                name => vec![command.find_subcommand(name)
+                    .ok_or_else(|| format!("[{}] is not a command (tables are [server], [client] or a client command)", table))?],
+            };
+            for setting in settings {
+                if setting.key == "config" {
+                    return Err(format!("{}: the configuration file cannot name another one", setting.name()));
+                }
+                if !levels.iter().any(|level| find_flag(level, &setting.key).is_some()) {
+                    return Err(format!("[{}] has no setting {}", table, setting.key));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where the subcommand `name` is in `args`, after any global flags.
+fn subcommand_position(args: &[OsString], name: &str) -> Option<usize> {
+    let mut position = 1;
+    while let Some(arg) = args.get(position) {
+        if arg == name {
+            return Some(position);
+        }
+        // A global flag's value is not the subcommand, even when it is spelled like one
+        position += if arg == "--config" { 2 } else { 1 };
+    }
+    None
+}
+
+/// The long flag of `command` a key names, with `_` standing for `-`.
+fn find_flag<'a>(command: &'a Command, key: &str) -> Option<&'a Arg> {
+    let key = key.replace('_', "-");
+    command.get_arguments().find(|arg| arg.get_long_and_visible_aliases().is_some_and(|longs| longs.contains(&key.as_str())))
+}
+
+/// The command-line arguments standing for a setting.
+fn flag_args(arg: &Arg, setting: &Setting) -> Result<Vec<OsString>, String> {
+    let long = arg.get_long().unwrap_or_default();
+    if !arg.get_action().takes_values() {
+        return match setting.value {
+            Value::Boolean(true) => Ok(vec![format!("--{}", long).into()]),
+            Value::Boolean(false) => Ok(Vec::new()),
+            _ => Err(format!("{} is a switch, set it to true or false", setting.name())),
+        };
+    }
+    let values = match &setting.value {
+        Value::Array(values) if arg.get_num_args().is_some_and(|range| range.max_values() > 1) || matches!(arg.get_action(), clap::ArgAction::Append) => values.clone(),
+        Value::Array(_) => return Err(format!("{} takes a single value", setting.name())),
+        value => vec![value.clone()],
+    };
+    values.iter()
+        .map(|value| {
+            let value = match value {
+                Value::String(string) => expand_home(string),
+                Value::Integer(integer) => integer.to_string(),
+                Value::Float(float) => float.to_string(),
+                Value::Boolean(boolean) => boolean.to_string(),
+                Value::Datetime(datetime) => datetime.to_string(),
+                Value::Array(_) => return Err(format!("{}: arrays cannot be nested", setting.name())),
+                Value::Table(_) => return Err(format!("{} takes a value, not a table", setting.name())),
+            };
+            Ok(format!("--{}={}", long, value).into())
+        })
+        .collect()
+}
+
+/// Replaces a leading `~/` with the home directory, as a shell would have.
+fn expand_home(value: &str) -> String {
+    match (value.strip_prefix("~/"), std::env::var("HOME")) {
+        (Some(rest), Ok(home)) => format!("{}/{}", home.trim_end_matches('/'), rest),
+        _ => value.to_string(),
+    }
+}
+
+/// The line a parse error is on, as " (line N)", when it points at one.
+fn error_line(text: &str, error: &toml::de::Error) -> String {
+    match error.span() {
+        Some(span) => format!(" (line {})", text[..span.start.min(text.len())].matches('\n').count() + 1),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Arg, ArgAction, Command};
+
+    /// A program shaped like ours: server flags at the top, client commands below.
+    fn command() -> Command {
+        let connection = [
+            Arg::new("server_url").long("server-url"),
+            Arg::new("quiet").long("quiet").action(ArgAction::SetTrue),
+            Arg::new("header").long("header").action(ArgAction::Append),
+        ];
+        let server = [
+            Arg::new("port").long("port"),
+            Arg::new("bind_address").long("bind-address").action(ArgAction::Append),
+        ];
+        Command::new("prog")
+            .arg(Arg::new("config").long("config").global(true))
+            .args(server.clone())
+            .subcommand(Command::new("server").args(server))
+            .subcommand(Command::new("download").args(connection.clone())
+                .arg(Arg::new("output_dir").long("output-dir"))
+                .arg(Arg::new("stdout").long("stdout").action(ArgAction::SetTrue).conflicts_with("output_dir")))
+            .subcommand(Command::new("list").args(connection))
+    }
+
+    /// The arguments the file turns `args` into.
+    fn apply(config: &str, args: &[&str]) -> Result<Vec<String>, String> {
+        let args: Vec<OsString> = args.iter().map(OsString::from).collect();
+        let matches = command().try_get_matches_from(&args).map_err(|e| e.to_string())?;
+        let applied = Config::parse(config)?.apply(command(), &matches, args)?;
+        Ok(applied.into_iter().map(|arg| arg.into_string().unwrap()).collect())
+    }
+
+    #[test]
+    fn server_settings_apply_without_a_subcommand() {
+        let config = "[server]\nport = 9000\nbind-address = [\"127.0.0.1\", \"::1\"]\n";
+        assert_eq!(apply(config, &["prog"]).unwrap(), ["prog", "--bind-address=127.0.0.1", "--bind-address=::1", "--port=9000"]);
+        assert_eq!(apply(config, &["prog", "--port", "1"]).unwrap(), ["prog", "--bind-address=127.0.0.1", "--bind-address=::1", "--port", "1"]);
+    }
+
+    #[test]
+    fn a_command_table_overrides_the_client_table() {
+        let config = "[client]\nserver_url = \"http://a\"\nquiet = true\n[download]\nserver-url = \"http://b\"\n";
+        assert_eq!(apply(config, &["prog", "download"]).unwrap(), ["prog", "download", "--quiet", "--server-url=http://b"]);
+        assert_eq!(apply(config, &["prog", "list"]).unwrap(), ["prog", "list", "--quiet", "--server-url=http://a"]);
+    }
+
+    #[test]
+    fn the_command_line_wins_over_the_file() {
+        let config = "[download]\nserver-url = \"http://b\"\noutput-dir = \"out\"\n";
+        assert_eq!(apply(config, &["prog", "download", "--server-url", "http://c", "--stdout"]).unwrap(),
+            ["prog", "download", "--server-url", "http://c", "--stdout"]);
+    }
+
+    #[test]
+    fn switches_and_repeatable_flags() {
+        assert_eq!(apply("[download]\nquiet = false\n", &["prog", "download"]).unwrap(), ["prog", "download"]);
+        assert_eq!(apply("[download]\nheader = [\"A: 1\", \"B: 2\"]\n", &["prog", "download"]).unwrap(),
+            ["prog", "download", "--header=A: 1", "--header=B: 2"]);
+        assert!(apply("[download]\nquiet = \"yes\"\n", &["prog", "download"]).unwrap_err().contains("is a switch"));
+        assert!(apply("[download]\nserver-url = [\"a\", \"b\"]\n", &["prog", "download"]).unwrap_err().contains("single value"));
+    }
+
+    #[test]
+    fn mistakes_are_reported_whichever_command_runs() {
+        assert!(apply("[client]\nserver-urll = \"x\"\n", &["prog"]).unwrap_err().contains("has no setting server-urll"));
+        assert!(apply("[uplaod]\nquiet = true\n", &["prog", "download"]).unwrap_err().contains("is not a command"));
+        assert!(apply("[server]\nconfig = \"other.toml\"\n", &["prog"]).unwrap_err().contains("cannot name another one"));
+        assert!(Config::parse("port = 1\n").unwrap_err().contains("outside any table"));
+    }
+
+    #[test]
+    fn syntax_errors_name_the_line() {
+        let error = Config::parse("[server]\nport = 1\nroot = /srv\n").unwrap_err();
+        assert!(error.ends_with("(line 3)"), "{}", error);
+        assert!(Config::parse("[server]\nport = 1\nport = 2\n").is_err());
+    }
+}
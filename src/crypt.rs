@@ -0,0 +1,369 @@
+//! Passphrase encryption of whole archives (`--encrypt` on the server, `--decrypt` on the
+//! client) and Ed25519 signatures over them (`--signing-key` and `--verify-key`).
+//!
+//! An encrypted archive is a 32-byte header followed by the archive in 64 KiB chunks, each
+//! sealed with AES-256-GCM-SIV and authenticated together with the header. The key is derived
+//! from the passphrase with scrypt, salted per archive from its ETag, and each chunk's nonce is
+//! its index plus a last-chunk flag (so truncation is detected). The same archive therefore
+//! always encrypts to the same bytes, which keeps Range requests, resumed and segmented
+//! downloads and `/checksum` working. GCM-SIV stays secure even if a nonce ever repeated with
+//! different data; all an observer can learn is whether two downloads carry the same archive.
+
+use aes_gcm_siv::{
+    aead::{AeadInPlace, KeyInit},
+    Aes256GcmSiv, Nonce,
+};
+use std::{
+    fs::File,
+    io::{self, BufRead, Read, Write},
+    path::Path,
+};
+
+use crate::archive::hex_digest;
+
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+/// Extension the server appends to the names of encrypted archives.
+pub const ENCRYPTED_EXTENSION: &str = "enc";
+
+/// First bytes of every encrypted archive; the last one is the format version.
+const MAGIC: &[u8; 8] = b"FSENC\0\0\x01";
+const HEADER_LEN: usize = 32;
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+/// scrypt cost (2^15 iterations, 32 MiB of memory) used for new archives
+const SCRYPT_LOG_N: u8 = 15;
+/// Highest scrypt cost accepted from a header, so a crafted file cannot demand gigabytes of memory
+const MAX_SCRYPT_LOG_N: u8 = 20;
+/// Largest chunk size accepted from a header
+const MAX_CHUNK_SIZE: usize = 16 << 20;
+
+/// The server's passphrase, ready to encrypt archives.
+pub(crate) struct ArchiveEncryption {
+    passphrase: String,
+    /// Stands in for the passphrase in ETags, so changing it invalidates partial downloads
+    /// without revealing anything about it
+    fingerprint: [u8; 32],
+}
+
+impl ArchiveEncryption {
+    pub(crate) fn new(passphrase: &str) -> io::Result<Self> {
+        if passphrase.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The encryption passphrase is empty"));
+        }
+        let fingerprint = derive_key(passphrase, b"file-server etag fingerprint", SCRYPT_LOG_N)?;
+        Ok(ArchiveEncryption { passphrase: passphrase.to_string(), fingerprint })
+    }
+
+    /// The ETag of the encrypted form of the archive tagged `etag`.
+    pub(crate) fn bind_etag(&self, etag: &str) -> String {
+        use sha2::{Digest, Sha256};
+        let digest = Sha256::new().chain_update(etag.as_bytes()).chain_update(self.fingerprint).finalize();
+        format!("\"{}\"", hex_digest(&digest[..16]))
+    }
+
+    /// The key for the archive whose (bound) ETag is `etag`. This runs scrypt, so call it on a
+    /// blocking thread.
+    pub(crate) fn key_for(&self, etag: &str) -> io::Result<SealingKey> {
+        use sha2::{Digest, Sha256};
+        let digest = Sha256::new().chain_update(b"file-server archive salt").chain_update(etag.as_bytes()).finalize();
+        let mut salt = [0u8; 16];
+        salt.copy_from_slice(&digest[..16]);
+        SealingKey::new(&self.passphrase, salt)
+    }
+}
+
+/// A derived key and the header it goes with.
+#[derive(Clone)]
+pub(crate) struct SealingKey {
+    cipher: Aes256GcmSiv,
+    header: [u8; HEADER_LEN],
+}
+
+impl SealingKey {
+    fn new(passphrase: &str, salt: [u8; 16]) -> io::Result<Self> {
+        Self::with_cost(passphrase, salt, SCRYPT_LOG_N)
+    }
+
+    fn with_cost(passphrase: &str, salt: [u8; 16], log_n: u8) -> io::Result<Self> {
+        let mut header = [0u8; HEADER_LEN];
+        header[..8].copy_from_slice(MAGIC);
+        header[8] = log_n;
+        header[12..16].copy_from_slice(&(CHUNK_SIZE as u32).to_be_bytes());
+        header[16..].copy_from_slice(&salt);
+
+        let key = derive_key(passphrase, &salt, log_n)?;
+        Ok(SealingKey { cipher: Aes256GcmSiv::new(&key.into()), header })
+    }
+
+    /// Encrypts a whole archive in memory.
+    pub(crate) fn seal(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let capacity = HEADER_LEN + data.len() + (data.len() / CHUNK_SIZE + 1) * TAG_LEN;
+        let mut writer = self.writer(Vec::with_capacity(capacity))?;
+        writer.write_all(data)?;
+        writer.finish()
+    }
+
+    /// A writer that encrypts everything written to it into `inner`. Call
+    /// [`EncryptWriter::finish`] at the end, or the last chunk is lost.
+    pub(crate) fn writer<W: Write>(&self, mut inner: W) -> io::Result<EncryptWriter<W>> {
+        inner.write_all(&self.header)?;
+        Ok(EncryptWriter { inner, key: self.clone(), buffer: Vec::with_capacity(CHUNK_SIZE + TAG_LEN), index: 0 })
+    }
+}
+
+/// Encrypts an archive as it is written. See [`SealingKey::writer`].
+pub(crate) struct EncryptWriter<W: Write> {
+    inner: W,
+    key: SealingKey,
+    buffer: Vec<u8>,
+    index: u64,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    /// Seals the final chunk and hands back the inner writer.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        self.seal_chunk(true)?;
+        Ok(self.inner)
+    }
+
+    fn seal_chunk(&mut self, last: bool) -> io::Result<()> {
+        self.key.cipher.encrypt_in_place(&chunk_nonce(self.index, last), &self.key.header, &mut self.buffer)
+            .map_err(|_| io::Error::other("Encryption failed"))?;
+        self.inner.write_all(&self.buffer)?;
+        self.buffer.clear();
+        self.index += 1;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut rest = data;
+        while !rest.is_empty() {
+            // A full chunk is only sealed once more data arrives; otherwise it may be the last one
+            if self.buffer.len() == CHUNK_SIZE {
+                self.seal_chunk(false)?;
+            }
+            let take = (CHUNK_SIZE - self.buffer.len()).min(rest.len());
+            self.buffer.extend_from_slice(&rest[..take]);
+            rest = &rest[take..];
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts an archive produced by `--encrypt` from `reader` into `writer`, returning the
+/// number of bytes written. A wrong passphrase, tampering and truncation all fail with
+/// `InvalidData`; whatever was written before the failing chunk should be discarded.
+pub fn decrypt<R: Read, W: Write>(reader: R, mut writer: W, passphrase: &str) -> io::Result<u64> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let mut reader = io::BufReader::new(reader);
+
+    let mut header = [0u8; HEADER_LEN];
+    reader.read_exact(&mut header).map_err(|_| invalid("Not an encrypted archive"))?;
+    if &header[..8] != MAGIC {
+        return Err(invalid("Not an encrypted archive"));
+    }
+    let log_n = header[8];
+    let chunk_size = u32::from_be_bytes(header[12..16].try_into().unwrap()) as usize;
+    if log_n > MAX_SCRYPT_LOG_N || chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+        return Err(invalid("Unsupported encryption parameters"));
+    }
+
+    let key = derive_key(passphrase, &header[16..], log_n)?;
+    let cipher = Aes256GcmSiv::new(&key.into());
+
+    let mut chunk = Vec::with_capacity(chunk_size + TAG_LEN);
+    let mut total = 0u64;
+    for index in 0.. {
+        chunk.clear();
+        (&mut reader).take((chunk_size + TAG_LEN) as u64).read_to_end(&mut chunk)?;
+        let last = chunk.len() < chunk_size + TAG_LEN || reader.fill_buf()?.is_empty();
+
+        cipher.decrypt_in_place(&chunk_nonce(index, last), &header, &mut chunk)
+            .map_err(|_| invalid("Wrong passphrase, or the archive is damaged or incomplete"))?;
+        writer.write_all(&chunk)?;
+        total += chunk.len() as u64;
+
+        if last {
+            break;
+        }
+    }
+    writer.flush()?;
+    Ok(total)
+}
+
+/// Decrypts the file at `source` into `dest`. Nothing is left at `dest` if decryption fails.
+pub fn decrypt_file(source: &Path, dest: &Path, passphrase: &str) -> io::Result<u64> {
+    let result = decrypt(File::open(source)?, io::BufWriter::new(File::create(dest)?), passphrase);
+    if result.is_err() {
+        let _ = std::fs::remove_file(dest);
+    }
+    result
+}
+
+/// Whether the file at `path` starts like an archive produced by `--encrypt`.
+pub fn is_encrypted(path: &Path) -> io::Result<bool> {
+    let mut magic = Vec::with_capacity(MAGIC.len());
+    File::open(path)?.take(MAGIC.len() as u64).read_to_end(&mut magic)?;
+    Ok(has_encrypted_magic(&magic))
+}
+
+pub(crate) fn has_encrypted_magic(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+fn chunk_nonce(index: u64, last: bool) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce.into()
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], log_n: u8) -> io::Result<[u8; 32]> {
+    let params = scrypt::Params::new(log_n, 8, 1, 32)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid scrypt parameters: {}", e)))?;
+    let mut key = [0u8; 32];
+    scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut key)
+        .map_err(|e| io::Error::other(format!("Key derivation failed: {}", e)))?;
+    Ok(key)
+}
+
+// --- SIGNATURES ---
+
+/// Reads an Ed25519 private key from a PKCS#8 PEM file, as written by
+/// `openssl genpkey -algorithm ed25519`.
+pub fn load_signing_key(path: &Path) -> io::Result<SigningKey> {
+    use ed25519_dalek::pkcs8::DecodePrivateKey;
+    let pem = std::fs::read_to_string(path)?;
+    SigningKey::from_pkcs8_pem(&pem)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Not an Ed25519 private key: {}", e)))
+}
+
+/// Reads an Ed25519 public key from a PEM file, as written by `openssl pkey -pubout`.
+pub fn load_verifying_key(path: &Path) -> io::Result<VerifyingKey> {
+    use ed25519_dalek::pkcs8::DecodePublicKey;
+    let pem = std::fs::read_to_string(path)?;
+    VerifyingKey::from_public_key_pem(&pem)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Not an Ed25519 public key: {}", e)))
+}
+
+/// Signs an archive given its SHA-256 digest (hex, as in [`crate::archive::ArchiveChecksum`]).
+/// The signature covers the 32 raw digest bytes, so it can also be checked with
+/// `openssl pkeyutl -verify -rawin` on the output of `openssl dgst -sha256 -binary`.
+pub(crate) fn sign_digest(key: &SigningKey, sha256: &str) -> io::Result<[u8; 64]> {
+    use ed25519_dalek::Signer;
+    Ok(key.sign(&parse_digest(sha256)?).to_bytes())
+}
+
+/// Checks a signature made by [`sign_digest`] against an archive's SHA-256 digest (hex).
+pub fn verify_digest_signature(key: &VerifyingKey, sha256: &str, signature: &[u8]) -> io::Result<()> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let signature = ed25519_dalek::Signature::from_slice(signature).map_err(|_| invalid("Malformed signature"))?;
+    key.verify_strict(&parse_digest(sha256)?, &signature)
+        .map_err(|_| invalid("Signature does not match the archive or the key"))
+}
+
+fn parse_digest(sha256: &str) -> io::Result<[u8; 32]> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("Not a SHA-256 digest: {}", sha256));
+    if sha256.len() != 64 || !sha256.is_ascii() {
+        return Err(invalid());
+    }
+    let mut digest = [0u8; 32];
+    for (byte, pair) in digest.iter_mut().zip(sha256.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).map_err(|_| invalid())?;
+    }
+    Ok(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+    /// A sealed chunk on the wire
+    const SEALED_CHUNK: usize = CHUNK_SIZE + TAG_LEN;
+
+    /// A cheap key, so the tests need not run full-cost scrypt for every archive.
+    fn key() -> SealingKey {
+        SealingKey::with_cost(PASSPHRASE, [7; 16], 4).unwrap()
+    }
+
+    /// Two and a half chunks of data that differs from chunk to chunk.
+    fn data() -> Vec<u8> {
+        (0..CHUNK_SIZE * 5 / 2).map(|index| (index % 251) as u8).collect()
+    }
+
+    fn open(sealed: &[u8], passphrase: &str) -> io::Result<Vec<u8>> {
+        let mut opened = Vec::new();
+        decrypt(sealed, &mut opened, passphrase).map(|_| opened)
+    }
+
+    fn assert_rejected(sealed: &[u8]) {
+        let error = open(sealed, PASSPHRASE).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}", error);
+    }
+
+    #[test]
+    fn round_trip() {
+        for data in [data(), Vec::new(), vec![1; CHUNK_SIZE], vec![2; CHUNK_SIZE * 2]] {
+            let sealed = key().seal(&data).unwrap();
+            assert!(has_encrypted_magic(&sealed));
+            assert_eq!(open(&sealed, PASSPHRASE).unwrap(), data);
+        }
+        // The same archive always seals to the same bytes, which Range requests rely on
+        assert_eq!(key().seal(&data()).unwrap(), key().seal(&data()).unwrap());
+    }
+
+    #[test]
+    fn server_keys_round_trip() {
+        let encryption = ArchiveEncryption::new(PASSPHRASE).unwrap();
+        let etag = encryption.bind_etag("\"archive\"");
+        let sealed = encryption.key_for(&etag).unwrap().seal(b"an archive").unwrap();
+        assert_eq!(open(&sealed, PASSPHRASE).unwrap(), b"an archive");
+        assert!(ArchiveEncryption::new("").is_err());
+    }
+
+    #[test]
+    fn wrong_passphrase_is_rejected() {
+        let sealed = key().seal(&data()).unwrap();
+        let error = open(&sealed, "Tr0ub4dor&3").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(open(b"not encrypted at all, but long enough for a header", PASSPHRASE).is_err());
+    }
+
+    #[test]
+    fn tampering_is_rejected() {
+        let sealed = key().seal(&data()).unwrap();
+        assert_eq!(sealed.len(), HEADER_LEN + 2 * SEALED_CHUNK + CHUNK_SIZE / 2 + TAG_LEN);
+        let chunk = |index: usize| HEADER_LEN + index * SEALED_CHUNK..HEADER_LEN + (index + 1) * SEALED_CHUNK;
+
+        // A changed byte anywhere: the salt, a chunk, a tag
+        for position in [20, HEADER_LEN + 5, chunk(1).start + 1000, sealed.len() - 1] {
+            let mut changed = sealed.clone();
+            changed[position] ^= 1;
+            assert_rejected(&changed);
+        }
+
+        // A dropped chunk
+        let mut dropped = sealed[..chunk(1).start].to_vec();
+        dropped.extend_from_slice(&sealed[chunk(1).end..]);
+        assert_rejected(&dropped);
+
+        // Two chunks swapped
+        let mut reordered = sealed[..HEADER_LEN].to_vec();
+        reordered.extend_from_slice(&sealed[chunk(1)]);
+        reordered.extend_from_slice(&sealed[chunk(0)]);
+        reordered.extend_from_slice(&sealed[chunk(2).start..]);
+        assert_rejected(&reordered);
+
+        // Cut off after a whole chunk, so the last one looks complete
+        assert_rejected(&sealed[..chunk(1).end]);
+        assert_rejected(&sealed[..sealed.len() - 1]);
+    }
+}
@@ -0,0 +1,301 @@
+//! WebDAV access to the served root under `/dav` (`--webdav`), so Finder, Windows Explorer,
+//! rclone and other WebDAV clients can mount it like a network drive.
+//!
+//! This is WebDAV class 1: PROPFIND (depth 0 and 1), GET and HEAD with single byte ranges, and
+//! with `--webdav-writable` also PUT and MKCOL. Paths go through the same sandboxing and
+//! exclusions as `/list` and `/download`. There is no locking, so clients that insist on it
+//! (Finder) mount the share read-only.
+
+use axum::{
+    extract::Request,
+    http::{header, HeaderMap, Method, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures_util::StreamExt;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info};
+
+use crate::archive::ExcludeRules;
+use crate::files::{entry_etag, entry_url};
+use crate::server::{
+    list_directory, list_entry, resolve_in_root, ListEntry, TempFileGuard,
+};
+use crate::storage::Storage;
+use crate::timeouts::body_error_status;
+
+/// Where the share is mounted in the URL space.
+pub(crate) const DAV_PREFIX: &str = "/dav";
+
+/// The served root as a WebDAV share.
+pub(crate) struct DavShare {
+    pub(crate) storage: Storage,
+    /// The canonical root, as in the server state
+    pub(crate) root: PathBuf,
+    pub(crate) exclude: ExcludeRules,
+    /// Whether PUT and MKCOL are accepted; only ever true for a local root
+    pub(crate) writable: bool,
+    /// Largest file a PUT may store
+    pub(crate) max_upload_size: u64,
+}
+
+type DavResult<T> = Result<T, (StatusCode, String)>;
+
+/// Answers one request for a path under [`DAV_PREFIX`].
+pub(crate) async fn handle(share: Arc<DavShare>, request: Request) -> Response {
+    let relative = match request_path(request.uri().path()) {
+        Ok(relative) => relative,
+        Err(e) => return e.into_response(),
+    };
+
+    let method = request.method().clone();
+    let result = match method.as_str() {
+        "OPTIONS" => Ok(options_response(&share)),
+        "PROPFIND" => propfind(&share, relative, request.headers()).await,
+        "GET" | "HEAD" => get(&share, relative, request.headers(), method == Method::HEAD).await,
+        "PUT" if share.writable => put(&share, relative, request).await,
+        "MKCOL" if share.writable => mkcol(&share, relative, request.headers()).await,
+        _ => {
+            debug!("Responding with 405 to WebDAV {} {}", method, relative);
+            Ok((StatusCode::METHOD_NOT_ALLOWED, [(header::ALLOW, allowed_methods(&share))], "Method not allowed on this share").into_response())
+        }
+    };
+    result.unwrap_or_else(IntoResponse::into_response)
+}
+
+/// The methods this share accepts, for `Allow` headers.
+fn allowed_methods(share: &DavShare) -> &'static str {
+    match share.writable {
+        true => "OPTIONS, GET, HEAD, PROPFIND, PUT, MKCOL",
+        false => "OPTIONS, GET, HEAD, PROPFIND",
+    }
+}
+
+fn options_response(share: &DavShare) -> Response {
+    (
+        StatusCode::OK,
+        [
+            (header::ALLOW, allowed_methods(share)),
+            (header::HeaderName::from_static("dav"), "1"),
+            // Makes Windows treat the share as WebDAV rather than FrontPage
+            (header::HeaderName::from_static("ms-author-via"), "DAV"),
+        ],
+    ).into_response()
+}
+
+/// The decoded path below the prefix, `/`-separated and without leading or trailing slashes.
+fn request_path(uri_path: &str) -> DavResult<String> {
+    let rest = uri_path.strip_prefix(DAV_PREFIX).unwrap_or(uri_path);
+    let mut segments = Vec::new();
+    for segment in rest.split('/').filter(|segment| !segment.is_empty() && *segment != ".") {
+        let segment = urlencoding::decode(segment)
+            .map_err(|_| (StatusCode::BAD_REQUEST, format!("Invalid path: {}", uri_path)))?;
+        if segment == ".." || segment.contains(['/', '\\', '\0']) {
+            return Err((StatusCode::BAD_REQUEST, format!("Invalid path: {}", uri_path)));
+        }
+        segments.push(segment.into_owned());
+    }
+    Ok(segments.join("/"))
+}
+
+/// Resolves an existing path in the share, refusing excluded ones like `/download` does.
+fn resolve_existing(share: &DavShare, relative: &str) -> DavResult<PathBuf> {
+    let resolved = resolve_in_root(&share.storage, &share.root, relative)?;
+    let is_dir = share.storage.metadata(&resolved).is_ok_and(|metadata| metadata.is_dir());
+    if share.exclude.is_excluded(&resolved, is_dir) {
+        return Err((StatusCode::FORBIDDEN, format!("Access denied: {}", relative)));
+    }
+    Ok(resolved)
+}
+
+/// Resolves the parent of a path that is about to be created, returning the full new path.
+/// The parent must exist (409 otherwise, as RFC 4918 asks) and the new path must not be excluded.
+fn resolve_new(share: &DavShare, relative: &str, is_dir: bool) -> DavResult<PathBuf> {
+    let (parent, name) = match relative.rsplit_once('/') {
+        Some((parent, name)) => (parent, name),
+        None if relative.is_empty() => return Err((StatusCode::METHOD_NOT_ALLOWED, "The share root already exists".to_string())),
+        None => ("", relative),
+    };
+    let parent_dir = match resolve_in_root(&share.storage, &share.root, parent) {
+        Ok(parent_dir) if parent_dir.is_dir() => parent_dir,
+        Ok(_) | Err((StatusCode::NOT_FOUND, _)) => {
+            return Err((StatusCode::CONFLICT, format!("Parent directory does not exist: {}", parent)));
+        }
+        Err(e) => return Err(e),
+    };
+    crate::server::ensure_portable(Path::new(name))?;
+    let path = parent_dir.join(name);
+    if share.exclude.is_excluded(&parent_dir, true) || share.exclude.is_excluded(&path, is_dir) {
+        return Err((StatusCode::FORBIDDEN, format!("Access denied: {}", relative)));
+    }
+    Ok(path)
+}
+
+// --- PROPFIND ---
+
+/// Describes a file or directory (and with `Depth: 1` its children) as a 207 Multi-Status.
+/// Every live property is returned whichever ones the request body asks for, which clients
+/// accept, so the body is not parsed.
+async fn propfind(share: &Arc<DavShare>, relative: String, headers: &HeaderMap) -> DavResult<Response> {
+    // A missing Depth means infinity per RFC 4918, but clients that omit it expect one level
+    let depth = headers.get("depth").and_then(|value| value.to_str().ok()).map(str::trim);
+    let children = match depth {
+        Some("0") => false,
+        Some("1") | None => true,
+        Some(_) => {
+            return Err((StatusCode::FORBIDDEN, "Only Depth 0 and 1 are supported".to_string()));
+        }
+    };
+
+    let share = share.clone();
+    let entries = tokio::task::spawn_blocking(move || {
+        let resolved = resolve_existing(&share, &relative)?;
+        let relative_path = resolved.strip_prefix(&share.root).map(Path::to_path_buf).unwrap_or_default();
+        let io_error = |e: io::Error| (StatusCode::INTERNAL_SERVER_ERROR, format!("Could not list {}: {}", relative, e));
+
+        let mut entries = vec![list_entry(&share.storage, &resolved, &relative_path).map_err(io_error)?];
+        if children && entries[0].is_dir {
+            entries.extend(list_directory(&share.storage, &share.root, &relative_path, &share.exclude).map_err(io_error)?);
+        }
+        Ok::<_, (StatusCode, String)>(entries)
+    }).await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Listing task failed".to_string()))??;
+
+    Ok((
+        StatusCode::MULTI_STATUS,
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        multistatus(&entries),
+    ).into_response())
+}
+
+/// The PROPFIND response body for `entries`.
+fn multistatus(entries: &[ListEntry]) -> String {
+    use quick_xml::escape::escape;
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n");
+    for entry in entries {
+        let modified = entry.mtime.map(|secs| httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(secs)));
+        xml.push_str("<D:response>");
+        xml.push_str(&format!("<D:href>{}</D:href>", escape(entry_url(DAV_PREFIX, entry).as_str())));
+        xml.push_str("<D:propstat><D:prop>");
+        xml.push_str(&format!("<D:displayname>{}</D:displayname>", escape(entry.name.as_str())));
+        if entry.is_dir {
+            xml.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+        } else {
+            xml.push_str("<D:resourcetype/>");
+            xml.push_str(&format!("<D:getcontentlength>{}</D:getcontentlength>", entry.size));
+            xml.push_str("<D:getcontenttype>application/octet-stream</D:getcontenttype>");
+            xml.push_str(&format!("<D:getetag>{}</D:getetag>", escape(entry_etag(entry.size, entry.mtime).as_str())));
+        }
+        if let Some(modified) = modified {
+            xml.push_str(&format!("<D:getlastmodified>{}</D:getlastmodified>", modified));
+        }
+        xml.push_str("</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat>");
+        xml.push_str("</D:response>\n");
+    }
+    xml.push_str("</D:multistatus>\n");
+    xml
+}
+
+// --- GET ---
+
+/// Sends a file, or the single byte range asked for. Requests for several ranges get the
+/// whole file, which the RFC allows and WebDAV clients never ask for anyway.
+async fn get(share: &Arc<DavShare>, relative: String, headers: &HeaderMap, head: bool) -> DavResult<Response> {
+    let lookup = share.clone();
+    let (path, metadata) = tokio::task::spawn_blocking(move || {
+        let path = resolve_existing(&lookup, &relative)?;
+        let metadata = lookup.storage.metadata(&path)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Could not read {}: {}", relative, e)))?;
+        if metadata.is_dir() {
+            return Err((StatusCode::METHOD_NOT_ALLOWED, format!("{} is a collection; use PROPFIND to list it", relative)));
+        }
+        Ok((path, metadata))
+    }).await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Lookup task failed".to_string()))??;
+
+    crate::files::send_file(&share.storage, path, &metadata, headers, head, "application/octet-stream")
+}
+
+// --- PUT / MKCOL ---
+
+/// Stores the request body as a file, replacing an existing one. The body goes to a hidden
+/// temporary file next to it first, so readers never see a half-written file.
+async fn put(share: &Arc<DavShare>, relative: String, request: Request) -> DavResult<Response> {
+    if request.headers().contains_key(header::CONTENT_RANGE) {
+        return Err((StatusCode::BAD_REQUEST, "Partial PUT with Content-Range is not supported".to_string()));
+    }
+
+    let lookup = (share.clone(), relative.clone());
+    let path = tokio::task::spawn_blocking(move || resolve_new(&lookup.0, &lookup.1, false)).await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Lookup task failed".to_string()))??;
+    let existed = match tokio::fs::metadata(&path).await {
+        Ok(metadata) if metadata.is_dir() => {
+            return Err((StatusCode::METHOD_NOT_ALLOWED, format!("{} is a collection", relative)));
+        }
+        Ok(_) => true,
+        Err(_) => false,
+    };
+
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let part_path = path.with_file_name(format!(".{}.upload", name));
+    let mut file = tokio::fs::File::create(&part_path).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Could not create {}: {}", part_path.display(), e)))?;
+    // Removes the partial file on every early return and when the client goes away midway
+    let mut part_guard = TempFileGuard(Some(part_path.clone()));
+
+    let mut size = 0u64;
+    let mut body = request.into_body().into_data_stream();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| (body_error_status(&e, StatusCode::BAD_REQUEST), format!("Error reading upload: {}", e)))?;
+        size += chunk.len() as u64;
+        if size > share.max_upload_size {
+            return Err((StatusCode::PAYLOAD_TOO_LARGE, format!("Upload exceeds the limit of {} bytes", share.max_upload_size)));
+        }
+        file.write_all(&chunk).await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Error writing upload: {}", e)))?;
+    }
+    file.flush().await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Error writing upload: {}", e)))?;
+    drop(file);
+
+    tokio::fs::rename(&part_path, &path).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Could not store {}: {}", path.display(), e)))?;
+    part_guard.0 = None;
+
+    info!("Stored {} ({} bytes) via WebDAV", path.display(), size);
+    Ok(match existed {
+        true => StatusCode::NO_CONTENT,
+        false => StatusCode::CREATED,
+    }.into_response())
+}
+
+/// Creates a directory whose parent exists.
+async fn mkcol(share: &Arc<DavShare>, relative: String, headers: &HeaderMap) -> DavResult<Response> {
+    // MKCOL bodies are reserved for extensions this server does not know
+    let has_body = headers.get(header::CONTENT_LENGTH).is_some_and(|length| length != "0")
+        || headers.contains_key(header::TRANSFER_ENCODING);
+    if has_body {
+        return Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, "MKCOL request bodies are not supported".to_string()));
+    }
+
+    let lookup = (share.clone(), relative.clone());
+    let path = tokio::task::spawn_blocking(move || resolve_new(&lookup.0, &lookup.1, true)).await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Lookup task failed".to_string()))??;
+
+    match tokio::fs::create_dir(&path).await {
+        Ok(()) => {
+            info!("Created directory {} via WebDAV", path.display());
+            Ok(StatusCode::CREATED.into_response())
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            Err((StatusCode::METHOD_NOT_ALLOWED, format!("{} already exists", relative)))
+        }
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Could not create {}: {}", relative, e))),
+    }
+}
@@ -0,0 +1,196 @@
+//! Finding servers on the local network with mDNS/DNS-SD.
+//!
+//! A server started with `--announce` registers itself as a `_fileserver._tcp` service (see
+//! [`Announcement`]); [`discover`] browses for those services and [`resolve`] looks one up by
+//! name, so clients can say `--server nas` instead of spelling out a URL.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io,
+    net::IpAddr,
+    time::Duration,
+};
+
+/// DNS-SD service type servers announce themselves under.
+pub const SERVICE_TYPE: &str = "_fileserver._tcp.local.";
+/// How long clients listen for announcements by default.
+pub const DEFAULT_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A server that answered on the local network.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DiscoveredServer {
+    /// The name the server announced itself under
+    pub name: String,
+    /// Its mDNS host name, e.g. `nas.local.`
+    pub host: String,
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    /// Whether it serves HTTPS
+    pub tls: bool,
+    /// A client sharing the archive it downloads (`download --share`) rather than a server
+    #[serde(default)]
+    pub peer: bool,
+}
+
+impl DiscoveredServer {
+    /// A base URL for the server, preferring an IPv4 LAN address. Loopback is used only when
+    /// it is all there is, and IPv6 link-local addresses (which need a zone) never.
+    pub fn url(&self) -> Option<String> {
+        let preference = |address: &&IpAddr| match address {
+            IpAddr::V4(v4) if !v4.is_loopback() => 0,
+            IpAddr::V6(v6) if !v6.is_loopback() && (v6.segments()[0] & 0xffc0) != 0xfe80 => 1,
+            IpAddr::V4(_) => 2,
+            IpAddr::V6(v6) if v6.is_loopback() => 3,
+            IpAddr::V6(_) => 4,
+        };
+        let address = self.addresses.iter().filter(|address| preference(address) < 4).min_by_key(preference)?;
+        let scheme = if self.tls { "https" } else { "http" };
+        Some(match address {
+            IpAddr::V4(address) => format!("{}://{}:{}", scheme, address, self.port),
+            IpAddr::V6(address) => format!("{}://[{}]:{}", scheme, address, self.port),
+        })
+    }
+}
+
+/// Keeps a server announced on the local network until it is dropped.
+pub struct Announcement {
+    daemon: mdns_sd::ServiceDaemon,
+    name: String,
+    fullname: String,
+}
+
+impl Announcement {
+    /// Registers `name` as a server listening on `port` on every local address.
+    pub fn start(name: &str, port: u16, tls: bool) -> io::Result<Self> {
+        Self::register(name, port, &[("tls", if tls { "1" } else { "0" })])
+    }
+
+    /// Registers `name` as a client sharing its download on `port` (see [`crate::peer`]).
+    pub fn start_peer(name: &str, port: u16) -> io::Result<Self> {
+        Self::register(name, port, &[("tls", "0"), ("peer", "1")])
+    }
+
+    fn register(name: &str, port: u16, properties: &[(&str, &str)]) -> io::Result<Self> {
+        let mdns_error = |e: mdns_sd::Error| io::Error::other(e.to_string());
+
+        let daemon = mdns_sd::ServiceDaemon::new().map_err(mdns_error)?;
+        let host = format!("{}.local.", host_label(name));
+        let mut properties = properties.to_vec();
+        properties.push(("version", env!("CARGO_PKG_VERSION")));
+        let service = mdns_sd::ServiceInfo::new(SERVICE_TYPE, name, &host, "", port, &properties[..])
+            .map_err(mdns_error)?
+            .enable_addr_auto();
+        let fullname = service.get_fullname().to_string();
+        daemon.register(service).map_err(mdns_error)?;
+        Ok(Announcement { daemon, name: name.to_string(), fullname })
+    }
+
+    /// The name clients pass as `--server`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Drop for Announcement {
+    fn drop(&mut self) {
+        // Say goodbye so browsers forget the server now rather than when the record expires
+        if let Ok(status) = self.daemon.unregister(&self.fullname) {
+            let _ = status.recv_timeout(Duration::from_secs(1));
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// The name servers announce themselves under by default: this machine's host name.
+pub fn default_name() -> String {
+    let name = gethostname::gethostname().to_string_lossy().into_owned();
+    match name.split('.').next() {
+        Some(label) if !label.is_empty() => label.to_string(),
+        _ => "file-server".to_string(),
+    }
+}
+
+/// Listens for `timeout` and returns every server that announced itself, sorted by name.
+pub async fn discover(timeout: Duration) -> io::Result<Vec<DiscoveredServer>> {
+    browse(timeout, |_| false).await
+}
+
+/// Collects announced servers for up to `timeout`, stopping early once `done` accepts one.
+async fn browse(timeout: Duration, done: impl Fn(&DiscoveredServer) -> bool) -> io::Result<Vec<DiscoveredServer>> {
+    let mdns_error = |e: mdns_sd::Error| io::Error::other(e.to_string());
+
+    let daemon = mdns_sd::ServiceDaemon::new().map_err(mdns_error)?;
+    let events = daemon.browse(SERVICE_TYPE).map_err(mdns_error)?;
+    let mut servers = BTreeMap::new();
+
+    let deadline = tokio::time::Instant::now() + timeout;
+    while let Ok(Ok(event)) = tokio::time::timeout_at(deadline, events.recv_async()).await {
+        match event {
+            mdns_sd::ServiceEvent::ServiceResolved(service) => {
+                let mut addresses: Vec<IpAddr> = service.addresses.iter().map(|address| address.to_ip_addr()).collect();
+                addresses.sort();
+                let server = DiscoveredServer {
+                    name: instance_name(&service.fullname),
+                    host: service.host.clone(),
+                    addresses,
+                    port: service.port,
+                    tls: service.txt_properties.get_property_val_str("tls") == Some("1"),
+                    peer: service.txt_properties.get_property_val_str("peer") == Some("1"),
+                };
+                let finished = done(&server);
+                servers.insert(service.fullname.clone(), server);
+                if finished {
+                    break;
+                }
+            }
+            mdns_sd::ServiceEvent::ServiceRemoved(_, fullname) => {
+                servers.remove(&fullname);
+            }
+            _ => {}
+        }
+    }
+
+    let _ = daemon.shutdown();
+    let mut servers: Vec<_> = servers.into_values().collect();
+    servers.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(servers)
+}
+
+/// Finds the server announced as `name` (compared case-insensitively) and returns its URL.
+pub async fn resolve(name: &str, timeout: Duration) -> io::Result<String> {
+    let matches = |server: &DiscoveredServer| server.name.eq_ignore_ascii_case(name);
+    let servers = browse(timeout, matches).await?;
+    let server = servers.iter().find(|server| matches(server))
+        .ok_or_else(|| {
+            let found: Vec<_> = servers.iter().map(|server| server.name.as_str()).collect();
+            let found = if found.is_empty() { "none".to_string() } else { found.join(", ") };
+            io::Error::new(io::ErrorKind::NotFound, format!("No server named {} on the local network (found: {})", name, found))
+        })?;
+    server.url().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Server {} announced no address", name)))
+}
+
+/// The instance part of a full service name, with DNS-SD escapes undone.
+fn instance_name(fullname: &str) -> String {
+    let escaped = fullname.strip_suffix(SERVICE_TYPE).unwrap_or(fullname).trim_end_matches('.');
+    let mut name = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => name.extend(chars.next()),
+            c => name.push(c),
+        }
+    }
+    name
+}
+
+/// A host name label derived from an instance name: letters, digits and hyphens only.
+fn host_label(name: &str) -> String {
+    let label: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+        .collect();
+    match label.trim_matches('-') {
+        "" => "file-server".to_string(),
+        label => label.to_string(),
+    }
+}
@@ -0,0 +1,229 @@
+//! Compressing responses on the fly for clients that send `Accept-Encoding`: single files,
+//! listings, the web UI and other text the server sends as it is.
+//!
+//! Only content types that compress well are touched, so archives, which are compressed
+//! already (or were asked for uncompressed), images and media go out unchanged. Brotli is
+//! preferred, then zstd, then gzip, among what the client accepts. Range requests and HEAD get
+//! the plain representation, which stays the only one ranges apply to. Compressed
+//! responses carry a weak form of the ETag, which conditional requests still match.
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    response::Response,
+};
+use futures_util::StreamExt;
+use std::io::{self, Write};
+
+/// Responses known to be smaller than this are not worth compressing.
+const MIN_COMPRESSED_SIZE: u64 = 1024;
+const ZSTD_LEVEL: i32 = 3;
+/// Brotli quality (0-11) and window (log2 of its size), fast enough for on-the-fly compression.
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+/// Bytes the brotli encoder buffers before compressing.
+const BROTLI_BUFFER: usize = 64 * 1024;
+
+/// Content types worth compressing, by prefix after dropping parameters.
+const COMPRESSIBLE_TYPES: &[&str] = &[
+    "text/",
+    "application/json",
+    "application/xml",
+    "application/javascript",
+    "application/wasm",
+    "image/svg+xml",
+    "image/x-icon",
+    "font/ttf",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// What a request's Accept-Encoding allows, brotli first, then zstd. Codings with `q=0` are refused, and
+/// `*` stands for any coding not named.
+fn accepted_encoding(headers: &HeaderMap) -> Option<Encoding> {
+    let accepted = headers.get_all(header::ACCEPT_ENCODING).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|coding| {
+            let mut parts = coding.split(';');
+            let name = parts.next()?.trim().to_ascii_lowercase();
+            let quality = parts
+                .find_map(|parameter| parameter.trim().strip_prefix("q=").map(|q| q.trim().parse::<f32>().unwrap_or(0.0)))
+                .unwrap_or(1.0);
+            Some((name, quality))
+        })
+        .collect::<Vec<_>>();
+    let allows = |encoding: Encoding| {
+        let named = accepted.iter().find(|(name, _)| name == encoding.name());
+        let any = accepted.iter().find(|(name, _)| name == "*");
+        named.or(any).is_some_and(|&(_, quality)| quality > 0.0)
+    };
+    [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip].into_iter().find(|&encoding| allows(encoding))
+}
+
+fn is_compressible(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()) else {
+        return false;
+    };
+    let essence = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    // Server-sent events must reach the client as they happen, not when a compressor lets go
+    essence != "text/event-stream" && COMPRESSIBLE_TYPES.iter().any(|prefix| essence.starts_with(prefix))
+}
+
+/// Middleware compressing a response for the client's Accept-Encoding when it is worth it.
+pub(crate) async fn negotiate_encoding(request: axum::extract::Request, next: axum::middleware::Next) -> Response {
+    let eligible = request.method() == Method::GET && !request.headers().contains_key(header::RANGE);
+    let encoding = accepted_encoding(request.headers());
+    let mut response = next.run(request).await;
+
+    let headers = response.headers();
+    if !is_compressible(headers) || headers.contains_key(header::CONTENT_ENCODING) {
+        return response;
+    }
+    // Whether the response would be compressed depends on the request's Accept-Encoding
+    response.headers_mut().append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    let length = response.headers().get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let (Some(encoding), true, StatusCode::OK) = (encoding, eligible, response.status()) else {
+        return response;
+    };
+    if length.is_some_and(|length| length < MIN_COMPRESSED_SIZE) {
+        return response;
+    }
+
+    let headers = response.headers_mut();
+    headers.remove(header::CONTENT_LENGTH);
+    headers.remove(header::ACCEPT_RANGES);
+    headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+    let weak_etag = headers.get(header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .filter(|etag| !etag.starts_with("W/"))
+        .and_then(|etag| HeaderValue::try_from(format!("W/{}", etag)).ok());
+    if let Some(etag) = weak_etag {
+        headers.insert(header::ETAG, etag);
+    }
+    response.map(|body| compress_body(body, encoding))
+}
+
+/// An encoder writing into a buffer that is drained after every chunk.
+enum Encoder {
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(Vec::new(), BROTLI_BUFFER, BROTLI_QUALITY, BROTLI_WINDOW))),
+            Encoding::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), ZSTD_LEVEL)?),
+            Encoding::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default())),
+        })
+    }
+
+    /// Compresses `data`, returning whatever output is ready.
+    fn compress(&mut self, data: &[u8]) -> io::Result<bytes::Bytes> {
+        let output = match self {
+            Encoder::Brotli(encoder) => {
+                encoder.write_all(data)?;
+                encoder.get_mut()
+            }
+            Encoder::Zstd(encoder) => {
+                encoder.write_all(data)?;
+                encoder.get_mut()
+            }
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(data)?;
+                encoder.get_mut()
+            }
+        };
+        Ok(bytes::Bytes::from(std::mem::take(output)))
+    }
+
+    fn finish(self) -> io::Result<bytes::Bytes> {
+        let output = match self {
+            Encoder::Brotli(encoder) => encoder.into_inner(),
+            Encoder::Zstd(encoder) => encoder.finish()?,
+            Encoder::Gzip(encoder) => encoder.finish()?,
+        };
+        Ok(bytes::Bytes::from(output))
+    }
+}
+
+fn compress_body(body: Body, encoding: Encoding) -> Body {
+    let encoder = match Encoder::new(encoding) {
+        Ok(encoder) => encoder,
+        Err(e) => return Body::from_stream(futures_util::stream::once(async move { Err::<bytes::Bytes, _>(e) })),
+    };
+    let stream = futures_util::stream::unfold(Some((body.into_data_stream(), encoder)), |state| async move {
+        let (mut stream, mut encoder) = state?;
+        loop {
+            let result = match stream.next().await {
+                Some(Ok(data)) => encoder.compress(&data),
+                Some(Err(e)) => Err(io::Error::other(e)),
+                None => return Some((encoder.finish(), None)),
+            };
+            match result {
+                Ok(output) if output.is_empty() => continue,
+                Ok(output) => return Some((Ok(output), Some((stream, encoder)))),
+                Err(e) => return Some((Err(e), None)),
+            }
+        }
+    });
+    Body::from_stream(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepting(value: &str) -> Option<Encoding> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_str(value).unwrap());
+        accepted_encoding(&headers)
+    }
+
+    #[test]
+    fn brotli_is_preferred_among_accepted_codings() {
+        assert_eq!(accepting("gzip, deflate, br, zstd"), Some(Encoding::Brotli));
+        assert_eq!(accepting("gzip, zstd"), Some(Encoding::Zstd));
+        assert_eq!(accepting("br;q=0, gzip"), Some(Encoding::Gzip));
+        assert_eq!(accepting("*"), Some(Encoding::Brotli));
+        assert_eq!(accepting("*, br;q=0, zstd;q=0"), Some(Encoding::Gzip));
+        assert_eq!(accepting("identity"), None);
+    }
+
+    #[test]
+    fn every_encoding_round_trips() {
+        use std::io::Read;
+        let text = "a listing that compresses well ".repeat(200);
+        for encoding in [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip] {
+            let mut encoder = Encoder::new(encoding).unwrap();
+            let mut compressed = encoder.compress(text.as_bytes()).unwrap().to_vec();
+            compressed.extend_from_slice(&encoder.finish().unwrap());
+            let mut decoded = Vec::new();
+            match encoding {
+                Encoding::Brotli => brotli::Decompressor::new(&compressed[..], 4096).read_to_end(&mut decoded),
+                Encoding::Zstd => zstd::stream::read::Decoder::new(&compressed[..]).unwrap().read_to_end(&mut decoded),
+                Encoding::Gzip => flate2::read::GzDecoder::new(&compressed[..]).read_to_end(&mut decoded),
+            }.unwrap();
+            assert_eq!(decoded, text.as_bytes(), "{:?}", encoding);
+        }
+    }
+}
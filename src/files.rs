@@ -0,0 +1,339 @@
+//! Sending single files as they are, for `GET /file/<path>`, WebDAV GETs, `--static` and
+//! `/browse`, and the filler `GET /bench` sends in place of a file.
+//!
+//! A file is answered with a Content-Type guessed from its extension, an ETag built from its
+//! size and modification time, and a single byte range when one is asked for. Requests for
+//! several ranges get the whole file, which the RFC allows. In static mode a directory is
+//! answered with its `index.html`, or else with an HTML index of its entries, which `/browse`
+//! shows for every directory.
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::{
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+use tracing::error;
+
+use crate::server::{if_range_matches, ListEntry, not_modified, not_modified_response, parse_range_header, RangeRequest, STREAM_CHUNK_SIZE};
+use crate::storage::{Storage, StorageMetadata};
+
+/// Size of the pieces ranged GETs read from storage; each is one request for object stores.
+const RANGE_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Content types by lowercase extension. Anything else is sent as `application/octet-stream`.
+const CONTENT_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("xml", "application/xml"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("log", "text/plain; charset=utf-8"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/x-icon"),
+    ("avif", "image/avif"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("flac", "audio/flac"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("mkv", "video/x-matroska"),
+    ("pdf", "application/pdf"),
+    ("wasm", "application/wasm"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("zip", "application/zip"),
+    ("tar", "application/x-tar"),
+    ("gz", "application/gzip"),
+    ("tgz", "application/gzip"),
+    ("zst", "application/zstd"),
+    ("xz", "application/x-xz"),
+    ("bz2", "application/x-bzip2"),
+];
+
+/// The Content-Type a file is sent with, by its extension.
+pub(crate) fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|extension| extension.to_str()).map(str::to_ascii_lowercase);
+    extension
+        .and_then(|extension| CONTENT_TYPES.iter().find(|(known, _)| *known == extension))
+        .map_or("application/octet-stream", |(_, content_type)| content_type)
+}
+
+/// A validator that changes whenever a file's size or modification time does.
+pub(crate) fn entry_etag(size: u64, mtime: Option<u64>) -> String {
+    format!("\"{:x}-{:x}\"", size, mtime.unwrap_or(0))
+}
+
+/// Answers a GET or HEAD for the file at `path`: 304 when the client's copy is current, the
+/// single range asked for, or the whole file.
+pub(crate) fn send_file(
+    storage: &Storage,
+    path: PathBuf,
+    metadata: &StorageMetadata,
+    headers: &HeaderMap,
+    head: bool,
+    content_type: &'static str,
+) -> Result<Response, (StatusCode, String)> {
+    let size = metadata.len;
+    let mtime = metadata.modified.duration_since(UNIX_EPOCH).ok().map(|duration| duration.as_secs());
+    let etag = entry_etag(size, mtime);
+    if not_modified(headers, &etag, metadata.modified) {
+        return Ok(not_modified_response(&etag, metadata.modified));
+    }
+
+    let range = match headers.get(header::RANGE) {
+        Some(value) if if_range_matches(headers, &etag) => parse_range_header(value, size)?,
+        _ => RangeRequest::Full,
+    };
+    let (status, start, length) = match range {
+        RangeRequest::Partial(ranges) if ranges.len() == 1 => (StatusCode::PARTIAL_CONTENT, ranges[0].start, ranges[0].length),
+        RangeRequest::Partial(_) | RangeRequest::Full => (StatusCode::OK, 0, size),
+        RangeRequest::Unsatisfiable => {
+            return Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{}", size))],
+            ).into_response());
+        }
+    };
+
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response_headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+    response_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if let Ok(etag) = HeaderValue::from_str(&etag) {
+        response_headers.insert(header::ETAG, etag);
+    }
+    if let Ok(modified) = HeaderValue::from_str(&httpdate::fmt_http_date(metadata.modified)) {
+        response_headers.insert(header::LAST_MODIFIED, modified);
+    }
+    if status == StatusCode::PARTIAL_CONTENT {
+        let content_range = format!("bytes {}-{}/{}", start, start + length - 1, size);
+        response_headers.insert(header::CONTENT_RANGE, HeaderValue::from_str(&content_range).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?);
+    }
+
+    let body = match head {
+        true => Body::empty(),
+        false => file_body(storage.clone(), path, start, length, status == StatusCode::OK),
+    };
+    Ok((status, response_headers, body).into_response())
+}
+
+/// Reads `length` bytes of a file from `start` on a blocking thread, handing them to the body
+/// as they come. Whole files are read sequentially; ranges in pieces, so object stores can
+/// fetch just that part.
+fn file_body(storage: Storage, path: PathBuf, start: u64, length: u64, whole: bool) -> Body {
+    let (tx, rx) = tokio::sync::mpsc::channel::<io::Result<bytes::Bytes>>(4);
+
+    tokio::task::spawn_blocking(move || {
+        let send = |chunk: Vec<u8>| tx.blocking_send(Ok(bytes::Bytes::from(chunk))).is_ok();
+        let result = (|| {
+            if whole {
+                let mut reader = storage.open(&path)?;
+                loop {
+                    let mut chunk = vec![0; STREAM_CHUNK_SIZE];
+                    let read = reader.read(&mut chunk)?;
+                    if read == 0 {
+                        return Ok(());
+                    }
+                    chunk.truncate(read);
+                    if !send(chunk) {
+                        return Ok(());
+                    }
+                }
+            }
+            let mut offset = 0;
+            while offset < length {
+                let chunk = storage.read_range(&path, start + offset, RANGE_CHUNK_SIZE.min(length - offset))?;
+                if chunk.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File shrank while it was being sent"));
+                }
+                offset += chunk.len() as u64;
+                if !send(chunk) {
+                    return Ok(());
+                }
+            }
+            Ok(())
+        })();
+
+        if let Err(e) = result {
+            error!("Error while sending {}: {}", path.display(), e);
+            let _ = tx.blocking_send(Err(e));
+        }
+    });
+
+    let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|item| (item, rx))
+    });
+    Body::from_stream(stream)
+}
+
+// --- BENCHMARK PAYLOAD ---
+// `GET /bench?size=N` sends N bytes that cost the server neither disk reads nor archive
+// building, so the `bench` command can measure the network and the HTTP stack alone.
+
+/// Largest payload `/bench` sends (64 GiB).
+pub(crate) const MAX_BENCH_SIZE: u64 = 64 << 30;
+/// Length of the block the payload repeats.
+const FILLER_SIZE: usize = 1 << 20;
+
+/// Answers a GET or HEAD for `size` bytes of filler, or the single range of it asked for. The
+/// bytes are pseudo-random, so compression along the way cannot flatter the numbers, and the
+/// same at every offset, so ranges line up.
+pub(crate) fn send_filler(size: u64, headers: &HeaderMap, head: bool) -> Result<Response, (StatusCode, String)> {
+    let range = match headers.get(header::RANGE) {
+        Some(value) => parse_range_header(value, size)?,
+        None => RangeRequest::Full,
+    };
+    let (status, start, length) = match range {
+        RangeRequest::Partial(ranges) if ranges.len() == 1 => (StatusCode::PARTIAL_CONTENT, ranges[0].start, ranges[0].length),
+        RangeRequest::Partial(_) | RangeRequest::Full => (StatusCode::OK, 0, size),
+        RangeRequest::Unsatisfiable => {
+            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, [(header::CONTENT_RANGE, format!("bytes */{}", size))]).into_response());
+        }
+    };
+
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+    response_headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+    response_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    response_headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    if status == StatusCode::PARTIAL_CONTENT {
+        let content_range = format!("bytes {}-{}/{}", start, start + length - 1, size);
+        response_headers.insert(header::CONTENT_RANGE, HeaderValue::from_str(&content_range).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?);
+    }
+    if head || length == 0 {
+        return Ok((status, response_headers, Body::empty()).into_response());
+    }
+
+    // One piece per block the range touches
+    let filler = filler();
+    let end = start + length;
+    let stream = futures_util::stream::iter(std::iter::successors(Some(start), move |&offset| {
+        let next = (offset / FILLER_SIZE as u64 + 1) * FILLER_SIZE as u64;
+        (next < end).then_some(next)
+    }).map(move |offset| {
+        let from = (offset % FILLER_SIZE as u64) as usize;
+        let to = FILLER_SIZE.min(from + (end - offset) as usize);
+        Ok::<_, io::Error>(filler.slice(from..to))
+    }));
+    Ok((status, response_headers, Body::from_stream(stream)).into_response())
+}
+
+/// The block of pseudo-random bytes, made on first use.
+fn filler() -> bytes::Bytes {
+    static FILLER: std::sync::OnceLock<bytes::Bytes> = std::sync::OnceLock::new();
+    FILLER.get_or_init(|| {
+        // xorshift64: fast, and random enough that no compressor finds anything to squeeze
+        let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+        let block: Vec<u8> = (0..FILLER_SIZE / 8).flat_map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state.to_le_bytes()
+        }).collect();
+        bytes::Bytes::from(block)
+    }).clone()
+}
+
+// --- DIRECTORY LISTINGS ---
+
+/// The file a directory is answered with in static mode, when it has one.
+pub(crate) const INDEX_FILE: &str = "index.html";
+
+/// The URL of an entry below `prefix`, with each segment percent-encoded and directories
+/// ending in `/`.
+pub(crate) fn entry_url(prefix: &str, entry: &ListEntry) -> String {
+    let mut url = prefix.trim_end_matches('/').to_string();
+    for segment in entry.path.split('/').filter(|segment| !segment.is_empty()) {
+        url.push('/');
+        url.push_str(&urlencoding::encode(segment));
+    }
+    if entry.is_dir {
+        url.push('/');
+    }
+    url
+}
+
+/// Where the links of HTML directory indexes point, and whether a directory's own
+/// `index.html` is sent instead.
+pub(crate) struct DirectoryPages {
+    /// Directories are at this prefix followed by their path
+    pub(crate) prefix: &'static str,
+    /// Files are at this prefix followed by their path
+    pub(crate) file_prefix: &'static str,
+    pub(crate) use_index_file: bool,
+}
+
+/// `--static`: everything at its own path.
+pub(crate) const STATIC_PAGES: DirectoryPages = DirectoryPages { prefix: "", file_prefix: "", use_index_file: true };
+/// `/browse`: always the index, with files sent from `/file`.
+pub(crate) const BROWSE_PAGES: DirectoryPages = DirectoryPages { prefix: "/browse", file_prefix: "/file", use_index_file: false };
+
+/// An HTML index of the directory at `relative` (`/`-separated, empty for the root): breadcrumbs
+/// back up the tree, then a table of its entries with their sizes and modification times.
+pub(crate) fn directory_index(pages: &DirectoryPages, relative: &str, entries: &[ListEntry]) -> String {
+    use quick_xml::escape::escape;
+
+    let segments: Vec<&str> = relative.split('/').filter(|segment| !segment.is_empty()).collect();
+    let title = format!("Index of /{}", segments.iter().map(|segment| format!("{}/", segment)).collect::<String>());
+    let mut html = format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<meta name=\"viewport\" content=\"width=device-width\">\n<title>{}</title>\n{}</head>\n<body>\n", escape(title.as_str()), INDEX_STYLE);
+
+    // Each crumb links to the directory it names; the last one is where we are
+    html.push_str("<nav>");
+    let mut href = format!("{}/", pages.prefix);
+    for (i, name) in std::iter::once("root").chain(segments.iter().copied()).enumerate() {
+        if i > 0 {
+            href.push_str(&urlencoding::encode(name));
+            href.push('/');
+            html.push_str(" / ");
+        }
+        match i == segments.len() {
+            true => html.push_str(&format!("<strong>{}</strong>", escape(name))),
+            false => html.push_str(&format!("<a href=\"{}\">{}</a>", escape(href.as_str()), escape(name))),
+        }
+    }
+    html.push_str("</nav>\n<table>\n<thead><tr><th>Name</th><th>Size</th><th>Modified</th></tr></thead>\n<tbody>\n");
+
+    if let Some((_, parents)) = segments.split_last() {
+        let parent: String = parents.iter().map(|segment| format!("{}/", urlencoding::encode(segment))).collect();
+        html.push_str(&format!("<tr><td><a href=\"{}/{}\">../</a></td><td></td><td></td></tr>\n", pages.prefix, escape(parent.as_str())));
+    }
+    for entry in entries {
+        let (url, name, size) = match entry.is_dir {
+            true => (entry_url(pages.prefix, entry), format!("{}/", entry.name), String::new()),
+            false => (entry_url(pages.file_prefix, entry), entry.name.clone(), crate::format_size(entry.size)),
+        };
+        let modified = entry.mtime.map(crate::format_unix_time).unwrap_or_default();
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            escape(url.as_str()), escape(name.as_str()), size, modified,
+        ));
+    }
+    html.push_str("</tbody>\n</table>\n</body>\n</html>\n");
+    html
+}
+
+/// Just enough style for the index to read as a table.
+const INDEX_STYLE: &str = "<style>\n\
+    body { font-family: system-ui, sans-serif; margin: 2em; }\n\
+    nav { margin-bottom: 1em; }\n\
+    table { border-collapse: collapse; }\n\
+    th, td { padding: 0.2em 1.5em 0.2em 0; text-align: left; }\n\
+    td:nth-child(2) { text-align: right; }\n\
+    </style>\n";
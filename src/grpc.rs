@@ -0,0 +1,357 @@
+//! The gRPC control plane (`--grpc`): the `fileserver.v1.FileServer` service described in
+//! `proto/fileserver.proto`, for programs that would rather make typed calls than parse the
+//! HTTP API's JSON. Archives are still fetched over HTTP, from the URL a job reports. The
+//! messages and the service are generated from the .proto by tonic (see `build.rs`).
+//!
+//! Every call is answered by the HTTP route doing the same thing (`/list`, `/download?async=1`,
+//! `/progress`, `/admin`), so authentication, per-user roots, draining and the limits apply
+//! exactly as they do there: the call's `authorization` metadata is the route's credential, and
+//! the route's error becomes the matching gRPC status. Calls need HTTP/2, over TLS or as h2c on
+//! a plain connection.
+
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, Extensions, HeaderMap, Method, StatusCode},
+    response::{IntoResponse, Response},
+    Router,
+};
+use futures_util::StreamExt;
+use serde::de::DeserializeOwned;
+use std::{convert::Infallible, pin::Pin};
+use tonic::Status;
+use tower::ServiceExt;
+use tracing::debug;
+
+use crate::admin::{AdminStatus, Transfer};
+use crate::archive::BuildProgress;
+use crate::server::{JobFailed, JobFinished, JobQueued, JobStarted, ListEntry};
+
+/// The messages and the service trait generated from `proto/fileserver.proto`.
+mod proto {
+    tonic::include_proto!("fileserver.v1");
+}
+
+use proto::file_server_server::{FileServer, FileServerServer};
+
+/// Where the service's methods live.
+pub(crate) const SERVICE_PATH: &str = "/fileserver.v1.FileServer";
+/// The largest HTTP answer read while serving a call.
+const MAX_ANSWER_SIZE: usize = 64 * 1024 * 1024;
+
+/// The methods standing for admin API calls, which like those are not counted as transfers.
+const ADMIN_METHODS: &[&str] = &["ListTransfers", "GetStatus", "Drain"];
+
+/// Puts the service in front of `app`, whose routes answer its calls. `admin` is whether the
+/// server has the admin API the transfer and drain calls need.
+pub(crate) fn layer(app: Router, admin: bool) -> Router {
+    // Dispatching before any routing, so the calls' requests carry no path parameters of their own
+    let service = FileServerServer::new(Calls { app: app.clone(), admin });
+    Router::new().fallback_service(tower::service_fn(move |request: Request| {
+        let (app, service) = (app.clone(), service.clone());
+        async move {
+            let call = request.uri().path().strip_prefix(SERVICE_PATH).is_some_and(|method| method.starts_with('/'));
+            Ok::<_, Infallible>(match call {
+                true => {
+                    debug!("gRPC call {}", request.uri().path());
+                    let response = service.oneshot(request).await.unwrap_or_else(|never| match never {});
+                    response.map(Body::new)
+                }
+                false => app.oneshot(request).await.unwrap_or_else(|never| match never {}),
+            })
+        }
+    }))
+}
+
+/// Whether `path` is a call of an admin method.
+pub(crate) fn is_admin_call(path: &str) -> bool {
+    path.strip_prefix(SERVICE_PATH)
+        .and_then(|method| method.strip_prefix('/'))
+        .is_some_and(|method| ADMIN_METHODS.contains(&method))
+}
+
+#[derive(Clone)]
+struct Calls {
+    app: Router,
+    admin: bool,
+}
+
+/// The status standing for an HTTP error answer.
+fn status_from_http(status: StatusCode, message: &str) -> Status {
+    use tonic::Code;
+    let code = match status {
+        StatusCode::BAD_REQUEST | StatusCode::RANGE_NOT_SATISFIABLE => Code::InvalidArgument,
+        StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+        StatusCode::FORBIDDEN => Code::PermissionDenied,
+        StatusCode::NOT_FOUND | StatusCode::GONE => Code::NotFound,
+        StatusCode::CONFLICT | StatusCode::PRECONDITION_FAILED => Code::FailedPrecondition,
+        StatusCode::PAYLOAD_TOO_LARGE | StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+        StatusCode::NOT_IMPLEMENTED => Code::Unimplemented,
+        StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+        StatusCode::GATEWAY_TIMEOUT | StatusCode::REQUEST_TIMEOUT => Code::DeadlineExceeded,
+        status if status.is_server_error() => Code::Internal,
+        _ => Code::Unknown,
+    };
+    let message = match message.trim() {
+        "" => status.to_string(),
+        message => message.to_string(),
+    };
+    Status::new(code, message)
+}
+
+/// A call being served, and what its HTTP requests copy from it.
+struct Call {
+    app: Router,
+    headers: HeaderMap,
+    extensions: Extensions,
+}
+
+impl Calls {
+    /// Splits a call into what its HTTP requests copy and its message.
+    fn call<T>(&self, request: tonic::Request<T>) -> (Call, T) {
+        let (metadata, extensions, message) = request.into_parts();
+        (Call { app: self.app.clone(), headers: metadata.into_headers(), extensions }, message)
+    }
+
+    #[allow(clippy::result_large_err)] // The generated methods return Status as it is
+    fn need_admin(&self) -> Result<(), Status> {
+        match self.admin {
+            true => Ok(()),
+            false => Err(Status::unimplemented("This server has no admin API (start it with --admin-token)")),
+        }
+    }
+}
+
+impl Call {
+    /// Sends the route standing for the call a request carrying the call's credentials and
+    /// connection.
+    async fn send(&self, method: Method, uri: &str) -> Response {
+        let mut request = Request::new(Body::empty());
+        *request.method_mut() = method;
+        *request.uri_mut() = match uri.parse() {
+            Ok(uri) => uri,
+            Err(_) => return (StatusCode::BAD_REQUEST, "Invalid path").into_response(),
+        };
+        for name in [header::AUTHORIZATION, header::USER_AGENT, header::HOST] {
+            if let Some(value) = self.headers.get(&name) {
+                request.headers_mut().insert(name, value.clone());
+            }
+        }
+        *request.extensions_mut() = self.extensions.clone();
+        self.app.clone().oneshot(request).await.unwrap_or_else(|never| match never {})
+    }
+
+    /// Sends a request and reads its JSON answer, or the status its error stands for.
+    async fn fetch<T: DeserializeOwned>(&self, method: Method, uri: &str) -> Result<T, Status> {
+        let response = self.send(method, uri).await;
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), MAX_ANSWER_SIZE).await
+            .map_err(|e| Status::internal(format!("Could not read the answer: {}", e)))?;
+        if !status.is_success() {
+            return Err(status_from_http(status, &String::from_utf8_lossy(&body)));
+        }
+        serde_json::from_slice(&body).map_err(|e| Status::internal(format!("Unexpected answer: {}", e)))
+    }
+
+    /// Reads or changes the admin status: `GET /admin`, or `POST`/`DELETE /admin/drain`.
+    async fn status(&self, method: Method) -> Result<tonic::Response<proto::ServerStatus>, Status> {
+        let uri = match method {
+            Method::GET => crate::admin::ADMIN_PREFIX.to_string(),
+            _ => format!("{}/drain", crate::admin::ADMIN_PREFIX),
+        };
+        let status: AdminStatus = self.fetch(method, &uri).await?;
+        Ok(tonic::Response::new(proto::ServerStatus {
+            draining: status.draining,
+            requests_in_flight: status.requests_in_flight as u64,
+        }))
+    }
+}
+
+type ProgressStream = Pin<Box<dyn futures_util::Stream<Item = Result<proto::ArchiveJobProgress, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl FileServer for Calls {
+    async fn list(&self, request: tonic::Request<proto::ListRequest>) -> Result<tonic::Response<proto::ListResponse>, Status> {
+        let (call, request) = self.call(request);
+        let uri = match request.path.is_empty() {
+            true => "/list".to_string(),
+            false => format!("/list?path={}", urlencoding::encode(&request.path)),
+        };
+        let entries: Vec<ListEntry> = call.fetch(Method::GET, &uri).await?;
+        let entries = entries.into_iter()
+            .map(|entry| proto::Entry { name: entry.name, path: entry.path, size: entry.size, mtime: entry.mtime, is_dir: entry.is_dir })
+            .collect();
+        Ok(tonic::Response::new(proto::ListResponse { entries }))
+    }
+
+    async fn create_archive_job(&self, request: tonic::Request<proto::ArchiveRequest>) -> Result<tonic::Response<proto::ArchiveJob>, Status> {
+        let (call, request) = self.call(request);
+        let mut query: Vec<String> = request.files.iter()
+            .map(|file| format!("files={}", urlencoding::encode(file)))
+            .collect();
+        for (parameter, value) in [("format", &request.format), ("compression", &request.compression), ("preset", &request.preset)] {
+            if !value.is_empty() {
+                query.push(format!("{}={}", parameter, urlencoding::encode(value)));
+            }
+        }
+        if let Some(level) = request.level {
+            query.push(format!("level={}", level));
+        }
+        if request.flatten {
+            query.push("flatten=true".to_string());
+        }
+        query.push("async=1".to_string());
+        let started: JobStarted = call.fetch(Method::GET, &format!("/download?{}", query.join("&"))).await?;
+        Ok(tonic::Response::new(proto::ArchiveJob {
+            job_id: started.job_id,
+            progress_url: started.progress_url,
+            download_url: started.download_url,
+        }))
+    }
+
+    type WatchArchiveJobStream = ProgressStream;
+
+    /// Streams a job's progress as its `/progress` events arrive, the last message or status
+    /// telling how the build ended.
+    async fn watch_archive_job(&self, request: tonic::Request<proto::WatchRequest>) -> Result<tonic::Response<ProgressStream>, Status> {
+        let (call, request) = self.call(request);
+        if request.job_id.is_empty() {
+            return Err(Status::invalid_argument("No job_id given"));
+        }
+        let response = call.send(Method::GET, &format!("/progress/{}", urlencoding::encode(&request.job_id))).await;
+        let status = response.status();
+        if !status.is_success() {
+            let body = axum::body::to_bytes(response.into_body(), MAX_ANSWER_SIZE).await.unwrap_or_default();
+            return Err(status_from_http(status, &String::from_utf8_lossy(&body)));
+        }
+
+        let events = crate::client::ServerEvents::default();
+        let messages = futures_util::stream::unfold(Some((response.into_body().into_data_stream(), events)), |state| async move {
+            let (mut body, mut events) = state?;
+            loop {
+                if let Some((event, data)) = events.next_event() {
+                    match progress_message(&event, &data) {
+                        Some(Ok(message)) if event == "done" => return Some((Ok(message), None)),
+                        Some(Ok(message)) => return Some((Ok(message), Some((body, events)))),
+                        Some(Err(status)) => return Some((Err(status), None)),
+                        None => continue,
+                    }
+                }
+                match body.next().await {
+                    Some(Ok(chunk)) => events.push(&chunk),
+                    Some(Err(e)) => return Some((Err(Status::unavailable(format!("Lost the job's progress: {}", e))), None)),
+                    None => return Some((Err(Status::unavailable("The job's progress ended before the build did")), None)),
+                }
+            }
+        });
+        Ok(tonic::Response::new(Box::pin(messages)))
+    }
+
+    async fn list_transfers(&self, request: tonic::Request<proto::ListTransfersRequest>) -> Result<tonic::Response<proto::ListTransfersResponse>, Status> {
+        self.need_admin()?;
+        let (call, _) = self.call(request);
+        let transfers: Vec<Transfer> = call.fetch(Method::GET, &format!("{}/transfers", crate::admin::ADMIN_PREFIX)).await?;
+        let transfers = transfers.into_iter()
+            .map(|transfer| proto::Transfer {
+                id: transfer.id,
+                client: transfer.client,
+                method: transfer.method,
+                path: transfer.path,
+                bytes_sent: transfer.bytes_sent,
+                seconds: transfer.seconds,
+            })
+            .collect();
+        Ok(tonic::Response::new(proto::ListTransfersResponse { transfers }))
+    }
+
+    async fn get_status(&self, request: tonic::Request<proto::GetStatusRequest>) -> Result<tonic::Response<proto::ServerStatus>, Status> {
+        self.need_admin()?;
+        self.call(request).0.status(Method::GET).await
+    }
+
+    async fn drain(&self, request: tonic::Request<proto::DrainRequest>) -> Result<tonic::Response<proto::ServerStatus>, Status> {
+        self.need_admin()?;
+        let (call, request) = self.call(request);
+        call.status(if request.undo { Method::DELETE } else { Method::POST }).await
+    }
+}
+
+/// The message for a `/progress` event, the status a failed build ends with, or `None` for
+/// events to skip.
+fn progress_message(event: &str, data: &str) -> Option<Result<proto::ArchiveJobProgress, Status>> {
+    match event {
+        "progress" => {
+            let progress: BuildProgress = serde_json::from_str(data).ok()?;
+            Some(Ok(proto::ArchiveJobProgress {
+                bytes_processed: progress.bytes_processed,
+                bytes_total: progress.bytes_total,
+                files_added: progress.files_added,
+                files_total: progress.files_total,
+                ..Default::default()
+            }))
+        }
+        "queued" => {
+            let queued: JobQueued = serde_json::from_str(data).ok()?;
+            Some(Ok(proto::ArchiveJobProgress { queue_position: queued.position as u64, ..Default::default() }))
+        }
+        "done" => {
+            let finished: JobFinished = serde_json::from_str(data).ok()?;
+            Some(Ok(proto::ArchiveJobProgress {
+                done: true,
+                size: finished.size,
+                download_url: finished.download_url,
+                ..Default::default()
+            }))
+        }
+        "failed" => {
+            let failed: JobFailed = serde_json::from_str(data).ok()?;
+            let status = StatusCode::from_u16(failed.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            Some(Err(status_from_http(status, &failed.message)))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_events_become_messages() {
+        let progress = progress_message("progress", r#"{"bytes_processed":5,"bytes_total":10,"files_added":1,"files_total":2}"#);
+        let progress = progress.unwrap().unwrap();
+        assert_eq!((progress.bytes_processed, progress.bytes_total, progress.files_added, progress.files_total), (5, 10, 1, 2));
+        assert!(!progress.done);
+
+        let queued = progress_message("queued", r#"{"position":3}"#).unwrap().unwrap();
+        assert_eq!(queued.queue_position, 3);
+
+        let done = progress_message("done", r#"{"size":42,"download_url":"/download?job=abc"}"#).unwrap().unwrap();
+        assert!(done.done);
+        assert_eq!((done.size, done.download_url.as_str()), (42, "/download?job=abc"));
+
+        assert!(progress_message("ping", "{}").is_none());
+    }
+
+    #[test]
+    fn failed_builds_end_with_the_matching_status() {
+        let failed = progress_message("failed", r#"{"status":404,"message":"No such file: x"}"#).unwrap().unwrap_err();
+        assert_eq!(failed.code(), tonic::Code::NotFound);
+        assert_eq!(failed.message(), "No such file: x");
+    }
+
+    #[test]
+    fn http_errors_map_to_grpc_codes() {
+        assert_eq!(status_from_http(StatusCode::UNAUTHORIZED, "").code(), tonic::Code::Unauthenticated);
+        assert_eq!(status_from_http(StatusCode::SERVICE_UNAVAILABLE, "draining").code(), tonic::Code::Unavailable);
+        assert_eq!(status_from_http(StatusCode::BAD_GATEWAY, "").code(), tonic::Code::Internal);
+        assert_eq!(status_from_http(StatusCode::FORBIDDEN, "  ").message(), "403 Forbidden");
+    }
+
+    #[test]
+    fn only_admin_methods_count_as_admin_calls() {
+        assert!(is_admin_call("/fileserver.v1.FileServer/Drain"));
+        assert!(!is_admin_call("/fileserver.v1.FileServer/List"));
+        assert!(!is_admin_call("/fileserver.v1.FileServerDrain"));
+    }
+}
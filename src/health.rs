@@ -0,0 +1,139 @@
+//! `GET /healthz` and `GET /readyz`, for Kubernetes probes and load balancers.
+//!
+//! `/healthz` answers as long as the server serves requests at all. `/readyz` also checks that
+//! the server can do its job: the root can be listed, --cache-dir and --upload-dir can be
+//! written to, the directories it writes to have at least --min-free-space left, and it is not
+//! draining. Both sit outside authentication, since probes carry no credentials, so their
+//! answers name what failed but no paths.
+
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use serde::Serialize;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tracing::debug;
+
+use crate::admin::ServerControl;
+use crate::storage::Storage;
+
+/// What `/readyz` checks.
+pub(crate) struct Readiness {
+    pub(crate) storage: Storage,
+    pub(crate) root: PathBuf,
+    pub(crate) cache_dir: Option<PathBuf>,
+    pub(crate) upload_dir: Option<PathBuf>,
+    /// A local root clients write to (`--webdav-writable`), whose free space counts too
+    pub(crate) writable_root: bool,
+    pub(crate) min_free_space: Option<u64>,
+    pub(crate) control: Arc<ServerControl>,
+}
+
+/// Body of a `/readyz` response.
+#[derive(Serialize, Debug)]
+struct ReadinessReport {
+    ready: bool,
+    checks: Vec<Check>,
+}
+
+#[derive(Serialize, Debug)]
+struct Check {
+    name: &'static str,
+    ok: bool,
+    /// Why it failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Makes probe file names unique across concurrent checks.
+static NEXT_PROBE: AtomicU64 = AtomicU64::new(0);
+
+impl Readiness {
+    fn check(&self) -> Vec<Check> {
+        let mut checks = Vec::new();
+        let mut add = |name, result: Result<(), String>| checks.push(Check { name, ok: result.is_ok(), error: result.err() });
+
+        add("root", self.storage.read_dir(&self.root).map(drop).map_err(|e| format!("The root cannot be listed: {}", e)));
+        if let Some(dir) = &self.cache_dir {
+            add("cache_dir", probe_writable(dir).map_err(|e| format!("The cache directory is not writable: {}", e)));
+        }
+        if let Some(dir) = &self.upload_dir {
+            add("upload_dir", probe_writable(dir).map_err(|e| format!("The upload directory is not writable: {}", e)));
+        }
+        if let Some(min_free_space) = self.min_free_space {
+            let written = [
+                ("cache directory", self.cache_dir.as_deref()),
+                ("upload directory", self.upload_dir.as_deref()),
+                ("root", self.writable_root.then_some(self.root.as_path())),
+            ];
+            let short = written.into_iter()
+                .filter_map(|(label, dir)| Some((label, dir?)))
+                .map(|(label, dir)| match available_space(dir) {
+                    Ok(available) if available < min_free_space => Err(format!(
+                        "The {} has {} free, less than {}", label, crate::format_size(available), crate::format_size(min_free_space),
+                    )),
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(format!("Free space of the {} cannot be read: {}", label, e)),
+                })
+                .collect::<Result<Vec<()>, String>>();
+            add("free_space", short.map(drop));
+        }
+        let draining = self.control.is_draining();
+        add("not_draining", if draining { Err("The server is draining".to_string()) } else { Ok(()) });
+        checks
+    }
+}
+
+/// Creates and removes a file in `dir`. Its `.tmp` extension gets it cleaned up with the
+/// cache's other leftovers should removing it fail.
+fn probe_writable(dir: &Path) -> io::Result<()> {
+    let probe = dir.join(format!(".readyz.{}.{}.tmp", std::process::id(), NEXT_PROBE.fetch_add(1, Ordering::Relaxed)));
+    std::fs::write(&probe, b"")?;
+    std::fs::remove_file(&probe)
+}
+
+/// Bytes an unprivileged process can still write to the file system holding `path`.
+fn available_space(path: &Path) -> io::Result<u64> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+        let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+        if unsafe { libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let stats = unsafe { stats.assume_init() };
+        #[allow(clippy::unnecessary_cast)] // The field types differ between platforms
+        Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        Err(io::Error::new(io::ErrorKind::Unsupported, "not supported on this platform"))
+    }
+}
+
+/// The probe routes, which the caller keeps outside authentication and draining.
+pub(crate) fn routes<S>(readiness: Arc<Readiness>) -> Router<S> {
+    Router::new()
+        .route("/healthz", get(|| async { "ok" }))
+        .route("/readyz", get(readyz_handler))
+        .with_state(readiness)
+}
+
+async fn readyz_handler(State(readiness): State<Arc<Readiness>>) -> (StatusCode, Json<ReadinessReport>) {
+    // Listing the root may need the storage backend
+    let checks = tokio::task::spawn_blocking(move || readiness.check()).await
+        .unwrap_or_else(|_| vec![Check { name: "checks", ok: false, error: Some("Readiness check task failed".to_string()) }]);
+    let ready = checks.iter().all(|check| check.ok);
+    if !ready {
+        let failed: Vec<_> = checks.iter().filter_map(|check| check.error.as_deref()).collect();
+        debug!("Responding with 503 to /readyz: {}", failed.join("; "));
+    }
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(ReadinessReport { ready, checks }))
+}
@@ -0,0 +1,203 @@
+//! Experimental HTTP/3: the server's routes over QUIC (`--http3`, cargo feature `http3`).
+//!
+//! A QUIC endpoint listens on the same port numbers as TCP, over UDP, with the server's TLS
+//! certificate. Requests go through the same router, access log and rate limits as HTTP/1.1
+//! and HTTP/2; TCP responses advertise the endpoint in `Alt-Svc`.
+
+use axum::{body::Body, Router};
+use bytes::{Buf, Bytes};
+use futures_util::StreamExt;
+use std::{io, net::SocketAddr, sync::Arc, time::Instant};
+use tokio::sync::{mpsc, watch};
+use tracing::{debug, warn};
+
+use crate::limits::ConnectionSlot;
+use crate::metrics::Metrics;
+use crate::rate::RateLimiter;
+use crate::server::{handle_request, Peer, RateLimits};
+
+/// Held by every connection and request in flight; the receiving end sees the channel close
+/// once all of them are done.
+type InFlight = mpsc::Sender<()>;
+
+/// Opens a QUIC endpoint on `addr` with the certificate and key of the HTTPS listener. IPv6
+/// endpoints are IPv6-only, like the TCP listeners.
+pub(crate) fn bind_endpoint(addr: SocketAddr, tls: &tokio_rustls::rustls::ServerConfig) -> io::Result<quinn::Endpoint> {
+    let mut tls = tls.clone();
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+    let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.bind(&addr.into())?;
+    quinn::Endpoint::new(quinn::EndpointConfig::default(), Some(config), socket.into(), Arc::new(quinn::TokioRuntime))
+}
+
+/// Serves HTTP/3 on `endpoints` until `stopping` carries the time shutdown was requested, then
+/// asks clients to finish (GOAWAY) and waits up to `drain_timeout` from then for requests in
+/// flight. Whatever is still running after that is cut off.
+pub(crate) async fn serve(
+    endpoints: Vec<quinn::Endpoint>,
+    app: Router,
+    rate_limits: RateLimits,
+    metrics: Arc<Metrics>,
+    mut stopping: watch::Receiver<Option<Instant>>,
+    drain_timeout: std::time::Duration,
+) {
+    let (in_flight, mut drained) = mpsc::channel::<()>(1);
+
+    let accept_loops: Vec<_> = endpoints.iter().map(|endpoint| {
+        let (endpoint, app, rate_limits, metrics) = (endpoint.clone(), app.clone(), rate_limits.clone(), metrics.clone());
+        let (mut stopping, in_flight) = (stopping.clone(), in_flight.clone());
+        tokio::spawn(async move {
+            loop {
+                let incoming = tokio::select! {
+                    incoming = endpoint.accept() => match incoming {
+                        Some(incoming) => incoming,
+                        None => break,
+                    },
+                    _ = stopping.changed() => break,
+                };
+                let connection = serve_connection(incoming, app.clone(), rate_limits.clone(), metrics.clone(), stopping.clone(), drain_timeout, in_flight.clone());
+                tokio::spawn(connection);
+            }
+        })
+    }).collect();
+    drop(in_flight);
+
+    let stopped_at = match stopping.wait_for(Option::is_some).await {
+        Ok(stopped_at) => stopped_at.unwrap_or_else(Instant::now),
+        Err(_) => Instant::now(),
+    };
+    for accept_loop in accept_loops {
+        let _ = accept_loop.await;
+    }
+
+    // Every connection and request holds a sender; recv() returns None once they are all gone
+    let deadline = tokio::time::Instant::from_std(stopped_at + drain_timeout);
+    if tokio::time::timeout_at(deadline, drained.recv()).await.is_err() {
+        warn!("Shutdown timeout elapsed, dropping remaining HTTP/3 connections");
+    }
+    for endpoint in &endpoints {
+        endpoint.close(0u32.into(), b"server shutting down");
+    }
+}
+
+/// Accepts requests on one QUIC connection, each in its own task, until the client closes it
+/// or shutdown is requested.
+async fn serve_connection(
+    incoming: quinn::Incoming,
+    app: Router,
+    rate_limits: RateLimits,
+    metrics: Arc<Metrics>,
+    mut stopping: watch::Receiver<Option<Instant>>,
+    drain_timeout: std::time::Duration,
+    in_flight: InFlight,
+) {
+    let remote_addr = incoming.remote_address();
+    let connection = match incoming.await {
+        Ok(connection) => connection,
+        Err(e) => {
+            debug!("QUIC handshake with {} failed: {}", remote_addr, e);
+            return;
+        }
+    };
+
+    let _connection = metrics.track_connection();
+    let limiters = rate_limits.for_connection();
+    let slot = rate_limits.open_connection(Peer::Ip(remote_addr));
+    let quic_connection = connection.clone();
+    let mut h3_connection: h3::server::Connection<_, Bytes> = match h3::server::Connection::new(h3_quinn::Connection::new(connection)).await {
+        Ok(h3_connection) => h3_connection,
+        Err(e) => {
+            debug!("HTTP/3 connection error from {}: {}", remote_addr, e);
+            return;
+        }
+    };
+
+    // Requests of this connection only; dropping the h3 connection would close the QUIC one
+    let (requests, mut requests_done) = mpsc::channel::<()>(1);
+    loop {
+        let resolver = tokio::select! {
+            accepted = h3_connection.accept() => match accepted {
+                Ok(Some(resolver)) => resolver,
+                Ok(None) => break,
+                Err(e) => {
+                    debug!("HTTP/3 connection error from {}: {}", remote_addr, e);
+                    break;
+                }
+            },
+            _ = stopping.changed() => {
+                let _ = h3_connection.shutdown(0).await;
+                break;
+            }
+        };
+
+        let (app, limiters, slot, metrics, requests) = (app.clone(), limiters.clone(), slot.clone(), metrics.clone(), requests.clone());
+        tokio::spawn(async move {
+            if let Err(e) = serve_request(resolver, app, &limiters, slot.as_deref(), metrics, remote_addr).await {
+                debug!("HTTP/3 stream error from {}: {}", remote_addr, e);
+            }
+            drop(requests);
+        });
+    }
+
+    drop(requests);
+    let _ = requests_done.recv().await;
+
+    // Dropping the h3 connection closes it at once, discarding response data the client has not
+    // acknowledged yet; after a GOAWAY, let the client close it once it has everything
+    let stopped_at = *stopping.borrow();
+    if let Some(stopped_at) = stopped_at {
+        let deadline = tokio::time::Instant::from_std(stopped_at + drain_timeout);
+        let _ = tokio::time::timeout_at(deadline, quic_connection.closed()).await;
+    }
+    drop(h3_connection);
+    drop(in_flight);
+}
+
+/// Reads one request off its stream, runs it through the router and sends the response back.
+async fn serve_request(
+    resolver: h3::server::RequestResolver<h3_quinn::Connection, Bytes>,
+    app: Router,
+    limiters: &[Arc<RateLimiter>],
+    slot: Option<&ConnectionSlot>,
+    metrics: Arc<Metrics>,
+    remote_addr: SocketAddr,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (request, stream) = resolver.resolve_request().await?;
+    let (mut send, recv) = stream.split();
+
+    // The request body is read as the handler consumes it, like over TCP
+    let body = futures_util::stream::unfold(Some(recv), |recv| async move {
+        let mut recv = recv?;
+        match recv.recv_data().await {
+            Ok(Some(mut data)) => Some((Ok(data.copy_to_bytes(data.remaining())), Some(recv))),
+            Ok(None) => None,
+            Err(e) => Some((Err(io::Error::other(e)), None)),
+        }
+    });
+    let request = request.map(|()| Body::from_stream(body));
+
+    let response = handle_request(app, limiters, slot, metrics, Peer::Ip(remote_addr), request).await;
+    let (parts, body) = response.into_parts();
+    send.send_response(axum::http::Response::from_parts(parts, ())).await?;
+
+    let mut body = body.into_data_stream();
+    while let Some(chunk) = body.next().await {
+        match chunk {
+            Ok(chunk) => send.send_data(chunk).await?,
+            Err(e) => {
+                // Resetting the stream tells the client the response is incomplete
+                send.stop_stream(h3::error::Code::H3_INTERNAL_ERROR);
+                return Err(e.into());
+            }
+        }
+    }
+    send.finish().await?;
+    Ok(())
+}
@@ -0,0 +1,229 @@
+//! Archives built in the background for `/download?async=1`, and the queue every build waits
+//! its turn in.
+//!
+//! Starting a job returns its ID at once; the build then runs on its own while clients follow
+//! it at `/progress/{job_id}` and fetch the result with `/download?job={job_id}`. Finished
+//! archives are kept in memory for [`JOB_RETENTION`] and then forgotten.
+//!
+//! With `--max-archive-jobs`, builds of any kind (downloads, jobs, checksums, the cache being
+//! refreshed) start that many at a time and the rest wait in line in arrival order; a job
+//! waiting reports its place in line on `/progress`. With `--archive-queue` as well, a build
+//! that would have to wait behind that many others is refused instead.
+
+use axum::http::StatusCode;
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+
+use crate::archive::ArchiveProgress;
+use crate::server::ArchivePlan;
+
+/// How long a finished job (and its archive) stays available.
+pub(crate) const JOB_RETENTION: Duration = Duration::from_secs(10 * 60);
+/// What a client refused a job for there being too many running is told to wait.
+pub(crate) const JOB_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// Where a job has got to.
+#[derive(Clone)]
+pub(crate) enum JobState {
+    Running,
+    /// The archive is ready. The plan describes it; its entries are no longer needed.
+    Done { plan: Arc<ArchivePlan>, data: bytes::Bytes },
+    Failed { status: StatusCode, message: String },
+}
+
+/// One background build.
+pub(crate) struct Job {
+    pub(crate) progress: ArchiveProgress,
+    /// Place in the build queue while the job waits there, 0 otherwise
+    queue_position: AtomicUsize,
+    state: watch::Sender<JobState>,
+    finished_at: Mutex<Option<Instant>>,
+}
+
+impl Job {
+    pub(crate) fn state(&self) -> JobState {
+        self.state.borrow().clone()
+    }
+
+    /// The job's place in the build queue (1 for next in line), or `None` once it is building.
+    pub(crate) fn queue_position(&self) -> Option<usize> {
+        Some(self.queue_position.load(Ordering::Relaxed)).filter(|&position| position > 0)
+    }
+
+    pub(crate) fn set_queue_position(&self, position: usize) {
+        self.queue_position.store(position, Ordering::Relaxed);
+    }
+
+    /// Follows the job's state; the receiver sees a change once it finishes.
+    pub(crate) fn subscribe(&self) -> watch::Receiver<JobState> {
+        self.state.subscribe()
+    }
+
+    /// Records the outcome of the build, starting the retention period.
+    pub(crate) fn finish(&self, state: JobState) {
+        *self.finished_at.lock().unwrap_or_else(|e| e.into_inner()) = Some(Instant::now());
+        self.state.send_replace(state);
+    }
+
+    fn expired(&self, now: Instant) -> bool {
+        let finished_at = *self.finished_at.lock().unwrap_or_else(|e| e.into_inner());
+        finished_at.is_some_and(|finished_at| now.duration_since(finished_at) > JOB_RETENTION)
+    }
+}
+
+/// Every job started since the server came up and not yet expired, by ID.
+#[derive(Default)]
+pub(crate) struct Jobs {
+    jobs: Mutex<HashMap<String, Arc<Job>>>,
+}
+
+impl Jobs {
+    /// Registers a new running job under a fresh unguessable ID, or returns `None` when
+    /// `max_running` jobs are running already.
+    pub(crate) fn start(&self, max_running: Option<usize>) -> io::Result<Option<(String, Arc<Job>)>> {
+        let mut bytes = [0u8; 16];
+        getrandom::getrandom(&mut bytes).map_err(io::Error::other)?;
+        let id: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+        let job = Arc::new(Job {
+            progress: ArchiveProgress::default(),
+            queue_position: AtomicUsize::new(0),
+            state: watch::channel(JobState::Running).0,
+            finished_at: Mutex::new(None),
+        });
+
+        let mut jobs = self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        jobs.retain(|_, job| !job.expired(now));
+        let running = jobs.values().filter(|job| matches!(*job.state.borrow(), JobState::Running)).count();
+        if max_running.is_some_and(|max| running >= max) {
+            return Ok(None);
+        }
+        jobs.insert(id.clone(), job.clone());
+        Ok(Some((id, job)))
+    }
+
+    pub(crate) fn get(&self, id: &str) -> Option<Arc<Job>> {
+        let jobs = self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+        jobs.get(id).filter(|job| !job.expired(Instant::now())).cloned()
+    }
+}
+
+/// Builds take turns: `max_running` at a time, with at most `max_waiting` in line behind them.
+/// The queue outlives reloads, which only change its limits.
+#[derive(Default)]
+pub(crate) struct BuildQueue {
+    turns: Mutex<Turns>,
+    /// Bumped whenever a build starts, ends or leaves the line, for those waiting to look again
+    changed: watch::Sender<()>,
+}
+
+#[derive(Default)]
+struct Turns {
+    max_running: Option<usize>,
+    max_waiting: Option<usize>,
+    running: usize,
+    /// Tickets of the builds waiting, first in line first
+    waiting: VecDeque<u64>,
+    next_ticket: u64,
+}
+
+/// A build's turn; dropping it lets the next build in line start.
+pub(crate) struct BuildSlot {
+    queue: Arc<BuildQueue>,
+}
+
+/// A place in line, given up when the build stops waiting for any reason.
+struct InLine<'a> {
+    queue: &'a BuildQueue,
+    ticket: u64,
+}
+
+impl BuildQueue {
+    /// Applies the limits of a new configuration; builds running already keep their turns.
+    pub(crate) fn set_limits(&self, max_running: Option<usize>, max_waiting: Option<usize>) {
+        let mut turns = self.turns();
+        turns.max_running = max_running;
+        turns.max_waiting = max_waiting;
+        drop(turns);
+        self.changed.send_replace(());
+    }
+
+    fn turns(&self) -> std::sync::MutexGuard<'_, Turns> {
+        self.turns.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn has_room(turns: &Turns) -> bool {
+        turns.max_running.is_none_or(|max| turns.running < max)
+    }
+
+    /// Waits for a turn to build, telling `queued` the build's place in line (1 for next)
+    /// while it waits. Returns `None` at once when the line is full.
+    pub(crate) async fn turn(self: &Arc<Self>, mut queued: impl FnMut(usize)) -> Option<BuildSlot> {
+        let mut changes = self.changed.subscribe();
+        let ticket = {
+            let mut turns = self.turns();
+            if turns.waiting.is_empty() && Self::has_room(&turns) {
+                turns.running += 1;
+                return Some(BuildSlot { queue: self.clone() });
+            }
+            if turns.max_waiting.is_some_and(|max| turns.waiting.len() >= max) {
+                return None;
+            }
+            let ticket = turns.next_ticket;
+            turns.next_ticket += 1;
+            turns.waiting.push_back(ticket);
+            ticket
+        };
+
+        let _in_line = InLine { queue: self, ticket };
+        loop {
+            {
+                let mut turns = self.turns();
+                let position = turns.waiting.iter().position(|&waiting| waiting == ticket).unwrap_or_default();
+                if position == 0 && Self::has_room(&turns) {
+                    turns.waiting.pop_front();
+                    turns.running += 1;
+                    drop(turns);
+                    self.changed.send_replace(());
+                    return Some(BuildSlot { queue: self.clone() });
+                }
+                queued(position + 1);
+            }
+            let _ = changes.changed().await;
+        }
+    }
+
+    /// How many builds are running and how many are waiting.
+    pub(crate) fn load(&self) -> (usize, usize) {
+        let turns = self.turns();
+        (turns.running, turns.waiting.len())
+    }
+}
+
+impl Drop for BuildSlot {
+    fn drop(&mut self) {
+        self.queue.turns().running -= 1;
+        self.queue.changed.send_replace(());
+    }
+}
+
+impl Drop for InLine<'_> {
+    fn drop(&mut self) {
+        let mut turns = self.queue.turns();
+        let before = turns.waiting.len();
+        turns.waiting.retain(|&waiting| waiting != self.ticket);
+        if turns.waiting.len() != before {
+            drop(turns);
+            self.queue.changed.send_replace(());
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! Archive files into a tarball or zip and serve it over HTTP, with a matching client.
+//!
+//! The `rust-file-archive-server` binary is a thin command-line layer over this crate.
+//! Other programs can use the same pieces directly:
+//!
+//! - [`archive`]: [`ArchiveBuilder`] builds, measures and verifies archives.
+//! - [`chunks`]: content-defined chunking and the client's store of received chunks.
+//! - [`compress`]: the [`Codec`]s tarballs are compressed with, and the [`compress::Compressor`]
+//!   trait for adding more.
+//! - [`server`]: [`FileServer`] serves archives, listings and uploads from a root directory
+//!   or an S3 bucket.
+//! - [`client`]: [`Downloader`] and [`Uploader`] talk to a running server.
+//! - [`bench`]: measures how fast a running server delivers a payload.
+//! - [`admin`]: the `/admin` API of a running server, and the client driving it.
+//! - [`crypt`]: decrypts archives from a server running with `--encrypt`.
+//! - [`discovery`]: announces servers on the local network and finds them.
+//! - [`output`]: prints client messages as prose or, with `--json`, as JSON lines.
+//! - [`peer`]: shares an archive with other clients while it downloads.
+//! - [`picker`]: the terminal file picker of `download --interactive`.
+//! - [`config`]: the configuration file, filling in a `clap` command line.
+//! - [`systemd`]: socket activation and readiness notification under systemd.
+
+pub mod admin;
+pub mod archive;
+mod audit;
+pub mod bench;
+mod cache;
+pub mod chunks;
+mod metrics;
+pub mod client;
+pub mod compress;
+pub mod config;
+pub mod crypt;
+mod dav;
+pub mod discovery;
+mod encoding;
+mod files;
+mod grpc;
+mod health;
+#[cfg(feature = "http3")]
+mod http3;
+mod jobs;
+mod limits;
+mod manage;
+pub mod output;
+mod paths;
+pub mod peer;
+pub mod picker;
+mod rate;
+pub mod server;
+mod s3;
+mod search;
+mod storage;
+pub mod systemd;
+mod timeouts;
+mod watch;
+
+pub use archive::{ArchiveBuilder, ArchiveFormat, Codec};
+pub use client::{Connection, Downloader, Uploader};
+pub use server::{FileServer, ServerConfig};
+
+/// Parses a byte size such as `1048576`, `512K`, `100M` or `2GiB` (binary multiples).
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let digits_end = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, suffix) = value.split_at(digits_end);
+
+    let number: u64 = number.parse().map_err(|_| format!("Invalid size: {}", value))?;
+    let multiplier: u64 = match suffix.trim().to_ascii_uppercase().trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("Invalid size suffix in {}: use K, M, G or T", value)),
+    };
+
+    number.checked_mul(multiplier).ok_or_else(|| format!("Size too large: {}", value))
+}
+
+/// Parses a duration such as `90` (seconds), `30s`, `15m`, `24h` or `7d`.
+pub fn parse_duration(value: &str) -> Result<std::time::Duration, String> {
+    let value = value.trim();
+    let digits_end = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, suffix) = value.split_at(digits_end);
+
+    let number: u64 = number.parse().map_err(|_| format!("Invalid duration: {}", value))?;
+    let multiplier: u64 = match suffix.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("Invalid duration suffix in {}: use s, m, h or d", value)),
+    };
+
+    number.checked_mul(multiplier)
+        .map(std::time::Duration::from_secs)
+        .ok_or_else(|| format!("Duration too long: {}", value))
+}
+
+/// Formats a byte count with binary units, e.g. `1.5 MiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{} B", bytes) } else { format!("{:.1} {}", value, UNITS[unit]) }
+}
+
+/// CPU time (user and system) this process has used so far; `None` where it cannot be read.
+pub fn process_cpu_time() -> Option<std::time::Duration> {
+    #[cfg(unix)]
+    {
+        let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+        if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+            return None;
+        }
+        let usage = unsafe { usage.assume_init() };
+        let time = |t: libc::timeval| std::time::Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+        Some(time(usage.ru_utime) + time(usage.ru_stime))
+    }
+    #[cfg(not(unix))]
+    None
+}
+
+/// Formats Unix seconds as a UTC `YYYY-MM-DD HH:MM` timestamp.
+pub fn format_unix_time(secs: u64) -> String {
+    // Civil-from-days conversion (Howard Hinnant's algorithm), valid for all post-epoch dates
+    let days = (secs / 86_400) as i64;
+    let minutes_of_day = (secs % 86_400) / 60;
+
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, minutes_of_day / 60, minutes_of_day % 60)
+}
@@ -0,0 +1,181 @@
+//! Caps on how many connections and requests each client may have.
+//!
+//! Every connection takes a [`ConnectionSlot`] when it is accepted. A connection over one of the
+//! caps is still served, but each of its requests is refused with a Retry-After, so clients
+//! learn why and when to retry instead of seeing a dropped socket: 503 Service Unavailable when
+//! the server as a whole has too many connections, 429 Too Many Requests when the client does.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+use crate::server::Peer;
+
+/// What a client refused for having too many connections is told to wait.
+const CONNECTION_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Why a request was refused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Refusal {
+    /// The server has its --max-connections open, whoever they are from
+    Busy,
+    /// This client is over its per-IP connection or request cap
+    TooMany,
+}
+
+/// Request buckets are only pruned once there are this many, so that quiet servers never scan.
+const MIN_PRUNE_THRESHOLD: usize = 1024;
+
+/// Per-IP and server-wide limits, with the connections and requests counted against them.
+/// Unix socket peers have no address, so only the server-wide ceiling applies to them.
+#[derive(Debug)]
+pub(crate) struct ClientLimits {
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    max_requests_per_minute: Option<u32>,
+    connections: Mutex<ConnectionCounts>,
+    requests: Mutex<RequestBuckets>,
+}
+
+#[derive(Debug, Default)]
+struct ConnectionCounts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+#[derive(Debug, Default)]
+struct RequestBuckets {
+    buckets: HashMap<IpAddr, Bucket>,
+    prune_at: usize,
+}
+
+/// A token bucket holding up to a minute's worth of requests, refilled continuously.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl ClientLimits {
+    /// Limits for the given caps, or `None` when there are none to enforce.
+    pub(crate) fn new(
+        max_connections: Option<usize>,
+        max_connections_per_ip: Option<usize>,
+        max_requests_per_minute: Option<u32>,
+    ) -> Option<Arc<Self>> {
+        if max_connections.is_none() && max_connections_per_ip.is_none() && max_requests_per_minute.is_none() {
+            return None;
+        }
+        Some(Arc::new(ClientLimits {
+            max_connections,
+            max_connections_per_ip,
+            max_requests_per_minute,
+            connections: Default::default(),
+            requests: Mutex::new(RequestBuckets { buckets: HashMap::new(), prune_at: MIN_PRUNE_THRESHOLD }),
+        }))
+    }
+
+    /// Counts a newly accepted connection from `peer`, unless it would exceed a cap.
+    pub(crate) fn open(self: &Arc<Self>, peer: Peer) -> ConnectionSlot {
+        let ip = match peer {
+            Peer::Ip(addr) => Some(addr.ip()),
+            Peer::Unix => None,
+        };
+
+        let mut connections = self.connections.lock().unwrap_or_else(|e| e.into_inner());
+        let over_total = self.max_connections.is_some_and(|max| connections.total >= max);
+        let over_ip = match (ip, self.max_connections_per_ip) {
+            (Some(ip), Some(max)) => connections.per_ip.get(&ip).is_some_and(|&count| count >= max),
+            _ => false,
+        };
+
+        // A client over its own cap is told so even when the server is full too
+        let refused = match (over_ip, over_total) {
+            (true, _) => Some(Refusal::TooMany),
+            (false, true) => Some(Refusal::Busy),
+            (false, false) => None,
+        };
+        if refused.is_none() {
+            connections.total += 1;
+            if let Some(ip) = ip {
+                *connections.per_ip.entry(ip).or_default() += 1;
+            }
+        }
+        ConnectionSlot { limits: self.clone(), ip, refused }
+    }
+
+    /// Takes one request from `ip`'s bucket, or says how long until one is available.
+    fn take_request(&self, ip: IpAddr) -> Result<(), Duration> {
+        let Some(per_minute) = self.max_requests_per_minute else { return Ok(()) };
+        let capacity = f64::from(per_minute);
+        let per_second = capacity / 60.0;
+        let now = Instant::now();
+
+        let mut requests = self.requests.lock().unwrap_or_else(|e| e.into_inner());
+        if requests.buckets.len() >= requests.prune_at {
+            // A bucket idle for a minute is full again, the same as having none
+            requests.buckets.retain(|_, bucket| now.duration_since(bucket.updated) < Duration::from_secs(60));
+            requests.prune_at = (requests.buckets.len() * 2).max(MIN_PRUNE_THRESHOLD);
+        }
+
+        let bucket = requests.buckets.entry(ip).or_insert(Bucket { tokens: capacity, updated: now });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        }
+    }
+}
+
+/// A connection's place under the connection caps, given back when it is dropped.
+pub(crate) struct ConnectionSlot {
+    limits: Arc<ClientLimits>,
+    ip: Option<IpAddr>,
+    refused: Option<Refusal>,
+}
+
+impl ConnectionSlot {
+    /// Whether a request on this connection may go ahead, or why not and how long the client
+    /// should wait.
+    pub(crate) fn admit(&self) -> Result<(), (Refusal, Duration)> {
+        if let Some(refusal) = self.refused {
+            return Err((refusal, CONNECTION_RETRY_AFTER));
+        }
+        match self.ip {
+            Some(ip) => self.limits.take_request(ip).map_err(|retry_after| (Refusal::TooMany, retry_after)),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the connection was over a cap when it was accepted.
+    pub(crate) fn is_refused(&self) -> bool {
+        self.refused.is_some()
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        if self.refused.is_some() {
+            return;
+        }
+        let mut connections = self.limits.connections.lock().unwrap_or_else(|e| e.into_inner());
+        connections.total -= 1;
+        if let Some(ip) = self.ip {
+            if let Some(count) = connections.per_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    connections.per_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
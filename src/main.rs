@@ -1,226 +1,3301 @@
-use axum::{
-    Router,
-    body::Body,
-    extract::{State},
-    http::{
-        HeaderValue, StatusCode,
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use futures_util::StreamExt;
+use local_ip_address::{local_ip, local_ipv6};
+use rust_file_archive_server::{
+    archive::{
+        check_manifest, compare_archive_manifest, extract_archive_with, hash_file, read_archive_manifest, ArchiveBuilder, ArchiveChecksum, ArchiveFormat, EntryKind, Codec, ExtractOptions, ExtractSummary, Manifest,
+        ManifestMismatch, OverwritePolicy, SymlinkPolicy,
     },
-    response::Response,
-    routing::get,
+    admin::{AdminClient, AdminStatus},
+    bench::{format_seconds, Bench, Payload},
+    client::{self, numbered_path, part_path, restore, ClientResult, Connection, Downloader, MirrorReport, SearchQuery, ServerError, SnapshotIndex, Uploader, DEFAULT_CLIENT_READ_TIMEOUT,
+        DEFAULT_CONNECT_TIMEOUT, DEFAULT_SERVER_URL},
+    config::{self, Config},
+    crypt,
+    discovery::{self, DEFAULT_DISCOVERY_TIMEOUT},
+    output::{self, emit},
+    peer::PeerShare,
+    picker,
+    format_size, format_unix_time, parse_duration, parse_size,
+    server::{
+        AuthConfig, CorsPolicy, FileServer, ServerConfig, DEFAULT_BACKLOG, DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_HEADERS,
+        DEFAULT_READ_TIMEOUT, DEFAULT_SERVER_PORT, DEFAULT_WRITE_TIMEOUT, MIN_MAX_HEADER_SIZE,
+    },
+    systemd,
 };
 use std::{
-    env,
-    net::SocketAddr,
+    io::{self, IsTerminal, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     path::{Path, PathBuf},
-    io::{self},
+    time::Duration,
 };
-use tar::Builder;
-use flate2::write::GzEncoder;
-use flate2::Compression;
-use local_ip_address::local_ip;
-
-// --- Port Configuration ---
-// This serves as the default port
-const DEFAULT_SERVER_PORT: u16 = 8080; 
+
+// --- Command-Line Defaults ---
+const DEFAULT_MAX_UPLOAD_SIZE: &str = "1G";
+const DEFAULT_CACHE_MAX_SIZE: &str = "1G";
+const DEFAULT_UPLOAD_CHUNK_SIZE: &str = "8M";
+const DEFAULT_MAX_HEADER_SIZE: &str = "64K";
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
 // --------------------------
 
-#[derive(Clone)]
-struct AppState {
-    // Field is unused by the handler, but needed for startup, so we allow dead_code to silence the warning
-    #[allow(dead_code)]
-    initial_files: Vec<String>,
+/// Command-line interface. Running without a subcommand behaves like `server`.
+#[derive(Parser, Debug)]
+#[command(version, about = "Archives files into a .tar.gz and serves it over HTTP")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    server: ServerArgs,
+
+    /// Print progress, results and errors as JSON lines (one object per line, each naming its
+    /// "event") instead of prose; the server logs as with --log-format json
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Read settings from this TOML file instead of ~/.config/file_server/config.toml (see the
+    /// README); flags given on the command line win over it
+    #[arg(long, global = true, value_name = "PATH", env = "FILE_SERVER_CONFIG")]
+    config: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Serve an archive of the given files
+    Server(Box<ServerArgs>),
+    /// Download (and resume) an archive from a running server
+    Download(DownloadArgs),
+    /// List the files a running server can archive
+    List(ListArgs),
+    /// Find files on a running server by name pattern, size and modification time
+    Search(SearchArgs),
+    /// Update a local directory with only the files that changed on the server
+    Sync(SyncArgs),
+    /// Keep a local directory a copy of one on the server, optionally on a schedule
+    Mirror(MirrorArgs),
+    /// Add an incremental snapshot of files on the server to a backup directory
+    Backup(BackupArgs),
+    /// Rebuild a snapshot from a backup directory, or list its snapshots
+    Restore(RestoreArgs),
+    /// Show (or check extracted files against) the manifest of an archive
+    Manifest(ManifestArgs),
+    /// Upload a file to a running server, resuming an interrupted upload
+    Upload(UploadArgs),
+    /// Delete files or directories on a running server (see `server --manage-files`)
+    Rm(RmArgs),
+    /// Move or rename a file or directory on a running server
+    Mv(MvArgs),
+    /// Create directories on a running server
+    Mkdir(MkdirArgs),
+    /// Pack local files into a tarball or zip file, or add them to an existing tarball
+    Archive(ArchiveArgs),
+    /// Unpack a downloaded tarball or zip file
+    Extract(ExtractArgs),
+    /// Check a downloaded archive's integrity, and its contents against a manifest or checksum file
+    Verify(VerifyArgs),
+    /// List the servers announcing themselves on the local network (see `server --announce`)
+    Discover(DiscoverArgs),
+    /// Mint an expiring download link for some files, for someone without credentials
+    Share(ShareArgs),
+    /// Measure download throughput and latency with different connection counts and chunk sizes
+    Bench(BenchArgs),
+    /// Inspect and control a running server through its admin API (see `server --admin-token`)
+    Admin(AdminArgs),
+    /// Print a shell completion script covering every subcommand and flag
+    Completions(CompletionsArgs),
+}
+
+/// Output format of the server's request and diagnostic logs.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors such as Loki or Elasticsearch
+    Json,
+}
+
+#[derive(Args, Clone, Debug)]
+struct ServerArgs {
+    /// Default files to archive when a request names none. A trailing port number is also accepted here.
+    #[arg(required_unless_present_any = ["root", "stop"])]
+    files: Vec<String>,
+
+    /// Directory that requested file paths are resolved against (defaults to the working directory),
+    /// or `s3://bucket/prefix` to serve objects from S3 or MinIO using the `AWS_*` environment variables
+    #[arg(long, value_name = "DIR|URL")]
+    root: Option<PathBuf>,
+
+    /// Port to listen on (defaults to 8080)
+    #[arg(long)]
+    port: Option<u16>,
+
+    /// Address to listen on, optionally with a port: `0.0.0.0`, `[::]:8080`, `192.168.1.5:9000`
+    /// (repeatable; defaults to 0.0.0.0). Addresses without a port use --port.
+    #[arg(long, value_name = "ADDR", value_parser = parse_bind_address)]
+    bind_address: Vec<BindAddress>,
+
+    /// Listen on a Unix domain socket at this path, e.g. behind nginx or Caddy. Without
+    /// --bind-address or a port, the server then listens on the socket only.
+    #[arg(long, value_name = "PATH")]
+    bind_unix: Option<PathBuf>,
+
+    /// Permission bits of the --bind-unix socket, in octal
+    #[arg(long, value_name = "MODE", value_parser = parse_mode, default_value = "660", requires = "bind_unix")]
+    unix_socket_mode: u32,
+
+    /// Also serve HTTP/3 over QUIC (UDP) on the same ports; needs --tls-cert and a build with
+    /// `--features http3` (experimental)
+    #[arg(long, requires = "tls_cert")]
+    http3: bool,
+
+    /// Skip the integrity check of each generated archive before it is sent
+    #[arg(long)]
+    skip_verify: bool,
+
+    /// Write the server's PID to this file on startup; it is removed again on clean shutdown
+    #[arg(long, value_name = "PATH")]
+    pid_file: Option<PathBuf>,
+
+    /// Detach from the terminal and keep serving in the background (Unix only). Logs go to
+    /// --log-file or --syslog; stop it again with `--stop --pid-file <PATH>`
+    #[arg(long, requires = "pid_file")]
+    daemon: bool,
+
+    /// Stop the server whose PID is in --pid-file instead of starting one, waiting up to
+    /// --shutdown-timeout seconds for its downloads to finish
+    #[arg(long, requires = "pid_file", conflicts_with = "daemon")]
+    stop: bool,
+
+    /// PEM certificate chain to serve HTTPS with (requires --tls-key)
+    #[arg(long, value_name = "PATH", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key matching --tls-cert
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Require client certificates issued by the CA(s) in this PEM file (mutual TLS);
+    /// connections without one fail the handshake
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    client_ca: Option<PathBuf>,
+
+    /// Require `Authorization: Bearer <TOKEN>` (repeatable; also read from FILE_SERVER_AUTH_TOKEN)
+    #[arg(long = "auth-token", value_name = "TOKEN", env = "FILE_SERVER_AUTH_TOKEN")]
+    auth_tokens: Vec<String>,
+
+    /// Require HTTP Basic credentials from an htpasswd-style file (`user:password` or `user:<bcrypt hash>`),
+    /// each optionally followed by `root=<dir>` (what the user sees instead of the root) and
+    /// `access=read-only` or `access=read-write`
+    #[arg(long, value_name = "PATH")]
+    auth_file: Option<PathBuf>,
+
+    /// Key for signing share links (see the `share` subcommand), so they survive a restart
+    /// (also read from FILE_SERVER_LINK_SECRET; a random key is used otherwise)
+    #[arg(long, env = "FILE_SERVER_LINK_SECRET", hide_env_values = true)]
+    link_secret: Option<String>,
+
+    /// Also serve every file in the root at its own path and directories as listings, like
+    /// `python -m http.server` (the routes such as /download keep their meaning)
+    #[arg(long = "static")]
+    static_files: bool,
+
+    /// Share the root over WebDAV under /dav (read-only), for mounting it in Finder, Explorer or rclone
+    #[arg(long)]
+    webdav: bool,
+
+    /// Also accept PUT and MKCOL on the WebDAV share, writing into the root (local roots only;
+    /// files are limited to --max-upload-size)
+    #[arg(long, requires = "webdav")]
+    webdav_writable: bool,
+
+    /// Let authenticated clients delete, move and rename files and create directories in the
+    /// root (the rm, mv and mkdir commands); needs a local root and --auth-token or --auth-file
+    #[arg(long)]
+    manage_files: bool,
+
+    /// Keep an index of the root for /search instead of walking it for every search
+    #[arg(long)]
+    search_index: bool,
+
+    /// How old the search index may get before it is rebuilt in the background
+    #[arg(long, value_name = "DURATION", default_value = "5m", value_parser = parse_duration, requires = "search_index")]
+    search_index_refresh: Duration,
+
+    /// Let browser frontends served from this origin call the server, e.g.
+    /// `https://app.example.com`, or `*` for any origin (repeatable)
+    #[arg(long, value_name = "ORIGIN")]
+    cors_origin: Vec<String>,
+
+    /// Request methods --cors-origin frontends may use, comma-separated (defaults to GET,HEAD,POST,PATCH)
+    #[arg(long, value_name = "METHODS", value_delimiter = ',', requires = "cors_origin")]
+    cors_methods: Vec<String>,
+
+    /// Request headers --cors-origin frontends may send, comma-separated
+    /// (defaults to Authorization,Content-Type,Range,If-Range,Upload-Offset)
+    #[arg(long, value_name = "HEADERS", value_delimiter = ',', requires = "cors_origin")]
+    cors_headers: Vec<String>,
+
+    /// Seconds to wait for in-flight downloads to finish after SIGTERM/SIGINT
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    shutdown_timeout: u64,
+
+    /// Seconds a client may take to send a request's headers, or to send more of a request
+    /// body the server is waiting for (0 waits forever)
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_READ_TIMEOUT.as_secs())]
+    read_timeout: u64,
+
+    /// Seconds a client may leave a response unread before its connection is closed (0 waits forever)
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_WRITE_TIMEOUT.as_secs())]
+    write_timeout: u64,
+
+    /// Seconds a connection may sit unused between requests before it is closed (0 keeps it open)
+    #[arg(long, visible_alias = "keep-alive", value_name = "SECS", default_value_t = DEFAULT_IDLE_TIMEOUT.as_secs())]
+    idle_timeout: u64,
+
+    /// Close every HTTP/1 connection after one response instead of keeping it open for more
+    #[arg(long)]
+    no_keep_alive: bool,
+
+    /// Largest request line and headers a client may send (at least 8K); bigger requests get 431
+    #[arg(long, value_name = "SIZE", value_parser = parse_header_size, default_value = DEFAULT_MAX_HEADER_SIZE)]
+    max_header_size: usize,
+
+    /// Most headers an HTTP/1 request may carry; requests with more get 431
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_HEADERS, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_headers: usize,
+
+    /// How many connections the kernel queues on each listening port before they are accepted
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_BACKLOG, value_parser = clap::value_parser!(u32).range(1..))]
+    backlog: u32,
+
+    /// Threads serving requests (default: one per CPU core); archives are built on threads of their own
+    #[arg(long, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    workers: Option<usize>,
+
+    /// How log lines are written to stdout; filter them with RUST_LOG (e.g. `RUST_LOG=debug` or `RUST_LOG=access=off`)
+    #[arg(long, value_enum, default_value_t)]
+    log_format: LogFormat,
+
+    /// Append log lines to this file instead of writing them to stdout; with --daemon, the
+    /// startup banner goes there too
+    #[arg(long, value_name = "PATH", conflicts_with = "syslog")]
+    log_file: Option<PathBuf>,
+
+    /// Send log lines to the local syslog daemon (/dev/log) instead of stdout (Unix only)
+    #[arg(long)]
+    syslog: bool,
+
+    /// Append a JSON line for every download to this file (time, client, user, files, bytes and
+    /// whether it completed); reopened on reload, so it can be rotated
+    #[arg(long, value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+
+    /// Send files, listings and the web UI uncompressed even to clients accepting gzip or zstd
+    #[arg(long)]
+    no_response_compression: bool,
+
+    /// Also serve the gRPC control plane described in proto/fileserver.proto (listing, archive
+    /// jobs and their progress, transfers and draining); needs HTTP/2, as h2c without TLS
+    #[arg(long)]
+    grpc: bool,
+
+    /// Report the server as not ready on /readyz when --cache-dir, --upload-dir or a writable
+    /// root has less free space than this, e.g. `5G`
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    min_free_space: Option<u64>,
+
+    /// Default compression for tarballs; clients can override it with ?compression=
+    #[arg(long, value_enum, default_value_t = Codec::Gzip)]
+    compression: Codec,
+
+    /// Encrypt every archive (AES-256-GCM-SIV with a key derived from --passphrase). Clients
+    /// unpack them with `download --decrypt` or `extract --decrypt`
+    #[arg(long, requires = "passphrase")]
+    encrypt: bool,
+
+    /// Passphrase for --encrypt (better passed through FILE_SERVER_PASSPHRASE than on the command line)
+    #[arg(long, env = "FILE_SERVER_PASSPHRASE", hide_env_values = true)]
+    passphrase: Option<String>,
+
+    /// Sign every archive with this Ed25519 private key (PKCS#8 PEM, e.g. from
+    /// `openssl genpkey -algorithm ed25519`); signatures are served at /download.sig
+    #[arg(long, value_name = "PEM")]
+    signing_key: Option<PathBuf>,
+
+    /// Send archives uncompressed by default (same as `--compression store`); worthwhile when
+    /// the files are already compressed media. Clients can still ask for ?compression=gzip
+    #[arg(long, conflicts_with_all = ["compression", "level"])]
+    store: bool,
+
+    /// Default compression level (gzip/xz 0-9, bzip2 1-9, zstd 1-22); clients can override it with ?level=
+    #[arg(long, alias = "compression-level")]
+    level: Option<i32>,
+
+    /// Compress each gzip or zstd tarball on N threads (0: one per CPU core). Gzip output is
+    /// then written pigz-style, in independently compressed 1 MiB blocks
+    #[arg(long, value_name = "N", default_value = "1", value_parser = parse_threads)]
+    threads: usize,
+
+    /// How symlinks inside archived directories are stored: follow archives their targets
+    /// (only those inside --root for client requests), preserve stores the links, skip leaves them out
+    #[arg(long, value_enum, default_value_t)]
+    symlinks: SymlinkPolicy,
+
+    /// Store fixed timestamps, owner 0 and mode 0644/0755 instead of each file's real metadata,
+    /// so archives for untrusted clients reveal nothing about accounts or file history
+    #[arg(long)]
+    no_preserve: bool,
+
+    /// Build byte-identical archives from identical files, for caches keyed on archive hashes:
+    /// entries are sorted by name and stored with --no-preserve's metadata
+    #[arg(long)]
+    reproducible: bool,
+
+    /// With --reproducible, keep modification times up to this many seconds since the Unix
+    /// epoch and clamp later ones to it, instead of one fixed timestamp
+    #[arg(long, value_name = "SECONDS", env = "SOURCE_DATE_EPOCH")]
+    source_date_epoch: Option<u64>,
+
+    /// Record each file's extended attributes in tarballs, POSIX ACLs and SELinux labels
+    /// included (as PAX `SCHILY.xattr.*` records, like `tar --xattrs`)
+    #[arg(long)]
+    xattrs: bool,
+
+    /// Never archive paths matching this gitignore-style pattern, e.g. `target/` or `*.tmp`
+    /// (repeatable). Patterns are also read from `.serverignore` in the root when it exists.
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Reject requests for archives whose files add up to more than this (before compression,
+    /// e.g. 20G) with 413 Payload Too Large, before building anything
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_archive_size: Option<u64>,
+
+    /// Reject requests for archives of more than this many files with 413 Payload Too Large
+    #[arg(long, value_name = "N")]
+    max_files: Option<usize>,
+
+    /// Also serve archives as numbered parts of at most this size (e.g. 2G), listed at
+    /// /download.index, for targets with a file-size limit
+    #[arg(long, value_name = "SIZE", value_parser = parse_split_size)]
+    split_size: Option<u64>,
+
+    /// Also serve archives as content-defined chunks at /download.chunks, so clients with
+    /// --chunk-store only download the chunks they have not seen before
+    #[arg(long)]
+    chunked: bool,
+
+    /// Keep generated archives in this directory and serve repeat requests for unchanged files from it
+    #[arg(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// Size the --cache-dir may grow to before the least recently used archives are deleted
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = DEFAULT_CACHE_MAX_SIZE)]
+    cache_max_size: u64,
+
+    /// Watch the root for changes: rebuild the default archive into --cache-dir whenever files
+    /// change, and let requests skip re-scanning the tree in between
+    #[arg(long, requires = "cache_dir")]
+    watch: bool,
+
+    /// A named set of files (relative to the root, globs allowed) clients can download with
+    /// `?preset=<name>`, given as NAME=PATH[,PATH...] (repeatable)
+    #[arg(long, value_name = "NAME=PATHS", value_parser = parse_preset)]
+    preset: Vec<(String, Vec<String>)>,
+
+    /// Build every --preset's archive (and the default archive) into --cache-dir at startup and
+    /// again whenever files change, so downloads of them start at once; implies --watch
+    #[arg(long, requires = "cache_dir")]
+    pregenerate: bool,
+
+    /// Cap the combined download rate of all clients, in bytes per second (e.g. 10M)
+    #[arg(long, value_name = "BYTES_PER_SEC", value_parser = parse_size)]
+    max_rate: Option<u64>,
+
+    /// Cap the download rate of each connection, in bytes per second (e.g. 2M)
+    #[arg(long, value_name = "BYTES_PER_SEC", value_parser = parse_size)]
+    max_rate_per_connection: Option<u64>,
+
+    /// Cap the number of open connections; requests on any beyond it get 503 Service Unavailable
+    /// with a Retry-After
+    #[arg(long, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_connections: Option<usize>,
+
+    /// Cap the number of open connections from any one IP address
+    #[arg(long, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_connections_per_ip: Option<usize>,
+
+    /// Cap how many requests any one IP address may make per minute (answered with 429 beyond it)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_requests_per_minute: Option<u32>,
+
+    /// Cap how many background builds (`/download?async=1`) run at once; more are refused with
+    /// 503 Service Unavailable and a Retry-After
+    #[arg(long, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_jobs: Option<usize>,
+
+    /// Cap how many archives are built at once, for downloads and background builds alike; the
+    /// others wait their turn in arrival order, and background builds report their place in line
+    #[arg(long, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_archive_jobs: Option<usize>,
+
+    /// With --max-archive-jobs, refuse builds with 503 Service Unavailable once this many are
+    /// waiting for a turn, instead of letting the line grow
+    #[arg(long, value_name = "N", requires = "max_archive_jobs")]
+    archive_queue: Option<usize>,
+
+    /// Enable the `/admin` API (cache, transfers, reload, drain) for clients presenting this
+    /// Bearer token; it takes no other credentials
+    #[arg(long, value_name = "TOKEN", env = "FILE_SERVER_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+
+    /// Stream full downloads while they are being compressed instead of buffering the whole archive.
+    /// Streamed responses have no Content-Length and skip verification; range requests are still buffered.
+    #[arg(long)]
+    stream: bool,
+
+    /// Accept POST /upload and store uploaded files in this directory (uploads are disabled without it)
+    #[arg(long, value_name = "DIR")]
+    upload_dir: Option<PathBuf>,
+
+    /// Maximum total size of one upload request, e.g. 500M or 2G
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = DEFAULT_MAX_UPLOAD_SIZE)]
+    max_upload_size: u64,
+
+    /// Answer /download with a report of what would be archived instead of building the archive
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Shell command to run before each new archive is built, with the requested paths as
+    /// arguments (e.g. a script that snapshots a database into the root). If it fails, the
+    /// request is answered with 500.
+    #[arg(long, value_name = "COMMAND")]
+    pre_archive_cmd: Option<String>,
+
+    /// Shell command to run on each freshly built archive, with the path of a temporary copy
+    /// as its argument (e.g. a virus scanner). If it fails, the archive is neither sent nor cached.
+    #[arg(long, value_name = "COMMAND", conflicts_with = "stream")]
+    post_archive_cmd: Option<String>,
+
+    /// Announce the server on the local network via mDNS (`_fileserver._tcp`), so clients can
+    /// find it with `discover` and connect with `--server <NAME>`
+    #[arg(long)]
+    announce: bool,
+
+    /// Name to announce the server under (defaults to the host name)
+    #[arg(long, value_name = "NAME", requires = "announce")]
+    announce_name: Option<String>,
+
+    /// Print a QR code of the download link on start, for phones and laptops nearby
+    #[arg(long)]
+    qr: bool,
+
+    /// Put a one-time token in the --qr link, so it gets past --auth-token/--auth-file for a single download
+    #[arg(long, requires = "qr")]
+    qr_token: bool,
+}
+
+/// How the client reaches the server; shared by every client subcommand.
+#[derive(Args, Debug)]
+struct ConnectionArgs {
+    /// Base URL of the archive server (http:// or https://)
+    #[arg(long, default_value = DEFAULT_SERVER_URL)]
+    server_url: String,
+
+    /// Name of a server announced on the local network (see `discover`), instead of --server-url
+    #[arg(long, value_name = "NAME", conflicts_with = "server_url")]
+    server: Option<String>,
+
+    /// Accept any TLS certificate, e.g. a self-signed one (insecure)
+    #[arg(long)]
+    insecure: bool,
+
+    /// Additional PEM CA certificate to trust for https:// servers
+    #[arg(long, value_name = "PATH")]
+    ca_cert: Option<PathBuf>,
+
+    /// PEM client certificate (chain) to present to servers that require one (see --client-ca)
+    #[arg(long, value_name = "PATH", requires = "client_key")]
+    client_cert: Option<PathBuf>,
+
+    /// PEM private key matching --client-cert
+    #[arg(long, value_name = "PATH", requires = "client_cert")]
+    client_key: Option<PathBuf>,
+
+    /// Bearer token to authenticate with
+    #[arg(long, env = "FILE_SERVER_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// User name for HTTP Basic authentication
+    #[arg(long, requires = "password")]
+    user: Option<String>,
+
+    /// Password for HTTP Basic authentication
+    #[arg(long, env = "FILE_SERVER_PASSWORD", hide_env_values = true, requires = "user")]
+    password: Option<String>,
+
+    /// Send requests through this proxy: http://, https://, socks5:// or socks5h:// (resolving
+    /// names on the proxy), with optional `user:password@`; a bare host:port is an HTTP proxy. Defaults to HTTP_PROXY, HTTPS_PROXY
+    /// or ALL_PROXY; hosts listed in NO_PROXY are always reached directly.
+    #[arg(long, value_name = "URL")]
+    proxy: Option<String>,
+
+    /// Give up when the server sends nothing for this many seconds (0 waits forever)
+    #[arg(long, visible_alias = "timeout", value_name = "SECS", default_value_t = DEFAULT_CLIENT_READ_TIMEOUT.as_secs())]
+    read_timeout: u64,
+
+    /// Give up on connecting to the server after this many seconds (0 waits forever)
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_CONNECT_TIMEOUT.as_secs())]
+    connect_timeout: u64,
+
+    /// Talk to the server over HTTP/3 (QUIC) only; needs an https:// server started with
+    /// --http3 and a build with `--features http3` (experimental)
+    #[arg(long)]
+    http3: bool,
+
+    /// Follow at most this many redirects per request (default 10)
+    #[arg(long, value_name = "N", conflicts_with = "no_redirect")]
+    max_redirects: Option<usize>,
+
+    /// Do not follow redirects; a redirect fails the request, naming where it pointed
+    #[arg(long)]
+    no_redirect: bool,
+
+    /// Send this header with every request, e.g. `-H "X-Tenant: acme"` for a gateway in front
+    /// of the server (repeatable)
+    #[arg(short = 'H', long = "header", value_name = "NAME: VALUE", value_parser = parse_header)]
+    headers: Vec<(String, String)>,
+}
+
+impl ConnectionArgs {
+    /// The connection to use, looking up a --server name on the local network first.
+    async fn connect(&self) -> ClientResult<Connection> {
+        let server_url = match &self.server {
+            Some(name) => discovery::resolve(name, DEFAULT_DISCOVERY_TIMEOUT).await?,
+            None => self.server_url.clone(),
+        };
+        Ok(Connection {
+            server_url,
+            insecure: self.insecure,
+            ca_cert: self.ca_cert.clone(),
+            client_identity: self.client_cert.clone().zip(self.client_key.clone()),
+            token: self.token.clone(),
+            basic_auth: self.user.clone().zip(self.password.clone()),
+            http3: self.http3,
+            proxy: self.proxy.clone(),
+            read_timeout: timeout_secs(self.read_timeout),
+            connect_timeout: timeout_secs(self.connect_timeout),
+            max_redirects: if self.no_redirect { Some(0) } else { self.max_redirects },
+            headers: self.headers.clone(),
+            ..Default::default()
+        })
+    }
+}
+
+#[derive(Args, Debug)]
+struct DownloadArgs {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    /// Where to save the archive (defaults to the name the server suggests, e.g.
+    /// `reports.tar.gz`, numbered `reports(1).tar.gz` and so on if that file exists). It is
+    /// written to `<output>.part` until complete and verified; an existing `.part` file is resumed.
+    /// `-` writes the archive to stdout as it arrives, with messages on stderr.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Directory to save the archive in (created if needed); a relative --output is taken
+    /// relative to it
+    #[arg(long, value_name = "DIR")]
+    output_dir: Option<PathBuf>,
+
+    /// Archive format to request from the server
+    #[arg(long, value_enum, default_value_t = ArchiveFormat::Tar)]
+    format: ArchiveFormat,
+
+    /// Tarball compression to request (defaults to the server's choice)
+    #[arg(long, value_enum)]
+    compression: Option<Codec>,
+
+    /// Ask for an uncompressed archive (same as `--compression store`)
+    #[arg(long, conflicts_with_all = ["compression", "level"])]
+    store: bool,
+
+    /// Compression level to request (defaults to the server's choice)
+    #[arg(long, alias = "compression-level")]
+    level: Option<i32>,
+
+    /// File, directory or glob pattern (e.g. `logs/**/*.log`) to request, relative to the server root (repeatable).
+    /// Omit to get the server's default set.
+    #[arg(short, long = "file", value_name = "PATH")]
+    files: Vec<String>,
+
+    /// Choose the files from the server's listing in a terminal picker instead of naming them
+    /// with --file: a tree to browse, a fuzzy filter and multi-select with space
+    #[arg(short, long, conflicts_with_all = ["files", "batch"])]
+    interactive: bool,
+
+    /// Store every file under its base name instead of keeping the structure of requested directories
+    #[arg(long)]
+    flatten: bool,
+
+    /// Print what the server would archive and exit without downloading anything
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Skip checking the finished download against the server's /checksum
+    #[arg(long)]
+    no_verify: bool,
+
+    /// Require a valid signature from the server's /download.sig, made by the private key
+    /// matching this Ed25519 public key (PEM, e.g. from `openssl pkey -pubout`)
+    #[arg(long, value_name = "PEM")]
+    verify_key: Option<PathBuf>,
+
+    /// How many times to reconnect after a network error before giving up
+    #[arg(long, default_value_t = 5)]
+    retries: u32,
+
+    /// Seconds to wait before the first reconnect; the wait doubles after each failed attempt
+    #[arg(long, value_name = "SECONDS", default_value_t = 1.0)]
+    retry_delay: f64,
+
+    /// Download in this many concurrent byte-range segments. Falls back to one connection if
+    /// the server cannot report the size up front.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..=64))]
+    connections: u16,
+
+    /// Cap the download rate, in bytes per second (e.g. 500K), shared across all --connections
+    #[arg(long, value_name = "BYTES_PER_SEC", value_parser = parse_size)]
+    limit_rate: Option<u64>,
+
+    /// Keep the chunks of every download in this directory and fetch only the chunks missing
+    /// from it, from a server running with --chunked. Archives are requested uncompressed
+    /// unless --compression says otherwise, since compression hides repeated content
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["connections", "stream"])]
+    chunk_store: Option<PathBuf>,
+
+    /// Have the server build the archive as a background job first and show its progress,
+    /// instead of waiting silently while a large archive is put together
+    #[arg(long = "async")]
+    async_build: bool,
+
+    /// Join the parts of an archive the server splits (--split-size) into the output file,
+    /// instead of keeping them as `<output>.001`, `.002`, ... (implied by --extract and --decrypt)
+    #[arg(long)]
+    join: bool,
+
+    /// Unpack the archive after it has been downloaded (and verified); the archive file is kept
+    #[arg(long)]
+    extract: bool,
+
+    /// Directory to unpack into with --extract
+    #[arg(long, value_name = "DIR", default_value = ".", requires = "extract")]
+    dest: PathBuf,
+
+    /// What --extract does with files that already exist
+    #[arg(long, value_enum, default_value_t, requires = "extract")]
+    overwrite: OverwritePolicy,
+
+    /// With --extract, restore the extended attributes (ACLs, SELinux labels) the tarball
+    /// recorded, from a server running with --xattrs
+    #[arg(long, requires = "extract")]
+    xattrs: bool,
+
+    /// With --extract, unpack the archive while it downloads instead of saving it first, so it
+    /// needs no disk space of its own (tarballs only)
+    #[arg(long, requires = "extract", conflicts_with_all = ["output", "connections", "decrypt", "join", "dry_run"])]
+    stream: bool,
+
+    /// Decrypt the archive from a server running with --encrypt once it has been downloaded and
+    /// verified, replacing the encrypted file
+    #[arg(long, requires = "passphrase")]
+    decrypt: bool,
+
+    /// Passphrase for --decrypt (also read from FILE_SERVER_PASSPHRASE)
+    #[arg(long, env = "FILE_SERVER_PASSPHRASE", hide_env_values = true)]
+    passphrase: Option<String>,
+
+    /// Download every archive listed in FILE: one per line as space-separated paths with an
+    /// optional `> OUTPUT` at the end, or a JSON array of `{"files": [...], "output": "..."}`.
+    /// The other options apply to each of them.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["output", "files", "stream", "dry_run"])]
+    batch: Option<PathBuf>,
+
+    /// How many archives of a --batch to download at once
+    #[arg(long, default_value_t = 4, requires = "batch", value_parser = clap::value_parser!(u16).range(1..=64))]
+    jobs: u16,
+
+    /// Share the archive with other clients on the local network while it downloads, and
+    /// afterwards until interrupted: they fetch it from here with `--server <name>` (see
+    /// `discover`) and the same files and options, instead of from the server
+    #[arg(long, conflicts_with_all = ["connections", "chunk_store", "stream", "batch", "decrypt", "dry_run", "async_build"])]
+    share: bool,
+
+    /// Port to share on (any free port by default)
+    #[arg(long, value_name = "PORT", default_value_t = 0, requires = "share")]
+    share_port: u16,
+
+    /// Name to announce the share under (defaults to `<host name>-peer`)
+    #[arg(long, value_name = "NAME", requires = "share")]
+    share_name: Option<String>,
+
+    /// Token other clients must send (with --token) to fetch the share; a download over https
+    /// or with credentials gets a random one when none is given, and is never shared openly
+    #[arg(long, value_name = "TOKEN", env = "FILE_SERVER_SHARE_TOKEN", hide_env_values = true, requires = "share")]
+    share_token: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct ListArgs {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    /// Directory (relative to the server root) to list
+    #[arg(long)]
+    path: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct RmArgs {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    /// Files or directories to delete, relative to the server root
+    #[arg(required = true)]
+    paths: Vec<String>,
+
+    /// Delete directories with everything in them (otherwise only empty ones)
+    #[arg(short, long)]
+    recursive: bool,
+}
+
+#[derive(Args, Debug)]
+struct MvArgs {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    /// File or directory to move, relative to the server root
+    from: String,
+
+    /// Its new path, relative to the server root
+    to: String,
+
+    /// Replace a file already at the new path
+    #[arg(short = 'f', long)]
+    overwrite: bool,
+}
+
+#[derive(Args, Debug)]
+struct MkdirArgs {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    /// Directories to create, relative to the server root
+    #[arg(required = true)]
+    paths: Vec<String>,
+
+    /// Create missing parent directories too, and accept directories that already exist
+    #[arg(short, long)]
+    parents: bool,
+}
+
+#[derive(Args, Debug)]
+struct SearchArgs {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    /// Glob pattern matched against file names, e.g. `*.log`, or with a `/` against paths
+    /// below the root, e.g. `logs/**/*.gz` (every file when omitted)
+    pattern: Option<String>,
+
+    /// Directory (relative to the server root) to search in
+    #[arg(long)]
+    path: Option<String>,
+
+    /// Only files at least this large, e.g. `1M`
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    min_size: Option<u64>,
+
+    /// Only files at most this large
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_size: Option<u64>,
+
+    /// Only files modified after this: a date (`2024-01-01`, `2024-01-01T12:00`, UTC), a Unix
+    /// time (`@1704067200`) or a time ago (`7d`)
+    #[arg(long, value_name = "TIME")]
+    newer_than: Option<String>,
+
+    /// Only files modified before this
+    #[arg(long, value_name = "TIME")]
+    older_than: Option<String>,
+
+    /// Most matches to show
+    #[arg(long, value_name = "N")]
+    limit: Option<usize>,
+
+    /// Print only the matching paths, one per line, e.g. to pass on to `download --files`
+    #[arg(long)]
+    paths: bool,
+}
+
+#[derive(Args, Debug)]
+struct ShareArgs {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    /// File, directory or glob pattern the link grants, relative to the server root (repeatable)
+    #[arg(short, long = "files", value_name = "PATH", required = true)]
+    files: Vec<String>,
+
+    /// How long the link works, e.g. `30m`, `24h` or `7d`
+    #[arg(long, value_name = "DURATION", default_value = "24h", value_parser = parse_duration)]
+    expires: Duration,
+
+    /// How many downloads the link allows (unlimited until it expires by default)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_uses: Option<u32>,
+}
+
+#[derive(Args, Debug)]
+struct AdminArgs {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    #[command(subcommand)]
+    action: AdminAction,
+}
+
+#[derive(Subcommand, Debug)]
+enum AdminAction {
+    /// Show whether the server drains, how many requests it is serving and how full its cache is
+    Status,
+    /// List the archives in the cache, or remove them
+    Cache {
+        /// Remove every cached archive, or with --etag just one
+        #[arg(long)]
+        purge: bool,
+        /// ETag of the archive to remove
+        #[arg(long, requires = "purge")]
+        etag: Option<String>,
+    },
+    /// List the requests being served
+    Transfers,
+    /// Apply the server's configuration again, as on SIGHUP
+    Reload,
+    /// Refuse new requests with 503 while the ones in flight finish, e.g. before maintenance
+    Drain {
+        /// End the drain and accept requests again
+        #[arg(long, conflicts_with = "wait")]
+        undo: bool,
+        /// Wait until no requests are left in flight
+        #[arg(long)]
+        wait: bool,
+    },
+}
+
+#[derive(Args, Debug)]
+struct CompletionsArgs {
+    /// Shell to complete for
+    #[arg(value_enum)]
+    shell: clap_complete::Shell,
+}
+
+#[derive(Args, Debug)]
+struct BenchArgs {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    /// Download the archive of these files instead of filler bytes (repeatable); the server
+    /// builds it for every request unless it has a --cache-dir
+    #[arg(short, long = "files", value_name = "PATH")]
+    files: Vec<String>,
+
+    /// How much filler to download per round
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "256M", conflicts_with = "files")]
+    size: u64,
+
+    /// Requests in flight at once, comma-separated to compare several
+    #[arg(long, value_name = "N", value_delimiter = ',', default_value = "1,4,16",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..=256))]
+    connections: Vec<usize>,
+
+    /// Size of each Range request, comma-separated to compare several
+    #[arg(long, value_name = "SIZE", value_delimiter = ',', default_value = "1M", value_parser = parse_split_size)]
+    chunk_size: Vec<u64>,
+
+    /// Times each combination downloads the whole payload
+    #[arg(long, value_name = "N", default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    rounds: u32,
+}
+
+#[derive(Args, Debug)]
+struct DiscoverArgs {
+    /// How long to listen for announcements
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_DISCOVERY_TIMEOUT.as_secs())]
+    timeout: u64,
+}
+
+#[derive(Args, Debug)]
+struct SyncArgs {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    /// File, directory or glob pattern to sync, relative to the server root (repeatable).
+    /// Omit to get the server's default set.
+    #[arg(short, long = "file", value_name = "PATH")]
+    files: Vec<String>,
+
+    /// Directory to bring up to date; files keep the paths they have inside the archive
+    #[arg(long, value_name = "DIR", default_value = ".")]
+    dest: PathBuf,
+
+    /// Archive format to transfer the changes in
+    #[arg(long, value_enum, default_value_t = ArchiveFormat::Tar)]
+    format: ArchiveFormat,
+
+    /// Tarball compression to request (defaults to the server's choice)
+    #[arg(long, value_enum)]
+    compression: Option<Codec>,
+
+    /// Store every file under its base name instead of keeping the structure of requested directories
+    #[arg(long)]
+    flatten: bool,
+
+    /// Cap the transfer rate, in bytes per second (e.g. 500K)
+    #[arg(long, value_name = "BYTES_PER_SEC", value_parser = parse_size)]
+    limit_rate: Option<u64>,
+
+    /// Decrypt the changes from a server running with --encrypt
+    #[arg(long, requires = "passphrase")]
+    decrypt: bool,
+
+    /// Passphrase for --decrypt (also read from FILE_SERVER_PASSPHRASE)
+    #[arg(long, env = "FILE_SERVER_PASSPHRASE", hide_env_values = true)]
+    passphrase: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct MirrorArgs {
+    /// Directory (or file) on the server, relative to its root
+    server_path: String,
+
+    /// Local directory to keep in step with it; the directory's contents land directly in here
+    local_dir: PathBuf,
+
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    /// Also delete local files and directories that no longer exist on the server
+    #[arg(long)]
+    delete: bool,
+
+    /// Only print what would be downloaded (and deleted)
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Mirror again every this many seconds until interrupted, instead of once
+    #[arg(long, value_name = "SECS")]
+    interval: Option<u64>,
+
+    /// Tarball compression to transfer changes with (defaults to the server's choice)
+    #[arg(long, value_enum)]
+    compression: Option<Codec>,
+
+    /// Cap the transfer rate, in bytes per second (e.g. 500K)
+    #[arg(long, value_name = "BYTES_PER_SEC", value_parser = parse_size)]
+    limit_rate: Option<u64>,
+
+    /// Decrypt the changes from a server running with --encrypt
+    #[arg(long, requires = "passphrase")]
+    decrypt: bool,
+
+    /// Passphrase for --decrypt (also read from FILE_SERVER_PASSPHRASE)
+    #[arg(long, env = "FILE_SERVER_PASSPHRASE", hide_env_values = true)]
+    passphrase: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct BackupArgs {
+    /// Backup directory; snapshots are added to the ones already in it
+    backup_dir: PathBuf,
+
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    /// File, directory or glob pattern to back up, relative to the server root (repeatable).
+    /// Omit to get the server's default set. Every snapshot in a directory is of the same files.
+    #[arg(short, long = "file", value_name = "PATH")]
+    files: Vec<String>,
+
+    /// Take a full snapshot even if the directory already has one to build on
+    #[arg(long)]
+    full: bool,
+
+    /// Archive format for the snapshots
+    #[arg(long, value_enum, default_value_t = ArchiveFormat::Tar)]
+    format: ArchiveFormat,
+
+    /// Tarball compression to request (defaults to the server's choice)
+    #[arg(long, value_enum)]
+    compression: Option<Codec>,
+
+    /// Store every file under its base name instead of keeping the structure of requested directories
+    #[arg(long)]
+    flatten: bool,
+
+    /// Cap the transfer rate, in bytes per second (e.g. 500K)
+    #[arg(long, value_name = "BYTES_PER_SEC", value_parser = parse_size)]
+    limit_rate: Option<u64>,
+}
+
+#[derive(Args, Debug)]
+struct RestoreArgs {
+    /// Backup directory written by `backup`
+    backup_dir: PathBuf,
+
+    /// Directory to rebuild the snapshot in; files keep the paths they have inside the archives
+    #[arg(long, value_name = "DIR", default_value = ".")]
+    dest: PathBuf,
+
+    /// Snapshot to restore (defaults to the latest)
+    #[arg(long, value_name = "ID")]
+    snapshot: Option<u32>,
+
+    /// Only list the snapshots in the backup
+    #[arg(long, conflicts_with_all = ["snapshot", "decrypt"])]
+    list: bool,
+
+    /// Decrypt snapshots taken from a server running with --encrypt
+    #[arg(long, requires = "passphrase")]
+    decrypt: bool,
+
+    /// Passphrase for --decrypt (also read from FILE_SERVER_PASSPHRASE)
+    #[arg(long, env = "FILE_SERVER_PASSPHRASE", hide_env_values = true)]
+    passphrase: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct ManifestArgs {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    /// Files (relative to the server root) the archive would contain; the server's defaults when omitted
+    #[arg(short, long = "file", value_name = "PATH")]
+    files: Vec<String>,
+
+    /// Describe the archive as downloaded with --flatten
+    #[arg(long)]
+    flatten: bool,
+
+    /// Instead of printing the manifest, check the files extracted into this directory against
+    /// it and exit with status 1 on any difference
+    #[arg(long, value_name = "DIR")]
+    check: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct UploadArgs {
+    /// Local files to upload; directories are uploaded file by file into matching
+    /// subdirectories below --path. `-` uploads stdin under --name, in one request that
+    /// cannot be resumed.
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    /// Name to store the file under (defaults to the local file name). Only for a single
+    /// file or with --archive.
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Subdirectory of the server's upload directory to store the files in
+    #[arg(long)]
+    path: Option<String>,
+
+    /// Replace existing files with the same name on the server
+    #[arg(long)]
+    overwrite: bool,
+
+    /// Pack everything into one archive and upload that instead (named `upload.<extension>`,
+    /// or after the only file, unless --name is given)
+    #[arg(long)]
+    archive: bool,
+
+    /// Archive format for --archive
+    #[arg(long, value_enum, default_value_t = ArchiveFormat::Tar, requires = "archive")]
+    format: ArchiveFormat,
+
+    /// Tarball compression for --archive
+    #[arg(long, value_enum, default_value_t, requires = "archive")]
+    compression: Codec,
+
+    /// Compression level for --archive (defaults to the codec's default)
+    #[arg(long, requires = "archive")]
+    level: Option<i32>,
+
+    /// Compress the --archive tarball on N threads (0: one per CPU core)
+    #[arg(long, value_name = "N", default_value = "1", value_parser = parse_threads, requires = "archive")]
+    threads: usize,
+
+    /// Bytes sent per request; an interrupted upload loses at most one chunk
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = DEFAULT_UPLOAD_CHUNK_SIZE)]
+    chunk_size: u64,
+
+    /// How many times to reconnect after a network error before giving up; each retry
+    /// continues from the last byte the server acknowledged
+    #[arg(long, default_value_t = 5)]
+    retries: u32,
+
+    /// Seconds to wait before the first reconnect; the wait doubles after each failed attempt
+    #[arg(long, value_name = "SECONDS", default_value_t = 1.0)]
+    retry_delay: f64,
+}
+
+#[derive(Args, Debug)]
+struct ArchiveArgs {
+    /// Archive to write
+    output: PathBuf,
+
+    /// Files and directories to pack; directories keep their structure below their own name
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+
+    /// Add the files to the existing tarball instead of replacing it: new files are added,
+    /// changed ones replace their old entry and everything else is kept. The tarball keeps
+    /// its compression; it is created if it does not exist yet
+    #[arg(long)]
+    append: bool,
+
+    /// Archive format
+    #[arg(long, value_enum, default_value_t = ArchiveFormat::Tar)]
+    format: ArchiveFormat,
+
+    /// Tarball compression
+    #[arg(long, value_enum, default_value_t)]
+    compression: Codec,
+
+    /// Compression level (defaults to the codec's default)
+    #[arg(long)]
+    level: Option<i32>,
+
+    /// Compress gzip and zstd tarballs on N threads (0: one per CPU core). Gzip output is then
+    /// written pigz-style, in independently compressed 1 MiB blocks
+    #[arg(long, value_name = "N", default_value = "1", value_parser = parse_threads)]
+    threads: usize,
+
+    /// Store every file under its base name, dropping directory structure
+    #[arg(long)]
+    flatten: bool,
+
+    /// Leave out paths matching this gitignore-style pattern, e.g. `*.tmp` (repeatable)
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Record each file's extended attributes, ACLs and SELinux labels included (tarballs only)
+    #[arg(long)]
+    xattrs: bool,
+
+    /// Write the same bytes for the same files every time: entries sorted by name, owner 0,
+    /// mode 0644/0755 and fixed (or --source-date-epoch clamped) timestamps
+    #[arg(long)]
+    reproducible: bool,
+
+    /// With --reproducible, keep modification times up to this many seconds since the Unix
+    /// epoch and clamp later ones to it
+    #[arg(long, value_name = "SECONDS", env = "SOURCE_DATE_EPOCH")]
+    source_date_epoch: Option<u64>,
+}
+
+#[derive(Args, Debug)]
+struct ExtractArgs {
+    /// Archive to unpack (tar, tar.gz, tar.zst, tar.xz, tar.bz2 or zip; detected from its contents)
+    archive: PathBuf,
+
+    /// Directory to unpack into (created if missing)
+    #[arg(long, value_name = "DIR", default_value = ".")]
+    dest: PathBuf,
+
+    /// What to do with files that already exist
+    #[arg(long, value_enum, default_value_t)]
+    overwrite: OverwritePolicy,
+
+    /// Restore the extended attributes (ACLs, SELinux labels) a tarball recorded; setting
+    /// some of them, such as `trusted.*` ones, needs root
+    #[arg(long)]
+    xattrs: bool,
+
+    /// The archive was encrypted by a server running with --encrypt
+    #[arg(long, requires = "passphrase")]
+    decrypt: bool,
+
+    /// Passphrase for --decrypt (also read from FILE_SERVER_PASSPHRASE)
+    #[arg(long, env = "FILE_SERVER_PASSPHRASE", hide_env_values = true)]
+    passphrase: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct VerifyArgs {
+    /// Archive to check (tar, tar.gz, tar.zst, tar.xz, tar.bz2 or zip; detected from its contents)
+    archive: PathBuf,
+
+    /// Manifest to check every entry against (JSON from `manifest --json`);
+    /// `<archive>.manifest.json` is used when it exists
+    #[arg(long, value_name = "FILE")]
+    manifest: Option<PathBuf>,
+
+    /// Checksum of the whole archive (`sha256sum` output or JSON from /checksum);
+    /// `<archive>.sha256` is used when it exists
+    #[arg(long, value_name = "FILE")]
+    checksum: Option<PathBuf>,
+}
+
+// --- CLIENT OUTPUT ---
+
+/// Fetches the server's dry-run report and prints it.
+async fn print_preview(connection: &ConnectionArgs, files: &[String]) -> ClientResult<()> {
+    let report = connection.connect().await?.preview(files).await?;
+
+    if output::is_json() {
+        output::print_event("preview", serde_json::to_value(&report)?);
+        return Ok(());
+    }
+
+    println!("🔎 Dry run: the server would archive {} file(s)", report.files.len());
+    for file in &report.files {
+        println!("  {:>12} bytes  {}", file.size, file.path);
+    }
+    println!("  Total size:                {} bytes", report.total_size);
+    println!("  Estimated compressed size: {} bytes", report.estimated_compressed_size);
+
+    Ok(())
+}
+
+/// Fetches the server's listing and prints it as a table (or one JSON line per entry).
+async fn print_listing(args: &ListArgs) -> ClientResult<()> {
+    let entries = args.connection.connect().await?.list(args.path.as_deref()).await?;
+
+    if output::is_json() {
+        for entry in &entries {
+            output::print_event("entry", serde_json::to_value(entry)?);
+        }
+        return Ok(());
+    }
+
+    println!("{:<4}  {:>10}  {:<16}  PATH", "TYPE", "SIZE", "MODIFIED");
+    for entry in &entries {
+        let kind = if entry.is_dir { "dir" } else { "file" };
+        let size = if entry.is_dir { "-".to_string() } else { format_size(entry.size) };
+        let modified = entry.mtime.map(format_unix_time).unwrap_or_else(|| "-".to_string());
+        let path = if entry.is_dir { format!("{}/", entry.path) } else { entry.path.clone() };
+        println!("{:<4}  {:>10}  {:<16}  {}", kind, size, modified, path);
+    }
+    println!("{} entries", entries.len());
+
+    Ok(())
+}
+
+async fn print_search(args: &SearchArgs) -> ClientResult<()> {
+    let query = SearchQuery {
+        pattern: args.pattern.clone(),
+        path: args.path.clone(),
+        min_size: args.min_size,
+        max_size: args.max_size,
+        newer_than: args.newer_than.clone(),
+        older_than: args.older_than.clone(),
+        limit: args.limit,
+    };
+    let results = args.connection.connect().await?.search(&query).await?;
+
+    if output::is_json() {
+        for file in &results.files {
+            output::print_event("match", serde_json::to_value(file)?);
+        }
+        if results.truncated {
+            output::print_event("truncated", serde_json::json!({ "shown": results.files.len() }));
+        }
+        return Ok(());
+    }
+
+    if args.paths {
+        for file in &results.files {
+            println!("{}", file.path);
+        }
+        return Ok(());
+    }
+    println!("{:>10}  {:<16}  PATH", "SIZE", "MODIFIED");
+    for file in &results.files {
+        let modified = file.mtime.map(format_unix_time).unwrap_or_else(|| "-".to_string());
+        println!("{:>10}  {:<16}  {}", format_size(file.size), modified, file.path);
+    }
+    match results.truncated {
+        true => println!("{} match(es) shown, more were left out (raise --limit)", results.files.len()),
+        false => println!("{} match(es)", results.files.len()),
+    }
+
+    Ok(())
+}
+
+/// Listens for announced servers and prints them as a table (or one JSON line per server).
+async fn print_discovered(args: &DiscoverArgs) -> ClientResult<()> {
+    let servers = discovery::discover(Duration::from_secs(args.timeout)).await?;
+
+    if output::is_json() {
+        for server in &servers {
+            output::print_event("server", serde_json::to_value(server)?);
+        }
+        return Ok(());
+    }
+
+    if servers.is_empty() {
+        println!("🔎 No servers found on the local network within {}s", args.timeout);
+        return Ok(());
+    }
+    println!("{:<24}  {:<32}  HOST", "NAME", "URL");
+    for server in &servers {
+        let url = server.url().unwrap_or_else(|| "-".to_string());
+        let name = if server.peer { format!("{} (peer)", server.name) } else { server.name.clone() };
+        println!("{:<24}  {:<32}  {}", name, url, server.host);
+    }
+    println!("{} server(s); connect with --server <NAME>", servers.len());
+
+    Ok(())
+}
+
+/// Fetches an archive manifest and prints it, or checks an extracted copy against it.
+async fn print_manifest(args: &ManifestArgs) -> ClientResult<()> {
+    let manifest = args.connection.connect().await?.manifest(&args.files, args.flatten).await?;
+
+    if let Some(dir) = &args.check {
+        let mismatches = check_manifest(&manifest, dir)?;
+        print_mismatches(&mismatches);
+        if !mismatches.is_empty() {
+            return Err(format!("{} of {} entries do not match the manifest", mismatches.len(), manifest.entries.len()).into());
+        }
+        emit("manifest_matches", serde_json::json!({ "path": dir, "entries": manifest.entries.len() }),
+            format_args!("✅ All {} entries in {} match the manifest", manifest.entries.len(), dir.display()));
+        return Ok(());
+    }
+
+    // One line holding the whole manifest, so it can be saved for `verify --manifest`
+    if output::is_json() {
+        output::print_event("manifest", serde_json::to_value(&manifest)?);
+        return Ok(());
+    }
+
+    println!("{:<7}  {:>10}  {:<16}  {:<12}  PATH", "TYPE", "SIZE", "MODIFIED", "SHA256");
+    for entry in &manifest.entries {
+        let (kind, size) = match entry.kind {
+            EntryKind::File => ("file", format_size(entry.size)),
+            EntryKind::Dir => ("dir", "-".to_string()),
+            EntryKind::Symlink => ("symlink", "-".to_string()),
+        };
+        let modified = entry.mtime.map(format_unix_time).unwrap_or_else(|| "-".to_string());
+        let sha256 = entry.sha256.as_deref().map(|digest| &digest[..12]).unwrap_or("-");
+        let path = match (&entry.kind, &entry.target) {
+            (EntryKind::Dir, _) => format!("{}/", entry.path),
+            (_, Some(target)) => format!("{} -> {}", entry.path, target),
+            _ => entry.path.clone(),
+        };
+        println!("{:<7}  {:>10}  {:<16}  {:<12}  {}", kind, size, modified, sha256, path);
+    }
+    println!("{} entries, ETag {}", manifest.entries.len(), manifest.etag);
+
+    Ok(())
+}
+
+/// Prints each difference from a manifest on its own line.
+fn print_mismatches(mismatches: &[ManifestMismatch]) {
+    for mismatch in mismatches {
+        emit("mismatch", serde_json::to_value(mismatch).unwrap_or_default(),
+            format_args!("✗ {}: {}", mismatch.path, mismatch.problem));
+    }
+}
+
+/// `path` with `.<extension>` added, if that file exists.
+fn existing_companion(path: &Path, extension: &str) -> Option<PathBuf> {
+    let mut companion = path.as_os_str().to_owned();
+    companion.push(format!(".{}", extension));
+    Some(PathBuf::from(companion)).filter(|companion| companion.is_file())
+}
+
+/// The SHA-256 a checksum file gives for `archive`: `sha256sum` output (the line naming the
+/// archive, or else the first) or the JSON served by /checksum.
+fn read_checksum_file(path: &Path, archive: &Path) -> ClientResult<String> {
+    let text = std::fs::read_to_string(path)?;
+    let digest = match serde_json::from_str::<ArchiveChecksum>(&text) {
+        Ok(checksum) => checksum.sha256,
+        Err(_) => {
+            let lines: Vec<(&str, &str)> = text.lines()
+                .filter_map(|line| line.split_once(char::is_whitespace))
+                .collect();
+            // sha256sum marks binary mode with a `*` before the name
+            let names_archive = |file: &str| Path::new(file.trim().trim_start_matches('*')).file_name() == archive.file_name();
+            let line = lines.iter().find(|(_, file)| names_archive(file)).or(lines.first());
+            line.map(|(digest, _)| digest.to_string()).unwrap_or_default()
+        }
+    };
+    if digest.len() != 64 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("{} does not hold a SHA-256 checksum", path.display()).into());
+    }
+    Ok(digest.to_ascii_lowercase())
+}
+
+/// Reads an archive through, checking its structure, then compares it with a checksum file
+/// and a manifest when there are any. Every problem is printed before failing.
+async fn verify(args: &VerifyArgs) -> ClientResult<()> {
+    let checksum = args.checksum.clone().or_else(|| existing_companion(&args.archive, "sha256"));
+    let manifest = args.manifest.clone().or_else(|| existing_companion(&args.archive, "manifest.json"));
+    let mut problems = 0;
+
+    let archive = args.archive.clone();
+    let entries = tokio::task::spawn_blocking(move || read_archive_manifest(&archive))
+        .await?
+        .map_err(|e| format!("{} is damaged: {}", args.archive.display(), e))?;
+    let files = entries.iter().filter(|entry| entry.kind == EntryKind::File).count();
+    let bytes = entries.iter().map(|entry| entry.size).sum();
+    emit("structure_verified", serde_json::json!({ "path": args.archive, "entries": entries.len(), "files": files, "bytes": bytes }),
+        format_args!("✅ {} reads back cleanly: {} entries, {} file(s), {}", args.archive.display(), entries.len(), files, format_size(bytes)));
+
+    if let Some(path) = &checksum {
+        let expected = read_checksum_file(path, &args.archive)?;
+        let archive = args.archive.clone();
+        let (_, actual) = tokio::task::spawn_blocking(move || hash_file(&archive)).await??;
+        if actual == expected {
+            emit("checksum_verified", serde_json::json!({ "path": args.archive, "sha256": actual, "checksum_file": path }),
+                format_args!("🔒 SHA-256 {} matches {}", actual, path.display()));
+        } else {
+            emit("checksum_mismatch", serde_json::json!({ "path": args.archive, "sha256": actual, "expected": expected, "checksum_file": path }),
+                format_args!("✗ SHA-256 is {}, {} expects {}", actual, path.display(), expected));
+            problems += 1;
+        }
+    }
+
+    if let Some(path) = &manifest {
+        let manifest: Manifest = serde_json::from_slice(&std::fs::read(path)?)
+            .map_err(|e| format!("{} is not a manifest: {}", path.display(), e))?;
+        let mismatches = compare_archive_manifest(&manifest, &entries);
+        print_mismatches(&mismatches);
+        if mismatches.is_empty() {
+            emit("manifest_matches", serde_json::json!({ "path": args.archive, "entries": manifest.entries.len(), "manifest": path }),
+                format_args!("✅ All {} entries match {}", manifest.entries.len(), path.display()));
+        }
+        problems += mismatches.len();
+    }
+
+    if checksum.is_none() && manifest.is_none() {
+        emit("nothing_to_compare", serde_json::json!({ "path": args.archive }),
+            format_args!("ℹ️  No manifest or checksum file found, only the structure was checked"));
+    }
+    if problems > 0 {
+        return Err(format!("{} failed verification ({} problem(s))", args.archive.display(), problems).into());
+    }
+    Ok(())
+}
+
+/// Prints a client error together with its underlying causes and exits with status 1. A
+/// timeout is reported as such, naming the option that sets it.
+fn exit_with_error(e: Box<dyn std::error::Error>) -> ! {
+    if let Some(message) = client::timeout_message(e.as_ref()) {
+        output::error(&message);
+        std::process::exit(1);
+    }
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        message.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+    output::error(&message);
+    std::process::exit(1);
+}
+
+
+async fn run_upload(args: UploadArgs) {
+    let connection = args.connection.connect().await.unwrap_or_else(|e| exit_with_error(e));
+    let uploader = |file: &Path, name: Option<String>, path: Option<String>| {
+        let mut uploader = Uploader::new(connection.clone(), file)
+            .overwrite(args.overwrite)
+            .chunk_size(args.chunk_size)
+            .retries(args.retries)
+            .retry_delay(Duration::try_from_secs_f64(args.retry_delay).unwrap_or_default());
+        if let Some(name) = name {
+            uploader = uploader.name(name);
+        }
+        if let Some(path) = path {
+            uploader = uploader.path(path);
+        }
+        uploader
+    };
+
+    if args.files.iter().any(|file| file == Path::new("-")) {
+        if args.files.len() > 1 || args.archive {
+            exit_with_error("- uploads stdin on its own, without other files or --archive".into());
+        }
+        if args.name.is_none() {
+            exit_with_error("Uploading from stdin needs a --name to store it under".into());
+        }
+        if let Err(e) = uploader(Path::new("-"), args.name.clone(), args.path.clone()).upload_stream(tokio::io::stdin()).await {
+            exit_with_error(e);
+        }
+        return;
+    }
+
+    if args.archive {
+        let (archive, built) = pack_upload(&args, connection.base_url()).await.unwrap_or_else(|e| exit_with_error(e));
+        let name = archive.file_name().map(|name| name.to_string_lossy().into_owned());
+        let name = args.name.clone().or(name);
+        match uploader(&archive, name, args.path.clone()).upload().await {
+            Ok(()) => {
+                if let Some(dir) = archive.parent() {
+                    let _ = std::fs::remove_dir_all(dir);
+                }
+            }
+            Err(e) => {
+                // Re-running the same command picks up this archive instead of packing a different
+                // one, unless the server turned the upload down outright
+                let refused = e.downcast_ref::<ServerError>().is_some_and(|e| e.status.is_client_error());
+                match archive.parent() {
+                    Some(dir) if refused => {
+                        let _ = std::fs::remove_dir_all(dir);
+                    }
+                    _ if built => output::warn(format_args!("Keeping {} to resume the upload with the same command", archive.display())),
+                    _ => {}
+                }
+                exit_with_error(e);
+            }
+        }
+        return;
+    }
+
+    let files = upload_files(&args.files, args.path.as_deref()).unwrap_or_else(|e| exit_with_error(e));
+    if args.name.is_some() && files.len() > 1 {
+        exit_with_error("--name needs a single file or --archive".into());
+    }
+    for (file, path) in &files {
+        if let Err(e) = uploader(file, args.name.clone(), path.clone()).upload().await {
+            exit_with_error(e);
+        }
+    }
+}
+
+/// Every file to upload with the server subdirectory it goes into: given files into `base`,
+/// and the files below a given directory into `base/<directory name>/...`.
+fn upload_files(paths: &[PathBuf], base: Option<&str>) -> ClientResult<Vec<(PathBuf, Option<String>)>> {
+    fn walk(dir: &Path, path: String, files: &mut Vec<(PathBuf, Option<String>)>) -> io::Result<()> {
+        let mut entries = std::fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let file_path = entry.path();
+            // Symlinked directories are skipped rather than followed, so a link loop can't recurse
+            if entry.file_type()?.is_dir() {
+                walk(&file_path, format!("{}/{}", path, entry.file_name().to_string_lossy()), files)?;
+            } else if file_path.is_file() {
+                files.push((file_path, Some(path.clone())));
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+    for file_path in paths {
+        let metadata = std::fs::metadata(file_path)
+            .map_err(|e| format!("Cannot upload {}: {}", file_path.display(), e))?;
+        if !metadata.is_dir() {
+            files.push((file_path.clone(), base.map(str::to_string)));
+            continue;
+        }
+        let dir_name = file_path.canonicalize()?.file_name()
+            .ok_or_else(|| format!("Cannot upload {}: it has no directory name", file_path.display()))?
+            .to_string_lossy().into_owned();
+        let path = match base {
+            Some(base) => format!("{}/{}", base.trim_end_matches('/'), dir_name),
+            None => dir_name,
+        };
+        walk(file_path, path, &mut files).map_err(|e| format!("Cannot read {}: {}", file_path.display(), e))?;
+    }
+    Ok(files)
+}
+
+/// Packs the files for `upload --archive` into the temporary directory, returning the archive
+/// and whether it was built now. The name is derived from the server, destination and files,
+/// so re-running an interrupted upload finds the archive again and resumes with the same bytes.
+async fn pack_upload(args: &UploadArgs, base_url: &str) -> ClientResult<(PathBuf, bool)> {
+    use sha2::{Digest, Sha256};
+
+    let builder = ArchiveBuilder::new()
+        .files(&args.files)
+        .format(args.format)
+        .codec(args.compression)
+        .threads(args.threads);
+    let builder = match args.level {
+        Some(level) => builder.level(level),
+        None => builder,
+    };
+    let extension = builder.options()?.extension();
+
+    let mut key = Sha256::new();
+    for part in [base_url, args.path.as_deref().unwrap_or_default(), args.name.as_deref().unwrap_or_default(), &extension] {
+        key.update(part.as_bytes());
+        key.update([0]);
+    }
+    for file_path in &args.files {
+        key.update(file_path.canonicalize().unwrap_or_else(|_| file_path.clone()).as_os_str().as_encoded_bytes());
+        key.update([0]);
+    }
+    let base_name = match args.files.as_slice() {
+        [file_path] => file_path.canonicalize().ok()
+            .and_then(|file_path| file_path.file_name().map(|name| name.to_string_lossy().into_owned()))
+            .unwrap_or_else(|| "upload".to_string()),
+        _ => "upload".to_string(),
+    };
+    let hash = format!("{:x}", key.finalize());
+    let dir = std::env::temp_dir().join(format!("rust-file-archive-upload-{}", &hash[..16]));
+    let archive = dir.join(format!("{}.{}", base_name, extension));
+
+    if archive.is_file() {
+        emit("archive_reused", serde_json::json!({ "path": archive }),
+            format_args!("📦 Reusing {} from an interrupted upload", archive.display()));
+        return Ok((archive, false));
+    }
+
+    std::fs::create_dir_all(&dir)?;
+    let partial = part_path(&archive);
+    let target = partial.clone();
+    tokio::task::spawn_blocking(move || -> io::Result<()> {
+        let writer = builder.write_to(io::BufWriter::new(std::fs::File::create(&target)?))?;
+        writer.into_inner().map_err(io::IntoInnerError::into_error)?.sync_all()
+    }).await?
+        .map_err(|e| format!("Could not pack the files: {}", e))?;
+    std::fs::rename(&partial, &archive)?;
+
+    let size = std::fs::metadata(&archive)?.len();
+    emit("archive_packed", serde_json::json!({ "path": archive, "bytes": size }),
+        format_args!("📦 Packed the files into {} ({})", archive.display(), format_size(size)));
+    Ok((archive, true))
+}
+
+/// Unpacks `archive` into `dest` off the async runtime and prints what happened.
+async fn extract(archive: &Path, dest: &Path, options: ExtractOptions) -> ClientResult<()> {
+    let (source, target) = (archive.to_path_buf(), dest.to_path_buf());
+    let summary = tokio::task::spawn_blocking(move || extract_archive_with(&source, &target, &options))
+        .await?
+        .map_err(|e| format!("Failed to extract {}: {}", archive.display(), e))?;
+    report_extracted(Some(archive), dest, &summary);
+    Ok(())
+}
+
+/// Prints what unpacking `archive` (or a streamed download, without one) into `dest` did.
+fn report_extracted(archive: Option<&Path>, dest: &Path, summary: &ExtractSummary) {
+    let fields = serde_json::json!({ "archive": archive, "dest": dest, "files": summary.files, "bytes": summary.bytes, "skipped": summary.skipped });
+    emit("extract_complete", fields, format_args!("📦 Extracted {} file(s), {} into {}", summary.files, format_size(summary.bytes), dest.display()));
+    if summary.skipped > 0 && !output::is_json() {
+        println!("   Skipped {} existing file(s)", summary.skipped);
+    }
+}
+
+/// `<path>.enc`, where a download to be decrypted into `path` is kept.
+fn encrypted_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", crypt::ENCRYPTED_EXTENSION));
+    PathBuf::from(name)
+}
+
+/// Decrypts `source` into `dest` and removes `source` once that worked.
+async fn decrypt(source: &Path, dest: &Path, passphrase: &str) -> ClientResult<()> {
+    let partial = part_path(dest);
+    let (from, to, passphrase) = (source.to_path_buf(), partial.clone(), passphrase.to_string());
+    let size = tokio::task::spawn_blocking(move || crypt::decrypt_file(&from, &to, &passphrase))
+        .await?
+        .map_err(|e| format!("Failed to decrypt {}: {}", source.display(), e))?;
+    std::fs::rename(&partial, dest)?;
+    std::fs::remove_file(source)?;
+
+    emit("decrypt_complete", serde_json::json!({ "path": dest, "bytes": size }),
+        format_args!("🔓 Decrypted {} ({})", dest.display(), format_size(size)));
+    Ok(())
+}
+
+/// Decrypts `archive` next to itself, unpacks the result and removes it again.
+async fn extract_encrypted(archive: &Path, dest: &Path, options: ExtractOptions, passphrase: &str) -> ClientResult<()> {
+    let file_name = archive.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let decrypted = archive.with_file_name(format!(".{}.decrypted", file_name));
+
+    let (from, to, passphrase) = (archive.to_path_buf(), decrypted.clone(), passphrase.to_string());
+    tokio::task::spawn_blocking(move || crypt::decrypt_file(&from, &to, &passphrase))
+        .await?
+        .map_err(|e| format!("Failed to decrypt {}: {}", archive.display(), e))?;
+
+    let result = extract(&decrypted, dest, options).await;
+    let _ = std::fs::remove_file(&decrypted);
+    result
+}
+
+/// Sends the server's `tracing` events to stdout (or --log-file, or --syslog), at `info` and
+/// above unless RUST_LOG says otherwise.
+fn init_logging(cli: &ServerArgs) -> Result<(), String> {
+    use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+    let (writer, ansi) = match &cli.log_file {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new().create(true).append(true).open(path)
+                .map_err(|e| format!("Could not open log file {}: {}", path.display(), e))?;
+            (BoxMakeWriter::new(std::sync::Mutex::new(file)), false)
+        }
+        None if cli.syslog => (BoxMakeWriter::new(Syslog::connect()?), false),
+        None => (BoxMakeWriter::new(io::stdout), io::stdout().is_terminal()),
+    };
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match cli.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().flatten_event(true).init(),
+    }
+    Ok(())
+}
+
+async fn run_archive(args: ArchiveArgs) {
+    let mut builder = ArchiveBuilder::new()
+        .files(&args.files)
+        .format(args.format)
+        .codec(args.compression)
+        .flatten(args.flatten)
+        .xattrs(args.xattrs)
+        .threads(args.threads);
+    if let Some(level) = args.level {
+        builder = builder.level(level);
+    }
+    for pattern in &args.exclude {
+        builder = builder.exclude(pattern);
+    }
+    if args.reproducible {
+        builder = builder.reproducible(args.source_date_epoch);
+    }
+
+    let (output, append) = (args.output.clone(), args.append);
+    let result = tokio::task::spawn_blocking(move || match append {
+        true => builder.append_to(&output).map(Some),
+        false => builder.write_file(&output).map(|()| None),
+    }).await;
+    let size = std::fs::metadata(&args.output).map(|metadata| metadata.len()).unwrap_or(0);
+    match result {
+        Ok(Ok(Some(summary))) => {
+            let fields = serde_json::json!({
+                "path": args.output, "bytes": size, "added": summary.added, "updated": summary.updated, "kept": summary.kept,
+            });
+            emit("archive_appended", fields, format_args!(
+                "📦 Updated {} ({}): {} added, {} updated, {} kept",
+                args.output.display(), format_size(size), summary.added, summary.updated, summary.kept
+            ));
+        }
+        Ok(Ok(None)) => emit("archive_created", serde_json::json!({ "path": args.output, "bytes": size }),
+            format_args!("📦 Packed {} ({})", args.output.display(), format_size(size))),
+        Ok(Err(e)) => exit_with_error(format!("Could not write {}: {}", args.output.display(), e).into()),
+        Err(e) => exit_with_error(e.into()),
+    }
+}
+
+async fn run_extract(args: ExtractArgs) {
+    let options = ExtractOptions { policy: args.overwrite, xattrs: args.xattrs, ..Default::default() };
+    let result = match args.passphrase.as_deref().filter(|_| args.decrypt) {
+        Some(passphrase) => extract_encrypted(&args.archive, &args.dest, options, passphrase).await,
+        None => extract(&args.archive, &args.dest, options).await,
+    };
+    if let Err(e) = result {
+        exit_with_error(e);
+    }
+}
+
+async fn run_verify(args: VerifyArgs) {
+    if let Err(e) = verify(&args).await {
+        exit_with_error(e);
+    }
+}
+
+async fn run_discover(args: DiscoverArgs) {
+    if let Err(e) = print_discovered(&args).await {
+        exit_with_error(e);
+    }
+}
+
+async fn run_rm(args: RmArgs) {
+    let connection = args.connection.connect().await.unwrap_or_else(|e| exit_with_error(e));
+    for path in &args.paths {
+        connection.remove(path, args.recursive).await.unwrap_or_else(|e| exit_with_error(e));
+        emit("removed", serde_json::json!({ "path": path }), format_args!("🗑️  Deleted {}", path));
+    }
+}
+
+async fn run_mv(args: MvArgs) {
+    let connection = args.connection.connect().await.unwrap_or_else(|e| exit_with_error(e));
+    connection.rename(&args.from, &args.to, args.overwrite).await.unwrap_or_else(|e| exit_with_error(e));
+    emit("moved", serde_json::json!({ "from": args.from, "to": args.to }), format_args!("✅ Moved {} to {}", args.from, args.to));
+}
+
+async fn run_mkdir(args: MkdirArgs) {
+    let connection = args.connection.connect().await.unwrap_or_else(|e| exit_with_error(e));
+    for path in &args.paths {
+        connection.make_dir(path, args.parents).await.unwrap_or_else(|e| exit_with_error(e));
+        emit("directory_created", serde_json::json!({ "path": path }), format_args!("📁 Created {}", path));
+    }
+}
+
+async fn run_share(args: ShareArgs) {
+    let connection = args.connection.connect().await.unwrap_or_else(|e| exit_with_error(e));
+    let link = connection.share(&args.files, args.expires, args.max_uses).await
+        .unwrap_or_else(|e| exit_with_error(e));
+
+    let uses = match link.max_uses {
+        Some(1) => " for a single download".to_string(),
+        Some(max_uses) => format!(" for {} downloads", max_uses),
+        None => String::new(),
+    };
+    let url = format!("{}{}", connection.base_url(), link.url);
+    emit("share_link", serde_json::json!({ "url": url, "token": link.token, "expires_at": link.expires_at, "max_uses": link.max_uses }),
+        format_args!("🔗 Share link valid until {} UTC{}:\n   {}", format_unix_time(link.expires_at), uses, url));
+}
+
+async fn run_bench(args: BenchArgs) {
+    let connection = args.connection.connect().await.unwrap_or_else(|e| exit_with_error(e));
+    let payload = match args.files.is_empty() {
+        true => Payload::Filler(args.size),
+        false => Payload::Files(args.files),
+    };
+    let bench = Bench::new(connection, payload.clone());
+    let size = bench.size().await.unwrap_or_else(|e| exit_with_error(e));
+    let what = match &payload {
+        Payload::Filler(_) => "filler".to_string(),
+        Payload::Files(files) => format!("the archive of {}", files.join(", ")),
+    };
+    emit("bench_started", serde_json::json!({ "bytes": size, "rounds": args.rounds }),
+        format_args!("⏱️  Downloading {} of {} {} time(s) per setting", format_size(size), what, args.rounds));
+
+    for &chunk_size in &args.chunk_size {
+        for &connections in &args.connections {
+            let report = bench.run(size, connections, chunk_size, args.rounds).await.unwrap_or_else(|e| exit_with_error(e));
+            let cpu = match (report.client_cpu_seconds, report.server_cpu_seconds) {
+                (Some(client), Some(server)) => format!(", CPU {} client / {} server", format_seconds(client), format_seconds(server)),
+                (Some(client), None) => format!(", CPU {} client", format_seconds(client)),
+                (None, _) => String::new(),
+            };
+            let latency = &report.latency;
+            emit("bench_result", serde_json::to_value(&report).unwrap_or_default(),
+                format_args!("📊 {:>3} connection(s), {} chunks: {}/s, latency p50 {} p90 {} p99 {}{}",
+                    connections, format_size(chunk_size), format_size(report.throughput as u64),
+                    format_seconds(latency.p50), format_seconds(latency.p90), format_seconds(latency.p99), cpu));
+        }
+    }
+}
+
+async fn run_admin(args: AdminArgs) {
+    let connection = args.connection.connect().await.unwrap_or_else(|e| exit_with_error(e));
+    let admin = AdminClient::new(connection);
+    let result = match args.action {
+        AdminAction::Status => admin.status().await.map(|status| print_admin_status("admin_status", &status)),
+        AdminAction::Cache { purge: false, .. } => admin.cache().await.map(|report| {
+            for archive in &report.archives {
+                emit("cached_archive", serde_json::to_value(archive).unwrap_or_default(),
+                    format_args!("{:>10}  {}", format_size(archive.size), archive.etag));
+            }
+            let usage = &report.usage;
+            emit("cache_usage", serde_json::to_value(usage).unwrap_or_default(),
+                format_args!("{} archive(s), {} of {}", usage.archives, format_size(usage.bytes), format_size(usage.max_bytes)));
+        }),
+        AdminAction::Cache { purge: true, etag } => admin.purge(etag.as_deref()).await.map(|purged| {
+            emit("cache_purged", serde_json::to_value(&purged).unwrap_or_default(),
+                format_args!("🗑️  Removed {} archive(s), {} from the cache", purged.removed, format_size(purged.bytes)));
+        }),
+        AdminAction::Transfers => admin.transfers().await.map(|transfers| {
+            for transfer in &transfers {
+                emit("transfer", serde_json::to_value(transfer).unwrap_or_default(),
+                    format_args!("#{:<5} {:<20} {} {}  {} in {}", transfer.id, transfer.client, transfer.method, transfer.path,
+                        format_size(transfer.bytes_sent), format_seconds(transfer.seconds)));
+            }
+            if transfers.is_empty() {
+                emit("transfers", serde_json::json!({ "count": 0 }), format_args!("No requests in flight"));
+            }
+        }),
+        AdminAction::Reload => admin.reload().await.map(|_| {
+            emit("reloaded", serde_json::json!({}), format_args!("✅ The server reloaded its configuration"));
+        }),
+        AdminAction::Drain { undo: true, .. } => admin.drain(false).await.map(|status| print_admin_status("drain_ended", &status)),
+        AdminAction::Drain { undo: false, wait } => match admin.drain(true).await {
+            Ok(mut status) => {
+                while wait && status.requests_in_flight > 0 {
+                    tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+                    status = admin.status().await.unwrap_or_else(|e| exit_with_error(e));
+                }
+                print_admin_status("draining", &status);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        },
+    };
+    if let Err(e) = result {
+        exit_with_error(e);
+    }
+}
+
+fn print_admin_status(event: &str, status: &AdminStatus) {
+    let state = match status.draining {
+        true => "Draining",
+        false => "Serving",
+    };
+    let cache = match &status.cache {
+        Some(usage) => format!(", cache {} archive(s), {} of {}", usage.archives, format_size(usage.bytes), format_size(usage.max_bytes)),
+        None => String::new(),
+    };
+    emit(event, serde_json::to_value(status).unwrap_or_default(),
+        format_args!("{}: {} request(s) in flight{}", state, status.requests_in_flight, cache));
+}
+
+async fn run_search(args: SearchArgs) {
+    if let Err(e) = print_search(&args).await {
+        exit_with_error(e);
+    }
+}
+
+async fn run_list(args: ListArgs) {
+    if let Err(e) = print_listing(&args).await {
+        exit_with_error(e);
+    }
+}
+
+async fn run_sync(args: SyncArgs) {
+    let connection = args.connection.connect().await.unwrap_or_else(|e| exit_with_error(e));
+    let mut downloader = Downloader::new(connection)
+        .files(args.files)
+        .format(args.format)
+        .flatten(args.flatten);
+    if let Some(codec) = args.compression {
+        downloader = downloader.compression(codec);
+    }
+    if let Some(rate) = args.limit_rate {
+        downloader = downloader.limit_rate(rate);
+    }
+
+    let passphrase = args.passphrase.as_deref().filter(|_| args.decrypt);
+    match downloader.sync(&args.dest, passphrase).await {
+        Ok(summary) => {
+            let fields = serde_json::json!({
+                "dest": args.dest, "files": summary.extracted.files, "bytes": summary.extracted.bytes, "unchanged": summary.unchanged,
+            });
+            match summary.extracted.files {
+                0 => emit("sync_complete", fields,
+                    format_args!("✅ {} is up to date ({} entries unchanged)", args.dest.display(), summary.unchanged)),
+                files => emit("sync_complete", fields, format_args!(
+                    "🔄 Updated {} file(s), {} in {} ({} entries unchanged)",
+                    files, format_size(summary.extracted.bytes), args.dest.display(), summary.unchanged
+                )),
+            }
+        }
+        Err(e) => exit_with_error(e),
+    }
+}
+
+async fn run_backup(args: BackupArgs) {
+    let connection = args.connection.connect().await.unwrap_or_else(|e| exit_with_error(e));
+    let mut downloader = Downloader::new(connection)
+        .files(args.files)
+        .format(args.format)
+        .flatten(args.flatten);
+    if let Some(codec) = args.compression {
+        downloader = downloader.compression(codec);
+    }
+    if let Some(rate) = args.limit_rate {
+        downloader = downloader.limit_rate(rate);
+    }
+
+    match downloader.backup(&args.backup_dir, args.full).await {
+        Ok(Some(snapshot)) => {
+            let kind = match snapshot.parent {
+                Some(parent) => format!("changes since snapshot {}", parent),
+                None => "full".to_string(),
+            };
+            let fields = serde_json::json!({
+                "dir": args.backup_dir, "snapshot": snapshot.id, "parent": snapshot.parent, "archive": snapshot.archive,
+                "changed": snapshot.changed, "unchanged": snapshot.unchanged, "bytes": snapshot.bytes,
+            });
+            emit("snapshot_created", fields, format_args!(
+                "💾 Snapshot {} ({}): {} entries changed, {} unchanged, {} in {}",
+                snapshot.id, kind, snapshot.changed, snapshot.unchanged, format_size(snapshot.bytes), args.backup_dir.display()
+            ));
+        }
+        Ok(None) => emit("snapshot_skipped", serde_json::json!({ "dir": args.backup_dir }),
+            format_args!("✅ Nothing changed since the last snapshot in {}", args.backup_dir.display())),
+        Err(e) => exit_with_error(e),
+    }
+}
+
+async fn run_restore(args: RestoreArgs) {
+    if args.list {
+        let index = match SnapshotIndex::load(&args.backup_dir).await {
+            Ok(Some(index)) => index,
+            Ok(None) => exit_with_error(format!("No backup in {}", args.backup_dir.display()).into()),
+            Err(e) => exit_with_error(e),
+        };
+        if output::is_json() {
+            for snapshot in &index.snapshots {
+                emit("snapshot", serde_json::to_value(snapshot).unwrap_or_default(), format_args!(""));
+            }
+            return;
+        }
+        println!("{:>4}  {:>6}  {:<20}  {:>8}  {:>9}  {:>10}", "ID", "PARENT", "TAKEN", "CHANGED", "UNCHANGED", "SIZE");
+        for snapshot in &index.snapshots {
+            let taken = httpdate::fmt_http_date(std::time::UNIX_EPOCH + Duration::from_secs(snapshot.created));
+            let parent = snapshot.parent.map_or_else(|| "-".to_string(), |parent| parent.to_string());
+            println!("{:>4}  {:>6}  {:<20}  {:>8}  {:>9}  {:>10}",
+                snapshot.id, parent, taken.get(5..25).unwrap_or(&taken), snapshot.changed, snapshot.unchanged, format_size(snapshot.bytes));
+        }
+        return;
+    }
+
+    let passphrase = args.passphrase.as_deref().filter(|_| args.decrypt);
+    match restore(&args.backup_dir, &args.dest, args.snapshot, passphrase).await {
+        Ok(summary) => {
+            let fields = serde_json::json!({
+                "dest": args.dest, "snapshot": summary.snapshot, "replayed": summary.replayed,
+                "files": summary.extracted.files, "bytes": summary.extracted.bytes, "removed": summary.removed,
+            });
+            emit("restore_complete", fields, format_args!(
+                "⏪ Restored snapshot {} into {} ({} snapshot(s) replayed: {} file(s), {} written, {} removed)",
+                summary.snapshot, args.dest.display(), summary.replayed, summary.extracted.files,
+                format_size(summary.extracted.bytes), summary.removed
+            ));
+        }
+        Err(e) => exit_with_error(e),
+    }
+}
+
+async fn run_mirror(args: MirrorArgs) {
+    let connection = args.connection.connect().await.unwrap_or_else(|e| exit_with_error(e));
+    let mut downloader = Downloader::new(connection).file(&args.server_path);
+    if let Some(codec) = args.compression {
+        downloader = downloader.compression(codec);
+    }
+    if let Some(rate) = args.limit_rate {
+        downloader = downloader.limit_rate(rate);
+    }
+    let passphrase = args.passphrase.as_deref().filter(|_| args.decrypt);
+
+    loop {
+        match downloader.mirror(&args.local_dir, args.delete, args.dry_run, passphrase).await {
+            Ok(report) => print_mirror_report(&args, &report),
+            // A scheduled mirror carries on after a failed pass, e.g. while the server restarts
+            Err(e) if args.interval.is_some() => output::error(&e.to_string()),
+            Err(e) => exit_with_error(e),
+        }
+        let Some(interval) = args.interval else { break };
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+    }
+}
+
+fn print_mirror_report(args: &MirrorArgs, report: &MirrorReport) {
+    if output::is_json() {
+        output::print_event("mirror_complete", serde_json::json!({
+            "dest": args.local_dir,
+            "dry_run": args.dry_run,
+            "changed": report.changed,
+            "removed": report.removed,
+            "deleted": args.delete && !args.dry_run,
+            "unchanged": report.unchanged,
+            "bytes": report.extracted.bytes,
+        }));
+        return;
+    }
+    if args.dry_run {
+        for path in &report.changed {
+            println!("  download  {}", path);
+        }
+    }
+    if args.delete {
+        for path in &report.removed {
+            println!("  delete    {}", path);
+        }
+    }
+
+    let removed = match (args.delete, report.removed.len()) {
+        (_, 0) => String::new(),
+        (true, count) if args.dry_run => format!(", {} to delete", count),
+        (true, count) => format!(", {} deleted", count),
+        (false, count) => format!(", {} only here (pass --delete to remove them)", count),
+    };
+    match (args.dry_run, report.changed.len()) {
+        (true, changed) => println!("🔎 {} to download, {} unchanged{}", changed, report.unchanged, removed),
+        (false, 0) => println!("✅ {} is up to date ({} unchanged{})", args.local_dir.display(), report.unchanged, removed),
+        (false, changed) => println!(
+            "🔄 Mirrored {} change(s), {} into {} ({} unchanged{})",
+            changed, format_size(report.extracted.bytes), args.local_dir.display(), report.unchanged, removed
+        ),
+    }
+}
+
+async fn run_manifest(args: ManifestArgs) {
+    if let Err(e) = print_manifest(&args).await {
+        exit_with_error(e);
+    }
+}
+
+async fn run_download(mut args: DownloadArgs) {
+    if args.interactive {
+        match pick_files(&args.connection).await {
+            Ok(Some(files)) => args.files = files,
+            Ok(None) => exit_with_error("Nothing was chosen".into()),
+            Err(e) => exit_with_error(e.into()),
+        }
+    }
+    let to_stdout = args.output.as_deref() == Some(Path::new("-"));
+    if to_stdout {
+        output::use_stderr();
+        let conflicting = [
+            (args.extract, "--extract"), (args.stream, "--stream"), (args.decrypt, "--decrypt"),
+            (args.output_dir.is_some(), "--output-dir"), (args.connections > 1, "--connections"), (args.chunk_store.is_some(), "--chunk-store"),
+            (args.share, "--share"),
+        ];
+        if let Some((_, flag)) = conflicting.iter().find(|(set, _)| *set) {
+            exit_with_error(format!("{} cannot be used with --output -", flag).into());
+        }
+        if std::io::stdout().is_terminal() {
+            exit_with_error("Refusing to write an archive to the terminal; pipe --output - into a program or a file".into());
+        }
+    }
+
+    let result = if args.dry_run {
+        print_preview(&args.connection, &args.files).await
+    } else {
+        let connection = args.connection.connect().await.unwrap_or_else(|e| exit_with_error(e));
+        let mut downloader = Downloader::new(connection)
+            .files(args.files.clone())
+            .format(args.format)
+            .flatten(args.flatten)
+            .connections(args.connections)
+            .retries(args.retries)
+            .retry_delay(Duration::try_from_secs_f64(args.retry_delay).unwrap_or_default())
+            .verify(!args.no_verify)
+            .background_build(args.async_build)
+            .join_parts(args.join || args.extract || args.decrypt);
+        if let Some(codec) = args.compression.or(args.store.then_some(Codec::Store)) {
+            downloader = downloader.compression(codec);
+        }
+        if let Some(level) = args.level {
+            downloader = downloader.level(level);
+        }
+        if let Some(rate) = args.limit_rate {
+            downloader = downloader.limit_rate(rate);
+        }
+        if let Some(dir) = &args.chunk_store {
+            downloader = downloader.chunk_store(dir);
+        }
+        if let Some(path) = &args.verify_key {
+            match crypt::load_verifying_key(path) {
+                Ok(key) => downloader = downloader.verify_key(key),
+                Err(e) => exit_with_error(format!("Could not load verify key {}: {}", path.display(), e).into()),
+            }
+        }
+
+        if to_stdout {
+            if let Err(e) = downloader.download_into(std::io::stdout()).await {
+                exit_with_error(e);
+            }
+            return;
+        }
+        if args.stream {
+            let extract_options = ExtractOptions { policy: args.overwrite, xattrs: args.xattrs, ..Default::default() };
+            let result = downloader.download_extracting(&args.dest, extract_options).await;
+            match result {
+                Ok(summary) => report_extracted(None, &args.dest, &summary),
+                Err(e) => exit_with_error(e),
+            }
+            return;
+        }
+
+        if let Some(dir) = &args.output_dir {
+            if let Err(e) = std::fs::create_dir_all(dir) {
+                exit_with_error(format!("Could not create {}: {}", dir.display(), e).into());
+            }
+        }
+        match &args.batch {
+            Some(batch) => run_batch(&downloader, batch, &args).await,
+            None => save_archive(&downloader, args.output.clone(), &args, &Default::default()).await.map(|_| ()),
+        }
+    };
+
+    if let Err(e) = result {
+        exit_with_error(e);
+    }
+}
+
+/// Lets the user choose what to download from the server's listing with the terminal picker,
+/// on stderr so a piped --output - is left alone. `None` when they cancelled.
+async fn pick_files(connection: &ConnectionArgs) -> Result<Option<Vec<String>>, String> {
+    let term = console::Term::stderr();
+    if !term.is_term() {
+        return Err("--interactive needs a terminal".to_string());
+    }
+    let connection = connection.connect().await.map_err(|e| e.to_string())?;
+    let runtime = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+        picker::pick(&term, |path| runtime.block_on(connection.list(path))).map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// Downloads an archive to `output` (in --output-dir), or when that is not given under the name
+/// the server suggests, then decrypts and extracts it as asked. Names taken by other downloads
+/// of the same batch are kept in `reserved`. Returns where the archive was saved.
+async fn save_archive(downloader: &Downloader, output: Option<PathBuf>, args: &DownloadArgs, reserved: &std::sync::Mutex<Vec<PathBuf>>) -> ClientResult<PathBuf> {
+    let dir = args.output_dir.as_deref().unwrap_or(Path::new(""));
+    let output = match output {
+        Some(output) => dir.join(output),
+        None => {
+            // A failing HEAD leaves the error to the download itself
+            let suggested = downloader.suggested_name().await.ok().flatten();
+            let mut name = suggested.unwrap_or_else(|| downloader.default_output().to_string_lossy().into_owned());
+            // Decrypting saves the archive inside under the name without the encryption's extension
+            if args.decrypt {
+                if let Some(plain) = name.strip_suffix(&format!(".{}", crypt::ENCRYPTED_EXTENSION)) {
+                    name = plain.to_string();
+                }
+            }
+            let mut reserved = reserved.lock().unwrap();
+            let output = numbered_path(dir, &name, &reserved);
+            reserved.push(output.clone());
+            output
+        }
+    };
+    let share = match args.share {
+        true => Some(start_share(downloader, &output, args).await?),
+        false => None,
+    };
+    let downloaded = match args.passphrase.as_deref().filter(|_| args.decrypt) {
+        // The encrypted file keeps its own name, so an interrupted run resumes it
+        Some(passphrase) => {
+            let encrypted = encrypted_path(&output);
+            match downloader.download(&encrypted).await {
+                Ok(()) => decrypt(&encrypted, &output, passphrase).await,
+                result => result,
+            }
+        }
+        None => downloader.download(&output).await.inspect(|()| {
+            if crypt::is_encrypted(&output).unwrap_or(false) && !args.extract {
+                emit("encrypted", serde_json::json!({ "path": output }),
+                    format_args!("🔐 {} is encrypted; unpack it with `extract --decrypt`", output.display()));
+            }
+        }),
+    };
+    if let (Ok(()), Some(share)) = (&downloaded, &share) {
+        share.complete();
+    }
+    let extract_options = ExtractOptions { policy: args.overwrite, xattrs: args.xattrs, ..Default::default() };
+    match downloaded {
+        Ok(()) if args.extract => extract(&output, &args.dest, extract_options).await,
+        result => result,
+    }?;
+    if let Some(share) = share {
+        keep_sharing(share, &output).await;
+    }
+    Ok(output)
+}
+
+/// Starts sharing the download into `output` with other clients, for --share.
+async fn start_share(downloader: &Downloader, output: &Path, args: &DownloadArgs) -> ClientResult<PeerShare> {
+    let name = args.share_name.clone().unwrap_or_else(|| format!("{}-peer", discovery::default_name()));
+    let share = PeerShare::start(downloader, output, args.share_port, Some(&name), args.share_token.clone()).await?;
+    let url = match local_ip() {
+        Ok(ip) => format!("http://{}:{}", ip, share.port()),
+        Err(_) => format!("port {}", share.port()),
+    };
+    match share.announced_as() {
+        Some(name) => emit("sharing", serde_json::json!({ "url": url, "name": name }),
+            format_args!("📡 Sharing the download at {} as {}; other clients can use `download --server {}` with the same files and options", url, name, name)),
+        None => emit("sharing", serde_json::json!({ "url": url }),
+            format_args!("📡 Sharing the download at {}; other clients can use `download --server-url {}` with the same files and options", url, url)),
+    }
+    if let Some(token) = share.token() {
+        // A token the user chose is theirs to hand out; a random one has to be shown
+        match args.share_token.is_some() {
+            true => emit("share_token", serde_json::json!({ "chosen": true }),
+                format_args!("🔑 Other clients need the share token you gave (--token)")),
+            false => emit("share_token", serde_json::json!({ "token": token }),
+                format_args!("🔑 The download is protected, so other clients need --token {}", token)),
+        }
+    }
+    Ok(share)
+}
+
+/// Serves the finished archive to other clients until interrupted, then says how much it sent.
+async fn keep_sharing(share: PeerShare, output: &Path) {
+    emit("still_sharing", serde_json::json!({ "path": output }),
+        format_args!("📡 Still sharing {}; press Ctrl+C to stop", output.display()));
+    let _ = tokio::signal::ctrl_c().await;
+    let stats = share.stats();
+    drop(share);
+    emit("share_summary", serde_json::json!({ "requests": stats.requests, "bytes": stats.bytes }),
+        format_args!("📡 Shared {} with other clients over {} request(s)", format_size(stats.bytes), stats.requests));
 }
 
-// --- ARCHIVING LOGIC ---
+// --- BATCH DOWNLOADS ---
+// `download --batch FILE` fetches many archives in one run, a few at a time over the same
+// pool of connections, and ends with a summary. One failed archive does not stop the others.
 
-/// Archives a list of file paths into a tar.gz file in memory (Bytes).
-fn create_tar_gz(file_paths: &[String]) -> io::Result<bytes::Bytes> {
-    let mut buffer = Vec::new();
-    let enc = GzEncoder::new(&mut buffer, Compression::default());
-    let mut tar = Builder::new(enc);
+/// One archive of a --batch file.
+#[derive(serde::Deserialize, Debug)]
+struct BatchEntry {
+    /// What to archive; the server's default set when empty
+    #[serde(default)]
+    files: Vec<String>,
+    /// Where to save it; named by the server when missing
+    output: Option<PathBuf>,
+}
 
-    for file_path_str in file_paths {
-        let file_path = PathBuf::from(file_path_str);
-        
-        if !file_path.exists() || !file_path.is_file() {
-            return Err(io::Error::new(io::ErrorKind::NotFound, 
-                format!("Source file not found or is a directory: {}", file_path_str)));
+impl BatchEntry {
+    /// How messages about this entry refer to it.
+    fn label(&self) -> String {
+        match (&self.output, self.files.is_empty()) {
+            (Some(output), _) => output.display().to_string(),
+            (None, true) => "the default files".to_string(),
+            (None, false) => self.files.join(" "),
         }
+    }
+}
 
-        let file_name = file_path.file_name()
-            .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "Invalid file path name"))?;
+/// Reads a --batch file: a JSON array of entries, or one entry per line as space-separated
+/// paths, optionally followed by `> OUTPUT`. Blank lines and lines starting with `#` are skipped.
+fn read_batch(path: &Path) -> ClientResult<Vec<BatchEntry>> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    if text.trim_start().starts_with('[') {
+        return serde_json::from_str(&text).map_err(|e| format!("Invalid batch file {}: {}", path.display(), e).into());
+    }
 
-        tar.append_path_with_name(&file_path, file_name)?;
+    let mut entries = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (files, output) = match line.rsplit_once('>') {
+            Some((files, output)) => match output.trim() {
+                "" => return Err(format!("{} line {}: no output name after `>`", path.display(), number + 1).into()),
+                output => (files, Some(PathBuf::from(output))),
+            },
+            None => (line, None),
+        };
+        entries.push(BatchEntry { files: files.split_whitespace().map(str::to_string).collect(), output });
     }
+    Ok(entries)
+}
 
-    let finished_tar = tar.into_inner().unwrap();
-    finished_tar.finish()?;
-    
-    Ok(bytes::Bytes::from(buffer))
+/// Downloads every archive of the batch file, --jobs at a time, then reports how it went.
+async fn run_batch(downloader: &Downloader, batch: &Path, args: &DownloadArgs) -> ClientResult<()> {
+    let entries = read_batch(batch)?;
+    let total = entries.len();
+    let jobs = usize::from(args.jobs).min(total.max(1));
+    emit("batch_started", serde_json::json!({ "batch": batch, "archives": total, "jobs": jobs }),
+        format_args!("📋 Downloading {} archive(s) from {}, {} at a time", total, batch.display(), jobs));
+    // Several bars at once would draw over each other
+    output::show_progress_bars(jobs == 1);
+
+    let started = std::time::Instant::now();
+    let reserved = std::sync::Mutex::new(Vec::new());
+    let results: Vec<(String, ClientResult<PathBuf>)> = futures_util::stream::iter(entries)
+        .map(|entry| {
+            let downloader = downloader.clone().files(entry.files.clone());
+            let reserved = &reserved;
+            async move {
+                let result = save_archive(&downloader, entry.output.clone(), args, reserved).await;
+                if let Err(e) = &result {
+                    output::error(&format!("{}: {}", entry.label(), e));
+                }
+                (entry.label(), result)
+            }
+        })
+        .buffer_unordered(jobs)
+        .collect()
+        .await;
+
+    let failed: Vec<&str> = results.iter().filter(|(_, result)| result.is_err()).map(|(label, _)| label.as_str()).collect();
+    let bytes: u64 = results.iter()
+        .filter_map(|(_, result)| result.as_ref().ok())
+        .filter_map(|output| std::fs::metadata(output).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    let seconds = started.elapsed().as_secs_f64();
+    emit("batch_complete", serde_json::json!({ "archives": total, "succeeded": total - failed.len(), "failed": failed, "bytes": bytes, "seconds": seconds }),
+        format_args!("📦 Batch complete: {} of {} archive(s) downloaded ({} in {:.1}s)", total - failed.len(), total, format_size(bytes), seconds));
+    match failed.is_empty() {
+        true => Ok(()),
+        false => Err(format!("{} of {} archive(s) failed: {}", failed.len(), total, failed.join(", ")).into()),
+    }
 }
 
-// --- AXUM HANDLER (The core of the server) ---
+// --- MAIN SETUP ---
 
-async fn download_handler(
-    State(state): State<AppState>, 
-    req: axum::extract::Request,
-) -> Result<Response, StatusCode> {
-    
-    let files_to_archive = &state.initial_files;
-    let archive_filename = "archive.tar.gz";
+fn main() {
+    let mut cli = match with_config(std::env::args_os().collect()) {
+        Ok(args) => Cli::parse_from(args),
+        Err(e) => {
+            output::error(&e);
+            std::process::exit(2);
+        }
+    };
+    output::use_json(cli.json);
+    if cli.json {
+        cli.server.log_format = LogFormat::Json;
+    }
 
-    // 1. Generate the archive in a blocking task
-    let files_for_task = files_to_archive.clone();
-    
-    let archive_data = match tokio::task::spawn_blocking(move || create_tar_gz(&files_for_task)).await {
-        Ok(Ok(data)) => data,
-        Ok(Err(e)) => {
-            eprintln!("Error creating archive: {:?}", e);
-            return Err(StatusCode::BAD_REQUEST);
+    // Only the server has --workers; the client commands run on the default runtime
+    let workers = match &cli.command {
+        Some(Command::Server(args)) => args.workers,
+        None => cli.server.workers,
+        Some(_) => None,
+    };
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    if let Some(workers) = workers {
+        runtime.worker_threads(workers);
+    }
+    match runtime.enable_all().build() {
+        Ok(runtime) => runtime.block_on(run(cli)),
+        Err(e) => {
+            output::error(&format!("Could not start the async runtime: {}", e));
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn run(cli: Cli) {
+    match cli.command {
+        Some(Command::Server(mut args)) => {
+            if cli.json {
+                args.log_format = LogFormat::Json;
+            }
+            start_server(*args).await
+        }
+        Some(Command::Download(args)) => run_download(args).await,
+        Some(Command::List(args)) => run_list(args).await,
+        Some(Command::Search(args)) => run_search(args).await,
+        Some(Command::Sync(args)) => run_sync(args).await,
+        Some(Command::Mirror(args)) => run_mirror(args).await,
+        Some(Command::Backup(args)) => run_backup(args).await,
+        Some(Command::Restore(args)) => run_restore(args).await,
+        Some(Command::Manifest(args)) => run_manifest(args).await,
+        Some(Command::Upload(args)) => run_upload(args).await,
+        Some(Command::Archive(args)) => run_archive(args).await,
+        Some(Command::Extract(args)) => run_extract(args).await,
+        Some(Command::Verify(args)) => run_verify(args).await,
+        Some(Command::Discover(args)) => run_discover(args).await,
+        Some(Command::Share(args)) => run_share(args).await,
+        Some(Command::Rm(args)) => run_rm(args).await,
+        Some(Command::Mv(args)) => run_mv(args).await,
+        Some(Command::Mkdir(args)) => run_mkdir(args).await,
+        Some(Command::Bench(args)) => run_bench(args).await,
+        Some(Command::Admin(args)) => run_admin(args).await,
+        Some(Command::Completions(args)) => {
+            let mut command = Cli::command();
+            let name = command.get_name().to_string();
+            clap_complete::generate(args.shell, &mut command, name, &mut io::stdout());
+        }
+        None => start_server(cli.server).await,
+    }
+}
+
+/// A --bind-address value; the port falls back to --port when left out.
+#[derive(Clone, Copy, Debug)]
+struct BindAddress {
+    ip: IpAddr,
+    port: Option<u16>,
+}
+
+/// A timeout given in seconds on the command line, where 0 turns it off.
+fn timeout_secs(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Parses `IP`, `IP:PORT`, `[IPv6]` or `[IPv6]:PORT`.
+fn parse_bind_address(value: &str) -> Result<BindAddress, String> {
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Ok(BindAddress { ip: addr.ip(), port: Some(addr.port()) });
+    }
+    let bare = value.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')).unwrap_or(value);
+    match bare.parse::<IpAddr>() {
+        Ok(ip) => Ok(BindAddress { ip, port: None }),
+        Err(_) => Err(format!("expected an IP address with an optional port, e.g. 0.0.0.0, [::]:8080 or 127.0.0.1:9000; got {:?}", value)),
+    }
+}
+
+/// Parses a `-H "Name: value"` header the way curl takes them.
+fn parse_header(value: &str) -> Result<(String, String), String> {
+    match value.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => Ok((name.trim().to_string(), value.trim().to_string())),
+        _ => Err(format!("expected a header as \"Name: value\", got {:?}", value)),
+    }
+}
+
+/// Parses a --preset as `NAME=PATH[,PATH...]`.
+fn parse_preset(value: &str) -> Result<(String, Vec<String>), String> {
+    let (name, paths) = value.split_once('=').ok_or_else(|| format!("expected NAME=PATH[,PATH...], got {:?}", value))?;
+    let paths: Vec<String> = paths.split(',').map(str::trim).filter(|path| !path.is_empty()).map(str::to_string).collect();
+    match (name.trim(), paths.is_empty()) {
+        ("", _) => Err("a preset needs a name".to_string()),
+        (_, true) => Err(format!("preset {} lists no paths", name.trim())),
+        (name, false) => Ok((name.to_string(), paths)),
+    }
+}
+
+/// Parses a --split-size, which cannot be 0.
+fn parse_split_size(value: &str) -> Result<u64, String> {
+    match parse_size(value)? {
+        0 => Err("the split size must be more than 0".to_string()),
+        size => Ok(size),
+    }
+}
+
+/// Parses a --max-header-size, which HTTP/1 needs to be at least 8 KiB.
+fn parse_header_size(value: &str) -> Result<usize, String> {
+    match parse_size(value)? {
+        size if size < MIN_MAX_HEADER_SIZE as u64 => Err(format!("the header limit must be at least {}", format_size(MIN_MAX_HEADER_SIZE as u64))),
+        size => Ok(usize::try_from(size).unwrap_or(usize::MAX)),
+    }
+}
+
+/// Parses a thread count, where 0 means one thread per CPU core.
+fn parse_threads(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(0) => Ok(std::thread::available_parallelism().map_or(1, |cores| cores.get())),
+        Ok(threads) => Ok(threads),
+        Err(_) => Err(format!("expected a number of threads, got {:?}", value)),
+    }
+}
+
+/// Parses octal permission bits such as `660` or `0770`.
+fn parse_mode(value: &str) -> Result<u32, String> {
+    match u32::from_str_radix(value, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("expected octal permission bits such as 660, got {:?}", value)),
+    }
+}
+
+/// Prints `url` as a QR code made of half-block characters, two modules per character cell.
+/// Light modules are drawn filled so the code scans on the usual dark terminal background.
+fn print_qr_code(url: &str) {
+    use qrcode::render::unicode::Dense1x2;
+
+    match qrcode::QrCode::new(url) {
+        Ok(code) => {
+            let image = code.render::<Dense1x2>()
+                .dark_color(Dense1x2::Light)
+                .light_color(Dense1x2::Dark)
+                .quiet_zone(true)
+                .build();
+            println!("{}", image);
+        }
+        Err(e) => output::warn(format_args!("Could not draw a QR code for {}: {}", url, e)),
+    }
+}
+
+/// The credentials the server accepts: --auth-token, --link-secret and the --auth-file users.
+fn auth_config(cli: &ServerArgs) -> Result<AuthConfig, String> {
+    let mut auth = AuthConfig::new(cli.auth_tokens.clone());
+    if let Some(secret) = &cli.link_secret {
+        auth = auth.with_link_secret(secret);
+    }
+    if let Some(auth_file) = &cli.auth_file {
+        auth = auth.with_users_file(auth_file)
+            .map_err(|e| format!("Could not load auth file {}: {}", auth_file.display(), e))?;
+    }
+    Ok(auth)
+}
+
+/// The server configuration the command line asks for, serving `default_files` when a
+/// request names none.
+fn server_config(cli: &ServerArgs, default_files: Vec<String>, auth: AuthConfig, socket_activated: bool) -> ServerConfig {
+    let cors = (!cli.cors_origin.is_empty()).then(|| {
+        let mut cors = CorsPolicy::new(cli.cors_origin.clone());
+        if !cli.cors_methods.is_empty() {
+            cors.methods = cli.cors_methods.clone();
+        }
+        if !cli.cors_headers.is_empty() {
+            cors.headers = cli.cors_headers.clone();
         }
-        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR), 
+        cors
+    });
+
+    ServerConfig {
+        root: cli.root.clone().unwrap_or_else(|| PathBuf::from(".")),
+        default_files,
+        skip_verify: cli.skip_verify,
+        stream: cli.stream,
+        dry_run: cli.dry_run,
+        pre_archive_cmd: cli.pre_archive_cmd.clone(),
+        post_archive_cmd: cli.post_archive_cmd.clone(),
+        compression: if cli.store { Codec::Store } else { cli.compression },
+        level: cli.level,
+        symlinks: cli.symlinks,
+        preserve_metadata: !cli.no_preserve,
+        reproducible: cli.reproducible,
+        mtime_clamp: cli.source_date_epoch.filter(|_| cli.reproducible),
+        xattrs: cli.xattrs,
+        threads: cli.threads,
+        exclude: cli.exclude.clone(),
+        max_archive_size: cli.max_archive_size,
+        max_files: cli.max_files,
+        split_size: cli.split_size,
+        chunked: cli.chunked,
+        upload_dir: cli.upload_dir.clone(),
+        max_upload_size: cli.max_upload_size,
+        cache_dir: cli.cache_dir.clone(),
+        cache_max_size: cli.cache_max_size,
+        watch: cli.watch,
+        presets: cli.preset.iter().cloned().collect(),
+        pregenerate: cli.pregenerate,
+        encrypt_passphrase: cli.passphrase.clone().filter(|_| cli.encrypt),
+        signing_key: cli.signing_key.clone(),
+        max_rate: cli.max_rate,
+        max_rate_per_connection: cli.max_rate_per_connection,
+        max_connections: cli.max_connections,
+        max_connections_per_ip: cli.max_connections_per_ip,
+        max_requests_per_minute: cli.max_requests_per_minute,
+        max_jobs: cli.max_jobs,
+        max_archive_jobs: cli.max_archive_jobs,
+        archive_queue: cli.archive_queue,
+        admin_token: cli.admin_token.clone(),
+        auth,
+        cors,
+        static_files: cli.static_files,
+        webdav: cli.webdav,
+        webdav_writable: cli.webdav_writable,
+        manage_files: cli.manage_files,
+        search_index: cli.search_index.then_some(cli.search_index_refresh),
+        audit_log: cli.audit_log.clone(),
+        compress_responses: !cli.no_response_compression,
+        grpc: cli.grpc,
+        min_free_space: cli.min_free_space,
+        tls: cli.tls_cert.clone().zip(cli.tls_key.clone()),
+        client_ca: cli.client_ca.clone(),
+        shutdown_timeout: Duration::from_secs(cli.shutdown_timeout),
+        read_timeout: timeout_secs(cli.read_timeout),
+        write_timeout: timeout_secs(cli.write_timeout),
+        idle_timeout: timeout_secs(cli.idle_timeout),
+        keep_alive: !cli.no_keep_alive,
+        max_header_size: cli.max_header_size,
+        max_headers: cli.max_headers,
+        backlog: cli.backlog,
+        announce: cli.announce.then(|| cli.announce_name.clone().unwrap_or_else(discovery::default_name)),
+        unix_socket: cli.bind_unix.clone(),
+        unix_socket_mode: cli.unix_socket_mode,
+        socket_activation: socket_activated,
+        http3: cli.http3,
+    }
+}
+
+/// `args` with the settings of the configuration file added for what they leave out, or as
+/// they are when there is no file. Help and mistakes on the command line are left to the parse
+/// that follows.
+fn with_config(args: Vec<std::ffi::OsString>) -> Result<Vec<std::ffi::OsString>, String> {
+    // A lenient first look finds the file and what the command line sets itself
+    let Ok(matches) = Cli::command().ignore_errors(true).try_get_matches_from(&args) else {
+        return Ok(args);
     };
-    
-    let file_size = archive_data.len() as u64;
+    match config_path(&matches) {
+        Some(path) => Config::read(&path)?.apply(Cli::command(), &matches, args).map_err(|e| format!("{}: {}", path.display(), e)),
+        None => Ok(args),
+    }
+}
 
-    let range_header = req.headers().get("Range");
-    
-    let mut res = Response::builder();
-    let headers = res.headers_mut().unwrap();
-
-    // Set general headers for all downloads
-    headers.insert(axum::http::header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
-    headers.insert(axum::http::header::CONTENT_TYPE, HeaderValue::from_static("application/x-tar"));
-    headers.insert(
-        axum::http::header::CONTENT_DISPOSITION,
-        HeaderValue::try_from(format!("attachment; filename=\"{}\"", archive_filename)).unwrap(),
-    );
-
-    match range_header {
-        Some(range_value) => {
-            // --- 2. Handle Range Request (206 Partial Content) for Resume ---
-            let range_str = range_value.to_str().unwrap_or("");
-            
-            // Parse returns Vec<HttpRange>
-            let ranges: Vec<http_range::HttpRange> = http_range::HttpRange::parse(range_str, file_size)
-                .map_err(|_| StatusCode::RANGE_NOT_SATISFIABLE)?;
-            
-            // Get the first range in the vector
-            let range = ranges.into_iter().next().unwrap(); 
-            
-            let start = range.start;
-            let length = range.length;
-            let end = start + length - 1;
-            let content_length = length;
-            
-            if start >= file_size || end >= file_size || start > end {
-                eprintln!("Range check failed: {}-{}/{}", start, end, file_size);
-                return Err(StatusCode::RANGE_NOT_SATISFIABLE);
-            }
-
-            // Slice the data for the requested range
-            let partial_data = archive_data.slice(start as usize..=end as usize);
-
-            // Set 206 Partial Content headers
-            headers.insert(axum::http::header::CONTENT_RANGE, 
-                HeaderValue::try_from(format!("bytes {}-{}/{}", start, end, file_size)).unwrap());
-            headers.insert(axum::http::header::CONTENT_LENGTH, 
-                HeaderValue::try_from(content_length).unwrap());
-
-            println!("<- Responding with 206 Partial Content: bytes {}-{}/{}", start, end, file_size);
-            
-            Ok(res.status(StatusCode::PARTIAL_CONTENT).body(Body::from(partial_data)).unwrap())
-        },
-        None => {
-            // --- 3. Handle Full Download Request (200 OK) ---
-            headers.insert(axum::http::header::CONTENT_LENGTH, 
-                HeaderValue::try_from(file_size).unwrap());
-            
-            println!("<- Responding with 200 OK (Full content, {} bytes)", file_size);
+/// The file named by --config, or else the default one if it exists.
+fn config_path(matches: &clap::ArgMatches) -> Option<PathBuf> {
+    matches.get_one::<PathBuf>("config").cloned()
+        .or_else(|| config::default_path().filter(|path| path.is_file()))
+}
+
+/// The server's flags for a reload: `current` with the configuration file read again, when
+/// there is one.
+fn reloaded_args(current: &ServerArgs) -> Result<ServerArgs, String> {
+    let args: Vec<std::ffi::OsString> = std::env::args_os().collect();
+    let in_use = Cli::command().ignore_errors(true).try_get_matches_from(&args).ok()
+        .and_then(|matches| config_path(&matches));
+    if in_use.is_none() {
+        return Ok(current.clone());
+    }
+    let cli = Cli::try_parse_from(with_config(args)?).map_err(|e| e.to_string())?;
+    Ok(match cli.command {
+        Some(Command::Server(args)) => *args,
+        _ => cli.server,
+    })
+}
 
-            Ok(res.status(StatusCode::OK).body(Body::from(archive_data)).unwrap())
+/// Applies the command line again on every SIGHUP and `POST /admin/reload`, picking up changes
+/// to the configuration file and the files it and the command line name (--auth-file,
+/// .serverignore, keys) and to the root directory. The running configuration stays in place
+/// when the new one has a problem.
+async fn reload_on_request(reloader: rust_file_archive_server::server::Reloader, cli: ServerArgs, default_files: Vec<String>, socket_activated: bool) {
+    let mut admin_requests = reloader.admin_requests();
+    let mut hangups = hangups();
+    loop {
+        let request = tokio::select! {
+            Some(request) = admin_requests.recv() => {
+                tracing::info!("Reload requested over the admin API, reloading the configuration");
+                Some(request)
+            }
+            Some(()) = hangups.next() => {
+                tracing::info!("SIGHUP received, reloading the configuration");
+                None
+            }
+            else => return,
+        };
+        let result = match reloaded_args(&cli).and_then(|args| auth_config(&args).map(|auth| (args, auth))) {
+            Ok((args, auth)) => reloader.reload(server_config(&args, default_files.clone(), auth, socket_activated)).await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e),
+        };
+        match &result {
+            Ok(()) => tracing::info!("Configuration reloaded"),
+            Err(e) => tracing::error!("Could not reload the configuration, keeping the running one: {}", e),
+        }
+        if let Some(request) = request {
+            request.respond(result);
         }
     }
 }
 
-// --- MAIN SETUP ---
+/// Every SIGHUP the process receives; none where there are no signals.
+fn hangups() -> futures_util::stream::BoxStream<'static, ()> {
+    #[cfg(unix)]
+    match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(signal) => return futures_util::stream::unfold(signal, |mut signal| async move {
+            signal.recv().await.map(|()| ((), signal))
+        }).boxed(),
+        Err(e) => tracing::warn!("Could not listen for SIGHUP: {}", e),
+    }
+    futures_util::stream::pending().boxed()
+}
 
-#[tokio::main]
-async fn main() {
-    let mut args: Vec<String> = env::args().collect();
-    
-    // --- ARGUMENT PARSING LOGIC ---
-    let files_start_index = 1; // args[0] is program path, files start at args[1]
-    
-    // 1. Check for minimum required arguments (program name + at least one file)
-    if args.len() < files_start_index + 1 {
-        eprintln!("Usage: cargo run -- <file_path_1> [file_path_2] [..] [optional_port]");
-        eprintln!("Example: cargo run -- fileA.txt fileB.txt 8888");
-        std::process::exit(1);
+async fn start_server(cli: ServerArgs) {
+    if cli.stop {
+        let pid_file = cli.pid_file.as_deref().unwrap_or(Path::new(""));
+        match stop_daemon(pid_file, Duration::from_secs(cli.shutdown_timeout)).await {
+            Ok(pid) => emit("server_stopped", serde_json::json!({ "pid": pid }), format_args!("Stopped the server (PID {})", pid)),
+            Err(e) => exit_with_error(e.into()),
+        }
+        return;
     }
-    
-    // 2. Extract arguments (excluding the program name itself)
-    let mut args_without_program_name: Vec<String> = args.drain(files_start_index..).collect();
-    
-    let mut server_port = DEFAULT_SERVER_PORT;
-    
-    // 3. Check if the last argument is a valid port number
-    if let Some(last_arg) = args_without_program_name.last() {
-        if let Ok(port) = last_arg.parse::<u16>() {
-            // It's a valid port, so use it and remove it from the list
-            server_port = port;
-            args_without_program_name.pop();
+    // The background process is this same command line again, marked so it goes on to serve
+    if cli.daemon && std::env::var_os(DAEMON_CHILD_ENV).is_none() {
+        let pid_file = cli.pid_file.as_deref().unwrap_or(Path::new(""));
+        match start_daemon(pid_file, cli.log_file.as_deref()).await {
+            Ok(pid) => emit("daemon_started", serde_json::json!({ "pid": pid, "pid_file": pid_file, "log_file": cli.log_file }), format_args!(
+                "Server running in the background (PID {}); stop it with --stop --pid-file {}", pid, pid_file.display(),
+            )),
+            Err(e) => exit_with_error(e.into()),
         }
+        return;
+    }
+
+    if let Err(e) = init_logging(&cli) {
+        exit_with_error(e.into());
     }
 
-    // 4. The remaining arguments are the file paths
-    let initial_files = args_without_program_name; // Now defined!
+    // --- ARGUMENT PARSING LOGIC ---
+    let mut initial_files = cli.files.clone();
+    let mut server_port = cli.port.unwrap_or(DEFAULT_SERVER_PORT);
+    let mut port_given = cli.port.is_some();
+    
+    // 1. Keep supporting the original `<files..> [port]` form: a numeric last argument is the port
+    if cli.port.is_none() {
+        if let Some(last_arg) = initial_files.last() {
+            if let Ok(port) = last_arg.parse::<u16>() {
+                // It's a valid port, so use it and remove it from the list
+                server_port = port;
+                port_given = true;
+                initial_files.pop();
+            }
+        }
+    }
 
-    // 5. Final validation 
-    if initial_files.is_empty() {
-         eprintln!("Error: You must specify at least one file path.");
+    // 2. Final validation: without a root to browse there must be a default file set
+    if initial_files.is_empty() && cli.root.is_none() {
+         output::error("You must specify at least one file path or a --root directory.");
          std::process::exit(1);
     }
+    for (i, (name, _)) in cli.preset.iter().enumerate() {
+        if cli.preset[..i].iter().any(|(earlier, _)| earlier == name) {
+            exit_with_error(format!("--preset {} is given more than once", name).into());
+        }
+    }
     
     // --- END ARGUMENT PARSING LOGIC ---
 
-    for file_path in &initial_files {
-        if !Path::new(file_path).is_file() {
-            eprintln!("Error: Required source file not found or is a directory: {}", file_path);
+    let mut auth = match auth_config(&cli) {
+        Ok(auth) => auth,
+        Err(e) => {
+            output::error(&e);
             std::process::exit(1);
         }
-    }
+    };
+    let auth_summary = auth.is_enabled().then(|| (auth.token_count(), auth.user_count(), auth.own_root_count()));
 
-    let local_ip_str = local_ip().map(|ip| ip.to_string()).unwrap_or_else(|e| {
-        eprintln!("Warning: Could not determine local IP. Using 127.0.0.1. Error: {}", e);
-        "127.0.0.1".to_string()
-    });
-    
-    let base_url = format!("http://{}:{}", local_ip_str, server_port);
+    let one_time_token = if cli.qr_token {
+        if !auth.is_enabled() {
+            output::error("--qr-token needs authentication (--auth-token or --auth-file); without it the link works as is.");
+            std::process::exit(1);
+        }
+        match auth.add_one_time_token() {
+            Ok(token) => Some(token),
+            Err(e) => {
+                output::error(&format!("Could not generate a one-time token: {}", e));
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    // Under a systemd .socket unit the listeners are passed in, and only bound here when asked
+    let socket_activated = systemd::is_socket_activated();
+    let config = server_config(&cli, initial_files.clone(), auth, socket_activated);
+
+    let addrs: Vec<SocketAddr> = match cli.bind_address.as_slice() {
+        [] if (cli.bind_unix.is_some() || socket_activated) && !port_given => Vec::new(),
+        [] => vec![SocketAddr::from((Ipv4Addr::UNSPECIFIED, server_port))],
+        bind_addresses => bind_addresses.iter()
+            .map(|address| SocketAddr::new(address.ip, address.port.unwrap_or(server_port)))
+            .collect(),
+    };
+    let server = match FileServer::bind_all(&addrs, config).await {
+        Ok(server) => server,
+        Err(e) => {
+            output::error(&e.to_string());
+            std::process::exit(1);
+        }
+    };
+
+    let listen_addrs = server.local_addrs().unwrap_or(addrs);
+    let first_addr = listen_addrs.first().copied().unwrap_or_else(|| SocketAddr::from((Ipv4Addr::LOCALHOST, 80)));
+    let server_port = first_addr.port();
+
+    // Links use the first address; a wildcard one is replaced with this machine's LAN address
+    let link_ip = match first_addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => local_ip().unwrap_or_else(|e| {
+            output::warn(format_args!("Could not determine local IP. Using 127.0.0.1. Error: {}", e));
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        }),
+        IpAddr::V6(ip) if ip.is_unspecified() => local_ipv6().unwrap_or_else(|e| {
+            output::warn(format_args!("Could not determine local IPv6 address. Using ::1. Error: {}", e));
+            IpAddr::V6(std::net::Ipv6Addr::LOCALHOST)
+        }),
+        ip => ip,
+    };
+
+    let scheme = if server.is_tls() { "https" } else { "http" };
+    // SocketAddr puts IPv6 addresses in brackets, as URLs need; without a TCP address,
+    // links are for `curl --unix-socket`, which ignores the host
+    let base_url = match listen_addrs.is_empty() {
+        true => format!("{}://localhost", scheme),
+        false => format!("{}://{}", scheme, SocketAddr::new(link_ip, server_port)),
+    };
     let download_url = format!("{}/download", base_url);
+    let share_url = match &one_time_token {
+        Some(token) => format!("{}?token={}", download_url, token),
+        None => download_url.clone(),
+    };
+
+    // Log collectors expect one JSON object per line, so the banner becomes a single event there
+    if let LogFormat::Json = cli.log_format {
+        tracing::info!(url = %base_url, root = %server.root().display(), "Server running");
+        if one_time_token.is_some() {
+            tracing::info!(url = %share_url, "One-time download link");
+        }
+    } else {
+        println!("--- File Archive Server Started (Axum) ---");
+        println!("Serving files under: {}", server.root().display());
+        println!("Default files (when a request names none): {:?}", initial_files);
+        let mut shown: Vec<String> = listen_addrs.iter().map(|addr| addr.to_string()).collect();
+        if let Some(path) = server.unix_socket() {
+            shown.push(format!("unix:{}", path.display()));
+        }
+        match (listen_addrs.is_empty(), server.unix_socket()) {
+            (true, Some(path)) => println!("Server running on: unix:{}", path.display()),
+            _ => println!("Server running on: {}", base_url),
+        }
+        if shown.len() > 1 {
+            println!("Listening on: {}", shown.join(", "));
+        }
+        println!("----------------------------------------------------------");
+        match (listen_addrs.is_empty(), server.unix_socket()) {
+            (true, Some(path)) => {
+                println!(" DOWNLOAD THROUGH THE SOCKET:");
+                println!("curl --unix-socket {} -OJ {}", path.display(), download_url);
+            }
+            _ => {
+                println!(" DIRECT DOWNLOAD LINK (Clickable, Port {}):", server_port);
+                println!("{}", download_url);
+                println!(" Browse and pick files in a browser: {}/", base_url);
+            }
+        }
+        println!("----------------------------------------------------------");
+
+        if cli.qr && listen_addrs.is_empty() {
+            output::warn(format_args!("--qr needs a TCP address to link to; not printing a QR code"));
+        } else if cli.qr {
+            print_qr_code(&share_url);
+            if one_time_token.is_some() {
+                println!("One-time link (valid for a single download): {}", share_url);
+            }
+        }
+
+        if socket_activated {
+            println!("Socket-activated: accepting connections on the sockets passed by systemd");
+        }
+        if cli.skip_verify {
+            println!("Archive verification disabled (--skip-verify)");
+        }
+        if cli.stream {
+            println!("Streaming mode: full downloads are compressed on the fly");
+        }
+        if cli.dry_run {
+            println!("Dry-run mode: /download reports what would be archived instead of sending it");
+        }
+        if let Some(command) = &cli.pre_archive_cmd {
+            println!("Before each archive: {}", command);
+        }
+        if let Some(command) = &cli.post_archive_cmd {
+            println!("After each archive: {}", command);
+        }
+        if let Some(rate) = cli.max_rate {
+            println!("Bandwidth limited to {}/s in total", format_size(rate));
+        }
+        if let Some(rate) = cli.max_rate_per_connection {
+            println!("Bandwidth limited to {}/s per connection", format_size(rate));
+        }
+        if let Some(max) = cli.max_archive_size {
+            println!("Archives limited to {} of files", format_size(max));
+        }
+        if let Some(max) = cli.max_files {
+            println!("Archives limited to {} files", max);
+        }
+        if let Some(size) = cli.split_size {
+            println!("Archives also served in parts of {}", format_size(size));
+        }
+        if cli.chunked {
+            println!("Archives also served as content-defined chunks");
+        }
+        if cli.xattrs {
+            println!("Extended attributes recorded in tarballs");
+        }
+        if cli.threads > 1 {
+            println!("Tarballs compressed on {} threads", cli.threads);
+        }
+        if let Some(max) = cli.max_connections {
+            println!("Connections limited to {} in total", max);
+        }
+        if let Some(max) = cli.max_connections_per_ip {
+            println!("Connections limited to {} per client IP", max);
+        }
+        if let Some(max) = cli.max_requests_per_minute {
+            println!("Requests limited to {} per minute per client IP", max);
+        }
+        if let Some(max) = cli.max_jobs {
+            println!("Background builds limited to {} at a time", max);
+        }
+        if let Some(max) = cli.max_archive_jobs {
+            match cli.archive_queue {
+                Some(queue) => println!("Archives built {} at a time, with up to {} waiting", max, queue),
+                None => println!("Archives built {} at a time", max),
+            }
+        }
+        if cli.admin_token.is_some() {
+            println!("Admin API at /admin");
+        }
+        if cli.grpc {
+            println!("gRPC control plane at /fileserver.v1.FileServer");
+        }
+        if let Some(workers) = cli.workers {
+            println!("Serving on {} worker thread(s)", workers);
+        }
+        if cli.no_keep_alive {
+            println!("Keep-alive off: HTTP/1 connections close after each response");
+        }
+        if let Some(cache_dir) = &cli.cache_dir {
+            println!("Caching archives in {} (up to {})", cache_dir.display(), format_size(cli.cache_max_size));
+        }
+        if cli.watch || cli.pregenerate {
+            println!("Watching {} for changes to keep cached archives fresh", server.root().display());
+        }
+        if !cli.preset.is_empty() {
+            let names: Vec<&str> = cli.preset.iter().map(|(name, _)| name.as_str()).collect();
+            let pregenerated = if cli.pregenerate { ", pregenerated" } else { "" };
+            println!("Presets{}: {} (as {}/download?preset=<name>)", pregenerated, names.join(", "), base_url);
+        }
+        if cli.signing_key.is_some() {
+            println!("Archives are signed: signatures at {}/download.sig", base_url);
+        }
+        if cli.encrypt {
+            println!("Archives are encrypted; clients need the passphrase (download --decrypt)");
+        }
+        if let Some(upload_dir) = &cli.upload_dir {
+            println!("Uploads enabled: POST {}/upload stores files in {}", base_url, upload_dir.display());
+        }
+        if let Some((tokens, users, own_roots)) = auth_summary {
+            println!("Authentication required ({} token(s), {} user(s))", tokens, users);
+            if own_roots > 0 {
+                println!("{} user(s) see a root of their own instead of the server root", own_roots);
+            }
+        }
+        if cli.static_files {
+            println!("Static files: {}/<path>", base_url);
+        }
+        if cli.webdav {
+            let access = if cli.webdav_writable { "read-write" } else { "read-only" };
+            println!("WebDAV share ({}): {}/dav/", access, base_url);
+        }
+        if cli.manage_files {
+            println!("File management enabled: clients may delete, move and create files in the root");
+        }
+        if cli.search_index {
+            println!("Search index kept for /search, refreshed after {}s", cli.search_index_refresh.as_secs());
+        }
+        if let Some(audit_log) = &cli.audit_log {
+            println!("Downloads are recorded in the audit log {}", audit_log.display());
+        }
+        if !cli.cors_origin.is_empty() {
+            println!("Cross-origin requests allowed from {}", cli.cors_origin.join(", "));
+        }
+        if server.is_http3() {
+            println!("HTTP/3 enabled on UDP port {} (experimental)", server_port);
+        }
+        if let Some(name) = server.announced_as() {
+            println!("Announced on the local network as \"{}\" (clients: --server \"{}\")", name, name);
+        }
+    }
 
-    println!("--- File Archive Server Started (Axum) ---");
-    println!("Files being served: {:?}", initial_files);
-    println!("Server running on: {}", base_url);
-    println!("----------------------------------------------------------");
-    println!(" DIRECT DOWNLOAD LINK (Clickable, Port {}):", server_port);
-    println!("{}", download_url);
-    println!("----------------------------------------------------------");
+    if let Some(pid_file) = &cli.pid_file {
+        if let Err(e) = std::fs::write(pid_file, format!("{}\n", std::process::id())) {
+            output::error(&format!("Could not write PID file {}: {}", pid_file.display(), e));
+            std::process::exit(1);
+        }
+    }
 
-    let app_state = AppState { initial_files };
-    let app = Router::new()
-        .route("/download", get(download_handler))
-        .with_state(app_state);
+    tokio::spawn(reload_on_request(server.reloader(), cli.clone(), initial_files.clone(), socket_activated));
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], server_port)); 
+    // Stop accepting connections on SIGTERM/SIGINT, then give in-flight downloads
+    // up to --shutdown-timeout seconds to finish before giving up on them.
+    server.run().await;
 
-    match tokio::net::TcpListener::bind(addr).await {
-        Ok(listener) => {
-            axum::serve(listener, app).await.unwrap();
+    if let Some(pid_file) = &cli.pid_file {
+        if let Err(e) = std::fs::remove_file(pid_file) {
+            output::warn(format_args!("Could not remove PID file {}: {}", pid_file.display(), e));
         }
-        Err(e) => {
-            eprintln!("Error binding to address {}:{} -- Is the port already in use?", local_ip_str, server_port);
-            eprintln!("Details: {}", e);
+    }
+
+    match cli.log_format {
+        LogFormat::Json => tracing::info!("Server stopped"),
+        LogFormat::Text => println!("Server stopped."),
+    }
+    let _ = io::stdout().flush();
+}
+
+// --- DAEMON MODE ---
+
+/// Set in the environment of the process `--daemon` starts, which then serves instead of
+/// starting another.
+const DAEMON_CHILD_ENV: &str = "FILE_SERVER_DAEMON_CHILD";
+
+/// How long `--daemon` waits for the background server to write its PID file (it has bound
+/// its sockets by then) before giving up on it.
+const DAEMON_START_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The PID in `pid_file`, if it names a process that is still running.
+fn running_pid(pid_file: &Path) -> Option<u32> {
+    let pid = read_pid(pid_file)?;
+    process_exists(pid).then_some(pid)
+}
+
+fn read_pid(pid_file: &Path) -> Option<u32> {
+    std::fs::read_to_string(pid_file).ok()?.trim().parse().ok()
+}
+
+/// Starts this command line again as a background process in its own session, with output
+/// going to `log_file` (or nowhere), and waits until it is serving. Returns its PID.
+#[cfg(unix)]
+async fn start_daemon(pid_file: &Path, log_file: Option<&Path>) -> Result<u32, String> {
+    use std::os::unix::process::CommandExt;
+    use std::process::Stdio;
+
+    if let Some(pid) = running_pid(pid_file) {
+        return Err(format!("A server is already running with PID {} (see {})", pid, pid_file.display()));
+    }
+    let output = match log_file {
+        Some(path) => std::fs::OpenOptions::new().create(true).append(true).open(path)
+            .map_err(|e| format!("Could not open log file {}: {}", path.display(), e))?,
+        None => std::fs::OpenOptions::new().write(true).open("/dev/null").map_err(|e| e.to_string())?,
+    };
+
+    let exe = std::env::current_exe().map_err(|e| format!("Could not find the server's executable: {}", e))?;
+    let mut command = std::process::Command::new(exe);
+    command.args(std::env::args_os().skip(1))
+        .env(DAEMON_CHILD_ENV, "1")
+        .stdin(Stdio::null())
+        .stdout(output.try_clone().map_err(|e| e.to_string())?)
+        .stderr(output);
+    // SAFETY: setsid only touches the new process, and is async-signal-safe
+    unsafe {
+        // A session of its own has no controlling terminal, so closing this one leaves it running
+        command.pre_exec(|| match libc::setsid() {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        });
+    }
+    let mut child = command.spawn().map_err(|e| format!("Could not start the server in the background: {}", e))?;
+
+    let started = std::time::Instant::now();
+    loop {
+        if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+            return Err(match log_file {
+                Some(path) => format!("The server exited during startup ({}); see {}", status, path.display()),
+                None => format!("The server exited during startup ({}); run it without --daemon or with --log-file to see why", status),
+            });
+        }
+        if read_pid(pid_file) == Some(child.id()) {
+            return Ok(child.id());
+        }
+        if started.elapsed() > DAEMON_START_TIMEOUT {
+            return Err(format!("The server (PID {}) has not finished starting after {}s", child.id(), DAEMON_START_TIMEOUT.as_secs()));
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[cfg(not(unix))]
+async fn start_daemon(_pid_file: &Path, _log_file: Option<&Path>) -> Result<u32, String> {
+    Err("--daemon is only supported on Unix; use a service manager instead".to_string())
+}
+
+/// Sends SIGTERM to the server in `pid_file` and waits for it to exit, which takes as long as
+/// its in-flight downloads do (up to its own --shutdown-timeout).
+#[cfg(unix)]
+async fn stop_daemon(pid_file: &Path, timeout: Duration) -> Result<u32, String> {
+    let pid = read_pid(pid_file)
+        .ok_or_else(|| format!("No PID in {}; is the server running?", pid_file.display()))?;
+    if !process_exists(pid) {
+        let _ = std::fs::remove_file(pid_file);
+        return Err(format!("No server is running with PID {}; removed the stale {}", pid, pid_file.display()));
+    }
+    // SAFETY: kill has no memory-safety preconditions
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } == -1 {
+        return Err(format!("Could not signal PID {}: {}", pid, io::Error::last_os_error()));
+    }
+
+    let started = std::time::Instant::now();
+    while process_exists(pid) {
+        // A little longer than the server's own drain, so its last log lines get written
+        if started.elapsed() > timeout + Duration::from_secs(5) {
+            return Err(format!("The server (PID {}) is still shutting down after {}s", pid, started.elapsed().as_secs()));
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    Ok(pid)
+}
+
+#[cfg(not(unix))]
+async fn stop_daemon(_pid_file: &Path, _timeout: Duration) -> Result<u32, String> {
+    Err("--stop is only supported on Unix".to_string())
+}
+
+#[cfg(unix)]
+fn process_exists(pid: u32) -> bool {
+    // SAFETY: signal 0 only checks whether the process exists
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    // EPERM: it exists, but belongs to another user
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_exists(_pid: u32) -> bool {
+    false
+}
+
+/// `--syslog`: every log line becomes one datagram to the local syslog daemon, with a
+/// priority matching its level (facility `daemon`).
+#[derive(Clone)]
+struct Syslog {
+    #[cfg(unix)]
+    socket: std::sync::Arc<std::os::unix::net::UnixDatagram>,
+}
+
+impl Syslog {
+    #[cfg(unix)]
+    fn connect() -> Result<Syslog, String> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()
+            .and_then(|socket| socket.connect("/dev/log").map(|()| socket))
+            .map_err(|e| format!("Could not connect to syslog at /dev/log: {}", e))?;
+        Ok(Syslog { socket: std::sync::Arc::new(socket) })
+    }
+
+    #[cfg(not(unix))]
+    fn connect() -> Result<Syslog, String> {
+        Err("--syslog is only supported on Unix".to_string())
+    }
+}
+
+/// One log line on its way to syslog, sent when the formatter is done with it.
+struct SyslogLine {
+    syslog: Syslog,
+    priority: u8,
+    line: Vec<u8>,
+}
+
+impl<'a> tracing_subscriber::fmt::MakeWriter<'a> for Syslog {
+    type Writer = SyslogLine;
+
+    fn make_writer(&'a self) -> SyslogLine {
+        SyslogLine { syslog: self.clone(), priority: syslog_priority(&tracing::Level::INFO), line: Vec::new() }
+    }
+
+    fn make_writer_for(&'a self, meta: &tracing::Metadata<'_>) -> SyslogLine {
+        SyslogLine { syslog: self.clone(), priority: syslog_priority(meta.level()), line: Vec::new() }
+    }
+}
+
+/// Facility `daemon` (3) with the severity of `level`.
+fn syslog_priority(level: &tracing::Level) -> u8 {
+    let severity = match *level {
+        tracing::Level::ERROR => 3,
+        tracing::Level::WARN => 4,
+        tracing::Level::INFO => 6,
+        _ => 7,
+    };
+    3 * 8 + severity
+}
+
+impl Write for SyslogLine {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SyslogLine {
+    fn drop(&mut self) {
+        let message = String::from_utf8_lossy(&self.line);
+        let message = message.trim_end();
+        if message.is_empty() {
+            return;
         }
+        let datagram = format!("<{}>rust-file-archive-server[{}]: {}", self.priority, std::process::id(), message);
+        #[cfg(unix)]
+        let _ = self.syslog.socket.send(datagram.as_bytes());
+        #[cfg(not(unix))]
+        let _ = (&self.syslog, datagram);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,223 @@
+//! Managing the files in the served root over HTTP (`--manage-files`): deleting, moving and
+//! renaming, and creating directories, for the `rm`, `mv` and `mkdir` commands.
+//!
+//! Paths go through the same sandboxing and exclusions as `/list` and `/download`, and the
+//! routes sit behind authentication, which the server insists on. The last component of a path
+//! is never followed, so deleting or moving a symlink acts on the link and not on its target.
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::{delete, post},
+    Router,
+};
+use serde::Deserialize;
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+use tracing::info;
+
+use crate::archive::ExcludeRules;
+use crate::server::{ensure_portable, resolve_in_root};
+use crate::storage::Storage;
+
+/// The served root, as far as managing its files goes.
+pub(crate) struct ManagedRoot {
+    pub(crate) storage: Storage,
+    /// The canonical root, as in the server state
+    pub(crate) root: PathBuf,
+    pub(crate) exclude: ExcludeRules,
+}
+
+type ManageResult<T> = Result<T, (StatusCode, String)>;
+
+/// Query of `DELETE /files`.
+#[derive(Deserialize, Debug)]
+struct RemoveParams {
+    path: String,
+    /// Delete a directory with everything in it; otherwise only empty ones go
+    #[serde(default)]
+    recursive: bool,
+}
+
+/// Query of `POST /files/move`.
+#[derive(Deserialize, Debug)]
+struct MoveParams {
+    from: String,
+    /// The new path, not the directory to move into
+    to: String,
+    /// Replace a file already at `to`
+    #[serde(default)]
+    overwrite: bool,
+}
+
+/// Query of `POST /files/mkdir`.
+#[derive(Deserialize, Debug)]
+struct MkdirParams {
+    path: String,
+    /// Create missing parent directories too, and accept a directory that already exists
+    #[serde(default)]
+    parents: bool,
+}
+
+/// The file management routes, which the caller puts behind authentication.
+pub(crate) fn routes<S>(managed: Arc<ManagedRoot>) -> Router<S> {
+    Router::new()
+        .route("/files", delete(remove_handler))
+        .route("/files/move", post(move_handler))
+        .route("/files/mkdir", post(mkdir_handler))
+        .with_state(managed)
+}
+
+/// `relative` without surrounding slashes, refusing the root itself and `.`/`..` components.
+fn clean_relative(relative: &str) -> ManageResult<&str> {
+    let relative = relative.trim_matches('/');
+    if relative.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "The root itself cannot be changed".to_string()));
+    }
+    if Path::new(relative).components().any(|component| !matches!(component, Component::Normal(_))) {
+        return Err((StatusCode::BAD_REQUEST, format!("Invalid path: {}", relative)));
+    }
+    Ok(relative)
+}
+
+/// Resolves the parent of `relative` inside the root and joins the last component, without
+/// following it. The parent must exist (409 otherwise) and neither may be excluded.
+fn resolve_entry(managed: &ManagedRoot, relative: &str) -> ManageResult<PathBuf> {
+    let relative = clean_relative(relative)?;
+    let (parent, name) = relative.rsplit_once('/').unwrap_or(("", relative));
+    let parent_dir = match resolve_in_root(&managed.storage, &managed.root, parent) {
+        Ok(parent_dir) if parent_dir.is_dir() => parent_dir,
+        Ok(_) | Err((StatusCode::NOT_FOUND, _)) => {
+            return Err((StatusCode::CONFLICT, format!("Parent directory does not exist: {}", parent)));
+        }
+        Err(e) => return Err(e),
+    };
+    let path = parent_dir.join(name);
+    let is_dir = std::fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_dir());
+    if managed.exclude.is_excluded(&parent_dir, true) || managed.exclude.is_excluded(&path, is_dir) {
+        return Err((StatusCode::FORBIDDEN, format!("Access denied: {}", relative)));
+    }
+    Ok(path)
+}
+
+/// Runs a file operation off the async workers.
+async fn blocking<T: Send + 'static>(task: impl FnOnce() -> ManageResult<T> + Send + 'static) -> ManageResult<T> {
+    tokio::task::spawn_blocking(task).await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "File operation task failed".to_string()))?
+}
+
+fn failed(what: &str, relative: &str, e: io::Error) -> (StatusCode, String) {
+    if e.kind() == io::ErrorKind::AlreadyExists {
+        return (StatusCode::CONFLICT, format!("{} already exists", relative));
+    }
+    let status = match e.kind() {
+        io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        io::ErrorKind::DirectoryNotEmpty => StatusCode::CONFLICT,
+        io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, format!("Could not {} {}: {}", what, relative, e))
+}
+
+async fn remove_handler(
+    State(managed): State<Arc<ManagedRoot>>,
+    Query(params): Query<RemoveParams>,
+) -> ManageResult<StatusCode> {
+    blocking(move || {
+        let path = resolve_entry(&managed, &params.path)?;
+        let metadata = std::fs::symlink_metadata(&path)
+            .map_err(|_| (StatusCode::NOT_FOUND, format!("No such file or directory: {}", params.path)))?;
+        let result = match (metadata.is_dir(), params.recursive) {
+            (true, true) => std::fs::remove_dir_all(&path),
+            (true, false) => std::fs::remove_dir(&path),
+            (false, _) => std::fs::remove_file(&path),
+        };
+        result.map_err(|e| match e.kind() {
+            io::ErrorKind::DirectoryNotEmpty => {
+                (StatusCode::CONFLICT, format!("Directory is not empty: {} (delete it recursively)", params.path))
+            }
+            _ => failed("delete", &params.path, e),
+        })?;
+        info!("Deleted {}", path.display());
+        Ok(StatusCode::NO_CONTENT)
+    }).await
+}
+
+async fn move_handler(
+    State(managed): State<Arc<ManagedRoot>>,
+    Query(params): Query<MoveParams>,
+) -> ManageResult<StatusCode> {
+    blocking(move || {
+        let from = resolve_entry(&managed, &params.from)?;
+        let from_metadata = std::fs::symlink_metadata(&from)
+            .map_err(|_| (StatusCode::NOT_FOUND, format!("No such file or directory: {}", params.from)))?;
+        ensure_portable(Path::new(&params.to))?;
+        let to = resolve_entry(&managed, &params.to)?;
+        if from_metadata.is_dir() && to.starts_with(&from) {
+            return Err((StatusCode::BAD_REQUEST, format!("Cannot move {} into itself", params.from)));
+        }
+        // rename() replaces what is there without asking, so check first
+        if let Ok(existing) = std::fs::symlink_metadata(&to) {
+            if existing.is_dir() || !params.overwrite {
+                return Err((StatusCode::CONFLICT, format!("{} already exists", params.to)));
+            }
+            if from_metadata.is_dir() {
+                return Err((StatusCode::CONFLICT, format!("Cannot replace the file {} with a directory", params.to)));
+            }
+        }
+        std::fs::rename(&from, &to).map_err(|e| failed("move", &params.from, e))?;
+        info!("Moved {} to {}", from.display(), to.display());
+        Ok(StatusCode::NO_CONTENT)
+    }).await
+}
+
+async fn mkdir_handler(
+    State(managed): State<Arc<ManagedRoot>>,
+    Query(params): Query<MkdirParams>,
+) -> ManageResult<StatusCode> {
+    blocking(move || {
+        let relative = clean_relative(&params.path)?;
+        ensure_portable(Path::new(relative))?;
+        if !params.parents {
+            let path = resolve_entry(&managed, relative)?;
+            std::fs::create_dir(&path).map_err(|e| failed("create", relative, e))?;
+            info!("Created directory {}", path.display());
+            return Ok(StatusCode::CREATED);
+        }
+
+        // Every directory on the way is checked: the deepest one that exists must be in the
+        // root, and none of the new ones may be excluded
+        let mut existing = relative;
+        let mut missing = Vec::new();
+        let base = loop {
+            match resolve_in_root(&managed.storage, &managed.root, existing) {
+                Ok(base) => break base,
+                Err((StatusCode::NOT_FOUND, _)) if !existing.is_empty() => {
+                    let (parent, name) = existing.rsplit_once('/').unwrap_or(("", existing));
+                    missing.push(name);
+                    existing = parent;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        if !base.is_dir() {
+            return Err((StatusCode::CONFLICT, format!("Not a directory: {}", existing)));
+        }
+        let mut path = base;
+        for name in missing.into_iter().rev() {
+            path.push(name);
+            if managed.exclude.is_excluded(&path, true) {
+                return Err((StatusCode::FORBIDDEN, format!("Access denied: {}", relative)));
+            }
+        }
+        if managed.exclude.is_excluded(&path, true) {
+            return Err((StatusCode::FORBIDDEN, format!("Access denied: {}", relative)));
+        }
+        std::fs::create_dir_all(&path).map_err(|e| failed("create", relative, e))?;
+        info!("Created directory {}", path.display());
+        Ok(StatusCode::CREATED)
+    }).await
+}
@@ -0,0 +1,219 @@
+//! Counters and histograms exported on GET /metrics in the Prometheus text format.
+//!
+//! Everything here is cheap to update from request paths: counters are atomics, and the few
+//! values that need a lock (per-status request counts, histograms) hold it only for an add.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Upper bounds, in seconds, of the request duration histogram buckets.
+const REQUEST_DURATION_BUCKETS: &[f64] = &[0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 60.0, 300.0];
+/// Upper bounds, in seconds, of the archive build histogram buckets.
+const ARCHIVE_BUILD_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// The server's metrics, shared by the routes and the accept loop.
+pub(crate) struct Metrics {
+    /// Keyed by method and status code
+    requests: Mutex<BTreeMap<(&'static str, u16), u64>>,
+    bytes_sent: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    active_connections: AtomicI64,
+    /// Requests being served, by ID, for `/admin/transfers`
+    in_flight: Mutex<BTreeMap<u64, Arc<InFlight>>>,
+    next_request: AtomicU64,
+    request_duration: Histogram,
+    archive_build: Histogram,
+}
+
+/// A request whose response has not been sent completely yet.
+pub(crate) struct InFlight {
+    pub(crate) id: u64,
+    pub(crate) client: String,
+    pub(crate) method: String,
+    /// Path and query
+    pub(crate) path: String,
+    pub(crate) started: Instant,
+    /// Response body bytes sent so far
+    pub(crate) sent: AtomicU64,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        Metrics {
+            requests: Mutex::new(BTreeMap::new()),
+            bytes_sent: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            active_connections: AtomicI64::new(0),
+            in_flight: Mutex::new(BTreeMap::new()),
+            next_request: AtomicU64::new(1),
+            request_duration: Histogram::new(REQUEST_DURATION_BUCKETS),
+            archive_build: Histogram::new(ARCHIVE_BUILD_BUCKETS),
+        }
+    }
+
+    /// Counts a finished request and the response bytes it sent.
+    pub(crate) fn record_request(&self, method: &axum::http::Method, status: u16, bytes: u64, duration: Duration) {
+        *self.requests.lock().unwrap().entry((method_label(method), status)).or_default() += 1;
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+        self.request_duration.observe(duration);
+    }
+
+    /// Records how long building (and verifying) one archive took.
+    pub(crate) fn record_archive_build(&self, duration: Duration) {
+        self.archive_build.observe(duration);
+    }
+
+    /// Counts a --cache-dir lookup.
+    pub(crate) fn record_cache_lookup(&self, hit: bool) {
+        let counter = if hit { &self.cache_hits } else { &self.cache_misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a connection as active until the returned guard is dropped.
+    pub(crate) fn track_connection(self: &Arc<Self>) -> ConnectionGuard {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self.clone())
+    }
+
+    /// Lists a request as in flight until the returned guard is dropped.
+    pub(crate) fn track_request(self: &Arc<Self>, client: String, method: String, path: String) -> RequestGuard {
+        let id = self.next_request.fetch_add(1, Ordering::Relaxed);
+        let request = Arc::new(InFlight { id, client, method, path, started: Instant::now(), sent: AtomicU64::new(0) });
+        self.in_flight.lock().unwrap().insert(id, request.clone());
+        RequestGuard(self.clone(), request)
+    }
+
+    /// The requests in flight, oldest first.
+    pub(crate) fn requests_in_flight(&self) -> Vec<Arc<InFlight>> {
+        self.in_flight.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Everything in the Prometheus text exposition format.
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+
+        header(&mut out, "file_server_requests_total", "counter", "Requests served, by method and status code.");
+        for ((method, status), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(out, "file_server_requests_total{{method=\"{}\",status=\"{}\"}} {}", method, status, count);
+        }
+
+        header(&mut out, "file_server_response_bytes_total", "counter", "Response body bytes sent.");
+        let _ = writeln!(out, "file_server_response_bytes_total {}", self.bytes_sent.load(Ordering::Relaxed));
+
+        header(&mut out, "file_server_cache_hits_total", "counter", "Archives served from --cache-dir.");
+        let _ = writeln!(out, "file_server_cache_hits_total {}", self.cache_hits.load(Ordering::Relaxed));
+        header(&mut out, "file_server_cache_misses_total", "counter", "Archives that were not in --cache-dir and had to be built.");
+        let _ = writeln!(out, "file_server_cache_misses_total {}", self.cache_misses.load(Ordering::Relaxed));
+
+        header(&mut out, "file_server_active_connections", "gauge", "Client connections currently open.");
+        let _ = writeln!(out, "file_server_active_connections {}", self.active_connections.load(Ordering::Relaxed));
+        header(&mut out, "file_server_requests_in_flight", "gauge", "Requests whose response has not been sent completely yet.");
+        let _ = writeln!(out, "file_server_requests_in_flight {}", self.in_flight.lock().unwrap().len());
+
+        header(&mut out, "file_server_request_duration_seconds", "histogram", "Time from receiving a request until its response was sent.");
+        self.request_duration.render(&mut out, "file_server_request_duration_seconds");
+
+        header(&mut out, "file_server_archive_build_seconds", "histogram", "Time spent building and verifying buffered archives.");
+        self.archive_build.render(&mut out, "file_server_archive_build_seconds");
+
+        if let Some(cpu) = crate::process_cpu_time() {
+            header(&mut out, "process_cpu_seconds_total", "counter", "User and system CPU time the server has used.");
+            let _ = writeln!(out, "process_cpu_seconds_total {}", cpu.as_secs_f64());
+        }
+
+        out
+    }
+}
+
+/// Keeps a connection counted in `file_server_active_connections`.
+pub(crate) struct ConnectionGuard(Arc<Metrics>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Keeps a request listed in flight, and counts the bytes of its response.
+pub(crate) struct RequestGuard(Arc<Metrics>, Arc<InFlight>);
+
+impl RequestGuard {
+    pub(crate) fn sent(&self, bytes: u64) {
+        self.1.sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.0.in_flight.lock().unwrap().remove(&self.1.id);
+    }
+}
+
+struct Histogram {
+    bounds: &'static [f64],
+    state: Mutex<HistogramState>,
+}
+
+struct HistogramState {
+    /// Observations per bucket (not cumulative); the last entry is the +Inf bucket
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        let state = HistogramState { counts: vec![0; bounds.len() + 1], sum: 0.0, count: 0 };
+        Histogram { bounds, state: Mutex::new(state) }
+    }
+
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = self.bounds.iter().position(|&bound| seconds <= bound).unwrap_or(self.bounds.len());
+        let mut state = self.state.lock().unwrap();
+        state.counts[bucket] += 1;
+        state.sum += seconds;
+        state.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        let state = self.state.lock().unwrap();
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&state.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, state.count);
+        let _ = writeln!(out, "{}_sum {}", name, state.sum);
+        let _ = writeln!(out, "{}_count {}", name, state.count);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Standard methods by name; anything else shares one label so clients cannot add series.
+fn method_label(method: &axum::http::Method) -> &'static str {
+    use axum::http::Method;
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::PATCH => "PATCH",
+        Method::DELETE => "DELETE",
+        Method::OPTIONS => "OPTIONS",
+        _ => "OTHER",
+    }
+}
@@ -0,0 +1,90 @@
+//! What the client prints: lines of prose for people or, with `--json`, one JSON object per
+//! line for scripts. Every JSON line names its `event` (e.g. `download_complete`, `progress`,
+//! `error`) next to the fields that describe it.
+
+use serde_json::{Map, Value};
+use std::{
+    fmt,
+    io::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Switches everything printed from now on to JSON lines.
+pub fn use_json(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+static STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Prints everything that would go to stdout on stderr instead, for when stdout carries data
+/// (`download --output -`).
+pub fn use_stderr() {
+    STDERR.store(true, Ordering::Relaxed);
+}
+
+static PROGRESS_BARS: AtomicBool = AtomicBool::new(true);
+
+/// Turns transfer progress bars on or off; several transfers at once would draw over each other.
+pub fn show_progress_bars(show: bool) {
+    PROGRESS_BARS.store(show, Ordering::Relaxed);
+}
+
+pub fn progress_bars_shown() -> bool {
+    PROGRESS_BARS.load(Ordering::Relaxed)
+}
+
+/// Prints `text`, or in JSON mode `fields` (an object) as the `event` line.
+pub fn emit(event: &str, fields: Value, text: fmt::Arguments<'_>) {
+    if is_json() {
+        print_event(event, fields);
+    } else if STDERR.load(Ordering::Relaxed) {
+        eprintln!("{}", text);
+    } else {
+        println!("{}", text);
+    }
+}
+
+/// Like [`emit`], for warnings: the text goes to stderr, the JSON line has a `message`.
+pub fn warn(message: fmt::Arguments<'_>) {
+    if is_json() {
+        print_event("warning", serde_json::json!({ "message": message.to_string() }));
+    } else {
+        eprintln!("Warning: {}", message);
+    }
+}
+
+/// Prints an error, to stderr as `Error: ...` or in JSON mode as an `error` line on stdout
+/// like everything else (unless that is switched to stderr), so scripts only have one stream
+/// to read.
+pub fn error(message: &str) {
+    if is_json() {
+        print_event("error", serde_json::json!({ "message": message }));
+    } else {
+        eprintln!("Error: {}", message);
+    }
+}
+
+/// Prints one JSON line: `fields` with `"event": event` first. Anything but an object is put
+/// under `value`.
+pub fn print_event(event: &str, fields: Value) {
+    let mut line = Map::new();
+    line.insert("event".to_string(), Value::from(event));
+    match fields {
+        Value::Object(fields) => line.extend(fields),
+        Value::Null => {}
+        value => {
+            line.insert("value".to_string(), value);
+        }
+    }
+    // A closed pipe only means nobody is reading any more
+    let _ = match STDERR.load(Ordering::Relaxed) {
+        true => writeln!(std::io::stderr().lock(), "{}", Value::Object(line)),
+        false => writeln!(std::io::stdout().lock(), "{}", Value::Object(line)),
+    };
+}
@@ -0,0 +1,158 @@
+//! Path handling that differs between Unix and Windows.
+//!
+//! Archive entry names and the paths clients request always use `/`, whatever the platform.
+//! On Windows, paths beyond 260 characters only work in their `\\?\` ("verbatim") form, and
+//! some names cannot be created at all: the device names `CON`, `NUL`, `COM1` and so on
+//! (with any extension), names ending in a dot or space, and names containing `<>:"|?*`. An
+//! archive made on Unix can hold any of them, so extraction on Windows refuses them by name
+//! instead of writing to a device or an alternate data stream.
+
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+/// A relative path with `/` separators, as archive entry names, manifests and listings use.
+pub(crate) fn slash_path(path: &Path) -> String {
+    let parts: Vec<_> = path.components().map(|part| part.as_os_str().to_string_lossy()).collect();
+    parts.join("/")
+}
+
+/// `requested` with backslashes turned into `/`, for clients that separate paths the Windows
+/// way; `None` if it has none.
+pub(crate) fn forward_slashes(requested: &str) -> Option<String> {
+    requested.contains('\\').then(|| requested.replace('\\', "/"))
+}
+
+/// Device names Windows reserves in every directory, with or without an extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "COM¹", "COM²", "COM³",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9", "LPT¹", "LPT²", "LPT³",
+];
+
+/// Why `name` (a single path component) cannot be created on Windows, or `None` if it can.
+pub(crate) fn windows_name_problem(name: &str) -> Option<&'static str> {
+    if name.ends_with(['.', ' ']) && name != "." && name != ".." {
+        return Some("Windows drops trailing dots and spaces from names");
+    }
+    if name.chars().any(|c| c < ' ' || matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*' | '\\' | '/')) {
+        return Some("the name contains a character Windows does not allow");
+    }
+    // `nul.txt` and `NUL .tar.gz` are the device as much as `NUL` is
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    if RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem)) {
+        return Some("the name is reserved for a device on Windows");
+    }
+    None
+}
+
+/// The first component of a relative path that could not be created on this platform, and
+/// why. Only Windows has such names.
+pub(crate) fn unportable_component(path: &Path) -> Option<(String, &'static str)> {
+    if !cfg!(windows) {
+        return None;
+    }
+    path.components().find_map(|component| match component {
+        Component::Normal(part) => {
+            let part = part.to_string_lossy();
+            windows_name_problem(&part).map(|problem| (part.into_owned(), problem))
+        }
+        _ => None,
+    })
+}
+
+/// `path` made absolute and, on Windows, put in its `\\?\` form so that files deeper than
+/// 260 characters below it can be created. Elsewhere it is returned as it is.
+pub(crate) fn long_path(path: &Path) -> io::Result<PathBuf> {
+    if !cfg!(windows) {
+        return Ok(path.to_path_buf());
+    }
+    // `absolute` also turns `/` into `\` and resolves `..`, which verbatim paths no longer do
+    let absolute = std::path::absolute(path)?;
+    let Some(text) = absolute.to_str() else { return Ok(absolute) };
+    Ok(PathBuf::from(match text {
+        _ if text.starts_with(r"\\?\") || text.starts_with(r"\\.\") => text.to_string(),
+        _ if text.starts_with(r"\\") => format!(r"\\?\UNC\{}", &text[2..]),
+        _ => format!(r"\\?\{}", text),
+    }))
+}
+
+/// `path` without the `\\?\` prefix Windows' canonical paths carry, for showing to people.
+pub(crate) fn plain_path(path: &Path) -> PathBuf {
+    let Some(text) = path.to_str().filter(|_| cfg!(windows)) else { return path.to_path_buf() };
+    match (text.strip_prefix(r"\\?\UNC\"), text.strip_prefix(r"\\?\")) {
+        (Some(share), _) => PathBuf::from(format!(r"\\{}", share)),
+        // Only drive paths; other verbatim paths have no plain form
+        (None, Some(rest)) if rest.as_bytes().get(1) == Some(&b':') => PathBuf::from(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forward_slashes_only_rewrites_backslashes() {
+        assert_eq!(forward_slashes(r"docs\reports\q1.txt").as_deref(), Some("docs/reports/q1.txt"));
+        assert_eq!(forward_slashes(r"docs/mixed\q1.txt").as_deref(), Some("docs/mixed/q1.txt"));
+        assert_eq!(forward_slashes(r"..\outside").as_deref(), Some("../outside"));
+        assert_eq!(forward_slashes("docs/reports/q1.txt"), None);
+        assert_eq!(forward_slashes(""), None);
+    }
+
+    #[test]
+    fn slash_path_joins_with_slashes() {
+        let path: PathBuf = ["docs", "reports", "q1.txt"].iter().collect();
+        assert_eq!(slash_path(&path), "docs/reports/q1.txt");
+        assert_eq!(slash_path(Path::new("q1.txt")), "q1.txt");
+    }
+
+    #[test]
+    fn windows_names() {
+        for name in ["CON", "nul.txt", "Com1.tar.gz", "LPT9", "NUL .txt", "COM¹"] {
+            assert_eq!(windows_name_problem(name), Some("the name is reserved for a device on Windows"), "{}", name);
+        }
+        for name in ["report.", "trailing ", "aux "] {
+            assert_eq!(windows_name_problem(name), Some("Windows drops trailing dots and spaces from names"), "{}", name);
+        }
+        for name in ["a:b", "what?", "star*", "pipe|", "quote\"", "back\\slash", "tab\t"] {
+            assert_eq!(windows_name_problem(name), Some("the name contains a character Windows does not allow"), "{}", name);
+        }
+        for name in ["console.txt", "COM10", "LPT", "nul_device", ".", "..", ".hidden", "report.txt"] {
+            assert_eq!(windows_name_problem(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn unportable_component_on_this_platform() {
+        let path = Path::new("docs/aux.txt/readme");
+        match cfg!(windows) {
+            true => assert_eq!(unportable_component(path),
+                Some(("aux.txt".to_string(), "the name is reserved for a device on Windows"))),
+            false => assert_eq!(unportable_component(path), None),
+        }
+        assert_eq!(unportable_component(Path::new("docs/readme.txt")), None);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn long_and_plain_paths() {
+        assert_eq!(long_path(Path::new(r"C:\data\files")).unwrap(), PathBuf::from(r"\\?\C:\data\files"));
+        assert_eq!(long_path(Path::new("C:/data/../files")).unwrap(), PathBuf::from(r"\\?\C:\files"));
+        assert_eq!(long_path(Path::new(r"\\server\share\dir")).unwrap(), PathBuf::from(r"\\?\UNC\server\share\dir"));
+        assert_eq!(long_path(Path::new(r"\\?\C:\data")).unwrap(), PathBuf::from(r"\\?\C:\data"));
+
+        assert_eq!(plain_path(Path::new(r"\\?\C:\data")), PathBuf::from(r"C:\data"));
+        assert_eq!(plain_path(Path::new(r"\\?\UNC\server\share")), PathBuf::from(r"\\server\share"));
+        assert_eq!(plain_path(Path::new(r"\\?\Volume{x}\data")), PathBuf::from(r"\\?\Volume{x}\data"));
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn long_and_plain_paths_are_unchanged() {
+        assert_eq!(long_path(Path::new("data/files")).unwrap(), PathBuf::from("data/files"));
+        assert_eq!(plain_path(Path::new(r"\\?\C:\data")), PathBuf::from(r"\\?\C:\data"));
+    }
+}
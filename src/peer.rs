@@ -0,0 +1,370 @@
+//! Sharing an archive with other machines while it downloads (`download --share`), so a room
+//! full of clients fetching the same large archive need not all pull it from the server.
+//!
+//! The sharing client answers `/download` for the one archive it is fetching, with the same
+//! query, ETag and size as the server: the bytes already on disk at once, and the rest as they
+//! arrive. Other clients use it like any server, resuming and verifying as usual; `/checksum`
+//! and `/download.sig` are fetched from the server once and passed on. It announces itself on
+//! the local network, so they find it with `discover` and `--server <name>`.
+//!
+//! A download fetched over TLS or with credentials is only shared with clients that send the
+//! share's own token as `Authorization: Bearer`, so it is not handed to the whole network.
+
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use std::{
+    collections::HashMap,
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::client::{part_path, server_error, sidecar_path, ClientResult, Downloader, HttpClient};
+use crate::discovery::Announcement;
+use crate::server::{if_range_matches, parse_range_header, RangeRequest};
+
+/// How often a reader waiting for bytes that have not arrived yet looks again.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Most bytes read from the file per chunk of a response.
+const READ_CHUNK: u64 = 256 * 1024;
+/// Query parameters that do not change the archive's bytes, left out when comparing requests.
+const IGNORED_PARAMS: &[&str] = &["name", "job"];
+
+/// A download being shared, until it is dropped.
+pub struct PeerShare {
+    shared: Arc<Shared>,
+    port: u16,
+    announcement: Option<Announcement>,
+    server: tokio::task::JoinHandle<()>,
+}
+
+/// Bytes of randomness in a token picked for a share.
+const TOKEN_BYTES: usize = 18;
+
+struct Shared {
+    /// The download's query, sorted, without [`IGNORED_PARAMS`]
+    query: Vec<(String, String)>,
+    files: Vec<String>,
+    etag: String,
+    size: u64,
+    disposition: Option<HeaderValue>,
+    /// Where the archive is saved once complete
+    output: PathBuf,
+    complete: AtomicBool,
+    upstream: Downloader,
+    client: HttpClient,
+    /// Server responses passed on to peers, by endpoint
+    proxied: tokio::sync::Mutex<HashMap<&'static str, Proxied>>,
+    served_bytes: AtomicU64,
+    served_requests: AtomicU64,
+    /// Bearer token other clients must send, if any
+    token: Option<String>,
+}
+
+#[derive(Clone)]
+struct Proxied {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: bytes::Bytes,
+}
+
+/// What a share passed on to other clients.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ShareStats {
+    /// Download requests from other clients
+    pub requests: u64,
+    /// Archive bytes sent to them
+    pub bytes: u64,
+}
+
+impl PeerShare {
+    /// Starts sharing what `downloader` downloads to `output`, listening on `port` (any free
+    /// port for 0) and announced on the local network as `name` when given. Other clients need
+    /// `token` when given; without one, a download over TLS or with credentials gets a random
+    /// token (see [`PeerShare::token`]) and any other is shared openly. A HEAD request tells the
+    /// share the archive's size and ETag first; archives the server splits into parts cannot
+    /// be shared.
+    pub async fn start(downloader: &Downloader, output: &Path, port: u16, name: Option<&str>, token: Option<String>) -> ClientResult<PeerShare> {
+        if downloader.parts_index().await?.is_some() {
+            return Err("The server splits this archive into parts, which cannot be shared while they download".into());
+        }
+        let client = downloader.http_client()?;
+        let head = downloader.request(&client, reqwest::Method::HEAD, "download").send().await?;
+        if !head.status().is_success() {
+            return Err(server_error(head).await);
+        }
+        let header_value = |name: reqwest::header::HeaderName| head.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+        let size = header_value(reqwest::header::CONTENT_LENGTH).and_then(|length| length.parse::<u64>().ok());
+        let (Some(size), Some(etag)) = (size, header_value(reqwest::header::ETAG)) else {
+            return Err("The server does not report the archive's size and ETag up front, which sharing needs (it may be streaming archives)".into());
+        };
+        let disposition = header_value(reqwest::header::CONTENT_DISPOSITION).and_then(|value| HeaderValue::try_from(value).ok());
+
+        let token = match token {
+            Some(token) => Some(token),
+            None if downloader.connection().is_protected() => Some(random_token()?),
+            None => None,
+        };
+
+        let url = downloader.request(&client, reqwest::Method::GET, "download").build()?.url().clone();
+        let shared = Arc::new(Shared {
+            query: comparable_query(&url),
+            files: url.query_pairs().filter(|(key, _)| key == "files").map(|(_, value)| value.into_owned()).collect(),
+            etag,
+            size,
+            disposition,
+            output: output.to_path_buf(),
+            complete: AtomicBool::new(false),
+            upstream: downloader.clone(),
+            client,
+            proxied: Default::default(),
+            served_bytes: AtomicU64::new(0),
+            served_requests: AtomicU64::new(0),
+            token,
+        });
+
+        let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await
+            .map_err(|e| format!("Could not listen on port {} to share the download: {}", port, e))?;
+        let port = listener.local_addr()?.port();
+        let app = Router::new()
+            .route("/download", get(download_handler))
+            .route("/checksum", get(|state: State<Arc<Shared>>| proxy_handler(state, "checksum")))
+            .route("/download.sig", get(|state: State<Arc<Shared>>| proxy_handler(state, "download.sig")))
+            .layer(axum::middleware::from_fn_with_state(shared.clone(), require_token))
+            .with_state(shared.clone());
+        let server = tokio::spawn(async move {
+            let _ = axum::serve(listener, app).await;
+        });
+
+        let announcement = match name.map(|name| Announcement::start_peer(name, port)).transpose() {
+            Ok(announcement) => announcement,
+            Err(e) => {
+                crate::output::warn(format_args!("Could not announce the share on the local network: {}", e));
+                None
+            }
+        };
+        Ok(PeerShare { shared, port, announcement, server })
+    }
+
+    /// The port other clients connect to.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// The token other clients must send as `Authorization: Bearer`, if the share needs one.
+    pub fn token(&self) -> Option<&str> {
+        self.shared.token.as_deref()
+    }
+
+    /// The name the share is announced under, for other clients' `--server`.
+    pub fn announced_as(&self) -> Option<&str> {
+        self.announcement.as_ref().map(Announcement::name)
+    }
+
+    /// Switches to serving the finished archive from its final name, once the download has
+    /// been verified and renamed.
+    pub fn complete(&self) {
+        self.shared.complete.store(true, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> ShareStats {
+        ShareStats {
+            requests: self.shared.served_requests.load(Ordering::Relaxed),
+            bytes: self.shared.served_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for PeerShare {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// A random token for a share whose download is protected.
+fn random_token() -> ClientResult<String> {
+    use base64::Engine;
+    let mut bytes = [0u8; TOKEN_BYTES];
+    getrandom::getrandom(&mut bytes).map_err(io::Error::other)?;
+    Ok(base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes))
+}
+
+/// Refuses requests without the share's token, when it has one.
+async fn require_token(State(shared): State<Arc<Shared>>, request: axum::extract::Request, next: axum::middleware::Next) -> Response {
+    let Some(token) = &shared.token else {
+        return next.run(request).await;
+    };
+    let sent = request.headers().get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match sent {
+        Some(sent) if crate::server::constant_time_eq(sent.trim().as_bytes(), token.as_bytes()) => next.run(request).await,
+        _ => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            "This share needs its token: run the download with --token <share token>",
+        ).into_response(),
+    }
+}
+
+/// A request's query as pairs that compare equal for requests of the same archive.
+fn comparable_query(url: &reqwest::Url) -> Vec<(String, String)> {
+    let mut query: Vec<(String, String)> = url.query_pairs()
+        .filter(|(key, _)| !IGNORED_PARAMS.contains(&key.as_ref()))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    query.sort();
+    query
+}
+
+impl Shared {
+    /// Refuses requests for any other archive than the one being shared.
+    fn check_query(&self, uri: &Uri) -> Result<(), (StatusCode, String)> {
+        let url = reqwest::Url::parse(&format!("http://peer{}", uri)).map_err(|_| (StatusCode::BAD_REQUEST, "Malformed request".to_string()))?;
+        if comparable_query(&url) == self.query {
+            return Ok(());
+        }
+        let files = if self.files.is_empty() { "the server's default files".to_string() } else { self.files.join(", ") };
+        Err((StatusCode::NOT_FOUND, format!(
+            "This client only shares the archive of {} it is downloading; request it with the same files and options, or ask the server",
+            files,
+        )))
+    }
+
+    /// Reads up to `max` bytes at `offset` of the archive, waiting for them to arrive.
+    async fn read_at(&self, offset: u64, max: u64) -> io::Result<bytes::Bytes> {
+        let partial = part_path(&self.output);
+        loop {
+            let complete = self.complete.load(Ordering::Relaxed);
+            if !complete {
+                // A segmented download writes out of order into a file of the full size
+                if sidecar_path(&partial, "segments").exists() {
+                    return Err(io::Error::other("the download is segmented, so its bytes cannot be shared as they arrive"));
+                }
+                if let Ok(etag) = tokio::fs::read_to_string(sidecar_path(&partial, "etag")).await {
+                    if etag.trim() != self.etag {
+                        return Err(io::Error::other("the archive changed on the server"));
+                    }
+                }
+            }
+            let path = if complete { &self.output } else { &partial };
+            // Opened for every chunk, so no open handle gets in the way of renaming the file
+            match tokio::fs::File::open(path).await {
+                Ok(mut file) => {
+                    let length = file.metadata().await?.len();
+                    if complete && length != self.size {
+                        return Err(io::Error::other("the downloaded archive was changed or removed"));
+                    }
+                    if offset < length {
+                        let mut buffer = vec![0; max.min(length - offset).min(READ_CHUNK) as usize];
+                        file.seek(SeekFrom::Start(offset)).await?;
+                        file.read_exact(&mut buffer).await?;
+                        return Ok(bytes::Bytes::from(buffer));
+                    }
+                }
+                // Between the download's rename and complete() being called
+                Err(e) if e.kind() == io::ErrorKind::NotFound && !complete => {}
+                Err(e) => return Err(e),
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// `/download`: the shared archive, or a range of it, streamed as it arrives.
+async fn download_handler(State(shared): State<Arc<Shared>>, headers: HeaderMap, uri: Uri) -> Result<Response, (StatusCode, String)> {
+    shared.check_query(&uri)?;
+    let size = shared.size;
+    let range = match headers.get(header::RANGE) {
+        Some(value) if if_range_matches(&headers, &shared.etag) => parse_range_header(value, size)?,
+        _ => RangeRequest::Full,
+    };
+    let (status, start, end) = match range {
+        RangeRequest::Partial(ranges) if ranges.len() == 1 => (StatusCode::PARTIAL_CONTENT, ranges[0].start, ranges[0].start + ranges[0].length),
+        RangeRequest::Unsatisfiable => {
+            let mut response = StatusCode::RANGE_NOT_SATISFIABLE.into_response();
+            response.headers_mut().insert(header::CONTENT_RANGE, HeaderValue::try_from(format!("bytes */{}", size)).unwrap());
+            return Ok(response);
+        }
+        // Peers only ever ask for one range; several get the whole archive
+        _ => (StatusCode::OK, 0, size),
+    };
+    shared.served_requests.fetch_add(1, Ordering::Relaxed);
+
+    let reader = shared.clone();
+    let stream = futures_util::stream::try_unfold(start, move |offset| {
+        let shared = reader.clone();
+        async move {
+            if offset >= end {
+                return Ok(None);
+            }
+            let chunk = shared.read_at(offset, end - offset).await?;
+            shared.served_bytes.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            Ok::<_, io::Error>(Some((chunk.clone(), offset + chunk.len() as u64)))
+        }
+    });
+
+    let mut response = Response::new(Body::from_stream(stream));
+    *response.status_mut() = status;
+    let response_headers = response.headers_mut();
+    response_headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+    response_headers.insert(header::CONTENT_LENGTH, HeaderValue::from(end - start));
+    response_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if let Ok(etag) = HeaderValue::try_from(shared.etag.as_str()) {
+        response_headers.insert(header::ETAG, etag);
+    }
+    if let Some(disposition) = &shared.disposition {
+        response_headers.insert(header::CONTENT_DISPOSITION, disposition.clone());
+    }
+    if status == StatusCode::PARTIAL_CONTENT {
+        response_headers.insert(header::CONTENT_RANGE, HeaderValue::try_from(format!("bytes {}-{}/{}", start, end - 1, size)).unwrap());
+    }
+    Ok(response)
+}
+
+/// `/checksum` and `/download.sig`: the server's answer for the shared archive, fetched once.
+async fn proxy_handler(State(shared): State<Arc<Shared>>, endpoint: &'static str) -> Response {
+    let mut proxied = shared.proxied.lock().await;
+    let answer = match proxied.get(endpoint) {
+        Some(answer) => answer.clone(),
+        None => match fetch_upstream(&shared, endpoint).await {
+            Ok(answer) => {
+                // Errors other than "not published" may be passing, so only those are asked again
+                if answer.status.is_success() || answer.status == StatusCode::NOT_FOUND {
+                    proxied.insert(endpoint, answer.clone());
+                }
+                answer
+            }
+            Err(message) => return (StatusCode::BAD_GATEWAY, message).into_response(),
+        },
+    };
+    let mut response = Response::new(Body::from(answer.body));
+    *response.status_mut() = answer.status;
+    *response.headers_mut() = answer.headers;
+    response
+}
+
+async fn fetch_upstream(shared: &Shared, endpoint: &str) -> Result<Proxied, String> {
+    let response = shared.upstream.request(&shared.client, reqwest::Method::GET, endpoint).send().await
+        .map_err(|e| format!("Could not reach the server: {}", e))?;
+    let status = response.status();
+    let mut headers = HeaderMap::new();
+    for name in [header::CONTENT_TYPE, header::ETAG] {
+        if let Some(value) = response.headers().get(&name).cloned() {
+            headers.insert(name, value);
+        }
+    }
+    let body = response.bytes().await.map_err(|e| format!("Could not reach the server: {}", e))?;
+    Ok(Proxied { status, headers, body })
+}
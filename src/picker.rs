@@ -0,0 +1,311 @@
+//! The terminal file picker of `download --interactive`: a tree of the server's listing to
+//! choose files and directories from, instead of typing their paths.
+//!
+//! Directories are listed when they are first opened. Typing filters the entries listed so far
+//! by a fuzzy match on their paths; space selects, Enter downloads what is selected (or the
+//! highlighted entry when nothing is), and Esc cancels.
+
+use console::{style, Key, Term};
+use std::collections::BTreeSet;
+
+use crate::client::ClientResult;
+use crate::format_size;
+use crate::server::ListEntry;
+
+/// Lines kept for the header and footer around the entries.
+const CHROME_LINES: usize = 3;
+const MIN_VISIBLE_ROWS: usize = 5;
+
+struct Node {
+    entry: ListEntry,
+    depth: usize,
+    parent: Option<usize>,
+    /// Listed children, once the directory has been opened
+    children: Option<Vec<usize>>,
+    expanded: bool,
+}
+
+struct Picker<F> {
+    list: F,
+    nodes: Vec<Node>,
+    roots: Vec<usize>,
+    selected: BTreeSet<String>,
+    filter: String,
+    cursor: usize,
+    scroll: usize,
+    /// A listing that failed, shown until the next key
+    status: Option<String>,
+}
+
+/// Lets the user choose entries of the server's listing on `term`, with `list` fetching a
+/// directory's entries (the root's for `None`). Returns the chosen paths, leaving out those
+/// inside a chosen directory, or `None` when the user cancelled.
+pub fn pick<F>(term: &Term, list: F) -> ClientResult<Option<Vec<String>>>
+where
+    F: FnMut(Option<&str>) -> ClientResult<Vec<ListEntry>>,
+{
+    let mut picker = Picker { list, nodes: Vec::new(), roots: Vec::new(), selected: BTreeSet::new(), filter: String::new(), cursor: 0, scroll: 0, status: None };
+    picker.roots = picker.load(None)?;
+    if picker.roots.is_empty() {
+        return Err("The server has no files to choose from".into());
+    }
+
+    term.hide_cursor()?;
+    let result = picker.run(term);
+    let _ = term.show_cursor();
+    result
+}
+
+impl<F> Picker<F>
+where
+    F: FnMut(Option<&str>) -> ClientResult<Vec<ListEntry>>,
+{
+    /// Lists a directory and adds its entries, directories first.
+    fn load(&mut self, parent: Option<usize>) -> ClientResult<Vec<usize>> {
+        let mut entries = (self.list)(parent.map(|parent| self.nodes[parent].entry.path.as_str()))?;
+        entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+        let depth = parent.map_or(0, |parent| self.nodes[parent].depth + 1);
+        Ok(entries.into_iter()
+            .map(|entry| {
+                self.nodes.push(Node { entry, depth, parent, children: None, expanded: false });
+                self.nodes.len() - 1
+            })
+            .collect())
+    }
+
+    fn run(&mut self, term: &Term) -> ClientResult<Option<Vec<String>>> {
+        let mut drawn = 0;
+        loop {
+            let rows = self.rows();
+            self.cursor = self.cursor.min(rows.len().saturating_sub(1));
+            drawn = self.draw(term, &rows, drawn)?;
+
+            let key = term.read_key()?;
+            self.status = None;
+            let current = rows.get(self.cursor).copied();
+            let page = self.visible_rows(term);
+            match key {
+                Key::ArrowUp => self.cursor = self.cursor.saturating_sub(1),
+                Key::ArrowDown => self.cursor += 1,
+                Key::PageUp => self.cursor = self.cursor.saturating_sub(page),
+                Key::PageDown => self.cursor += page,
+                Key::Home => self.cursor = 0,
+                Key::End => self.cursor = rows.len().saturating_sub(1),
+                Key::ArrowRight => {
+                    if let Some(node) = current {
+                        self.expand(node);
+                    }
+                }
+                Key::Tab => {
+                    if let Some(node) = current {
+                        match self.nodes[node].expanded {
+                            true => self.nodes[node].expanded = false,
+                            false => self.expand(node),
+                        }
+                    }
+                }
+                Key::ArrowLeft => {
+                    if let Some(node) = current {
+                        self.collapse(node, &rows);
+                    }
+                }
+                Key::Char(' ') => {
+                    if let Some(node) = current {
+                        let path = &self.nodes[node].entry.path;
+                        if !self.selected.remove(path) {
+                            self.selected.insert(path.clone());
+                        }
+                    }
+                }
+                Key::Enter => {
+                    let chosen = match (self.selected.is_empty(), current) {
+                        (true, Some(node)) => vec![self.nodes[node].entry.path.clone()],
+                        (true, None) => continue,
+                        (false, _) => self.chosen(),
+                    };
+                    term.clear_last_lines(drawn)?;
+                    return Ok(Some(chosen));
+                }
+                Key::Backspace => {
+                    self.filter.pop();
+                    self.cursor = 0;
+                }
+                Key::Escape if !self.filter.is_empty() => {
+                    self.filter.clear();
+                    self.cursor = 0;
+                }
+                Key::Escape | Key::CtrlC => {
+                    term.clear_last_lines(drawn)?;
+                    return Ok(None);
+                }
+                Key::Char(c) if !c.is_control() => {
+                    self.filter.push(c);
+                    self.cursor = 0;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Opens a directory, listing it first if needed.
+    fn expand(&mut self, node: usize) {
+        if !self.nodes[node].entry.is_dir {
+            return;
+        }
+        if self.nodes[node].children.is_none() {
+            match self.load(Some(node)) {
+                Ok(children) => self.nodes[node].children = Some(children),
+                Err(e) => {
+                    self.status = Some(format!("Could not list {}: {}", self.nodes[node].entry.path, e));
+                    return;
+                }
+            }
+        }
+        self.nodes[node].expanded = true;
+    }
+
+    /// Closes an open directory, or otherwise moves to the entry's parent.
+    fn collapse(&mut self, node: usize, rows: &[usize]) {
+        if self.nodes[node].expanded {
+            self.nodes[node].expanded = false;
+        } else if let Some(parent) = self.nodes[node].parent {
+            if let Some(row) = rows.iter().position(|&row| row == parent) {
+                self.cursor = row;
+            }
+        }
+    }
+
+    /// The entries on screen: the open part of the tree, or without structure those matching
+    /// the filter, best match first.
+    fn rows(&self) -> Vec<usize> {
+        if self.filter.is_empty() {
+            let mut rows = Vec::new();
+            let mut pending: Vec<usize> = self.roots.iter().rev().copied().collect();
+            while let Some(node) = pending.pop() {
+                rows.push(node);
+                if let (true, Some(children)) = (self.nodes[node].expanded, &self.nodes[node].children) {
+                    pending.extend(children.iter().rev());
+                }
+            }
+            return rows;
+        }
+        let mut matches: Vec<(usize, usize)> = (0..self.nodes.len())
+            .filter_map(|node| fuzzy_score(&self.filter, &self.nodes[node].entry.path).map(|score| (score, node)))
+            .collect();
+        matches.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| self.nodes[a.1].entry.path.cmp(&self.nodes[b.1].entry.path)));
+        matches.into_iter().map(|(_, node)| node).collect()
+    }
+
+    fn visible_rows(&self, term: &Term) -> usize {
+        let (height, _) = term.size();
+        (height as usize).saturating_sub(CHROME_LINES).max(MIN_VISIBLE_ROWS)
+    }
+
+    /// Whether the entry is inside a selected directory.
+    fn inside_selected(&self, node: usize) -> bool {
+        let mut parent = self.nodes[node].parent;
+        while let Some(node) = parent {
+            if self.selected.contains(&self.nodes[node].entry.path) {
+                return true;
+            }
+            parent = self.nodes[node].parent;
+        }
+        false
+    }
+
+    /// The selected paths without those a selected directory already includes.
+    fn chosen(&self) -> Vec<String> {
+        self.selected.iter()
+            .filter(|path| !self.selected.iter().any(|dir| path.strip_prefix(dir.as_str()).is_some_and(|rest| rest.starts_with('/'))))
+            .cloned()
+            .collect()
+    }
+
+    /// Redraws the picker over the `drawn` lines drawn last time, returning how many it drew.
+    fn draw(&mut self, term: &Term, rows: &[usize], drawn: usize) -> ClientResult<usize> {
+        let (_, width) = term.size();
+        let width = width as usize;
+        let visible = self.visible_rows(term);
+        if self.cursor < self.scroll {
+            self.scroll = self.cursor;
+        } else if self.cursor >= self.scroll + visible {
+            self.scroll = self.cursor + 1 - visible;
+        }
+
+        let mut lines = Vec::with_capacity(visible + CHROME_LINES);
+        lines.push(match self.filter.is_empty() {
+            true => style(truncate("Type to filter · ↑↓ move · →← open/close · space select · Enter download · Esc cancel", width)).dim().to_string(),
+            false => format!("Filter: {}", style(truncate(&self.filter, width.saturating_sub(8))).bold()),
+        });
+        for (row, &node) in rows.iter().enumerate().skip(self.scroll).take(visible) {
+            let line = truncate(&self.row_text(node), width);
+            lines.push(if row == self.cursor { style(line).reverse().to_string() } else { line });
+        }
+        if rows.is_empty() {
+            lines.push(style("No listed entry matches; open more directories to search them").dim().to_string());
+        }
+        lines.push(match &self.status {
+            Some(status) => style(truncate(status, width)).red().to_string(),
+            None => style(format!("{} selected", self.selected.len())).dim().to_string(),
+        });
+
+        term.clear_last_lines(drawn)?;
+        for line in &lines {
+            term.write_line(line)?;
+        }
+        term.flush()?;
+        Ok(lines.len())
+    }
+
+    fn row_text(&self, node: usize) -> String {
+        let entry = &self.nodes[node].entry;
+        let mark = if self.selected.contains(&entry.path) {
+            "[x]"
+        } else if self.inside_selected(node) {
+            "[+]"
+        } else {
+            "[ ]"
+        };
+        let name = if self.filter.is_empty() { &entry.name } else { &entry.path };
+        match (entry.is_dir, self.nodes[node].expanded) {
+            _ if !self.filter.is_empty() && entry.is_dir => format!("{} {}/", mark, name),
+            (true, true) => format!("{} {}▾ {}/", mark, "  ".repeat(self.nodes[node].depth), name),
+            (true, false) => format!("{} {}▸ {}/", mark, "  ".repeat(self.nodes[node].depth), name),
+            (false, _) if !self.filter.is_empty() => format!("{} {}  {}", mark, name, format_size(entry.size)),
+            (false, _) => format!("{} {}  {}  {}", mark, "  ".repeat(self.nodes[node].depth), name, format_size(entry.size)),
+        }
+    }
+}
+
+/// How well `filter` matches `path` as a case-insensitive subsequence, or `None` when it does
+/// not. Runs of consecutive characters and matches at the start of a name score higher.
+fn fuzzy_score(filter: &str, path: &str) -> Option<usize> {
+    let mut score = 0;
+    let mut previous: Option<usize> = None;
+    let path: Vec<char> = path.chars().flat_map(char::to_lowercase).collect();
+    let mut position = 0;
+    for wanted in filter.chars().flat_map(char::to_lowercase) {
+        let found = position + path[position..].iter().position(|&c| c == wanted)?;
+        score += 1;
+        if previous.is_some_and(|previous| previous + 1 == found) {
+            score += 2;
+        }
+        if found == 0 || matches!(path[found - 1], '/' | '.' | '_' | '-' | ' ') {
+            score += 3;
+        }
+        previous = Some(found);
+        position = found + 1;
+    }
+    // Among equal matches, shorter paths are closer to what was typed
+    Some(score * 1000 - path.len().min(999))
+}
+
+/// Cuts `text` to `width` characters, marking the cut with `…`.
+fn truncate(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text.to_string();
+    }
+    let mut cut: String = text.chars().take(width.saturating_sub(1)).collect();
+    cut.push('…');
+    cut
+}
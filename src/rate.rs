@@ -0,0 +1,40 @@
+//! Pacing transfers to a fixed number of bytes per second.
+
+use std::{sync::Mutex, time::Duration};
+use tokio::time::Instant;
+
+/// Paces the bytes passed through it to a fixed rate. Idle time earns no credit, so a
+/// limiter shared between transfers caps their combined rate.
+pub(crate) struct RateLimiter {
+    bytes_per_second: u64,
+    next_free: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(bytes_per_second: u64) -> Self {
+        RateLimiter { bytes_per_second: bytes_per_second.max(1), next_free: Mutex::new(Instant::now()) }
+    }
+
+    pub(crate) fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second
+    }
+
+    /// Books the time `bytes` take at this rate and returns when they may be sent.
+    pub(crate) fn reserve(&self, bytes: usize) -> Instant {
+        let mut next_free = self.next_free.lock().unwrap();
+        let start = (*next_free).max(Instant::now());
+        *next_free = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64);
+        start
+    }
+
+    /// Waits until `bytes` more may be transferred.
+    pub(crate) async fn pace(&self, bytes: usize) {
+        tokio::time::sleep_until(self.reserve(bytes)).await;
+    }
+}
+
+impl std::fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RateLimiter").field("bytes_per_second", &self.bytes_per_second).finish()
+    }
+}
@@ -0,0 +1,467 @@
+//! Serving files from an S3-compatible bucket (`--root s3://bucket/prefix`).
+//!
+//! Objects are read with plain REST calls signed with AWS Signature Version 4. Keys below the
+//! prefix appear as a `/`-rooted tree: `s3://media/site` serves `media/site/a/b.txt` as
+//! `/a/b.txt`, and every key prefix ending in `/` is a directory. Credentials, region and
+//! endpoint come from the usual AWS environment variables:
+//!
+//! - `AWS_ACCESS_KEY_ID` / `AWS_SECRET_ACCESS_KEY` (and `AWS_SESSION_TOKEN`); requests are
+//!   sent unsigned without them, which public buckets allow
+//! - `AWS_REGION` or `AWS_DEFAULT_REGION`, `us-east-1` by default
+//! - `AWS_ENDPOINT_URL_S3` or `AWS_ENDPOINT_URL` for MinIO and other S3-compatible stores,
+//!   which are addressed path-style (`http://localhost:9000/bucket/key`)
+
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fmt,
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::archive::EntryKind;
+use crate::storage::{StorageBackend, StorageMetadata};
+
+/// Scheme prefix of a `--root` that names a bucket.
+pub(crate) const S3_URL_PREFIX: &str = "s3://";
+
+/// How long metadata seen in a listing is trusted before objects are asked again.
+const LISTING_TTL: Duration = Duration::from_secs(10);
+/// SHA-256 of an empty body, the payload hash of every request sent.
+const EMPTY_PAYLOAD_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+/// A bucket (or a prefix inside one) read through the S3 REST API.
+pub(crate) struct S3Storage {
+    /// The root as given, for messages
+    url: String,
+    /// Key prefix the served tree starts at: empty, or ending in `/`
+    prefix: String,
+    /// Scheme, host and (for path-style addressing) `/bucket`, without a trailing slash
+    base_url: String,
+    /// Path part of `base_url`, which is part of every signed URI
+    base_path: String,
+    region: String,
+    credentials: Option<Credentials>,
+    client: reqwest::Client,
+    /// Requests are async; the trait is blocking, so they are driven on the server's runtime
+    runtime: tokio::runtime::Handle,
+    /// Metadata of recently listed objects and directories, keyed by served path
+    listed: Mutex<HashMap<PathBuf, (Instant, StorageMetadata)>>,
+}
+
+struct Credentials {
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+impl fmt::Debug for S3Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3Storage")
+            .field("url", &self.url)
+            .field("endpoint", &self.base_url)
+            .field("region", &self.region)
+            .field("signed", &self.credentials.is_some())
+            .finish()
+    }
+}
+
+impl S3Storage {
+    /// Parses `s3://bucket[/prefix]` and reads the AWS settings from the environment. Must be
+    /// called from within a tokio runtime, which later requests are driven on.
+    pub(crate) fn from_url(url: &str) -> io::Result<Self> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        let location = url.strip_prefix(S3_URL_PREFIX)
+            .ok_or_else(|| invalid(format!("Not an s3:// URL: {}", url)))?;
+        let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+        if bucket.is_empty() {
+            return Err(invalid(format!("No bucket in {}", url)));
+        }
+        let prefix = match prefix.trim_matches('/') {
+            "" => String::new(),
+            prefix => format!("{}/", prefix),
+        };
+
+        let env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let region = env("AWS_REGION").or_else(|| env("AWS_DEFAULT_REGION")).unwrap_or_else(|| "us-east-1".to_string());
+        let base_url = match env("AWS_ENDPOINT_URL_S3").or_else(|| env("AWS_ENDPOINT_URL")) {
+            Some(endpoint) => format!("{}/{}", endpoint.trim_end_matches('/'), urlencoding::encode(bucket)),
+            None => format!("https://{}.s3.{}.amazonaws.com", bucket, region),
+        };
+        let parsed = reqwest::Url::parse(&base_url).map_err(|e| invalid(format!("Invalid S3 endpoint {}: {}", base_url, e)))?;
+        let base_path = parsed.path().trim_end_matches('/').to_string();
+
+        let credentials = match (env("AWS_ACCESS_KEY_ID"), env("AWS_SECRET_ACCESS_KEY")) {
+            (Some(access_key), Some(secret_key)) => Some(Credentials { access_key, secret_key, session_token: env("AWS_SESSION_TOKEN") }),
+            _ => None,
+        };
+
+        let runtime = tokio::runtime::Handle::try_current()
+            .map_err(|_| io::Error::other("S3 storage needs a tokio runtime"))?;
+
+        Ok(S3Storage {
+            url: url.to_string(),
+            prefix,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            base_path,
+            region,
+            credentials,
+            client: reqwest::Client::new(),
+            runtime,
+            listed: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The object key a served path refers to (without the leading `/`).
+    fn key(&self, path: &Path) -> String {
+        let relative: Vec<_> = normalize(path).components()
+            .filter_map(|part| match part {
+                Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+                _ => None,
+            })
+            .collect();
+        format!("{}{}", self.prefix, relative.join("/"))
+    }
+
+    /// Sends a signed request for `key` (empty for the bucket itself) with the given query.
+    fn send(&self, method: reqwest::Method, key: &str, query: &[(&str, &str)], range: Option<String>) -> io::Result<reqwest::Response> {
+        let uri = format!("{}/{}", self.base_path,
+            key.split('/').map(|segment| urlencoding::encode(segment).into_owned()).collect::<Vec<_>>().join("/"));
+        let mut query: Vec<(String, String)> = query.iter()
+            .map(|(name, value)| (urlencoding::encode(name).into_owned(), urlencoding::encode(value).into_owned()))
+            .collect();
+        query.sort();
+        let query = query.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>().join("&");
+
+        let url = match query.is_empty() {
+            true => format!("{}{}", self.base_url, &uri[self.base_path.len()..]),
+            false => format!("{}{}?{}", self.base_url, &uri[self.base_path.len()..], query),
+        };
+        let url = reqwest::Url::parse(&url).map_err(|e| io::Error::other(format!("Invalid S3 URL {}: {}", url, e)))?;
+
+        let mut request = self.client.request(method.clone(), url.clone());
+        if let Some(range) = range {
+            request = request.header(reqwest::header::RANGE, range);
+        }
+        if let Some(credentials) = &self.credentials {
+            for (name, value) in sign(credentials, &self.region, method.as_str(), &url, &uri, &query, time::OffsetDateTime::now_utc()) {
+                request = request.header(name, value);
+            }
+        }
+
+        self.runtime.block_on(request.send())
+            .map_err(|e| io::Error::other(format!("S3 request to {} failed: {}", self.url, e)))
+    }
+
+    /// Turns an unsuccessful response into an error describing `key`.
+    fn check(&self, response: reqwest::Response, key: &str) -> io::Result<reqwest::Response> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let kind = match status {
+            reqwest::StatusCode::NOT_FOUND => io::ErrorKind::NotFound,
+            reqwest::StatusCode::FORBIDDEN | reqwest::StatusCode::UNAUTHORIZED => io::ErrorKind::PermissionDenied,
+            _ => io::ErrorKind::Other,
+        };
+        let detail = self.runtime.block_on(response.text()).unwrap_or_default();
+        let code = detail.split("<Code>").nth(1).and_then(|rest| rest.split("</Code>").next()).unwrap_or("");
+        Err(io::Error::new(kind, format!("S3 returned {} for {}{}", status, key,
+            if code.is_empty() { String::new() } else { format!(" ({})", code) })))
+    }
+
+    /// One page of a ListObjectsV2 call.
+    fn list(&self, prefix: &str, delimiter: bool, max_keys: Option<&str>, token: Option<&str>) -> io::Result<ListBucketResult> {
+        let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+        if delimiter {
+            query.push(("delimiter", "/"));
+        }
+        if let Some(max_keys) = max_keys {
+            query.push(("max-keys", max_keys));
+        }
+        if let Some(token) = token {
+            query.push(("continuation-token", token));
+        }
+
+        let response = self.check(self.send(reqwest::Method::GET, "", &query, None)?, prefix)?;
+        let body = self.runtime.block_on(response.text())
+            .map_err(|e| io::Error::other(format!("Could not read S3 listing: {}", e)))?;
+        quick_xml::de::from_str(&body)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid S3 listing: {}", e)))
+    }
+
+    fn remember(&self, path: PathBuf, metadata: StorageMetadata) {
+        let mut listed = self.listed.lock().unwrap();
+        listed.retain(|_, (seen, _)| seen.elapsed() < LISTING_TTL);
+        listed.insert(path, (Instant::now(), metadata));
+    }
+}
+
+impl StorageBackend for S3Storage {
+    fn metadata(&self, path: &Path) -> io::Result<StorageMetadata> {
+        let path = normalize(path);
+        if path == Path::new("/") {
+            return Ok(directory_metadata());
+        }
+        if let Some((seen, metadata)) = self.listed.lock().unwrap().get(&path) {
+            if seen.elapsed() < LISTING_TTL {
+                return Ok(metadata.clone());
+            }
+        }
+
+        let key = self.key(&path);
+        let response = self.send(reqwest::Method::HEAD, &key, &[], None)?;
+        let metadata = match response.status() {
+            reqwest::StatusCode::NOT_FOUND => {
+                // No such object; it is a directory if anything has it as a prefix
+                let listing = self.list(&format!("{}/", key), false, Some("1"), None)?;
+                if listing.contents.is_empty() && listing.common_prefixes.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::NotFound, format!("No such object: {}", key)));
+                }
+                directory_metadata()
+            }
+            _ => {
+                let response = self.check(response, &key)?;
+                let header = |name| response.headers().get(name).and_then(|value| value.to_str().ok());
+                let mut metadata = file_metadata(
+                    header(reqwest::header::CONTENT_LENGTH).and_then(|len| len.parse().ok()).unwrap_or(0),
+                    header(reqwest::header::LAST_MODIFIED).and_then(|date| httpdate::parse_http_date(date).ok()),
+                );
+                metadata.version = header(reqwest::header::ETAG).map(str::to_string);
+                metadata
+            }
+        };
+
+        self.remember(path, metadata.clone());
+        Ok(metadata)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<StorageMetadata> {
+        // Object stores have no links
+        self.metadata(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        let path = normalize(path);
+        let prefix = match self.key(&path) {
+            key if key.is_empty() || key.ends_with('/') => key,
+            key => format!("{}/", key),
+        };
+
+        let mut names = Vec::new();
+        let mut token = None;
+        loop {
+            let page = self.list(&prefix, true, None, token.as_deref())?;
+            for object in page.contents {
+                let Some(name) = object.key.strip_prefix(&prefix).filter(|name| !name.is_empty()) else {
+                    // The marker object some tools create for an empty directory
+                    continue;
+                };
+                let mut metadata = file_metadata(object.size, parse_listing_date(&object.last_modified));
+                metadata.version = object.etag;
+                self.remember(path.join(name), metadata);
+                names.push(OsString::from(name));
+            }
+            for common in page.common_prefixes {
+                let Some(name) = common.prefix.strip_prefix(&prefix).map(|name| name.trim_end_matches('/')) else { continue };
+                if !name.is_empty() {
+                    self.remember(path.join(name), directory_metadata());
+                    names.push(OsString::from(name));
+                }
+            }
+
+            match (page.is_truncated, page.next_continuation_token) {
+                (true, Some(next)) => token = Some(next),
+                _ => break,
+            }
+        }
+
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, format!("Object storage has no symlinks: {}", path.display())))
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        let path = normalize(path);
+        self.metadata(&path)?;
+        Ok(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        let key = self.key(path);
+        let response = self.check(self.send(reqwest::Method::GET, &key, &[], None)?, &key)?;
+        Ok(Box::new(ObjectReader { runtime: self.runtime.clone(), response, chunk: bytes::Bytes::new() }))
+    }
+
+    fn read_range(&self, path: &Path, start: u64, len: u64) -> io::Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let key = self.key(path);
+        let range = format!("bytes={}-{}", start, start + len - 1);
+        let response = self.send(reqwest::Method::GET, &key, &[], Some(range))?;
+        if response.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+            return Ok(Vec::new());
+        }
+        let response = self.check(response, &key)?;
+        let mut data = self.runtime.block_on(response.bytes())
+            .map_err(|e| io::Error::other(format!("Could not read {}: {}", key, e)))?
+            .to_vec();
+        data.truncate(len as usize);
+        Ok(data)
+    }
+}
+
+/// The body of a GET, read chunk by chunk as archiving asks for it.
+struct ObjectReader {
+    runtime: tokio::runtime::Handle,
+    response: reqwest::Response,
+    chunk: bytes::Bytes,
+}
+
+impl Read for ObjectReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.runtime.block_on(self.response.chunk()).map_err(io::Error::other)? {
+                Some(chunk) => self.chunk = chunk,
+                None => return Ok(0),
+            }
+        }
+        let count = buf.len().min(self.chunk.len());
+        buf[..count].copy_from_slice(&self.chunk[..count]);
+        self.chunk = self.chunk.slice(count..);
+        Ok(count)
+    }
+}
+
+/// A ListObjectsV2 response, as far as it is used.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct ListBucketResult {
+    #[serde(default)]
+    contents: Vec<ListedObject>,
+    #[serde(default)]
+    common_prefixes: Vec<ListedPrefix>,
+    #[serde(default)]
+    is_truncated: bool,
+    next_continuation_token: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct ListedObject {
+    key: String,
+    #[serde(default)]
+    size: u64,
+    #[serde(default)]
+    last_modified: String,
+    #[serde(rename = "ETag")]
+    etag: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct ListedPrefix {
+    prefix: String,
+}
+
+fn file_metadata(len: u64, modified: Option<SystemTime>) -> StorageMetadata {
+    StorageMetadata {
+        kind: Some(EntryKind::File),
+        len,
+        modified: modified.unwrap_or(std::time::UNIX_EPOCH),
+        mode: 0o644,
+        uid: 0,
+        gid: 0,
+        version: None,
+    }
+}
+
+/// Key prefixes have no metadata of their own, so directories all look alike.
+fn directory_metadata() -> StorageMetadata {
+    StorageMetadata { kind: Some(EntryKind::Dir), mode: 0o755, ..file_metadata(0, None) }
+}
+
+/// A listing's `LastModified`, e.g. `2024-05-01T12:00:00.000Z`.
+fn parse_listing_date(date: &str) -> Option<SystemTime> {
+    time::OffsetDateTime::parse(date, &time::format_description::well_known::Rfc3339).ok().map(SystemTime::from)
+}
+
+/// Resolves `.` and `..` without touching the store, never climbing above `/`.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::from("/");
+    for part in path.components() {
+        match part {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    normalized
+}
+
+/// The headers that sign a request with AWS Signature Version 4. `uri` and `query` must be
+/// the already encoded path and sorted query string the request is sent with.
+fn sign(
+    credentials: &Credentials,
+    region: &str,
+    method: &str,
+    url: &reqwest::Url,
+    uri: &str,
+    query: &str,
+    now: time::OffsetDateTime,
+) -> Vec<(&'static str, String)> {
+    use hmac::{Hmac, Mac};
+    use sha2::{Digest, Sha256};
+
+    let hmac = |key: &[u8], data: &str| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+        mac.update(data.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    };
+
+    let date = format!("{:04}{:02}{:02}", now.year(), now.month() as u8, now.day());
+    let timestamp = format!("{}T{:02}{:02}{:02}Z", date, now.hour(), now.minute(), now.second());
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    };
+
+    let mut headers = vec![
+        ("host", host),
+        ("x-amz-content-sha256", EMPTY_PAYLOAD_SHA256.to_string()),
+        ("x-amz-date", timestamp.clone()),
+    ];
+    if let Some(token) = &credentials.session_token {
+        headers.push(("x-amz-security-token", token.clone()));
+    }
+    let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value.trim())).collect();
+    let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+
+    let canonical_request = format!("{}\n{}\n{}\n{}\n{}\n{}",
+        method, uri, query, canonical_headers, signed_headers, EMPTY_PAYLOAD_SHA256);
+    let scope = format!("{}/{}/s3/aws4_request", date, region);
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        timestamp, scope, crate::archive::hex_digest(&Sha256::digest(canonical_request.as_bytes())));
+
+    let key = hmac(format!("AWS4{}", credentials.secret_key).as_bytes(), &date);
+    let key = hmac(&key, region);
+    let key = hmac(&key, "s3");
+    let key = hmac(&key, "aws4_request");
+    let signature = crate::archive::hex_digest(&hmac(&key, &string_to_sign));
+
+    // reqwest sets Host itself, from the same URL
+    headers.retain(|(name, _)| *name != "host");
+    headers.push(("authorization", format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key, scope, signed_headers, signature)));
+    headers
+}
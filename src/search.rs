@@ -0,0 +1,280 @@
+//! `GET /search`: finding files in the root by name pattern, size and modification time, so
+//! clients can build archive requests from a query instead of exact paths.
+//!
+//! Every search walks the root the way `/list` sees it, with exclusions applied and symlinks
+//! leading outside the root left out. With `--search-index` the walk is kept as an index
+//! instead: searches use it while it is younger than the refresh interval, and an older one
+//! still answers while a fresh walk runs in the background.
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, warn};
+
+use crate::archive::ExcludeRules;
+use crate::server::{list_entry, resolve_in_root, ListEntry};
+use crate::storage::Storage;
+
+/// How many matches a search returns unless it asks for fewer.
+const DEFAULT_SEARCH_LIMIT: usize = 1000;
+/// The most matches a search can ask for.
+const MAX_SEARCH_LIMIT: usize = 100_000;
+
+/// Query parameters for `/search`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+struct SearchParams {
+    /// Glob pattern: matched against file names, or against the path below the root when it
+    /// contains a `/`. Every file matches without one.
+    q: Option<String>,
+    /// Directory (relative to the root) to search; the whole root when omitted
+    path: Option<String>,
+    /// Smallest and largest file size, e.g. `1M`
+    min_size: Option<String>,
+    max_size: Option<String>,
+    /// Modified after or before a date (`2024-01-01`, `2024-01-01T12:00:00`, UTC), a Unix time
+    /// (`@1704067200`) or a time ago (`7d`)
+    newer_than: Option<String>,
+    older_than: Option<String>,
+    /// Most matches to return
+    limit: Option<usize>,
+}
+
+/// Body of a `/search` response.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SearchResults {
+    /// Matching files, sorted by path
+    pub files: Vec<ListEntry>,
+    /// More files matched than the limit allowed
+    pub truncated: bool,
+}
+
+/// What a search looks through.
+pub(crate) struct SearchRoot {
+    pub(crate) storage: Storage,
+    /// The canonical root, as in the server state
+    pub(crate) root: PathBuf,
+    pub(crate) exclude: ExcludeRules,
+    /// With `--search-index`
+    pub(crate) index: Option<SearchIndex>,
+}
+
+/// Every file in the root from the latest walk, refreshed once it is older than `refresh`.
+pub(crate) struct SearchIndex {
+    refresh: Duration,
+    files: RwLock<Option<(Instant, Arc<Vec<ListEntry>>)>>,
+    /// A walk is running in the background
+    refreshing: AtomicBool,
+}
+
+impl SearchIndex {
+    pub(crate) fn new(refresh: Duration) -> Self {
+        SearchIndex { refresh, files: RwLock::new(None), refreshing: AtomicBool::new(false) }
+    }
+}
+
+impl SearchRoot {
+    /// Every file below the root, from the index when there is one.
+    fn files(self: &Arc<Self>) -> Arc<Vec<ListEntry>> {
+        let Some(index) = &self.index else {
+            return Arc::new(self.walk());
+        };
+        let current = index.files.read().unwrap_or_else(|e| e.into_inner()).clone();
+        match current {
+            Some((built, files)) => {
+                if built.elapsed() >= index.refresh && !index.refreshing.swap(true, Ordering::AcqRel) {
+                    let search_root = self.clone();
+                    tokio::task::spawn_blocking(move || search_root.rebuild_index());
+                }
+                files
+            }
+            // The first search waits for the walk; later ones get the index right away
+            None => self.rebuild_index(),
+        }
+    }
+
+    fn rebuild_index(&self) -> Arc<Vec<ListEntry>> {
+        let started = Instant::now();
+        let files = Arc::new(self.walk());
+        debug!("Indexed {} file(s) for searching in {:.1?}", files.len(), started.elapsed());
+        if let Some(index) = &self.index {
+            *index.files.write().unwrap_or_else(|e| e.into_inner()) = Some((Instant::now(), files.clone()));
+            index.refreshing.store(false, Ordering::Release);
+        }
+        files
+    }
+
+    /// Walks the whole root, sorted by path. Directories reached twice through symlinks are
+    /// walked once.
+    fn walk(&self) -> Vec<ListEntry> {
+        let mut files = Vec::new();
+        let mut seen = HashSet::new();
+        let mut pending = vec![PathBuf::new()];
+        while let Some(relative_dir) = pending.pop() {
+            let full_dir = self.root.join(&relative_dir);
+            if !seen.insert(self.storage.canonicalize(&full_dir).unwrap_or_else(|_| full_dir.clone())) {
+                continue;
+            }
+            let names = match self.storage.read_dir(&full_dir) {
+                Ok(names) => names,
+                Err(e) => {
+                    warn!("Could not search {}: {}", full_dir.display(), e);
+                    continue;
+                }
+            };
+            for name in names {
+                let path = full_dir.join(&name);
+                match self.storage.canonicalize(&path) {
+                    Ok(resolved) if resolved.starts_with(&self.root) => {}
+                    _ => continue,
+                }
+                let Ok(metadata) = self.storage.metadata(&path) else { continue };
+                if self.exclude.is_excluded(&path, metadata.is_dir()) {
+                    continue;
+                }
+                let relative = relative_dir.join(&name);
+                if metadata.is_dir() {
+                    pending.push(relative);
+                } else if let Ok(entry) = list_entry(&self.storage, &path, &relative) {
+                    files.push(entry);
+                }
+            }
+        }
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        files
+    }
+}
+
+/// The filters of one search, checked and parsed.
+struct Filter {
+    pattern: Option<glob::Pattern>,
+    /// Match the pattern against the whole relative path, not just the name
+    whole_path: bool,
+    /// Only paths below this directory, with a trailing `/`
+    below: Option<String>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    newer_than: Option<u64>,
+    older_than: Option<u64>,
+}
+
+impl Filter {
+    fn new(search_root: &SearchRoot, params: &SearchParams) -> Result<Self, (StatusCode, String)> {
+        let bad_request = |message: String| (StatusCode::BAD_REQUEST, message);
+        let pattern = params.q.as_deref().filter(|q| !q.is_empty())
+            .map(|q| glob::Pattern::new(q.trim_start_matches('/')).map_err(|e| bad_request(format!("Invalid pattern {}: {}", q, e.msg))))
+            .transpose()?;
+        let below = match params.path.as_deref().map(|path| path.trim_matches('/')).filter(|path| !path.is_empty()) {
+            Some(path) => {
+                let resolved = resolve_in_root(&search_root.storage, &search_root.root, path)?;
+                let relative = resolved.strip_prefix(&search_root.root).unwrap_or(Path::new("")).to_string_lossy().replace('\\', "/");
+                Some(format!("{}/", relative)).filter(|below| below != "/")
+            }
+            None => None,
+        };
+        let size = |value: &Option<String>| value.as_deref().map(crate::parse_size).transpose().map_err(bad_request);
+        let time = |value: &Option<String>| value.as_deref().map(parse_time).transpose().map_err(bad_request);
+        Ok(Filter {
+            whole_path: params.q.as_deref().is_some_and(|q| q.trim_start_matches('/').contains('/')),
+            pattern,
+            below,
+            min_size: size(&params.min_size)?,
+            max_size: size(&params.max_size)?,
+            newer_than: time(&params.newer_than)?,
+            older_than: time(&params.older_than)?,
+        })
+    }
+
+    fn matches(&self, file: &ListEntry) -> bool {
+        let options = glob::MatchOptions { require_literal_separator: true, ..Default::default() };
+        let matches_pattern = match &self.pattern {
+            Some(pattern) if self.whole_path => pattern.matches_with(&file.path, options),
+            Some(pattern) => pattern.matches_with(&file.name, options),
+            None => true,
+        };
+        let mtime = file.mtime.unwrap_or(0);
+        matches_pattern
+            && self.below.as_deref().is_none_or(|below| file.path.starts_with(below))
+            && self.min_size.is_none_or(|min| file.size >= min)
+            && self.max_size.is_none_or(|max| file.size <= max)
+            && self.newer_than.is_none_or(|time| mtime > time)
+            && self.older_than.is_none_or(|time| mtime < time)
+    }
+}
+
+/// Parses a point in time as Unix seconds: `2024-01-01` or `2024-01-01T12:00[:00]` (UTC, also
+/// with a space or a trailing `Z`), `@<seconds>`, or a duration ago such as `7d`.
+fn parse_time(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    if let Some(seconds) = value.strip_prefix('@') {
+        return seconds.parse().map_err(|_| format!("Invalid Unix time: {}", value));
+    }
+    if value.len() >= 10 && value.as_bytes()[4] == b'-' {
+        return parse_date(value).ok_or_else(|| format!("Invalid date: {} (use YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS)", value));
+    }
+    let ago = crate::parse_duration(value).map_err(|_| format!("Invalid time: {} (use a date, @<unix time> or a duration such as 7d)", value))?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    Ok(now.saturating_sub(ago).as_secs())
+}
+
+fn parse_date(value: &str) -> Option<u64> {
+    let value = value.trim_end_matches('Z');
+    let (date, time) = value.split_once(['T', ' ']).unwrap_or((value, "00:00"));
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let mut time = time.splitn(3, ':').map(str::parse::<u64>);
+    let (hour, minute) = (time.next()?.ok()?, time.next()?.ok()?);
+    let second = time.next().transpose().ok()?.unwrap_or(0);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    // Days-from-civil (Howard Hinnant's algorithm), the inverse of `format_unix_time`
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = u64::try_from(era * 146_097 + day_of_era - 719_468).ok()?;
+    Some(days * 86_400 + hour * 3_600 + minute * 60 + second)
+}
+
+/// The `/search` route, which the caller puts behind authentication.
+pub(crate) fn routes<S>(search_root: Arc<SearchRoot>) -> Router<S> {
+    Router::new()
+        .route("/search", get(search_handler))
+        .with_state(search_root)
+}
+
+async fn search_handler(
+    State(search_root): State<Arc<SearchRoot>>,
+    Query(params): Query<SearchParams>,
+) -> Result<Json<SearchResults>, (StatusCode, String)> {
+    let limit = params.limit.unwrap_or(DEFAULT_SEARCH_LIMIT).clamp(1, MAX_SEARCH_LIMIT);
+    // Walking and resolving may need the storage backend, so they happen off the async workers
+    let results = tokio::task::spawn_blocking(move || {
+        let filter = Filter::new(&search_root, &params)?;
+        let files = search_root.files();
+        let mut matches = files.iter().filter(|file| filter.matches(file));
+        let found: Vec<ListEntry> = matches.by_ref().take(limit).cloned().collect();
+        let truncated = matches.next().is_some();
+        Ok::<_, (StatusCode, String)>(SearchResults { files: found, truncated })
+    }).await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Search task failed".to_string()))??;
+
+    debug!("Responding with {} search match(es){}", results.files.len(), if results.truncated { " (truncated)" } else { "" });
+    Ok(Json(results))
+}
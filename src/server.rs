@@ -0,0 +1,5053 @@
+//! The archive server: HTTP routes, authentication, uploads and the accept loop.
+//!
+//! [`FileServer::bind`] takes a [`ServerConfig`] and a listen address (or several, with
+//! [`FileServer::bind_all`]); [`FileServer::run`]
+//! serves until SIGINT/SIGTERM, or [`FileServer::run_until`] until any future resolves.
+//! [`FileServer::reloader`] swaps in a new configuration while it runs.
+//! Diagnostics are emitted as `tracing` events, with one event per request under the `access`
+//! target; install a subscriber to see them.
+
+use axum::{
+    Router,
+    body::Body,
+    extract::{DefaultBodyLimit, Multipart, State},
+    http::{
+        HeaderMap, HeaderValue, Method, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    routing::{any, get, post},
+    Json,
+};
+use axum_extra::extract::Query;
+use futures_util::{FutureExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+    io::{self, Write},
+    time::Duration,
+};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, error, info, warn};
+
+use crate::admin::{refuse_while_draining, AdminState, ReloadRequest, ServerControl};
+use crate::audit::{AuditLog, AuditedDownload, DownloadOutcome};
+use crate::cache::{ArchiveCache, CacheWriter};
+pub use crate::search::SearchResults;
+use crate::chunks::{ChunkIndex, ChunkRequest};
+use crate::dav::{DavShare, DAV_PREFIX};
+use crate::discovery::Announcement;
+use crate::encoding::negotiate_encoding;
+use crate::health::Readiness;
+use crate::jobs::{BuildQueue, BuildSlot, Job, JobState, Jobs, JOB_RETRY_AFTER};
+use crate::limits::{ClientLimits, ConnectionSlot, Refusal};
+use crate::manage::ManagedRoot;
+use crate::timeouts::{body_error_status, limit_body_stalls, Activity, ActiveBody, TimeoutStream, Timeouts};
+use crate::crypt::{sign_digest, ArchiveEncryption, SealingKey, SigningKey, ENCRYPTED_EXTENSION};
+use crate::metrics::Metrics;
+use crate::rate::RateLimiter;
+use crate::search::{SearchIndex, SearchRoot};
+use crate::s3::{S3Storage, S3_URL_PREFIX};
+use crate::storage::{Storage, StorageMetadata};
+use crate::watch::RootWatcher;
+use crate::archive::{
+    archive_etag, archive_last_modified, collect_entries, create_archive, create_archive_with_progress, dry_run_archive,
+    changed_entries, hash_file, manifest_entries, measure_archive, verify_archive, write_tar, ArchiveChecksum, ArchiveEntry, EntryKind, EntryOptions,
+    ExcludeRules, SymlinkPolicy, IGNORE_FILE_NAME, ArchiveFormat, ArchiveOptions, Codec, DryRunReport,
+    Manifest, ManifestEntry,
+    archive_file_name,
+};
+
+// --- Port Configuration ---
+/// Port the server listens on when none is given.
+pub const DEFAULT_SERVER_PORT: u16 = 8080; 
+// --------------------------
+
+// --- Upload Configuration ---
+/// Largest upload request accepted by default (1 GiB).
+pub const DEFAULT_MAX_UPLOAD_SIZE: u64 = 1 << 30;
+/// Extra room for multipart boundaries and headers on top of --max-upload-size
+const UPLOAD_OVERHEAD_ALLOWANCE: u64 = 1024 * 1024;
+// --------------------------
+
+// --- Timeout Configuration ---
+/// Default for [`ServerConfig::read_timeout`].
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(60);
+/// Default for [`ServerConfig::write_timeout`].
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Default for [`ServerConfig::idle_timeout`].
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+// --------------------------
+
+// --- Connection Configuration ---
+/// Default for [`ServerConfig::backlog`].
+pub const DEFAULT_BACKLOG: u32 = 1024;
+/// Default for [`ServerConfig::max_header_size`] (64 KiB).
+pub const DEFAULT_MAX_HEADER_SIZE: usize = 64 << 10;
+/// Default for [`ServerConfig::max_headers`].
+pub const DEFAULT_MAX_HEADERS: usize = 100;
+/// The smallest [`ServerConfig::max_header_size`] HTTP/1 connections can work with.
+pub const MIN_MAX_HEADER_SIZE: usize = 8 << 10;
+// --------------------------
+
+// --- Cache Configuration ---
+/// Default limit for --cache-dir (1 GiB).
+pub const DEFAULT_CACHE_MAX_SIZE: u64 = 1 << 30;
+// --------------------------
+
+// --- SERVER SETUP ---
+
+/// How a [`FileServer`] behaves; mirrors the `server` command-line options.
+#[derive(Debug)]
+pub struct ServerConfig {
+    /// Directory requested file paths are resolved against, or an `s3://bucket/prefix` URL to
+    /// serve objects from (credentials, region and endpoint come from the `AWS_*` variables)
+    pub root: PathBuf,
+    /// Files (relative to `root`) archived when a request names none
+    pub default_files: Vec<String>,
+    /// Named file sets, each a list of paths relative to `root` (globs allowed), that
+    /// requests can ask for with `?preset=<name>` instead of listing the files
+    pub presets: std::collections::BTreeMap<String, Vec<String>>,
+    /// Build the default archive and every preset's into the cache at startup and again after
+    /// every change below `root`, so no request waits for them (requires `cache_dir`; implies `watch`)
+    pub pregenerate: bool,
+    /// Skip the integrity check of each generated archive before it is sent
+    pub skip_verify: bool,
+    /// Stream full downloads while they are compressed instead of buffering them
+    pub stream: bool,
+    /// Answer /download with a report of what would be archived
+    pub dry_run: bool,
+    /// Shell command run before a new archive is planned, with the requested paths as
+    /// arguments (e.g. to snapshot a database into the root); a failure answers 500
+    pub pre_archive_cmd: Option<String>,
+    /// Shell command run on every freshly built archive, with the path of a temporary copy
+    /// as its argument (e.g. a virus scan); a failure answers 500 and nothing is sent or
+    /// cached. Needs the whole archive, so it rules out `stream`
+    pub post_archive_cmd: Option<String>,
+    /// Default tarball compression, overridable per request
+    pub compression: Codec,
+    /// Default compression level, overridable per request
+    pub level: Option<i32>,
+    /// How symlinks inside archived directories (and in `default_files`) are archived
+    pub symlinks: SymlinkPolicy,
+    /// Keep permissions, timestamps and owners in archives; false normalizes them
+    pub preserve_metadata: bool,
+    /// Build byte-identical archives from identical files: entries sorted by name and
+    /// metadata normalized, whatever `preserve_metadata` says
+    pub reproducible: bool,
+    /// Latest modification time stored in archives; see [`ArchiveOptions::mtime_clamp`]
+    pub mtime_clamp: Option<u64>,
+    /// Record extended attributes (POSIX ACLs and SELinux labels among them) of files in a
+    /// local root in tarballs
+    pub xattrs: bool,
+    /// Threads compressing each tarball; see [`ArchiveOptions::threads`]
+    pub threads: usize,
+    /// Gitignore-style patterns that are never archived, on top of `<root>/.serverignore`
+    pub exclude: Vec<String>,
+    /// Largest total size of the files in one archive, before compression; requests for more
+    /// are answered with 413 Payload Too Large before anything is built
+    pub max_archive_size: Option<u64>,
+    /// Most files one archive may contain
+    pub max_files: Option<usize>,
+    /// Serve archives as numbered parts of at most this many bytes (`?part=1`, `?part=2`, ...),
+    /// described by `/download.index`
+    pub split_size: Option<u64>,
+    /// Also serve archives as content-defined chunks: `/download.chunks` lists them and
+    /// `POST /download.chunks` sends only the ones a client does not have
+    pub chunked: bool,
+    /// Directory where generated archives are kept and reused; caching is off without it
+    pub cache_dir: Option<PathBuf>,
+    /// Total size the cache may grow to before the least recently used archives are deleted
+    pub cache_max_size: u64,
+    /// Watch `root` for changes: requests reuse the file list and ETag worked out for them
+    /// until something changes, and the default archive is rebuilt into the cache after every
+    /// change (requires `cache_dir`)
+    pub watch: bool,
+    /// Encrypt every archive with this passphrase (see [`crate::crypt`])
+    pub encrypt_passphrase: Option<String>,
+    /// Ed25519 private key (PKCS#8 PEM) to sign archives with, served at /download.sig
+    pub signing_key: Option<PathBuf>,
+    /// Bytes per second shared by all responses together
+    pub max_rate: Option<u64>,
+    /// Bytes per second for the responses on any one connection
+    pub max_rate_per_connection: Option<u64>,
+    /// Open connections allowed in total; requests on any beyond it get 503 Service Unavailable
+    pub max_connections: Option<usize>,
+    /// Open connections allowed from any one IP address
+    pub max_connections_per_ip: Option<usize>,
+    /// Requests allowed from any one IP address per minute, in bursts of up to that many
+    pub max_requests_per_minute: Option<u32>,
+    /// Background builds (`/download?async=1`) allowed to run at once; more get 503
+    pub max_jobs: Option<usize>,
+    /// Archive builds of any kind allowed to run at once; the others wait their turn in line
+    pub max_archive_jobs: Option<usize>,
+    /// Builds allowed to wait in line under `max_archive_jobs`; more get 503
+    pub archive_queue: Option<usize>,
+    /// Bearer token for the `/admin` API, which is off without one
+    pub admin_token: Option<String>,
+    /// Where uploads are stored; uploads are disabled without it
+    pub upload_dir: Option<PathBuf>,
+    /// Maximum size of one upload request
+    pub max_upload_size: u64,
+    /// Credentials required on every route; off when empty
+    pub auth: AuthConfig,
+    /// Cross-origin requests browsers may make; none without it
+    pub cors: Option<CorsPolicy>,
+    /// Serve every file in the root at its own path, and directories as listings, as well as the
+    /// other routes
+    pub static_files: bool,
+    /// Share the root over WebDAV under `/dav`
+    pub webdav: bool,
+    /// Let WebDAV clients create files and directories in the root (local roots only)
+    pub webdav_writable: bool,
+    /// Accept deleting, moving and creating directories in the root (`/files`); needs a local
+    /// root and authentication
+    pub manage_files: bool,
+    /// Keep an index of the root for `/search`, refreshed in the background once it is this old;
+    /// without one every search walks the root
+    pub search_index: Option<Duration>,
+    /// Append a JSON line for every download to this file: who pulled which files, how many
+    /// bytes, and whether it completed
+    pub audit_log: Option<PathBuf>,
+    /// Compress text responses (files, listings, the web UI) with zstd or gzip for clients that
+    /// accept it; archives are never compressed twice
+    pub compress_responses: bool,
+    /// Also serve the gRPC control plane (`fileserver.v1.FileServer`), whose calls the HTTP
+    /// routes answer
+    pub grpc: bool,
+    /// `/readyz` fails when the cache directory, the upload directory or a writable root has
+    /// less space left than this
+    pub min_free_space: Option<u64>,
+    /// PEM certificate chain and private key to serve HTTPS with
+    pub tls: Option<(PathBuf, PathBuf)>,
+    /// PEM CA certificate(s) client certificates must be issued by; connections without one
+    /// are refused during the TLS handshake (needs `tls`)
+    pub client_ca: Option<PathBuf>,
+    /// How long in-flight requests may run after shutdown is requested
+    pub shutdown_timeout: Duration,
+    /// How long a client may take to send a request's headers, and then each piece of its
+    /// body while a handler waits for it
+    pub read_timeout: Option<Duration>,
+    /// How long a client may leave a response unread before the connection is closed
+    pub write_timeout: Option<Duration>,
+    /// How long a connection may sit unused with no request in flight
+    pub idle_timeout: Option<Duration>,
+    /// Keep HTTP/1 connections open between requests; without it each is closed after one response
+    pub keep_alive: bool,
+    /// Largest request head (request line and headers together) a client may send, in bytes;
+    /// at least [`MIN_MAX_HEADER_SIZE`]
+    pub max_header_size: usize,
+    /// Most headers a request may carry (HTTP/1)
+    pub max_headers: usize,
+    /// How many connections the kernel queues on each TCP listener before they are accepted
+    pub backlog: u32,
+    /// Announce the server on the local network under this name (see [`crate::discovery`])
+    pub announce: Option<String>,
+    /// Also listen on a Unix domain socket at this path (Unix only), e.g. behind a reverse proxy
+    pub unix_socket: Option<PathBuf>,
+    /// Permission bits of the Unix socket file
+    pub unix_socket_mode: u32,
+    /// Also accept connections on the sockets systemd passed in (see [`crate::systemd`])
+    pub socket_activation: bool,
+    /// Also serve HTTP/3 over QUIC on the same ports (needs `tls` and the `http3` cargo feature)
+    pub http3: bool,
+}
+
+impl ServerConfig {
+    /// Serves files under `root` with the same defaults as the command line.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        ServerConfig {
+            root: root.into(),
+            default_files: Vec::new(),
+            skip_verify: false,
+            stream: false,
+            dry_run: false,
+            pre_archive_cmd: None,
+            post_archive_cmd: None,
+            compression: Codec::default(),
+            level: None,
+            symlinks: SymlinkPolicy::default(),
+            preserve_metadata: true,
+            reproducible: false,
+            mtime_clamp: None,
+            xattrs: false,
+            threads: 1,
+            exclude: Vec::new(),
+            max_archive_size: None,
+            max_files: None,
+            split_size: None,
+            chunked: false,
+            cache_dir: None,
+            cache_max_size: DEFAULT_CACHE_MAX_SIZE,
+            watch: false,
+            presets: Default::default(),
+            pregenerate: false,
+            encrypt_passphrase: None,
+            signing_key: None,
+            max_rate: None,
+            max_rate_per_connection: None,
+            max_connections: None,
+            max_connections_per_ip: None,
+            max_requests_per_minute: None,
+            max_jobs: None,
+            max_archive_jobs: None,
+            archive_queue: None,
+            admin_token: None,
+            upload_dir: None,
+            max_upload_size: DEFAULT_MAX_UPLOAD_SIZE,
+            auth: AuthConfig::default(),
+            cors: None,
+            static_files: false,
+            webdav: false,
+            webdav_writable: false,
+            manage_files: false,
+            search_index: None,
+            audit_log: None,
+            compress_responses: true,
+            grpc: false,
+            min_free_space: None,
+            tls: None,
+            client_ca: None,
+            shutdown_timeout: Duration::from_secs(30),
+            read_timeout: Some(DEFAULT_READ_TIMEOUT),
+            write_timeout: Some(DEFAULT_WRITE_TIMEOUT),
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            keep_alive: true,
+            max_header_size: DEFAULT_MAX_HEADER_SIZE,
+            max_headers: DEFAULT_MAX_HEADERS,
+            backlog: DEFAULT_BACKLOG,
+            announce: None,
+            unix_socket: None,
+            unix_socket_mode: DEFAULT_UNIX_SOCKET_MODE,
+            socket_activation: false,
+            http3: false,
+        }
+    }
+}
+
+/// A bound, ready-to-run archive server.
+///
+/// ```no_run
+/// use rust_file_archive_server::server::{FileServer, ServerConfig};
+///
+/// # async fn serve() -> std::io::Result<()> {
+/// let server = FileServer::bind("0.0.0.0:8080".parse().unwrap(), ServerConfig::new("/srv/files")).await?;
+/// server.run().await;
+/// # Ok(())
+/// # }
+/// ```
+pub struct FileServer {
+    listeners: Vec<Listener>,
+    /// Socket file to remove when the server stops
+    unix_socket: Option<PathBuf>,
+    /// QUIC endpoints serving HTTP/3, one per TCP address
+    #[cfg(feature = "http3")]
+    quic_endpoints: Vec<quinn::Endpoint>,
+    app: Router,
+    tls: Option<tokio_rustls::TlsAcceptor>,
+    rate_limits: RateLimits,
+    timeouts: Timeouts,
+    http: HttpSettings,
+    root: PathBuf,
+    shutdown_timeout: Duration,
+    cache: Option<std::sync::Arc<ArchiveCache>>,
+    metrics: std::sync::Arc<Metrics>,
+    /// The routes and limits of the latest configuration, replaced by [`Reloader::reload`]
+    live: std::sync::Arc<LiveService>,
+    /// Withdrawn when the server stops
+    announcement: Option<Announcement>,
+}
+
+impl FileServer {
+    /// Validates `config` (root, default files, TLS material), creates the upload directory
+    /// and starts listening on `addr`. Errors describe which part of the config is wrong.
+    pub async fn bind(addr: SocketAddr, config: ServerConfig) -> io::Result<FileServer> {
+        Self::bind_all(&[addr], config).await
+    }
+
+    /// Like [`bind`](Self::bind), but listens on every address in `addrs`, e.g. `0.0.0.0:8080`
+    /// and `[::]:8080` for dual-stack serving. IPv6 sockets accept IPv6 connections only, so
+    /// the two do not collide.
+    /// `addrs` may be empty when `config.unix_socket` is set or systemd passed sockets in.
+    pub async fn bind_all(addrs: &[SocketAddr], config: ServerConfig) -> io::Result<FileServer> {
+        let context = |message: String| move |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", message, e));
+        let inherited = match config.socket_activation {
+            true => crate::systemd::take_listen_fds().map_err(context("Could not take the sockets passed by systemd".to_string()))?,
+            false => Vec::new(),
+        };
+        if addrs.is_empty() && config.unix_socket.is_none() && inherited.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "No address to listen on"));
+        }
+
+        let tls_config = match &config.tls {
+            Some((cert, key)) => Some(load_tls_config(cert, key, config.client_ca.as_deref())
+                .map_err(context("Could not load TLS certificate/key".to_string()))?),
+            None if config.client_ca.is_some() => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Client certificates need a TLS certificate and key"));
+            }
+            None => None,
+        };
+        if config.http3 && tls_config.is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "HTTP/3 needs a TLS certificate and key"));
+        }
+        #[cfg(not(feature = "http3"))]
+        if config.http3 {
+            return Err(io::Error::new(io::ErrorKind::Unsupported,
+                "This build has no HTTP/3 support; rebuild with --features http3 (and RUSTFLAGS=\"--cfg reqwest_unstable\")"));
+        }
+        let tls = tls_config.clone().map(tokio_rustls::TlsAcceptor::from);
+
+        let cache = match &config.cache_dir {
+            Some(dir) => Some(std::sync::Arc::new(ArchiveCache::open(dir, config.cache_max_size)
+                .map_err(context(format!("Could not open cache directory {}", dir.display())))?)),
+            None => None,
+        };
+        if config.watch && cache.is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Watching the root needs a cache directory to keep archives in"));
+        }
+        if config.pregenerate && cache.is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Pregenerating archives needs a cache directory to keep them in"));
+        }
+        let kept = KeptState {
+            cache: cache.clone(),
+            metrics: std::sync::Arc::new(Metrics::new()),
+            archive_checksums: Default::default(),
+            upload_locks: Default::default(),
+            jobs: Default::default(),
+            builds: Default::default(),
+            control: Default::default(),
+            // The cache writes would otherwise look like changes to the files being served
+            watch: (config.watch || config.pregenerate).then(|| config.cache_dir.iter().filter_map(|dir| dir.canonicalize().ok()).collect()),
+        };
+
+        let (unix_socket, unix_socket_mode, announce) = (config.unix_socket.clone(), config.unix_socket_mode, config.announce.clone());
+        #[cfg(feature = "http3")]
+        let http3 = config.http3;
+        let timeouts = Timeouts { read: config.read_timeout, write: config.write_timeout, idle: config.idle_timeout };
+        let http = HttpSettings {
+            keep_alive: config.keep_alive,
+            max_header_size: config.max_header_size.max(MIN_MAX_HEADER_SIZE),
+            max_headers: config.max_headers,
+        };
+        let backlog = config.backlog;
+        let shutdown_timeout = config.shutdown_timeout;
+        let service = build_service(config, &kept, None).await?;
+        let (root, metrics) = (service.shown_root.clone(), kept.metrics.clone());
+        let rate_limits = RateLimits::new(service.limits.clone());
+        let live = std::sync::Arc::new(LiveService::start(service, kept));
+
+        // Every request goes to the routes of the latest reload, also on connections opened before it
+        let routes = live.clone();
+        let app = Router::new().fallback_service(tower::service_fn(move |request: axum::extract::Request| {
+            let current = routes.routes();
+            async move { tower::ServiceExt::oneshot(current, request).await }
+        }));
+
+        let mut listeners = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            listeners.push(Listener::Tcp(bind_listener(*addr, backlog)
+                .map_err(context(format!("Could not bind to {} -- Is the port already in use?", addr)))?));
+        }
+        // Inherited TCP sockets count as listen addresses; Unix ones are added with our own below
+        let mut inherited_unix = Vec::new();
+        for socket in inherited {
+            match adopt_listener(socket).map_err(context("Could not use a socket passed by systemd".to_string()))? {
+                listener @ Listener::Tcp(_) => listeners.push(listener),
+                #[cfg(unix)]
+                listener @ Listener::Unix(_) => inherited_unix.push(listener),
+            }
+        }
+
+        // QUIC uses the same port numbers over UDP, and TCP responses point clients at it
+        #[cfg(feature = "http3")]
+        let (quic_endpoints, app) = match tls_config.as_deref().filter(|_| http3) {
+            Some(tls_config) => {
+                if listeners.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "HTTP/3 needs a TCP address to listen on"));
+                }
+                let mut endpoints = Vec::with_capacity(listeners.len());
+                for listener in &listeners {
+                    let addr = listener.local_addr()?;
+                    endpoints.push(crate::http3::bind_endpoint(addr, tls_config)
+                        .map_err(context(format!("Could not bind to UDP {} for HTTP/3", addr)))?);
+                }
+                let alt_svc = HeaderValue::from_str(&format!("h3=\":{}\"; ma=86400", listeners[0].local_addr()?.port()))
+                    .map_err(io::Error::other)?;
+                let app = app.layer(axum::middleware::map_response(move |mut response: Response| {
+                    let alt_svc = alt_svc.clone();
+                    async move {
+                        response.headers_mut().insert(axum::http::header::ALT_SVC, alt_svc);
+                        response
+                    }
+                }));
+                (endpoints, app)
+            }
+            None => (Vec::new(), app),
+        };
+
+        let announcement = match (&announce, listeners.first()) {
+            (Some(name), Some(_)) => {
+                let port = listeners[0].local_addr()?.port();
+                Some(Announcement::start(name, port, tls.is_some())
+                    .map_err(context(format!("Could not announce {} via mDNS", name)))?)
+            }
+            (Some(_), None) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Announcing the server needs a TCP address to listen on"));
+            }
+            (None, _) => None,
+        };
+
+        // Bound last, so that no other error can leave the socket file behind
+        if let Some(path) = &unix_socket {
+            listeners.push(bind_unix_listener(path, unix_socket_mode)
+                .map_err(context(format!("Could not listen on {}", path.display())))?);
+        }
+        listeners.extend(inherited_unix);
+
+        Ok(FileServer {
+            listeners,
+            unix_socket,
+            #[cfg(feature = "http3")]
+            quic_endpoints,
+            app,
+            tls,
+            rate_limits,
+            timeouts,
+            http,
+            root,
+            shutdown_timeout,
+            cache,
+            metrics,
+            live,
+            announcement,
+        })
+    }
+
+    /// A handle for applying a new configuration while the server runs, e.g. on SIGHUP.
+    pub fn reloader(&self) -> Reloader {
+        Reloader { live: self.live.clone(), rate_limits: self.rate_limits.clone() }
+    }
+
+    /// The address actually bound, e.g. to learn the port after binding port 0. With several
+    /// addresses, the first one.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.listeners.first() {
+            Some(Listener::Tcp(listener)) => listener.local_addr(),
+            _ => Err(io::Error::new(io::ErrorKind::NotFound, "Not listening on a TCP address")),
+        }
+    }
+
+    /// Every TCP address actually bound, in the order they were given.
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners.iter()
+            .filter_map(|listener| match listener {
+                Listener::Tcp(listener) => Some(listener.local_addr()),
+                #[cfg(unix)]
+                Listener::Unix(_) => None,
+            })
+            .collect()
+    }
+
+    /// The Unix socket the server listens on, with `unix_socket`.
+    pub fn unix_socket(&self) -> Option<&Path> {
+        self.unix_socket.as_deref()
+    }
+
+    /// The canonical root directory being served, or the `s3://` URL of a bucket.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The name the server is announced under on the local network, with `announce`.
+    pub fn announced_as(&self) -> Option<&str> {
+        self.announcement.as_ref().map(Announcement::name)
+    }
+
+    /// Whether connections are served over TLS.
+    pub fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    /// Whether HTTP/3 is served alongside HTTP/1.1 and HTTP/2, with `http3`.
+    pub fn is_http3(&self) -> bool {
+        #[cfg(feature = "http3")]
+        return !self.quic_endpoints.is_empty();
+        #[cfg(not(feature = "http3"))]
+        false
+    }
+
+    /// The routes, for mounting into another axum application instead of calling `run`.
+    pub fn router(&self) -> Router {
+        self.app.clone()
+    }
+
+    /// Serves until SIGINT/SIGTERM, then drains in-flight requests (see `shutdown_timeout`).
+    pub async fn run(self) {
+        self.run_until(shutdown_signal()).await
+    }
+
+    /// Serves until `shutdown` resolves, then drains in-flight requests and removes the
+    /// temporary files of any that were cut off.
+    pub async fn run_until(self, shutdown: impl std::future::Future<Output = ()>) {
+        // HTTP/3 drains alongside the TCP connections, from the moment shutdown is requested
+        #[cfg(feature = "http3")]
+        let (shutdown, http3_task) = {
+            let (stopping, stopping_rx) = tokio::sync::watch::channel(None);
+            let task = (!self.quic_endpoints.is_empty()).then(|| tokio::spawn(crate::http3::serve(
+                self.quic_endpoints, self.app.clone(), self.rate_limits.clone(), self.metrics.clone(), stopping_rx, self.shutdown_timeout,
+            )));
+            let shutdown = async move {
+                shutdown.await;
+                let _ = stopping.send(Some(std::time::Instant::now()));
+            };
+            (shutdown, task)
+        };
+
+        // Polled once before telling systemd we are up, so that signal handlers are in place for
+        // a stop that follows right away
+        let mut shutdown = Box::pin(shutdown);
+        let stopped = (&mut shutdown).now_or_never().is_some();
+        crate::systemd::notify("READY=1");
+        let shutdown = async move {
+            if !stopped {
+                shutdown.await;
+            }
+            crate::systemd::notify("STOPPING=1");
+        };
+
+        let context = ConnectionContext { app: self.app, rate_limits: self.rate_limits, metrics: self.metrics, timeouts: self.timeouts, http: self.http };
+        run_accept_loop(self.listeners, self.tls, context, shutdown, self.shutdown_timeout).await;
+        #[cfg(feature = "http3")]
+        if let Some(task) = http3_task {
+            let _ = task.await;
+        }
+        drop(self.announcement);
+
+        if let Some(path) = &self.unix_socket {
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!("Could not remove socket {}: {}", path.display(), e);
+            }
+        }
+
+        self.live.stop_watching();
+
+        if let Some(cache) = self.cache {
+            if let Err(e) = tokio::task::spawn_blocking(move || cache.remove_temp_files()).await.unwrap_or(Ok(())) {
+                warn!("Could not clean up the cache directory: {}", e);
+            }
+        }
+    }
+}
+
+/// What a reload keeps: the archive cache, counters, checksums already worked out, the locks of
+/// uploads in progress, background jobs and the builds waiting their turn, which stay valid
+/// whatever the new settings are.
+#[derive(Clone)]
+struct KeptState {
+    cache: Option<std::sync::Arc<ArchiveCache>>,
+    metrics: std::sync::Arc<Metrics>,
+    archive_checksums: std::sync::Arc<ArchiveChecksums>,
+    upload_locks: std::sync::Arc<UploadLocks>,
+    jobs: std::sync::Arc<Jobs>,
+    builds: std::sync::Arc<BuildQueue>,
+    /// Draining and reload requests from the admin API
+    control: std::sync::Arc<ServerControl>,
+    /// With `watch`, the paths the root watcher ignores
+    watch: Option<Vec<PathBuf>>,
+}
+
+/// The routes serving one configuration, with the limits for the connections they are served on.
+struct Service {
+    app: Router,
+    state: AppState,
+    limits: ConnectionLimits,
+    /// The root as shown to people: canonical, or the S3 URL
+    shown_root: PathBuf,
+    watcher: Option<RootWatcher>,
+}
+
+/// Checks the reloadable parts of `config` (root, default files, archive options, encryption,
+/// uploads, authentication, CORS, WebDAV, limits) and builds the routes serving them.
+/// `previous` is the state of the configuration being replaced, whose share links and one-time
+/// tokens stay valid.
+async fn build_service(config: ServerConfig, kept: &KeptState, previous: Option<&AppState>) -> io::Result<Service> {
+    let context = |message: String| move |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", message, e));
+
+    let (storage, root, shown_root) = match config.root.to_str().filter(|root| root.starts_with(S3_URL_PREFIX)) {
+        Some(url) => {
+            let storage = Storage::new(S3Storage::from_url(url)
+                .map_err(context(format!("Invalid S3 root {}", url)))?);
+            // Listing the top level up front catches a wrong bucket, endpoint or credentials
+            let probe = storage.clone();
+            tokio::task::spawn_blocking(move || probe.read_dir(Path::new("/"))).await
+                .unwrap_or_else(|_| Err(io::Error::other("Listing task failed")))
+                .map_err(context(format!("Could not list {}", url)))?;
+            // Keys are served as a `/`-rooted tree
+            (storage, PathBuf::from("/"), config.root.clone())
+        }
+        None => {
+            if !config.root.is_dir() {
+                return Err(io::Error::new(io::ErrorKind::NotFound,
+                    format!("Root directory not found or is not a directory: {}", config.root.display())));
+            }
+            // Requested paths are checked against the canonical root, so resolve it once up front
+            let root = config.root.canonicalize()
+                .map_err(context(format!("Could not resolve root directory {}", config.root.display())))?;
+            // On Windows that is the `\\?\` form, which also lifts the 260 character limit below it
+            (Storage::default(), root.clone(), crate::paths::plain_path(&root))
+        }
+    };
+
+    let (probe, probe_root, default_files) = (storage.clone(), root.clone(), config.default_files.clone());
+    let missing = tokio::task::spawn_blocking(move || {
+        default_files.into_iter().find(|file_path| probe.metadata(&probe_root.join(file_path)).is_err())
+    }).await.unwrap_or_default();
+    if let Some(file_path) = missing {
+        return Err(io::Error::new(io::ErrorKind::NotFound,
+            format!("Required source file not found: {}", file_path)));
+    }
+
+    // An ignore file is only looked for in a local root
+    let ignore_file = storage.is_local().then(|| root.join(IGNORE_FILE_NAME));
+    let exclude = ExcludeRules::new(&root, &config.exclude, ignore_file.as_deref())
+        .map_err(context("Invalid exclude pattern".to_string()))?;
+
+    if let Some(upload_dir) = &config.upload_dir {
+        std::fs::create_dir_all(upload_dir)
+            .map_err(context(format!("Could not create upload directory {}", upload_dir.display())))?;
+    }
+    let upload_dir = config.upload_dir.as_deref().map(crate::paths::long_path).transpose()
+        .map_err(context("Could not resolve the upload directory".to_string()))?;
+
+    let watcher = match &kept.watch {
+        None => None,
+        Some(_) if !storage.is_local() => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "Only a local root directory can be watched")),
+        Some(ignored) => Some(RootWatcher::start(&root, ignored.clone())
+            .map_err(|e| io::Error::other(format!("Could not watch {}: {}", root.display(), e)))?),
+    };
+
+    let encryption = match &config.encrypt_passphrase {
+        Some(passphrase) => Some(std::sync::Arc::new(ArchiveEncryption::new(passphrase)
+            .map_err(context("Could not set up encryption".to_string()))?)),
+        None => None,
+    };
+
+    let signing_key = match &config.signing_key {
+        Some(path) => Some(std::sync::Arc::new(crate::crypt::load_signing_key(path)
+            .map_err(context(format!("Could not load signing key {}", path.display())))?)),
+        None => None,
+    };
+
+    if config.post_archive_cmd.is_some() && config.stream {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "A post-archive command needs whole archives, so it cannot be combined with streaming"));
+    }
+    if config.webdav_writable && !config.webdav {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "A writable WebDAV share needs WebDAV enabled"));
+    }
+    if config.webdav_writable && !storage.is_local() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Only a local root directory can be shared writable over WebDAV"));
+    }
+    let dav = config.webdav.then(|| std::sync::Arc::new(DavShare {
+        storage: storage.clone(),
+        root: root.clone(),
+        exclude: exclude.clone(),
+        writable: config.webdav_writable,
+        max_upload_size: config.max_upload_size,
+    }));
+
+    let mut auth = config.auth;
+    if let Some(previous) = previous {
+        auth.inherit(&previous.auth);
+    }
+    if auth.is_enabled() {
+        auth.ensure_link_key().map_err(context("Could not generate a share link key".to_string()))?;
+    }
+    if config.manage_files && !storage.is_local() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Only the files in a local root directory can be managed"));
+    }
+    if config.manage_files && !auth.is_enabled() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Managing files needs authentication (--auth-token or --auth-file)"));
+    }
+    let search_root = std::sync::Arc::new(SearchRoot {
+        storage: storage.clone(),
+        root: root.clone(),
+        exclude: exclude.clone(),
+        index: config.search_index.map(SearchIndex::new),
+    });
+    let managed = config.manage_files.then(|| std::sync::Arc::new(ManagedRoot {
+        storage: storage.clone(),
+        root: root.clone(),
+        exclude: exclude.clone(),
+    }));
+    // Opened again on every reload, so a rotated log is picked up
+    let audit_log = match &config.audit_log {
+        Some(path) => Some(std::sync::Arc::new(AuditLog::open(path)
+            .map_err(context(format!("Could not open the audit log {}", path.display())))?)),
+        None => None,
+    };
+
+    let upload_body_limit = config.max_upload_size.saturating_add(UPLOAD_OVERHEAD_ALLOWANCE);
+    kept.builds.set_limits(config.max_archive_jobs, config.archive_queue);
+    let app_state = AppState {
+        root: root.clone(),
+        storage,
+        initial_files: config.default_files,
+        presets: std::sync::Arc::new(config.presets),
+        pregenerate: config.pregenerate,
+        skip_verify: config.skip_verify,
+        stream: config.stream,
+        dry_run: config.dry_run,
+        pre_archive_cmd: config.pre_archive_cmd,
+        post_archive_cmd: config.post_archive_cmd,
+        compression: config.compression,
+        level: config.level,
+        symlinks: config.symlinks,
+        preserve_metadata: config.preserve_metadata && !config.reproducible,
+        sort_entries: config.reproducible,
+        mtime_clamp: config.mtime_clamp,
+        xattrs: config.xattrs,
+        threads: config.threads.max(1),
+        exclude,
+        max_archive_size: config.max_archive_size,
+        max_files: config.max_files,
+        split_size: config.split_size,
+        chunk_indexes: config.chunked.then(Default::default),
+        cache: kept.cache.clone(),
+        encryption,
+        signing_key,
+        upload_dir,
+        max_upload_size: config.max_upload_size,
+        auth: std::sync::Arc::new(auth),
+        archive_checksums: kept.archive_checksums.clone(),
+        upload_locks: kept.upload_locks.clone(),
+        plans: watcher.as_ref().map(|_| Default::default()),
+        metrics: kept.metrics.clone(),
+        dav,
+        jobs: kept.jobs.clone(),
+        max_jobs: config.max_jobs,
+        builds: kept.builds.clone(),
+        audit_log,
+    };
+    let mut own_roots = std::collections::HashMap::new();
+    for (user, user_root) in app_state.auth.own_roots() {
+        if !app_state.storage.is_local() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Users with a root of their own need a local server root"));
+        }
+        let user_root = root.join(user_root).canonicalize()
+            .map_err(context(format!("Could not resolve the root of user {}", user)))?;
+        if !user_root.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("The root of user {} is not a directory: {}", user, user_root.display())));
+        }
+        // Inside the server root its exclusions still apply; elsewhere the directory has its own
+        let exclude = match user_root.starts_with(&root) {
+            true => app_state.exclude.clone(),
+            false => ExcludeRules::new(&user_root, &config.exclude, Some(&user_root.join(IGNORE_FILE_NAME)))
+                .map_err(context("Invalid exclude pattern".to_string()))?,
+        };
+        let upload_dir = match &app_state.upload_dir {
+            Some(upload_dir) => {
+                let upload_dir = upload_dir.join(user);
+                std::fs::create_dir_all(&upload_dir)
+                    .map_err(context(format!("Could not create upload directory {}", upload_dir.display())))?;
+                Some(upload_dir)
+            }
+            None => None,
+        };
+        let user_state = AppState {
+            root: user_root.clone(),
+            // Default files and presets name paths in the server root
+            initial_files: Vec::new(),
+            presets: Default::default(),
+            pregenerate: false,
+            exclude: exclude.clone(),
+            upload_dir,
+            plans: None,
+            dav: config.webdav.then(|| std::sync::Arc::new(DavShare {
+                storage: app_state.storage.clone(),
+                root: user_root.clone(),
+                exclude: exclude.clone(),
+                writable: config.webdav_writable,
+                max_upload_size: config.max_upload_size,
+            })),
+            ..app_state.clone()
+        };
+        let search_root = std::sync::Arc::new(SearchRoot {
+            storage: app_state.storage.clone(),
+            root: user_root.clone(),
+            exclude: exclude.clone(),
+            index: config.search_index.map(SearchIndex::new),
+        });
+        let managed = config.manage_files.then(|| std::sync::Arc::new(ManagedRoot {
+            storage: app_state.storage.clone(),
+            root: user_root,
+            exclude,
+        }));
+        let routes = app_routes(user_state, config.static_files, config.webdav, upload_body_limit, search_root, managed);
+        own_roots.insert(user.to_string(), routes);
+    }
+
+    let mut app = app_routes(app_state.clone(), config.static_files, config.webdav, upload_body_limit, search_root, managed);
+    if !own_roots.is_empty() {
+        // Dispatching happens before any routing, so every router matches the whole request
+        let accounts = std::sync::Arc::new(OwnRoots { auth: app_state.auth.clone(), routes: own_roots, server_root: app });
+        app = Router::new().fallback_service(tower::service_fn(move |request: axum::extract::Request| {
+            let accounts = accounts.clone();
+            async move { Ok::<_, std::convert::Infallible>(accounts.route(request).await) }
+        }));
+    }
+    if config.compress_responses {
+        app = app.layer(axum::middleware::from_fn(negotiate_encoding));
+    }
+    // Outside authentication, since browsers send preflight requests without credentials
+    if let Some(cors) = &config.cors {
+        app = app.layer(cors.layer()?);
+    }
+    if let Some(read_timeout) = config.read_timeout {
+        app = app.layer(axum::middleware::map_request(move |request: axum::extract::Request| async move {
+            request.map(|body| limit_body_stalls(body, read_timeout))
+        }));
+    }
+    // Outside authentication too: the admin token is the only credential the admin API takes
+    if let Some(token) = &config.admin_token {
+        let admin = AdminState {
+            token: token.as_str().into(),
+            control: kept.control.clone(),
+            cache: kept.cache.clone(),
+            metrics: kept.metrics.clone(),
+        };
+        app = app
+            .layer(axum::middleware::from_fn_with_state(kept.control.clone(), refuse_while_draining))
+            .merge(crate::admin::routes(admin));
+    }
+    // Calls go through everything above, to be authorized and refused like the requests they stand for
+    if config.grpc {
+        app = crate::grpc::layer(app, config.admin_token.is_some());
+    }
+    // Probes carry no credentials, and a draining server answers /readyz with 503 itself
+    let readiness = Readiness {
+        storage: app_state.storage.clone(),
+        root: app_state.root.clone(),
+        cache_dir: kept.cache.as_ref().map(|cache| cache.dir().to_path_buf()),
+        upload_dir: app_state.upload_dir.clone(),
+        writable_root: config.webdav_writable,
+        min_free_space: config.min_free_space,
+        control: kept.control.clone(),
+    };
+    app = app.merge(crate::health::routes(std::sync::Arc::new(readiness)));
+
+
+    let limits = ConnectionLimits {
+        global: config.max_rate.map(|rate| std::sync::Arc::new(RateLimiter::new(rate))),
+        per_connection: config.max_rate_per_connection,
+        clients: ClientLimits::new(config.max_connections, config.max_connections_per_ip, config.max_requests_per_minute),
+    };
+    Ok(Service { app, state: app_state, limits, shown_root, watcher })
+}
+
+/// The routes serving `state`, behind authentication.
+fn app_routes(
+    state: AppState,
+    static_files: bool,
+    webdav: bool,
+    upload_body_limit: u64,
+    search_root: std::sync::Arc<SearchRoot>,
+    managed: Option<std::sync::Arc<ManagedRoot>>,
+) -> Router {
+    // In static mode the root is a directory like any other, and the web UI gives way to its listing
+    let index = match static_files {
+        true => get(static_handler),
+        false => get(index_handler),
+    };
+    let mut app = Router::new()
+        .route("/", index)
+        .route("/download", get(download_handler).head(download_head_handler))
+        .route("/progress/:job_id", get(progress_handler))
+        .route("/preview", get(preview_handler))
+        .route("/checksum", get(checksum_handler))
+        .route("/manifest", get(manifest_handler))
+        .route("/sync", post(sync_handler).layer(DefaultBodyLimit::max(MAX_SYNC_MANIFEST_SIZE)))
+        .route("/download.sig", get(signature_handler))
+        .route("/download.index", get(parts_index_handler))
+        .route("/download.chunks", get(chunk_index_handler)
+            .post(chunks_handler).layer(DefaultBodyLimit::max(MAX_SYNC_MANIFEST_SIZE)))
+        .route("/list", get(list_handler))
+        .route("/file/*path", get(file_handler))
+        .route("/browse", get(browse_handler))
+        .route("/browse/", get(browse_handler))
+        .route("/browse/*path", get(browse_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/bench", get(bench_handler))
+        .route("/share", post(share_handler))
+        .route("/upload", post(upload_handler).layer(DefaultBodyLimit::max(upload_body_limit as usize)))
+        .route("/upload/session", post(create_upload_session_handler))
+        .route("/upload/session/:id", get(upload_session_status_handler).patch(upload_chunk_handler));
+    if static_files {
+        app = app.route("/*path", get(static_handler));
+    }
+    if webdav {
+        app = app
+            .route(DAV_PREFIX, any(dav_handler))
+            .route(&format!("{}/", DAV_PREFIX), any(dav_handler))
+            .route(&format!("{}/*path", DAV_PREFIX), any(dav_handler));
+    }
+    app = app.merge(crate::search::routes(search_root));
+    if let Some(managed) = managed {
+        app = app.merge(crate::manage::routes(managed));
+    }
+    // Inside authentication, so downloads are audited with the user they were served to
+    if state.audit_log.is_some() {
+        app = app.route_layer(axum::middleware::from_fn_with_state(state.clone(), audit_downloads));
+    }
+    app.route_layer(axum::middleware::from_fn_with_state(state.clone(), require_auth))
+        .with_state(state)
+}
+
+/// Middleware marking the responses to downloads for the audit log, which writes their line
+/// once the body has been sent.
+async fn audit_downloads(
+    State(state): State<AppState>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let Some(log) = state.audit_log.clone() else {
+        return next.run(request).await;
+    };
+    let (mut parts, body) = request.into_parts();
+    let route = parts.extensions.get::<axum::extract::MatchedPath>().map_or_else(String::new, |route| route.as_str().to_string());
+    let path = parts.uri.path().to_string();
+    let below = |prefix: &str| {
+        let relative = path.strip_prefix(prefix).unwrap_or(&path).trim_start_matches('/');
+        vec![urlencoding::decode(relative).map_or_else(|_| relative.to_string(), |decoded| decoded.into_owned())]
+    };
+    let files = match (&parts.method, route.as_str()) {
+        (&Method::GET, "/download") | (&Method::POST, "/download.chunks") => {
+            let params = <Query<ArchiveParams> as axum::extract::FromRequestParts<()>>::from_request_parts(&mut parts, &()).await
+                .map(|Query(params)| params)
+                .unwrap_or_default();
+            match requested_files(&state, &params) {
+                Ok(files) if files.is_empty() && params.job.is_none() => state.initial_files.clone(),
+                Ok(files) => files,
+                Err(_) => params.files,
+            }
+        }
+        (&Method::GET, "/file/*path") => below("/file"),
+        (&Method::GET, "/browse/*path") => below("/browse"),
+        (&Method::GET, "/*path") => below(""),
+        (&Method::GET, route) if route.starts_with(DAV_PREFIX) => below(DAV_PREFIX),
+        _ => return next.run(axum::extract::Request::from_parts(parts, body)).await,
+    };
+    let request = axum::extract::Request::from_parts(parts, body);
+    let user = request.extensions().get::<AuthenticatedUser>().map(|user| user.0.clone());
+    let mut response = next.run(request).await;
+    response.extensions_mut().insert(AuditedDownload { log, user, files });
+    response
+}
+
+/// The routes of each user with a root of their own, which their requests are handed to.
+struct OwnRoots {
+    auth: std::sync::Arc<AuthConfig>,
+    routes: std::collections::HashMap<String, Router>,
+    /// Everyone else's
+    server_root: Router,
+}
+
+impl OwnRoots {
+    /// Sends the requests of users with a root of their own to the routes serving it. Their
+    /// credentials are only checked here; everything else, share links and requests without
+    /// valid credentials included, goes on to the server root.
+    async fn route(&self, mut request: axum::extract::Request) -> Response {
+        let mut routes = None;
+        if let Some(user) = self.auth.authenticate(request.headers()).await {
+            routes = self.routes.get(&user.0).filter(|_| user.1.root.is_some()).cloned();
+            request.extensions_mut().insert(user);
+        }
+        let routes = routes.unwrap_or_else(|| self.server_root.clone());
+        match tower::ServiceExt::oneshot(routes, request).await {
+            Ok(response) => response,
+            Err(never) => match never {},
+        }
+    }
+}
+
+// --- RELOADING ---
+
+/// What a running server serves right now; a reload replaces it as a whole.
+struct LiveService {
+    routes: std::sync::RwLock<Router>,
+    state: std::sync::RwLock<AppState>,
+    kept: KeptState,
+    /// Refreshes cached archives with `watch`, restarted on the new root by a reload
+    watch_task: std::sync::Mutex<Option<tokio::task::JoinHandle<()>>>,
+    /// Reloads run one at a time
+    reloading: tokio::sync::Mutex<()>,
+}
+
+impl LiveService {
+    fn start(service: Service, kept: KeptState) -> Self {
+        let watch_task = service.watcher.map(|watcher| tokio::spawn(watch_root(service.state.clone(), watcher)));
+        LiveService {
+            routes: std::sync::RwLock::new(service.app),
+            state: std::sync::RwLock::new(service.state),
+            kept,
+            watch_task: std::sync::Mutex::new(watch_task),
+            reloading: Default::default(),
+        }
+    }
+
+    fn routes(&self) -> Router {
+        self.routes.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn state(&self) -> AppState {
+        self.state.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn replace(&self, service: Service) {
+        self.stop_watching();
+        let watch_task = service.watcher.map(|watcher| tokio::spawn(watch_root(service.state.clone(), watcher)));
+        *self.watch_task.lock().unwrap_or_else(|e| e.into_inner()) = watch_task;
+        *self.routes.write().unwrap_or_else(|e| e.into_inner()) = service.app;
+        *self.state.write().unwrap_or_else(|e| e.into_inner()) = service.state;
+    }
+
+    fn stop_watching(&self) {
+        if let Some(task) = self.watch_task.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            task.abort();
+        }
+    }
+}
+
+/// Applies a new configuration to a running [`FileServer`], e.g. when it receives SIGHUP.
+#[derive(Clone)]
+pub struct Reloader {
+    live: std::sync::Arc<LiveService>,
+    rate_limits: RateLimits,
+}
+
+impl Reloader {
+    /// Serves every request from now on according to `config`: its root, default files,
+    /// archive options, encryption and signing, uploads, authentication, CORS, WebDAV, and rate
+    /// and client limits. Requests in flight, downloads included, finish under the settings they
+    /// started with, and share links and one-time tokens stay valid unless the link secret
+    /// changed. Listen addresses, TLS, HTTP/3, the announcement, the cache, `watch`, the
+    /// shutdown timeout and the write and idle timeouts keep their values from startup.
+    ///
+    /// A `config` that does not check out (a missing root, an unreadable key) is an error and
+    /// changes nothing.
+    pub async fn reload(&self, config: ServerConfig) -> io::Result<()> {
+        let _reloading = self.live.reloading.lock().await;
+        crate::systemd::notify("RELOADING=1");
+        let result = build_service(config, &self.live.kept, Some(&self.live.state())).await.map(|service| {
+            self.rate_limits.replace(service.limits.clone());
+            self.live.replace(service);
+        });
+        crate::systemd::notify("READY=1");
+        result
+    }
+
+    /// Hands every `POST /admin/reload` to the returned receiver, which answers it with the
+    /// outcome of applying the configuration again. Without a receiver the admin API answers
+    /// 501 Not Implemented; a new receiver replaces the previous one.
+    pub fn admin_requests(&self) -> tokio::sync::mpsc::UnboundedReceiver<ReloadRequest> {
+        self.live.kept.control.reload_requests()
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    /// `/` when serving from object storage
+    root: PathBuf,
+    storage: Storage,
+    initial_files: Vec<String>,
+    presets: std::sync::Arc<std::collections::BTreeMap<String, Vec<String>>>,
+    pregenerate: bool,
+    skip_verify: bool,
+    stream: bool,
+    dry_run: bool,
+    pre_archive_cmd: Option<String>,
+    post_archive_cmd: Option<String>,
+    compression: Codec,
+    level: Option<i32>,
+    symlinks: SymlinkPolicy,
+    preserve_metadata: bool,
+    sort_entries: bool,
+    mtime_clamp: Option<u64>,
+    xattrs: bool,
+    threads: usize,
+    exclude: ExcludeRules,
+    max_archive_size: Option<u64>,
+    max_files: Option<usize>,
+    split_size: Option<u64>,
+    /// Chunk lists of recently chunked archives, keyed by ETag; only with `chunked`
+    chunk_indexes: Option<std::sync::Arc<ChunkIndexes>>,
+    cache: Option<std::sync::Arc<ArchiveCache>>,
+    encryption: Option<std::sync::Arc<ArchiveEncryption>>,
+    signing_key: Option<std::sync::Arc<SigningKey>>,
+    upload_dir: Option<PathBuf>,
+    max_upload_size: u64,
+    auth: std::sync::Arc<AuthConfig>,
+    /// Sizes and checksums of archives built or measured so far, keyed by ETag, so HEAD and
+    /// /checksum can answer cheaply.
+    archive_checksums: std::sync::Arc<ArchiveChecksums>,
+    /// One lock per upload session with a chunk in flight
+    upload_locks: std::sync::Arc<UploadLocks>,
+    /// Plans reused until the watcher sees a change; only with --watch
+    plans: Option<std::sync::Arc<PlanMemo>>,
+    metrics: std::sync::Arc<Metrics>,
+    /// Only with --webdav
+    dav: Option<std::sync::Arc<DavShare>>,
+    jobs: std::sync::Arc<Jobs>,
+    max_jobs: Option<usize>,
+    builds: std::sync::Arc<BuildQueue>,
+    /// Only with --audit-log
+    audit_log: Option<std::sync::Arc<AuditLog>>,
+}
+
+/// Query parameters shared by `/download`, `/preview`, `/checksum`, `/manifest` and `/sync`.
+#[derive(Deserialize, Debug, Default)]
+struct ArchiveParams {
+    #[serde(default)]
+    files: Vec<String>,
+    /// Archive one of the server's named file sets instead of `files`
+    preset: Option<String>,
+    #[serde(default)]
+    format: ArchiveFormat,
+    /// Overrides the server's default `--compression` for tarballs
+    compression: Option<Codec>,
+    /// Overrides the server's default `--level`
+    level: Option<i32>,
+    /// Store every file under its base name instead of keeping directory structure
+    #[serde(default)]
+    flatten: bool,
+    /// Build the archive as a background job and answer with its ID (`/download?async=1`)
+    #[serde(default, rename = "async", deserialize_with = "deserialize_flag")]
+    async_build: bool,
+    /// Send the archive a finished background job built, instead of planning one
+    job: Option<String>,
+    /// Send only this part (numbered from 1) of an archive split by `--split-size`
+    part: Option<u32>,
+    /// File name to suggest for the archive, instead of one derived from the requested files
+    name: Option<String>,
+}
+
+/// Accepts `1`/`0` as well as `true`/`false`, for flags like `?async=1`.
+fn deserialize_flag<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    match String::deserialize(deserializer)?.as_str() {
+        "1" | "true" => Ok(true),
+        "0" | "false" => Ok(false),
+        other => Err(serde::de::Error::custom(format!("expected 1, 0, true or false, got {:?}", other))),
+    }
+}
+
+/// Query parameters for `/list`.
+#[derive(Deserialize, Debug, Default)]
+struct ListParams {
+    /// Directory (relative to the root) to list; the root itself when omitted
+    path: Option<String>,
+}
+
+/// One file or directory in a `/list` or `/search` response.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ListEntry {
+    pub name: String,
+    /// Path relative to the server root, usable as a `files=` value
+    pub path: String,
+    pub size: u64,
+    /// Modification time in seconds since the Unix epoch, when the platform reports one
+    pub mtime: Option<u64>,
+    pub is_dir: bool,
+}
+
+/// Query parameters for `/upload`.
+#[derive(Deserialize, Debug, Default)]
+struct UploadParams {
+    /// Subdirectory of the upload directory to store the files in
+    path: Option<String>,
+    /// Replace files that already exist instead of rejecting the upload
+    #[serde(default)]
+    overwrite: bool,
+}
+
+/// One stored file in an `/upload` response.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadedFile {
+    pub name: String,
+    pub size: u64,
+}
+
+
+// --- LISTING ---
+
+/// Builds a listing entry for `full_path`, which is reported as `relative_path`.
+pub(crate) fn list_entry(storage: &Storage, full_path: &Path, relative_path: &Path) -> io::Result<ListEntry> {
+    let metadata = storage.metadata(full_path)?;
+    let mtime = metadata.modified.duration_since(std::time::UNIX_EPOCH).ok()
+        .map(|duration| duration.as_secs());
+
+    Ok(ListEntry {
+        name: relative_path.file_name().unwrap_or(relative_path.as_os_str()).to_string_lossy().into_owned(),
+        path: relative_path.to_string_lossy().replace('\\', "/"),
+        size: if metadata.is_dir() { 0 } else { metadata.len },
+        mtime,
+        is_dir: metadata.is_dir(),
+    })
+}
+
+/// Lists the immediate children of `relative_dir` under `root`, sorted by name.
+/// A path that names a file lists just that file. Symlinks leading outside the root and
+/// excluded paths are hidden.
+pub(crate) fn list_directory(storage: &Storage, root: &Path, relative_dir: &Path, exclude: &ExcludeRules) -> io::Result<Vec<ListEntry>> {
+    let full_dir = root.join(relative_dir);
+
+    if storage.metadata(&full_dir)?.is_file() {
+        return Ok(vec![list_entry(storage, &full_dir, relative_dir)?]);
+    }
+
+    let mut entries = Vec::new();
+    for name in storage.read_dir(&full_dir)? {
+        let path = full_dir.join(&name);
+        match storage.canonicalize(&path) {
+            Ok(resolved) if resolved.starts_with(root) => {}
+            _ => continue,
+        }
+        if exclude.is_excluded(&path, storage.metadata(&path).is_ok_and(|metadata| metadata.is_dir())) {
+            continue;
+        }
+
+        entries.push(list_entry(storage, &path, &relative_dir.join(name))?);
+    }
+
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+// --- CORS ---
+
+/// Request methods cross-origin callers may use by default: everything the routes accept.
+pub const DEFAULT_CORS_METHODS: &[&str] = &["GET", "HEAD", "POST", "PATCH"];
+/// Request headers cross-origin callers may send by default.
+pub const DEFAULT_CORS_HEADERS: &[&str] = &["Authorization", "Content-Type", "Range", "If-Range", UPLOAD_OFFSET_HEADER];
+/// Response headers browser scripts may read, beyond the few that are always visible.
+const CORS_EXPOSED_HEADERS: &[&str] = &[
+    "Content-Length", "Content-Range", "Content-Disposition", "Accept-Ranges", "ETag", "Last-Modified",
+    UPLOAD_OFFSET_HEADER, SYNC_UNCHANGED_HEADER,
+];
+
+/// Which browser frontends on other origins may call the server, and how.
+#[derive(Clone, Debug)]
+pub struct CorsPolicy {
+    /// Allowed origins such as `https://app.example.com`, or `*` for any
+    pub origins: Vec<String>,
+    /// Allowed request methods
+    pub methods: Vec<String>,
+    /// Allowed request headers
+    pub headers: Vec<String>,
+}
+
+impl CorsPolicy {
+    /// Allows `origins` to use every route with the default methods and headers.
+    pub fn new(origins: Vec<String>) -> Self {
+        CorsPolicy {
+            origins,
+            methods: DEFAULT_CORS_METHODS.iter().map(|method| method.to_string()).collect(),
+            headers: DEFAULT_CORS_HEADERS.iter().map(|header| header.to_string()).collect(),
+        }
+    }
+
+    /// The middleware answering preflight requests and adding `Access-Control-*` headers.
+    fn layer(&self) -> io::Result<tower_http::cors::CorsLayer> {
+        use tower_http::cors::{AllowOrigin, CorsLayer};
+        let invalid = |what: &str, value: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid CORS {}: {:?}", what, value));
+
+        let origin = if self.origins.iter().any(|origin| origin == "*") {
+            AllowOrigin::any()
+        } else {
+            let origins = self.origins.iter()
+                .map(|origin| {
+                    // Browsers send the origin without a trailing slash, and it must match exactly
+                    let trimmed = origin.trim_end_matches('/');
+                    match trimmed.contains("://") {
+                        true => HeaderValue::from_str(trimmed).map_err(|_| invalid("origin", origin)),
+                        false => Err(invalid("origin (expected e.g. https://app.example.com)", origin)),
+                    }
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            AllowOrigin::list(origins)
+        };
+        let methods = self.methods.iter()
+            .map(|method| axum::http::Method::from_bytes(method.trim().to_ascii_uppercase().as_bytes()).map_err(|_| invalid("method", method)))
+            .collect::<io::Result<Vec<_>>>()?;
+        let headers = self.headers.iter()
+            .map(|header| axum::http::HeaderName::from_bytes(header.trim().as_bytes()).map_err(|_| invalid("header", header)))
+            .collect::<io::Result<Vec<_>>>()?;
+        let exposed: Vec<_> = CORS_EXPOSED_HEADERS.iter()
+            .filter_map(|header| axum::http::HeaderName::from_bytes(header.as_bytes()).ok())
+            .collect();
+
+        Ok(CorsLayer::new()
+            .allow_origin(origin)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .expose_headers(exposed)
+            .max_age(Duration::from_secs(3600)))
+    }
+}
+
+// --- AUTHENTICATION ---
+
+/// Credentials the server accepts. With neither tokens nor users configured, auth is off.
+#[derive(Debug, Default)]
+pub struct AuthConfig {
+    tokens: Vec<String>,
+    /// User name -> password entry and account from the htpasswd-style file
+    users: std::collections::HashMap<String, (PasswordEntry, Account)>,
+    /// Tokens that let a single download of the default files through, with its resumes
+    one_time_tokens: std::sync::Arc<std::sync::Mutex<Vec<OneTimeToken>>>,
+    /// Key signing the share links minted at `/share`
+    link_key: Option<Vec<u8>>,
+    /// Downloads started so far with each share link that has a use limit, by link ID
+    link_uses: std::sync::Arc<std::sync::Mutex<std::collections::HashMap<String, ShareLinkUses>>>,
+}
+
+#[derive(Debug)]
+enum PasswordEntry {
+    Plain(String),
+    Bcrypt(String),
+}
+
+/// What a user from the users file may see and do.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Account {
+    /// Directory the user sees instead of the server root (`root=`), relative to the server root
+    /// unless absolute
+    root: Option<PathBuf>,
+    /// No uploads, WebDAV writes or file management (`access=read-only`)
+    read_only: bool,
+}
+
+/// The identity a request authenticated as, and the account it has, stored in the request
+/// extensions.
+#[derive(Clone, Debug)]
+struct AuthenticatedUser(String, Account);
+
+impl AuthenticatedUser {
+    /// Someone without an account of their own: a token, a share link or a one-time token.
+    fn anonymous(name: &str) -> Self {
+        AuthenticatedUser(name.to_string(), Account::default())
+    }
+}
+
+impl AuthConfig {
+    /// Accepts `Authorization: Bearer <token>` for any of `tokens`.
+    pub fn new(tokens: Vec<String>) -> Self {
+        AuthConfig { tokens, ..Default::default() }
+    }
+
+    /// Mints a random token that authenticates one `GET /download?token=<token>` of the server's
+    /// default files, and resumes of it, for links handed to browsers, which cannot send an
+    /// Authorization header. Whatever files the request names are ignored.
+    pub fn add_one_time_token(&mut self) -> io::Result<String> {
+        use base64::Engine;
+        let mut bytes = [0u8; 18];
+        getrandom::getrandom(&mut bytes).map_err(io::Error::other)?;
+        let token = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes);
+        let uses = ShareLinkUses::until(u64::MAX);
+        self.one_time_tokens.lock().unwrap_or_else(|e| e.into_inner()).push(OneTimeToken { token: token.clone(), uses });
+        Ok(token)
+    }
+
+    /// Signs share links with `secret`, so links minted before a restart keep working.
+    /// Without one, the server picks a random key at startup.
+    pub fn with_link_secret(mut self, secret: &str) -> Self {
+        self.link_key = Some(secret.as_bytes().to_vec());
+        self
+    }
+
+    /// Also accepts HTTP Basic credentials for the users in an htpasswd-style file.
+    pub fn with_users_file(mut self, path: &Path) -> io::Result<Self> {
+        self.users.extend(Self::load_users(path)?);
+        Ok(self)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty() || !self.users.is_empty()
+    }
+
+    /// Takes over what `previous` has handed out, for a reload: unused one-time tokens, share
+    /// link use counts and, without a link secret of its own, the key share links are signed with.
+    fn inherit(&mut self, previous: &AuthConfig) {
+        let minted = std::mem::take(&mut *self.one_time_tokens.lock().unwrap_or_else(|e| e.into_inner()));
+        previous.one_time_tokens.lock().unwrap_or_else(|e| e.into_inner()).extend(minted);
+        self.one_time_tokens = previous.one_time_tokens.clone();
+        self.link_uses = previous.link_uses.clone();
+        if self.link_key.is_none() {
+            self.link_key = previous.link_key.clone();
+        }
+    }
+
+    pub fn token_count(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn user_count(&self) -> usize {
+        self.users.len()
+    }
+
+    /// How many users see a directory of their own instead of the server root.
+    pub fn own_root_count(&self) -> usize {
+        self.users.values().filter(|(_, account)| account.root.is_some()).count()
+    }
+
+    /// The users with a root of their own, and that root.
+    fn own_roots(&self) -> impl Iterator<Item = (&str, &Path)> {
+        self.users.iter().filter_map(|(user, (_, account))| Some((user.as_str(), account.root.as_deref()?)))
+    }
+
+    /// Reads an htpasswd-style file: one `user:password` pair per line, where the password is
+    /// either plain text or a bcrypt hash (`htpasswd -B`), optionally followed by
+    /// whitespace-separated `root=<dir>` and `access=read-only|read-write` settings. Blank lines
+    /// and `#` comments are ignored.
+    fn load_users(path: &Path) -> io::Result<std::collections::HashMap<String, (PasswordEntry, Account)>> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut users = std::collections::HashMap::new();
+
+        for (line_number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: {}", path.display(), line_number + 1, message));
+            // Settings are taken off the end, so plain passwords may still contain spaces
+            let (mut credentials, mut account) = (line, Account::default());
+            while let Some((rest, setting)) = credentials.rsplit_once([' ', '\t']) {
+                if let Some(root) = setting.strip_prefix("root=") {
+                    account.root = Some(PathBuf::from(root));
+                } else if let Some(access) = setting.strip_prefix("access=") {
+                    account.read_only = match access {
+                        "read-only" | "ro" => true,
+                        "read-write" | "rw" => false,
+                        _ => return Err(invalid(format!("unknown access {}; use read-only or read-write", access))),
+                    };
+                } else {
+                    break;
+                }
+                credentials = rest.trim_end();
+            }
+
+            let (user, password) = credentials.split_once(':').ok_or_else(|| invalid("expected user:password".to_string()))?;
+            // Users with their own root upload into a directory named after them
+            if account.root.is_some() && !matches!(Path::new(user).components().collect::<Vec<_>>()[..], [Component::Normal(_)]) {
+                return Err(invalid(format!("user {} cannot have a root of their own: the name must be usable as a directory name", user)));
+            }
+
+            let entry = if password.starts_with("$2a$") || password.starts_with("$2b$") || password.starts_with("$2y$") {
+                PasswordEntry::Bcrypt(password.to_string())
+            } else if password.starts_with("$apr1$") || password.starts_with("{SHA}") || password.starts_with("$1$") {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("{}:{}: unsupported hash for user {}; use bcrypt (htpasswd -B) or plain text", path.display(), line_number + 1, user)));
+            } else {
+                PasswordEntry::Plain(password.to_string())
+            };
+
+            users.insert(user.to_string(), (entry, account));
+        }
+
+        Ok(users)
+    }
+
+    /// Checks the Authorization header, returning who the request authenticated as.
+    async fn authenticate(&self, headers: &HeaderMap) -> Option<AuthenticatedUser> {
+        let value = headers.get(axum::http::header::AUTHORIZATION)?.to_str().ok()?;
+        let (scheme, credentials) = value.split_once(' ')?;
+        let credentials = credentials.trim();
+
+        if scheme.eq_ignore_ascii_case("Bearer") {
+            return self.tokens.iter()
+                .any(|token| constant_time_eq(token.as_bytes(), credentials.as_bytes()))
+                .then(|| AuthenticatedUser::anonymous("token"));
+        }
+
+        if scheme.eq_ignore_ascii_case("Basic") {
+            use base64::Engine;
+            let decoded = base64::engine::general_purpose::STANDARD.decode(credentials).ok()?;
+            let decoded = String::from_utf8(decoded).ok()?;
+            let (user, password) = decoded.split_once(':')?;
+
+            let (entry, account) = self.users.get(user)?;
+            let valid = match entry {
+                PasswordEntry::Plain(expected) => constant_time_eq(expected.as_bytes(), password.as_bytes()),
+                PasswordEntry::Bcrypt(hash) => {
+                    // bcrypt is deliberately slow, so keep it off the async workers
+                    let (hash, password) = (hash.clone(), password.to_string());
+                    tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash).unwrap_or(false))
+                        .await
+                        .unwrap_or(false)
+                }
+            };
+            return valid.then(|| AuthenticatedUser(user.to_string(), account.clone()));
+        }
+
+        None
+    }
+
+    /// Checks the one-time token in a `GET`/`HEAD /download` query, granting the default files.
+    /// Its first GET uses it up; afterwards only resumes of that download get through, as with
+    /// a share link allowing one use.
+    fn redeem_one_time_token(&self, request: &axum::extract::Request) -> Result<Option<LinkGrant>, (StatusCode, String)> {
+        let method = request.method();
+        if (method != axum::http::Method::GET && method != axum::http::Method::HEAD) || request.uri().path() != "/download" {
+            return Ok(None);
+        }
+        let Some(token) = request.uri().query().and_then(|query| query.split('&').find_map(|pair| pair.strip_prefix("token="))) else {
+            return Ok(None);
+        };
+
+        let mut tokens = self.one_time_tokens.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(minted) = tokens.iter_mut().find(|candidate| constant_time_eq(candidate.token.as_bytes(), token.as_bytes())) else {
+            return Ok(None);
+        };
+        let limited = match method == axum::http::Method::GET {
+            true => Some(LimitedUse { of: UseOf::OneTimeToken(minted.token.clone()), max_uses: 1, charged: minted.uses.charge(request, 1)? }),
+            false => None,
+        };
+        Ok(Some(LinkGrant { holder: "one-time token", files: Vec::new(), limited }))
+    }
+
+    /// Picks a random key for share links unless [`AuthConfig::with_link_secret`] set one.
+    fn ensure_link_key(&mut self) -> io::Result<()> {
+        if self.link_key.is_none() {
+            let mut key = vec![0u8; 32];
+            getrandom::getrandom(&mut key).map_err(io::Error::other)?;
+            self.link_key = Some(key);
+        }
+        Ok(())
+    }
+
+    /// Signs a link token granting downloads of `files` until `expires_at` (seconds since the
+    /// Unix epoch), at most `max_uses` times when given.
+    fn mint_share_link(&self, files: Vec<String>, expires_at: u64, max_uses: Option<u32>) -> io::Result<String> {
+        use base64::Engine;
+        use hmac::Mac;
+        let key = self.link_key.as_deref()
+            .ok_or_else(|| io::Error::other("No key to sign share links with"))?;
+
+        let mut id = [0u8; 12];
+        getrandom::getrandom(&mut id).map_err(io::Error::other)?;
+        let claims = ShareLinkClaims {
+            id: id.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            files,
+            expires_at,
+            max_uses,
+        };
+
+        let engine = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        let payload = engine.encode(serde_json::to_vec(&claims)?);
+        let signature = engine.encode(share_link_signature(key, &payload).finalize().into_bytes());
+        Ok(format!("{}.{}", payload, signature))
+    }
+
+    /// Checks the share link in a `GET`/`HEAD /download` query, returning the files it grants.
+    /// Every GET of a link with a use limit counts as a use, except a single-range request
+    /// whose If-Range names an archive already downloaded with it; see [`AuthConfig::settle_use`].
+    fn redeem_share_link(&self, request: &axum::extract::Request) -> Result<Option<LinkGrant>, (StatusCode, String)> {
+        use base64::Engine;
+        use hmac::Mac;
+
+        let method = request.method();
+        if (method != axum::http::Method::GET && method != axum::http::Method::HEAD) || request.uri().path() != "/download" {
+            return Ok(None);
+        }
+        let Some(token) = request.uri().query().and_then(|query| query.split('&').find_map(|pair| pair.strip_prefix("token="))) else {
+            return Ok(None);
+        };
+        // One-time tokens have no signature part
+        let (Some((payload, signature)), Some(key)) = (token.split_once('.'), self.link_key.as_deref()) else {
+            return Ok(None);
+        };
+
+        let invalid = || (StatusCode::FORBIDDEN, "Invalid share link".to_string());
+        let engine = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        let signature = engine.decode(signature).map_err(|_| invalid())?;
+        share_link_signature(key, payload).verify_slice(&signature).map_err(|_| invalid())?;
+        let claims: ShareLinkClaims = engine.decode(payload).ok()
+            .and_then(|payload| serde_json::from_slice(&payload).ok())
+            .ok_or_else(invalid)?;
+
+        let now = unix_time_now();
+        if claims.expires_at <= now {
+            return Err((StatusCode::FORBIDDEN, "This share link has expired".to_string()));
+        }
+
+        let limited = match (claims.max_uses, method == axum::http::Method::GET) {
+            (Some(max_uses), true) => {
+                let mut uses = self.link_uses.lock().unwrap_or_else(|e| e.into_inner());
+                uses.retain(|_, link| link.expires_at > now);
+                let link = uses.entry(claims.id.clone()).or_insert_with(|| ShareLinkUses::until(claims.expires_at));
+                let charged = link.charge(request, max_uses)?;
+                Some(LimitedUse { of: UseOf::ShareLink(claims.id), max_uses, charged })
+            }
+            _ => None,
+        };
+
+        Ok(Some(LinkGrant { holder: "share link", files: claims.files, limited }))
+    }
+
+    /// Finishes counting a request let through by a share link with a use limit or a one-time
+    /// token, now that its answer is known; see [`ShareLinkUses::settle`].
+    fn settle_use(&self, limited: LimitedUse, response: Response) -> Response {
+        match &limited.of {
+            UseOf::ShareLink(id) => match self.link_uses.lock().unwrap_or_else(|e| e.into_inner()).get_mut(id) {
+                Some(link) => link.settle(&limited, response),
+                None => response,
+            },
+            UseOf::OneTimeToken(token) => match self.one_time_tokens.lock().unwrap_or_else(|e| e.into_inner()).iter_mut().find(|minted| &minted.token == token) {
+                Some(minted) => minted.uses.settle(&limited, response),
+                None => response,
+            },
+        }
+    }
+}
+
+/// Compares two secrets without short-circuiting on the first differing byte.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Middleware rejecting requests without valid credentials when auth is configured.
+async fn require_auth(
+    State(state): State<AppState>,
+    mut request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    if !state.auth.is_enabled() {
+        return next.run(request).await;
+    }
+
+    let grant = match state.auth.redeem_share_link(&request) {
+        Ok(None) => state.auth.redeem_one_time_token(&request),
+        grant => grant,
+    };
+    match grant {
+        Ok(Some(grant)) => {
+            *request.uri_mut() = share_link_uri(request.uri(), &grant.files);
+            request.extensions_mut().insert(AuthenticatedUser::anonymous(grant.holder));
+            let response = next.run(request).await;
+            return match grant.limited {
+                Some(limited) => state.auth.settle_use(limited, response),
+                None => response,
+            };
+        }
+        Ok(None) => {}
+        Err((status, message)) => {
+            debug!("Responding with {}: {}", status, message);
+            return (status, message).into_response();
+        }
+    }
+
+    // Users with a root of their own were authenticated on the way to it
+    let user = match request.extensions().get::<AuthenticatedUser>().cloned() {
+        Some(user) => Some(user),
+        None => state.auth.authenticate(request.headers()).await,
+    };
+    match user {
+        Some(user) => {
+            if let Some((status, message)) = account_refusal(&user, &request) {
+                debug!("Responding with {} to {}: {}", status, user.0, message);
+                return (status, message).into_response();
+            }
+            request.extensions_mut().insert(user);
+            next.run(request).await
+        }
+        None => {
+            debug!("Responding with 401 Unauthorized: {} {}", request.method(), request.uri().path());
+            (
+                StatusCode::UNAUTHORIZED,
+                [(axum::http::header::WWW_AUTHENTICATE, "Basic realm=\"file-archive-server\", Bearer")],
+                "Authentication required",
+            ).into_response()
+        }
+    }
+}
+
+/// Why `user`'s account does not allow `request`, if it does not.
+fn account_refusal(user: &AuthenticatedUser, request: &axum::extract::Request) -> Option<(StatusCode, String)> {
+    let (method, path) = (request.method(), request.uri().path());
+    let under = |prefix: &str| path == prefix || path.starts_with(&format!("{}/", prefix));
+    let writes = !matches!(method.as_str(), "GET" | "HEAD" | "OPTIONS" | "PROPFIND")
+        && (under("/upload") || under("/files") || under(DAV_PREFIX));
+    if user.1.read_only && writes {
+        return Some((StatusCode::FORBIDDEN, format!("{} has read-only access", user.0)));
+    }
+    // A share link is redeemed without credentials, against the server root
+    if user.1.root.is_some() && path == "/share" {
+        return Some((StatusCode::FORBIDDEN, "Share links are not available to users with a root of their own".to_string()));
+    }
+    None
+}
+
+// --- SHARE LINKS ---
+
+/// The longest a share link can stay valid.
+pub const MAX_SHARE_LINK_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Query parameters a share link's holder may still choose; everything else comes from the link.
+const SHARE_LINK_PARAMS: &[&str] = &["format", "compression", "level", "flatten", "part"];
+
+/// Body of `POST /share`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ShareRequest {
+    /// Paths (relative to the root) the link grants, exactly as they would be passed as `files=`
+    pub files: Vec<String>,
+    /// Seconds until the link stops working
+    pub expires_in: u64,
+    /// How many downloads the link allows; unlimited until it expires when omitted
+    pub max_uses: Option<u32>,
+}
+
+/// A minted share link, as returned by `POST /share`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ShareLink {
+    pub token: String,
+    /// Path and query of the download, relative to the server's base URL
+    pub url: String,
+    /// Seconds since the Unix epoch
+    pub expires_at: u64,
+    pub max_uses: Option<u32>,
+}
+
+/// What a share link token carries, signed by the server.
+#[derive(Serialize, Deserialize)]
+struct ShareLinkClaims {
+    id: String,
+    files: Vec<String>,
+    expires_at: u64,
+    max_uses: Option<u32>,
+}
+
+/// What a valid share link or one-time token lets a request download.
+struct LinkGrant {
+    /// Who the request is logged as
+    holder: &'static str,
+    /// The files it may download; none for the default files
+    files: Vec<String>,
+    /// Set for a GET counted against a use limit
+    limited: Option<LimitedUse>,
+}
+
+/// A request counted, or not yet counted, against a use limit.
+struct LimitedUse {
+    of: UseOf,
+    max_uses: u32,
+    /// Whether it was counted already, or only resumes a download that was
+    charged: bool,
+}
+
+/// Whose use limit a request counts against.
+enum UseOf {
+    /// A share link, by ID
+    ShareLink(String),
+    OneTimeToken(String),
+}
+
+/// A token minted by [`AuthConfig::add_one_time_token`], with the download it allows.
+#[derive(Debug)]
+struct OneTimeToken {
+    token: String,
+    uses: ShareLinkUses,
+}
+
+#[derive(Debug)]
+struct ShareLinkUses {
+    expires_at: u64,
+    count: u32,
+    /// ETags of the archives the counted downloads were answered with
+    etags: Vec<String>,
+}
+
+impl ShareLinkUses {
+    fn until(expires_at: u64) -> Self {
+        ShareLinkUses { expires_at, count: 0, etags: Vec::new() }
+    }
+
+    /// Counts `request` as a use unless it asks for a single range with an If-Range naming an
+    /// archive a counted download got, returning whether it did. Several ranges in one request
+    /// are refused, as they could piece together a whole archive.
+    fn charge(&mut self, request: &axum::extract::Request, max_uses: u32) -> Result<bool, (StatusCode, String)> {
+        let header = |name| request.headers().get(name).and_then(|value| value.to_str().ok()).map(str::trim);
+        let range = header(axum::http::header::RANGE);
+        if range.is_some_and(|range| range.contains(',')) {
+            return Err((StatusCode::RANGE_NOT_SATISFIABLE,
+                "A share link with a use limit serves one range per request".to_string()));
+        }
+        let if_range = header(axum::http::header::IF_RANGE);
+        if let (Some(_), Some(if_range)) = (range, if_range) {
+            if self.etags.iter().any(|etag| etag == if_range) {
+                return Ok(false);
+            }
+        }
+        if self.count >= max_uses {
+            return Err((StatusCode::GONE, "This link has been used up".to_string()));
+        }
+        self.count += 1;
+        Ok(true)
+    }
+
+    /// Finishes counting a request now that its answer is known. A resume the server answered
+    /// in full (because the files changed) is counted after all, and refused if no use is left
+    /// by then; the ETag of every counted download is remembered, so it can be resumed later.
+    fn settle(&mut self, limited: &LimitedUse, response: Response) -> Response {
+        if !limited.charged && response.status() != StatusCode::PARTIAL_CONTENT && response.status().is_success() {
+            if self.count >= limited.max_uses {
+                return (StatusCode::GONE, "This link has been used up").into_response();
+            }
+            self.count += 1;
+        }
+        if let Some(etag) = response.headers().get(axum::http::header::ETAG).and_then(|etag| etag.to_str().ok()) {
+            if !self.etags.iter().any(|known| known == etag) {
+                self.etags.push(etag.to_string());
+            }
+        }
+        response
+    }
+}
+
+fn share_link_signature(key: &[u8], payload: &str) -> hmac::Hmac<sha2::Sha256> {
+    use hmac::Mac;
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+fn unix_time_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Rewrites a `/download` URI to ask for exactly the files a share link grants, dropping any
+/// parameter that could reach beyond them, such as other `files=` or a `job=`.
+fn share_link_uri(uri: &axum::http::Uri, files: &[String]) -> axum::http::Uri {
+    let kept = uri.query().unwrap_or("").split('&')
+        .filter(|pair| SHARE_LINK_PARAMS.contains(&pair.split('=').next().unwrap_or("")))
+        .map(str::to_string);
+    let files = files.iter().map(|file| format!("files={}", urlencoding::encode(file)));
+    let query: Vec<String> = files.chain(kept).collect();
+
+    format!("{}?{}", uri.path(), query.join("&")).parse().unwrap_or_else(|_| uri.clone())
+}
+
+/// Mints a share link for some files (`POST /share`), for handing a download to someone
+/// without credentials.
+async fn share_handler(
+    State(state): State<AppState>,
+    Json(request): Json<ShareRequest>,
+) -> Result<Json<ShareLink>, (StatusCode, String)> {
+    if !state.auth.is_enabled() {
+        return Err((StatusCode::BAD_REQUEST,
+            "Share links need authentication (--auth-token or --auth-file); without it every download is open".to_string()));
+    }
+    if request.files.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "A share link needs at least one file".to_string()));
+    }
+    if request.expires_in == 0 || request.expires_in > MAX_SHARE_LINK_LIFETIME.as_secs() {
+        return Err((StatusCode::BAD_REQUEST,
+            format!("Share links can last between 1 second and {} days", MAX_SHARE_LINK_LIFETIME.as_secs() / 86_400)));
+    }
+    if request.max_uses == Some(0) {
+        return Err((StatusCode::BAD_REQUEST, "A share link needs to allow at least one use".to_string()));
+    }
+
+    // Fail now rather than when the link is followed
+    let params = ArchiveParams { files: request.files.clone(), ..Default::default() };
+    resolve_requested_paths(&state, &params).await?;
+
+    let expires_at = unix_time_now() + request.expires_in;
+    let token = state.auth.mint_share_link(request.files, expires_at, request.max_uses)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Could not mint share link: {}", e)))?;
+    info!("Minted share link expiring at {} (max uses: {:?})", expires_at, request.max_uses);
+
+    Ok(Json(ShareLink {
+        url: format!("/download?token={}", token),
+        token,
+        expires_at,
+        max_uses: request.max_uses,
+    }))
+}
+
+// --- STREAMING ---
+
+/// Size of the chunks handed from the archiving thread to the response body.
+pub(crate) const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A blocking `Write` adapter that forwards archive bytes to an async response body in chunks.
+struct ChannelWriter {
+    tx: tokio::sync::mpsc::Sender<io::Result<bytes::Bytes>>,
+    buffer: Vec<u8>,
+    /// With --cache-dir, the archive is also written there, and finished even when the client
+    /// goes away, so that resuming it later is served the same bytes
+    cache: Option<CacheWriter>,
+    client_gone: bool,
+}
+
+impl ChannelWriter {
+    fn send_buffer(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(STREAM_CHUNK_SIZE));
+        if let Some(cache) = &mut self.cache {
+            cache.write_all(&chunk)?;
+        }
+        if self.client_gone {
+            return Ok(());
+        }
+        match self.tx.blocking_send(Ok(bytes::Bytes::from(chunk))) {
+            Ok(()) => Ok(()),
+            Err(_) if self.cache.is_some() => {
+                debug!("Client disconnected, finishing the streamed archive for the cache");
+                self.client_gone = true;
+                Ok(())
+            }
+            Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "Client disconnected")),
+        }
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(data);
+        if self.buffer.len() >= STREAM_CHUNK_SIZE {
+            self.send_buffer()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buffer()
+    }
+}
+
+/// Starts building a tarball on a blocking thread and returns a body that yields it as it is produced.
+/// Only a couple of chunks are ever held in memory; a slow client simply slows the archiver down.
+/// With `cache`, the archive is stored there under `etag` too once it is complete. The `turn`
+/// is given up once the build ends.
+fn stream_tar(
+    entries: Vec<ArchiveEntry>,
+    options: ArchiveOptions,
+    key: Option<SealingKey>,
+    cache: Option<(std::sync::Arc<ArchiveCache>, String)>,
+    turn: BuildSlot,
+) -> Body {
+    let (tx, rx) = tokio::sync::mpsc::channel(4);
+
+    tokio::task::spawn_blocking(move || {
+        let _turn = turn;
+        let cache = cache.and_then(|(cache, etag)| match cache.writer(&etag) {
+            Ok(writer) => Some(writer),
+            Err(e) => {
+                warn!("Could not cache archive: {}", e);
+                None
+            }
+        });
+        let writer = ChannelWriter { tx: tx.clone(), buffer: Vec::with_capacity(STREAM_CHUNK_SIZE), cache, client_gone: false };
+        let result = match key {
+            Some(key) => key.writer(writer)
+                .and_then(|writer| write_tar(&entries, &options, writer))
+                .and_then(|writer| writer.finish()),
+            None => write_tar(&entries, &options, writer),
+        };
+        let result = result.and_then(|mut writer| writer.flush().map(|()| writer));
+
+        match result {
+            Ok(writer) => {
+                if let Some(Err(e)) = writer.cache.map(CacheWriter::commit) {
+                    warn!("Could not cache archive: {}", e);
+                }
+            }
+            Err(e) => {
+                error!("Error while streaming archive: {:?}", e);
+                // Surfacing the error aborts the response so the client sees a failed transfer
+                let _ = tx.blocking_send(Err(e));
+            }
+        }
+    });
+
+    let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|item| (item, rx))
+    });
+    Body::from_stream(stream)
+}
+
+/// An archive being sent: held in memory when it was just built (or by a job), or the cached
+/// copy on disk, which is read as it goes out so that even a multi-gigabyte archive only ever
+/// has a few chunks of it in memory.
+enum ServedArchive {
+    Memory(bytes::Bytes),
+    /// `length` bytes of a cache file from `offset` on. The file is open already, so the cache
+    /// evicting it meanwhile doesn't cut the response short
+    Cached { file: std::fs::File, offset: u64, length: u64 },
+}
+
+/// A piece of a response body: bytes of its own, or a range of the archive being sent.
+enum BodyPiece {
+    Bytes(bytes::Bytes),
+    Range { start: u64, length: u64 },
+}
+
+impl BodyPiece {
+    fn len(&self) -> u64 {
+        match self {
+            BodyPiece::Bytes(bytes) => bytes.len() as u64,
+            BodyPiece::Range { length, .. } => *length,
+        }
+    }
+}
+
+impl ServedArchive {
+    fn len(&self) -> u64 {
+        match self {
+            ServedArchive::Memory(data) => data.len() as u64,
+            ServedArchive::Cached { length, .. } => *length,
+        }
+    }
+
+    /// The `length` bytes from `start` on, which then count as the whole archive.
+    fn slice(self, start: u64, length: u64) -> Self {
+        match self {
+            ServedArchive::Memory(data) => ServedArchive::Memory(data.slice(start as usize..(start + length) as usize)),
+            ServedArchive::Cached { file, offset, .. } => ServedArchive::Cached { file, offset: offset + start, length },
+        }
+    }
+
+    /// A body made of `pieces`, in order.
+    fn body(self, pieces: Vec<BodyPiece>) -> Body {
+        let (mut file, offset) = match self {
+            ServedArchive::Memory(data) => {
+                let piece = move |piece| match piece {
+                    BodyPiece::Bytes(bytes) => bytes,
+                    BodyPiece::Range { start, length } => data.slice(start as usize..(start + length) as usize),
+                };
+                // Slicing copies nothing, so the body is simply the list of slices
+                let chunks: Vec<_> = pieces.into_iter().map(piece).collect();
+                return match <[bytes::Bytes; 1]>::try_from(chunks) {
+                    Ok([chunk]) => Body::from(chunk),
+                    Err(chunks) => Body::from_stream(futures_util::stream::iter(chunks.into_iter().map(Ok::<_, io::Error>))),
+                };
+            }
+            ServedArchive::Cached { file, offset, .. } => (file, offset),
+        };
+
+        let (tx, rx) = tokio::sync::mpsc::channel::<io::Result<bytes::Bytes>>(4);
+        tokio::task::spawn_blocking(move || {
+            use std::io::{Read, Seek};
+
+            let send = |chunk: bytes::Bytes| tx.blocking_send(Ok(chunk)).is_ok();
+            let result = (|| {
+                for piece in pieces {
+                    let (start, length) = match piece {
+                        BodyPiece::Bytes(bytes) => match send(bytes) {
+                            true => continue,
+                            false => return Ok(()),
+                        },
+                        BodyPiece::Range { start, length } => (start, length),
+                    };
+                    file.seek(io::SeekFrom::Start(offset + start))?;
+                    let mut left = length;
+                    while left > 0 {
+                        let mut chunk = vec![0; left.min(STREAM_CHUNK_SIZE as u64) as usize];
+                        file.read_exact(&mut chunk)?;
+                        left -= chunk.len() as u64;
+                        if !send(bytes::Bytes::from(chunk)) {
+                            return Ok(());
+                        }
+                    }
+                }
+                Ok(())
+            })();
+
+            if let Err(e) = result {
+                error!("Error while sending a cached archive: {}", e);
+                let _ = tx.blocking_send(Err(e));
+            }
+        });
+
+        let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|item| (item, rx))
+        });
+        Body::from_stream(stream)
+    }
+}
+
+// --- RANGE REQUESTS ---
+
+/// More ranges than this in one request are answered with the whole archive, since
+/// serving them would cost more than the full body.
+const MAX_BYTE_RANGES: usize = 64;
+
+/// How a request's Range header should be answered.
+pub(crate) enum RangeRequest {
+    /// No usable Range: send the whole archive.
+    Full,
+    /// Sorted, non-overlapping ranges, each clamped to the archive.
+    Partial(Vec<http_range::HttpRange>),
+    /// Every range starts past the end of the archive, or asks for its last 0 bytes.
+    Unsatisfiable,
+}
+
+/// Parses a Range header per RFC 7233. Range units other than `bytes` are ignored, as the
+/// RFC requires, while syntactically broken byte ranges are rejected with 400. Besides
+/// `start-end`, a range may be open (`start-`, to the end) or a suffix (`-n`, the last `n`
+/// bytes, or all of them when there are fewer). Overlapping and adjacent ranges are merged
+/// so no byte is sent twice.
+pub(crate) fn parse_range_header(value: &HeaderValue, size: u64) -> Result<RangeRequest, (StatusCode, String)> {
+    let malformed = || (StatusCode::BAD_REQUEST, format!("Malformed Range header: {:?}", value));
+
+    let (unit, specs) = value.to_str().ok()
+        .and_then(|header| header.split_once('='))
+        .ok_or_else(malformed)?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return Ok(RangeRequest::Full);
+    }
+
+    let mut ranges = match http_range::HttpRange::parse(&format!("bytes={}", specs), size) {
+        Ok(ranges) if ranges.is_empty() => return Err(malformed()),
+        Ok(ranges) => ranges,
+        Err(http_range::HttpRangeParseError::NoOverlap) => return Ok(RangeRequest::Unsatisfiable),
+        Err(http_range::HttpRangeParseError::InvalidRange) => return Err(malformed()),
+    };
+    // A suffix of an empty body comes back as a range of no bytes, which nothing can be sent for
+    ranges.retain(|range| range.length > 0);
+    if ranges.is_empty() {
+        return Ok(RangeRequest::Unsatisfiable);
+    }
+
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<http_range::HttpRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.start + last.length => {
+                let end = (last.start + last.length).max(range.start + range.length);
+                last.length = end - last.start;
+            }
+            _ => merged.push(range),
+        }
+    }
+
+    if merged.len() > MAX_BYTE_RANGES {
+        return Ok(RangeRequest::Full);
+    }
+    Ok(RangeRequest::Partial(merged))
+}
+
+/// Whether a Range request may be honored: true unless an If-Range header names a different
+/// ETag. Only strong ETags match; Last-Modified has one-second resolution, so dates never do.
+pub(crate) fn if_range_matches(request_headers: &HeaderMap, etag: &str) -> bool {
+    match request_headers.get(axum::http::header::IF_RANGE) {
+        Some(if_range) => if_range.to_str().map(|value| value.trim() == etag).unwrap_or(false),
+        None => true,
+    }
+}
+
+/// Whether a GET or HEAD may be answered with 304 Not Modified (RFC 7232): its If-None-Match
+/// lists `etag` (or is `*`), or, without an If-None-Match, nothing has changed since its
+/// If-Modified-Since. Dates are compared to the second, the resolution HTTP dates have.
+pub(crate) fn not_modified(request_headers: &HeaderMap, etag: &str, last_modified: std::time::SystemTime) -> bool {
+    if let Some(if_none_match) = request_headers.get(axum::http::header::IF_NONE_MATCH) {
+        let Ok(tags) = if_none_match.to_str() else { return false };
+        // Caches may send back a weak form of the tag, which still names the same archive
+        let strip_weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+        return tags.split(',').any(|tag| tag.trim() == "*" || strip_weak(tag) == strip_weak(etag));
+    }
+    let unix_secs = |time: std::time::SystemTime| time.duration_since(std::time::UNIX_EPOCH).map_or(0, |duration| duration.as_secs());
+    request_headers.get(axum::http::header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok())
+        .is_some_and(|since| unix_secs(last_modified) <= unix_secs(since))
+}
+
+/// The 304 answer to a conditional request, carrying the validators a 200 would.
+pub(crate) fn not_modified_response(etag: &str, last_modified: std::time::SystemTime) -> Response {
+    let mut response = StatusCode::NOT_MODIFIED.into_response();
+    if let Ok(etag) = HeaderValue::try_from(etag) {
+        response.headers_mut().insert(axum::http::header::ETAG, etag);
+    }
+    if let Ok(modified) = HeaderValue::try_from(httpdate::fmt_http_date(last_modified)) {
+        response.headers_mut().insert(axum::http::header::LAST_MODIFIED, modified);
+    }
+    response
+}
+
+/// A multipart boundary that cannot be predicted from the archive contents.
+fn byteranges_boundary(etag: &str) -> String {
+    use sha2::{Digest, Sha256};
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or(0);
+    let digest = Sha256::new().chain_update(etag.as_bytes()).chain_update(now.to_le_bytes()).finalize();
+    digest.iter().take(12).map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Lays out a multipart/byteranges body for an archive of `size` bytes, with the range data
+/// left to be taken from the archive rather than copied.
+fn byteranges_body(
+    size: u64,
+    ranges: &[http_range::HttpRange],
+    content_type: &str,
+    boundary: &str,
+) -> Vec<BodyPiece> {
+    let mut parts = Vec::with_capacity(ranges.len() * 2 + 1);
+
+    for range in ranges {
+        let end = range.start + range.length - 1;
+        parts.push(BodyPiece::Bytes(bytes::Bytes::from(format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+            boundary, content_type, range.start, end, size
+        ))));
+        parts.push(BodyPiece::Range { start: range.start, length: range.length });
+    }
+    parts.push(BodyPiece::Bytes(bytes::Bytes::from(format!("\r\n--{}--\r\n", boundary))));
+
+    parts
+}
+
+// --- AXUM HANDLER (The core of the server) ---
+
+/// Resolves the files a request asked for against the server root, falling back to
+/// the default set given at startup when the request names none. Requested paths may be
+/// glob patterns, which expand to every matching file.
+/// Client-requested paths are confined to the root; the startup defaults were chosen by the
+/// operator and are used as given.
+fn requested_paths(state: &AppState, files: &[String]) -> Result<Vec<PathBuf>, (StatusCode, String)> {
+    if files.is_empty() {
+        if state.initial_files.is_empty() {
+            return Err((StatusCode::BAD_REQUEST, "No files requested: pass one or more ?files=<path> parameters".to_string()));
+        }
+        return Ok(state.initial_files.iter().map(|file| state.root.join(file)).collect());
+    }
+
+    let mut paths = Vec::new();
+    for requested in files {
+        // Windows clients may separate with backslashes; a file whose name has one is tried first
+        let file = match crate::paths::forward_slashes(requested) {
+            Some(slashed) if state.storage.metadata(&state.root.join(requested)).is_err() => slashed,
+            _ => requested.clone(),
+        };
+        if is_glob_pattern(&file) && state.storage.metadata(&state.root.join(&file)).is_err() {
+            if !state.storage.is_local() {
+                return Err((StatusCode::BAD_REQUEST, format!("Glob patterns need a local root: {}", file)));
+            }
+            paths.extend(expand_glob(&state.storage, &state.root, &file, &state.exclude)?);
+        } else {
+            paths.push(resolve_in_root(&state.storage, &state.root, &file)?);
+        }
+    }
+    Ok(paths)
+}
+
+/// The paths an archive request names: its `files=`, those of its `preset=`, or none for the
+/// default files.
+fn requested_files(state: &AppState, params: &ArchiveParams) -> Result<Vec<String>, (StatusCode, String)> {
+    match &params.preset {
+        None => Ok(params.files.clone()),
+        Some(_) if !params.files.is_empty() => Err((StatusCode::BAD_REQUEST, "Pass either files= or preset=, not both".to_string())),
+        Some(name) => state.presets.get(name).cloned()
+            .ok_or_else(|| (StatusCode::NOT_FOUND, format!("No preset named {}", name))),
+    }
+}
+
+/// Runs [`requested_paths`] on a blocking thread, as resolving paths may need the storage backend.
+async fn resolve_requested_paths(state: &AppState, params: &ArchiveParams) -> Result<Vec<PathBuf>, (StatusCode, String)> {
+    let (state, files) = (state.clone(), requested_files(state, params)?);
+    tokio::task::spawn_blocking(move || requested_paths(&state, &files)).await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Path resolution task failed".to_string()))?
+}
+
+/// How a request's paths are expanded into archive entries. Followed links must stay inside the
+/// root unless the operator's default files are being archived.
+fn entry_options(state: &AppState, params: &ArchiveParams) -> EntryOptions {
+    EntryOptions {
+        flatten: params.flatten,
+        symlinks: state.symlinks,
+        exclude: state.exclude.clone(),
+        within: (!params.files.is_empty() || params.preset.is_some()).then(|| state.root.clone()),
+        storage: state.storage.clone(),
+        sort: state.sort_entries,
+    }
+}
+
+/// Whether a requested path contains glob metacharacters. A file whose real name contains
+/// them is still matched literally, since that path is checked first.
+fn is_glob_pattern(requested: &str) -> bool {
+    requested.contains(['*', '?', '['])
+}
+
+/// Expands a glob pattern such as `logs/**/*.log` against the root into the regular files it
+/// matches, in sorted order. Patterns that are absolute or climb with `..` are refused before
+/// anything is looked at, so the answer says nothing about files elsewhere; matches reached
+/// through a link out of the root and excluded files are passed over, as search does.
+fn expand_glob(storage: &Storage, root: &Path, pattern: &str, exclude: &ExcludeRules) -> Result<Vec<PathBuf>, (StatusCode, String)> {
+    if Path::new(pattern).components().any(|part| !matches!(part, Component::Normal(_) | Component::CurDir)) {
+        warn!("Rejected pattern outside the root: {}", pattern);
+        return Err((StatusCode::FORBIDDEN, format!("Access denied: {}", pattern)));
+    }
+    let full_pattern = root.join(pattern);
+    let matches = glob::glob(&full_pattern.to_string_lossy())
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid pattern {}: {}", pattern, e.msg)))?;
+
+    let mut paths = Vec::new();
+    for entry in matches {
+        let Ok(path) = entry else { continue };
+        match storage.canonicalize(&path) {
+            Ok(resolved) if resolved.starts_with(root) && path.is_file() && !exclude.is_excluded(&path, false) => paths.push(resolved),
+            _ => continue,
+        }
+    }
+
+    if paths.is_empty() {
+        return Err((StatusCode::NOT_FOUND, format!("No files match {}", pattern)));
+    }
+    Ok(paths)
+}
+
+/// Resolves a client-supplied path against the (canonical) root, following `..` and symlinks,
+/// and refuses anything that ends up outside it.
+pub(crate) fn resolve_in_root(storage: &Storage, root: &Path, requested: &str) -> Result<PathBuf, (StatusCode, String)> {
+    // Backslashes separate paths from Windows clients, unless a file by the literal name exists
+    let resolved = storage.canonicalize(&root.join(requested))
+        .or_else(|e| match crate::paths::forward_slashes(requested) {
+            Some(slashed) => storage.canonicalize(&root.join(slashed)),
+            None => Err(e),
+        })
+        .map_err(|_| (StatusCode::NOT_FOUND, format!("No such file or directory: {}", requested)))?;
+
+    if !resolved.starts_with(root) {
+        warn!("Rejected path outside the root: {}", requested);
+        return Err((StatusCode::FORBIDDEN, format!("Access denied: {}", requested)));
+    }
+
+    Ok(resolved)
+}
+
+/// Confirms that an existing directory, after resolving symlinks, is still inside `base`.
+async fn ensure_within(base: &Path, dir: &Path) -> Result<(), (StatusCode, String)> {
+    let base = tokio::fs::canonicalize(base).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Could not resolve {}: {}", base.display(), e)))?;
+    let resolved = tokio::fs::canonicalize(dir).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Could not resolve {}: {}", dir.display(), e)))?;
+
+    if !resolved.starts_with(&base) {
+        warn!("Rejected upload destination outside the upload directory: {}", dir.display());
+        return Err((StatusCode::FORBIDDEN, format!("Access denied: {}", dir.display())));
+    }
+    Ok(())
+}
+
+/// `Path::exists` without blocking the async workers on a slow disk or network mount.
+async fn path_exists(path: &Path) -> bool {
+    tokio::fs::try_exists(path).await.unwrap_or(false)
+}
+
+/// Reduces a client-supplied file name to its final component, rejecting names
+/// that would refer to a directory.
+fn sanitize_file_name(name: &str) -> Option<String> {
+    let base = name.rsplit(['/', '\\']).next()?.trim();
+    if base.is_empty() || base == "." || base == ".." {
+        None
+    } else {
+        Some(base.to_string())
+    }
+}
+
+/// Refuses a name the server's platform cannot create (a device name such as `NUL` on
+/// Windows), rather than writing to whatever it stands for.
+pub(crate) fn ensure_portable(path: &Path) -> Result<(), (StatusCode, String)> {
+    match crate::paths::unportable_component(path) {
+        Some((part, problem)) => Err((StatusCode::BAD_REQUEST, format!("Invalid name {}: {}", part, problem))),
+        None => Ok(()),
+    }
+}
+
+/// Returns the configured upload directory, or 403 when uploads are disabled.
+fn enabled_upload_dir(state: &AppState) -> Result<&Path, (StatusCode, String)> {
+    state.upload_dir.as_deref()
+        .ok_or((StatusCode::FORBIDDEN, "Uploads are disabled: start the server with --upload-dir".to_string()))
+}
+
+/// Resolves the optional `path=` subdirectory of an upload, which may only descend into the upload directory.
+fn upload_destination(upload_dir: &Path, path: Option<&str>) -> Result<PathBuf, (StatusCode, String)> {
+    match path {
+        Some(path) => {
+            let relative = PathBuf::from(crate::paths::forward_slashes(path).unwrap_or_else(|| path.to_string()));
+            if relative.components().any(|c| !matches!(c, std::path::Component::Normal(_) | std::path::Component::CurDir)) {
+                return Err((StatusCode::BAD_REQUEST, format!("Invalid upload path: {}", path)));
+            }
+            ensure_portable(&relative)?;
+            Ok(upload_dir.join(relative))
+        }
+        None => Ok(upload_dir.to_path_buf()),
+    }
+}
+
+/// Deletes the file it holds when dropped, unless the path has been taken out first.
+pub(crate) struct TempFileGuard(pub(crate) Option<PathBuf>);
+
+impl Drop for TempFileGuard {
+    fn drop(&mut self) {
+        if let Some(path) = self.0.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// The status and message for a multipart body that could not be read.
+fn multipart_error(e: axum::extract::multipart::MultipartError) -> (StatusCode, String) {
+    (body_error_status(&e, e.status()), e.body_text())
+}
+
+/// Accepts multipart/form-data uploads and stores every file field in the upload directory.
+/// Files are written to a hidden temporary name first and renamed once complete.
+async fn upload_handler(
+    State(state): State<AppState>,
+    Query(params): Query<UploadParams>,
+    user: Option<axum::Extension<AuthenticatedUser>>,
+    mut multipart: Multipart,
+) -> Result<Json<Vec<UploadedFile>>, (StatusCode, String)> {
+    // 1. Work out (and create) the destination directory
+    let upload_dir = enabled_upload_dir(&state)?;
+    let dest_dir = upload_destination(upload_dir, params.path.as_deref())?;
+    tokio::fs::create_dir_all(&dest_dir).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Could not create {}: {}", dest_dir.display(), e)))?;
+    ensure_within(upload_dir, &dest_dir).await?;
+
+    // 2. Store each file field, enforcing the size limit across the whole request
+    let mut uploaded = Vec::new();
+    let mut total_size = 0u64;
+
+    while let Some(mut field) = multipart.next_field().await.map_err(multipart_error)? {
+        // Plain form fields carry no file name and are ignored
+        let Some(file_name) = field.file_name().and_then(sanitize_file_name) else {
+            continue;
+        };
+        ensure_portable(Path::new(&file_name))?;
+
+        let final_path = dest_dir.join(&file_name);
+        if !params.overwrite && path_exists(&final_path).await {
+            return Err((StatusCode::CONFLICT, format!("{} already exists (pass ?overwrite=true to replace it)", file_name)));
+        }
+
+        let part_path = dest_dir.join(format!(".{}.upload", file_name));
+        let mut file = tokio::fs::File::create(&part_path).await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Could not create {}: {}", part_path.display(), e)))?;
+        // Removes the partial file on every early return, and also when the request is dropped
+        // midway (client disconnect, or shutdown cutting off the upload)
+        let mut part_guard = TempFileGuard(Some(part_path.clone()));
+
+        let mut size = 0u64;
+        let write_result: Result<(), (StatusCode, String)> = async {
+            while let Some(chunk) = field.chunk().await.map_err(multipart_error)? {
+                size += chunk.len() as u64;
+                if total_size + size > state.max_upload_size {
+                    return Err((StatusCode::PAYLOAD_TOO_LARGE,
+                        format!("Upload exceeds the limit of {} bytes", state.max_upload_size)));
+                }
+                file.write_all(&chunk).await
+                    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Error writing upload: {}", e)))?;
+            }
+            file.flush().await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Error writing upload: {}", e)))
+        }.await;
+
+        write_result?;
+        drop(file);
+
+        tokio::fs::rename(&part_path, &final_path).await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Could not store {}: {}", final_path.display(), e)))?;
+        part_guard.0 = None;
+
+        match &user {
+            Some(axum::Extension(AuthenticatedUser(name, _))) => {
+                info!("Stored upload {} ({} bytes) from {}", final_path.display(), size, name);
+            }
+            None => info!("Stored upload {} ({} bytes)", final_path.display(), size),
+        }
+        total_size += size;
+        uploaded.push(UploadedFile { name: file_name, size });
+    }
+
+    if uploaded.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "No file fields found in the upload".to_string()));
+    }
+
+    Ok(Json(uploaded))
+}
+
+// --- RESUMABLE UPLOADS ---
+//
+// A client opens a session with POST /upload/session?name=..&size=.., then appends chunks with
+// PATCH /upload/session/{id} carrying an `Upload-Offset` header that must equal the number of
+// bytes already received. Session IDs are derived from the destination and size, so re-opening
+// the same upload after a disconnect (or a server restart) picks up the stored offset.
+
+/// Header carrying the byte offset a chunk starts at (and, in responses, the bytes received so far).
+pub const UPLOAD_OFFSET_HEADER: &str = "Upload-Offset";
+/// Directory inside the upload directory that holds in-progress sessions.
+const UPLOAD_SESSIONS_DIR: &str = ".uploads";
+
+/// Query parameters for `POST /upload/session`.
+#[derive(Deserialize, Debug)]
+struct UploadSessionParams {
+    name: String,
+    size: u64,
+    path: Option<String>,
+    #[serde(default)]
+    overwrite: bool,
+}
+
+/// What the server persists about an in-progress upload.
+#[derive(Serialize, Deserialize, Debug)]
+struct UploadSession {
+    name: String,
+    path: Option<String>,
+    size: u64,
+    overwrite: bool,
+}
+
+/// Progress of a resumable upload, returned by every session endpoint.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadStatus {
+    pub id: String,
+    pub offset: u64,
+    pub size: u64,
+    pub complete: bool,
+}
+
+// --- BACKGROUND JOBS ---
+//
+// GET /download?async=1 plans the archive, starts building it and answers 202 with a job ID.
+// GET /progress/{job_id} streams server-sent events: `progress` (a `BuildProgress`) twice a
+// second while the build runs, then `done` or `failed`. GET /download?job={job_id} (and HEAD)
+// serves the finished archive like any other download, Range requests included.
+
+/// How often `/progress` reports on a running build.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The 202 response to `/download?async=1`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JobStarted {
+    pub job_id: String,
+    /// Where to follow the build, relative to the server's base URL
+    pub progress_url: String,
+    /// Where to fetch the archive once it is done
+    pub download_url: String,
+}
+
+/// The `queued` event of `/progress`, sent instead of `progress` while the job waits for a
+/// turn to build.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JobQueued {
+    /// 1 for next in line
+    pub position: usize,
+}
+
+/// The `done` event of `/progress`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JobFinished {
+    pub size: u64,
+    pub download_url: String,
+}
+
+/// The `failed` event of `/progress`: the error the download would have answered with.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JobFailed {
+    pub status: u16,
+    pub message: String,
+}
+
+/// Plans the archive, starts building it in the background and answers 202 Accepted with
+/// the URLs to follow and fetch it at.
+async fn start_archive_job(state: AppState, params: ArchiveParams) -> Result<Response, ArchiveError> {
+    run_pre_archive_cmd(&state, &params).await?;
+    let plan = plan_archive(&state, &params).await?;
+    let started = state.jobs.start(state.max_jobs)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Could not start a job: {}", e)))?;
+    let Some((id, job)) = started else {
+        warn!("{} archive jobs are running already, refusing another with 503", state.max_jobs.unwrap_or_default());
+        return Err(ArchiveError::Busy(JOB_RETRY_AFTER));
+    };
+    info!("Started archive job {} ({} entries)", id, plan.entries.len());
+
+    let started = JobStarted {
+        progress_url: format!("/progress/{}", id),
+        download_url: format!("/download?job={}", id),
+        job_id: id,
+    };
+    tokio::spawn(run_archive_job(state, plan, job));
+
+    let location = HeaderValue::try_from(started.progress_url.as_str())
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((StatusCode::ACCEPTED, [(axum::http::header::LOCATION, location)], Json(started)).into_response())
+}
+
+/// Builds (or fetches from the cache) the archive of a job and records the outcome.
+async fn run_archive_job(state: AppState, mut plan: ArchivePlan, job: std::sync::Arc<Job>) {
+    let entries = std::mem::take(&mut plan.entries);
+    let result = async {
+        let cached = match &state.cache {
+            Some(cache) => read_cached_archive(cache, &plan.etag).await,
+            None => None,
+        };
+        let data = match cached {
+            Some(data) => {
+                let (progress_job, progress_entries) = (job.clone(), entries);
+                let _ = tokio::task::spawn_blocking(move || {
+                    let totals = progress_job.progress.set_totals(&progress_entries);
+                    progress_job.progress.complete();
+                    totals
+                }).await;
+                data
+            }
+            None => {
+                let data = build_archive(&state, entries, plan.options, &plan.etag, Some(&job)).await?;
+                if let Some(cache) = &state.cache {
+                    store_cached_archive(cache.clone(), &plan.etag, data.clone()).await;
+                }
+                data
+            }
+        };
+        remember_checksum(&state, &plan.etag, &data).await;
+        Ok::<_, (StatusCode, String)>(data)
+    }.await;
+
+    match result {
+        Ok(data) => {
+            info!("Archive job finished ({} bytes, {})", data.len(), plan.etag);
+            job.finish(JobState::Done { plan: std::sync::Arc::new(plan), data });
+        }
+        Err((status, message)) => {
+            warn!("Archive job failed: {}", message);
+            job.finish(JobState::Failed { status, message });
+        }
+    }
+}
+
+/// The plan and archive of a finished job, or the error to answer `?job=` with.
+fn finished_job(state: &AppState, id: &str) -> Result<(ArchivePlan, bytes::Bytes), (StatusCode, String)> {
+    let job = state.jobs.get(id)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("No such job (it may have expired): {}", id)))?;
+    match job.state() {
+        JobState::Done { plan, data } => Ok((plan.as_ref().clone(), data)),
+        JobState::Running => Err((StatusCode::CONFLICT, format!("The archive is still being built; follow /progress/{}", id))),
+        JobState::Failed { status, message } => Err((status, message)),
+    }
+}
+
+/// Streams a job's progress as server-sent events until it finishes.
+async fn progress_handler(
+    State(state): State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> Result<Response, (StatusCode, String)> {
+    let job = state.jobs.get(&id).ok_or_else(|| (StatusCode::NOT_FOUND, format!("No such job: {}", id)))?;
+    let updates = job.subscribe();
+
+    let events = futures_util::stream::unfold(Some((job, updates, true)), move |next| {
+        let id = id.clone();
+        async move {
+            let (job, mut updates, first) = next?;
+            if !first && matches!(*updates.borrow(), JobState::Running) {
+                let _ = tokio::time::timeout(PROGRESS_INTERVAL, updates.changed()).await;
+            }
+
+            let progress = match job.queue_position() {
+                Some(position) => axum::response::sse::Event::default().event("queued").json_data(JobQueued { position }).ok()?,
+                None => axum::response::sse::Event::default().event("progress").json_data(job.progress.snapshot()).ok()?,
+            };
+            let outcome = match job.state() {
+                JobState::Running => return Some((vec![progress], Some((job, updates, false)))),
+                JobState::Done { data, .. } => {
+                    let finished = JobFinished { size: data.len() as u64, download_url: format!("/download?job={}", id) };
+                    axum::response::sse::Event::default().event("done").json_data(finished).ok()?
+                }
+                JobState::Failed { status, message } => {
+                    let failed = JobFailed { status: status.as_u16(), message };
+                    axum::response::sse::Event::default().event("failed").json_data(failed).ok()?
+                }
+            };
+            Some((vec![progress, outcome], None))
+        }
+    });
+    let events = events.flat_map(|events| futures_util::stream::iter(events.into_iter().map(Ok::<_, std::convert::Infallible>)));
+
+    Ok(axum::response::sse::Sse::new(events).into_response())
+}
+
+/// Derives a stable session ID from where the upload will land and how big it is.
+fn upload_session_id(session: &UploadSession) -> String {
+    use sha2::{Digest, Sha256};
+
+    let mut hasher = Sha256::new();
+    hasher.update(session.path.as_deref().unwrap_or("").as_bytes());
+    hasher.update([0]);
+    hasher.update(session.name.as_bytes());
+    hasher.update([0]);
+    hasher.update(session.size.to_le_bytes());
+
+    hasher.finalize().iter().take(16).map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Paths of a session's metadata and partial data files.
+fn upload_session_paths(upload_dir: &Path, id: &str) -> (PathBuf, PathBuf) {
+    let sessions_dir = upload_dir.join(UPLOAD_SESSIONS_DIR);
+    (sessions_dir.join(format!("{}.json", id)), sessions_dir.join(format!("{}.part", id)))
+}
+
+/// Loads a session and the number of bytes received so far, or 404 if it doesn't exist.
+async fn load_upload_session(upload_dir: &Path, id: &str) -> Result<(UploadSession, u64), (StatusCode, String)> {
+    // IDs are hex digests; anything else can't name a session file
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err((StatusCode::NOT_FOUND, format!("Unknown upload session: {}", id)));
+    }
+
+    let (meta_path, part_path) = upload_session_paths(upload_dir, id);
+    let meta = tokio::fs::read(&meta_path).await
+        .map_err(|_| (StatusCode::NOT_FOUND, format!("Unknown upload session: {}", id)))?;
+    let session: UploadSession = serde_json::from_slice(&meta)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Corrupt upload session {}: {}", id, e)))?;
+    let offset = tokio::fs::metadata(&part_path).await.map(|m| m.len()).unwrap_or(0);
+
+    Ok((session, offset))
+}
+
+/// Locks serializing the chunks of each upload session, keyed by session ID.
+type UploadLocks = std::sync::Mutex<std::collections::HashMap<String, std::sync::Arc<tokio::sync::Mutex<()>>>>;
+
+/// Waits until no other chunk of session `id` is being written. Locks nobody holds any more are
+/// dropped on the way, so the map only grows with the sessions receiving chunks right now.
+async fn lock_upload_session(locks: &UploadLocks, id: &str) -> tokio::sync::OwnedMutexGuard<()> {
+    let lock = {
+        let mut locks = locks.lock().unwrap_or_else(|e| e.into_inner());
+        locks.retain(|_, lock| std::sync::Arc::strong_count(lock) > 1);
+        locks.entry(id.to_string()).or_default().clone()
+    };
+    lock.lock_owned().await
+}
+
+/// Moves a fully received upload to its destination and forgets the session.
+async fn finish_upload_session(upload_dir: &Path, id: &str, session: &UploadSession) -> Result<PathBuf, (StatusCode, String)> {
+    let dest_dir = upload_destination(upload_dir, session.path.as_deref())?;
+    let final_path = dest_dir.join(&session.name);
+    if !session.overwrite && path_exists(&final_path).await {
+        return Err((StatusCode::CONFLICT, format!("{} already exists", session.name)));
+    }
+
+    let internal = |e: io::Error| (StatusCode::INTERNAL_SERVER_ERROR, format!("Could not store {}: {}", final_path.display(), e));
+    let (meta_path, part_path) = upload_session_paths(upload_dir, id);
+    tokio::fs::create_dir_all(&dest_dir).await.map_err(internal)?;
+    ensure_within(upload_dir, &dest_dir).await?;
+    tokio::fs::rename(&part_path, &final_path).await.map_err(internal)?;
+    let _ = tokio::fs::remove_file(&meta_path).await;
+
+    info!("Completed resumable upload {} ({} bytes)", final_path.display(), session.size);
+    Ok(final_path)
+}
+
+/// Opens (or re-opens) a resumable upload session and reports how much has been received.
+async fn create_upload_session_handler(
+    State(state): State<AppState>,
+    Query(params): Query<UploadSessionParams>,
+) -> Result<(StatusCode, Json<UploadStatus>), (StatusCode, String)> {
+    let upload_dir = enabled_upload_dir(&state)?;
+
+    let name = sanitize_file_name(&params.name)
+        .ok_or((StatusCode::BAD_REQUEST, format!("Invalid file name: {}", params.name)))?;
+    ensure_portable(Path::new(&name))?;
+    if params.size > state.max_upload_size {
+        return Err((StatusCode::PAYLOAD_TOO_LARGE, format!("Upload exceeds the limit of {} bytes", state.max_upload_size)));
+    }
+
+    let dest_dir = upload_destination(upload_dir, params.path.as_deref())?;
+    if !params.overwrite && path_exists(&dest_dir.join(&name)).await {
+        return Err((StatusCode::CONFLICT, format!("{} already exists (pass ?overwrite=true to replace it)", name)));
+    }
+
+    let session = UploadSession { name, path: params.path, size: params.size, overwrite: params.overwrite };
+    let id = upload_session_id(&session);
+    let (meta_path, part_path) = upload_session_paths(upload_dir, &id);
+
+    // An existing session for the same destination and size is resumed as-is
+    if let Ok((_, offset)) = load_upload_session(upload_dir, &id).await {
+        info!("Resuming upload session {} for {} at byte {}", id, session.name, offset);
+        return Ok((StatusCode::OK, Json(UploadStatus { id, offset, size: session.size, complete: false })));
+    }
+
+    let internal = |e: io::Error| (StatusCode::INTERNAL_SERVER_ERROR, format!("Could not create upload session: {}", e));
+    tokio::fs::create_dir_all(upload_dir.join(UPLOAD_SESSIONS_DIR)).await.map_err(internal)?;
+    tokio::fs::File::create(&part_path).await.map_err(internal)?;
+    let meta = serde_json::to_vec(&session).map_err(|e| internal(e.into()))?;
+    tokio::fs::write(&meta_path, meta).await.map_err(internal)?;
+
+    // A zero-byte upload is complete as soon as it starts
+    if session.size == 0 {
+        finish_upload_session(upload_dir, &id, &session).await?;
+        return Ok((StatusCode::CREATED, Json(UploadStatus { id, offset: 0, size: 0, complete: true })));
+    }
+
+    info!("Opened upload session {} for {} ({} bytes)", id, session.name, session.size);
+    Ok((StatusCode::CREATED, Json(UploadStatus { id, offset: 0, size: session.size, complete: false })))
+}
+
+/// Reports how many bytes of an upload session have been received.
+async fn upload_session_status_handler(
+    State(state): State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> Result<Json<UploadStatus>, (StatusCode, String)> {
+    let upload_dir = enabled_upload_dir(&state)?;
+    let (session, offset) = load_upload_session(upload_dir, &id).await?;
+    Ok(Json(UploadStatus { id, offset, size: session.size, complete: false }))
+}
+
+/// Appends one chunk to an upload session. The chunk must start exactly at the current offset.
+async fn upload_chunk_handler(
+    State(state): State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    request_headers: HeaderMap,
+    body: Body,
+) -> Result<Response, (StatusCode, String)> {
+    let upload_dir = enabled_upload_dir(&state)?;
+    load_upload_session(upload_dir, &id).await?;
+
+    // 1. The client's idea of the offset must match ours, otherwise it has to re-sync first.
+    // The offset is read again under the session's lock, so two chunks sent for the same
+    // offset can't both be appended.
+    let claimed_offset: u64 = request_headers.get(UPLOAD_OFFSET_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .ok_or((StatusCode::BAD_REQUEST, format!("Missing or invalid {} header", UPLOAD_OFFSET_HEADER)))?;
+
+    let _appending = lock_upload_session(&state.upload_locks, &id).await;
+    let (session, offset) = load_upload_session(upload_dir, &id).await?;
+    if claimed_offset != offset {
+        return Ok((
+            StatusCode::CONFLICT,
+            [(UPLOAD_OFFSET_HEADER, offset.to_string())],
+            format!("Offset mismatch: server has {} bytes, chunk starts at {}", offset, claimed_offset),
+        ).into_response());
+    }
+
+    // 2. Append the chunk, refusing to grow past the declared size
+    let (_, part_path) = upload_session_paths(upload_dir, &id);
+    let mut file = tokio::fs::OpenOptions::new().append(true).open(&part_path).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Could not open upload session: {}", e)))?;
+
+    let mut received = offset;
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| (body_error_status(&e, StatusCode::BAD_REQUEST), format!("Error reading chunk: {}", e)))?;
+        if received + chunk.len() as u64 > session.size {
+            return Err((StatusCode::PAYLOAD_TOO_LARGE, format!("Chunk runs past the declared size of {} bytes", session.size)));
+        }
+        file.write_all(&chunk).await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Error writing upload: {}", e)))?;
+        received += chunk.len() as u64;
+    }
+    file.flush().await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Error writing upload: {}", e)))?;
+    drop(file);
+
+    // 3. The last chunk moves the file into place
+    let complete = received == session.size;
+    if complete {
+        finish_upload_session(upload_dir, &id, &session).await?;
+    }
+
+    Ok((
+        [(UPLOAD_OFFSET_HEADER, received.to_string())],
+        Json(UploadStatus { id, offset: received, size: session.size, complete }),
+    ).into_response())
+}
+
+/// Exports request, traffic, cache and archive build metrics for Prometheus.
+async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        state.metrics.render(),
+    )
+}
+
+/// Hands requests under `/dav` to the WebDAV share.
+async fn dav_handler(State(state): State<AppState>, request: axum::extract::Request) -> Response {
+    match state.dav {
+        Some(share) => crate::dav::handle(share, request).await,
+        None => (StatusCode::NOT_FOUND, "WebDAV is disabled: start the server with --webdav").into_response(),
+    }
+}
+
+/// Looks up a file or directory in the root for `/file` and static serving, refusing excluded
+/// paths like `/download` does.
+fn lookup_entry(state: &AppState, requested: &str) -> Result<(PathBuf, StorageMetadata), (StatusCode, String)> {
+    let path = resolve_in_root(&state.storage, &state.root, requested)?;
+    let metadata = state.storage.metadata(&path)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Could not read {}: {}", requested, e)))?;
+    if state.exclude.is_excluded(&path, metadata.is_dir()) {
+        return Err((StatusCode::FORBIDDEN, format!("Access denied: {}", requested)));
+    }
+    Ok((path, metadata))
+}
+
+/// Sends one file from the root as it is, with ranges and an ETag, instead of in an archive.
+async fn file_handler(
+    State(state): State<AppState>,
+    axum::extract::Path(requested): axum::extract::Path<String>,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let lookup = state.clone();
+    let (path, metadata) = tokio::task::spawn_blocking(move || {
+        let (path, metadata) = lookup_entry(&lookup, &requested)?;
+        if metadata.is_dir() {
+            return Err((StatusCode::BAD_REQUEST, format!("{} is a directory; use /list or /download for it", requested)));
+        }
+        Ok((path, metadata))
+    }).await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Lookup task failed".to_string()))??;
+
+    debug!("Sending {} as a single file", path.display());
+    let content_type = crate::files::content_type(&path);
+    crate::files::send_file(&state.storage, path, &metadata, &headers, method == Method::HEAD, content_type)
+}
+
+#[derive(Deserialize)]
+struct BenchQuery {
+    size: String,
+}
+
+/// `GET /bench?size=N`: N bytes of filler (sizes such as `64M` work too) for the `bench`
+/// command, up to [`crate::files::MAX_BENCH_SIZE`].
+async fn bench_handler(
+    axum::extract::Query(query): axum::extract::Query<BenchQuery>,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let size = crate::parse_size(&query.size).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    if size > crate::files::MAX_BENCH_SIZE {
+        return Err((StatusCode::BAD_REQUEST, format!("Benchmark payloads are limited to {}", crate::format_size(crate::files::MAX_BENCH_SIZE))));
+    }
+    crate::files::send_filler(size, &headers, method == Method::HEAD)
+}
+
+/// What a request for a path in static mode or under `/browse` turned out to be.
+enum StaticEntry {
+    File(PathBuf, StorageMetadata),
+    /// The directory's path relative to the root, and its entries
+    Listing(String, Vec<ListEntry>),
+    /// A directory asked for without its trailing slash
+    Redirect,
+}
+
+/// Serves the root the way `python -m http.server` does (`--static`): files by their path,
+/// directories by their `index.html` or an index page. Routes such as `/download` take precedence.
+async fn static_handler(
+    State(state): State<AppState>,
+    uri: axum::http::Uri,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    serve_tree(state, uri, method, headers, &crate::files::STATIC_PAGES).await
+}
+
+/// Index pages for clicking through the root in a browser (`/browse/<path>`).
+async fn browse_handler(
+    State(state): State<AppState>,
+    uri: axum::http::Uri,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    serve_tree(state, uri, method, headers, &crate::files::BROWSE_PAGES).await
+}
+
+/// Answers a request for a file or directory below `pages.prefix`.
+async fn serve_tree(
+    state: AppState,
+    uri: axum::http::Uri,
+    method: Method,
+    headers: HeaderMap,
+    pages: &'static crate::files::DirectoryPages,
+) -> Result<Response, (StatusCode, String)> {
+    let decoded = urlencoding::decode(uri.path())
+        .map_err(|_| (StatusCode::BAD_REQUEST, format!("Invalid path: {}", uri.path())))?;
+    let shown = decoded.strip_prefix(pages.prefix).unwrap_or(&decoded).to_string();
+    let lookup = state.clone();
+    let entry = tokio::task::spawn_blocking(move || {
+        let requested = shown.trim_start_matches('/');
+        let (path, metadata) = lookup_entry(&lookup, requested)?;
+        if !metadata.is_dir() {
+            return Ok(StaticEntry::File(path, metadata));
+        }
+        if !shown.ends_with('/') {
+            return Ok(StaticEntry::Redirect);
+        }
+        if pages.use_index_file {
+            let index = path.join(crate::files::INDEX_FILE);
+            match lookup.storage.metadata(&index) {
+                Ok(index_metadata) if index_metadata.is_file() && !lookup.exclude.is_excluded(&index, false) => {
+                    return Ok(StaticEntry::File(index, index_metadata));
+                }
+                _ => {}
+            }
+        }
+        let relative = path.strip_prefix(&lookup.root).map(Path::to_path_buf).unwrap_or_default();
+        list_directory(&lookup.storage, &lookup.root, &relative, &lookup.exclude)
+            .map(|entries| StaticEntry::Listing(crate::paths::slash_path(&relative), entries))
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Could not list {}: {}", requested, e)))
+    }).await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Lookup task failed".to_string()))??;
+
+    match entry {
+        StaticEntry::File(path, metadata) => {
+            let content_type = crate::files::content_type(&path);
+            crate::files::send_file(&state.storage, path, &metadata, &headers, method == Method::HEAD, content_type)
+        }
+        StaticEntry::Listing(relative, entries) => {
+            debug!("Responding with the index of '{}' ({} entries)", relative, entries.len());
+            Ok(axum::response::Html(crate::files::directory_index(pages, &relative, &entries)).into_response())
+        }
+        // Relative links in the directory's pages only resolve below a slash
+        StaticEntry::Redirect => Ok(axum::response::Redirect::permanent(&format!("{}/", uri.path())).into_response()),
+    }
+}
+
+/// The browser page served at `/`: browses `/list` and downloads the ticked entries from `/download`.
+const WEB_UI: &str = include_str!("ui.html");
+
+/// Serves the web UI, so people without the client can pick files and download an archive.
+async fn index_handler() -> axum::response::Html<&'static str> {
+    axum::response::Html(WEB_UI)
+}
+
+/// Lists the files available under the server root (or a subdirectory of it) as JSON.
+async fn list_handler(
+    State(state): State<AppState>,
+    Query(params): Query<ListParams>,
+) -> Result<Json<Vec<ListEntry>>, (StatusCode, String)> {
+    // Resolving the path may already need the storage backend, so it happens off the async workers too
+    let listing = tokio::task::spawn_blocking(move || {
+        let relative_dir = match &params.path {
+            Some(path) => {
+                let resolved = resolve_in_root(&state.storage, &state.root, path)?;
+                resolved.strip_prefix(&state.root).map(Path::to_path_buf).unwrap_or_default()
+            }
+            None => PathBuf::new(),
+        };
+        let entries = list_directory(&state.storage, &state.root, &relative_dir, &state.exclude);
+        Ok::<_, (StatusCode, String)>((relative_dir, entries))
+    }).await;
+    let (relative_dir, entries) = listing
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Listing task failed".to_string()))??;
+
+    match entries {
+        Ok(entries) => {
+            debug!("Responding with listing of '{}' ({} entries)", relative_dir.display(), entries.len());
+            Ok(Json(entries))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            Err((StatusCode::NOT_FOUND, format!("No such file or directory: {}", relative_dir.display())))
+        }
+        Err(e) => {
+            error!("Error listing {}: {:?}", relative_dir.display(), e);
+            Err((StatusCode::BAD_REQUEST, format!("Error listing {}: {}", relative_dir.display(), e)))
+        }
+    }
+}
+
+/// Reports what `/download` would archive, without building anything.
+async fn preview_handler(
+    State(state): State<AppState>,
+    Query(params): Query<ArchiveParams>,
+) -> Result<Json<DryRunReport>, (StatusCode, String)> {
+    let files_for_task = resolve_requested_paths(&state, &params).await?;
+    let entry_options = entry_options(&state, &params);
+
+    match tokio::task::spawn_blocking(move || dry_run_archive(&collect_entries(&files_for_task, &entry_options)?)).await {
+        Ok(Ok(mut report)) => {
+            // Report paths relative to the root rather than exposing the server's layout
+            for file in &mut report.files {
+                if let Ok(relative) = Path::new(&file.path).strip_prefix(&state.root) {
+                    file.path = crate::paths::slash_path(relative);
+                }
+            }
+            debug!("Responding with dry-run report ({} files, {} bytes)", report.files.len(), report.total_size);
+            Ok(Json(report))
+        }
+        Ok(Err(e)) => {
+            error!("Error preparing dry-run report: {:?}", e);
+            Err((StatusCode::BAD_REQUEST, format!("Error preparing dry-run report: {}", e)))
+        }
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "Dry-run task failed".to_string())),
+    }
+}
+
+/// Everything about a requested archive that is known before building it.
+#[derive(Clone)]
+pub(crate) struct ArchivePlan {
+    entries: Vec<ArchiveEntry>,
+    options: ArchiveOptions,
+    /// Sent encrypted with the server's passphrase
+    encrypted: bool,
+    filename: String,
+    etag: String,
+    last_modified: std::time::SystemTime,
+}
+
+impl ArchivePlan {
+    /// What the archive is sent as; encrypted archives are opaque to anything but the client.
+    fn content_type(&self) -> &'static str {
+        if self.encrypted { "application/octet-stream" } else { self.options.content_type() }
+    }
+
+    /// Headers shared by every response describing this archive.
+    fn insert_headers(&self, headers: &mut HeaderMap) {
+        headers.insert(axum::http::header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        headers.insert(axum::http::header::CONTENT_TYPE, HeaderValue::from_static(self.content_type()));
+        headers.insert(axum::http::header::CONTENT_DISPOSITION, attachment(&self.filename));
+        headers.insert(axum::http::header::ETAG, HeaderValue::try_from(self.etag.as_str()).unwrap());
+        headers.insert(
+            axum::http::header::LAST_MODIFIED,
+            HeaderValue::try_from(httpdate::fmt_http_date(self.last_modified)).unwrap(),
+        );
+    }
+}
+
+/// `Content-Disposition` for a download saved as `filename`. Names that are not plain ASCII
+/// also get the RFC 6266 `filename*` form, with `_` standing in for other characters in the
+/// plain one for clients that only read that.
+fn attachment(filename: &str) -> HeaderValue {
+    let value = match filename.is_ascii() {
+        true => format!("attachment; filename=\"{}\"", filename),
+        false => {
+            let fallback: String = filename.chars().map(|c| if c.is_ascii() { c } else { '_' }).collect();
+            format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, urlencoding::encode(filename))
+        }
+    };
+    HeaderValue::try_from(value).unwrap()
+}
+
+/// Resolves the files and options of an archive request and tags the result.
+async fn plan_archive(state: &AppState, params: &ArchiveParams) -> Result<ArchivePlan, ArchiveError> {
+    let codec = params.compression.unwrap_or(state.compression);
+    // The server's default level is meant for compressing codecs, so it never applies to a store request
+    let level = match codec == Codec::Store {
+        true => params.level,
+        false => params.level.or(state.level),
+    };
+    let options = ArchiveOptions::new(params.format, codec, level)
+        .map(|options| ArchiveOptions {
+            preserve_metadata: state.preserve_metadata,
+            xattrs: state.xattrs,
+            threads: state.threads,
+            mtime_clamp: state.mtime_clamp,
+            ..options
+        })
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let requested = match requested_files(state, params)? {
+        files if files.is_empty() => state.initial_files.clone(),
+        files => files,
+    };
+    // A preset's archive is named after the preset
+    let chosen_name = params.name.as_deref().or(params.preset.as_deref());
+    let mut filename = archive_file_name(&requested, chosen_name, &options.extension());
+    if state.encryption.is_some() {
+        filename = format!("{}.{}", filename, ENCRYPTED_EXTENSION);
+    }
+    let files = resolve_requested_paths(state, params).await?;
+    let entry_options = entry_options(state, params);
+
+    // Expand directories, then tag this exact archive so clients can tell whether a partial
+    // download still matches. With --watch, the result stands until the files change
+    let memo = state.plans.clone();
+    let quotas = (state.max_archive_size.is_some() || state.max_files.is_some()).then_some((state.max_archive_size, state.max_files));
+    let validators = tokio::task::spawn_blocking(move || {
+        let key = format!("{:?} {} {:?}", files, entry_options.flatten, options);
+        let generation = memo.as_ref().map(|memo| memo.generation());
+        let (entries, etag, last_modified) = match memo.as_ref().and_then(|memo| memo.get(&key)) {
+            Some(plan) => plan,
+            None => {
+                let entries = collect_entries(&files, &entry_options)?;
+                let (etag, last_modified) = (archive_etag(&entries, &options)?, archive_last_modified(&entries)?);
+                if let (Some(memo), Some(generation)) = (&memo, generation) {
+                    memo.insert(key, generation, (entries.clone(), etag.clone(), last_modified));
+                }
+                (entries, etag, last_modified)
+            }
+        };
+
+        let exceeded = match quotas {
+            Some((max_archive_size, max_files)) => QuotaExceeded::check(&entries, max_archive_size, max_files)?,
+            None => None,
+        };
+        Ok::<_, io::Error>((entries, etag, last_modified, exceeded))
+    }).await;
+
+    match validators {
+        Ok(Ok((_, _, _, Some(exceeded)))) => {
+            warn!("Rejected archive request: {}", exceeded.error);
+            Err(ArchiveError::Quota(exceeded))
+        }
+        Ok(Ok((entries, etag, last_modified, None))) => {
+            // Encrypted archives get their own tags, which also change with the passphrase
+            let etag = match &state.encryption {
+                Some(encryption) => encryption.bind_etag(&etag),
+                None => etag,
+            };
+            Ok(ArchivePlan { entries, options, encrypted: state.encryption.is_some(), filename, etag, last_modified })
+        }
+        Ok(Err(e)) if e.kind() == io::ErrorKind::PermissionDenied => Err((StatusCode::FORBIDDEN, e.to_string()).into()),
+        Ok(Err(e)) => Err((StatusCode::BAD_REQUEST, format!("Error reading source files: {}", e)).into()),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "ETag task failed".to_string()).into()),
+    }
+}
+
+/// Body of the 413 Payload Too Large answer to a request for an archive over
+/// `--max-archive-size` or `--max-files`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QuotaExceeded {
+    pub error: String,
+    /// Files the archive would contain
+    pub files: usize,
+    /// Their total size before compression
+    pub size: u64,
+    pub max_files: Option<usize>,
+    pub max_archive_size: Option<u64>,
+}
+
+impl QuotaExceeded {
+    /// Counts the files among `entries` and adds up their sizes, which is cheap next to
+    /// building the archive, and checks them against the limits.
+    fn check(entries: &[ArchiveEntry], max_archive_size: Option<u64>, max_files: Option<usize>) -> io::Result<Option<Self>> {
+        let (mut files, mut size) = (0usize, 0u64);
+        for entry in entries.iter().filter(|entry| entry.kind == EntryKind::File) {
+            files += 1;
+            size += entry.storage.metadata(&entry.source)?.len;
+        }
+
+        let error = match (max_files, max_archive_size) {
+            (Some(max), _) if files > max => format!("The archive would contain {} files, more than the limit of {}", files, max),
+            (_, Some(max)) if size > max => format!("The archive would hold {} bytes of files, more than the limit of {}", size, max),
+            _ => return Ok(None),
+        };
+        Ok(Some(QuotaExceeded { error, files, size, max_files, max_archive_size }))
+    }
+}
+
+/// Why a request for an archive was turned down: a status and message like any other handler
+/// error, or a quota, which is explained as JSON.
+enum ArchiveError {
+    Status(StatusCode, String),
+    Quota(QuotaExceeded),
+    /// Too many background builds are running; 503 with a Retry-After
+    Busy(Duration),
+}
+
+const BUSY_JOBS_MESSAGE: &str = "Too many archives are being built in the background, try again later";
+const QUEUE_FULL_MESSAGE: &str = "Too many archives are waiting to be built, try again later";
+
+impl From<(StatusCode, String)> for ArchiveError {
+    fn from((status, message): (StatusCode, String)) -> Self {
+        ArchiveError::Status(status, message)
+    }
+}
+
+impl std::fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArchiveError::Status(_, message) => f.write_str(message),
+            ArchiveError::Quota(exceeded) => f.write_str(&exceeded.error),
+            ArchiveError::Busy(_) => f.write_str(BUSY_JOBS_MESSAGE),
+        }
+    }
+}
+
+impl IntoResponse for ArchiveError {
+    fn into_response(self) -> Response {
+        match self {
+            ArchiveError::Status(status, message) => (status, message).into_response(),
+            ArchiveError::Quota(exceeded) => (StatusCode::PAYLOAD_TOO_LARGE, Json(exceeded)).into_response(),
+            ArchiveError::Busy(retry_after) => retry_later(StatusCode::SERVICE_UNAVAILABLE, retry_after, BUSY_JOBS_MESSAGE),
+        }
+    }
+}
+
+/// The size and checksum of a planned archive, measured once per ETag.
+async fn archive_checksum(state: &AppState, plan: &ArchivePlan) -> Result<ArchiveChecksum, (StatusCode, String)> {
+    if let Some(checksum) = state.archive_checksums.lock().unwrap_or_else(|e| e.into_inner()).get(&plan.etag) {
+        return Ok(checksum.clone());
+    }
+
+    // Hashing a cached copy is cheaper than compressing everything again
+    let cached = state.cache.as_ref().and_then(|cache| cache.get(&plan.etag));
+    let entries = plan.entries.clone();
+    let options = plan.options;
+    let key = sealing_key(state, &plan.etag).await?;
+    let checksum = match tokio::task::spawn_blocking(move || match (cached.as_deref().map(hash_file), key) {
+        (Some(Ok((size, sha256))), _) => Ok(ArchiveChecksum { sha256, size }),
+        // The encrypted size and hash are only known once the archive has been sealed
+        (_, Some(key)) => create_archive(&entries, &options)
+            .and_then(|data| key.seal(&data))
+            .map(|sealed| ArchiveChecksum::of(&sealed)),
+        _ => measure_archive(&entries, &options),
+    }).await {
+        Ok(Ok(checksum)) => checksum,
+        Ok(Err(e)) => return Err((StatusCode::BAD_REQUEST, format!("Error creating archive: {}", e))),
+        Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "Archive task failed".to_string())),
+    };
+    remember_archive_checksum(&state.archive_checksums, &plan.etag, checksum.clone());
+    Ok(checksum)
+}
+
+/// Checksums kept at most; one is only a few dozen bytes, but every distinct request adds one.
+/// Older ones are measured again when asked for.
+const MAX_ARCHIVE_CHECKSUMS: usize = 4096;
+/// Archive sizes and checksums by ETag.
+type ArchiveChecksums = std::sync::Mutex<std::collections::HashMap<String, ArchiveChecksum>>;
+
+/// Keeps the checksum of the archive tagged `etag`, starting over once the map is full, as the
+/// chunk lists do.
+fn remember_archive_checksum(checksums: &ArchiveChecksums, etag: &str, checksum: ArchiveChecksum) {
+    let mut checksums = checksums.lock().unwrap_or_else(|e| e.into_inner());
+    if checksums.len() >= MAX_ARCHIVE_CHECKSUMS && !checksums.contains_key(etag) {
+        checksums.clear();
+    }
+    checksums.insert(etag.to_string(), checksum);
+}
+
+/// Reports the SHA-256 and size of the archive `/download` would send for the same query,
+/// so clients can check a finished (possibly resumed) download end to end.
+async fn checksum_handler(
+    State(state): State<AppState>,
+    Query(params): Query<ArchiveParams>,
+) -> Result<Response, ArchiveError> {
+    let plan = plan_archive(&state, &params).await?;
+    let checksum = archive_checksum(&state, &plan).await?;
+
+    debug!("Responding with checksum {} ({} bytes)", checksum.sha256, checksum.size);
+
+    let mut response = Json(checksum).into_response();
+    response.headers_mut().insert(axum::http::header::ETAG, HeaderValue::try_from(plan.etag).unwrap());
+    Ok(response)
+}
+
+/// Lists every entry the archive for the same query will contain, with sizes, modification
+/// times and per-file SHA-256, under the archive's ETag.
+async fn manifest_handler(
+    State(state): State<AppState>,
+    Query(params): Query<ArchiveParams>,
+) -> Result<Response, ArchiveError> {
+    let plan = plan_archive(&state, &params).await?;
+    let entries = plan.entries.clone();
+    let options = plan.options;
+
+    let entries = match tokio::task::spawn_blocking(move || manifest_entries(&entries, &options)).await {
+        Ok(Ok(entries)) => entries,
+        Ok(Err(e)) => {
+            error!("Error preparing manifest: {:?}", e);
+            return Err((StatusCode::BAD_REQUEST, format!("Error preparing manifest: {}", e)).into());
+        }
+        Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "Manifest task failed".to_string()).into()),
+    };
+
+    debug!("Responding with manifest ({} entries)", entries.len());
+
+    let mut response = Json(Manifest { etag: plan.etag.clone(), entries }).into_response();
+    response.headers_mut().insert(axum::http::header::ETAG, HeaderValue::try_from(plan.etag).unwrap());
+    Ok(response)
+}
+
+/// Answers /download.sig with the Ed25519 signature (64 raw bytes) of the archive the same
+/// query would download, as sent (so encrypted when --encrypt is on).
+async fn signature_handler(
+    State(state): State<AppState>,
+    Query(params): Query<ArchiveParams>,
+) -> Result<Response, ArchiveError> {
+    let Some(signing_key) = state.signing_key.clone() else {
+        return Err((StatusCode::NOT_FOUND, "Archives are not signed (start the server with --signing-key)".to_string()).into());
+    };
+
+    let plan = plan_archive(&state, &params).await?;
+    let checksum = archive_checksum(&state, &plan).await?;
+    let signature = sign_digest(&signing_key, &checksum.sha256)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Could not sign archive: {}", e)))?;
+
+    debug!("Responding with signature for {}", checksum.sha256);
+
+    Ok(Response::builder()
+        .header(axum::http::header::CONTENT_TYPE, "application/octet-stream")
+        .header(axum::http::header::CONTENT_DISPOSITION, attachment(&format!("{}.sig", plan.filename)))
+        .header(axum::http::header::ETAG, plan.etag.as_str())
+        .body(Body::from(signature.to_vec()))
+        .unwrap())
+}
+
+// --- SPLIT ARCHIVES ---
+// With --split-size, an archive is also served as numbered parts (`archive.tar.gz.001`,
+// `.002`, ...) for targets that cannot hold one large file. Parts are plain byte slices of the
+// archive, so joining them in order gives it back.
+
+/// The parts a split archive is served as, from `/download.index`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ArchiveParts {
+    /// Name of the whole archive; part `n` is named `<filename>.<n as 3 digits>`
+    pub filename: String,
+    /// ETag of the whole archive
+    pub etag: String,
+    pub size: u64,
+    /// Lowercase hex SHA-256 of the whole archive, i.e. of the parts joined in order
+    pub sha256: String,
+    pub part_size: u64,
+    pub parts: Vec<ArchivePart>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ArchivePart {
+    /// Numbered from 1, as requested with `/download?part=`
+    pub number: u32,
+    pub name: String,
+    /// Where the part starts in the whole archive
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// The name a part is saved under, e.g. `archive.tar.gz.001`.
+pub fn part_file_name(filename: &str, number: u32) -> String {
+    format!("{}.{:03}", filename, number)
+}
+
+/// Every part of a `size`-byte archive split every `part_size` bytes. Even an empty archive has one.
+fn archive_parts(filename: &str, size: u64, part_size: u64) -> Vec<ArchivePart> {
+    let count = size.div_ceil(part_size).max(1);
+    (0..count)
+        .map(|index| {
+            let offset = index * part_size;
+            let number = index as u32 + 1;
+            ArchivePart { number, name: part_file_name(filename, number), offset, size: part_size.min(size - offset) }
+        })
+        .collect()
+}
+
+/// The part `?part=` asked for, or why it cannot be sent.
+fn requested_part(state: &AppState, filename: &str, size: u64, number: u32) -> Result<ArchivePart, (StatusCode, String)> {
+    let Some(part_size) = state.split_size else {
+        return Err((StatusCode::BAD_REQUEST, "Archives are not split (start the server with --split-size)".to_string()));
+    };
+    let parts = archive_parts(filename, size, part_size);
+    let count = parts.len();
+    parts.into_iter()
+        .find(|part| part.number == number)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Part {} does not exist; the archive has {} parts", number, count)))
+}
+
+/// Gives a response the name and ETag of one part instead of the whole archive's, so a resumed
+/// part is never appended to with bytes of another.
+fn insert_part_headers(headers: &mut HeaderMap, part: &ArchivePart, etag: &str) {
+    headers.insert(axum::http::header::CONTENT_DISPOSITION, attachment(&part.name));
+    headers.insert(axum::http::header::ETAG, HeaderValue::try_from(part_etag(etag, part.number)).unwrap());
+}
+
+fn part_etag(etag: &str, number: u32) -> String {
+    format!("\"{}.{:03}\"", etag.trim_matches('"'), number)
+}
+
+/// Lists the parts `/download?part=` serves for the same query, with the size and SHA-256 of
+/// the whole archive to check them against once joined.
+async fn parts_index_handler(
+    State(state): State<AppState>,
+    Query(params): Query<ArchiveParams>,
+) -> Result<Response, ArchiveError> {
+    let Some(part_size) = state.split_size else {
+        return Err((StatusCode::NOT_FOUND, "Archives are not split (start the server with --split-size)".to_string()).into());
+    };
+
+    let (plan, checksum) = match &params.job {
+        Some(id) => finished_job(&state, id).map(|(plan, data)| (plan, ArchiveChecksum::of(&data)))?,
+        None => {
+            let plan = plan_archive(&state, &params).await?;
+            let checksum = archive_checksum(&state, &plan).await?;
+            (plan, checksum)
+        }
+    };
+    let parts = archive_parts(&plan.filename, checksum.size, part_size);
+
+    debug!("Responding with index of {} parts ({} bytes)", parts.len(), checksum.size);
+
+    let mut response = Json(ArchiveParts {
+        filename: plan.filename.clone(),
+        etag: plan.etag.clone(),
+        size: checksum.size,
+        sha256: checksum.sha256,
+        part_size,
+        parts,
+    }).into_response();
+    response.headers_mut().insert(axum::http::header::ETAG, HeaderValue::try_from(plan.etag).unwrap());
+    Ok(response)
+}
+
+/// Generates an archive in a blocking task and, unless --skip-verify is set, makes sure what
+/// was built is actually a readable archive before it is sent.
+async fn build_archive(
+    state: &AppState,
+    entries: Vec<ArchiveEntry>,
+    options: ArchiveOptions,
+    etag: &str,
+    job: Option<&std::sync::Arc<Job>>,
+) -> Result<bytes::Bytes, (StatusCode, String)> {
+    let _turn = build_turn(state, job.map(|job| job.as_ref())).await?;
+    let started = std::time::Instant::now();
+    let job = job.cloned();
+    let archive_data = match tokio::task::spawn_blocking(move || match job {
+        Some(job) => job.progress.set_totals(&entries)
+            .and_then(|()| create_archive_with_progress(&entries, &options, &job.progress)),
+        None => create_archive(&entries, &options),
+    }).await {
+        Ok(Ok(data)) => data,
+        Ok(Err(e)) => {
+            error!("Error creating archive: {:?}", e);
+            return Err((StatusCode::BAD_REQUEST, format!("Error creating archive: {}", e)));
+        }
+        Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "Archive task failed".to_string())),
+    };
+
+    if !state.skip_verify {
+        let data_for_task = archive_data.clone();
+        match tokio::task::spawn_blocking(move || verify_archive(&data_for_task, &options)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => {
+                error!("Archive verification failed: {:?}", e);
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Generated archive failed verification: {}", e)));
+            }
+            Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "Archive verification task failed".to_string())),
+        }
+    }
+
+    // The command sees what the client will unpack, even when it is sent encrypted
+    if let Some(command) = &state.post_archive_cmd {
+        run_post_archive_cmd(command, &archive_data, &options).await?;
+    }
+
+    // Verification checks the plain archive; what is sent (and cached) is the sealed one
+    let archive_data = match sealing_key(state, etag).await? {
+        Some(key) => match tokio::task::spawn_blocking(move || key.seal(&archive_data)).await {
+            Ok(Ok(sealed)) => bytes::Bytes::from(sealed),
+            Ok(Err(e)) => return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Could not encrypt archive: {}", e))),
+            Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "Encryption task failed".to_string())),
+        },
+        None => archive_data,
+    };
+
+    state.metrics.record_archive_build(started.elapsed());
+    Ok(archive_data)
+}
+
+/// Waits for a turn to build an archive under --max-archive-jobs, letting `job` report its
+/// place in line meanwhile, or refuses with 503 when --archive-queue builds are waiting already.
+async fn build_turn(state: &AppState, job: Option<&Job>) -> Result<BuildSlot, (StatusCode, String)> {
+    let mut waited = false;
+    let turn = state.builds.turn(|position| {
+        if !waited {
+            debug!("Waiting for a turn to build an archive ({} in line)", position);
+            waited = true;
+        }
+        if let Some(job) = job {
+            job.set_queue_position(position);
+        }
+    }).await;
+    if let Some(job) = job {
+        job.set_queue_position(0);
+    }
+    turn.ok_or_else(|| {
+        let (running, waiting) = state.builds.load();
+        warn!("{} archives are being built and {} are waiting already, refusing another with 503", running, waiting);
+        (StatusCode::SERVICE_UNAVAILABLE, QUEUE_FULL_MESSAGE.to_string())
+    })
+}
+
+/// Hashes an archive that was just built or read, unless its checksum is already known, so
+/// HEAD and /checksum need not measure it again.
+async fn remember_checksum(state: &AppState, etag: &str, data: &bytes::Bytes) {
+    if state.archive_checksums.lock().unwrap_or_else(|e| e.into_inner()).contains_key(etag) {
+        return;
+    }
+    let data = data.clone();
+    if let Ok(checksum) = tokio::task::spawn_blocking(move || ArchiveChecksum::of(&data)).await {
+        remember_archive_checksum(&state.archive_checksums, etag, checksum);
+    }
+}
+
+/// The key for encrypting the archive tagged `etag`, if the server encrypts archives.
+async fn sealing_key(state: &AppState, etag: &str) -> Result<Option<SealingKey>, (StatusCode, String)> {
+    let Some(encryption) = state.encryption.clone() else { return Ok(None) };
+    let etag = etag.to_string();
+    match tokio::task::spawn_blocking(move || encryption.key_for(&etag)).await {
+        Ok(Ok(key)) => Ok(Some(key)),
+        Ok(Err(e)) => Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Could not derive the encryption key: {}", e))),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "Key derivation task failed".to_string())),
+    }
+}
+
+/// Reads the cached copy of an archive, if there is one. A file that cannot be read is
+/// dropped from the cache and the archive is rebuilt instead.
+async fn read_cached_archive(cache: &ArchiveCache, etag: &str) -> Option<bytes::Bytes> {
+    let path = cache.get(etag)?;
+    match tokio::fs::read(&path).await {
+        Ok(data) => {
+            debug!("Cache hit for {}", etag);
+            Some(bytes::Bytes::from(data))
+        }
+        Err(e) => {
+            warn!("Could not read cached archive {}: {}", path.display(), e);
+            cache.remove(etag);
+            None
+        }
+    }
+}
+
+/// Opens the cached copy of an archive for sending straight from disk, if there is one. A file
+/// that cannot be opened is dropped from the cache and the archive is rebuilt instead.
+async fn open_cached_archive(cache: &ArchiveCache, etag: &str) -> Option<ServedArchive> {
+    let path = cache.get(etag)?;
+    let opened = async {
+        let file = tokio::fs::File::open(&path).await?;
+        let length = file.metadata().await?.len();
+        Ok::<_, io::Error>((file.into_std().await, length))
+    };
+    match opened.await {
+        Ok((file, length)) => {
+            debug!("Cache hit for {}", etag);
+            Some(ServedArchive::Cached { file, offset: 0, length })
+        }
+        Err(e) => {
+            warn!("Could not open cached archive {}: {}", path.display(), e);
+            cache.remove(etag);
+            None
+        }
+    }
+}
+
+/// Adds a freshly built archive to the cache. Failing to cache is logged, never fatal.
+async fn store_cached_archive(cache: std::sync::Arc<ArchiveCache>, etag: &str, data: bytes::Bytes) {
+    let etag = etag.to_string();
+    let result = tokio::task::spawn_blocking(move || cache.insert(&etag, &data)).await;
+    if let Ok(Err(e)) = result {
+        warn!("Could not cache archive: {}", e);
+    }
+}
+
+/// Answers HEAD /download with the headers a GET would send, so clients can learn the
+/// archive's size before downloading. Nothing is kept in memory, and sizes are remembered
+/// per ETag so repeated probes of unchanged files are free.
+async fn download_head_handler(
+    State(state): State<AppState>,
+    Query(params): Query<ArchiveParams>,
+    request_headers: HeaderMap,
+) -> Result<Response, ArchiveError> {
+
+    if state.dry_run {
+        return preview_handler(State(state), Query(params)).await
+            .map(IntoResponse::into_response)
+            .map_err(ArchiveError::from);
+    }
+
+    let (plan, file_size) = match &params.job {
+        Some(id) => finished_job(&state, id).map(|(plan, data)| (plan, Some(data.len() as u64)))?,
+        None => (plan_archive(&state, &params).await?, None),
+    };
+    // Nothing needs measuring for a client whose copy is still current
+    let served_etag = params.part.map_or_else(|| plan.etag.clone(), |number| part_etag(&plan.etag, number));
+    if not_modified(&request_headers, &served_etag, plan.last_modified) {
+        debug!("Responding to HEAD with 304 Not Modified ({})", served_etag);
+        return Ok(not_modified_response(&served_etag, plan.last_modified));
+    }
+    let file_size = match file_size {
+        Some(size) => size,
+        None => archive_checksum(&state, &plan).await?.size,
+    };
+
+    let mut res = Response::builder();
+    let headers = res.headers_mut().unwrap();
+    plan.insert_headers(headers);
+    let file_size = match params.part {
+        Some(number) => {
+            let part = requested_part(&state, &plan.filename, file_size, number)?;
+            insert_part_headers(headers, &part, &plan.etag);
+            part.size
+        }
+        None => file_size,
+    };
+    headers.insert(axum::http::header::CONTENT_LENGTH, HeaderValue::from(file_size));
+
+    debug!("Responding to HEAD with 200 OK ({} bytes)", file_size);
+
+    Ok(res.status(StatusCode::OK).body(Body::empty()).unwrap())
+}
+
+async fn download_handler(
+    State(state): State<AppState>, 
+    Query(params): Query<ArchiveParams>,
+    request_headers: HeaderMap,
+) -> Result<Response, ArchiveError> {
+    
+    if state.dry_run {
+        return preview_handler(State(state), Query(params)).await
+            .map(IntoResponse::into_response)
+            .map_err(ArchiveError::from);
+    }
+
+    if params.async_build {
+        return start_archive_job(state, params).await;
+    }
+    if params.part.is_some() && state.split_size.is_none() {
+        return Err((StatusCode::BAD_REQUEST, "Archives are not split (start the server with --split-size)".to_string()).into());
+    }
+
+    // 1. Work out what to archive and tag it, unless a background job already built it.
+    // Ranges and parts continue an archive a full request already started, so only that runs
+    // the pre-archive command
+    let (plan, finished) = match &params.job {
+        Some(id) => finished_job(&state, id).map(|(plan, data)| (plan, Some(data)))?,
+        None => {
+            if !request_headers.contains_key(axum::http::header::RANGE) && params.part.is_none() {
+                run_pre_archive_cmd(&state, &params).await?;
+            }
+            (plan_archive(&state, &params).await?, None)
+        }
+    };
+    let options = plan.options;
+    let content_type = plan.content_type();
+    let etag = plan.etag.clone();
+
+    // A Range guarded by a stale If-Range is ignored, so the client gets the new archive in full
+    let served_etag = params.part.map_or_else(|| etag.clone(), |number| part_etag(&etag, number));
+    if not_modified(&request_headers, &served_etag, plan.last_modified) {
+        debug!("Responding with 304 Not Modified ({})", served_etag);
+        return Ok(not_modified_response(&served_etag, plan.last_modified));
+    }
+    let range_header = request_headers.get(axum::http::header::RANGE)
+        .filter(|_| if_range_matches(&request_headers, &served_etag));
+
+    let mut res = Response::builder();
+    plan.insert_headers(res.headers_mut().unwrap());
+
+    // 1a. An archive already in the cache (or built by the job) is sent as is
+    let cached = match &state.cache {
+        _ if finished.is_some() => finished.map(ServedArchive::Memory),
+        Some(cache) => {
+            let cached = open_cached_archive(cache, &etag).await;
+            state.metrics.record_cache_lookup(cached.is_some());
+            cached
+        }
+        None => None,
+    };
+
+    // 1b. With --stream, other full downloads are produced straight into the response body
+    if cached.is_none() && state.stream && options.is_streamable() && range_header.is_none() && params.part.is_none() {
+        let file_count = plan.entries.iter().filter(|entry| entry.kind == EntryKind::File).count();
+        debug!("Responding with 200 OK (Streaming archive of {} files)", file_count);
+
+        let key = sealing_key(&state, &etag).await?;
+        let turn = build_turn(&state, None).await?;
+        return Ok(res
+            .status(StatusCode::OK)
+            .body(stream_tar(plan.entries, options, key, state.cache.clone().map(|cache| (cache, etag)), turn))
+            .unwrap());
+    }
+
+    // 1c. Otherwise build (and verify) it, keeping a copy for next time
+    let archive_data = match cached {
+        Some(archive) => archive,
+        None => {
+            let data = build_archive(&state, plan.entries, options, &etag, None).await?;
+            if let Some(cache) = &state.cache {
+                store_cached_archive(cache.clone(), &etag, data.clone()).await;
+            }
+            ServedArchive::Memory(data)
+        }
+    };
+
+    // A cached copy is hashed from disk when HEAD or /checksum first need it
+    if let ServedArchive::Memory(data) = &archive_data {
+        remember_checksum(&state, &etag, data).await;
+    }
+
+    let headers = res.headers_mut().unwrap();
+
+    // 1d. A part of a split archive is sent as a file of its own, ranges included
+    let archive_data = match params.part {
+        Some(number) => {
+            let part = requested_part(&state, &plan.filename, archive_data.len(), number)?;
+            insert_part_headers(headers, &part, &etag);
+            archive_data.slice(part.offset, part.size)
+        }
+        None => archive_data,
+    };
+    let file_size = archive_data.len();
+
+    let ranges = match range_header {
+        Some(range_value) => parse_range_header(range_value, file_size)?,
+        None => RangeRequest::Full,
+    };
+
+    match ranges {
+        RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+            // --- 2. Handle Range Request (206 Partial Content) for Resume ---
+            let range = ranges[0];
+            let start = range.start;
+            let end = start + range.length - 1;
+
+            // Set 206 Partial Content headers
+            headers.insert(axum::http::header::CONTENT_RANGE, 
+                HeaderValue::try_from(format!("bytes {}-{}/{}", start, end, file_size)).unwrap());
+            headers.insert(axum::http::header::CONTENT_LENGTH, 
+                HeaderValue::from(range.length));
+
+            debug!("Responding with 206 Partial Content: bytes {}-{}/{}", start, end, file_size);
+            
+            let body = archive_data.body(vec![BodyPiece::Range { start, length: range.length }]);
+            Ok(res.status(StatusCode::PARTIAL_CONTENT).body(body).unwrap())
+        },
+        RangeRequest::Partial(ranges) => {
+            // --- 2a. Several ranges go out as one multipart/byteranges body ---
+            let boundary = byteranges_boundary(&etag);
+            let parts = byteranges_body(file_size, &ranges, content_type, &boundary);
+            let content_length: u64 = parts.iter().map(BodyPiece::len).sum();
+
+            headers.insert(axum::http::header::CONTENT_TYPE,
+                HeaderValue::try_from(format!("multipart/byteranges; boundary={}", boundary)).unwrap());
+            headers.insert(axum::http::header::CONTENT_LENGTH,
+                HeaderValue::from(content_length));
+
+            debug!("Responding with 206 Partial Content: {} ranges of {} bytes", ranges.len(), file_size);
+
+            Ok(res.status(StatusCode::PARTIAL_CONTENT).body(archive_data.body(parts)).unwrap())
+        },
+        RangeRequest::Unsatisfiable => {
+            debug!("Range not satisfiable for {} bytes", file_size);
+            headers.insert(axum::http::header::CONTENT_RANGE,
+                HeaderValue::try_from(format!("bytes */{}", file_size)).unwrap());
+            headers.insert(axum::http::header::CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+
+            Ok(res.status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .body(Body::from(format!("Range not satisfiable: archive is {} bytes", file_size)))
+                .unwrap())
+        },
+        RangeRequest::Full => {
+            // --- 3. Handle Full Download Request (200 OK) ---
+            headers.insert(axum::http::header::CONTENT_LENGTH, 
+                HeaderValue::from(file_size));
+            
+            debug!("Responding with 200 OK (Full content, {} bytes)", file_size);
+
+            let body = archive_data.body(vec![BodyPiece::Range { start: 0, length: file_size }]);
+            Ok(res.status(StatusCode::OK).body(body).unwrap())
+        }
+    }
+}
+
+// --- CHUNKED TRANSFERS ---
+//
+// With `chunked`, GET /download.chunks takes the same query as /download and lists the chunks
+// the archive is cut into (see `crate::chunks`). The client then POSTs the hashes it already
+// has and gets the rest: the missing chunks, each once and in archive order, concatenated and
+// compressed with zstd as one body.
+
+/// Chunk lists kept at most; older ones are worked out again when asked for.
+const MAX_CHUNK_INDEXES: usize = 16;
+/// Chunk lists by ETag.
+type ChunkIndexes = std::sync::Mutex<std::collections::HashMap<String, std::sync::Arc<ChunkIndex>>>;
+/// Header telling a client how many chunks the body of `POST /download.chunks` holds.
+pub const CHUNKS_SENT_HEADER: &str = "Chunks-Sent";
+
+/// The archive a chunk request is about, from a finished job or the cache, or built anew and
+/// cached like a download.
+async fn chunked_archive(state: &AppState, params: &ArchiveParams) -> Result<(ArchivePlan, bytes::Bytes), ArchiveError> {
+    if state.chunk_indexes.is_none() {
+        return Err((StatusCode::NOT_FOUND, "Archives are not chunked (start the server with --chunked)".to_string()).into());
+    }
+    if let Some(id) = &params.job {
+        return Ok(finished_job(state, id)?);
+    }
+    let plan = plan_archive(state, params).await?;
+    let cached = match &state.cache {
+        Some(cache) => {
+            let cached = read_cached_archive(cache, &plan.etag).await;
+            state.metrics.record_cache_lookup(cached.is_some());
+            cached
+        }
+        None => None,
+    };
+    let data = match cached {
+        Some(data) => data,
+        None => {
+            let data = build_archive(state, plan.entries.clone(), plan.options, &plan.etag, None).await?;
+            if let Some(cache) = &state.cache {
+                store_cached_archive(cache.clone(), &plan.etag, data.clone()).await;
+            }
+            data
+        }
+    };
+    remember_checksum(state, &plan.etag, &data).await;
+    Ok((plan, data))
+}
+
+/// The chunk list of an archive, cutting it into chunks unless that was done already.
+async fn chunk_index(state: &AppState, etag: &str, data: bytes::Bytes) -> Result<std::sync::Arc<ChunkIndex>, (StatusCode, String)> {
+    let Some(indexes) = &state.chunk_indexes else {
+        return Err((StatusCode::NOT_FOUND, "Archives are not chunked (start the server with --chunked)".to_string()));
+    };
+    if let Some(index) = indexes.lock().unwrap_or_else(|e| e.into_inner()).get(etag) {
+        return Ok(index.clone());
+    }
+    let tag = etag.to_string();
+    let index = match tokio::task::spawn_blocking(move || ChunkIndex::of(&tag, &data)).await {
+        Ok(index) => std::sync::Arc::new(index),
+        Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "Chunking task failed".to_string())),
+    };
+    let mut indexes = indexes.lock().unwrap_or_else(|e| e.into_inner());
+    if indexes.len() >= MAX_CHUNK_INDEXES {
+        indexes.clear();
+    }
+    indexes.insert(etag.to_string(), index.clone());
+    Ok(index)
+}
+
+/// Lists the chunks of the archive `/download` would send for the same query.
+async fn chunk_index_handler(
+    State(state): State<AppState>,
+    Query(params): Query<ArchiveParams>,
+) -> Result<Response, ArchiveError> {
+    let (plan, data) = chunked_archive(&state, &params).await?;
+    let index = chunk_index(&state, &plan.etag, data).await?;
+
+    debug!("Responding with index of {} chunks ({} bytes)", index.chunks.len(), index.size);
+
+    let mut response = Json(index.as_ref()).into_response();
+    response.headers_mut().insert(axum::http::header::ETAG, HeaderValue::try_from(plan.etag).unwrap());
+    Ok(response)
+}
+
+/// Sends the chunks of the archive the client does not have. A client working from the index
+/// of an archive that has changed since gets 412 Precondition Failed, and should fetch the
+/// new index.
+async fn chunks_handler(
+    State(state): State<AppState>,
+    Query(params): Query<ArchiveParams>,
+    Json(request): Json<ChunkRequest>,
+) -> Result<Response, ArchiveError> {
+    let (plan, data) = chunked_archive(&state, &params).await?;
+    if plan.etag != request.etag {
+        return Err((StatusCode::PRECONDITION_FAILED, "The archive has changed since its chunks were listed".to_string()).into());
+    }
+    let index = chunk_index(&state, &plan.etag, data.clone()).await?;
+
+    let have: std::collections::HashSet<String> = request.have.into_iter().collect();
+    let (sent, body) = match tokio::task::spawn_blocking(move || {
+        // Offsets of the first copy of each chunk
+        let mut offsets = std::collections::HashMap::new();
+        let mut offset = 0u64;
+        for chunk in &index.chunks {
+            offsets.entry(chunk.hash.as_str()).or_insert(offset);
+            offset += chunk.size;
+        }
+
+        let mut body = Vec::new();
+        let mut encoder = Codec::Zstd.encoder(&mut body, Codec::Zstd.default_level())?;
+        let mut sent = 0usize;
+        for chunk in index.missing(&have) {
+            let start = offsets[chunk.hash.as_str()] as usize;
+            encoder.write_all(&data[start..start + chunk.size as usize])?;
+            sent += 1;
+        }
+        encoder.finish()?;
+        Ok::<_, io::Error>((sent, body))
+    }).await {
+        Ok(Ok(chunks)) => chunks,
+        Ok(Err(e)) => return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Error compressing chunks: {}", e)).into()),
+        Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "Chunk task failed".to_string()).into()),
+    };
+
+    debug!("Responding with {} missing chunks ({} bytes compressed)", sent, body.len());
+
+    Ok(Response::builder()
+        .header(axum::http::header::CONTENT_TYPE, Codec::Zstd.content_type())
+        .header(axum::http::header::CONTENT_LENGTH, body.len())
+        .header(axum::http::header::ETAG, plan.etag.as_str())
+        .header(CHUNKS_SENT_HEADER, sent)
+        .body(Body::from(body))
+        .unwrap())
+}
+
+// --- ARCHIVE HOOKS ---
+//
+// --pre-archive-cmd and --post-archive-cmd are run through `sh -c` (`cmd /C` on Windows) with
+// their arguments appended, so they can be a script path or a short pipeline. Arguments go in
+// as `"$@"` on Unix and escaped one by one for cmd, so no file name is ever run as a command.
+// The request waits for them; output goes to the log and a failure answers 500.
+
+/// Runs the pre-archive command, if any, with the paths `params` ask for.
+async fn run_pre_archive_cmd(state: &AppState, params: &ArchiveParams) -> Result<(), (StatusCode, String)> {
+    let Some(command) = &state.pre_archive_cmd else { return Ok(()) };
+    let files = resolve_requested_paths(state, params).await?;
+    run_hook("Pre-archive", command, files.iter().map(|file| file.as_os_str())).await
+}
+
+/// Writes `data` to a temporary file and runs the post-archive command on it.
+async fn run_post_archive_cmd(command: &str, data: &bytes::Bytes, options: &ArchiveOptions) -> Result<(), (StatusCode, String)> {
+    static NEXT_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    let id = NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let path = std::env::temp_dir().join(format!("file-server-archive-{}-{}.{}", std::process::id(), id, options.extension()));
+    let _guard = TempFileGuard(Some(path.clone()));
+    tokio::fs::write(&path, data).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Could not write the archive for the post-archive command: {}", e)))?;
+    run_hook("Post-archive", command, [path.as_os_str()]).await
+}
+
+async fn run_hook<I, S>(label: &str, command: &str, args: I) -> Result<(), (StatusCode, String)>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+{
+    #[cfg(unix)]
+    let mut process = {
+        let mut process = tokio::process::Command::new("sh");
+        process.arg("-c").arg(format!("{} \"$@\"", command)).arg("sh").args(args);
+        process
+    };
+    #[cfg(windows)]
+    let mut process = {
+        // cmd takes the line as it is, so each argument is escaped here; /S strips just the
+        // outer quotes
+        let mut line = command.to_string();
+        for arg in args {
+            let arg = arg.as_ref().to_str().ok_or_else(|| (StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Cannot pass a path that is not valid Unicode to the {} command", label.to_lowercase())))?;
+            line.push(' ');
+            line.push_str(&cmd_quote(arg));
+        }
+        let mut process = tokio::process::Command::new("cmd");
+        process.args(["/S", "/C"]).raw_arg(format!("\"{}\"", line));
+        process
+    };
+    let started = std::time::Instant::now();
+    // A client that gives up stops the command too
+    let output = process.stdin(std::process::Stdio::null()).kill_on_drop(true).output().await
+        .map_err(|e| {
+            error!("Could not run the {} command: {}", label.to_lowercase(), e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("Could not run the {} command", label.to_lowercase()))
+        })?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        // The command's output stays in the log; it may say more than clients should see
+        error!("{} command failed ({}): {}", label, output.status, stderr.trim());
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("{} command failed ({})", label, output.status)));
+    }
+    if !stderr.trim().is_empty() {
+        debug!("{} command: {}", label, stderr.trim());
+    }
+    debug!("{} command finished in {:.1?}", label, started.elapsed());
+    Ok(())
+}
+
+/// `arg` as one argument on a `cmd /C` line: quoted the way programs split their command line,
+/// then with every character cmd treats specially escaped by `^`, so a file named `a&b.txt`
+/// or `100%x.txt` reaches the command unchanged instead of running something else.
+#[cfg(any(windows, test))]
+fn cmd_quote(arg: &str) -> String {
+    let mut quoted = String::with_capacity(arg.len() + 2);
+    if !arg.is_empty() && !arg.contains([' ', '\t', '\n', '\u{b}', '"']) {
+        quoted.push_str(arg);
+    } else {
+        quoted.push('"');
+        let mut backslashes = 0;
+        for c in arg.chars() {
+            match c {
+                '\\' => backslashes += 1,
+                // Backslashes before a quote are doubled, and the quote itself escaped
+                '"' => {
+                    quoted.push_str(&"\\".repeat(backslashes * 2 + 1));
+                    backslashes = 0;
+                }
+                _ => {
+                    quoted.push_str(&"\\".repeat(backslashes));
+                    backslashes = 0;
+                }
+            }
+            if c != '\\' {
+                quoted.push(c);
+            }
+        }
+        // So the closing quote is not escaped
+        quoted.push_str(&"\\".repeat(backslashes * 2));
+        quoted.push('"');
+    }
+
+    let mut escaped = String::with_capacity(quoted.len() * 2);
+    for c in quoted.chars() {
+        if matches!(c, '(' | ')' | '%' | '!' | '^' | '"' | '<' | '>' | '&' | '|') {
+            escaped.push('^');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// --- DELTA SYNC ---
+//
+// POST /sync takes the same query as /download and a JSON body listing what the client already
+// has (see `archive::local_manifest`). The response is an archive of only the entries that are
+// new or changed, or 204 No Content when the client is up to date.
+
+/// Header telling a syncing client how many entries it already had.
+pub const SYNC_UNCHANGED_HEADER: &str = "Sync-Unchanged";
+/// Largest client manifest POST /sync accepts, enough for several hundred thousand files.
+const MAX_SYNC_MANIFEST_SIZE: usize = 64 * 1024 * 1024;
+
+/// Body of `POST /sync`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SyncRequest {
+    /// Everything under the client's sync directory, with paths as they appear in the archive
+    pub have: Vec<ManifestEntry>,
+}
+
+/// Answers POST /sync with an archive of the entries the client does not have yet. Deltas
+/// depend on the client, so they are never cached, and they are encrypted like full downloads.
+async fn sync_handler(
+    State(state): State<AppState>,
+    Query(params): Query<ArchiveParams>,
+    Json(request): Json<SyncRequest>,
+) -> Result<Response, ArchiveError> {
+    // 1. Work out the full archive, then drop everything the client already has
+    run_pre_archive_cmd(&state, &params).await?;
+    let plan = plan_archive(&state, &params).await?;
+    let options = plan.options;
+    let content_type = plan.content_type();
+    let total = plan.entries.len();
+
+    let delta = tokio::task::spawn_blocking(move || {
+        let changed = changed_entries(plan.entries, &request.have)?;
+        let etag = archive_etag(&changed, &options)?;
+        Ok::<_, io::Error>((changed, etag))
+    }).await;
+    let (changed, etag) = match delta {
+        Ok(Ok(delta)) => delta,
+        Ok(Err(e)) => return Err((StatusCode::BAD_REQUEST, format!("Error comparing files: {}", e)).into()),
+        Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "Sync task failed".to_string()).into()),
+    };
+    let etag = match &state.encryption {
+        Some(encryption) => encryption.bind_etag(&etag),
+        None => etag,
+    };
+
+    let mut res = Response::builder();
+    let headers = res.headers_mut().unwrap();
+    headers.insert(SYNC_UNCHANGED_HEADER, HeaderValue::from(total - changed.len()));
+
+    if changed.is_empty() {
+        debug!("Responding with 204 No Content (all {} entries unchanged)", total);
+        return Ok(res.status(StatusCode::NO_CONTENT).body(Body::empty()).unwrap());
+    }
+
+    // 2. Build the delta like any other archive
+    let changed_count = changed.len();
+    let archive_data = build_archive(&state, changed, options, &etag, None).await?;
+    let headers = res.headers_mut().unwrap();
+    headers.insert(axum::http::header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    headers.insert(axum::http::header::CONTENT_LENGTH, HeaderValue::from(archive_data.len()));
+    headers.insert(axum::http::header::ETAG, HeaderValue::try_from(etag).unwrap());
+
+    debug!("Responding with 200 OK ({} of {} entries changed, {} bytes)", changed_count, total, archive_data.len());
+
+    Ok(res.status(StatusCode::OK).body(Body::from(archive_data)).unwrap())
+}
+
+// --- WATCH MODE ---
+
+/// Most plans remembered at once; past this the memo starts over.
+const MAX_MEMOIZED_PLANS: usize = 256;
+
+/// The entries, ETag and Last-Modified time worked out for one kind of request.
+type MemoizedPlan = (Vec<ArchiveEntry>, String, std::time::SystemTime);
+
+/// Archive plans kept between requests while the watcher reports no changes, so requests
+/// skip walking and stat-ing the tree.
+#[derive(Default)]
+struct PlanMemo {
+    /// Bumped on every change, so a plan worked out across one is not stored
+    generation: std::sync::atomic::AtomicU64,
+    plans: std::sync::Mutex<std::collections::HashMap<String, MemoizedPlan>>,
+}
+
+impl PlanMemo {
+    fn generation(&self) -> u64 {
+        self.generation.load(std::sync::atomic::Ordering::Acquire)
+    }
+
+    fn get(&self, key: &str) -> Option<MemoizedPlan> {
+        self.plans.lock().unwrap_or_else(|e| e.into_inner()).get(key).cloned()
+    }
+
+    /// Remembers a plan worked out at `generation`, unless the files changed since.
+    fn insert(&self, key: String, generation: u64, plan: MemoizedPlan) {
+        let mut plans = self.plans.lock().unwrap_or_else(|e| e.into_inner());
+        if self.generation() != generation {
+            return;
+        }
+        if plans.len() >= MAX_MEMOIZED_PLANS {
+            plans.clear();
+        }
+        plans.insert(key, plan);
+    }
+
+    fn invalidate(&self) {
+        let mut plans = self.plans.lock().unwrap_or_else(|e| e.into_inner());
+        self.generation.fetch_add(1, std::sync::atomic::Ordering::Release);
+        plans.clear();
+    }
+}
+
+/// Keeps the default archive in the cache current: it is built at startup and again after
+/// every change under the root, and remembered plans are dropped whenever files change.
+async fn watch_root(state: AppState, mut watcher: RootWatcher) {
+    refresh_archives(&state).await;
+    while let Some(changed) = watcher.next_change().await {
+        if let Some(plans) = &state.plans {
+            plans.invalidate();
+        }
+        debug!("{} path(s) changed under the root, refreshing cached archives", changed);
+        refresh_archives(&state).await;
+    }
+}
+
+/// Refreshes the default archive and, with --pregenerate, every preset's, one after another.
+async fn refresh_archives(state: &AppState) {
+    if !state.initial_files.is_empty() {
+        refresh_archive(state, &ArchiveParams::default(), "the default archive").await;
+    }
+    if state.pregenerate {
+        for name in state.presets.keys() {
+            let params = ArchiveParams { preset: Some(name.clone()), ..Default::default() };
+            refresh_archive(state, &params, &format!("preset {}", name)).await;
+        }
+    }
+}
+
+/// Builds the archive `params` ask for with the default options into the cache, unless an
+/// up-to-date copy is already there. Failures are logged; requests then build it themselves.
+async fn refresh_archive(state: &AppState, params: &ArchiveParams, label: &str) {
+    let Some(cache) = &state.cache else { return };
+    if state.dry_run {
+        return;
+    }
+
+    let result = async {
+        let plan = plan_archive(state, params).await?;
+        if cache.get(&plan.etag).is_some() {
+            return Ok(());
+        }
+        let started = std::time::Instant::now();
+        let data = build_archive(state, plan.entries, plan.options, &plan.etag, None).await?;
+        remember_checksum(state, &plan.etag, &data).await;
+        info!("Built {} into the cache as {} ({} bytes in {:.1}s)", label, plan.etag, data.len(), started.elapsed().as_secs_f64());
+        store_cached_archive(cache.clone(), &plan.etag, data).await;
+        Ok::<_, ArchiveError>(())
+    }.await;
+
+    if let Err(e) = result {
+        warn!("Could not refresh {}: {}", label, e);
+    }
+}
+
+// --- SHUTDOWN HANDLING ---
+
+/// Resolves once the process receives Ctrl+C (SIGINT) or, on Unix, SIGTERM.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Could not listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                warn!("Could not listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+// --- BANDWIDTH LIMITING ---
+
+/// The limits new connections are held to, shared by the accept loops and replaced by a reload.
+/// Connections keep the limits they were accepted under.
+#[derive(Clone, Default)]
+pub(crate) struct RateLimits(std::sync::Arc<std::sync::RwLock<ConnectionLimits>>);
+
+/// The --max-rate limiter shared by every connection, the rate each connection gets its own limiter
+/// for, and the per-client connection and request caps.
+#[derive(Clone, Default)]
+struct ConnectionLimits {
+    global: Option<std::sync::Arc<RateLimiter>>,
+    per_connection: Option<u64>,
+    /// Connection and request caps per client (see [`ClientLimits`])
+    clients: Option<std::sync::Arc<ClientLimits>>,
+}
+
+impl RateLimits {
+    fn new(limits: ConnectionLimits) -> Self {
+        RateLimits(std::sync::Arc::new(std::sync::RwLock::new(limits)))
+    }
+
+    fn current(&self) -> ConnectionLimits {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Holds new connections to `limits`. An unchanged --max-rate keeps its limiter, so that
+    /// old and new connections share one budget.
+    fn replace(&self, mut limits: ConnectionLimits) {
+        let mut current = self.0.write().unwrap_or_else(|e| e.into_inner());
+        if let (Some(old), Some(new)) = (&current.global, &limits.global) {
+            if old.bytes_per_second() == new.bytes_per_second() {
+                limits.global = Some(old.clone());
+            }
+        }
+        *current = limits;
+    }
+
+    /// The limiters that apply to responses on a new connection.
+    pub(crate) fn for_connection(&self) -> Vec<std::sync::Arc<RateLimiter>> {
+        let limits = self.current();
+        let per_connection = limits.per_connection.map(|rate| std::sync::Arc::new(RateLimiter::new(rate)));
+        limits.global.into_iter().chain(per_connection).collect()
+    }
+
+    /// Counts a new connection from `peer` against the client limits, if there are any.
+    pub(crate) fn open_connection(&self, peer: Peer) -> Option<std::sync::Arc<ConnectionSlot>> {
+        let slot = self.current().clients?.open(peer);
+        if slot.is_refused() {
+            warn!("Too many connections, refusing the requests of {} until it reconnects", peer);
+        }
+        Some(std::sync::Arc::new(slot))
+    }
+}
+
+/// Re-chunks a response body into small pieces and releases each one only when every limiter
+/// allows it. Headers, including Content-Length, are untouched.
+fn throttle_body(body: Body, limiters: Vec<std::sync::Arc<RateLimiter>>) -> Body {
+    use futures_util::StreamExt;
+
+    // Around 50 pieces per second at the slowest rate, so pacing stays smooth
+    let slowest = limiters.iter().map(|limiter| limiter.bytes_per_second()).min().unwrap_or(u64::MAX);
+    let chunk_size = (slowest / 50).clamp(1024, 64 * 1024) as usize;
+
+    let stream = futures_util::stream::unfold(
+        (body.into_data_stream(), bytes::Bytes::new()),
+        move |(mut stream, mut pending)| {
+            let limiters = limiters.clone();
+            async move {
+                if pending.is_empty() {
+                    match stream.next().await? {
+                        Ok(data) => pending = data,
+                        Err(e) => return Some((Err(e), (stream, pending))),
+                    }
+                }
+
+                let chunk = pending.split_to(chunk_size.min(pending.len()));
+                if let Some(ready) = limiters.iter().map(|limiter| limiter.reserve(chunk.len())).max() {
+                    tokio::time::sleep_until(ready).await;
+                }
+                Some((Ok(chunk), (stream, pending)))
+            }
+        },
+    );
+    Body::from_stream(stream)
+}
+
+// --- ACCESS LOG ---
+/// One access log line, written once the response body has been sent or the client went away.
+struct AccessRecord {
+    metrics: std::sync::Arc<Metrics>,
+    /// Lists the request for `/admin/transfers` until it is done
+    in_flight: crate::metrics::RequestGuard,
+    client: Peer,
+    method: axum::http::Method,
+    path: String,
+    range: Option<String>,
+    status: StatusCode,
+    bytes: u64,
+    /// The response body was sent to the end
+    complete: bool,
+    started: std::time::Instant,
+    /// Downloads also get a line in the audit log
+    audit: Option<AuditedDownload>,
+}
+
+impl AccessRecord {
+    fn new<B>(metrics: std::sync::Arc<Metrics>, client: Peer, request: &axum::http::Request<B>) -> Self {
+        let path = request.uri().path_and_query().map_or("/", |path| path.as_str()).to_string();
+        AccessRecord {
+            in_flight: metrics.track_request(client.to_string(), request.method().to_string(), path.clone()),
+            metrics,
+            client,
+            method: request.method().clone(),
+            path,
+            range: request.headers().get(axum::http::header::RANGE)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            status: StatusCode::OK,
+            bytes: 0,
+            complete: false,
+            started: std::time::Instant::now(),
+            audit: None,
+        }
+    }
+}
+
+impl Drop for AccessRecord {
+    fn drop(&mut self) {
+        let duration = self.started.elapsed();
+        self.metrics.record_request(&self.method, self.status.as_u16(), self.bytes, duration);
+        info!(
+            target: "access",
+            client = %self.client,
+            method = %self.method,
+            path = %self.path,
+            range = self.range.as_deref(),
+            status = self.status.as_u16(),
+            bytes = self.bytes,
+            duration_ms = duration.as_millis() as u64,
+            "{} {} {} {}B", self.method, self.path, self.status.as_u16(), self.bytes,
+        );
+        if let Some(audit) = &self.audit {
+            audit.record(DownloadOutcome {
+                client: &self.client.to_string(),
+                method: self.method.as_str(),
+                path: &self.path,
+                status: self.status.as_u16(),
+                bytes: self.bytes,
+                complete: self.complete,
+                started: std::time::SystemTime::now() - duration,
+                duration,
+            });
+        }
+    }
+}
+
+/// Response body that counts the bytes passing through it for the access log. Size hints are
+/// passed on untouched, so Content-Length handling is the same as without logging.
+struct LoggedBody {
+    inner: Body,
+    record: AccessRecord,
+    /// Content length, when known up front
+    length: Option<u64>,
+}
+
+impl hyper::body::Body for LoggedBody {
+    type Data = bytes::Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Result<hyper::body::Frame<Self::Data>, Self::Error>>> {
+        let frame = std::task::ready!(std::pin::Pin::new(&mut self.inner).poll_frame(cx));
+        if let Some(data) = frame.as_ref().and_then(|frame| frame.as_ref().ok()).and_then(|frame| frame.data_ref()) {
+            self.record.bytes += data.len() as u64;
+            self.record.in_flight.sent(data.len() as u64);
+        }
+        // Bodies of a known length are not polled again after their last byte
+        self.record.complete |= frame.is_none() || self.inner.is_end_stream()
+            || self.length.is_some_and(|length| self.record.bytes >= length);
+        std::task::Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+fn log_access(mut response: Response, mut record: AccessRecord) -> Response {
+    record.status = response.status();
+    record.audit = response.extensions_mut().remove::<AuditedDownload>();
+    let length = hyper::body::Body::size_hint(response.body()).exact().or_else(|| {
+        response.headers().get(axum::http::header::CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+    });
+    // An empty body may never be polled
+    record.complete = length == Some(0);
+    response.map(|inner| Body::new(LoggedBody { inner, record, length }))
+}
+
+// --- CONNECTION HANDLING ---
+
+/// Loads a PEM certificate chain and private key into a TLS config that offers HTTP/2 and HTTP/1.1.
+/// With `client_ca`, clients must present a certificate issued by one of the CAs in that file.
+fn load_tls_config(
+    cert_path: &Path,
+    key_path: &Path,
+    client_ca: Option<&Path>,
+) -> io::Result<std::sync::Arc<tokio_rustls::rustls::ServerConfig>> {
+    let certs = rustls_pemfile::certs(&mut io::BufReader::new(File::open(cert_path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("No certificates found in {}", cert_path.display())));
+    }
+
+    let key = rustls_pemfile::private_key(&mut io::BufReader::new(File::open(key_path)?))?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("No private key found in {}", key_path.display())))?;
+
+    let builder = tokio_rustls::rustls::ServerConfig::builder();
+    let builder = match client_ca {
+        Some(ca_path) => {
+            let mut roots = tokio_rustls::rustls::RootCertStore::empty();
+            let file = File::open(ca_path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", ca_path.display(), e)))?;
+            for ca in rustls_pemfile::certs(&mut io::BufReader::new(file)) {
+                roots.add(ca?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", ca_path.display(), e)))?;
+            }
+            if roots.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("No CA certificates found in {}", ca_path.display())));
+            }
+            let verifier = tokio_rustls::rustls::server::WebPkiClientVerifier::builder(std::sync::Arc::new(roots))
+                .build()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(std::sync::Arc::new(config))
+}
+
+/// Runs one request through the routes, pacing the response with the connection's `limiters`
+/// and writing its access log line once the body has been sent. A request over the client
+/// limits of its connection's `slot` is refused with 503 or 429 and a Retry-After instead.
+pub(crate) async fn handle_request<B>(
+    app: Router,
+    limiters: &[std::sync::Arc<RateLimiter>],
+    slot: Option<&ConnectionSlot>,
+    metrics: std::sync::Arc<Metrics>,
+    remote_addr: Peer,
+    mut request: axum::http::Request<B>,
+) -> Response
+where
+    B: axum::body::HttpBody<Data = bytes::Bytes> + Send + 'static,
+    B::Error: Into<axum::BoxError>,
+{
+    use tower::ServiceExt;
+
+    // Same extension `into_make_service_with_connect_info` would provide
+    if let Peer::Ip(addr) = remote_addr {
+        request.extensions_mut().insert(axum::extract::ConnectInfo(addr));
+    }
+    let record = AccessRecord::new(metrics, remote_addr, &request);
+    if let Some(Err((refusal, retry_after))) = slot.map(ConnectionSlot::admit) {
+        return log_access(refused_request(&request, refusal, retry_after, slot.is_some_and(ConnectionSlot::is_refused)), record);
+    }
+    let response = match app.oneshot(request.map(Body::new)).await {
+        Ok(response) => response,
+        Err(never) => match never {},
+    };
+    let response = match limiters.is_empty() {
+        true => response,
+        false => response.map(|body| throttle_body(body, limiters.to_vec())),
+    };
+    log_access(response, record)
+}
+
+/// A 503 Service Unavailable (the server is busy) or 429 Too Many Requests (this client is over
+/// its caps) response telling the client how many seconds to wait. When the connection itself
+/// is over a cap, an HTTP/1 client is also asked to close it.
+fn refused_request<B>(request: &axum::http::Request<B>, refusal: Refusal, retry_after: Duration, close: bool) -> Response {
+    let (status, message) = match refusal {
+        Refusal::Busy => (StatusCode::SERVICE_UNAVAILABLE, "The server is busy, try again later"),
+        Refusal::TooMany => (StatusCode::TOO_MANY_REQUESTS, "Too many requests, try again later"),
+    };
+    debug!("Responding with {} to {} {} (retry after {:.0}s)", status, request.method(), request.uri().path(), retry_after.as_secs_f64().ceil().max(1.0));
+    let mut response = retry_later(status, retry_after, message);
+    // HTTP/2 and HTTP/3 forbid connection-specific headers
+    if close && request.version() <= axum::http::Version::HTTP_11 {
+        response.headers_mut().insert(axum::http::header::CONNECTION, HeaderValue::from_static("close"));
+    }
+    response
+}
+
+/// A refusal asking the client to come back after `retry_after` (whole seconds, at least one).
+fn retry_later(status: StatusCode, retry_after: Duration, message: &'static str) -> Response {
+    let seconds = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    (status, [(axum::http::header::RETRY_AFTER, HeaderValue::from(seconds))], message).into_response()
+}
+
+/// What every TCP connection is served with.
+#[derive(Clone)]
+struct ConnectionContext {
+    app: Router,
+    rate_limits: RateLimits,
+    metrics: std::sync::Arc<Metrics>,
+    timeouts: Timeouts,
+    http: HttpSettings,
+}
+
+/// How connections speak HTTP, from [`ServerConfig`]'s keep-alive and header limits.
+#[derive(Clone, Copy)]
+struct HttpSettings {
+    keep_alive: bool,
+    max_header_size: usize,
+    max_headers: usize,
+}
+
+/// Serves HTTP/1.1 or HTTP/2 on one accepted connection until it closes, times out, or
+/// graceful shutdown finishes it.
+async fn serve_connection<S>(
+    stream: S,
+    remote_addr: Peer,
+    context: ConnectionContext,
+    watcher: hyper_util::server::graceful::Watcher,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let ConnectionContext { app, rate_limits, metrics, timeouts, http } = context;
+    let _connection = metrics.track_connection();
+    let limiters = rate_limits.for_connection();
+    let slot = rate_limits.open_connection(remote_addr);
+    let activity = Activity::default();
+    let stream = TimeoutStream::new(stream, timeouts, activity.clone());
+    let hyper_service = hyper::service::service_fn(move |request: axum::http::Request<hyper::body::Incoming>| {
+        let (app, limiters, slot, metrics) = (app.clone(), limiters.clone(), slot.clone(), metrics.clone());
+        let active = activity.begin();
+        async move {
+            let response = handle_request(app, &limiters, slot.as_deref(), metrics, remote_addr, request).await;
+            Ok::<_, std::convert::Infallible>(response.map(|body| Body::new(ActiveBody::new(body, active))))
+        }
+    });
+
+    let mut builder = hyper_util::server::conn::auto::Builder::new(hyper_util::rt::TokioExecutor::new());
+    // The header timeout also covers the wait for the next request on a kept-alive HTTP/1 connection
+    builder.http1()
+        .timer(hyper_util::rt::TokioTimer::new())
+        .header_read_timeout(timeouts.read)
+        .keep_alive(http.keep_alive)
+        // The read buffer has to hold the whole head; bodies are read through it in pieces
+        .max_buf_size(http.max_header_size)
+        .max_headers(http.max_headers);
+    builder.http2()
+        .max_header_list_size(u32::try_from(http.max_header_size).unwrap_or(u32::MAX));
+    let connection = builder.serve_connection_with_upgrades(hyper_util::rt::TokioIo::new(stream), hyper_service);
+
+    if let Err(e) = watcher.watch(connection.into_owned()).await {
+        debug!("Connection error from {}: {}", remote_addr, e);
+    }
+}
+
+/// Opens a listening socket on `addr` that queues up to `backlog` connections. IPv6 sockets are
+/// made IPv6-only, so that `[::]` and `0.0.0.0` can be bound side by side on every platform.
+fn bind_listener(addr: SocketAddr, backlog: u32) -> io::Result<tokio::net::TcpListener> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::STREAM, Some(socket2::Protocol::TCP))?;
+    // Same as tokio's own bind: allow restarting while old connections sit in TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(i32::try_from(backlog).unwrap_or(i32::MAX))?;
+    socket.set_nonblocking(true)?;
+    tokio::net::TcpListener::from_std(socket.into())
+}
+
+/// Serves on a listening socket systemd bound for us: TCP for `ListenStream=<port>`, Unix for
+/// `ListenStream=/path`.
+fn adopt_listener(socket: socket2::Socket) -> io::Result<Listener> {
+    if socket.r#type()? != socket2::Type::STREAM {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Not a stream socket (use ListenStream=)"));
+    }
+    socket.set_nonblocking(true)?;
+    let addr = socket.local_addr()?;
+    if addr.as_socket().is_some() {
+        return tokio::net::TcpListener::from_std(socket.into()).map(Listener::Tcp);
+    }
+    #[cfg(unix)]
+    if addr.is_unix() {
+        let listener = std::os::unix::net::UnixListener::from(std::os::fd::OwnedFd::from(socket));
+        return tokio::net::UnixListener::from_std(listener).map(Listener::Unix);
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidInput, "Neither a TCP nor a Unix socket"))
+}
+
+/// Default permission bits of the Unix socket: the owner and its group (e.g. the proxy's) may connect.
+pub const DEFAULT_UNIX_SOCKET_MODE: u32 = 0o660;
+
+/// Listens on a Unix socket at `path` with permission bits `mode`. A socket file left behind by
+/// a server that is no longer running is replaced; a live one or any other file is an error.
+#[cfg(unix)]
+fn bind_unix_listener(path: &Path, mode: u32) -> io::Result<Listener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "File exists and is not a socket"));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "Another server is listening on it"));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(Listener::Unix(listener))
+}
+
+#[cfg(not(unix))]
+fn bind_unix_listener(_path: &Path, _mode: u32) -> io::Result<Listener> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets are not supported on this platform"))
+}
+
+/// A socket the server accepts connections on.
+enum Listener {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+/// A connection accepted from a [`Listener`].
+enum Accepted {
+    Tcp(tokio::net::TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+impl Listener {
+    async fn accept(&self) -> io::Result<(Accepted, Peer)> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().await
+                .map(|(stream, addr)| (Accepted::Tcp(stream), Peer::Ip(addr))),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.accept().await
+                .map(|(stream, _)| (Accepted::Unix(stream), Peer::Unix)),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr(),
+            #[cfg(unix)]
+            Listener::Unix(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, "Unix sockets have no IP address")),
+        }
+    }
+}
+
+/// Who is on the other end of a connection, as shown in logs.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Peer {
+    /// A TCP or QUIC peer
+    Ip(SocketAddr),
+    /// Unix socket peers (usually a local reverse proxy) have no address
+    Unix,
+}
+
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Peer::Ip(addr) => write!(f, "{}", addr.ip()),
+            Peer::Unix => f.write_str("unix"),
+        }
+    }
+}
+
+/// Hands an accepted connection to its own task, completing the TLS handshake first when enabled.
+fn spawn_connection<S>(
+    stream: S,
+    remote_addr: Peer,
+    tls: &Option<tokio_rustls::TlsAcceptor>,
+    context: ConnectionContext,
+    watcher: hyper_util::server::graceful::Watcher,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    match tls {
+        Some(acceptor) => {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                match acceptor.accept(stream).await {
+                    Ok(tls_stream) => serve_connection(tls_stream, remote_addr, context, watcher).await,
+                    Err(e) => warn!("TLS handshake with {} failed: {}", remote_addr, e),
+                }
+            });
+        }
+        None => {
+            tokio::spawn(serve_connection(stream, remote_addr, context, watcher));
+        }
+    }
+}
+
+/// Accepts connections on all `listeners` (optionally completing a TLS handshake first) until
+/// `shutdown` resolves, then waits up to `shutdown_timeout` for in-flight requests to finish.
+async fn run_accept_loop(
+    listeners: Vec<Listener>,
+    tls: Option<tokio_rustls::TlsAcceptor>,
+    context: ConnectionContext,
+    shutdown: impl std::future::Future<Output = ()>,
+    shutdown_timeout: Duration,
+) {
+    let graceful = hyper_util::server::graceful::GracefulShutdown::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        let accept_any = futures_util::future::select_all(listeners.iter().map(|listener| Box::pin(listener.accept())));
+        let (stream, remote_addr) = tokio::select! {
+            (accepted, _, _) = accept_any => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Error accepting connection: {}", e);
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let (context, watcher) = (context.clone(), graceful.watcher());
+        match stream {
+            Accepted::Tcp(stream) => spawn_connection(stream, remote_addr, &tls, context, watcher),
+            #[cfg(unix)]
+            Accepted::Unix(stream) => spawn_connection(stream, remote_addr, &tls, context, watcher),
+        }
+    }
+
+    // Stop accepting connections, then give in-flight downloads a bounded time to finish
+    drop(listeners);
+    info!("Shutdown requested, waiting up to {}s for in-flight downloads...", shutdown_timeout.as_secs());
+
+    tokio::select! {
+        _ = graceful.shutdown() => {}
+        _ = tokio::time::sleep(shutdown_timeout) => {
+            warn!("Shutdown timeout elapsed, dropping remaining connections");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The (start, length) pairs a Range header asks for in an archive of `size` bytes.
+    fn ranges(header: &str, size: u64) -> Result<Option<Vec<(u64, u64)>>, StatusCode> {
+        match parse_range_header(&HeaderValue::from_str(header).unwrap(), size) {
+            Ok(RangeRequest::Partial(ranges)) => Ok(Some(ranges.iter().map(|range| (range.start, range.length)).collect())),
+            Ok(RangeRequest::Full) => Ok(None),
+            Ok(RangeRequest::Unsatisfiable) => Ok(Some(Vec::new())),
+            Err((status, _)) => Err(status),
+        }
+    }
+
+    #[test]
+    fn single_ranges() {
+        assert_eq!(ranges("bytes=0-99", 1000), Ok(Some(vec![(0, 100)])));
+        assert_eq!(ranges("bytes=900-", 1000), Ok(Some(vec![(900, 100)])));
+        assert_eq!(ranges("bytes=-100", 1000), Ok(Some(vec![(900, 100)])));
+        // Ends past the archive are clamped, and a suffix longer than it is all of it
+        assert_eq!(ranges("bytes=500-5000", 1000), Ok(Some(vec![(500, 500)])));
+        assert_eq!(ranges("bytes=-5000", 1000), Ok(Some(vec![(0, 1000)])));
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_ranges_are_merged() {
+        assert_eq!(ranges("bytes=500-599,0-99,50-149", 1000), Ok(Some(vec![(0, 150), (500, 100)])));
+        assert_eq!(ranges("bytes=0-99,100-199", 1000), Ok(Some(vec![(0, 200)])));
+        assert_eq!(ranges("bytes=0-9,-10,-5", 1000), Ok(Some(vec![(0, 10), (990, 10)])));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(ranges("bytes=1000-", 1000), Ok(Some(Vec::new())));
+        assert_eq!(ranges("bytes=2000-3000,1000-1999", 1000), Ok(Some(Vec::new())));
+        assert_eq!(ranges("bytes=-0", 1000), Ok(Some(Vec::new())));
+        assert_eq!(ranges("bytes=-10", 0), Ok(Some(Vec::new())));
+    }
+
+    #[test]
+    fn other_units_and_too_many_ranges_get_the_whole_archive() {
+        assert_eq!(ranges("items=0-5", 1000), Ok(None));
+        let many: Vec<_> = (0..=MAX_BYTE_RANGES as u64).map(|index| format!("{}-{}", index * 10, index * 10 + 1)).collect();
+        assert_eq!(ranges(&format!("bytes={}", many.join(",")), 10_000), Ok(None));
+        let allowed: Vec<_> = many[..MAX_BYTE_RANGES].to_vec();
+        assert_eq!(ranges(&format!("bytes={}", allowed.join(",")), 10_000).unwrap().unwrap().len(), MAX_BYTE_RANGES);
+    }
+
+    #[test]
+    fn malformed_ranges_are_rejected() {
+        for header in ["bytes", "bytes=", "bytes=abc", "bytes=10-5", "bytes=--5", "bytes=0-1,x"] {
+            assert_eq!(ranges(header, 1000), Err(StatusCode::BAD_REQUEST), "{}", header);
+        }
+    }
+
+    #[test]
+    fn if_range_needs_the_same_strong_etag() {
+        let mut headers = HeaderMap::new();
+        assert!(if_range_matches(&headers, "\"abc\""));
+        headers.insert(axum::http::header::IF_RANGE, HeaderValue::from_static("\"abc\""));
+        assert!(if_range_matches(&headers, "\"abc\""));
+        assert!(!if_range_matches(&headers, "\"def\""));
+        headers.insert(axum::http::header::IF_RANGE, HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert!(!if_range_matches(&headers, "\"abc\""));
+    }
+
+    #[test]
+    fn archive_checksums_are_capped() {
+        let checksums = ArchiveChecksums::default();
+        let checksum = || ArchiveChecksum { sha256: String::new(), size: 0 };
+        for index in 0..MAX_ARCHIVE_CHECKSUMS {
+            remember_archive_checksum(&checksums, &index.to_string(), checksum());
+        }
+        // Measuring one again does not make room
+        remember_archive_checksum(&checksums, "0", checksum());
+        assert_eq!(checksums.lock().unwrap().len(), MAX_ARCHIVE_CHECKSUMS);
+        remember_archive_checksum(&checksums, "new", checksum());
+        let checksums = checksums.lock().unwrap();
+        assert_eq!(checksums.len(), 1);
+        assert!(checksums.contains_key("new"));
+    }
+
+    #[test]
+    fn cmd_quote_escapes_what_cmd_would_run() {
+        assert_eq!(cmd_quote(r"C:\share\report.txt"), r"C:\share\report.txt");
+        assert_eq!(cmd_quote(r"C:\share\a&calc.txt"), r"C:\share\a^&calc.txt");
+        assert_eq!(cmd_quote("a|b>c<d^e(f)!g%PATH%"), "a^|b^>c^<d^^e^(f^)^!g^%PATH^%");
+        assert_eq!(cmd_quote(r"C:\my files\a & b.txt"), r#"^"C:\my files\a ^& b.txt^""#);
+        assert_eq!(cmd_quote(""), r#"^"^""#);
+        // Backslashes only need doubling before a quote, including the closing one
+        assert_eq!(cmd_quote(r"C:\my dir\"), r#"^"C:\my dir\\^""#);
+        assert_eq!(cmd_quote(r#"say "hi""#), r#"^"say \^"hi\^"^""#);
+    }
+}
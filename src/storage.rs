@@ -0,0 +1,165 @@
+//! Where served files are read from: the local filesystem, or an object store (see [`crate::s3`]).
+//!
+//! Collecting entries, building archives and listing directories go through [`Storage`], so
+//! they work the same whatever the root is. All methods block; call them from blocking threads.
+
+use std::{
+    ffi::OsString,
+    fmt,
+    io::{self, Read, Seek},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use crate::archive::EntryKind;
+
+/// The operations archiving and listing need from a storage backend. Paths are absolute:
+/// local paths for [`LocalStorage`], `/`-rooted keys for object stores.
+pub(crate) trait StorageBackend: Send + Sync + fmt::Debug {
+    /// Metadata of `path`, following symlinks.
+    fn metadata(&self, path: &Path) -> io::Result<StorageMetadata>;
+    /// Metadata of `path` itself, even when it is a symlink.
+    fn symlink_metadata(&self, path: &Path) -> io::Result<StorageMetadata>;
+    /// Names of the entries directly inside the directory `path`, in no particular order.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>>;
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+    /// `path` with `.`, `..` and symlinks resolved; fails when it does not exist.
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+    /// The contents of the file at `path`.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>>;
+    /// Up to `len` bytes of the file at `path`, starting at `start`.
+    fn read_range(&self, path: &Path, start: u64, len: u64) -> io::Result<Vec<u8>>;
+    /// Whether paths are local filesystem paths, usable with `std::fs`, globbing and watching.
+    fn is_local(&self) -> bool {
+        false
+    }
+}
+
+/// A shared handle on a storage backend; the local filesystem by default.
+#[derive(Clone, Debug)]
+pub(crate) struct Storage(Arc<dyn StorageBackend>);
+
+impl Storage {
+    pub(crate) fn new(backend: impl StorageBackend + 'static) -> Self {
+        Storage(Arc::new(backend))
+    }
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Storage::new(LocalStorage)
+    }
+}
+
+impl std::ops::Deref for Storage {
+    type Target = dyn StorageBackend;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+/// What archiving needs to know about a file, directory or link.
+#[derive(Clone, Debug)]
+pub(crate) struct StorageMetadata {
+    /// None for anything that cannot be archived (sockets, devices, FIFOs)
+    pub kind: Option<EntryKind>,
+    pub len: u64,
+    pub modified: SystemTime,
+    /// Permission bits
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    /// A backend's own version tag (an object's ETag), which changes with the contents
+    pub version: Option<String>,
+}
+
+impl StorageMetadata {
+    pub(crate) fn is_dir(&self) -> bool {
+        self.kind == Some(EntryKind::Dir)
+    }
+
+    pub(crate) fn is_file(&self) -> bool {
+        self.kind == Some(EntryKind::File)
+    }
+
+    pub(crate) fn is_symlink(&self) -> bool {
+        self.kind == Some(EntryKind::Symlink)
+    }
+}
+
+impl From<std::fs::Metadata> for StorageMetadata {
+    fn from(metadata: std::fs::Metadata) -> Self {
+        let file_type = metadata.file_type();
+        let kind = if file_type.is_symlink() {
+            Some(EntryKind::Symlink)
+        } else if file_type.is_dir() {
+            Some(EntryKind::Dir)
+        } else if file_type.is_file() {
+            Some(EntryKind::File)
+        } else {
+            None
+        };
+
+        #[cfg(unix)]
+        let (mode, uid, gid) = {
+            use std::os::unix::fs::MetadataExt;
+            (metadata.mode() & 0o7777, metadata.uid() as u64, metadata.gid() as u64)
+        };
+        #[cfg(not(unix))]
+        let (mode, uid, gid) = (if metadata.permissions().readonly() { 0o444 } else { 0o644 }, 0, 0);
+
+        StorageMetadata {
+            kind,
+            len: metadata.len(),
+            modified: metadata.modified().unwrap_or(std::time::UNIX_EPOCH),
+            mode,
+            uid,
+            gid,
+            version: None,
+        }
+    }
+}
+
+/// Files on the local filesystem.
+#[derive(Debug)]
+pub(crate) struct LocalStorage;
+
+impl StorageBackend for LocalStorage {
+    fn metadata(&self, path: &Path) -> io::Result<StorageMetadata> {
+        std::fs::metadata(path).map(StorageMetadata::from)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<StorageMetadata> {
+        std::fs::symlink_metadata(path).map(StorageMetadata::from)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        std::fs::read_dir(path)?.map(|entry| entry.map(|entry| entry.file_name())).collect()
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        std::fs::read_link(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        path.canonicalize()
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(std::fs::File::open(path)?))
+    }
+
+    fn read_range(&self, path: &Path, start: u64, len: u64) -> io::Result<Vec<u8>> {
+        let mut file = std::fs::File::open(path)?;
+        file.seek(io::SeekFrom::Start(start))?;
+        let mut data = Vec::new();
+        file.take(len).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,89 @@
+//! Running under systemd: socket activation and readiness notification.
+//!
+//! With a `.socket` unit, systemd binds the listening sockets itself and starts the server on
+//! the first connection, passing the sockets as file descriptors 3 and up (`LISTEN_FDS`).
+//! With `Type=notify`, it waits for `READY=1` on `NOTIFY_SOCKET` before treating the service
+//! as started. Both do nothing when the variables are not set, or on platforms without systemd.
+
+use std::io;
+
+/// The first file descriptor systemd passes (`SD_LISTEN_FDS_START`).
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Whether systemd passed this process listening sockets, i.e. `LISTEN_FDS` is set and
+/// `LISTEN_PID` names this process.
+pub fn is_socket_activated() -> bool {
+    listen_fds_count() > 0
+}
+
+fn listen_fds_count() -> i32 {
+    let for_us = std::env::var("LISTEN_PID").ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    match for_us {
+        true => std::env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse().ok()).unwrap_or(0),
+        false => 0,
+    }
+}
+
+/// Takes ownership of the sockets systemd passed, in the order of the socket unit's `Listen*=`
+/// lines. The variables are cleared so that child processes do not take them as well.
+#[cfg(unix)]
+pub(crate) fn take_listen_fds() -> io::Result<Vec<socket2::Socket>> {
+    use std::os::fd::FromRawFd;
+
+    let count = listen_fds_count();
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: systemd hands these descriptors to this process, and nothing else owns them
+            let socket = unsafe { socket2::Socket::from_raw_fd(fd) };
+            // Inherited descriptors would otherwise leak into processes the server starts
+            socket.set_cloexec(true)?;
+            Ok(socket)
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn take_listen_fds() -> io::Result<Vec<socket2::Socket>> {
+    Ok(Vec::new())
+}
+
+/// Sends `state` (e.g. `READY=1` or `STOPPING=1`) to the service manager, if it asked for
+/// notifications.
+pub(crate) fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else { return };
+    if let Err(e) = send_notification(&path, state) {
+        tracing::debug!("Could not notify systemd ({}): {}", state, e);
+    }
+}
+
+#[cfg(unix)]
+fn send_notification(path: &std::ffi::OsStr, state: &str) -> io::Result<()> {
+    use std::os::unix::{ffi::OsStrExt, net::UnixDatagram};
+
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        // A leading @ names a socket in the abstract namespace
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send_notification(_path: &std::ffi::OsStr, _state: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Service notifications need Unix sockets"))
+}
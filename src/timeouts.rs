@@ -0,0 +1,230 @@
+//! Timeouts that reap stalled and idle connections.
+//!
+//! A client that stops reading a response, stops sending a request, or leaves a connection
+//! open without using it would otherwise hold the connection (and, mid-download, the archive
+//! being sent) for as long as it likes.
+
+use axum::body::Body;
+use futures_util::StreamExt;
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+/// How long the server waits for clients; `None` waits forever.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Timeouts {
+    /// For the headers of a request and, while a handler reads a request body, for each piece of it
+    pub(crate) read: Option<Duration>,
+    /// For a client to accept more of a response it has stopped reading
+    pub(crate) write: Option<Duration>,
+    /// For the next request on a connection with none in flight
+    pub(crate) idle: Option<Duration>,
+}
+
+/// Counts the requests in flight on one connection, which is only idle without any.
+#[derive(Clone, Default)]
+pub(crate) struct Activity(Arc<AtomicUsize>);
+
+impl Activity {
+    /// Marks a request as in flight until the returned guard is dropped.
+    pub(crate) fn begin(&self) -> ActiveRequest {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        ActiveRequest(self.0.clone())
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.load(Ordering::Relaxed) == 0
+    }
+}
+
+/// A request in flight; see [`Activity::begin`].
+pub(crate) struct ActiveRequest(Arc<AtomicUsize>);
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A connection that fails with [`io::ErrorKind::TimedOut`] once a write has made no progress
+/// for the write timeout, or nothing has been read or written for the idle timeout while no
+/// request was in flight.
+pub(crate) struct TimeoutStream<S> {
+    inner: S,
+    write_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    activity: Activity,
+    write_deadline: Option<Pin<Box<Sleep>>>,
+    idle_deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> TimeoutStream<S> {
+    pub(crate) fn new(inner: S, timeouts: Timeouts, activity: Activity) -> Self {
+        TimeoutStream {
+            inner,
+            write_timeout: timeouts.write,
+            idle_timeout: timeouts.idle,
+            activity,
+            write_deadline: None,
+            idle_deadline: timeouts.idle.map(|idle| Box::pin(tokio::time::sleep(idle))),
+        }
+    }
+
+    /// Restarts the idle timeout after bytes went either way.
+    fn touch(&mut self) {
+        if let (Some(deadline), Some(idle)) = (&mut self.idle_deadline, self.idle_timeout) {
+            deadline.as_mut().reset(Instant::now() + idle);
+        }
+    }
+
+    fn check_idle(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        let (Some(deadline), Some(idle)) = (&mut self.idle_deadline, self.idle_timeout) else { return Ok(()) };
+        while deadline.as_mut().poll(cx).is_ready() {
+            if self.activity.is_idle() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "Connection idle for too long"));
+            }
+            // A request is in flight without sending anything yet, e.g. while its archive is built
+            deadline.as_mut().reset(Instant::now() + idle);
+        }
+        Ok(())
+    }
+
+    /// Called while a write is pending: starts the write timeout, or fails once it has run out.
+    fn check_write_stall(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        let Some(timeout) = self.write_timeout else { return Ok(()) };
+        let deadline = self.write_deadline.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        match deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => Err(io::Error::new(io::ErrorKind::TimedOut, "Client stopped reading the response")),
+            Poll::Pending => Ok(()),
+        }
+    }
+
+    fn on_write<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>, progressed: impl Fn(&T) -> bool) -> Poll<io::Result<T>> {
+        match poll {
+            Poll::Ready(result) => {
+                self.write_deadline = None;
+                if result.as_ref().is_ok_and(progressed) {
+                    self.touch();
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => match self.check_write_stall(cx) {
+                Ok(()) => Poll::Pending,
+                Err(e) => Poll::Ready(Err(e)),
+            },
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TimeoutStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                if buf.filled().len() > filled {
+                    self.touch();
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => match self.check_idle(cx) {
+                Ok(()) => Poll::Pending,
+                Err(e) => Poll::Ready(Err(e)),
+            },
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TimeoutStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.on_write(cx, poll, |&written| written > 0)
+    }
+
+    fn poll_write_vectored(mut self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        self.on_write(cx, poll, |&written| written > 0)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        self.on_write(cx, poll, |_| false)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// A request body that fails once the client has sent nothing for `timeout` while the handler
+/// was waiting for more of it.
+pub(crate) fn limit_body_stalls(body: Body, timeout: Duration) -> Body {
+    let stream = futures_util::stream::unfold(Some(body.into_data_stream()), move |stream| async move {
+        let mut stream = stream?;
+        match tokio::time::timeout(timeout, stream.next()).await {
+            Ok(Some(Ok(chunk))) => Some((Ok(chunk), Some(stream))),
+            Ok(Some(Err(e))) => Some((Err(io::Error::other(e)), None)),
+            Ok(None) => None,
+            Err(_) => Some((Err(io::Error::new(io::ErrorKind::TimedOut, "Client stopped sending the request body")), None)),
+        }
+    });
+    Body::from_stream(stream)
+}
+
+/// A response body that keeps its request counted as in flight until it has been sent.
+/// Size hints are passed on untouched.
+pub(crate) struct ActiveBody {
+    inner: Body,
+    _request: ActiveRequest,
+}
+
+impl ActiveBody {
+    pub(crate) fn new(inner: Body, request: ActiveRequest) -> Self {
+        ActiveBody { inner, _request: request }
+    }
+}
+
+impl hyper::body::Body for ActiveBody {
+    type Data = bytes::Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<hyper::body::Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// The status for a request body that could not be read: 408 Request Timeout when
+/// [`limit_body_stalls`] gave up on the client, `otherwise` for anything else.
+pub(crate) fn body_error_status(error: &(dyn std::error::Error + 'static), otherwise: axum::http::StatusCode) -> axum::http::StatusCode {
+    let mut source = Some(error);
+    while let Some(e) = source {
+        if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::TimedOut) {
+            return axum::http::StatusCode::REQUEST_TIMEOUT;
+        }
+        source = e.source();
+    }
+    otherwise
+}
@@ -0,0 +1,59 @@
+//! Watching the server root for changes (`--watch`).
+//!
+//! Filesystem notifications arrive in bursts (an editor saving a file, a `git checkout`), so
+//! they are collected until the tree has been quiet for a moment and then reported as one change.
+
+use notify::Watcher;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+/// How long the tree must be quiet before a burst of events counts as one change.
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+/// Longest a change is held back while events keep arriving.
+const MAX_SETTLE_WAIT: Duration = Duration::from_secs(10);
+
+/// Recursive notifications for a directory tree, minus some ignored subtrees.
+pub(crate) struct RootWatcher {
+    /// Kept alive for as long as events are wanted
+    _watcher: notify::RecommendedWatcher,
+    events: mpsc::UnboundedReceiver<PathBuf>,
+}
+
+impl RootWatcher {
+    /// Starts watching `root` and everything below it. Events for paths under `ignored` (e.g.
+    /// a cache directory inside the root, which the server itself writes to) are dropped.
+    pub(crate) fn start(root: &Path, ignored: Vec<PathBuf>) -> notify::Result<Self> {
+        let (sender, events) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            let Ok(event) = event else { return };
+            if event.kind.is_access() {
+                return;
+            }
+            for path in event.paths {
+                if !ignored.iter().any(|ignored| path.starts_with(ignored)) {
+                    let _ = sender.send(path);
+                }
+            }
+        })?;
+        watcher.watch(root, notify::RecursiveMode::Recursive)?;
+        Ok(RootWatcher { _watcher: watcher, events })
+    }
+
+    /// Waits for the next change and for the tree to settle afterwards (or at most
+    /// `MAX_SETTLE_WAIT`), returning how many distinct paths were touched.
+    pub(crate) async fn next_change(&mut self) -> Option<usize> {
+        let mut changed = std::collections::HashSet::new();
+        changed.insert(self.events.recv().await?);
+        let started = tokio::time::Instant::now();
+        while started.elapsed() < MAX_SETTLE_WAIT {
+            match tokio::time::timeout(SETTLE_TIME, self.events.recv()).await {
+                Ok(Some(path)) => changed.insert(path),
+                _ => break,
+            };
+        }
+        Some(changed.len())
+    }
+}
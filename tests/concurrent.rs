@@ -0,0 +1,75 @@
+//! Simultaneous downloads each get a whole, identical archive, with or without a cache.
+
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use rust_file_archive_server::ServerConfig;
+
+const CLIENTS: usize = 12;
+
+fn sample_root() -> PathBuf {
+    let root = common::temp_dir("concurrent");
+    for index in 0..20 {
+        common::write(&root, &format!("dir/file-{:02}.txt", index), format!("line {}\n", index).repeat(5000));
+    }
+    common::write(&root, "other.txt", "a different archive\n");
+    root
+}
+
+/// Downloads `query` from `CLIENTS` clients at once and returns what each received.
+async fn download_all(base: &str, query: &str) -> Vec<bytes::Bytes> {
+    let downloads: Vec<_> = (0..CLIENTS).map(|_| {
+        let url = format!("{}/download?{}", base, query);
+        tokio::spawn(async move {
+            let response = reqwest::get(url).await.unwrap();
+            assert_eq!(response.status(), 200);
+            response.bytes().await.unwrap()
+        })
+    }).collect();
+    let mut bodies = Vec::new();
+    for download in downloads {
+        bodies.push(download.await.unwrap());
+    }
+    bodies
+}
+
+fn assert_identical(bodies: &[bytes::Bytes], entries: usize) {
+    assert!(bodies.iter().all(|body| body == &bodies[0]), "the downloads differ");
+    assert_eq!(common::tar_gz_names(&bodies[0]).len(), entries);
+}
+
+fn temp_files(dir: &Path) -> Vec<PathBuf> {
+    std::fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "tmp"))
+        .collect()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_downloads_are_identical() {
+    let base = common::serve(ServerConfig::new(sample_root())).await;
+    // Both archives are built at the same time
+    let (dir, other) = tokio::join!(download_all(&base, "files=dir"), download_all(&base, "files=other.txt"));
+    assert_identical(&dir, 21);
+    assert_identical(&other, 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_downloads_through_the_cache_are_identical() {
+    for stream in [false, true] {
+        let cache_dir = common::temp_dir("concurrent-cache");
+        let mut config = ServerConfig::new(sample_root());
+        config.cache_dir = Some(cache_dir.clone());
+        config.stream = stream;
+        let base = common::serve(config).await;
+
+        let first = download_all(&base, "files=dir").await;
+        assert_identical(&first, 21);
+        // Later ones are served from the cache, and must match what was built
+        let cached = download_all(&base, "files=dir").await;
+        assert_eq!(cached[0], first[0]);
+        assert_identical(&cached, 21);
+        assert_eq!(temp_files(&cache_dir), Vec::<PathBuf>::new(), "temporary files were left behind");
+    }
+}
@@ -0,0 +1,99 @@
+//! WebDAV writes (`--webdav-writable`): PUT status codes, paths it must refuse, and partial
+//! uploads that must not be left behind.
+
+mod common;
+
+use std::{path::{Path, PathBuf}, time::Duration};
+
+use rust_file_archive_server::ServerConfig;
+
+/// Serves a writable share of a root holding `docs/` and an excluded `secret/`, and returns it too.
+async fn dav_server() -> (String, PathBuf) {
+    let root = common::temp_dir("dav");
+    common::write(&root, "docs/readme.txt", "read me\n");
+    common::write(&root, "secret/keys.txt", "keys\n");
+    let mut config = ServerConfig::new(root.clone());
+    config.webdav = true;
+    config.webdav_writable = true;
+    config.exclude = vec!["secret/".to_string(), "*.key".to_string()];
+    config.max_upload_size = 1024;
+    (common::serve(config).await, root)
+}
+
+async fn put(base: &str, path: &str, body: impl Into<reqwest::Body>) -> reqwest::StatusCode {
+    reqwest::Client::new().put(format!("{}/dav/{}", base, path)).body(body).send().await.unwrap().status()
+}
+
+/// The hidden `.name.upload` files a PUT writes to before renaming.
+fn leftover_uploads(dir: &Path) -> Vec<PathBuf> {
+    std::fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.to_string_lossy().ends_with(".upload"))
+        .collect()
+}
+
+#[tokio::test]
+async fn put_creates_then_replaces() {
+    let (base, root) = dav_server().await;
+
+    assert_eq!(put(&base, "docs/new.txt", "first").await, 201);
+    assert_eq!(std::fs::read_to_string(root.join("docs/new.txt")).unwrap(), "first");
+    assert_eq!(put(&base, "docs/new.txt", "second").await, 204);
+    assert_eq!(std::fs::read_to_string(root.join("docs/new.txt")).unwrap(), "second");
+
+    // A collection is neither replaced nor created through
+    assert_eq!(put(&base, "docs", "data").await, 405);
+    assert_eq!(put(&base, "missing/new.txt", "data").await, 409);
+    assert!(leftover_uploads(&root.join("docs")).is_empty());
+}
+
+#[tokio::test]
+async fn put_stays_in_the_share() {
+    let (base, root) = dav_server().await;
+
+    for path in ["..%2Fescaped.txt", "docs/..%2F..%2Fescaped.txt"] {
+        assert_eq!(put(&base, path, "data").await, 400, "{}", path);
+    }
+    assert!(!root.parent().unwrap().join("escaped.txt").exists());
+
+    for path in ["secret/new.txt", "docs/new.key"] {
+        assert_eq!(put(&base, path, "data").await, 403, "{}", path);
+    }
+    assert!(!root.join("secret/new.txt").exists());
+    assert!(!root.join("docs/new.key").exists());
+    assert_eq!(reqwest::Client::new().request(reqwest::Method::from_bytes(b"MKCOL").unwrap(), format!("{}/dav/secret/sub", base))
+        .send().await.unwrap().status(), 403);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn put_does_not_follow_links_out_of_the_share() {
+    let (base, root) = dav_server().await;
+    let outside = common::temp_dir("dav-outside");
+    std::os::unix::fs::symlink(&outside, root.join("out")).unwrap();
+
+    assert!(put(&base, "out/planted.txt", "data").await.is_client_error());
+    assert!(!outside.join("planted.txt").exists());
+}
+
+#[tokio::test]
+async fn failed_puts_leave_nothing_behind() {
+    let (base, root) = dav_server().await;
+
+    assert_eq!(put(&base, "docs/big.txt", vec![b'x'; 4096]).await, 413);
+    assert!(!root.join("docs/big.txt").exists());
+    assert!(leftover_uploads(&root.join("docs")).is_empty());
+
+    // A client that goes away part way through
+    let broken = futures_util::stream::iter([
+        Ok(bytes::Bytes::from_static(b"the start")),
+        Err(std::io::Error::other("client gave up")),
+    ]);
+    let result = reqwest::Client::new().put(format!("{}/dav/docs/broken.txt", base))
+        .body(reqwest::Body::wrap_stream(broken)).send().await;
+    assert!(!matches!(result, Ok(response) if response.status().is_success()));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    assert!(!root.join("docs/broken.txt").exists());
+    assert!(leftover_uploads(&root.join("docs")).is_empty());
+}
@@ -0,0 +1,62 @@
+//! Dry runs report what an archive would hold without building or writing it.
+
+mod common;
+
+use rust_file_archive_server::{archive::DryRunReport, ArchiveBuilder, ServerConfig};
+
+/// A root with four files in all, three of them below `docs/`.
+fn sample_root() -> std::path::PathBuf {
+    let root = common::temp_dir("dry-run");
+    common::write(&root, "readme.txt", "read me\n");
+    common::write(&root, "docs/a.txt", "a".repeat(100));
+    common::write(&root, "docs/b.txt", "b".repeat(200));
+    common::write(&root, "docs/deeper/c.txt", "c".repeat(300));
+    root
+}
+
+fn assert_sample(report: &DryRunReport) {
+    let mut names: Vec<_> = report.files.iter().map(|file| file.name.as_str()).collect();
+    names.sort();
+    assert_eq!(names, ["docs/a.txt", "docs/b.txt", "docs/deeper/c.txt", "readme.txt"]);
+    assert_eq!(report.total_size, 608);
+    assert!(report.estimated_compressed_size > 0);
+}
+
+#[test]
+fn builder_reports_every_file() {
+    let root = sample_root();
+    let report = ArchiveBuilder::new().file(root.join("readme.txt")).file(root.join("docs")).dry_run().unwrap();
+    assert_sample(&report);
+}
+
+#[tokio::test]
+async fn server_answers_with_a_report_and_writes_nothing() {
+    let root = sample_root();
+    let cache_dir = common::temp_dir("dry-run-cache");
+    let mut config = ServerConfig::new(&root);
+    config.dry_run = true;
+    config.cache_dir = Some(cache_dir.clone());
+    let base = common::serve(config).await;
+
+    let response = reqwest::get(format!("{}/download?files=readme.txt&files=docs", base)).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_sample(&response.json().await.unwrap());
+    assert_eq!(std::fs::read_dir(&cache_dir).unwrap().count(), 0, "the dry run wrote to the cache");
+}
+
+#[tokio::test]
+async fn download_dry_run_writes_no_file() {
+    let root = sample_root();
+    let base = common::serve(ServerConfig::new(&root)).await;
+    let work_dir = common::temp_dir("dry-run-client");
+
+    let output = tokio::process::Command::new(env!("CARGO_BIN_EXE_rust-file-archive-server"))
+        .current_dir(&work_dir)
+        .args(["download", "--server-url", &base, "--dry-run", "--output", "out.tar.gz", "--file", "readme.txt", "--file", "docs"])
+        .output().await.unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let printed = String::from_utf8_lossy(&output.stdout);
+    assert!(printed.contains("would archive 4 file(s)"), "{}", printed);
+    assert!(printed.contains("608 bytes"), "{}", printed);
+    assert_eq!(std::fs::read_dir(&work_dir).unwrap().count(), 0, "the dry run wrote a file");
+}
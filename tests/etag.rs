@@ -0,0 +1,54 @@
+//! Archive ETags change whenever the archive would.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::{os::unix::fs::PermissionsExt, path::Path};
+
+use rust_file_archive_server::ArchiveBuilder;
+
+fn etag(path: &Path, preserve_metadata: bool) -> String {
+    ArchiveBuilder::new().file(path).preserve_metadata(preserve_metadata).etag().unwrap()
+}
+
+fn chmod(path: &Path, mode: u32) {
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+}
+
+#[test]
+fn permission_changes_change_the_etag() {
+    let dir = common::temp_dir("etag-mode");
+    let file = common::write(&dir, "script.sh", "echo hello\n");
+    chmod(&file, 0o644);
+    let (preserved, normalized) = (etag(&file, true), etag(&file, false));
+    assert_eq!(etag(&file, true), preserved);
+
+    // Stored as it is only when metadata is preserved
+    chmod(&file, 0o600);
+    assert_ne!(etag(&file, true), preserved);
+    assert_eq!(etag(&file, false), normalized);
+
+    // The executable bit is kept either way
+    chmod(&file, 0o755);
+    assert_ne!(etag(&file, false), normalized);
+}
+
+#[test]
+fn owner_changes_change_the_etag() {
+    // Only root can give files away
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+    let dir = common::temp_dir("etag-owner");
+    let file = common::write(&dir, "data.txt", "data\n");
+    std::os::unix::fs::chown(&file, Some(0), Some(0)).unwrap();
+    let (preserved, normalized) = (etag(&file, true), etag(&file, false));
+
+    std::os::unix::fs::chown(&file, Some(1234), None).unwrap();
+    let by_user = etag(&file, true);
+    assert_ne!(by_user, preserved);
+    std::os::unix::fs::chown(&file, None, Some(1234)).unwrap();
+    assert_ne!(etag(&file, true), by_user);
+    assert_eq!(etag(&file, false), normalized);
+}
@@ -0,0 +1,105 @@
+//! Extraction refuses entries and symlinks that would reach outside the destination.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use rust_file_archive_server::archive::{extract_archive, OverwritePolicy};
+
+enum Entry<'a> {
+    File(&'a str, &'a str),
+    Link(&'a str, &'a str),
+}
+
+/// Writes a tarball of `entries` and an empty destination next to it.
+fn tarball(entries: &[Entry]) -> (PathBuf, PathBuf) {
+    let dir = common::temp_dir("extract");
+    let mut builder = tar::Builder::new(Vec::new());
+    for entry in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        match *entry {
+            Entry::File(name, contents) => {
+                header.set_size(contents.len() as u64);
+                builder.append_data(&mut header, name, contents.as_bytes()).unwrap();
+            }
+            Entry::Link(name, target) => {
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_size(0);
+                builder.append_link(&mut header, name, target).unwrap();
+            }
+        }
+    }
+    let archive = common::write(&dir, "archive.tar", builder.into_inner().unwrap());
+    let dest = dir.join("dest");
+    (archive, dest)
+}
+
+fn zipfile(entries: &[Entry]) -> (PathBuf, PathBuf) {
+    let dir = common::temp_dir("extract-zip");
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let options = zip::write::SimpleFileOptions::default();
+    for entry in entries {
+        match *entry {
+            Entry::File(name, contents) => {
+                zip.start_file(name, options).unwrap();
+                std::io::Write::write_all(&mut zip, contents.as_bytes()).unwrap();
+            }
+            Entry::Link(name, target) => zip.add_symlink(name, target, options).unwrap(),
+        }
+    }
+    let archive = common::write(&dir, "archive.zip", zip.finish().unwrap().into_inner());
+    (archive, dir.join("dest"))
+}
+
+fn assert_refused((archive, dest): (PathBuf, PathBuf), link: &str) {
+    let result = extract_archive(&archive, &dest, OverwritePolicy::Error);
+    assert!(result.is_err(), "{} was extracted", link);
+    assert!(dest.join(link).symlink_metadata().is_err(), "{} was created", link);
+}
+
+fn assert_link(dest: &Path, link: &str, target: &str) {
+    assert_eq!(std::fs::read_link(dest.join(link)).unwrap(), Path::new(target));
+}
+
+#[test]
+fn parent_targets_are_refused() {
+    assert_refused(tarball(&[Entry::Link("up", "..")]), "up");
+    assert_refused(tarball(&[Entry::Link("d/up", "../../outside")]), "d/up");
+    assert_refused(tarball(&[Entry::Link("abs", "/etc/passwd")]), "abs");
+    assert_refused(tarball(&[Entry::Link("d/sneaky", "e/../../../outside")]), "d/sneaky");
+    assert_refused(zipfile(&[Entry::Link("up", "../outside")]), "up");
+    assert_refused(zipfile(&[Entry::Link("abs", "/etc/passwd")]), "abs");
+}
+
+#[test]
+fn chained_links_are_refused() {
+    // Each passes on its own: `up` leads to the destination, so `up/..` is its parent
+    assert_refused(tarball(&[Entry::Link("d/e/up", "../.."), Entry::Link("z", "d/e/up/..")]), "z");
+    assert_refused(tarball(&[Entry::Link("z", "d/e/up/.."), Entry::Link("d/e/up", "../..")]), "z");
+    assert_refused(zipfile(&[Entry::Link("d/e/up", "../.."), Entry::Link("z", "d/e/up/..")]), "z");
+    // A link below a link climbs from where that one leads
+    assert_refused(tarball(&[Entry::Link("x/y/s", "../.."), Entry::Link("x/y/s/l", "..")]), "x/y/s/l");
+    assert_refused(zipfile(&[Entry::Link("x/y/s", "../.."), Entry::Link("x/y/s/l", "..")]), "x/y/s/l");
+}
+
+#[test]
+fn links_inside_the_destination_are_extracted() {
+    let entries = [
+        Entry::File("docs/readme.txt", "read me\n"),
+        Entry::Link("docs/same.txt", "readme.txt"),
+        Entry::Link("docs/sub/up.txt", "../readme.txt"),
+        Entry::Link("top", "docs"),
+        Entry::Link("d/e/up", "../.."),
+        Entry::Link("via", "top/readme.txt"),
+    ];
+    for (archive, dest) in [tarball(&entries), zipfile(&entries)] {
+        extract_archive(&archive, &dest, OverwritePolicy::Error).unwrap();
+        assert_link(&dest, "docs/sub/up.txt", "../readme.txt");
+        assert_link(&dest, "d/e/up", "../..");
+        assert_eq!(std::fs::read_to_string(dest.join("via")).unwrap(), "read me\n");
+        assert_eq!(std::fs::read_to_string(dest.join("docs/sub/up.txt")).unwrap(), "read me\n");
+    }
+}
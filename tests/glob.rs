@@ -0,0 +1,44 @@
+//! Glob patterns in `files=` only ever match files inside the root.
+
+mod common;
+
+use rust_file_archive_server::ServerConfig;
+
+async fn serve_root() -> String {
+    let dir = common::temp_dir("glob");
+    let root = dir.join("root");
+    common::write(&root, "logs/a.log", "a\n");
+    common::write(&root, "logs/deeper/b.log", "b\n");
+    common::write(&root, "notes.txt", "not a log\n");
+    common::write(&dir, "outside/secret.log", "secret\n");
+    common::write(&dir, "secrets.txt", "secret\n");
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(dir.join("outside"), root.join("logs/out")).unwrap();
+    common::serve(ServerConfig::new(&root)).await
+}
+
+async fn status(base: &str, files: &str) -> u16 {
+    reqwest::get(format!("{}/download?files={}", base, files)).await.unwrap().status().as_u16()
+}
+
+#[tokio::test]
+async fn patterns_leaving_the_root_are_refused_alike() {
+    let base = serve_root().await;
+    // Whether something matches outside the root makes no difference to the answer
+    for files in ["../secret%5Bs%5D.txt", "../nothing%5Bs%5D.txt", "../outside/*.log", "logs/../../*.txt", "/etc/pass%5Bw%5Dd", "/nothing/*"] {
+        assert_eq!(status(&base, files).await, 403, "{}", files);
+    }
+}
+
+#[tokio::test]
+async fn matches_through_links_out_of_the_root_are_skipped() {
+    let base = serve_root().await;
+    let response = reqwest::get(format!("{}/download?files=**/*.log", base)).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let mut names = common::tar_gz_names(&response.bytes().await.unwrap());
+    names.sort();
+    assert_eq!(names, ["a.log", "b.log"]);
+
+    assert_eq!(status(&base, "logs/out/*.log").await, 404);
+    assert_eq!(status(&base, "*.none").await, 404);
+}
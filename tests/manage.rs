@@ -0,0 +1,95 @@
+//! File management (`--manage-files`): moves and deletes stay in the root and refuse what they
+//! can't do cleanly.
+
+mod common;
+
+use std::path::PathBuf;
+
+use rust_file_archive_server::{server::AuthConfig, ServerConfig};
+
+const TOKEN: &str = "manage-token";
+
+/// Serves a managed root holding `dir/inner/file.txt` and `dir/other.txt`, and returns it too.
+async fn managed_server() -> (String, PathBuf) {
+    let root = common::temp_dir("manage");
+    common::write(&root, "dir/inner/file.txt", "inner\n");
+    common::write(&root, "dir/other.txt", "other\n");
+    let mut config = ServerConfig::new(root.clone());
+    config.auth = AuthConfig::new(vec![TOKEN.to_string()]);
+    config.manage_files = true;
+    (common::serve(config).await, root)
+}
+
+async fn move_entry(base: &str, from: &str, to: &str) -> reqwest::StatusCode {
+    reqwest::Client::new().post(format!("{}/files/move", base))
+        .query(&[("from", from), ("to", to)])
+        .bearer_auth(TOKEN).send().await.unwrap().status()
+}
+
+async fn remove(base: &str, path: &str, recursive: bool) -> reqwest::StatusCode {
+    reqwest::Client::new().delete(format!("{}/files", base))
+        .query(&[("path", path), ("recursive", &recursive.to_string())])
+        .bearer_auth(TOKEN).send().await.unwrap().status()
+}
+
+#[tokio::test]
+async fn moves_stay_in_the_root() {
+    let (base, root) = managed_server().await;
+
+    for to in ["../x", "dir/../../x", "dir/.."] {
+        assert_eq!(move_entry(&base, "dir/other.txt", to).await, 400, "to={}", to);
+    }
+    assert_eq!(move_entry(&base, "../manage", "x").await, 400);
+    assert!(root.join("dir/other.txt").exists());
+
+    assert_eq!(move_entry(&base, "dir/other.txt", "renamed.txt").await, 204);
+    assert_eq!(std::fs::read_to_string(root.join("renamed.txt")).unwrap(), "other\n");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn moves_through_symlinked_parents_are_refused() {
+    let (base, root) = managed_server().await;
+    let outside = common::temp_dir("manage-outside");
+    common::write(&outside, "theirs.txt", "not ours\n");
+    std::os::unix::fs::symlink(&outside, root.join("out")).unwrap();
+
+    assert!(move_entry(&base, "dir/other.txt", "out/stolen.txt").await.is_client_error());
+    assert!(move_entry(&base, "out/theirs.txt", "mine.txt").await.is_client_error());
+    assert!(remove(&base, "out/theirs.txt", false).await.is_client_error());
+    assert!(root.join("dir/other.txt").exists());
+    assert!(!outside.join("stolen.txt").exists());
+    assert!(outside.join("theirs.txt").exists());
+
+    // The link itself is an entry like any other, and moving it leaves its target alone
+    assert_eq!(move_entry(&base, "out", "moved-link").await, 204);
+    assert!(root.join("moved-link").symlink_metadata().unwrap().file_type().is_symlink());
+    assert!(outside.join("theirs.txt").exists());
+}
+
+#[tokio::test]
+async fn directories_cannot_move_into_themselves() {
+    let (base, root) = managed_server().await;
+
+    assert_eq!(move_entry(&base, "dir", "dir/inner/dir").await, 400);
+    assert_eq!(move_entry(&base, "dir", "dir/moved").await, 400);
+    assert!(root.join("dir/inner/file.txt").exists());
+
+    // A sibling that only shares a prefix is fine
+    assert_eq!(move_entry(&base, "dir/inner", "dir-inner").await, 204);
+    assert!(root.join("dir-inner/file.txt").exists());
+}
+
+#[tokio::test]
+async fn non_empty_directories_need_a_recursive_delete() {
+    let (base, root) = managed_server().await;
+
+    assert_eq!(remove(&base, "dir", false).await, 409);
+    assert!(root.join("dir/inner/file.txt").exists());
+
+    assert_eq!(remove(&base, "dir/inner/file.txt", false).await, 204);
+    assert_eq!(remove(&base, "dir/inner", false).await, 204);
+    assert_eq!(remove(&base, "dir", true).await, 204);
+    assert!(!root.join("dir").exists());
+    assert_eq!(remove(&base, "dir", true).await, 404);
+}
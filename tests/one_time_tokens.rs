@@ -0,0 +1,55 @@
+//! One-time tokens (--qr-token): a single download of the default files, and its resumes.
+
+mod common;
+
+use rust_file_archive_server::{server::AuthConfig, ServerConfig};
+
+/// Serves a root whose default file is `report.txt`, returning its URL and a one-time token.
+async fn token_server() -> (String, String) {
+    let root = common::temp_dir("one-time-tokens");
+    common::write(&root, "report.txt", "quarterly figures\n".repeat(2000));
+    common::write(&root, "private.txt", "not shared\n");
+    let mut config = ServerConfig::new(root);
+    config.default_files = vec!["report.txt".to_string()];
+    config.auth = AuthConfig::new(vec!["one-time-tokens-token".to_string()]);
+    let token = config.auth.add_one_time_token().unwrap();
+    (common::serve(config).await, token)
+}
+
+#[tokio::test]
+async fn tokens_grant_only_the_default_files() {
+    let (base, token) = token_server().await;
+    let client = reqwest::Client::new();
+
+    let response = client.get(format!("{}/download?files=private.txt&token={}", base, token)).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(common::tar_gz_names(&response.bytes().await.unwrap()), ["report.txt"]);
+
+    for path in ["/list", "/checksum"] {
+        let response = client.get(format!("{}{}?token={}", base, path, token)).send().await.unwrap();
+        assert_eq!(response.status(), 401, "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn tokens_allow_one_download_and_its_resumes() {
+    let (base, token) = token_server().await;
+    let client = reqwest::Client::new();
+    let url = format!("{}/download?token={}", base, token);
+
+    // HEAD doesn't use the token up
+    assert_eq!(client.head(&url).send().await.unwrap().status(), 200);
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    let etag = response.headers()["etag"].to_str().unwrap().to_string();
+    let whole = response.bytes().await.unwrap();
+
+    assert_eq!(client.get(&url).send().await.unwrap().status(), 410);
+
+    let range = format!("bytes={}-", whole.len() / 2);
+    let response = client.get(&url).header("Range", &range).header("If-Range", &etag).send().await.unwrap();
+    assert_eq!(response.status(), 206);
+    assert_eq!(response.bytes().await.unwrap(), whole[whole.len() / 2..]);
+    let response = client.get(&url).header("Range", &range).send().await.unwrap();
+    assert_eq!(response.status(), 410);
+}
@@ -0,0 +1,46 @@
+//! Several ranges at once come back as multipart/byteranges.
+
+mod common;
+
+use rust_file_archive_server::ServerConfig;
+
+/// The Content-Type of a whole archive and of every part of a two-range response from a
+/// server started with `config`.
+async fn content_types(config: ServerConfig) -> (String, Vec<String>) {
+    let base = common::serve(config).await;
+    let whole = reqwest::get(format!("{}/download?files=data.txt", base)).await.unwrap();
+    let whole = whole.headers()["content-type"].to_str().unwrap().to_string();
+    let response = reqwest::Client::new().get(format!("{}/download?files=data.txt", base))
+        .header("Range", "bytes=0-9,100-109")
+        .send().await.unwrap();
+    assert_eq!(response.status(), 206);
+    assert!(response.headers()["content-type"].to_str().unwrap().starts_with("multipart/byteranges; boundary="));
+    let body = response.bytes().await.unwrap();
+    let parts = String::from_utf8_lossy(&body).lines()
+        .filter_map(|line| line.strip_prefix("Content-Type: "))
+        .map(str::to_string)
+        .collect();
+    (whole, parts)
+}
+
+fn root() -> std::path::PathBuf {
+    let root = common::temp_dir("ranges");
+    common::write(&root, "data.txt", "some data\n".repeat(1000));
+    root
+}
+
+#[tokio::test]
+async fn parts_carry_the_archive_type() {
+    let (whole, parts) = content_types(ServerConfig::new(root())).await;
+    assert_ne!(whole, "application/octet-stream");
+    assert_eq!(parts, [whole.clone(), whole]);
+}
+
+#[tokio::test]
+async fn parts_of_an_encrypted_archive_are_opaque() {
+    let mut config = ServerConfig::new(root());
+    config.encrypt_passphrase = Some("correct horse battery staple".to_string());
+    let (whole, parts) = content_types(config).await;
+    assert_eq!(whole, "application/octet-stream");
+    assert_eq!(parts, [whole.clone(), whole]);
+}
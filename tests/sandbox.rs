@@ -0,0 +1,63 @@
+//! Requested paths never reach outside the server root, by `..` or through a symlink.
+
+mod common;
+
+use rust_file_archive_server::ServerConfig;
+
+const SECRET: &str = "the contents of a file outside the root";
+
+/// Serves `root/` of a fresh directory that also holds `outside/secret.txt`, with links out of
+/// the root, and returns the server's base URL.
+async fn serve_root() -> String {
+    let dir = common::temp_dir("sandbox");
+    let root = dir.join("root");
+    common::write(&root, "public.txt", "public\n");
+    common::write(&dir, "outside/secret.txt", SECRET);
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(dir.join("outside"), root.join("escape")).unwrap();
+        std::os::unix::fs::symlink(dir.join("outside/secret.txt"), root.join("secret-link.txt")).unwrap();
+        std::os::unix::fs::symlink("../outside/secret.txt", root.join("relative-link.txt")).unwrap();
+        std::os::unix::fs::symlink("public.txt", root.join("inside-link.txt")).unwrap();
+    }
+    common::serve(ServerConfig::new(&root)).await
+}
+
+async fn assert_refused(url: String) {
+    let response = reqwest::get(&url).await.unwrap();
+    let status = response.status().as_u16();
+    let body = response.text().await.unwrap_or_default();
+    assert!(status == 403 || status == 404, "{} answered {}", url, status);
+    assert!(!body.contains(SECRET), "{} revealed the secret", url);
+}
+
+#[tokio::test]
+async fn dot_dot_segments_are_refused() {
+    let base = serve_root().await;
+    for files in ["../outside/secret.txt", "..", "./../outside", "public.txt/../../outside/secret.txt", "%2E%2E/outside/secret.txt", "..%2Foutside"] {
+        assert_refused(format!("{}/download?files={}", base, files)).await;
+    }
+    assert_refused(format!("{}/download?files=public.txt&files=../outside/secret.txt", base)).await;
+    assert_refused(format!("{}/download?files=/etc/passwd", base)).await;
+    assert_refused(format!("{}/file/..%2Foutside%2Fsecret.txt", base)).await;
+    assert_refused(format!("{}/list?path=..", base)).await;
+
+    let response = reqwest::get(format!("{}/download?files=public.txt", base)).await.unwrap();
+    assert_eq!(response.status(), 200);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn symlinks_out_of_the_root_are_refused() {
+    let base = serve_root().await;
+    for files in ["escape", "escape/secret.txt", "secret-link.txt", "relative-link.txt"] {
+        assert_refused(format!("{}/download?files={}", base, files)).await;
+    }
+    assert_refused(format!("{}/file/escape/secret.txt", base)).await;
+    assert_refused(format!("{}/file/secret-link.txt", base)).await;
+
+    // Links that stay inside the root are fine
+    let response = reqwest::get(format!("{}/download?files=inside-link.txt", base)).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(common::tar_gz_names(&response.bytes().await.unwrap()).len(), 1);
+}
@@ -0,0 +1,94 @@
+//! Share links: use limits, resumes that don't count against them, and expiry.
+
+mod common;
+
+use std::time::Duration;
+
+use rust_file_archive_server::{server::{AuthConfig, ShareLink, ShareRequest}, ServerConfig};
+
+const TOKEN: &str = "share-links-token";
+
+async fn share_server() -> String {
+    let root = common::temp_dir("share-links");
+    common::write(&root, "report.txt", "quarterly figures\n".repeat(2000));
+    common::write(&root, "private.txt", "not shared\n");
+    let mut config = ServerConfig::new(root);
+    config.auth = AuthConfig::new(vec![TOKEN.to_string()]);
+    common::serve(config).await
+}
+
+async fn share(client: &reqwest::Client, base: &str, expires_in: u64, max_uses: Option<u32>) -> ShareLink {
+    let request = ShareRequest { files: vec!["report.txt".to_string()], expires_in, max_uses };
+    let response = client.post(format!("{}/share", base)).bearer_auth(TOKEN).json(&request).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    response.json().await.unwrap()
+}
+
+#[tokio::test]
+async fn links_are_refused_once_used_up() {
+    let base = share_server().await;
+    let client = reqwest::Client::new();
+    let link = share(&client, &base, 3600, Some(2)).await;
+    let url = format!("{}{}", base, link.url);
+
+    for _ in 0..2 {
+        assert_eq!(client.get(&url).send().await.unwrap().status(), 200);
+    }
+    assert_eq!(client.get(&url).send().await.unwrap().status(), 410);
+    // HEAD doesn't download anything, so it never counted
+    assert_eq!(client.head(&url).send().await.unwrap().status(), 200);
+}
+
+#[tokio::test]
+async fn resumes_are_not_counted() {
+    let base = share_server().await;
+    let client = reqwest::Client::new();
+    let link = share(&client, &base, 3600, Some(1)).await;
+    let url = format!("{}{}", base, link.url);
+
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    let etag = response.headers()["etag"].to_str().unwrap().to_string();
+    let whole = response.bytes().await.unwrap();
+
+    for _ in 0..3 {
+        let response = client.get(&url).header("Range", "bytes=100-").header("If-Range", &etag).send().await.unwrap();
+        assert_eq!(response.status(), 206);
+        assert_eq!(response.bytes().await.unwrap(), whole[100..]);
+    }
+
+    // A range without the ETag of a download made with the link is a new download
+    let response = client.get(&url).header("Range", "bytes=100-").send().await.unwrap();
+    assert_eq!(response.status(), 410);
+    let response = client.get(&url).header("Range", "bytes=100-").header("If-Range", "\"other\"").send().await.unwrap();
+    assert_eq!(response.status(), 410);
+    let response = client.get(&url).header("Range", "bytes=0-9,100-").header("If-Range", &etag).send().await.unwrap();
+    assert_eq!(response.status(), 416);
+}
+
+#[tokio::test]
+async fn expired_links_are_refused() {
+    let base = share_server().await;
+    let client = reqwest::Client::new();
+    let link = share(&client, &base, 1, None).await;
+    let url = format!("{}{}", base, link.url);
+
+    tokio::time::sleep(Duration::from_millis(2100)).await;
+    assert_eq!(client.get(&url).send().await.unwrap().status(), 403);
+    assert_eq!(client.head(&url).send().await.unwrap().status(), 403);
+}
+
+#[tokio::test]
+async fn links_grant_only_their_files() {
+    let base = share_server().await;
+    let client = reqwest::Client::new();
+    let link = share(&client, &base, 3600, None).await;
+
+    let response = client.get(format!("{}{}&files=private.txt", base, link.url)).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(common::tar_gz_names(&response.bytes().await.unwrap()), ["report.txt"]);
+
+    // Nor does the link open anything but /download
+    let response = client.get(format!("{}/list?token={}", base, link.token)).send().await.unwrap();
+    assert_eq!(response.status(), 401);
+}
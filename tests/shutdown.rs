@@ -0,0 +1,56 @@
+//! SIGTERM drains in-flight downloads before the server exits (`--pid-file`, `--shutdown-timeout`).
+
+#![cfg(unix)]
+
+mod common;
+
+use std::{io::Read, process::{Command, Stdio}, time::{Duration, Instant}};
+
+#[tokio::test]
+async fn sigterm_lets_downloads_finish_and_removes_the_pid_file() {
+    let dir = common::temp_dir("shutdown");
+    // Incompressible, so the download takes a couple of seconds at the rate below
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let data: Vec<u8> = (0..400_000).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as u8
+    }).collect();
+    let root = dir.join("root");
+    common::write(&root, "big.bin", &data);
+    let pid_file = dir.join("server.pid");
+    let port = common::free_port();
+
+    let mut server = Command::new(env!("CARGO_BIN_EXE_rust-file-archive-server"))
+        .arg("server").arg("--root").arg(&root)
+        .args(["--bind-address", "127.0.0.1", "--port", &port.to_string(), "--max-rate", "200K"])
+        .arg("--pid-file").arg(&pid_file)
+        .stdout(Stdio::null()).stderr(Stdio::null())
+        .spawn().unwrap();
+
+    let base = format!("http://127.0.0.1:{}", port);
+    let started = Instant::now();
+    while reqwest::get(format!("{}/list", base)).await.is_err() {
+        assert!(started.elapsed() < Duration::from_secs(10), "the server did not start");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let pid = std::fs::read_to_string(&pid_file).unwrap();
+    assert_eq!(pid.trim(), server.id().to_string());
+
+    let response = reqwest::get(format!("{}/download?files=big.bin", base)).await.unwrap();
+    assert_eq!(response.status(), 200);
+    unsafe { libc::kill(server.id() as libc::pid_t, libc::SIGTERM) };
+    let archive = response.bytes().await.unwrap();
+
+    let mut entries = tar::Archive::new(flate2::read::GzDecoder::new(&archive[..]));
+    let mut entry = entries.entries().unwrap().next().unwrap().unwrap();
+    let mut received = Vec::new();
+    entry.read_to_end(&mut received).unwrap();
+    assert!(received == data, "the download was cut off at {} of {} bytes", received.len(), data.len());
+
+    assert!(server.wait().unwrap().success());
+    assert!(!pid_file.exists(), "the pid file was left behind");
+    // Nothing is accepted once the server is gone
+    assert!(reqwest::get(format!("{}/list", base)).await.is_err());
+}
@@ -0,0 +1,112 @@
+//! Resumable uploads: opening, resuming and completing a session, and chunks that don't start
+//! where the server's copy ends.
+
+mod common;
+
+use std::{path::PathBuf, time::Duration};
+
+use rust_file_archive_server::{server::UploadStatus, ServerConfig};
+
+const CONTENTS: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+/// Serves an empty root with uploads going to a directory of their own, which is returned too.
+async fn upload_server() -> (String, PathBuf) {
+    let uploads = common::temp_dir("upload-sessions-into");
+    let mut config = ServerConfig::new(common::temp_dir("upload-sessions"));
+    config.upload_dir = Some(uploads.clone());
+    (common::serve(config).await, uploads)
+}
+
+async fn open(client: &reqwest::Client, base: &str) -> (reqwest::StatusCode, UploadStatus) {
+    let response = client.post(format!("{}/upload/session?name=data.bin&size={}", base, CONTENTS.len()))
+        .send().await.unwrap();
+    (response.status(), response.json().await.unwrap())
+}
+
+async fn status(client: &reqwest::Client, base: &str, id: &str) -> UploadStatus {
+    let response = client.get(format!("{}/upload/session/{}", base, id)).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    response.json().await.unwrap()
+}
+
+fn send_chunk(client: &reqwest::Client, base: &str, id: &str, offset: usize, chunk: impl Into<reqwest::Body>) -> reqwest::RequestBuilder {
+    client.patch(format!("{}/upload/session/{}", base, id))
+        .header("Upload-Offset", offset.to_string())
+        .body(chunk)
+}
+
+#[tokio::test]
+async fn sessions_resume_and_complete() {
+    let (base, uploads) = upload_server().await;
+    let client = reqwest::Client::new();
+
+    let (created, session) = open(&client, &base).await;
+    assert_eq!(created, 201);
+    assert_eq!((session.offset, session.size, session.complete), (0, CONTENTS.len() as u64, false));
+
+    let half = CONTENTS.len() / 2;
+    let response = send_chunk(&client, &base, &session.id, 0, &CONTENTS[..half]).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["Upload-Offset"], half.to_string().as_str());
+    assert_eq!(status(&client, &base, &session.id).await.offset, half as u64);
+
+    // Opening the same upload again picks it up where it stopped
+    let (resumed, again) = open(&client, &base).await;
+    assert_eq!(resumed, 200);
+    assert_eq!((again.id.as_str(), again.offset), (session.id.as_str(), half as u64));
+    assert!(!uploads.join("data.bin").exists());
+
+    let response = send_chunk(&client, &base, &session.id, half, &CONTENTS[half..]).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    let done: UploadStatus = response.json().await.unwrap();
+    assert!(done.complete);
+    assert_eq!(std::fs::read(uploads.join("data.bin")).unwrap(), CONTENTS);
+
+    // A completed session is gone
+    let response = client.get(format!("{}/upload/session/{}", base, session.id)).send().await.unwrap();
+    assert_eq!(response.status(), 404);
+}
+
+#[tokio::test]
+async fn chunks_at_the_wrong_offset_are_refused() {
+    let (base, uploads) = upload_server().await;
+    let client = reqwest::Client::new();
+    let (_, session) = open(&client, &base).await;
+    send_chunk(&client, &base, &session.id, 0, &CONTENTS[..10]).send().await.unwrap();
+
+    for offset in [0, 5, 20] {
+        let response = send_chunk(&client, &base, &session.id, offset, &CONTENTS[offset..]).send().await.unwrap();
+        assert_eq!(response.status(), 409, "offset {}", offset);
+        assert_eq!(response.headers()["Upload-Offset"], "10");
+    }
+    assert_eq!(status(&client, &base, &session.id).await.offset, 10);
+    assert!(!uploads.join("data.bin").exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn only_one_of_two_chunks_for_the_same_offset_is_appended() {
+    let (base, uploads) = upload_server().await;
+    let client = reqwest::Client::new();
+    let (_, session) = open(&client, &base).await;
+    let half = CONTENTS.len() / 2;
+
+    // The first chunk's bytes are held back, so the second arrives while it is still being received
+    let slow = futures_util::stream::once(async move {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        Ok::<_, std::io::Error>(&CONTENTS[..half])
+    });
+    let first = send_chunk(&client, &base, &session.id, 0, reqwest::Body::wrap_stream(slow)).send();
+    let second = async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        send_chunk(&client, &base, &session.id, 0, &CONTENTS[..half]).send().await
+    };
+    let (first, second) = tokio::join!(first, second);
+
+    let mut statuses = [first.unwrap().status().as_u16(), second.unwrap().status().as_u16()];
+    statuses.sort();
+    assert_eq!(statuses, [200, 409]);
+    assert_eq!(status(&client, &base, &session.id).await.offset, half as u64);
+
+    send_chunk(&client, &base, &session.id, half, &CONTENTS[half..]).send().await.unwrap();
+    assert_eq!(std::fs::read(uploads.join("data.bin")).unwrap(), CONTENTS);
+}
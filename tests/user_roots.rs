@@ -0,0 +1,91 @@
+//! Users with a root of their own only reach that root, and read-only accounts can't write.
+
+mod common;
+
+use rust_file_archive_server::{server::{AuthConfig, ListEntry}, ServerConfig};
+
+/// Serves a root with a directory for each team, for alice (teams/a), bob (teams/b, read-only)
+/// and an auditor who sees the whole root read-only.
+async fn teams_server() -> String {
+    let root = common::temp_dir("user-roots");
+    common::write(&root, "teams/a/a.txt", "alice's notes\n");
+    common::write(&root, "teams/b/b.txt", "bob's secret\n");
+    let users = common::write(&common::temp_dir("user-roots-auth"), "users",
+        "alice:alice-pw root=teams/a\nbob:bob-pw root=teams/b access=read-only\nauditor:auditor-pw access=read-only\n");
+
+    let mut config = ServerConfig::new(root);
+    config.auth = AuthConfig::new(Vec::new()).with_users_file(&users).unwrap();
+    config.upload_dir = Some(common::temp_dir("user-roots-uploads"));
+    config.webdav = true;
+    config.webdav_writable = true;
+    config.manage_files = true;
+    common::serve(config).await
+}
+
+async fn list(client: &reqwest::Client, base: &str, user: &str, path: &str) -> reqwest::Response {
+    client.get(format!("{}/list?path={}", base, path)).basic_auth(user, Some(format!("{}-pw", user))).send().await.unwrap()
+}
+
+#[tokio::test]
+async fn users_only_reach_their_own_root() {
+    let base = teams_server().await;
+    let client = reqwest::Client::new();
+
+    let entries: Vec<ListEntry> = list(&client, &base, "alice", "").await.json().await.unwrap();
+    let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+    assert_eq!(names, ["a.txt"]);
+
+    for path in ["../b", "teams/b", "/"] {
+        let response = list(&client, &base, "alice", path).await;
+        assert!(response.status().is_client_error(), "{} listed {}", path, response.status());
+        assert!(!response.text().await.unwrap().contains("b.txt"));
+    }
+
+    let alice = |url: String| client.get(url).basic_auth("alice", Some("alice-pw"));
+    for files in ["../b/b.txt", "teams/b/b.txt", "../../teams/b"] {
+        let response = alice(format!("{}/download?files={}", base, files)).send().await.unwrap();
+        assert!(response.status().is_client_error(), "{} downloaded with {}", files, response.status());
+        assert!(!response.text().await.unwrap().contains("bob's secret"));
+    }
+    let response = alice(format!("{}/file/..%2Fb%2Fb.txt", base)).send().await.unwrap();
+    assert!(response.status().is_client_error());
+    assert!(!response.text().await.unwrap().contains("bob's secret"));
+    assert_eq!(alice(format!("{}/file/a.txt", base)).send().await.unwrap().status(), 200);
+
+    // Bob still gets his own files
+    let response = client.get(format!("{}/download?files=b.txt", base)).basic_auth("bob", Some("bob-pw")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(common::tar_gz_names(&response.bytes().await.unwrap()), ["b.txt"]);
+}
+
+#[tokio::test]
+async fn read_only_accounts_cannot_write() {
+    let base = teams_server().await;
+    let client = reqwest::Client::new();
+
+    for user in ["bob", "auditor"] {
+        let password = format!("{}-pw", user);
+        let requests = [
+            client.post(format!("{}/upload", base)).body("data"),
+            client.post(format!("{}/upload/session?name=x.txt&size=4", base)),
+            client.delete(format!("{}/files?path=b.txt", base)),
+            client.post(format!("{}/files/mkdir?path=new", base)),
+            client.put(format!("{}/dav/new.txt", base)).body("data"),
+            client.request(reqwest::Method::from_bytes(b"MKCOL").unwrap(), format!("{}/dav/new", base)),
+        ];
+        for request in requests {
+            let request = request.basic_auth(user, Some(&password)).build().unwrap();
+            let described = format!("{} {} as {}", request.method(), request.url().path(), user);
+            let response = client.execute(request).await.unwrap();
+            assert_eq!(response.status(), 403, "{}", described);
+        }
+
+        // Reading is still fine
+        let response = client.get(format!("{}/list", base)).basic_auth(user, Some(&password)).send().await.unwrap();
+        assert_eq!(response.status(), 200, "{}", user);
+    }
+
+    // Alice may write into her root
+    let response = client.put(format!("{}/dav/new.txt", base)).basic_auth("alice", Some("alice-pw")).body("data").send().await.unwrap();
+    assert_eq!(response.status(), 201);
+}
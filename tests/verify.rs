@@ -0,0 +1,19 @@
+//! Generated archives are read back before they are sent (`--skip-verify` turns that off).
+
+mod common;
+
+use rust_file_archive_server::{archive::verify_archive, ArchiveBuilder};
+
+#[test]
+fn truncated_tar_gz_fails_verification() {
+    let dir = common::temp_dir("truncated");
+    let file = common::write(&dir, "data.txt", "some data to archive\n".repeat(1000));
+    let builder = ArchiveBuilder::new().file(&file);
+    let archive = builder.build().unwrap();
+    let options = builder.options().unwrap();
+
+    assert_eq!(verify_archive(&archive, &options).unwrap(), 21_000);
+    for len in [archive.len() / 2, archive.len() - 8] {
+        assert!(verify_archive(&archive[..len], &options).is_err(), "{} of {} bytes passed", len, archive.len());
+    }
+}
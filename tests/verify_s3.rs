@@ -0,0 +1,45 @@
+//! Archives built from object storage are verified too: an object cut short fails the
+//! download instead of sending a broken archive. Kept apart because it points the S3 client at
+//! a fake bucket through the environment.
+
+mod common;
+
+use axum::{extract::Path as UrlPath, http::header, response::IntoResponse, routing::get, Router};
+use rust_file_archive_server::ServerConfig;
+
+/// The size a fake bucket lists its one object with; a GET returns much less, as a connection
+/// dropped mid-object would.
+const LISTED_SIZE: u64 = 100_000;
+const SENT: &[u8] = b"only the start";
+
+async fn listing() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "application/xml")], format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?><ListBucketResult><Name>bucket</Name><IsTruncated>false</IsTruncated>\
+         <Contents><Key>big.bin</Key><LastModified>2024-01-01T00:00:00.000Z</LastModified><ETag>\"1\"</ETag><Size>{}</Size></Contents>\
+         </ListBucketResult>",
+        LISTED_SIZE,
+    ))
+}
+
+async fn object(UrlPath(key): UrlPath<String>) -> impl IntoResponse {
+    assert_eq!(key, "big.bin");
+    SENT
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn corrupt_archive_is_refused_with_500() {
+    let bucket = Router::new().route("/bucket/", get(listing)).route("/bucket/*key", get(object));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, bucket).await.unwrap() });
+
+    // The only test in this binary, so nothing else reads the environment meanwhile
+    std::env::set_var("AWS_ENDPOINT_URL_S3", &endpoint);
+    std::env::remove_var("AWS_ACCESS_KEY_ID");
+    let base = common::serve(ServerConfig::new("s3://bucket")).await;
+
+    let response = reqwest::get(format!("{}/download?files=big.bin", base)).await.unwrap();
+    assert_eq!(response.status(), 500);
+    let body = response.text().await.unwrap();
+    assert!(body.starts_with("Generated archive failed verification"), "{}", body);
+}
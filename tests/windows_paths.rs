@@ -0,0 +1,67 @@
+//! Clients may separate requested paths with backslashes; entry names always use `/`.
+
+mod common;
+
+use std::io::Read;
+
+use rust_file_archive_server::ServerConfig;
+
+async fn serve_root() -> String {
+    let root = common::temp_dir("windows-paths");
+    common::write(&root, "docs/reports/q1.txt", "first quarter\n");
+    common::write(&root, "docs/reports/q2.txt", "second quarter\n");
+    common::write(&root, "docs/readme.txt", "read me\n");
+    common::serve(ServerConfig::new(&root)).await
+}
+
+async fn download(base: &str, query: &str) -> bytes::Bytes {
+    let response = reqwest::get(format!("{}/download?{}", base, query)).await.unwrap();
+    assert_eq!(response.status(), 200, "{}", query);
+    response.bytes().await.unwrap()
+}
+
+fn sorted(mut names: Vec<String>) -> Vec<String> {
+    names.sort();
+    names
+}
+
+#[tokio::test]
+async fn backslash_paths_give_slash_entry_names() {
+    let base = serve_root().await;
+
+    let archive = download(&base, r"files=docs%5Creports%5Cq1.txt").await;
+    assert_eq!(common::tar_gz_names(&archive), ["q1.txt"]);
+
+    let archive = download(&base, r"files=docs%5Creports&files=docs/readme.txt").await;
+    assert_eq!(sorted(common::tar_gz_names(&archive)), ["readme.txt", "reports", "reports/q1.txt", "reports/q2.txt"]);
+
+    let archive = download(&base, r"files=docs%5Creports&format=zip").await;
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(archive)).unwrap();
+    let names = sorted(zip.file_names().map(str::to_string).collect());
+    assert!(names.iter().all(|name| !name.contains('\\')), "{:?}", names);
+    assert_eq!(names.iter().filter(|name| name.starts_with("reports/q")).count(), 2, "{:?}", names);
+    let mut contents = String::new();
+    zip.by_name("reports/q2.txt").unwrap().read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "second quarter\n");
+}
+
+#[tokio::test]
+async fn backslash_dot_dot_is_refused() {
+    let base = serve_root().await;
+    for files in [r"..%5C..%5Cetc%5Cpasswd", r"docs%5C..%5C..", r"docs/reports%5C..%5C..%5C.."] {
+        let status = reqwest::get(format!("{}/download?files={}", base, files)).await.unwrap().status().as_u16();
+        assert!(status == 403 || status == 404, "{} answered {}", files, status);
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn a_file_named_with_a_backslash_is_served_as_it_is() {
+    let root = common::temp_dir("backslash-name");
+    common::write(&root, r"odd\name.txt", "literal\n");
+    common::write(&root, "odd/name.txt", "nested\n");
+    let base = common::serve(ServerConfig::new(&root)).await;
+
+    let archive = download(&base, r"files=odd%5Cname.txt").await;
+    assert_eq!(common::tar_gz_names(&archive), [r"odd\name.txt"]);
+}